@@ -12,6 +12,627 @@ CREATE TABLE IF NOT EXISTS accounts (
   appservice_id TEXT,
   -- Is this account a server admin
   is_admin bool DEFAULT FALSE NOT NULL,
-  is_guest bool DEFAULT FALSE NOT NULL
+  is_guest bool DEFAULT FALSE NOT NULL,
+  -- Whether the account has been deactivated
+  deactivated bool DEFAULT FALSE NOT NULL,
+  -- Whether an admin has let the account send events as fast as it likes
+  send_limit_exempt bool DEFAULT FALSE NOT NULL
 );
-CREATE INDEX IF NOT EXISTS idx_accounts_is_guest ON accounts(is_guest);
\ No newline at end of file
+CREATE INDEX IF NOT EXISTS idx_accounts_is_guest ON accounts(is_guest);
+
+DROP TABLE IF EXISTS stream_positions;
+CREATE TABLE IF NOT EXISTS stream_positions (
+  -- The stream, e.g. account_data
+  stream TEXT NOT NULL PRIMARY KEY,
+  -- The position of the latest change to the stream
+  position BIGINT NOT NULL
+);
+-- Hands out the next position in a stream. The stream's row stays locked
+-- until the transaction taking the position ends, so positions are
+-- committed in order, and a stored position is never passed by one that
+-- is still to be committed.
+CREATE OR REPLACE FUNCTION next_stream_position(name TEXT) RETURNS BIGINT AS $$
+  INSERT INTO stream_positions (stream, position) VALUES (name, 1)
+  ON CONFLICT (stream) DO UPDATE SET position = stream_positions.position + 1
+  RETURNING position
+$$ LANGUAGE SQL;
+
+DROP TABLE IF EXISTS account_data;
+CREATE TABLE IF NOT EXISTS account_data (
+  -- The Matrix user ID localpart the data belongs to
+  localpart TEXT NOT NULL,
+  -- The room the data is scoped to, or the empty string for global account data
+  room_id TEXT NOT NULL DEFAULT '',
+  -- The event type of the account data, e.g. m.ignored_user_list
+  data_type TEXT NOT NULL,
+  content JSONB NOT NULL,
+  -- Position of the last change to the data, so sync only sends changes
+  stream_id BIGINT NOT NULL,
+  PRIMARY KEY (localpart, room_id, data_type)
+);
+CREATE INDEX IF NOT EXISTS idx_account_data_localpart_stream ON account_data(localpart, stream_id);
+CREATE INDEX IF NOT EXISTS idx_account_data_stream ON account_data(stream_id);
+
+DROP TABLE IF EXISTS ignored_users;
+CREATE TABLE IF NOT EXISTS ignored_users (
+  -- The localpart of the user doing the ignoring
+  localpart TEXT NOT NULL,
+  -- The fully qualified ID of the ignored user
+  ignored_user_id TEXT NOT NULL,
+  PRIMARY KEY (localpart, ignored_user_id)
+);
+CREATE INDEX IF NOT EXISTS idx_ignored_users_ignored ON ignored_users(ignored_user_id);
+
+DROP TABLE IF EXISTS e2e_device_keys;
+CREATE TABLE IF NOT EXISTS e2e_device_keys (
+  localpart TEXT NOT NULL,
+  device_id TEXT NOT NULL,
+  -- When the keys were last uploaded, as a unix timestamp (ms resolution).
+  ts_added_ms BIGINT NOT NULL,
+  -- The signed device keys object, as uploaded by the client
+  key_json JSONB NOT NULL,
+  PRIMARY KEY (localpart, device_id)
+);
+
+DROP TABLE IF EXISTS e2e_one_time_keys;
+CREATE TABLE IF NOT EXISTS e2e_one_time_keys (
+  localpart TEXT NOT NULL,
+  device_id TEXT NOT NULL,
+  -- The key algorithm, e.g. signed_curve25519
+  algorithm TEXT NOT NULL,
+  key_id TEXT NOT NULL,
+  -- When the key was uploaded, as a unix timestamp (ms resolution).
+  ts_added_ms BIGINT NOT NULL,
+  key_json JSONB NOT NULL,
+  PRIMARY KEY (localpart, device_id, algorithm, key_id)
+);
+
+DROP TABLE IF EXISTS e2e_fallback_keys;
+CREATE TABLE IF NOT EXISTS e2e_fallback_keys (
+  localpart TEXT NOT NULL,
+  device_id TEXT NOT NULL,
+  algorithm TEXT NOT NULL,
+  key_id TEXT NOT NULL,
+  key_json JSONB NOT NULL,
+  -- Whether the key has been handed out since it was uploaded
+  used BOOL DEFAULT FALSE NOT NULL,
+  PRIMARY KEY (localpart, device_id, algorithm)
+);
+
+DROP TABLE IF EXISTS devices;
+CREATE TABLE IF NOT EXISTS devices (
+  localpart TEXT NOT NULL,
+  device_id TEXT NOT NULL,
+  -- The human readable name of the device, as set by the client
+  display_name TEXT,
+  -- When the device was last used, as a unix timestamp (ms resolution).
+  last_seen_ts BIGINT,
+  last_seen_ip TEXT,
+  PRIMARY KEY (localpart, device_id)
+);
+
+DROP TABLE IF EXISTS device_inbox;
+CREATE TABLE IF NOT EXISTS device_inbox (
+  -- Position of the message in the to-device stream
+  stream_id BIGINT NOT NULL PRIMARY KEY,
+  -- The recipient of the message
+  localpart TEXT NOT NULL,
+  device_id TEXT NOT NULL,
+  -- The complete to-device event (type, sender and content)
+  message_json JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_device_inbox_device ON device_inbox(localpart, device_id, stream_id);
+
+DROP TABLE IF EXISTS device_inbox_txns;
+CREATE TABLE IF NOT EXISTS device_inbox_txns (
+  -- The fully qualified user ID of the sender
+  sender TEXT NOT NULL,
+  sender_device_id TEXT NOT NULL,
+  txn_id TEXT NOT NULL,
+  -- When the transaction was processed, as a unix timestamp (ms resolution).
+  ts_added_ms BIGINT NOT NULL,
+  PRIMARY KEY (sender, sender_device_id, txn_id)
+);
+
+DROP TABLE IF EXISTS e2e_cross_signing_keys;
+CREATE TABLE IF NOT EXISTS e2e_cross_signing_keys (
+  localpart TEXT NOT NULL,
+  -- The usage of the key: master, self_signing or user_signing
+  key_type TEXT NOT NULL,
+  -- When the key was uploaded, as a unix timestamp (ms resolution).
+  ts_added_ms BIGINT NOT NULL,
+  key_json JSONB NOT NULL,
+  PRIMARY KEY (localpart, key_type)
+);
+
+DROP TABLE IF EXISTS e2e_cross_signing_signatures;
+CREATE TABLE IF NOT EXISTS e2e_cross_signing_signatures (
+  -- The fully qualified ID of the user who made the signature
+  signer TEXT NOT NULL,
+  -- The signer's key used, e.g. ed25519:<public key>
+  signing_key_id TEXT NOT NULL,
+  -- The fully qualified ID of the user whose key was signed
+  target_user_id TEXT NOT NULL,
+  -- The device ID or cross-signing public key that was signed
+  target_id TEXT NOT NULL,
+  signature TEXT NOT NULL,
+  PRIMARY KEY (signer, signing_key_id, target_user_id, target_id)
+);
+CREATE INDEX IF NOT EXISTS idx_e2e_cross_signing_signatures_target ON e2e_cross_signing_signatures(target_user_id);
+
+DROP TABLE IF EXISTS e2e_room_keys_versions;
+CREATE TABLE IF NOT EXISTS e2e_room_keys_versions (
+  localpart TEXT NOT NULL,
+  version BIGINT NOT NULL,
+  -- The algorithm used for storing backups, e.g. m.megolm_backup.v1.curve25519-aes-sha2
+  algorithm TEXT NOT NULL,
+  -- Algorithm-dependent data, e.g. the public key the backup is encrypted to
+  auth_data JSONB NOT NULL,
+  -- Bumped every time the keys in the backup change
+  etag BIGINT DEFAULT 0 NOT NULL,
+  -- Deleted versions are kept so their version numbers are never reused
+  deleted BOOL DEFAULT FALSE NOT NULL,
+  PRIMARY KEY (localpart, version)
+);
+
+DROP TABLE IF EXISTS e2e_room_keys;
+CREATE TABLE IF NOT EXISTS e2e_room_keys (
+  localpart TEXT NOT NULL,
+  version BIGINT NOT NULL,
+  room_id TEXT NOT NULL,
+  session_id TEXT NOT NULL,
+  first_message_index BIGINT NOT NULL,
+  forwarded_count BIGINT NOT NULL,
+  is_verified BOOL NOT NULL,
+  -- The encrypted session, as uploaded by the client
+  session_data JSONB NOT NULL,
+  PRIMARY KEY (localpart, version, room_id, session_id)
+);
+
+DROP TABLE IF EXISTS device_lists_stream;
+CREATE TABLE IF NOT EXISTS device_lists_stream (
+  -- Position of the change in the device list stream
+  stream_id BIGINT NOT NULL PRIMARY KEY,
+  -- The fully qualified ID of the user whose devices or keys changed
+  user_id TEXT NOT NULL,
+  -- When the change happened, as a unix timestamp (ms resolution).
+  ts_added_ms BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_device_lists_stream_user ON device_lists_stream(user_id);
+
+DROP TABLE IF EXISTS dehydrated_devices;
+CREATE TABLE IF NOT EXISTS dehydrated_devices (
+  -- Each user has at most one dehydrated device
+  localpart TEXT PRIMARY KEY,
+  device_id TEXT NOT NULL,
+  -- The encrypted device (pickle), opaque to the server
+  device_data JSONB NOT NULL,
+  -- When the device was uploaded, as a unix timestamp (ms resolution).
+  ts_added_ms BIGINT NOT NULL
+);
+
+DROP TABLE IF EXISTS local_media;
+CREATE TABLE IF NOT EXISTS local_media (
+  media_id TEXT PRIMARY KEY,
+  -- The content type stored for the media, declared by the uploader or sniffed
+  content_type TEXT NOT NULL,
+  -- The size of the content in bytes
+  media_length BIGINT NOT NULL,
+  -- The file name given by the uploader
+  upload_name TEXT,
+  -- The fully qualified ID of the uploader
+  user_id TEXT NOT NULL,
+  -- When the media was uploaded, as a unix timestamp (ms resolution).
+  created_ts BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_local_media_user ON local_media(user_id, created_ts);
+
+DROP TABLE IF EXISTS url_previews;
+CREATE TABLE IF NOT EXISTS url_previews (
+  url TEXT PRIMARY KEY,
+  -- The OpenGraph data returned to clients
+  og JSONB NOT NULL,
+  -- When the preview was generated, as a unix timestamp (ms resolution).
+  ts_added_ms BIGINT NOT NULL,
+  -- When the preview must be regenerated, as a unix timestamp (ms resolution).
+  expires_ts BIGINT NOT NULL
+);
+
+DROP TABLE IF EXISTS remote_media;
+CREATE TABLE IF NOT EXISTS remote_media (
+  -- The server the media was uploaded to
+  origin TEXT NOT NULL,
+  media_id TEXT NOT NULL,
+  -- The ID the content is kept under in the media store
+  file_id TEXT NOT NULL,
+  content_type TEXT NOT NULL,
+  -- The size of the content in bytes
+  media_length BIGINT NOT NULL,
+  upload_name TEXT,
+  -- When the media was fetched, as a unix timestamp (ms resolution).
+  created_ts BIGINT NOT NULL,
+  -- When the media was last served, as a unix timestamp (ms resolution).
+  last_access_ts BIGINT NOT NULL,
+  PRIMARY KEY (origin, media_id)
+);
+CREATE INDEX IF NOT EXISTS idx_remote_media_last_access ON remote_media(last_access_ts);
+
+DROP TABLE IF EXISTS audit_log;
+CREATE TABLE IF NOT EXISTS audit_log (
+  id BIGSERIAL PRIMARY KEY,
+  -- When the action happened, as a unix timestamp (ms resolution).
+  ts BIGINT NOT NULL,
+  -- The user who performed the action, if any
+  user_id TEXT,
+  -- The client address the action was performed from, if any
+  ip TEXT,
+  -- What happened, e.g. `media.scan`
+  action TEXT NOT NULL,
+  -- Action specific details
+  details JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_audit_log_user ON audit_log(user_id, id);
+-- The audit log is append-only: entries can't be changed or deleted, not
+-- even when a user is erased
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER audit_log_append_only BEFORE UPDATE OR DELETE OR TRUNCATE ON audit_log
+  FOR EACH STATEMENT EXECUTE PROCEDURE audit_log_append_only();
+
+DROP TABLE IF EXISTS received_transactions;
+CREATE TABLE IF NOT EXISTS received_transactions (
+  -- The server that sent the transaction
+  origin TEXT NOT NULL,
+  txn_id TEXT NOT NULL,
+  -- The response sent, replayed if the transaction is sent again
+  response JSONB NOT NULL,
+  -- When the transaction was received, as a unix timestamp (ms resolution).
+  ts_added_ms BIGINT NOT NULL,
+  PRIMARY KEY (origin, txn_id)
+);
+CREATE INDEX IF NOT EXISTS idx_received_transactions_ts ON received_transactions(ts_added_ms);
+
+DROP TABLE IF EXISTS received_pdus;
+CREATE TABLE IF NOT EXISTS received_pdus (
+  event_id TEXT PRIMARY KEY,
+  -- The server that sent the event
+  origin TEXT NOT NULL,
+  -- When the event was accepted, as a unix timestamp (ms resolution). Events
+  -- received again before the replay window passes aren't checked again.
+  ts_added_ms BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_received_pdus_ts ON received_pdus(ts_added_ms);
+
+DROP TABLE IF EXISTS federation_outbound;
+CREATE TABLE IF NOT EXISTS federation_outbound (
+  -- Items are sent in the order they were queued
+  id BIGSERIAL PRIMARY KEY,
+  -- The server the item is for
+  destination TEXT NOT NULL,
+  -- Whether the item is a PDU rather than an EDU
+  is_pdu BOOLEAN NOT NULL,
+  item_json JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_federation_outbound_destination ON federation_outbound(destination, is_pdu, id);
+
+DROP TABLE IF EXISTS federation_destinations;
+CREATE TABLE IF NOT EXISTS federation_destinations (
+  destination TEXT PRIMARY KEY,
+  -- When delivery last failed, as a unix timestamp (ms resolution).
+  retry_last_ts BIGINT NOT NULL,
+  -- How long to wait after the last failure before retrying, in ms.
+  retry_interval BIGINT NOT NULL
+);
+
+DROP TABLE IF EXISTS server_keys;
+CREATE TABLE IF NOT EXISTS server_keys (
+  server_name TEXT NOT NULL,
+  key_id TEXT NOT NULL,
+  -- The unpadded base64 encoded public key
+  verify_key TEXT NOT NULL,
+  -- Until when signatures made with the key are trusted, as a unix timestamp (ms resolution).
+  valid_until_ts BIGINT NOT NULL,
+  -- When the server stopped using the key, if it has (ms resolution).
+  expired_ts BIGINT,
+  -- When the key was fetched, as a unix timestamp (ms resolution).
+  ts_added_ms BIGINT NOT NULL,
+  PRIMARY KEY (server_name, key_id)
+);
+
+DROP TABLE IF EXISTS room_invites;
+CREATE TABLE IF NOT EXISTS room_invites (
+  -- Position in the invite stream, bumped when a user is invited again
+  stream_id BIGINT NOT NULL,
+  localpart TEXT NOT NULL,
+  room_id TEXT NOT NULL,
+  event_json JSONB NOT NULL,
+  -- Stripped state events of the room, as given by the inviting server
+  invite_room_state JSONB NOT NULL,
+  PRIMARY KEY (localpart, room_id)
+);
+
+DROP TABLE IF EXISTS profiles;
+CREATE TABLE IF NOT EXISTS profiles (
+  localpart TEXT PRIMARY KEY,
+  displayname TEXT,
+  -- The mxc:// URI of the user's avatar
+  avatar_url TEXT
+);
+
+DROP TABLE IF EXISTS presence;
+CREATE TABLE IF NOT EXISTS presence (
+  -- The fully qualified ID of the user, local or remote
+  user_id TEXT PRIMARY KEY,
+  -- online, unavailable or offline
+  presence TEXT NOT NULL,
+  status_msg TEXT,
+  -- When the user was last active, as a unix timestamp (ms resolution).
+  last_active_ts BIGINT NOT NULL,
+  currently_active BOOLEAN NOT NULL,
+  -- When the presence was last heard of, as a unix timestamp (ms
+  -- resolution). Users not heard of for a while are marked offline.
+  updated_ts BIGINT NOT NULL
+);
+
+DROP TABLE IF EXISTS push_rules;
+CREATE TABLE IF NOT EXISTS push_rules (
+  localpart TEXT PRIMARY KEY,
+  -- The user's own rules, and their changes to the default rules. The
+  -- default rules themselves aren't stored.
+  rules JSONB NOT NULL
+);
+
+DROP TABLE IF EXISTS pushers;
+CREATE TABLE IF NOT EXISTS pushers (
+  localpart TEXT NOT NULL,
+  app_id TEXT NOT NULL,
+  pushkey TEXT NOT NULL,
+  -- The pusher as set by the client
+  pusher_json JSONB NOT NULL,
+  -- The last queued notification delivered
+  last_stream_id BIGINT NOT NULL,
+  -- When delivery last failed, as a unix timestamp (ms resolution).
+  retry_last_ts BIGINT NOT NULL DEFAULT 0,
+  -- How long to wait after the last failure before retrying, in ms, or 0
+  retry_interval BIGINT NOT NULL DEFAULT 0,
+  -- When delivery last succeeded, as a unix timestamp (ms resolution).
+  last_success_ts BIGINT NOT NULL DEFAULT 0,
+  PRIMARY KEY (localpart, app_id, pushkey)
+);
+CREATE INDEX IF NOT EXISTS idx_pushers_pushkey ON pushers(app_id, pushkey);
+
+DROP TABLE IF EXISTS push_outbound;
+CREATE TABLE IF NOT EXISTS push_outbound (
+  -- Notifications are delivered in the order they were queued
+  stream_id BIGSERIAL PRIMARY KEY,
+  -- The user being notified
+  localpart TEXT NOT NULL,
+  -- When the notification was queued, as a unix timestamp (ms resolution).
+  queued_ts BIGINT NOT NULL,
+  -- The notification for the push gateway, without its devices
+  notification JSONB NOT NULL,
+  -- The tweaks from the push rule actions, e.g. sound and highlight
+  tweaks JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_push_outbound_localpart ON push_outbound(localpart, stream_id);
+
+DROP TABLE IF EXISTS push_counts;
+CREATE TABLE IF NOT EXISTS push_counts (
+  localpart TEXT NOT NULL,
+  room_id TEXT NOT NULL,
+  -- The thread the counts are for, or `main` for the main timeline
+  thread_id TEXT NOT NULL,
+  -- Events since the user's last read receipt that notified them
+  notification_count BIGINT NOT NULL DEFAULT 0,
+  -- Of those, the events that were highlighted
+  highlight_count BIGINT NOT NULL DEFAULT 0,
+  -- Position of the last change to the counts, so sync only sends changes
+  stream_id BIGINT NOT NULL,
+  PRIMARY KEY (localpart, room_id, thread_id)
+);
+CREATE INDEX IF NOT EXISTS idx_push_counts_stream ON push_counts(localpart, stream_id);
+CREATE INDEX IF NOT EXISTS idx_push_counts_position ON push_counts(stream_id);
+
+DROP TABLE IF EXISTS blocked_rooms;
+CREATE TABLE IF NOT EXISTS blocked_rooms (
+  room_id TEXT NOT NULL PRIMARY KEY,
+  -- The fully qualified ID of the admin who blocked the room
+  blocked_by TEXT NOT NULL,
+  -- When the room was blocked, as a unix timestamp (ms resolution).
+  ts BIGINT NOT NULL
+);
+
+DROP TABLE IF EXISTS admin_jobs;
+CREATE TABLE IF NOT EXISTS admin_jobs (
+  job_id BIGSERIAL PRIMARY KEY,
+  room_id TEXT NOT NULL,
+  -- What the job does, e.g. {"kind": "shutdown", "message": null}
+  job JSONB NOT NULL,
+  -- pending, running, complete or failed
+  status TEXT NOT NULL,
+  -- What the job has done so far
+  progress JSONB NOT NULL,
+  -- Why the job failed, if it did
+  error TEXT,
+  -- The fully qualified ID of the admin who started the job
+  created_by TEXT NOT NULL,
+  -- When the job was started, as a unix timestamp (ms resolution).
+  created_ts BIGINT NOT NULL,
+  -- When the job last made progress, as a unix timestamp (ms resolution).
+  updated_ts BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_admin_jobs_status ON admin_jobs(status);
+
+DROP TABLE IF EXISTS admin_job_members;
+CREATE TABLE IF NOT EXISTS admin_job_members (
+  -- The export job the members were snapshotted by
+  job_id BIGINT NOT NULL,
+  user_id TEXT NOT NULL,
+  membership TEXT NOT NULL,
+  PRIMARY KEY (job_id, user_id)
+);
+
+DROP TABLE IF EXISTS token_revocations;
+CREATE TABLE IF NOT EXISTS token_revocations (
+  localpart TEXT PRIMARY KEY,
+  -- Access tokens issued up to this unix timestamp (ms resolution) are refused
+  revoked_ts BIGINT NOT NULL,
+  -- The device whose tokens were kept, if any
+  kept_device_id TEXT,
+  -- The kept device's tokens issued up to this unix timestamp (ms
+  -- resolution) are still refused
+  kept_since_ts BIGINT NOT NULL
+);
+
+DROP TABLE IF EXISTS uia_sessions;
+CREATE TABLE IF NOT EXISTS uia_sessions (
+  -- The session ID given to the client in the 401 challenge
+  session_id TEXT PRIMARY KEY,
+  -- The user and device that started the session
+  localpart TEXT NOT NULL,
+  device_id TEXT NOT NULL,
+  -- What the session authorizes, e.g. account.deactivate. It is deleted
+  -- once it has authorized a request.
+  operation TEXT NOT NULL,
+  -- When the session was started, as a unix timestamp (ms resolution)
+  created_ts BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_uia_sessions_created_ts ON uia_sessions(created_ts);
+
+DROP TABLE IF EXISTS room_third_party_invites;
+CREATE TABLE IF NOT EXISTS room_third_party_invites (
+  room_id TEXT NOT NULL,
+  -- The identity server's token for the invite
+  token TEXT NOT NULL,
+  -- The user who sent the invite
+  sender TEXT NOT NULL,
+  -- The invitee's display name, as given by the identity server
+  display_name TEXT NOT NULL,
+  -- The keys the bound invite may be signed with, as a JSON array of
+  -- {public_key, key_validity_url}
+  public_keys JSONB NOT NULL,
+  -- When the invite was sent, as a unix timestamp (ms resolution).
+  created_ts BIGINT NOT NULL,
+  PRIMARY KEY (room_id, token)
+);
+
+DROP TABLE IF EXISTS event_reports;
+CREATE TABLE IF NOT EXISTS event_reports (
+  id BIGSERIAL PRIMARY KEY,
+  room_id TEXT NOT NULL,
+  event_id TEXT NOT NULL,
+  -- The local user who reported the event, if they were local
+  reporter TEXT,
+  -- The server that forwarded the report, if it was forwarded
+  origin TEXT,
+  reason TEXT,
+  -- From -100, the most offensive, to 0
+  score BIGINT,
+  -- When the report was received, as a unix timestamp (ms resolution)
+  received_ts BIGINT NOT NULL
+);
+
+DROP TABLE IF EXISTS ip_blocks;
+CREATE TABLE IF NOT EXISTS ip_blocks (
+  id BIGSERIAL PRIMARY KEY,
+  -- The blocked network in CIDR notation
+  net TEXT NOT NULL,
+  -- client or federation
+  api TEXT NOT NULL,
+  reason TEXT,
+  -- When the block was added, as a unix timestamp (ms resolution)
+  created_ts BIGINT NOT NULL,
+  -- When the block is lifted, as a unix timestamp (ms resolution), if it
+  -- ever is
+  expires_ts BIGINT
+);
+
+DROP TABLE IF EXISTS room_memberships;
+CREATE TABLE IF NOT EXISTS room_memberships (
+  room_id TEXT NOT NULL,
+  user_id TEXT NOT NULL,
+  -- join, invite, leave, ban or knock
+  membership TEXT NOT NULL,
+  -- Orders members by when they became members, bumped when a user who
+  -- wasn't joined or invited is again
+  stream_id BIGSERIAL,
+  PRIMARY KEY (room_id, user_id)
+);
+CREATE INDEX IF NOT EXISTS idx_room_memberships_members ON room_memberships(room_id, stream_id)
+  WHERE membership IN ('join', 'invite');
+
+DROP TABLE IF EXISTS room_directory;
+CREATE TABLE IF NOT EXISTS room_directory (
+  -- A room published to the server's public room directory
+  room_id TEXT NOT NULL PRIMARY KEY,
+  -- When it was published, as a unix timestamp (ms resolution)
+  published_ts BIGINT NOT NULL
+);
+
+DROP TABLE IF EXISTS room_summaries;
+CREATE TABLE IF NOT EXISTS room_summaries (
+  room_id TEXT NOT NULL PRIMARY KEY,
+  -- The earliest joined or invited members, as a JSON array of user IDs
+  heroes JSONB NOT NULL,
+  joined_member_count BIGINT NOT NULL,
+  invited_member_count BIGINT NOT NULL
+);
+
+DROP TABLE IF EXISTS scheduled_jobs;
+CREATE TABLE IF NOT EXISTS scheduled_jobs (
+  job_id BIGSERIAL PRIMARY KEY,
+  -- The task that runs the job, e.g. media_retention
+  kind TEXT NOT NULL,
+  -- What the task is given to run the job with
+  payload JSONB NOT NULL,
+  -- Milliseconds between runs of a periodic job, or NULL for a job run once
+  interval_ms BIGINT,
+  -- pending, running, complete or failed
+  status TEXT NOT NULL,
+  -- When the job is next due, as a unix timestamp (ms resolution). A
+  -- running job is due again if it hasn't finished by then, in case the
+  -- process running it stopped. For a finished job run once, when it
+  -- finished.
+  next_run_ts BIGINT NOT NULL,
+  -- When the job last started, as a unix timestamp (ms resolution).
+  last_run_ts BIGINT,
+  -- How many times in a row the job has failed
+  failures INT NOT NULL,
+  -- Why the job last failed, if it did
+  last_error TEXT,
+  -- When the job was scheduled, as a unix timestamp (ms resolution).
+  created_ts BIGINT NOT NULL
+);
+-- There is one periodic job of each kind
+CREATE UNIQUE INDEX IF NOT EXISTS idx_scheduled_jobs_periodic ON scheduled_jobs(kind)
+  WHERE interval_ms IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_scheduled_jobs_due ON scheduled_jobs(status, next_run_ts);
+
+DROP TABLE IF EXISTS appservice_outbound;
+CREATE TABLE IF NOT EXISTS appservice_outbound (
+  -- Events are sent in the order they were queued
+  stream_id BIGSERIAL PRIMARY KEY,
+  -- The application service the event is for, as in its registration
+  appservice_id TEXT NOT NULL,
+  event JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_appservice_outbound_appservice_id
+  ON appservice_outbound(appservice_id, stream_id);
+
+DROP TABLE IF EXISTS appservice_state;
+CREATE TABLE IF NOT EXISTS appservice_state (
+  appservice_id TEXT PRIMARY KEY,
+  -- The ID of the last transaction sent, or being sent
+  txn_id BIGINT NOT NULL,
+  -- The last queued event the application service accepted
+  last_stream_id BIGINT NOT NULL,
+  -- The last queued event in the transaction being sent, if one is
+  pending_stream_id BIGINT,
+  -- When sending last failed, as a unix timestamp (ms resolution).
+  retry_last_ts BIGINT NOT NULL,
+  -- How long to wait after the last failure before retrying, in ms, or 0
+  retry_interval BIGINT NOT NULL
+);
\ No newline at end of file
@@ -0,0 +1,359 @@
+//! Application services: bridges and bots given a namespace of users, room
+//! aliases and rooms on this server by a registration file.
+//!
+//! An application service acts as its sender, or as any user in its user
+//! namespace, by authenticating with its `as_token`. The events it is
+//! interested in are queued for it, and sent to its `url` in transactions by
+//! `sender`.
+//!
+//! TODO: Query application services for unknown users and room aliases in
+//! their namespaces, once profiles and aliases are looked up. Let them log
+//! in with `m.login.application_service`. Keep other users from creating
+//! aliases in exclusive alias namespaces once aliases can be created.
+use std::{error::Error, fmt};
+
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::db::Store;
+
+pub mod sender;
+
+/// Why a registration file can't be used.
+#[derive(Debug, PartialEq)]
+pub struct InvalidRegistration(String);
+
+impl fmt::Display for InvalidRegistration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid application service registration: {}", self.0)
+    }
+}
+
+impl Error for InvalidRegistration {}
+
+/// A registration file, as it is written.
+#[derive(Deserialize)]
+struct Registration {
+    id: String,
+    url: Option<String>,
+    as_token: String,
+    hs_token: String,
+    sender_localpart: String,
+    #[serde(default)]
+    namespaces: Namespaces,
+    rate_limited: Option<bool>,
+}
+
+#[derive(Default, Deserialize)]
+struct Namespaces {
+    #[serde(default)]
+    users: Vec<NamespaceEntry>,
+    #[serde(default)]
+    aliases: Vec<NamespaceEntry>,
+    #[serde(default)]
+    rooms: Vec<NamespaceEntry>,
+}
+
+#[derive(Deserialize)]
+struct NamespaceEntry {
+    #[serde(default)]
+    exclusive: bool,
+    regex: String,
+}
+
+/// Some of the user IDs, room aliases or room IDs an application service
+/// is interested in.
+#[derive(Clone, Debug)]
+pub struct Namespace {
+    /// Whether only the application service may create what matches
+    pub exclusive: bool,
+    /// Matches a whole ID
+    regex: Regex,
+}
+
+impl Namespace {
+    fn new(entry: &NamespaceEntry) -> Result<Self, InvalidRegistration> {
+        // Matched against whole IDs, as they are in other implementations
+        let regex = Regex::new(&format!("^(?:{})$", entry.regex))
+            .map_err(|e| InvalidRegistration(format!("bad namespace regex: {}", e)))?;
+        Ok(Namespace {
+            exclusive: entry.exclusive,
+            regex,
+        })
+    }
+
+    pub fn is_match(&self, id: &str) -> bool {
+        self.regex.is_match(id)
+    }
+}
+
+/// A registered application service.
+#[derive(Clone, Debug)]
+pub struct AppService {
+    pub id: String,
+    /// Where transactions of events are sent, if the service wants any
+    pub url: Option<String>,
+    /// The token the service authenticates to us with
+    pub as_token: String,
+    /// The token we authenticate to the service with
+    pub hs_token: String,
+    /// The fully qualified ID of the user the service acts as by default
+    pub sender: String,
+    pub users: Vec<Namespace>,
+    pub rooms: Vec<Namespace>,
+    /// Whether the service's users are rate limited
+    pub rate_limited: bool,
+}
+
+impl AppService {
+    /// Reads a registration file, with `hostname` as the server its sender
+    /// is on.
+    pub fn parse(yaml: &str, hostname: &str) -> Result<Self, InvalidRegistration> {
+        let registration: Registration =
+            serde_yaml::from_str(yaml).map_err(|e| InvalidRegistration(e.to_string()))?;
+        if registration.as_token == registration.hs_token {
+            return Err(InvalidRegistration(format!(
+                "{} uses the same token both ways",
+                registration.id
+            )));
+        }
+        let compile = |entries: &[NamespaceEntry]| {
+            entries
+                .iter()
+                .map(Namespace::new)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        // Nothing creates room aliases yet for the alias namespace to
+        // reserve, but a registration with a broken one is still refused
+        compile(&registration.namespaces.aliases)?;
+        Ok(AppService {
+            sender: format!("@{}:{}", registration.sender_localpart, hostname),
+            users: compile(&registration.namespaces.users)?,
+            rooms: compile(&registration.namespaces.rooms)?,
+            rate_limited: registration.rate_limited.unwrap_or(true),
+            id: registration.id,
+            url: registration.url.filter(|url| !url.is_empty()),
+            as_token: registration.as_token,
+            hs_token: registration.hs_token,
+        })
+    }
+
+    /// Whether the service may act as a user: its sender, or a user in its
+    /// namespace.
+    pub fn is_user_in_namespace(&self, user_id: &str) -> bool {
+        user_id == self.sender || self.users.iter().any(|ns| ns.is_match(user_id))
+    }
+
+    pub fn is_room_in_namespace(&self, room_id: &str) -> bool {
+        self.rooms.iter().any(|ns| ns.is_match(room_id))
+    }
+
+    /// Whether the service is sent an event: one sent by, or about the
+    /// membership of, a user it may act as, or one in a room in its
+    /// namespace.
+    ///
+    /// TODO: Also send events in rooms a user in the namespace is in, and in
+    /// rooms with an alias in the namespace, once rooms exist.
+    pub fn is_interested_in_event(&self, event: &Value) -> bool {
+        let field = |name: &str| event.get(name).and_then(Value::as_str);
+        let is_member_event = field("type") == Some("m.room.member");
+        field("sender").map_or(false, |sender| self.is_user_in_namespace(sender))
+            || (is_member_event
+                && field("state_key").map_or(false, |user_id| self.is_user_in_namespace(user_id)))
+            || field("room_id").map_or(false, |room_id| self.is_room_in_namespace(room_id))
+    }
+}
+
+/// The registered application services.
+#[derive(Clone, Debug, Default)]
+pub struct AppServices(Vec<AppService>);
+
+impl AppServices {
+    /// Reads the registration files at `paths`, with `hostname` as the
+    /// server the services' senders are on.
+    pub fn load(paths: &[String], hostname: &str) -> Result<Self, Box<dyn Error>> {
+        let mut services = Vec::new();
+        for path in paths {
+            let yaml = std::fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read {}: {}", path, e))?;
+            services.push(AppService::parse(&yaml, hostname)?);
+        }
+        Ok(AppServices::new(services)?)
+    }
+
+    /// Checks that services don't share IDs or tokens.
+    pub fn new(services: Vec<AppService>) -> Result<Self, InvalidRegistration> {
+        for (i, service) in services.iter().enumerate() {
+            for other in &services[..i] {
+                if service.id == other.id {
+                    return Err(InvalidRegistration(format!(
+                        "{} is registered twice",
+                        service.id
+                    )));
+                }
+                if service.as_token == other.as_token {
+                    return Err(InvalidRegistration(format!(
+                        "{} and {} use the same token",
+                        other.id, service.id
+                    )));
+                }
+            }
+        }
+        Ok(AppServices(services))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &AppService> {
+        self.0.iter()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&AppService> {
+        self.0.iter().find(|service| service.id == id)
+    }
+
+    /// The service an access token belongs to, if any.
+    pub fn by_token(&self, access_token: &str) -> Option<&AppService> {
+        self.0.iter().find(|service| {
+            ring::constant_time::verify_slices_are_equal(
+                service.as_token.as_bytes(),
+                access_token.as_bytes(),
+            )
+            .is_ok()
+        })
+    }
+
+    /// The service that has claimed a user ID for itself, if any.
+    pub fn exclusive_user(&self, user_id: &str) -> Option<&AppService> {
+        self.0.iter().find(|service| {
+            service
+                .users
+                .iter()
+                .any(|ns| ns.exclusive && ns.is_match(user_id))
+        })
+    }
+}
+
+/// Queues an event for every application service interested in it, and
+/// wakes the sender.
+pub async fn queue_event<T: Store>(
+    storage: &T,
+    services: &AppServices,
+    notifier: &sender::Notifier,
+    event: &Value,
+) -> Result<(), Box<dyn Error>> {
+    for service in services.iter() {
+        if service.url.is_some() && service.is_interested_in_event(event) {
+            storage.add_appservice_event(&service.id, event).await?;
+            notifier.notify(&service.id);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const REGISTRATION: &str = r##"
+id: irc
+url: "http://127.0.0.1:9999"
+as_token: as_secret
+hs_token: hs_secret
+sender_localpart: irc_bot
+rate_limited: false
+protocols: ["irc"]
+namespaces:
+  users:
+    - exclusive: true
+      regex: "@irc_.*:example\\.com"
+  aliases:
+    - exclusive: false
+      regex: "#irc_.*:example\\.com"
+  rooms: []
+"##;
+
+    fn service() -> AppService {
+        AppService::parse(REGISTRATION, "example.com").unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let service = service();
+        assert_eq!(service.id, "irc");
+        assert_eq!(service.url.as_deref(), Some("http://127.0.0.1:9999"));
+        assert_eq!(service.sender, "@irc_bot:example.com");
+        assert!(!service.rate_limited);
+        assert!(service.users[0].exclusive);
+        assert!(service.rooms.is_empty());
+
+        // Everything but the tokens, the ID and the sender is optional
+        let minimal = "id: bot\nurl: null\nas_token: a\nhs_token: b\nsender_localpart: bot";
+        let service = AppService::parse(minimal, "example.com").unwrap();
+        assert_eq!(service.url, None);
+        assert!(service.rate_limited);
+
+        let same_tokens = "id: bot\nas_token: a\nhs_token: a\nsender_localpart: bot";
+        assert!(AppService::parse(same_tokens, "example.com").is_err());
+        let bad_regex = format!("{}\nnamespaces:\n  users: [{{regex: \"(\"}}]", minimal);
+        assert!(AppService::parse(&bad_regex, "example.com").is_err());
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let service = service();
+        assert!(service.is_user_in_namespace("@irc_alice:example.com"));
+        assert!(service.is_user_in_namespace("@irc_bot:example.com"));
+        assert!(!service.is_user_in_namespace("@alice:example.com"));
+        // Regexes match whole IDs
+        assert!(!service.is_user_in_namespace("@irc_alice:example.com.evil"));
+        assert!(!service.is_user_in_namespace("@x@irc_alice:example.com"));
+        assert!(!service.is_room_in_namespace("!room:example.com"));
+    }
+
+    #[test]
+    fn test_is_interested_in_event() {
+        let service = service();
+        assert!(service.is_interested_in_event(&json!({
+            "type": "m.room.message",
+            "sender": "@irc_alice:example.com",
+            "room_id": "!room:example.com",
+        })));
+        assert!(service.is_interested_in_event(&json!({
+            "type": "m.room.member",
+            "sender": "@bob:remote.com",
+            "state_key": "@irc_alice:example.com",
+            "room_id": "!room:example.com",
+        })));
+        // Only membership events are about their state key
+        assert!(!service.is_interested_in_event(&json!({
+            "type": "m.room.topic",
+            "sender": "@bob:remote.com",
+            "state_key": "@irc_alice:example.com",
+            "room_id": "!room:example.com",
+        })));
+    }
+
+    #[test]
+    fn test_app_services() {
+        let other = AppService::parse(
+            "id: bot\nas_token: bot_as\nhs_token: bot_hs\nsender_localpart: bot",
+            "example.com",
+        )
+        .unwrap();
+        let services = AppServices::new(vec![service(), other.clone()]).unwrap();
+        assert_eq!(services.by_token("as_secret").unwrap().id, "irc");
+        assert_eq!(services.by_token("bot_as").unwrap().id, "bot");
+        assert!(services.by_token("hs_secret").is_none());
+        assert_eq!(
+            services
+                .exclusive_user("@irc_alice:example.com")
+                .unwrap()
+                .id,
+            "irc"
+        );
+        assert!(services.exclusive_user("@bot:example.com").is_none());
+
+        assert!(AppServices::new(vec![other.clone(), other]).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+//! Sends application services the events queued for them, in transactions.
+//!
+//! Events are first queued in the `Store` for each service interested in
+//! them, so nothing is lost if the server restarts before they are sent.
+//! Each service with something queued has a sending task, which sends
+//! transactions until the service has caught up. A transaction is sent
+//! again, with the same ID and events, until the service accepts it, so the
+//! service can tell when it has already seen one. Services that fail are
+//! retried with exponential backoff.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::Rc;
+use std::time::Duration;
+
+use actix_web::client::Client;
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+use serde_json::{json, Value};
+
+use super::AppService;
+use crate::{clock::now_ms, db::Store, CONFIG};
+
+/// The most events sent in one transaction.
+const BATCH_SIZE: i64 = 100;
+/// How long to wait for an application service to respond.
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to wait before retrying a service the first time it fails.
+const MIN_RETRY_INTERVAL: i64 = 5 * 1000;
+/// The longest to wait between retries of a service.
+const MAX_RETRY_INTERVAL: i64 = 5 * 60 * 1000;
+
+/// Wakes the sender when an event is queued for an application service.
+/// Can be cloned and used from any thread.
+#[derive(Clone, Debug)]
+pub struct Notifier(UnboundedSender<String>);
+
+impl Notifier {
+    pub fn notify(&self, appservice_id: &str) {
+        // The sender only stops when the server does
+        let _ = self.0.unbounded_send(appservice_id.to_owned());
+    }
+}
+
+/// The services with a running sending task, and whether more was queued
+/// for each since its task last looked.
+type Active = Rc<RefCell<HashMap<String, bool>>>;
+
+/// Starts the sender on the current thread, first catching up on whatever
+/// was left unsent when the server last stopped.
+pub fn start<T: Store + 'static>(storage: T) -> Notifier {
+    let (sender, receiver) = mpsc::unbounded();
+    actix_rt::spawn(run(storage, receiver));
+    Notifier(sender)
+}
+
+async fn run<T: Store + 'static>(storage: T, mut wakeups: UnboundedReceiver<String>) {
+    let active = Active::default();
+    match storage.get_appservices_with_events().await {
+        Ok(services) => {
+            for appservice_id in services {
+                wake(&storage, &active, appservice_id);
+            }
+        }
+        Err(e) => tracing::error!(error = %e, "Unable to load the application service queue"),
+    }
+    while let Some(appservice_id) = wakeups.next().await {
+        wake(&storage, &active, appservice_id);
+    }
+}
+
+/// Makes sure a sending task is running for a service.
+fn wake<T: Store + 'static>(storage: &T, active: &Active, appservice_id: String) {
+    let mut tasks = active.borrow_mut();
+    if let Some(queued) = tasks.get_mut(&appservice_id) {
+        *queued = true;
+        return;
+    }
+    tasks.insert(appservice_id.clone(), false);
+    actix_rt::spawn(deliver(storage.clone(), active.clone(), appservice_id));
+}
+
+/// Sends everything queued for a service, waiting out its backoff if it
+/// fails.
+async fn deliver<T: Store>(storage: T, active: Active, appservice_id: String) {
+    loop {
+        let wait = match CONFIG.appservices.get(&appservice_id) {
+            Some(service) => match send_all(&storage, service).await {
+                Ok(wait) => wait,
+                Err(e) => {
+                    tracing::error!(%appservice_id, error = %e, "Unable to update the application service queue");
+                    Some(MIN_RETRY_INTERVAL)
+                }
+            },
+            // Left over from a service that is no longer registered
+            None => None,
+        };
+        if let Some(wait) = wait {
+            actix_rt::time::delay_for(Duration::from_millis(wait as u64)).await;
+            continue;
+        }
+
+        // Anything queued while we were sending is picked up by going round
+        // again
+        let mut tasks = active.borrow_mut();
+        if tasks.get(&appservice_id) == Some(&true) {
+            tasks.insert(appservice_id.clone(), false);
+            continue;
+        }
+        tasks.remove(&appservice_id);
+        return;
+    }
+}
+
+/// Sends a service transactions of what is queued for it until it has
+/// caught up. Returns how long to wait before trying again if it failed.
+async fn send_all<T: Store>(
+    storage: &T,
+    service: &AppService,
+) -> Result<Option<i64>, Box<dyn Error>> {
+    let mut state = storage.get_appservice_state(&service.id).await?;
+    let retry_wait = state.retry_last_ts + state.retry_interval - now_ms();
+    if state.retry_interval > 0 && retry_wait > 0 {
+        return Ok(Some(retry_wait));
+    }
+
+    loop {
+        let events = storage
+            .get_appservice_events(
+                &service.id,
+                state.last_stream_id,
+                state.pending_stream_id.unwrap_or(i64::MAX),
+                BATCH_SIZE,
+            )
+            .await?;
+        let up_to = match (state.pending_stream_id, events.last()) {
+            (Some(up_to), _) => up_to,
+            (None, Some((stream_id, _))) => {
+                // The transaction's events are fixed before it is first
+                // sent, so a retry has the same ones
+                state.txn_id += 1;
+                state.pending_stream_id = Some(*stream_id);
+                storage.set_appservice_state(&service.id, &state).await?;
+                *stream_id
+            }
+            (None, None) => return Ok(None),
+        };
+
+        let events: Vec<Value> = events.into_iter().map(|(_, event)| event).collect();
+        if let Err(e) = send_transaction(service, state.txn_id, &events).await {
+            tracing::warn!(appservice_id = %service.id, txn_id = state.txn_id, error = %e, "Unable to send transaction");
+            state.retry_last_ts = now_ms();
+            state.retry_interval = next_retry_interval(state.retry_interval);
+            storage.set_appservice_state(&service.id, &state).await?;
+            return Ok(Some(state.retry_interval));
+        }
+
+        state.last_stream_id = up_to;
+        state.pending_stream_id = None;
+        state.retry_interval = 0;
+        storage.set_appservice_state(&service.id, &state).await?;
+        storage.delete_appservice_events(&service.id, up_to).await?;
+    }
+}
+
+/// How long to wait before retrying a service that failed again.
+fn next_retry_interval(previous: i64) -> i64 {
+    if previous <= 0 {
+        MIN_RETRY_INTERVAL
+    } else {
+        (previous * 2).min(MAX_RETRY_INTERVAL)
+    }
+}
+
+/// Where a service is sent a transaction.
+fn transaction_url(url: &str, txn_id: i64) -> String {
+    format!(
+        "{}/_matrix/app/v1/transactions/{}",
+        url.trim_end_matches('/'),
+        txn_id
+    )
+}
+
+/// Sends a transaction of events to a service.
+async fn send_transaction(
+    service: &AppService,
+    txn_id: i64,
+    events: &[Value],
+) -> Result<(), String> {
+    let url = service
+        .url
+        .as_deref()
+        .ok_or("Application service has no URL")?;
+    let res = Client::build()
+        .timeout(TIMEOUT)
+        .finish()
+        .put(transaction_url(url, txn_id))
+        .bearer_auth(&service.hs_token)
+        .send_json(&json!({ "events": events }))
+        .await
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("Application service responded {}", res.status()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transaction_url() {
+        assert_eq!(
+            transaction_url("http://localhost:9000/", 5),
+            "http://localhost:9000/_matrix/app/v1/transactions/5"
+        );
+        assert_eq!(
+            transaction_url("https://bridge.example.com/irc", 6),
+            "https://bridge.example.com/irc/_matrix/app/v1/transactions/6"
+        );
+    }
+
+    #[test]
+    fn test_next_retry_interval() {
+        assert_eq!(next_retry_interval(0), MIN_RETRY_INTERVAL);
+        assert_eq!(next_retry_interval(MIN_RETRY_INTERVAL), 10 * 1000);
+        assert_eq!(next_retry_interval(MAX_RETRY_INTERVAL), MAX_RETRY_INTERVAL);
+    }
+}
@@ -0,0 +1,187 @@
+//! The audit log of security relevant actions, e.g. failed password checks,
+//! admin operations and deactivations.
+//!
+//! Entries are appended to the `Store`, where they can't be changed or
+//! deleted, and can be listed through the admin API. They can also be
+//! forwarded to syslog as they are recorded, to keep them somewhere else too,
+//! and the most security relevant ones sent to webhooks, see `webhooks`.
+//!
+//! TODO: Record redactions once rooms exist.
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use crate::{db::Store, webhooks, CONFIG};
+
+/// The syslog priority entries are forwarded with: the `authpriv` facility
+/// at `notice` severity.
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 5;
+
+/// Where audit log entries are forwarded to as they are recorded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Forwarder {
+    /// A syslog daemon listening on a UDP address
+    Udp(String),
+    /// A syslog daemon listening on a local datagram socket, e.g. `/dev/log`
+    Unix(PathBuf),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidForwarder(String);
+
+impl fmt::Display for InvalidForwarder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid audit log forwarder `{}`, expected `udp://host:port` or `unix:<path>`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidForwarder {}
+
+impl FromStr for Forwarder {
+    type Err = InvalidForwarder;
+
+    /// Parses `udp://host:port` or `unix:<path>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(addr) = s.strip_prefix("udp://") {
+            if !addr.is_empty() {
+                return Ok(Forwarder::Udp(addr.to_owned()));
+            }
+        } else if let Some(path) = s.strip_prefix("unix:") {
+            if !path.is_empty() {
+                return Ok(Forwarder::Unix(path.into()));
+            }
+        }
+        Err(InvalidForwarder(s.to_owned()))
+    }
+}
+
+impl Forwarder {
+    /// Sends a syslog message. Datagrams are sent without waiting for the
+    /// daemon, so this doesn't block.
+    fn send(&self, message: &str) -> io::Result<()> {
+        match self {
+            Forwarder::Udp(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.send_to(message.as_bytes(), addr.as_str())?;
+            }
+            Forwarder::Unix(path) => {
+                UnixDatagram::unbound()?.send_to(message.as_bytes(), path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends an entry to the audit log, forwarding it to syslog and webhooks if
+/// configured to. Forwarding failures are logged, but don't fail the action.
+pub async fn record<T: Store>(
+    storage: &T,
+    user_id: Option<&str>,
+    ip: Option<IpAddr>,
+    action: &str,
+    details: &Value,
+) -> Result<(), Box<dyn Error>> {
+    let ip = ip.map(|ip| ip.to_string());
+    storage
+        .add_audit_log_entry(user_id, ip.as_deref(), action, details)
+        .await?;
+
+    if let Some(forwarder) = &CONFIG.audit_forwarder {
+        let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let message = syslog_message(
+            &timestamp,
+            &CONFIG.hostname,
+            user_id,
+            ip.as_deref(),
+            action,
+            details,
+        );
+        if let Err(e) = forwarder.send(&message) {
+            tracing::warn!(error = %e, %action, "Unable to forward audit log entry");
+        }
+    }
+    if let Err(e) = webhooks::notify(storage, user_id, ip.as_deref(), action, details).await {
+        tracing::warn!(error = %e, %action, "Unable to queue webhook notifications");
+    }
+    Ok(())
+}
+
+/// Formats an entry as an RFC 5424 syslog message, with the entry as JSON.
+fn syslog_message(
+    timestamp: &str,
+    hostname: &str,
+    user_id: Option<&str>,
+    ip: Option<&str>,
+    action: &str,
+    details: &Value,
+) -> String {
+    let entry = serde_json::json!({
+        "user_id": user_id,
+        "ip": ip,
+        "action": action,
+        "details": details,
+    });
+    format!(
+        "<{}>1 {} {} maelstrom {} audit - {}",
+        SYSLOG_PRIORITY,
+        timestamp,
+        hostname,
+        std::process::id(),
+        entry
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_forwarder() {
+        assert_eq!(
+            "udp://localhost:514".parse(),
+            Ok(Forwarder::Udp("localhost:514".to_owned()))
+        );
+        assert_eq!(
+            "unix:/dev/log".parse(),
+            Ok(Forwarder::Unix("/dev/log".into()))
+        );
+        assert!("udp://".parse::<Forwarder>().is_err());
+        assert!("/dev/log".parse::<Forwarder>().is_err());
+    }
+
+    #[test]
+    fn test_syslog_message() {
+        let message = syslog_message(
+            "2020-01-01T00:00:00.000Z",
+            "example.com",
+            Some("@admin:example.com"),
+            Some("203.0.113.7"),
+            "admin.user.deactivate",
+            &json!({"erase": true}),
+        );
+        let prefix = "<85>1 2020-01-01T00:00:00.000Z example.com maelstrom ";
+        assert!(message.starts_with(prefix));
+        let entry = message.splitn(2, " audit - ").nth(1).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(entry).unwrap(),
+            json!({
+                "user_id": "@admin:example.com",
+                "ip": "203.0.113.7",
+                "action": "admin.user.deactivate",
+                "details": {"erase": true},
+            })
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! The replication bus, which broadcasts what changed to every process
+//! sharing the database.
+//!
+//! Messages are always delivered to the subscribers in this process. With
+//! `REDIS_ADDR` set they are also published to a Redis channel, and the
+//! messages other processes publish there are delivered here, so e.g. a
+//! sync worker wakes up when the main process queues something for a user.
+use std::sync::{mpsc, Arc, Mutex};
+
+use futures::channel::mpsc::{self as async_mpsc, UnboundedReceiver, UnboundedSender};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::CONFIG;
+
+mod redis;
+
+lazy_static::lazy_static! {
+    /// The bus of this process. Connects to Redis the first time it is used.
+    pub static ref BUS: Bus = Bus::start(
+        CONFIG.redis_addr.as_deref(),
+        &format!("maelstrom:{}:bus", CONFIG.hostname),
+    );
+}
+
+/// Something that changed.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// These local users have something new to sync
+    NewData { localparts: Vec<String> },
+    /// An entry of an in-memory cache is stale, and should be dropped
+    Invalidate { cache: String, key: String },
+}
+
+/// A message, and the process it came from.
+#[derive(Debug, Deserialize, Serialize)]
+struct Envelope {
+    origin: String,
+    message: Message,
+}
+
+type Subscribers = Arc<Mutex<Vec<UnboundedSender<Message>>>>;
+
+/// Delivers messages to subscribers. Can be cloned and used from any thread.
+#[derive(Clone, Debug)]
+pub struct Bus {
+    subscribers: Subscribers,
+    /// Tells this process's messages apart from other processes'
+    origin: String,
+    /// Hands messages to the Redis publisher, if there is one
+    publisher: Option<Arc<Mutex<mpsc::Sender<Vec<u8>>>>>,
+}
+
+impl Bus {
+    /// Starts a bus, shared with other processes through `channel` on the
+    /// Redis server at `redis_addr`, if given.
+    fn start(redis_addr: Option<&str>, channel: &str) -> Self {
+        let origin: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .collect();
+        let subscribers = Subscribers::default();
+        let publisher = redis_addr.map(|addr| {
+            let addr = addr.trim_start_matches("redis://").trim_end_matches('/');
+            let (sender, payloads) = mpsc::channel();
+            redis::start_publisher(addr.to_owned(), channel.to_owned(), payloads);
+
+            let own_origin = origin.clone();
+            let local = subscribers.clone();
+            redis::start_subscriber(addr.to_owned(), channel.to_owned(), move |payload| {
+                match serde_json::from_slice::<Envelope>(&payload) {
+                    // Our own messages were delivered when they were sent
+                    Ok(envelope) if envelope.origin == own_origin => {}
+                    Ok(envelope) => deliver(&local, envelope.message),
+                    Err(e) => tracing::warn!(error = %e, "Invalid message on the bus"),
+                }
+            });
+            Arc::new(Mutex::new(sender))
+        });
+        Bus {
+            subscribers,
+            origin,
+            publisher,
+        }
+    }
+
+    /// Sends a message to every subscriber, in every process.
+    pub fn publish(&self, message: Message) {
+        if let Some(publisher) = &self.publisher {
+            let envelope = Envelope {
+                origin: self.origin.clone(),
+                message: message.clone(),
+            };
+            match serde_json::to_vec(&envelope) {
+                // The publisher only stops with the process
+                Ok(payload) => {
+                    let _ = publisher.lock().unwrap().send(payload);
+                }
+                Err(e) => tracing::error!(error = %e, "Unable to encode a bus message"),
+            }
+        }
+        deliver(&self.subscribers, message);
+    }
+
+    /// Tells every process that these local users have something new to
+    /// sync.
+    pub fn new_data<'a>(&self, localparts: impl IntoIterator<Item = &'a str>) {
+        let mut localparts: Vec<String> = localparts.into_iter().map(str::to_owned).collect();
+        localparts.sort();
+        localparts.dedup();
+        if !localparts.is_empty() {
+            self.publish(Message::NewData { localparts });
+        }
+    }
+
+    /// Receives every message sent from now on, until the receiver is
+    /// dropped.
+    pub fn subscribe(&self) -> UnboundedReceiver<Message> {
+        let (sender, receiver) = async_mpsc::unbounded();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| !subscriber.is_closed());
+        subscribers.push(sender);
+        receiver
+    }
+}
+
+fn deliver(subscribers: &Subscribers, message: Message) {
+    // Subscribers that went away are dropped as we go
+    subscribers
+        .lock()
+        .unwrap()
+        .retain(|subscriber| subscriber.unbounded_send(message.clone()).is_ok());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_local_delivery() {
+        let bus = Bus::start(None, "bus");
+        let mut first = bus.subscribe();
+        let second = bus.subscribe();
+        drop(second);
+
+        bus.new_data(vec!["bob", "alice", "bob"]);
+        let message = futures::executor::block_on(first.next());
+        assert_eq!(
+            message,
+            Some(Message::NewData {
+                localparts: vec!["alice".to_owned(), "bob".to_owned()],
+            })
+        );
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+
+        // Nothing is sent for nobody
+        bus.new_data(vec![]);
+        assert!(first.try_next().is_err());
+    }
+
+    #[test]
+    fn test_message_serde() {
+        let message = Message::Invalidate {
+            cache: "well_known".to_owned(),
+            key: "example.com".to_owned(),
+        };
+        let value = serde_json::to_value(&message).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({"type": "invalidate", "cache": "well_known", "key": "example.com"})
+        );
+        assert_eq!(serde_json::from_value::<Message>(value).unwrap(), message);
+    }
+}
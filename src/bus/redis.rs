@@ -0,0 +1,189 @@
+//! Just enough of the Redis protocol (RESP) to publish to, and subscribe to,
+//! a pub/sub channel. Connections are blocking, so each is kept on a thread
+//! of its own.
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait before connecting again after losing a connection.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long Redis may take to answer a command.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A value in a Redis reply.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+/// Encodes a command as an array of bulk strings.
+pub fn command(args: &[&[u8]]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    command
+}
+
+/// Reads one reply.
+pub fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<Reply> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.trim_end_matches(&['\r', '\n'][..]);
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid Redis reply");
+    if line.is_empty() {
+        return Err(invalid());
+    }
+    let (kind, rest) = line.split_at(1);
+    match kind {
+        "+" => Ok(Reply::Simple(rest.to_owned())),
+        "-" => Ok(Reply::Error(rest.to_owned())),
+        ":" => rest.parse().map(Reply::Integer).map_err(|_| invalid()),
+        "$" => {
+            let len: i64 = rest.parse().map_err(|_| invalid())?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            // The data is followed by a CRLF
+            let mut data = vec![0; len as usize + 2];
+            reader.read_exact(&mut data)?;
+            data.truncate(len as usize);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        "*" => {
+            let len: i64 = rest.parse().map_err(|_| invalid())?;
+            if len < 0 {
+                return Ok(Reply::Array(None));
+            }
+            let items = (0..len)
+                .map(|_| read_reply(reader))
+                .collect::<io::Result<_>>()?;
+            Ok(Reply::Array(Some(items)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// The payload of a pub/sub message pushed to a subscriber, if `reply` is
+/// one.
+fn message_payload(reply: Reply) -> Option<Vec<u8>> {
+    match reply {
+        Reply::Array(Some(items)) => match items.as_slice() {
+            [Reply::Bulk(Some(kind)), _, Reply::Bulk(Some(payload))] if kind == b"message" => {
+                Some(payload.clone())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn connect(addr: &str) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Starts a thread publishing each payload received on `payloads` to
+/// `channel`, connecting again whenever the connection is lost. Payloads
+/// that can't be published are dropped.
+pub fn start_publisher(addr: String, channel: String, payloads: Receiver<Vec<u8>>) {
+    thread::spawn(move || {
+        let mut connection = None;
+        for payload in payloads {
+            if let Err(e) = publish(&mut connection, &addr, &channel, &payload) {
+                tracing::warn!(error = %e, "Unable to publish to Redis");
+                connection = None;
+            }
+        }
+    });
+}
+
+fn publish(
+    connection: &mut Option<BufReader<TcpStream>>,
+    addr: &str,
+    channel: &str,
+    payload: &[u8],
+) -> io::Result<()> {
+    if connection.is_none() {
+        let stream = connect(addr)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        *connection = Some(BufReader::new(stream));
+    }
+    let reader = connection.as_mut().expect("Connected above");
+    reader
+        .get_mut()
+        .write_all(&command(&[b"PUBLISH", channel.as_bytes(), payload]))?;
+    match read_reply(reader)? {
+        Reply::Error(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        _ => Ok(()),
+    }
+}
+
+/// Starts a thread subscribed to `channel`, handing each payload published
+/// to it to `deliver`. Subscribes again whenever the connection is lost.
+pub fn start_subscriber<F>(addr: String, channel: String, deliver: F)
+where
+    F: Fn(Vec<u8>) + Send + 'static,
+{
+    thread::spawn(move || loop {
+        if let Err(e) = subscribe(&addr, &channel, &deliver) {
+            tracing::warn!(error = %e, "Lost the Redis subscription, reconnecting");
+        }
+        thread::sleep(RECONNECT_INTERVAL);
+    });
+}
+
+/// Subscribes to `channel`, handing each payload published to it to
+/// `deliver` until the connection is lost.
+fn subscribe<F: Fn(Vec<u8>)>(addr: &str, channel: &str, deliver: &F) -> io::Result<()> {
+    let mut stream = connect(addr)?;
+    stream.write_all(&command(&[b"SUBSCRIBE", channel.as_bytes()]))?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        if let Some(payload) = message_payload(read_reply(&mut reader)?) {
+            deliver(payload);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        assert_eq!(
+            command(&[b"PUBLISH", b"bus", b"{}"]),
+            b"*3\r\n$7\r\nPUBLISH\r\n$3\r\nbus\r\n$2\r\n{}\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_read_reply() {
+        let mut data: &[u8] =
+            b"*3\r\n$7\r\nmessage\r\n$3\r\nbus\r\n$4\r\na\r\nb\r\n:1\r\n-ERR no\r\n";
+        let reply = read_reply(&mut data).unwrap();
+        assert_eq!(message_payload(reply), Some(b"a\r\nb".to_vec()));
+        assert_eq!(read_reply(&mut data).unwrap(), Reply::Integer(1));
+        assert_eq!(
+            read_reply(&mut data).unwrap(),
+            Reply::Error("ERR no".to_owned())
+        );
+        assert!(read_reply(&mut data).is_err());
+
+        // Subscription confirmations aren't messages
+        let mut data: &[u8] = b"*3\r\n$9\r\nsubscribe\r\n$3\r\nbus\r\n:1\r\n";
+        assert_eq!(message_payload(read_reply(&mut data).unwrap()), None);
+    }
+}
@@ -0,0 +1,147 @@
+//! Size-bounded in-memory caches in front of the `Store`.
+//!
+//! Each cache holds up to a budget of bytes, and forgets the least recently
+//! used entries first once it is over. Entries are only a copy of what is
+//! stored, so they can be dropped at any time; a process that changes
+//! something cached publishes a `bus::Message::Invalidate` naming the cache,
+//! so every process sharing the database drops its copy.
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+use serde_json::Value;
+
+/// A cache of up to `capacity` bytes, evicting the least recently used
+/// entries first.
+#[derive(Debug)]
+pub struct Lru<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    /// The keys, by when they were last used
+    recency: BTreeMap<u64, K>,
+    /// Counts uses, to order them
+    clock: u64,
+    size: usize,
+    capacity: usize,
+    weigh: fn(&K, &V) -> usize,
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: V,
+    used: u64,
+    size: usize,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Lru<K, V> {
+    /// Creates a cache of up to `capacity` bytes, where `weigh` says roughly
+    /// how many bytes an entry takes.
+    pub fn new(capacity: usize, weigh: fn(&K, &V) -> usize) -> Self {
+        Lru {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            size: 0,
+            capacity,
+            weigh,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Gets an entry, marking it as the most recently used.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let now = self.tick();
+        let entry = self.entries.get_mut(key)?;
+        let key = self.recency.remove(&entry.used)?;
+        entry.used = now;
+        self.recency.insert(now, key);
+        Some(entry.value.clone())
+    }
+
+    /// Adds or replaces an entry, evicting others until it fits. Entries
+    /// bigger than the whole cache aren't kept.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.remove(&key);
+        let size = (self.weigh)(&key, &value);
+        if size > self.capacity {
+            return;
+        }
+        while self.size + size > self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(used) => *used,
+                None => break,
+            };
+            if let Some(key) = self.recency.remove(&oldest) {
+                if let Some(entry) = self.entries.remove(&key) {
+                    self.size -= entry.size;
+                }
+            }
+        }
+        let used = self.tick();
+        self.recency.insert(used, key.clone());
+        self.entries.insert(key, Entry { value, used, size });
+        self.size += size;
+    }
+
+    /// Removes an entry, returning it if it was cached.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.used);
+        self.size -= entry.size;
+        Some(entry.value)
+    }
+}
+
+/// Roughly how many bytes a JSON value takes.
+pub fn json_size(value: &Value) -> usize {
+    let own = std::mem::size_of::<Value>();
+    match value {
+        Value::String(s) => own + s.len(),
+        Value::Array(values) => own + values.iter().map(json_size).sum::<usize>(),
+        Value::Object(map) => {
+            own + map
+                .iter()
+                .map(|(k, v)| k.len() + json_size(v))
+                .sum::<usize>()
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => own,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru() {
+        let mut lru: Lru<&str, usize> = Lru::new(10, |_, size| *size);
+        lru.insert("a", 4);
+        lru.insert("b", 4);
+        assert_eq!(lru.get("a"), Some(4));
+        // `b` is the least recently used now
+        lru.insert("c", 4);
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("a"), Some(4));
+        assert_eq!(lru.size, 8);
+
+        // Replacing an entry replaces its size
+        lru.insert("a", 2);
+        assert_eq!(lru.size, 6);
+        // Entries bigger than the cache aren't kept
+        lru.insert("d", 11);
+        assert_eq!(lru.get("d"), None);
+        assert_eq!(lru.remove("c"), Some(4));
+        assert_eq!(lru.size, 2);
+    }
+}
@@ -0,0 +1,10 @@
+//! The current time as a unix timestamp, the form it is stored and sent in.
+//! A clock set before the epoch reads as the epoch.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The current time as a unix timestamp in milliseconds.
+pub fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
@@ -1,8 +1,33 @@
 pub mod postgres;
+pub mod slow_queries;
 
 pub use postgres::PostgresStore;
 
+use crate::models::{
+    access::IpBlock,
+    account::TokenRevocation,
+    admin::{
+        Account, AdminJob, AdminRoom, AuditLogEntry, AuditLogParams, JobStatus,
+        ListScheduledJobsParams, RoomJob, RoomMember, ScheduledJob,
+    },
+    appservice::AppServiceState,
+    auth::UiaSession,
+    directory::PublicRoom,
+    federation::{DestinationRetry, RoomInvite, ServerKey},
+    keys::{KeySignature, OneTimeKey},
+    media::{LocalMedia, RemoteMedia},
+    presence::Presence,
+    profile::Profile,
+    push::{PushCounts, Pusher, PusherState, QueuedNotification, UserPushRules},
+    report::EventReport,
+    room::{RoomSummary, ThirdPartyInvite},
+    room_keys::{BackupVersion, RoomKey},
+    sync::StreamPositions,
+    to_device,
+};
 use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::BTreeMap;
 use std::error::Error;
 
 /// A Storage Driver.
@@ -17,4 +42,953 @@ pub trait Store: Clone + Sync + Send + Sized {
     /// Determines if a username is available for registration.
     /// TODO: Create more generic error responses
     async fn is_username_available(&self, username: &str) -> Result<bool, Box<dyn Error>>;
+
+    /// Gets a piece of account data for a user. `room_id` scopes the data to
+    /// a room; `None` fetches global account data.
+    async fn get_account_data(
+        &self,
+        localpart: &str,
+        room_id: Option<&str>,
+        data_type: &str,
+    ) -> Result<Option<Value>, Box<dyn Error>>;
+
+    /// Creates or replaces a piece of account data for a user, moving it to
+    /// the end of the account data stream.
+    async fn set_account_data(
+        &self,
+        localpart: &str,
+        room_id: Option<&str>,
+        data_type: &str,
+        content: &Value,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets a user's account data that changed after stream position `from`,
+    /// up to and including `to`, as `(room_id, data_type, content)`.
+    async fn get_account_data_changes(
+        &self,
+        localpart: &str,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<(Option<String>, String, Value)>, Box<dyn Error>>;
+
+    /// Gets the fully qualified IDs of the users that `localpart` ignores.
+    async fn get_ignored_users(&self, localpart: &str) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Replaces the set of users that `localpart` ignores, and the
+    /// `m.ignored_user_list` account data listing them, together.
+    async fn set_ignored_users(
+        &self,
+        localpart: &str,
+        ignored: &[String],
+        content: &Value,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Creates or replaces the end-to-end identity keys of a device.
+    async fn set_device_keys(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        keys: &Value,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the identity keys for a user's devices, as `(device_id, keys)`
+    /// pairs. An empty `device_ids` returns the keys of every device.
+    async fn get_device_keys(
+        &self,
+        localpart: &str,
+        device_ids: &[String],
+    ) -> Result<Vec<(String, Value)>, Box<dyn Error>>;
+
+    /// Stores one-time keys for a device. Keys already known by ID are left
+    /// untouched.
+    async fn add_one_time_keys(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        keys: &[OneTimeKey],
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Counts the unclaimed one-time keys of a device, per algorithm.
+    async fn count_one_time_keys(
+        &self,
+        localpart: &str,
+        device_id: &str,
+    ) -> Result<BTreeMap<String, i64>, Box<dyn Error>>;
+
+    /// Atomically claims (removes and returns) a one-time key of the given
+    /// algorithm. If none remain, the device's fallback key for that
+    /// algorithm is returned and marked as used instead.
+    async fn claim_one_time_key(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        algorithm: &str,
+    ) -> Result<Option<OneTimeKey>, Box<dyn Error>>;
+
+    /// Replaces the fallback keys of a device, one per algorithm.
+    async fn set_fallback_keys(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        keys: &[OneTimeKey],
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the algorithms for which a device has a fallback key that has
+    /// not been handed out yet.
+    async fn get_unused_fallback_key_types(
+        &self,
+        localpart: &str,
+        device_id: &str,
+    ) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Gets the IDs of every device belonging to a user.
+    async fn get_device_ids(&self, localpart: &str) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Queues to-device messages for delivery. Returns `false`, queueing
+    /// nothing, if the sending device already used this transaction ID.
+    async fn add_to_device_messages(
+        &self,
+        sender: &str,
+        sender_device_id: &str,
+        txn_id: &str,
+        message_type: &str,
+        messages: &[to_device::Message],
+    ) -> Result<bool, Box<dyn Error>>;
+
+    /// Gets up to `limit` queued to-device events for a device with a stream
+    /// position after `since`, up to and including `to`, as
+    /// `(stream_id, event)` pairs.
+    async fn get_to_device_messages(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        since: i64,
+        to: i64,
+        limit: i64,
+    ) -> Result<Vec<(i64, Value)>, Box<dyn Error>>;
+
+    /// Removes to-device messages the device has acknowledged, i.e. those
+    /// with a stream position up to and including `up_to`.
+    async fn delete_to_device_messages(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        up_to: i64,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the argon2 encoded password hash of an account, if it has one.
+    async fn get_password_hash(&self, localpart: &str) -> Result<Option<String>, Box<dyn Error>>;
+
+    /// Creates or replaces one of a user's cross-signing keys. `key_type`
+    /// is the key's usage, e.g. `master`.
+    async fn set_cross_signing_key(
+        &self,
+        localpart: &str,
+        key_type: &str,
+        key: &Value,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets a user's cross-signing keys, keyed by usage.
+    async fn get_cross_signing_keys(
+        &self,
+        localpart: &str,
+    ) -> Result<BTreeMap<String, Value>, Box<dyn Error>>;
+
+    /// Stores signatures made over device or cross-signing keys.
+    async fn add_key_signatures(&self, signatures: &[KeySignature]) -> Result<(), Box<dyn Error>>;
+
+    /// Gets every stored signature made over the keys of a user.
+    async fn get_key_signatures(
+        &self,
+        target_user_id: &str,
+    ) -> Result<Vec<KeySignature>, Box<dyn Error>>;
+
+    /// Creates a new room key backup version, returning its version number.
+    async fn create_backup_version(
+        &self,
+        localpart: &str,
+        algorithm: &str,
+        auth_data: &Value,
+    ) -> Result<i64, Box<dyn Error>>;
+
+    /// Gets a room key backup version, or the latest one if `version` is
+    /// `None`. Deleted versions are never returned.
+    async fn get_backup_version(
+        &self,
+        localpart: &str,
+        version: Option<i64>,
+    ) -> Result<Option<BackupVersion>, Box<dyn Error>>;
+
+    /// Replaces the `auth_data` of a backup version.
+    async fn update_backup_version(
+        &self,
+        localpart: &str,
+        version: i64,
+        auth_data: &Value,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Deletes a backup version and the keys stored in it. Returns `false`
+    /// if the version did not exist.
+    async fn delete_backup_version(
+        &self,
+        localpart: &str,
+        version: i64,
+    ) -> Result<bool, Box<dyn Error>>;
+
+    /// Gets the keys of a backup version, optionally limited to a room or to
+    /// a single session.
+    async fn get_room_keys(
+        &self,
+        localpart: &str,
+        version: i64,
+        room_id: Option<&str>,
+        session_id: Option<&str>,
+    ) -> Result<Vec<RoomKey>, Box<dyn Error>>;
+
+    /// Creates or replaces keys in a backup version and bumps its etag.
+    async fn put_room_keys(
+        &self,
+        localpart: &str,
+        version: i64,
+        keys: &[RoomKey],
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Deletes keys from a backup version, optionally limited to a room or
+    /// to a single session, and bumps its etag.
+    async fn delete_room_keys(
+        &self,
+        localpart: &str,
+        version: i64,
+        room_id: Option<&str>,
+        session_id: Option<&str>,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Counts the keys stored in a backup version.
+    async fn count_room_keys(&self, localpart: &str, version: i64) -> Result<i64, Box<dyn Error>>;
+
+    /// Records that a user's device list or keys changed, returning the
+    /// change's position in the device list stream.
+    async fn add_device_list_change(&self, user_id: &str) -> Result<i64, Box<dyn Error>>;
+
+    /// Gets the users whose device lists changed after stream position
+    /// `from`, up to and including `to`.
+    async fn get_device_list_changes(
+        &self,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Gets the latest positions of the streams sync reads, in a single
+    /// round trip. Every change up to a returned position is committed.
+    async fn get_stream_positions(&self) -> Result<StreamPositions, Box<dyn Error>>;
+
+    /// Replaces the user's dehydrated device, registering `device_id` as one
+    /// of their devices. The previous dehydrated device, if any, is removed
+    /// along with its keys and queued messages, and its ID returned.
+    async fn set_dehydrated_device(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        device_data: &Value,
+        display_name: Option<&str>,
+    ) -> Result<Option<String>, Box<dyn Error>>;
+
+    /// Gets the user's dehydrated device as `(device_id, device_data)`.
+    async fn get_dehydrated_device(
+        &self,
+        localpart: &str,
+    ) -> Result<Option<(String, Value)>, Box<dyn Error>>;
+
+    /// Removes the user's dehydrated device along with its keys and queued
+    /// messages, returning its ID if there was one.
+    async fn delete_dehydrated_device(
+        &self,
+        localpart: &str,
+    ) -> Result<Option<String>, Box<dyn Error>>;
+
+    /// Records a piece of media uploaded to this server.
+    async fn add_local_media(&self, media: &LocalMedia) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the metadata of a piece of media uploaded to this server.
+    async fn get_local_media(&self, media_id: &str) -> Result<Option<LocalMedia>, Box<dyn Error>>;
+
+    /// Gets a cached URL preview that has not expired by `now`.
+    async fn get_url_preview(&self, url: &str, now: i64) -> Result<Option<Value>, Box<dyn Error>>;
+
+    /// Caches a URL preview until `expires_ts`.
+    async fn set_url_preview(
+        &self,
+        url: &str,
+        og: &Value,
+        expires_ts: i64,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the total size, in bytes, of the media a user has uploaded.
+    async fn get_media_usage(&self, user_id: &str) -> Result<i64, Box<dyn Error>>;
+
+    /// Gets the IDs of the users whose uploads take up more than `quota`
+    /// bytes.
+    async fn get_users_over_media_quota(&self, quota: i64) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Gets the media a user has uploaded, oldest first.
+    async fn get_local_media_by_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<LocalMedia>, Box<dyn Error>>;
+
+    /// Gets the media uploaded by local users whose accounts are deactivated.
+    /// `server_name` is this server's name, as found in local user IDs.
+    async fn get_local_media_of_deactivated_users(
+        &self,
+        server_name: &str,
+    ) -> Result<Vec<LocalMedia>, Box<dyn Error>>;
+
+    /// Removes the record of a piece of local media.
+    async fn delete_local_media(&self, media_id: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the cached remote media that has not been served since `ts`.
+    async fn get_remote_media_accessed_before(
+        &self,
+        ts: i64,
+    ) -> Result<Vec<RemoteMedia>, Box<dyn Error>>;
+
+    /// Removes the record of a piece of cached remote media.
+    async fn delete_remote_media(&self, origin: &str, media_id: &str)
+        -> Result<(), Box<dyn Error>>;
+
+    /// Appends an entry to the audit log of security relevant actions.
+    async fn add_audit_log_entry(
+        &self,
+        user_id: Option<&str>,
+        ip: Option<&str>,
+        action: &str,
+        details: &Value,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Lists the audit log entries matching `params`, newest first, up to
+    /// `limit` of them.
+    async fn get_audit_log(
+        &self,
+        params: &AuditLogParams,
+        limit: i64,
+    ) -> Result<Vec<AuditLogEntry>, Box<dyn Error>>;
+
+    /// Gets the response sent for a transaction received from another
+    /// server, if it has been received before.
+    async fn get_received_transaction(
+        &self,
+        origin: &str,
+        txn_id: &str,
+    ) -> Result<Option<Value>, Box<dyn Error>>;
+
+    /// Records the response sent for a transaction received from another
+    /// server.
+    async fn set_received_transaction(
+        &self,
+        origin: &str,
+        txn_id: &str,
+        response: &Value,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Queues PDUs and EDUs to be sent to another server.
+    async fn add_federation_outbound(
+        &self,
+        destination: &str,
+        pdus: &[Value],
+        edus: &[Value],
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the oldest PDUs and EDUs queued for a server, up to `max_pdus`
+    /// and `max_edus` of each, along with their IDs in the queue.
+    async fn get_federation_outbound(
+        &self,
+        destination: &str,
+        max_pdus: i64,
+        max_edus: i64,
+    ) -> Result<(Vec<(i64, Value)>, Vec<(i64, Value)>), Box<dyn Error>>;
+
+    /// Removes delivered items from the federation queue.
+    async fn delete_federation_outbound(&self, ids: &[i64]) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the servers that have items queued for them.
+    async fn get_federation_destinations(&self) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Gets when delivery to a server may be retried, if it last failed.
+    async fn get_destination_retry(
+        &self,
+        destination: &str,
+    ) -> Result<Option<DestinationRetry>, Box<dyn Error>>;
+
+    /// Sets when delivery to a server may be retried, or clears it once
+    /// delivery succeeds.
+    async fn set_destination_retry(
+        &self,
+        destination: &str,
+        retry: Option<&DestinationRetry>,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the cached keys of a server, including ones it no longer uses.
+    async fn get_server_keys(&self, server_name: &str) -> Result<Vec<ServerKey>, Box<dyn Error>>;
+
+    /// Caches keys fetched for a server, replacing any cached with the same
+    /// IDs.
+    async fn set_server_keys(
+        &self,
+        server_name: &str,
+        keys: &[ServerKey],
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Records an invite of a local user to a room, replacing any earlier
+    /// invite to the same room.
+    async fn add_room_invite(
+        &self,
+        localpart: &str,
+        invite: &RoomInvite,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets a user's invites made after the stream position `since`, up to
+    /// and including `to`, with their stream positions, in stream order.
+    async fn get_room_invites(
+        &self,
+        localpart: &str,
+        since: i64,
+        to: i64,
+    ) -> Result<Vec<(i64, RoomInvite)>, Box<dyn Error>>;
+
+    /// Gets the metadata of a piece of remote media cached locally.
+    async fn get_remote_media(
+        &self,
+        origin: &str,
+        media_id: &str,
+    ) -> Result<Option<RemoteMedia>, Box<dyn Error>>;
+
+    /// Records a piece of remote media fetched into the cache. If it was
+    /// recorded already, the existing record is kept.
+    async fn add_remote_media(&self, media: &RemoteMedia) -> Result<(), Box<dyn Error>>;
+
+    /// Records that a piece of cached remote media was served at `ts`.
+    async fn touch_remote_media(
+        &self,
+        origin: &str,
+        media_id: &str,
+        ts: i64,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets all cached remote media, least recently served first.
+    async fn get_remote_media_by_access(&self) -> Result<Vec<RemoteMedia>, Box<dyn Error>>;
+
+    /// Records a user's presence, replacing what was known before.
+    async fn set_presence(&self, presence: &Presence) -> Result<(), Box<dyn Error>>;
+
+    /// Marks the users whose presence hasn't been heard of since
+    /// `before_ts` as offline, returning how many were.
+    async fn time_out_presence(&self, before_ts: i64) -> Result<u64, Box<dyn Error>>;
+
+    /// Gets a user's last known presence.
+    async fn get_presence(&self, user_id: &str) -> Result<Option<Presence>, Box<dyn Error>>;
+
+    /// Gets a local user's display name and avatar.
+    async fn get_profile(&self, localpart: &str) -> Result<Profile, Box<dyn Error>>;
+
+    /// Sets a local user's display name, or removes it with `None`.
+    async fn set_displayname(
+        &self,
+        localpart: &str,
+        displayname: Option<&str>,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Sets a local user's avatar, or removes it with `None`.
+    async fn set_avatar_url(
+        &self,
+        localpart: &str,
+        avatar_url: Option<&str>,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the push rules a user has set, if they have set any.
+    async fn get_push_rules(
+        &self,
+        localpart: &str,
+    ) -> Result<Option<UserPushRules>, Box<dyn Error>>;
+
+    /// Replaces the push rules a user has set.
+    async fn set_push_rules(
+        &self,
+        localpart: &str,
+        rules: &UserPushRules,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Adds or updates a pusher. New pushers only deliver notifications
+    /// queued after they were added.
+    async fn set_pusher(&self, localpart: &str, pusher: &Pusher) -> Result<(), Box<dyn Error>>;
+
+    /// Removes a user's pusher.
+    async fn delete_pusher(
+        &self,
+        localpart: &str,
+        app_id: &str,
+        pushkey: &str,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Removes the pushers of other users with the same app ID and pushkey.
+    async fn delete_other_pushers(
+        &self,
+        localpart: &str,
+        app_id: &str,
+        pushkey: &str,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets a user's pushers and how far each has got.
+    async fn get_pushers(
+        &self,
+        localpart: &str,
+    ) -> Result<Vec<(Pusher, PusherState)>, Box<dyn Error>>;
+
+    /// Records how far a pusher has got.
+    async fn set_pusher_state(
+        &self,
+        localpart: &str,
+        app_id: &str,
+        pushkey: &str,
+        state: &PusherState,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Queues a notification for a user's pushers, returning its position
+    /// in the user's notification stream.
+    async fn add_push_notification(
+        &self,
+        localpart: &str,
+        notification: &Value,
+        tweaks: &Value,
+    ) -> Result<i64, Box<dyn Error>>;
+
+    /// Gets up to `limit` notifications queued for a user after stream
+    /// position `after`.
+    async fn get_push_notifications(
+        &self,
+        localpart: &str,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<QueuedNotification>, Box<dyn Error>>;
+
+    /// Removes a user's queued notifications up to and including stream
+    /// position `up_to`.
+    async fn delete_push_notifications(
+        &self,
+        localpart: &str,
+        up_to: i64,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the users with notifications queued.
+    async fn get_push_users(&self) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Adds to a user's unread notification counts in a thread of a room.
+    async fn add_push_counts(
+        &self,
+        localpart: &str,
+        room_id: &str,
+        thread_id: &str,
+        notifications: i64,
+        highlights: i64,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Resets a user's unread notification counts in a room, as a read
+    /// receipt does. `thread_id` limits it to one thread; `None` resets every
+    /// thread, as an unthreaded receipt does.
+    async fn clear_push_counts(
+        &self,
+        localpart: &str,
+        room_id: &str,
+        thread_id: Option<&str>,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets a user's unread notification counts, in every thread, of the
+    /// rooms where any changed after stream position `from`, up to and
+    /// including `to`.
+    async fn get_push_counts(
+        &self,
+        localpart: &str,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<PushCounts>, Box<dyn Error>>;
+
+    /// Gets how many events a user has been notified of and not read, in all
+    /// their rooms.
+    async fn get_unread_count(&self, localpart: &str) -> Result<i64, Box<dyn Error>>;
+
+    /// Checks the database can be reached and has the whole schema,
+    /// returning the tables missing from it.
+    async fn check_schema(&self) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Gets a local account.
+    async fn get_account(&self, localpart: &str) -> Result<Option<Account>, Box<dyn Error>>;
+
+    /// Gets up to `limit` local accounts in order of localpart, starting
+    /// after `from`. Guests and deactivated accounts are only included if
+    /// asked for.
+    async fn get_accounts(
+        &self,
+        from: Option<&str>,
+        limit: i64,
+        guests: bool,
+        deactivated: bool,
+    ) -> Result<Vec<Account>, Box<dyn Error>>;
+
+    /// Creates a local account, with an argon2 encoded password hash if it
+    /// has a password. Returns `false` if the localpart is taken.
+    async fn create_account(
+        &self,
+        localpart: &str,
+        password_hash: Option<&str>,
+        is_admin: bool,
+    ) -> Result<bool, Box<dyn Error>>;
+
+    /// Creates a local account for a user of an application service, which
+    /// acts as the user instead of them logging in. Returns `false` if the
+    /// localpart is taken.
+    async fn create_appservice_account(
+        &self,
+        localpart: &str,
+        appservice_id: &str,
+    ) -> Result<bool, Box<dyn Error>>;
+
+    /// Replaces the argon2 encoded password hash of an account. `None`
+    /// removes its password.
+    async fn set_password_hash(
+        &self,
+        localpart: &str,
+        password_hash: Option<&str>,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Deactivates or reactivates an account.
+    async fn set_account_deactivated(
+        &self,
+        localpart: &str,
+        deactivated: bool,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Lets an account send events without being throttled, or stops it.
+    async fn set_send_limit_exempt(
+        &self,
+        localpart: &str,
+        exempt: bool,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Erases what a local user has stored on the server: their password,
+    /// profile, account data, devices, keys, backups, pushers and presence.
+    /// The account itself and the media they uploaded are kept.
+    async fn erase_user(&self, localpart: &str, user_id: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Gets up to `limit` rooms the server knows of, in order of room ID,
+    /// starting after `from`.
+    async fn get_admin_rooms(
+        &self,
+        from: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<AdminRoom>, Box<dyn Error>>;
+
+    /// Blocks a room, so local users can't be invited to or join it.
+    async fn block_room(&self, room_id: &str, blocked_by: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Determines if a room is blocked.
+    async fn is_room_blocked(&self, room_id: &str) -> Result<bool, Box<dyn Error>>;
+
+    /// Gets the localparts of the local users invited to a room.
+    async fn get_room_invitees(&self, room_id: &str) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Removes a local user's invite to a room, returning whether they had
+    /// one.
+    async fn delete_room_invite(
+        &self,
+        localpart: &str,
+        room_id: &str,
+    ) -> Result<bool, Box<dyn Error>>;
+
+    /// Queues a room moderation job, returning its ID.
+    async fn add_admin_job(
+        &self,
+        room_id: &str,
+        job: &RoomJob,
+        created_by: &str,
+    ) -> Result<i64, Box<dyn Error>>;
+
+    /// Gets a room moderation job.
+    async fn get_admin_job(&self, job_id: i64) -> Result<Option<AdminJob>, Box<dyn Error>>;
+
+    /// Gets the IDs of the room moderation jobs that haven't finished, in
+    /// the order they were queued.
+    async fn get_unfinished_admin_jobs(&self) -> Result<Vec<i64>, Box<dyn Error>>;
+
+    /// Records how far a room moderation job has got.
+    async fn update_admin_job(
+        &self,
+        job_id: i64,
+        status: JobStatus,
+        progress: &Value,
+        error: Option<&str>,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Schedules a job of `kind` to run every `interval_ms`, first at
+    /// `first_run_ts`. There is one periodic job of each kind: if there
+    /// already is one, only its interval is changed, bringing it forward if
+    /// it was due more than an interval from now.
+    async fn add_periodic_job(
+        &self,
+        kind: &str,
+        interval_ms: i64,
+        first_run_ts: i64,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Schedules a job of `kind` to run once at `run_ts`, returning its ID.
+    async fn add_scheduled_job(
+        &self,
+        kind: &str,
+        payload: &Value,
+        run_ts: i64,
+    ) -> Result<i64, Box<dyn Error>>;
+
+    /// Marks up to `limit` jobs of the given kinds that are due by `now` as
+    /// running, and due again at `lease_until` in case this process stops
+    /// before finishing them. Jobs another process is claiming at the same
+    /// time are skipped.
+    async fn claim_scheduled_jobs(
+        &self,
+        kinds: &[String],
+        now: i64,
+        lease_until: i64,
+        limit: i64,
+    ) -> Result<Vec<ScheduledJob>, Box<dyn Error>>;
+
+    /// Records the outcome of a run of a job, and when it is next due.
+    async fn finish_scheduled_job(
+        &self,
+        job_id: i64,
+        status: JobStatus,
+        next_run_ts: i64,
+        failures: i32,
+        error: Option<&str>,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Lists scheduled jobs in the order they were scheduled.
+    async fn get_scheduled_jobs(
+        &self,
+        params: &ListScheduledJobsParams,
+        limit: i64,
+    ) -> Result<Vec<ScheduledJob>, Box<dyn Error>>;
+
+    /// Deletes the jobs run once that finished before `before_ts`,
+    /// returning how many were deleted.
+    async fn delete_finished_scheduled_jobs(&self, before_ts: i64) -> Result<u64, Box<dyn Error>>;
+
+    /// Queues an event to be sent to an application service.
+    async fn add_appservice_event(
+        &self,
+        appservice_id: &str,
+        event: &Value,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets up to `limit` of the events queued for an application service
+    /// after stream ID `after`, up to and including `up_to`, oldest first,
+    /// with their stream IDs.
+    async fn get_appservice_events(
+        &self,
+        appservice_id: &str,
+        after: i64,
+        up_to: i64,
+        limit: i64,
+    ) -> Result<Vec<(i64, Value)>, Box<dyn Error>>;
+
+    /// Removes the events an application service accepted, up to and
+    /// including stream ID `up_to`.
+    async fn delete_appservice_events(
+        &self,
+        appservice_id: &str,
+        up_to: i64,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the IDs of the application services with events queued.
+    async fn get_appservices_with_events(&self) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Gets how far sending an application service its events has got.
+    async fn get_appservice_state(
+        &self,
+        appservice_id: &str,
+    ) -> Result<AppServiceState, Box<dyn Error>>;
+
+    /// Records how far sending an application service its events has got.
+    async fn set_appservice_state(
+        &self,
+        appservice_id: &str,
+        state: &AppServiceState,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Sets a user's membership of a room, or forgets it if `None`,
+    /// returning the membership it replaced.
+    async fn set_room_membership(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        membership: Option<&str>,
+    ) -> Result<Option<String>, Box<dyn Error>>;
+
+    /// Gets a user's membership of a room, if it is known.
+    async fn get_room_membership(
+        &self,
+        room_id: &str,
+        user_id: &str,
+    ) -> Result<Option<String>, Box<dyn Error>>;
+
+    /// Gets up to `limit` of a room's joined or invited members, in the
+    /// order they became members.
+    async fn get_room_heroes(
+        &self,
+        room_id: &str,
+        limit: usize,
+    ) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Gets the stored summary of a room's members, if there is one.
+    async fn get_room_summary(&self, room_id: &str) -> Result<Option<RoomSummary>, Box<dyn Error>>;
+
+    /// Replaces the stored summary of a room's members.
+    async fn set_room_summary(
+        &self,
+        room_id: &str,
+        summary: &RoomSummary,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets which of a user's access tokens are revoked, if any are.
+    async fn get_token_revocation(
+        &self,
+        localpart: &str,
+    ) -> Result<Option<TokenRevocation>, Box<dyn Error>>;
+
+    /// Gets the users' token revocations made after `since_ts`, by
+    /// localpart.
+    async fn get_token_revocations(
+        &self,
+        since_ts: i64,
+    ) -> Result<Vec<(String, TokenRevocation)>, Box<dyn Error>>;
+
+    /// Replaces which of a user's access tokens are revoked.
+    async fn set_token_revocation(
+        &self,
+        localpart: &str,
+        revocation: &TokenRevocation,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Records an invite of a third party identifier to a room, stored on
+    /// an identity server.
+    async fn add_third_party_invite(&self, invite: &ThirdPartyInvite)
+        -> Result<(), Box<dyn Error>>;
+
+    /// Gets the third party invite to a room with the identity server's
+    /// `token`, if it is still waiting to be bound.
+    async fn get_third_party_invite(
+        &self,
+        room_id: &str,
+        token: &str,
+    ) -> Result<Option<ThirdPartyInvite>, Box<dyn Error>>;
+
+    /// Deletes a third party invite, returning whether there was one.
+    async fn delete_third_party_invite(
+        &self,
+        room_id: &str,
+        token: &str,
+    ) -> Result<bool, Box<dyn Error>>;
+
+    /// Gets a page of the rooms published to the room directory, with the
+    /// most joined members first, and how many are published in all.
+    /// Blocked rooms are left out.
+    async fn get_public_rooms(
+        &self,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(Vec<PublicRoom>, i64), Box<dyn Error>>;
+
+    /// Records a report of an event, returning its ID.
+    async fn add_event_report(&self, report: &EventReport) -> Result<i64, Box<dyn Error>>;
+
+    /// Blocks a network from an API, returning the block's ID. The block's
+    /// own ID is ignored.
+    async fn add_ip_block(&self, block: &IpBlock) -> Result<i64, Box<dyn Error>>;
+
+    /// Gets the IP blocks that haven't expired by `now_ts`, a unix timestamp
+    /// (ms resolution), oldest first.
+    async fn get_ip_blocks(&self, now_ts: i64) -> Result<Vec<IpBlock>, Box<dyn Error>>;
+
+    /// Removes an IP block, returning whether there was one.
+    async fn delete_ip_block(&self, block_id: i64) -> Result<bool, Box<dyn Error>>;
+
+    /// Counts the local accounts that aren't deactivated, guests included.
+    async fn count_active_accounts(&self) -> Result<i64, Box<dyn Error>>;
+
+    /// Counts the rooms the server knows of: those with a known member, or
+    /// a local user invited to them.
+    async fn count_rooms(&self) -> Result<i64, Box<dyn Error>>;
+
+    /// Gets up to `limit` of the users whose membership of a room is known,
+    /// with their membership, in order of user ID, starting after `from`.
+    async fn get_room_members(
+        &self,
+        room_id: &str,
+        from: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<RoomMember>, Box<dyn Error>>;
+
+    /// Adds members to the snapshot an export job is taking. Members
+    /// already in it are left as they are.
+    async fn add_exported_members(
+        &self,
+        job_id: i64,
+        members: &[RoomMember],
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the members an export job snapshotted, in order of user ID.
+    async fn get_exported_members(&self, job_id: i64) -> Result<Vec<RoomMember>, Box<dyn Error>>;
+
+    /// Gets which of the given events have been received from another server
+    /// and accepted before.
+    async fn get_received_pdus(&self, event_ids: &[String]) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Records events received from `origin` and accepted. Events already
+    /// recorded are left as they are.
+    async fn add_received_pdus(
+        &self,
+        origin: &str,
+        event_ids: &[String],
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Forgets the transactions and events received from other servers
+    /// before `before_ts`, a unix timestamp (ms resolution), returning how
+    /// many were forgotten.
+    async fn delete_received_before(&self, before_ts: i64) -> Result<u64, Box<dyn Error>>;
+
+    /// Stores a new User-Interactive Authentication session.
+    async fn add_uia_session(
+        &self,
+        session_id: &str,
+        session: &UiaSession,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Gets a User-Interactive Authentication session, if it hasn't been
+    /// used up.
+    async fn get_uia_session(&self, session_id: &str)
+        -> Result<Option<UiaSession>, Box<dyn Error>>;
+
+    /// Uses up a User-Interactive Authentication session, returning whether
+    /// it was still there to be used.
+    async fn delete_uia_session(&self, session_id: &str) -> Result<bool, Box<dyn Error>>;
+
+    /// Forgets the User-Interactive Authentication sessions started before
+    /// `before_ts`, a unix timestamp (ms resolution), returning how many
+    /// were forgotten.
+    async fn delete_uia_sessions_before(&self, before_ts: i64) -> Result<u64, Box<dyn Error>>;
+
+    /// Registers a device for a user, returning `false`, changing nothing,
+    /// if they already have a device with that ID.
+    async fn add_device(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        display_name: Option<&str>,
+    ) -> Result<bool, Box<dyn Error>>;
 }
@@ -1,7 +1,33 @@
 use super::Store;
+use crate::clock::now_ms;
+use crate::models::{
+    access::{Api, IpBlock},
+    account::TokenRevocation,
+    account_data::IGNORED_USER_LIST,
+    admin::{
+        Account, AdminJob, AdminRoom, AuditLogEntry, AuditLogParams, JobStatus,
+        ListScheduledJobsParams, RoomJob, RoomMember, ScheduledJob,
+    },
+    appservice::AppServiceState,
+    auth::UiaSession,
+    directory::PublicRoom,
+    federation::{DestinationRetry, RoomInvite, ServerKey},
+    keys::{KeySignature, OneTimeKey},
+    media::{LocalMedia, RemoteMedia},
+    presence::Presence,
+    profile::Profile,
+    push::{PushCounts, Pusher, PusherState, QueuedNotification, UserPushRules},
+    report::EventReport,
+    room::{RoomSummary, ThirdPartyInvite},
+    room_keys::{BackupVersion, KeyBackupData, RoomKey},
+    sync::StreamPositions,
+    to_device,
+};
 use async_trait::async_trait;
+use serde_json::Value;
 use sqlx::postgres::PgPool;
 use sqlx::postgres::PgQueryAs;
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
 
 /// A Postgres Data Store
@@ -23,6 +49,12 @@ impl PostgresStore {
 
         Ok(Self { pool })
     }
+
+    /// Closes the connections once the queries running on them are done, so
+    /// no write is cut off when the server exits.
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
 }
 
 #[async_trait]
@@ -31,6 +63,7 @@ impl Store for PostgresStore {
         "Initialized PostgresStore".to_string()
     }
 
+    #[tracing::instrument(skip(self))]
     async fn is_username_available(&self, username: &str) -> Result<bool, Box<dyn Error>> {
         let row: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM accounts where localpart = $1")
             .bind(username)
@@ -39,4 +72,3282 @@ impl Store for PostgresStore {
 
         Ok(row.0 == 0)
     }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_account_data(
+        &self,
+        localpart: &str,
+        room_id: Option<&str>,
+        data_type: &str,
+    ) -> Result<Option<Value>, Box<dyn Error>> {
+        let row: Option<(Value,)> = sqlx::query_as(
+            "SELECT content FROM account_data
+             WHERE localpart = $1 AND room_id = $2 AND data_type = $3",
+        )
+        .bind(localpart)
+        .bind(room_id.unwrap_or(""))
+        .bind(data_type)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|r| r.0))
+    }
+
+    #[tracing::instrument(skip(self, content))]
+    async fn set_account_data(
+        &self,
+        localpart: &str,
+        room_id: Option<&str>,
+        data_type: &str,
+        content: &Value,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO account_data (localpart, room_id, data_type, content, stream_id)
+             VALUES ($1, $2, $3, $4, next_stream_position('account_data'))
+             ON CONFLICT (localpart, room_id, data_type) DO UPDATE
+             SET content = $4, stream_id = next_stream_position('account_data')",
+        )
+        .bind(localpart)
+        .bind(room_id.unwrap_or(""))
+        .bind(data_type)
+        .bind(content.clone())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_account_data_changes(
+        &self,
+        localpart: &str,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<(Option<String>, String, Value)>, Box<dyn Error>> {
+        let rows: Vec<(String, String, Value)> = sqlx::query_as(
+            "SELECT room_id, data_type, content FROM account_data
+             WHERE localpart = $1 AND stream_id > $2 AND stream_id <= $3
+             ORDER BY stream_id",
+        )
+        .bind(localpart)
+        .bind(from)
+        .bind(to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(room_id, data_type, content)| {
+                let room_id = if room_id.is_empty() {
+                    None
+                } else {
+                    Some(room_id)
+                };
+                (room_id, data_type, content)
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_ignored_users(&self, localpart: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as("SELECT ignored_user_id FROM ignored_users WHERE localpart = $1")
+                .bind(localpart)
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter().map(|r| r.0).collect())
+    }
+
+    #[tracing::instrument(skip(self, ignored, content))]
+    async fn set_ignored_users(
+        &self,
+        localpart: &str,
+        ignored: &[String],
+        content: &Value,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM ignored_users WHERE localpart = $1")
+            .bind(localpart)
+            .execute(&mut tx)
+            .await?;
+        for user_id in ignored {
+            sqlx::query("INSERT INTO ignored_users (localpart, ignored_user_id) VALUES ($1, $2)")
+                .bind(localpart)
+                .bind(user_id)
+                .execute(&mut tx)
+                .await?;
+        }
+        sqlx::query(
+            "INSERT INTO account_data (localpart, room_id, data_type, content, stream_id)
+             VALUES ($1, '', $2, $3, next_stream_position('account_data'))
+             ON CONFLICT (localpart, room_id, data_type) DO UPDATE
+             SET content = $3, stream_id = next_stream_position('account_data')",
+        )
+        .bind(localpart)
+        .bind(IGNORED_USER_LIST)
+        .bind(content.clone())
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, keys))]
+    async fn set_device_keys(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        keys: &Value,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO e2e_device_keys (localpart, device_id, ts_added_ms, key_json)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (localpart, device_id) DO UPDATE
+             SET ts_added_ms = $3, key_json = $4",
+        )
+        .bind(localpart)
+        .bind(device_id)
+        .bind(now_ms())
+        .bind(keys.clone())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, device_ids))]
+    async fn get_device_keys(
+        &self,
+        localpart: &str,
+        device_ids: &[String],
+    ) -> Result<Vec<(String, Value)>, Box<dyn Error>> {
+        let rows: Vec<(String, Value)> = sqlx::query_as(
+            "SELECT device_id, key_json FROM e2e_device_keys
+             WHERE localpart = $1 AND (cardinality($2::TEXT[]) = 0 OR device_id = ANY($2))",
+        )
+        .bind(localpart)
+        .bind(device_ids.to_vec())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    #[tracing::instrument(skip(self, keys))]
+    async fn add_one_time_keys(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        keys: &[OneTimeKey],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        for key in keys {
+            sqlx::query(
+                "INSERT INTO e2e_one_time_keys
+                 (localpart, device_id, algorithm, key_id, ts_added_ms, key_json)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 ON CONFLICT DO NOTHING",
+            )
+            .bind(localpart)
+            .bind(device_id)
+            .bind(&key.algorithm)
+            .bind(&key.key_id)
+            .bind(now_ms())
+            .bind(key.key.clone())
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn count_one_time_keys(
+        &self,
+        localpart: &str,
+        device_id: &str,
+    ) -> Result<BTreeMap<String, i64>, Box<dyn Error>> {
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT algorithm, COUNT(*) FROM e2e_one_time_keys
+             WHERE localpart = $1 AND device_id = $2
+             GROUP BY algorithm",
+        )
+        .bind(localpart)
+        .bind(device_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn claim_one_time_key(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        algorithm: &str,
+    ) -> Result<Option<OneTimeKey>, Box<dyn Error>> {
+        // Deleting with RETURNING under SKIP LOCKED guarantees that two
+        // concurrent claims never hand out the same key.
+        let claimed: Option<(String, Value)> = sqlx::query_as(
+            "DELETE FROM e2e_one_time_keys
+             WHERE (localpart, device_id, algorithm, key_id) IN (
+                 SELECT localpart, device_id, algorithm, key_id FROM e2e_one_time_keys
+                 WHERE localpart = $1 AND device_id = $2 AND algorithm = $3
+                 ORDER BY ts_added_ms, key_id
+                 LIMIT 1
+                 FOR UPDATE SKIP LOCKED
+             )
+             RETURNING key_id, key_json",
+        )
+        .bind(localpart)
+        .bind(device_id)
+        .bind(algorithm)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let claimed = match claimed {
+            Some(claimed) => Some(claimed),
+            None => {
+                sqlx::query_as(
+                    "UPDATE e2e_fallback_keys SET used = TRUE
+                     WHERE localpart = $1 AND device_id = $2 AND algorithm = $3
+                     RETURNING key_id, key_json",
+                )
+                .bind(localpart)
+                .bind(device_id)
+                .bind(algorithm)
+                .fetch_optional(&self.pool)
+                .await?
+            }
+        };
+
+        Ok(claimed.map(|(key_id, key)| OneTimeKey {
+            algorithm: algorithm.to_owned(),
+            key_id,
+            key,
+        }))
+    }
+
+    #[tracing::instrument(skip(self, keys))]
+    async fn set_fallback_keys(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        keys: &[OneTimeKey],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        for key in keys {
+            sqlx::query(
+                "INSERT INTO e2e_fallback_keys
+                 (localpart, device_id, algorithm, key_id, key_json, used)
+                 VALUES ($1, $2, $3, $4, $5, FALSE)
+                 ON CONFLICT (localpart, device_id, algorithm) DO UPDATE
+                 SET key_id = $4, key_json = $5, used = FALSE",
+            )
+            .bind(localpart)
+            .bind(device_id)
+            .bind(&key.algorithm)
+            .bind(&key.key_id)
+            .bind(key.key.clone())
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_unused_fallback_key_types(
+        &self,
+        localpart: &str,
+        device_id: &str,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            "SELECT algorithm FROM e2e_fallback_keys
+             WHERE localpart = $1 AND device_id = $2 AND NOT used",
+        )
+        .bind(localpart)
+        .bind(device_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.0).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_device_ids(&self, localpart: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as("SELECT device_id FROM devices WHERE localpart = $1")
+                .bind(localpart)
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter().map(|r| r.0).collect())
+    }
+
+    #[tracing::instrument(skip(self, messages))]
+    async fn add_to_device_messages(
+        &self,
+        sender: &str,
+        sender_device_id: &str,
+        txn_id: &str,
+        message_type: &str,
+        messages: &[to_device::Message],
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        let inserted = sqlx::query(
+            "INSERT INTO device_inbox_txns (sender, sender_device_id, txn_id, ts_added_ms)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(sender)
+        .bind(sender_device_id)
+        .bind(txn_id)
+        .bind(now_ms())
+        .execute(&mut tx)
+        .await?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+        for message in messages {
+            let event = serde_json::json!({
+                "type": message_type,
+                "sender": sender,
+                "content": message.content,
+            });
+            sqlx::query(
+                "INSERT INTO device_inbox (stream_id, localpart, device_id, message_json)
+                 VALUES (next_stream_position('to_device'), $1, $2, $3)",
+            )
+            .bind(&message.localpart)
+            .bind(&message.device_id)
+            .bind(event)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_to_device_messages(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        since: i64,
+        to: i64,
+        limit: i64,
+    ) -> Result<Vec<(i64, Value)>, Box<dyn Error>> {
+        let rows: Vec<(i64, Value)> = sqlx::query_as(
+            "SELECT stream_id, message_json FROM device_inbox
+             WHERE localpart = $1 AND device_id = $2 AND stream_id > $3 AND stream_id <= $4
+             ORDER BY stream_id
+             LIMIT $5",
+        )
+        .bind(localpart)
+        .bind(device_id)
+        .bind(since)
+        .bind(to)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_to_device_messages(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        up_to: i64,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "DELETE FROM device_inbox
+             WHERE localpart = $1 AND device_id = $2 AND stream_id <= $3",
+        )
+        .bind(localpart)
+        .bind(device_id)
+        .bind(up_to)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_password_hash(&self, localpart: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let row: Option<(Option<String>,)> =
+            sqlx::query_as("SELECT password_hash FROM accounts WHERE localpart = $1")
+                .bind(localpart)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(row.and_then(|r| r.0))
+    }
+
+    #[tracing::instrument(skip(self, key))]
+    async fn set_cross_signing_key(
+        &self,
+        localpart: &str,
+        key_type: &str,
+        key: &Value,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO e2e_cross_signing_keys (localpart, key_type, ts_added_ms, key_json)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (localpart, key_type) DO UPDATE
+             SET ts_added_ms = $3, key_json = $4",
+        )
+        .bind(localpart)
+        .bind(key_type)
+        .bind(now_ms())
+        .bind(key.clone())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_cross_signing_keys(
+        &self,
+        localpart: &str,
+    ) -> Result<BTreeMap<String, Value>, Box<dyn Error>> {
+        let rows: Vec<(String, Value)> = sqlx::query_as(
+            "SELECT key_type, key_json FROM e2e_cross_signing_keys WHERE localpart = $1",
+        )
+        .bind(localpart)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().collect())
+    }
+
+    #[tracing::instrument(skip(self, signatures))]
+    async fn add_key_signatures(&self, signatures: &[KeySignature]) -> Result<(), Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        for sig in signatures {
+            sqlx::query(
+                "INSERT INTO e2e_cross_signing_signatures
+                 (signer, signing_key_id, target_user_id, target_id, signature)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (signer, signing_key_id, target_user_id, target_id) DO UPDATE
+                 SET signature = $5",
+            )
+            .bind(&sig.signer)
+            .bind(&sig.signing_key_id)
+            .bind(&sig.target_user_id)
+            .bind(&sig.target_id)
+            .bind(&sig.signature)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_key_signatures(
+        &self,
+        target_user_id: &str,
+    ) -> Result<Vec<KeySignature>, Box<dyn Error>> {
+        let rows: Vec<(String, String, String, String)> = sqlx::query_as(
+            "SELECT signer, signing_key_id, target_id, signature
+             FROM e2e_cross_signing_signatures WHERE target_user_id = $1",
+        )
+        .bind(target_user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(signer, signing_key_id, target_id, signature)| KeySignature {
+                    signer,
+                    signing_key_id,
+                    target_user_id: target_user_id.to_owned(),
+                    target_id,
+                    signature,
+                },
+            )
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self, auth_data))]
+    async fn create_backup_version(
+        &self,
+        localpart: &str,
+        algorithm: &str,
+        auth_data: &Value,
+    ) -> Result<i64, Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        let (version,): (i64,) = sqlx::query_as(
+            "SELECT COALESCE(MAX(version), 0) + 1 FROM e2e_room_keys_versions
+             WHERE localpart = $1",
+        )
+        .bind(localpart)
+        .fetch_one(&mut tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO e2e_room_keys_versions (localpart, version, algorithm, auth_data)
+             VALUES ($1, $2, $3, $4)",
+        )
+        .bind(localpart)
+        .bind(version)
+        .bind(algorithm)
+        .bind(auth_data.clone())
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(version)
+    }
+
+    #[tracing::instrument(skip(self, version))]
+    async fn get_backup_version(
+        &self,
+        localpart: &str,
+        version: Option<i64>,
+    ) -> Result<Option<BackupVersion>, Box<dyn Error>> {
+        let row: Option<(i64, String, Value, i64)> = sqlx::query_as(
+            "SELECT version, algorithm, auth_data, etag FROM e2e_room_keys_versions
+             WHERE localpart = $1 AND NOT deleted AND ($2::BIGINT IS NULL OR version = $2)
+             ORDER BY version DESC
+             LIMIT 1",
+        )
+        .bind(localpart)
+        .bind(version)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(
+            row.map(|(version, algorithm, auth_data, etag)| BackupVersion {
+                version,
+                algorithm,
+                auth_data,
+                etag,
+            }),
+        )
+    }
+
+    #[tracing::instrument(skip(self, auth_data))]
+    async fn update_backup_version(
+        &self,
+        localpart: &str,
+        version: i64,
+        auth_data: &Value,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "UPDATE e2e_room_keys_versions SET auth_data = $3
+             WHERE localpart = $1 AND version = $2 AND NOT deleted",
+        )
+        .bind(localpart)
+        .bind(version)
+        .bind(auth_data.clone())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_backup_version(
+        &self,
+        localpart: &str,
+        version: i64,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        // Versions are marked deleted rather than removed so their numbers
+        // are never reused.
+        let updated = sqlx::query(
+            "UPDATE e2e_room_keys_versions SET deleted = TRUE
+             WHERE localpart = $1 AND version = $2 AND NOT deleted",
+        )
+        .bind(localpart)
+        .bind(version)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query("DELETE FROM e2e_room_keys WHERE localpart = $1 AND version = $2")
+            .bind(localpart)
+            .bind(version)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(updated > 0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_room_keys(
+        &self,
+        localpart: &str,
+        version: i64,
+        room_id: Option<&str>,
+        session_id: Option<&str>,
+    ) -> Result<Vec<RoomKey>, Box<dyn Error>> {
+        let rows: Vec<(String, String, i64, i64, bool, Value)> = sqlx::query_as(
+            "SELECT room_id, session_id, first_message_index, forwarded_count, is_verified,
+                    session_data
+             FROM e2e_room_keys
+             WHERE localpart = $1 AND version = $2
+               AND ($3::TEXT IS NULL OR room_id = $3)
+               AND ($4::TEXT IS NULL OR session_id = $4)",
+        )
+        .bind(localpart)
+        .bind(version)
+        .bind(room_id)
+        .bind(session_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(
+                    room_id,
+                    session_id,
+                    first_message_index,
+                    forwarded_count,
+                    is_verified,
+                    session_data,
+                )| {
+                    RoomKey {
+                        room_id,
+                        session_id,
+                        data: KeyBackupData {
+                            first_message_index,
+                            forwarded_count,
+                            is_verified,
+                            session_data,
+                        },
+                    }
+                },
+            )
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self, keys))]
+    async fn put_room_keys(
+        &self,
+        localpart: &str,
+        version: i64,
+        keys: &[RoomKey],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        for key in keys {
+            sqlx::query(
+                "INSERT INTO e2e_room_keys
+                 (localpart, version, room_id, session_id, first_message_index,
+                  forwarded_count, is_verified, session_data)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                 ON CONFLICT (localpart, version, room_id, session_id) DO UPDATE
+                 SET first_message_index = $5, forwarded_count = $6, is_verified = $7,
+                     session_data = $8",
+            )
+            .bind(localpart)
+            .bind(version)
+            .bind(&key.room_id)
+            .bind(&key.session_id)
+            .bind(key.data.first_message_index)
+            .bind(key.data.forwarded_count)
+            .bind(key.data.is_verified)
+            .bind(key.data.session_data.clone())
+            .execute(&mut tx)
+            .await?;
+        }
+        sqlx::query(
+            "UPDATE e2e_room_keys_versions SET etag = etag + 1
+             WHERE localpart = $1 AND version = $2",
+        )
+        .bind(localpart)
+        .bind(version)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_room_keys(
+        &self,
+        localpart: &str,
+        version: i64,
+        room_id: Option<&str>,
+        session_id: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "DELETE FROM e2e_room_keys
+             WHERE localpart = $1 AND version = $2
+               AND ($3::TEXT IS NULL OR room_id = $3)
+               AND ($4::TEXT IS NULL OR session_id = $4)",
+        )
+        .bind(localpart)
+        .bind(version)
+        .bind(room_id)
+        .bind(session_id)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query(
+            "UPDATE e2e_room_keys_versions SET etag = etag + 1
+             WHERE localpart = $1 AND version = $2",
+        )
+        .bind(localpart)
+        .bind(version)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn count_room_keys(&self, localpart: &str, version: i64) -> Result<i64, Box<dyn Error>> {
+        let row: (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM e2e_room_keys WHERE localpart = $1 AND version = $2",
+        )
+        .bind(localpart)
+        .bind(version)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_device_list_change(&self, user_id: &str) -> Result<i64, Box<dyn Error>> {
+        let row: (i64,) = sqlx::query_as(
+            "INSERT INTO device_lists_stream (stream_id, user_id, ts_added_ms)
+             VALUES (next_stream_position('device_lists'), $1, $2)
+             RETURNING stream_id",
+        )
+        .bind(user_id)
+        .bind(now_ms())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_device_list_changes(
+        &self,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            "SELECT DISTINCT user_id FROM device_lists_stream
+             WHERE stream_id > $1 AND stream_id <= $2",
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.0).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_stream_positions(&self) -> Result<StreamPositions, Box<dyn Error>> {
+        // Positions are committed in order, so everything up to a stored
+        // position is already visible. Streams nothing was written to yet
+        // have no row, and stay at 0.
+        let rows: Vec<(String, i64)> =
+            sqlx::query_as("SELECT stream, position FROM stream_positions")
+                .fetch_all(&self.pool)
+                .await?;
+
+        let mut positions = StreamPositions::default();
+        for (stream, position) in rows {
+            match stream.as_str() {
+                "to_device" => positions.to_device = position,
+                "device_lists" => positions.device_lists = position,
+                "invites" => positions.invites = position,
+                "push_counts" => positions.push_counts = position,
+                "account_data" => positions.account_data = position,
+                _ => {}
+            }
+        }
+        Ok(positions)
+    }
+
+    #[tracing::instrument(skip(self, device_data))]
+    async fn set_dehydrated_device(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        device_data: &Value,
+        display_name: Option<&str>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        let previous: Option<(String,)> = sqlx::query_as(
+            "DELETE FROM dehydrated_devices WHERE localpart = $1 RETURNING device_id",
+        )
+        .bind(localpart)
+        .fetch_optional(&mut tx)
+        .await?;
+        if let Some((previous,)) = &previous {
+            for table in DEVICE_TABLES {
+                sqlx::query(&format!(
+                    "DELETE FROM {} WHERE localpart = $1 AND device_id = $2",
+                    table
+                ))
+                .bind(localpart)
+                .bind(previous)
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        sqlx::query(
+            "INSERT INTO dehydrated_devices (localpart, device_id, device_data, ts_added_ms)
+             VALUES ($1, $2, $3, $4)",
+        )
+        .bind(localpart)
+        .bind(device_id)
+        .bind(device_data)
+        .bind(now_ms())
+        .execute(&mut tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO devices (localpart, device_id, display_name) VALUES ($1, $2, $3)
+             ON CONFLICT (localpart, device_id) DO UPDATE SET display_name = $3",
+        )
+        .bind(localpart)
+        .bind(device_id)
+        .bind(display_name)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(previous.map(|r| r.0))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_dehydrated_device(
+        &self,
+        localpart: &str,
+    ) -> Result<Option<(String, Value)>, Box<dyn Error>> {
+        let row: Option<(String, Value)> = sqlx::query_as(
+            "SELECT device_id, device_data FROM dehydrated_devices WHERE localpart = $1",
+        )
+        .bind(localpart)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_dehydrated_device(
+        &self,
+        localpart: &str,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        let row: Option<(String,)> = sqlx::query_as(
+            "DELETE FROM dehydrated_devices WHERE localpart = $1 RETURNING device_id",
+        )
+        .bind(localpart)
+        .fetch_optional(&mut tx)
+        .await?;
+        if let Some((device_id,)) = &row {
+            for table in DEVICE_TABLES {
+                sqlx::query(&format!(
+                    "DELETE FROM {} WHERE localpart = $1 AND device_id = $2",
+                    table
+                ))
+                .bind(localpart)
+                .bind(device_id)
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+
+        Ok(row.map(|r| r.0))
+    }
+
+    #[tracing::instrument(skip(self, media))]
+    async fn add_local_media(&self, media: &LocalMedia) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO local_media
+             (media_id, content_type, media_length, upload_name, user_id, created_ts)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(&media.media_id)
+        .bind(&media.content_type)
+        .bind(media.media_length)
+        .bind(&media.upload_name)
+        .bind(&media.user_id)
+        .bind(media.created_ts)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_local_media(&self, media_id: &str) -> Result<Option<LocalMedia>, Box<dyn Error>> {
+        let row: Option<LocalMediaRow> = sqlx::query_as(
+            "SELECT media_id, content_type, media_length, upload_name, user_id, created_ts
+             FROM local_media WHERE media_id = $1",
+        )
+        .bind(media_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(
+            |(media_id, content_type, media_length, upload_name, user_id, created_ts)| LocalMedia {
+                media_id,
+                content_type,
+                media_length,
+                upload_name,
+                user_id,
+                created_ts,
+            },
+        ))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_url_preview(&self, url: &str, now: i64) -> Result<Option<Value>, Box<dyn Error>> {
+        let row: Option<(Value,)> =
+            sqlx::query_as("SELECT og FROM url_previews WHERE url = $1 AND expires_ts > $2")
+                .bind(url)
+                .bind(now)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(row.map(|r| r.0))
+    }
+
+    #[tracing::instrument(skip(self, og))]
+    async fn set_url_preview(
+        &self,
+        url: &str,
+        og: &Value,
+        expires_ts: i64,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO url_previews (url, og, ts_added_ms, expires_ts) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (url) DO UPDATE SET og = $2, ts_added_ms = $3, expires_ts = $4",
+        )
+        .bind(url)
+        .bind(og)
+        .bind(now_ms())
+        .bind(expires_ts)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_media_usage(&self, user_id: &str) -> Result<i64, Box<dyn Error>> {
+        let row: (i64,) = sqlx::query_as(
+            "SELECT COALESCE(SUM(media_length), 0)::BIGINT FROM local_media WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_users_over_media_quota(&self, quota: i64) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            "SELECT user_id FROM local_media GROUP BY user_id HAVING SUM(media_length) > $1",
+        )
+        .bind(quota)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.0).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_local_media_by_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<LocalMedia>, Box<dyn Error>> {
+        let rows: Vec<LocalMediaRow> = sqlx::query_as(
+            "SELECT media_id, content_type, media_length, upload_name, user_id, created_ts
+             FROM local_media WHERE user_id = $1
+             ORDER BY created_ts",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(local_media_from_row).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_local_media_of_deactivated_users(
+        &self,
+        server_name: &str,
+    ) -> Result<Vec<LocalMedia>, Box<dyn Error>> {
+        let rows: Vec<LocalMediaRow> = sqlx::query_as(
+            "SELECT m.media_id, m.content_type, m.media_length, m.upload_name, m.user_id,
+                    m.created_ts
+             FROM local_media m
+             JOIN accounts a ON m.user_id = '@' || a.localpart || ':' || $1
+             WHERE a.deactivated",
+        )
+        .bind(server_name)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(local_media_from_row).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_local_media(&self, media_id: &str) -> Result<(), Box<dyn Error>> {
+        sqlx::query("DELETE FROM local_media WHERE media_id = $1")
+            .bind(media_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_remote_media_accessed_before(
+        &self,
+        ts: i64,
+    ) -> Result<Vec<RemoteMedia>, Box<dyn Error>> {
+        let rows: Vec<RemoteMediaRow> = sqlx::query_as(
+            "SELECT origin, media_id, file_id, content_type, media_length, upload_name,
+                        created_ts, last_access_ts
+                 FROM remote_media WHERE last_access_ts < $1",
+        )
+        .bind(ts)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(remote_media_from_row).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_remote_media(
+        &self,
+        origin: &str,
+        media_id: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("DELETE FROM remote_media WHERE origin = $1 AND media_id = $2")
+            .bind(origin)
+            .bind(media_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, details))]
+    async fn add_audit_log_entry(
+        &self,
+        user_id: Option<&str>,
+        ip: Option<&str>,
+        action: &str,
+        details: &Value,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO audit_log (ts, user_id, ip, action, details) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(now_ms())
+        .bind(user_id)
+        .bind(ip)
+        .bind(action)
+        .bind(details)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_audit_log(
+        &self,
+        params: &AuditLogParams,
+        limit: i64,
+    ) -> Result<Vec<AuditLogEntry>, Box<dyn Error>> {
+        let rows: Vec<(i64, i64, Option<String>, Option<String>, String, Value)> = sqlx::query_as(
+            "SELECT id, ts, user_id, ip, action, details FROM audit_log
+             WHERE ($1::BIGINT IS NULL OR id < $1)
+               AND ($2::TEXT IS NULL OR user_id = $2)
+               AND ($3::TEXT IS NULL OR left(action, length($3)) = $3)
+               AND ($4::BIGINT IS NULL OR ts >= $4)
+               AND ($5::BIGINT IS NULL OR ts < $5)
+             ORDER BY id DESC LIMIT $6",
+        )
+        .bind(params.from)
+        .bind(params.user_id.as_deref())
+        .bind(params.action.as_deref())
+        .bind(params.since)
+        .bind(params.until)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(id, ts, user_id, ip, action, details)| AuditLogEntry {
+                id,
+                ts,
+                user_id,
+                ip,
+                action,
+                details,
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_received_transaction(
+        &self,
+        origin: &str,
+        txn_id: &str,
+    ) -> Result<Option<Value>, Box<dyn Error>> {
+        let row: Option<(Value,)> = sqlx::query_as(
+            "SELECT response FROM received_transactions WHERE origin = $1 AND txn_id = $2",
+        )
+        .bind(origin)
+        .bind(txn_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|(response,)| response))
+    }
+
+    #[tracing::instrument(skip(self, response))]
+    async fn set_received_transaction(
+        &self,
+        origin: &str,
+        txn_id: &str,
+        response: &Value,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO received_transactions (origin, txn_id, response, ts_added_ms)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(origin)
+        .bind(txn_id)
+        .bind(response)
+        .bind(now_ms())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, pdus, edus))]
+    async fn add_federation_outbound(
+        &self,
+        destination: &str,
+        pdus: &[Value],
+        edus: &[Value],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        let items = pdus
+            .iter()
+            .map(|pdu| (true, pdu))
+            .chain(edus.iter().map(|edu| (false, edu)));
+        for (is_pdu, item) in items {
+            sqlx::query(
+                "INSERT INTO federation_outbound (destination, is_pdu, item_json)
+                 VALUES ($1, $2, $3)",
+            )
+            .bind(destination)
+            .bind(is_pdu)
+            .bind(item)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_federation_outbound(
+        &self,
+        destination: &str,
+        max_pdus: i64,
+        max_edus: i64,
+    ) -> Result<(Vec<(i64, Value)>, Vec<(i64, Value)>), Box<dyn Error>> {
+        let mut items = Vec::new();
+        for &(is_pdu, limit) in &[(true, max_pdus), (false, max_edus)] {
+            let rows: Vec<(i64, Value)> = sqlx::query_as(
+                "SELECT id, item_json FROM federation_outbound
+                 WHERE destination = $1 AND is_pdu = $2
+                 ORDER BY id LIMIT $3",
+            )
+            .bind(destination)
+            .bind(is_pdu)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+            items.push(rows);
+        }
+        let edus = items.pop().unwrap_or_default();
+        let pdus = items.pop().unwrap_or_default();
+
+        Ok((pdus, edus))
+    }
+
+    #[tracing::instrument(skip(self, ids))]
+    async fn delete_federation_outbound(&self, ids: &[i64]) -> Result<(), Box<dyn Error>> {
+        sqlx::query("DELETE FROM federation_outbound WHERE id = ANY($1)")
+            .bind(ids.to_vec())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_federation_destinations(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as("SELECT DISTINCT destination FROM federation_outbound")
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter().map(|(destination,)| destination).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_destination_retry(
+        &self,
+        destination: &str,
+    ) -> Result<Option<DestinationRetry>, Box<dyn Error>> {
+        let row: Option<(i64, i64)> = sqlx::query_as(
+            "SELECT retry_last_ts, retry_interval FROM federation_destinations
+             WHERE destination = $1",
+        )
+        .bind(destination)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|(retry_last_ts, retry_interval)| DestinationRetry {
+            retry_last_ts,
+            retry_interval,
+        }))
+    }
+
+    #[tracing::instrument(skip(self, retry))]
+    async fn set_destination_retry(
+        &self,
+        destination: &str,
+        retry: Option<&DestinationRetry>,
+    ) -> Result<(), Box<dyn Error>> {
+        match retry {
+            Some(retry) => {
+                sqlx::query(
+                    "INSERT INTO federation_destinations (destination, retry_last_ts, retry_interval)
+                     VALUES ($1, $2, $3)
+                     ON CONFLICT (destination) DO UPDATE SET retry_last_ts = $2, retry_interval = $3",
+                )
+                .bind(destination)
+                .bind(retry.retry_last_ts)
+                .bind(retry.retry_interval)
+                .execute(&self.pool)
+                .await?;
+            }
+            None => {
+                sqlx::query("DELETE FROM federation_destinations WHERE destination = $1")
+                    .bind(destination)
+                    .execute(&self.pool)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_server_keys(&self, server_name: &str) -> Result<Vec<ServerKey>, Box<dyn Error>> {
+        let rows: Vec<(String, String, i64, Option<i64>)> = sqlx::query_as(
+            "SELECT key_id, verify_key, valid_until_ts, expired_ts FROM server_keys
+            WHERE server_name = $1",
+        )
+        .bind(server_name)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(key_id, key, valid_until_ts, expired_ts)| ServerKey {
+                key_id,
+                key,
+                valid_until_ts,
+                expired_ts,
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self, keys))]
+    async fn set_server_keys(
+        &self,
+        server_name: &str,
+        keys: &[ServerKey],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        for key in keys {
+            sqlx::query(
+                "INSERT INTO server_keys
+                (server_name, key_id, verify_key, valid_until_ts, expired_ts, ts_added_ms)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (server_name, key_id) DO UPDATE
+                SET verify_key = $3, valid_until_ts = $4, expired_ts = $5, ts_added_ms = $6",
+            )
+            .bind(server_name)
+            .bind(&key.key_id)
+            .bind(&key.key)
+            .bind(key.valid_until_ts)
+            .bind(key.expired_ts)
+            .bind(now_ms())
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, invite))]
+    async fn add_room_invite(
+        &self,
+        localpart: &str,
+        invite: &RoomInvite,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO room_invites
+             (stream_id, localpart, room_id, event_json, invite_room_state)
+             VALUES (next_stream_position('invites'), $1, $2, $3, $4)
+             ON CONFLICT (localpart, room_id) DO UPDATE
+             SET stream_id = EXCLUDED.stream_id,
+                 event_json = $3, invite_room_state = $4",
+        )
+        .bind(localpart)
+        .bind(&invite.room_id)
+        .bind(&invite.event)
+        .bind(Value::from(invite.invite_room_state.clone()))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_room_invites(
+        &self,
+        localpart: &str,
+        since: i64,
+        to: i64,
+    ) -> Result<Vec<(i64, RoomInvite)>, Box<dyn Error>> {
+        let rows: Vec<(i64, String, Value, Value)> = sqlx::query_as(
+            "SELECT stream_id, room_id, event_json, invite_room_state FROM room_invites
+             WHERE localpart = $1 AND stream_id > $2 AND stream_id <= $3
+             ORDER BY stream_id",
+        )
+        .bind(localpart)
+        .bind(since)
+        .bind(to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(stream_id, room_id, event, invite_room_state)| {
+                let invite_room_state =
+                    serde_json::from_value(invite_room_state).unwrap_or_default();
+                (
+                    stream_id,
+                    RoomInvite {
+                        room_id,
+                        event,
+                        invite_room_state,
+                    },
+                )
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_remote_media(
+        &self,
+        origin: &str,
+        media_id: &str,
+    ) -> Result<Option<RemoteMedia>, Box<dyn Error>> {
+        let row: Option<RemoteMediaRow> = sqlx::query_as(
+            "SELECT origin, media_id, file_id, content_type, media_length, upload_name,
+                    created_ts, last_access_ts
+             FROM remote_media WHERE origin = $1 AND media_id = $2",
+        )
+        .bind(origin)
+        .bind(media_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(remote_media_from_row))
+    }
+
+    #[tracing::instrument(skip(self, media))]
+    async fn add_remote_media(&self, media: &RemoteMedia) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO remote_media
+             (origin, media_id, file_id, content_type, media_length, upload_name, created_ts,
+              last_access_ts)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+             ON CONFLICT (origin, media_id) DO NOTHING",
+        )
+        .bind(&media.origin)
+        .bind(&media.media_id)
+        .bind(&media.file_id)
+        .bind(&media.content_type)
+        .bind(media.media_length)
+        .bind(&media.upload_name)
+        .bind(media.created_ts)
+        .bind(media.last_access_ts)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn touch_remote_media(
+        &self,
+        origin: &str,
+        media_id: &str,
+        ts: i64,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "UPDATE remote_media SET last_access_ts = $3 WHERE origin = $1 AND media_id = $2",
+        )
+        .bind(origin)
+        .bind(media_id)
+        .bind(ts)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_remote_media_by_access(&self) -> Result<Vec<RemoteMedia>, Box<dyn Error>> {
+        let rows: Vec<RemoteMediaRow> = sqlx::query_as(
+            "SELECT origin, media_id, file_id, content_type, media_length, upload_name,
+                    created_ts, last_access_ts
+             FROM remote_media ORDER BY last_access_ts",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(remote_media_from_row).collect())
+    }
+
+    #[tracing::instrument(skip(self, presence))]
+    async fn set_presence(&self, presence: &Presence) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO presence
+                (user_id, presence, status_msg, last_active_ts, currently_active, updated_ts)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (user_id) DO UPDATE
+             SET presence = $2, status_msg = $3, last_active_ts = $4, currently_active = $5,
+                 updated_ts = $6",
+        )
+        .bind(&presence.user_id)
+        .bind(&presence.presence)
+        .bind(&presence.status_msg)
+        .bind(presence.last_active_ts)
+        .bind(presence.currently_active)
+        .bind(now_ms())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn time_out_presence(&self, before_ts: i64) -> Result<u64, Box<dyn Error>> {
+        let updated = sqlx::query(
+            "UPDATE presence SET presence = 'offline', currently_active = FALSE
+             WHERE presence <> 'offline' AND updated_ts < $1",
+        )
+        .bind(before_ts)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(updated)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_presence(&self, user_id: &str) -> Result<Option<Presence>, Box<dyn Error>> {
+        let row: Option<(String, String, Option<String>, i64, bool)> = sqlx::query_as(
+            "SELECT user_id, presence, status_msg, last_active_ts, currently_active
+             FROM presence WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(
+            |(user_id, presence, status_msg, last_active_ts, currently_active)| Presence {
+                user_id,
+                presence,
+                status_msg,
+                last_active_ts,
+                currently_active,
+            },
+        ))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_profile(&self, localpart: &str) -> Result<Profile, Box<dyn Error>> {
+        let row: Option<(Option<String>, Option<String>)> =
+            sqlx::query_as("SELECT displayname, avatar_url FROM profiles WHERE localpart = $1")
+                .bind(localpart)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(
+            row.map_or_else(Profile::default, |(displayname, avatar_url)| Profile {
+                displayname,
+                avatar_url,
+            }),
+        )
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set_displayname(
+        &self,
+        localpart: &str,
+        displayname: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO profiles (localpart, displayname) VALUES ($1, $2)
+             ON CONFLICT (localpart) DO UPDATE SET displayname = $2",
+        )
+        .bind(localpart)
+        .bind(displayname)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set_avatar_url(
+        &self,
+        localpart: &str,
+        avatar_url: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO profiles (localpart, avatar_url) VALUES ($1, $2)
+             ON CONFLICT (localpart) DO UPDATE SET avatar_url = $2",
+        )
+        .bind(localpart)
+        .bind(avatar_url)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_push_rules(
+        &self,
+        localpart: &str,
+    ) -> Result<Option<UserPushRules>, Box<dyn Error>> {
+        let row: Option<(Value,)> =
+            sqlx::query_as("SELECT rules FROM push_rules WHERE localpart = $1")
+                .bind(localpart)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        match row {
+            Some((rules,)) => Ok(Some(serde_json::from_value(rules)?)),
+            None => Ok(None),
+        }
+    }
+
+    #[tracing::instrument(skip(self, rules))]
+    async fn set_push_rules(
+        &self,
+        localpart: &str,
+        rules: &UserPushRules,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO push_rules (localpart, rules) VALUES ($1, $2)
+             ON CONFLICT (localpart) DO UPDATE SET rules = $2",
+        )
+        .bind(localpart)
+        .bind(serde_json::to_value(rules)?)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, pusher))]
+    async fn set_pusher(&self, localpart: &str, pusher: &Pusher) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO pushers (localpart, app_id, pushkey, pusher_json, last_stream_id)
+             VALUES ($1, $2, $3, $4,
+                     (SELECT COALESCE(MAX(stream_id), 0) FROM push_outbound WHERE localpart = $1))
+             ON CONFLICT (localpart, app_id, pushkey) DO UPDATE SET pusher_json = $4",
+        )
+        .bind(localpart)
+        .bind(&pusher.app_id)
+        .bind(&pusher.pushkey)
+        .bind(serde_json::to_value(pusher)?)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, pushkey))]
+    async fn delete_pusher(
+        &self,
+        localpart: &str,
+        app_id: &str,
+        pushkey: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("DELETE FROM pushers WHERE localpart = $1 AND app_id = $2 AND pushkey = $3")
+            .bind(localpart)
+            .bind(app_id)
+            .bind(pushkey)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, pushkey))]
+    async fn delete_other_pushers(
+        &self,
+        localpart: &str,
+        app_id: &str,
+        pushkey: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("DELETE FROM pushers WHERE localpart != $1 AND app_id = $2 AND pushkey = $3")
+            .bind(localpart)
+            .bind(app_id)
+            .bind(pushkey)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_pushers(
+        &self,
+        localpart: &str,
+    ) -> Result<Vec<(Pusher, PusherState)>, Box<dyn Error>> {
+        let rows: Vec<(Value, i64, i64, i64, i64)> = sqlx::query_as(
+            "SELECT pusher_json, last_stream_id, retry_last_ts, retry_interval, last_success_ts
+             FROM pushers WHERE localpart = $1",
+        )
+        .bind(localpart)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut pushers = Vec::with_capacity(rows.len());
+        for (pusher, last_stream_id, retry_last_ts, retry_interval, last_success_ts) in rows {
+            let state = PusherState {
+                last_stream_id,
+                retry_last_ts,
+                retry_interval,
+                last_success_ts,
+            };
+            pushers.push((serde_json::from_value(pusher)?, state));
+        }
+        Ok(pushers)
+    }
+
+    #[tracing::instrument(skip(self, state, pushkey))]
+    async fn set_pusher_state(
+        &self,
+        localpart: &str,
+        app_id: &str,
+        pushkey: &str,
+        state: &PusherState,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "UPDATE pushers
+             SET last_stream_id = $4, retry_last_ts = $5, retry_interval = $6, last_success_ts = $7
+             WHERE localpart = $1 AND app_id = $2 AND pushkey = $3",
+        )
+        .bind(localpart)
+        .bind(app_id)
+        .bind(pushkey)
+        .bind(state.last_stream_id)
+        .bind(state.retry_last_ts)
+        .bind(state.retry_interval)
+        .bind(state.last_success_ts)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, notification, tweaks))]
+    async fn add_push_notification(
+        &self,
+        localpart: &str,
+        notification: &Value,
+        tweaks: &Value,
+    ) -> Result<i64, Box<dyn Error>> {
+        let row: (i64,) = sqlx::query_as(
+            "INSERT INTO push_outbound (localpart, queued_ts, notification, tweaks)
+             VALUES ($1, $2, $3, $4) RETURNING stream_id",
+        )
+        .bind(localpart)
+        .bind(now_ms())
+        .bind(notification)
+        .bind(tweaks)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_push_notifications(
+        &self,
+        localpart: &str,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<QueuedNotification>, Box<dyn Error>> {
+        let rows: Vec<(i64, i64, Value, Value)> = sqlx::query_as(
+            "SELECT stream_id, queued_ts, notification, tweaks FROM push_outbound
+             WHERE localpart = $1 AND stream_id > $2
+             ORDER BY stream_id LIMIT $3",
+        )
+        .bind(localpart)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(stream_id, queued_ts, notification, tweaks)| QueuedNotification {
+                    stream_id,
+                    queued_ts,
+                    notification,
+                    tweaks,
+                },
+            )
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_push_notifications(
+        &self,
+        localpart: &str,
+        up_to: i64,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("DELETE FROM push_outbound WHERE localpart = $1 AND stream_id <= $2")
+            .bind(localpart)
+            .bind(up_to)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_push_users(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows: Vec<(String,)> = sqlx::query_as("SELECT DISTINCT localpart FROM push_outbound")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|r| r.0).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_push_counts(
+        &self,
+        localpart: &str,
+        room_id: &str,
+        thread_id: &str,
+        notifications: i64,
+        highlights: i64,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO push_counts
+             (localpart, room_id, thread_id, notification_count, highlight_count, stream_id)
+             VALUES ($1, $2, $3, $4, $5, next_stream_position('push_counts'))
+             ON CONFLICT (localpart, room_id, thread_id) DO UPDATE SET
+             notification_count = push_counts.notification_count + $4,
+             highlight_count = push_counts.highlight_count + $5,
+             stream_id = next_stream_position('push_counts')",
+        )
+        .bind(localpart)
+        .bind(room_id)
+        .bind(thread_id)
+        .bind(notifications)
+        .bind(highlights)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn clear_push_counts(
+        &self,
+        localpart: &str,
+        room_id: &str,
+        thread_id: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        // Counts already at zero are left alone, so sync doesn't send them
+        // again
+        sqlx::query(
+            "UPDATE push_counts
+             SET notification_count = 0, highlight_count = 0,
+                 stream_id = next_stream_position('push_counts')
+             WHERE localpart = $1 AND room_id = $2 AND ($3::TEXT IS NULL OR thread_id = $3)
+             AND (notification_count != 0 OR highlight_count != 0)",
+        )
+        .bind(localpart)
+        .bind(room_id)
+        .bind(thread_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_push_counts(
+        &self,
+        localpart: &str,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<PushCounts>, Box<dyn Error>> {
+        let rows: Vec<(String, String, i64, i64)> = sqlx::query_as(
+            "SELECT room_id, thread_id, notification_count, highlight_count FROM push_counts
+             WHERE localpart = $1 AND room_id IN
+             (SELECT room_id FROM push_counts
+              WHERE localpart = $1 AND stream_id > $2 AND stream_id <= $3)",
+        )
+        .bind(localpart)
+        .bind(from)
+        .bind(to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(room_id, thread_id, notification_count, highlight_count)| PushCounts {
+                    room_id,
+                    thread_id,
+                    notification_count,
+                    highlight_count,
+                },
+            )
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_unread_count(&self, localpart: &str) -> Result<i64, Box<dyn Error>> {
+        let row: (i64,) = sqlx::query_as(
+            "SELECT COALESCE(SUM(notification_count), 0)::BIGINT FROM push_counts
+             WHERE localpart = $1",
+        )
+        .bind(localpart)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn check_schema(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            "SELECT table_name::TEXT FROM information_schema.tables
+             WHERE table_schema = current_schema()",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let existing: HashSet<String> = rows.into_iter().map(|r| r.0).collect();
+        Ok(schema_tables()
+            .filter(|table| !existing.contains(*table))
+            .map(str::to_owned)
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_account(&self, localpart: &str) -> Result<Option<Account>, Box<dyn Error>> {
+        let row: Option<AccountRow> = sqlx::query_as(
+            "SELECT localpart, created_ts, is_admin, is_guest, deactivated, appservice_id,
+                    send_limit_exempt
+             FROM accounts WHERE localpart = $1",
+        )
+        .bind(localpart)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(account_from_row))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_accounts(
+        &self,
+        from: Option<&str>,
+        limit: i64,
+        guests: bool,
+        deactivated: bool,
+    ) -> Result<Vec<Account>, Box<dyn Error>> {
+        let rows: Vec<AccountRow> = sqlx::query_as(
+            "SELECT localpart, created_ts, is_admin, is_guest, deactivated, appservice_id,
+                    send_limit_exempt
+             FROM accounts
+             WHERE localpart > $1 AND ($2 OR NOT is_guest) AND ($3 OR NOT deactivated)
+             ORDER BY localpart LIMIT $4",
+        )
+        .bind(from.unwrap_or_default())
+        .bind(guests)
+        .bind(deactivated)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(account_from_row).collect())
+    }
+
+    #[tracing::instrument(skip(self, password_hash))]
+    async fn create_account(
+        &self,
+        localpart: &str,
+        password_hash: Option<&str>,
+        is_admin: bool,
+    ) -> Result<bool, Box<dyn Error>> {
+        let created = sqlx::query(
+            "INSERT INTO accounts (localpart, created_ts, password_hash, is_admin)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (localpart) DO NOTHING",
+        )
+        .bind(localpart)
+        .bind(now_ms())
+        .bind(password_hash)
+        .bind(is_admin)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(created == 1)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn create_appservice_account(
+        &self,
+        localpart: &str,
+        appservice_id: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let created = sqlx::query(
+            "INSERT INTO accounts (localpart, created_ts, is_admin, appservice_id)
+             VALUES ($1, $2, FALSE, $3)
+             ON CONFLICT (localpart) DO NOTHING",
+        )
+        .bind(localpart)
+        .bind(now_ms())
+        .bind(appservice_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(created == 1)
+    }
+
+    #[tracing::instrument(skip(self, password_hash))]
+    async fn set_password_hash(
+        &self,
+        localpart: &str,
+        password_hash: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("UPDATE accounts SET password_hash = $2 WHERE localpart = $1")
+            .bind(localpart)
+            .bind(password_hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set_account_deactivated(
+        &self,
+        localpart: &str,
+        deactivated: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("UPDATE accounts SET deactivated = $2 WHERE localpart = $1")
+            .bind(localpart)
+            .bind(deactivated)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set_send_limit_exempt(
+        &self,
+        localpart: &str,
+        exempt: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("UPDATE accounts SET send_limit_exempt = $2 WHERE localpart = $1")
+            .bind(localpart)
+            .bind(exempt)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn erase_user(&self, localpart: &str, user_id: &str) -> Result<(), Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE accounts SET password_hash = NULL WHERE localpart = $1")
+            .bind(localpart)
+            .execute(&mut tx)
+            .await?;
+        for table in ERASED_BY_LOCALPART.iter() {
+            sqlx::query(&format!("DELETE FROM {} WHERE localpart = $1", table))
+                .bind(localpart)
+                .execute(&mut tx)
+                .await?;
+        }
+        for (table, column) in ERASED_BY_USER_ID.iter() {
+            sqlx::query(&format!("DELETE FROM {} WHERE {} = $1", table, column))
+                .bind(user_id)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_admin_rooms(
+        &self,
+        from: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<AdminRoom>, Box<dyn Error>> {
+        let rows: Vec<(String, i64, bool)> = sqlx::query_as(
+            "SELECT r.room_id, COUNT(i.localpart), bool_or(b.room_id IS NOT NULL)
+             FROM (SELECT room_id FROM room_invites UNION SELECT room_id FROM blocked_rooms) r
+             LEFT JOIN room_invites i ON i.room_id = r.room_id
+             LEFT JOIN blocked_rooms b ON b.room_id = r.room_id
+             WHERE r.room_id > $1
+             GROUP BY r.room_id ORDER BY r.room_id LIMIT $2",
+        )
+        .bind(from.unwrap_or_default())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(room_id, invited_members, blocked)| AdminRoom {
+                room_id,
+                invited_members,
+                blocked,
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn block_room(&self, room_id: &str, blocked_by: &str) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO blocked_rooms (room_id, blocked_by, ts) VALUES ($1, $2, $3)
+             ON CONFLICT (room_id) DO NOTHING",
+        )
+        .bind(room_id)
+        .bind(blocked_by)
+        .bind(now_ms())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn is_room_blocked(&self, room_id: &str) -> Result<bool, Box<dyn Error>> {
+        let row: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM blocked_rooms WHERE room_id = $1")
+            .bind(room_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.0 > 0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_room_invitees(&self, room_id: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as("SELECT localpart FROM room_invites WHERE room_id = $1")
+                .bind(room_id)
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter().map(|r| r.0).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_room_invite(
+        &self,
+        localpart: &str,
+        room_id: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let deleted = sqlx::query("DELETE FROM room_invites WHERE localpart = $1 AND room_id = $2")
+            .bind(localpart)
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
+    #[tracing::instrument(skip(self, job))]
+    async fn add_admin_job(
+        &self,
+        room_id: &str,
+        job: &RoomJob,
+        created_by: &str,
+    ) -> Result<i64, Box<dyn Error>> {
+        let now = now_ms();
+        let row: (i64,) = sqlx::query_as(
+            "INSERT INTO admin_jobs
+                (room_id, job, status, progress, created_by, created_ts, updated_ts)
+             VALUES ($1, $2, $3, '{}', $4, $5, $5)
+             RETURNING job_id",
+        )
+        .bind(room_id)
+        .bind(serde_json::to_value(job)?)
+        .bind(JobStatus::Pending.as_str())
+        .bind(created_by)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_admin_job(&self, job_id: i64) -> Result<Option<AdminJob>, Box<dyn Error>> {
+        let row: Option<AdminJobRow> = sqlx::query_as(
+            "SELECT job_id, room_id, job, status, progress, error, created_by, created_ts,
+                    updated_ts
+             FROM admin_jobs WHERE job_id = $1",
+        )
+        .bind(job_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => Ok(Some(admin_job_from_row(row)?)),
+            None => Ok(None),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_unfinished_admin_jobs(&self) -> Result<Vec<i64>, Box<dyn Error>> {
+        let rows: Vec<(i64,)> = sqlx::query_as(
+            "SELECT job_id FROM admin_jobs WHERE status IN ($1, $2) ORDER BY job_id",
+        )
+        .bind(JobStatus::Pending.as_str())
+        .bind(JobStatus::Running.as_str())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.0).collect())
+    }
+
+    #[tracing::instrument(skip(self, progress))]
+    async fn update_admin_job(
+        &self,
+        job_id: i64,
+        status: JobStatus,
+        progress: &Value,
+        error: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "UPDATE admin_jobs SET status = $2, progress = $3, error = $4, updated_ts = $5
+             WHERE job_id = $1",
+        )
+        .bind(job_id)
+        .bind(status.as_str())
+        .bind(progress)
+        .bind(error)
+        .bind(now_ms())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_periodic_job(
+        &self,
+        kind: &str,
+        interval_ms: i64,
+        first_run_ts: i64,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO scheduled_jobs
+                (kind, payload, interval_ms, status, next_run_ts, failures, created_ts)
+             VALUES ($1, 'null', $2, $3, $4, 0, $5)
+             ON CONFLICT (kind) WHERE interval_ms IS NOT NULL DO UPDATE
+             SET interval_ms = $2,
+                 next_run_ts = LEAST(scheduled_jobs.next_run_ts, $5 + $2)",
+        )
+        .bind(kind)
+        .bind(interval_ms)
+        .bind(JobStatus::Pending.as_str())
+        .bind(first_run_ts)
+        .bind(now_ms())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, payload))]
+    async fn add_scheduled_job(
+        &self,
+        kind: &str,
+        payload: &Value,
+        run_ts: i64,
+    ) -> Result<i64, Box<dyn Error>> {
+        let row: (i64,) = sqlx::query_as(
+            "INSERT INTO scheduled_jobs
+                (kind, payload, status, next_run_ts, failures, created_ts)
+             VALUES ($1, $2, $3, $4, 0, $5)
+             RETURNING job_id",
+        )
+        .bind(kind)
+        .bind(payload)
+        .bind(JobStatus::Pending.as_str())
+        .bind(run_ts)
+        .bind(now_ms())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn claim_scheduled_jobs(
+        &self,
+        kinds: &[String],
+        now: i64,
+        lease_until: i64,
+        limit: i64,
+    ) -> Result<Vec<ScheduledJob>, Box<dyn Error>> {
+        let rows: Vec<ScheduledJobRow> = sqlx::query_as(
+            "UPDATE scheduled_jobs SET status = $4, next_run_ts = $3, last_run_ts = $2
+             WHERE job_id IN (
+                 SELECT job_id FROM scheduled_jobs
+                 WHERE kind = ANY($1) AND status IN ($4, $5) AND next_run_ts <= $2
+                 ORDER BY next_run_ts
+                 LIMIT $6
+                 FOR UPDATE SKIP LOCKED
+             )
+             RETURNING job_id, kind, payload, interval_ms, status, next_run_ts, last_run_ts,
+                       failures, last_error",
+        )
+        .bind(kinds.to_vec())
+        .bind(now)
+        .bind(lease_until)
+        .bind(JobStatus::Running.as_str())
+        .bind(JobStatus::Pending.as_str())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(scheduled_job_from_row).collect()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn finish_scheduled_job(
+        &self,
+        job_id: i64,
+        status: JobStatus,
+        next_run_ts: i64,
+        failures: i32,
+        error: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "UPDATE scheduled_jobs
+             SET status = $2, next_run_ts = $3, failures = $4, last_error = $5
+             WHERE job_id = $1",
+        )
+        .bind(job_id)
+        .bind(status.as_str())
+        .bind(next_run_ts)
+        .bind(failures)
+        .bind(error)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_scheduled_jobs(
+        &self,
+        params: &ListScheduledJobsParams,
+        limit: i64,
+    ) -> Result<Vec<ScheduledJob>, Box<dyn Error>> {
+        let rows: Vec<ScheduledJobRow> = sqlx::query_as(
+            "SELECT job_id, kind, payload, interval_ms, status, next_run_ts, last_run_ts,
+                    failures, last_error
+             FROM scheduled_jobs
+             WHERE ($1::TEXT IS NULL OR kind = $1) AND ($2::TEXT IS NULL OR status = $2)
+             ORDER BY job_id
+             LIMIT $3",
+        )
+        .bind(params.kind.as_deref())
+        .bind(params.status.map(JobStatus::as_str))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(scheduled_job_from_row).collect()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_finished_scheduled_jobs(&self, before_ts: i64) -> Result<u64, Box<dyn Error>> {
+        let deleted = sqlx::query(
+            "DELETE FROM scheduled_jobs
+             WHERE interval_ms IS NULL AND status IN ($1, $2) AND next_run_ts < $3",
+        )
+        .bind(JobStatus::Complete.as_str())
+        .bind(JobStatus::Failed.as_str())
+        .bind(before_ts)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(deleted)
+    }
+
+    #[tracing::instrument(skip(self, event))]
+    async fn add_appservice_event(
+        &self,
+        appservice_id: &str,
+        event: &Value,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("INSERT INTO appservice_outbound (appservice_id, event) VALUES ($1, $2)")
+            .bind(appservice_id)
+            .bind(event)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_appservice_events(
+        &self,
+        appservice_id: &str,
+        after: i64,
+        up_to: i64,
+        limit: i64,
+    ) -> Result<Vec<(i64, Value)>, Box<dyn Error>> {
+        let rows: Vec<(i64, Value)> = sqlx::query_as(
+            "SELECT stream_id, event FROM appservice_outbound
+             WHERE appservice_id = $1 AND stream_id > $2 AND stream_id <= $3
+             ORDER BY stream_id LIMIT $4",
+        )
+        .bind(appservice_id)
+        .bind(after)
+        .bind(up_to)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_appservice_events(
+        &self,
+        appservice_id: &str,
+        up_to: i64,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query("DELETE FROM appservice_outbound WHERE appservice_id = $1 AND stream_id <= $2")
+            .bind(appservice_id)
+            .bind(up_to)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_appservices_with_events(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as("SELECT DISTINCT appservice_id FROM appservice_outbound")
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter().map(|r| r.0).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_appservice_state(
+        &self,
+        appservice_id: &str,
+    ) -> Result<AppServiceState, Box<dyn Error>> {
+        let row: Option<(i64, i64, Option<i64>, i64, i64)> = sqlx::query_as(
+            "SELECT txn_id, last_stream_id, pending_stream_id, retry_last_ts, retry_interval
+             FROM appservice_state WHERE appservice_id = $1",
+        )
+        .bind(appservice_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map_or_else(
+            Default::default,
+            |(txn_id, last_stream_id, pending_stream_id, retry_last_ts, retry_interval)| {
+                AppServiceState {
+                    txn_id,
+                    last_stream_id,
+                    pending_stream_id,
+                    retry_last_ts,
+                    retry_interval,
+                }
+            },
+        ))
+    }
+
+    #[tracing::instrument(skip(self, state))]
+    async fn set_appservice_state(
+        &self,
+        appservice_id: &str,
+        state: &AppServiceState,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO appservice_state
+                (appservice_id, txn_id, last_stream_id, pending_stream_id, retry_last_ts,
+                 retry_interval)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (appservice_id) DO UPDATE
+             SET txn_id = $2, last_stream_id = $3, pending_stream_id = $4, retry_last_ts = $5,
+                 retry_interval = $6",
+        )
+        .bind(appservice_id)
+        .bind(state.txn_id)
+        .bind(state.last_stream_id)
+        .bind(state.pending_stream_id)
+        .bind(state.retry_last_ts)
+        .bind(state.retry_interval)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set_room_membership(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        membership: Option<&str>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let membership = match membership {
+            Some(membership) => membership,
+            None => {
+                let row: Option<(String,)> = sqlx::query_as(
+                    "DELETE FROM room_memberships WHERE room_id = $1 AND user_id = $2
+                     RETURNING membership",
+                )
+                .bind(room_id)
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+                return Ok(row.map(|r| r.0));
+            }
+        };
+        // Members keep their place among the heroes until they stop being
+        // members
+        let row: (Option<String>,) = sqlx::query_as(
+            "WITH old AS (
+                 SELECT membership FROM room_memberships WHERE room_id = $1 AND user_id = $2
+             )
+             INSERT INTO room_memberships (room_id, user_id, membership) VALUES ($1, $2, $3)
+             ON CONFLICT (room_id, user_id) DO UPDATE
+             SET membership = EXCLUDED.membership,
+                 stream_id = CASE
+                     WHEN room_memberships.membership IN ('join', 'invite')
+                         AND EXCLUDED.membership IN ('join', 'invite')
+                     THEN room_memberships.stream_id
+                     ELSE nextval('room_memberships_stream_id_seq')
+                 END
+             RETURNING (SELECT membership FROM old)",
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .bind(membership)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_room_membership(
+        &self,
+        room_id: &str,
+        user_id: &str,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let row: Option<(String,)> = sqlx::query_as(
+            "SELECT membership FROM room_memberships WHERE room_id = $1 AND user_id = $2",
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|r| r.0))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_room_heroes(
+        &self,
+        room_id: &str,
+        limit: usize,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            "SELECT user_id FROM room_memberships
+             WHERE room_id = $1 AND membership IN ('join', 'invite')
+             ORDER BY stream_id LIMIT $2",
+        )
+        .bind(room_id)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.0).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_room_summary(&self, room_id: &str) -> Result<Option<RoomSummary>, Box<dyn Error>> {
+        let row: Option<(Value, i64, i64)> = sqlx::query_as(
+            "SELECT heroes, joined_member_count, invited_member_count FROM room_summaries
+             WHERE room_id = $1",
+        )
+        .bind(room_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(match row {
+            Some((heroes, joined_member_count, invited_member_count)) => Some(RoomSummary {
+                heroes: serde_json::from_value(heroes)?,
+                joined_member_count,
+                invited_member_count,
+            }),
+            None => None,
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set_room_summary(
+        &self,
+        room_id: &str,
+        summary: &RoomSummary,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO room_summaries
+                 (room_id, heroes, joined_member_count, invited_member_count)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (room_id) DO UPDATE
+             SET heroes = EXCLUDED.heroes,
+                 joined_member_count = EXCLUDED.joined_member_count,
+                 invited_member_count = EXCLUDED.invited_member_count",
+        )
+        .bind(room_id)
+        .bind(Value::from(summary.heroes.clone()))
+        .bind(summary.joined_member_count)
+        .bind(summary.invited_member_count)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_token_revocation(
+        &self,
+        localpart: &str,
+    ) -> Result<Option<TokenRevocation>, Box<dyn Error>> {
+        let row: Option<TokenRevocationRow> = sqlx::query_as(
+            "SELECT localpart, revoked_ts, kept_device_id, kept_since_ts FROM token_revocations
+             WHERE localpart = $1",
+        )
+        .bind(localpart)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| token_revocation_from_row(row).1))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_token_revocations(
+        &self,
+        since_ts: i64,
+    ) -> Result<Vec<(String, TokenRevocation)>, Box<dyn Error>> {
+        let rows: Vec<TokenRevocationRow> = sqlx::query_as(
+            "SELECT localpart, revoked_ts, kept_device_id, kept_since_ts FROM token_revocations
+             WHERE revoked_ts > $1",
+        )
+        .bind(since_ts)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(token_revocation_from_row).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set_token_revocation(
+        &self,
+        localpart: &str,
+        revocation: &TokenRevocation,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO token_revocations (localpart, revoked_ts, kept_device_id, kept_since_ts)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (localpart) DO UPDATE
+             SET revoked_ts = EXCLUDED.revoked_ts, kept_device_id = EXCLUDED.kept_device_id,
+                 kept_since_ts = EXCLUDED.kept_since_ts",
+        )
+        .bind(localpart)
+        .bind(revocation.revoked_ts)
+        .bind(&revocation.kept_device_id)
+        .bind(revocation.kept_since_ts)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_third_party_invite(
+        &self,
+        invite: &ThirdPartyInvite,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO room_third_party_invites
+                 (room_id, token, sender, display_name, public_keys, created_ts)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             ON CONFLICT (room_id, token) DO NOTHING",
+        )
+        .bind(&invite.room_id)
+        .bind(&invite.token)
+        .bind(&invite.sender)
+        .bind(&invite.display_name)
+        .bind(serde_json::to_value(&invite.public_keys)?)
+        .bind(now_ms())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_third_party_invite(
+        &self,
+        room_id: &str,
+        token: &str,
+    ) -> Result<Option<ThirdPartyInvite>, Box<dyn Error>> {
+        let row: Option<(String, String, Value)> = sqlx::query_as(
+            "SELECT sender, display_name, public_keys FROM room_third_party_invites
+             WHERE room_id = $1 AND token = $2",
+        )
+        .bind(room_id)
+        .bind(token)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(match row {
+            Some((sender, display_name, public_keys)) => Some(ThirdPartyInvite {
+                room_id: room_id.to_owned(),
+                token: token.to_owned(),
+                sender,
+                display_name,
+                public_keys: serde_json::from_value(public_keys)?,
+            }),
+            None => None,
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_third_party_invite(
+        &self,
+        room_id: &str,
+        token: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        let deleted =
+            sqlx::query("DELETE FROM room_third_party_invites WHERE room_id = $1 AND token = $2")
+                .bind(room_id)
+                .bind(token)
+                .execute(&self.pool)
+                .await?;
+
+        Ok(deleted > 0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_public_rooms(
+        &self,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(Vec<PublicRoom>, i64), Box<dyn Error>> {
+        let rows: Vec<(String, Option<i64>, i64)> = sqlx::query_as(
+            "SELECT d.room_id, s.joined_member_count, COUNT(*) OVER ()
+             FROM room_directory d
+             LEFT JOIN room_summaries s ON s.room_id = d.room_id
+             WHERE d.room_id NOT IN (SELECT room_id FROM blocked_rooms)
+             ORDER BY s.joined_member_count DESC NULLS LAST, d.room_id
+             OFFSET $1 LIMIT $2",
+        )
+        .bind(offset)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let total = rows.first().map_or(0, |row| row.2);
+        let rooms = rows
+            .into_iter()
+            .map(|(room_id, joined_member_count, _)| PublicRoom {
+                room_id,
+                num_joined_members: joined_member_count.unwrap_or(0),
+                world_readable: false,
+                guest_can_join: false,
+                name: None,
+                topic: None,
+                canonical_alias: None,
+                aliases: Vec::new(),
+                avatar_url: None,
+            })
+            .collect();
+        Ok((rooms, total))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_event_report(&self, report: &EventReport) -> Result<i64, Box<dyn Error>> {
+        let row: (i64,) = sqlx::query_as(
+            "INSERT INTO event_reports
+                 (room_id, event_id, reporter, origin, reason, score, received_ts)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             RETURNING id",
+        )
+        .bind(&report.room_id)
+        .bind(&report.event_id)
+        .bind(&report.reporter)
+        .bind(&report.origin)
+        .bind(&report.reason)
+        .bind(report.score)
+        .bind(now_ms())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_ip_block(&self, block: &IpBlock) -> Result<i64, Box<dyn Error>> {
+        let row: (i64,) = sqlx::query_as(
+            "INSERT INTO ip_blocks (net, api, reason, created_ts, expires_ts)
+             VALUES ($1, $2, $3, $4, $5)
+             RETURNING id",
+        )
+        .bind(&block.net)
+        .bind(block.api.as_str())
+        .bind(&block.reason)
+        .bind(block.created_ts)
+        .bind(block.expires_ts)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_ip_blocks(&self, now_ts: i64) -> Result<Vec<IpBlock>, Box<dyn Error>> {
+        let rows: Vec<IpBlockRow> = sqlx::query_as(
+            "SELECT id, net, api, reason, created_ts, expires_ts FROM ip_blocks
+             WHERE expires_ts IS NULL OR expires_ts > $1
+             ORDER BY id",
+        )
+        .bind(now_ts)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().filter_map(ip_block_from_row).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_ip_block(&self, block_id: i64) -> Result<bool, Box<dyn Error>> {
+        let deleted = sqlx::query("DELETE FROM ip_blocks WHERE id = $1")
+            .bind(block_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn count_active_accounts(&self) -> Result<i64, Box<dyn Error>> {
+        let row: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM accounts WHERE NOT deactivated")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn count_rooms(&self) -> Result<i64, Box<dyn Error>> {
+        // TODO: Count the rooms table once rooms are stored
+        let row: (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM
+                 (SELECT room_id FROM room_summaries UNION SELECT room_id FROM room_invites) r",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_room_members(
+        &self,
+        room_id: &str,
+        from: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<RoomMember>, Box<dyn Error>> {
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            "SELECT user_id, membership FROM room_memberships
+             WHERE room_id = $1 AND user_id > $2
+             ORDER BY user_id LIMIT $3",
+        )
+        .bind(room_id)
+        .bind(from.unwrap_or_default())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(user_id, membership)| RoomMember {
+                user_id,
+                membership,
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self, members))]
+    async fn add_exported_members(
+        &self,
+        job_id: i64,
+        members: &[RoomMember],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        for member in members {
+            sqlx::query(
+                "INSERT INTO admin_job_members (job_id, user_id, membership)
+                 VALUES ($1, $2, $3)
+                 ON CONFLICT DO NOTHING",
+            )
+            .bind(job_id)
+            .bind(&member.user_id)
+            .bind(&member.membership)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_exported_members(&self, job_id: i64) -> Result<Vec<RoomMember>, Box<dyn Error>> {
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            "SELECT user_id, membership FROM admin_job_members
+             WHERE job_id = $1 ORDER BY user_id",
+        )
+        .bind(job_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(user_id, membership)| RoomMember {
+                user_id,
+                membership,
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_received_pdus(&self, event_ids: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as("SELECT event_id FROM received_pdus WHERE event_id = ANY($1)")
+                .bind(event_ids.to_vec())
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter().map(|(event_id,)| event_id).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_received_pdus(
+        &self,
+        origin: &str,
+        event_ids: &[String],
+    ) -> Result<(), Box<dyn Error>> {
+        let now = now_ms();
+        let mut tx = self.pool.begin().await?;
+        for event_id in event_ids {
+            sqlx::query(
+                "INSERT INTO received_pdus (event_id, origin, ts_added_ms)
+                 VALUES ($1, $2, $3)
+                 ON CONFLICT DO NOTHING",
+            )
+            .bind(event_id)
+            .bind(origin)
+            .bind(now)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_received_before(&self, before_ts: i64) -> Result<u64, Box<dyn Error>> {
+        let mut tx = self.pool.begin().await?;
+        let transactions = sqlx::query("DELETE FROM received_transactions WHERE ts_added_ms < $1")
+            .bind(before_ts)
+            .execute(&mut tx)
+            .await?;
+        let pdus = sqlx::query("DELETE FROM received_pdus WHERE ts_added_ms < $1")
+            .bind(before_ts)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(transactions + pdus)
+    }
+
+    #[tracing::instrument(skip(self, session))]
+    async fn add_uia_session(
+        &self,
+        session_id: &str,
+        session: &UiaSession,
+    ) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            "INSERT INTO uia_sessions (session_id, localpart, device_id, operation, created_ts)
+             VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(session_id)
+        .bind(&session.localpart)
+        .bind(&session.device_id)
+        .bind(&session.operation)
+        .bind(session.created_ts)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_uia_session(
+        &self,
+        session_id: &str,
+    ) -> Result<Option<UiaSession>, Box<dyn Error>> {
+        let row: Option<(String, String, String, i64)> = sqlx::query_as(
+            "SELECT localpart, device_id, operation, created_ts FROM uia_sessions
+             WHERE session_id = $1",
+        )
+        .bind(session_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(
+            row.map(|(localpart, device_id, operation, created_ts)| UiaSession {
+                localpart,
+                device_id,
+                operation,
+                created_ts,
+            }),
+        )
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_uia_session(&self, session_id: &str) -> Result<bool, Box<dyn Error>> {
+        let deleted = sqlx::query("DELETE FROM uia_sessions WHERE session_id = $1")
+            .bind(session_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_uia_sessions_before(&self, before_ts: i64) -> Result<u64, Box<dyn Error>> {
+        let deleted = sqlx::query("DELETE FROM uia_sessions WHERE created_ts < $1")
+            .bind(before_ts)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(deleted)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_device(
+        &self,
+        localpart: &str,
+        device_id: &str,
+        display_name: Option<&str>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let added = sqlx::query(
+            "INSERT INTO devices (localpart, device_id, display_name) VALUES ($1, $2, $3)
+             ON CONFLICT (localpart, device_id) DO NOTHING",
+        )
+        .bind(localpart)
+        .bind(device_id)
+        .bind(display_name)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(added == 1)
+    }
+}
+/// The tables `erase_user` erases a user's rows from, by localpart.
+const ERASED_BY_LOCALPART: [&str; 18] = [
+    "account_data",
+    "profiles",
+    "ignored_users",
+    "e2e_device_keys",
+    "e2e_one_time_keys",
+    "e2e_fallback_keys",
+    "devices",
+    "device_inbox",
+    "e2e_cross_signing_keys",
+    "e2e_room_keys_versions",
+    "e2e_room_keys",
+    "dehydrated_devices",
+    "room_invites",
+    "push_rules",
+    "pushers",
+    "push_outbound",
+    "push_counts",
+    "uia_sessions",
+];
+
+/// The tables `erase_user` erases a user's rows from, and the column holding
+/// their fully qualified ID.
+const ERASED_BY_USER_ID: [(&str, &str); 5] = [
+    ("admin_job_members", "user_id"),
+    ("device_inbox_txns", "sender"),
+    ("e2e_cross_signing_signatures", "signer"),
+    ("e2e_cross_signing_signatures", "target_user_id"),
+    ("presence", "user_id"),
+];
+
+/// A row of the `accounts` table.
+type AccountRow = (String, i64, bool, bool, bool, Option<String>, bool);
+
+fn account_from_row(row: AccountRow) -> Account {
+    Account {
+        localpart: row.0,
+        created_ts: row.1,
+        is_admin: row.2,
+        is_guest: row.3,
+        deactivated: row.4,
+        appservice_id: row.5,
+        send_limit_exempt: row.6,
+    }
+}
+
+/// A row of the `admin_jobs` table.
+type AdminJobRow = (
+    i64,
+    String,
+    Value,
+    String,
+    Value,
+    Option<String>,
+    String,
+    i64,
+    i64,
+);
+
+fn admin_job_from_row(row: AdminJobRow) -> Result<AdminJob, Box<dyn Error>> {
+    Ok(AdminJob {
+        job_id: row.0,
+        room_id: row.1,
+        job: serde_json::from_value(row.2)?,
+        status: JobStatus::parse(&row.3).ok_or_else(|| format!("Unknown job status {}", row.3))?,
+        progress: row.4,
+        error: row.5,
+        created_by: row.6,
+        created_ts: row.7,
+        updated_ts: row.8,
+    })
+}
+
+/// A row of the `scheduled_jobs` table.
+type ScheduledJobRow = (
+    i64,
+    String,
+    Value,
+    Option<i64>,
+    String,
+    i64,
+    Option<i64>,
+    i32,
+    Option<String>,
+);
+
+fn scheduled_job_from_row(row: ScheduledJobRow) -> Result<ScheduledJob, Box<dyn Error>> {
+    Ok(ScheduledJob {
+        job_id: row.0,
+        kind: row.1,
+        payload: row.2,
+        interval_ms: row.3,
+        status: JobStatus::parse(&row.4).ok_or_else(|| format!("Unknown job status {}", row.4))?,
+        next_run_ts: row.5,
+        last_run_ts: row.6,
+        failures: row.7,
+        last_error: row.8,
+    })
+}
+
+/// A row of the `local_media` table.
+type LocalMediaRow = (String, String, i64, Option<String>, String, i64);
+
+fn local_media_from_row(row: LocalMediaRow) -> LocalMedia {
+    let (media_id, content_type, media_length, upload_name, user_id, created_ts) = row;
+    LocalMedia {
+        media_id,
+        content_type,
+        media_length,
+        upload_name,
+        user_id,
+        created_ts,
+    }
+}
+
+/// A row of the `remote_media` table.
+type RemoteMediaRow = (
+    String,
+    String,
+    String,
+    String,
+    i64,
+    Option<String>,
+    i64,
+    i64,
+);
+
+fn remote_media_from_row(row: RemoteMediaRow) -> RemoteMedia {
+    let (
+        origin,
+        media_id,
+        file_id,
+        content_type,
+        media_length,
+        upload_name,
+        created_ts,
+        last_access_ts,
+    ) = row;
+    RemoteMedia {
+        origin,
+        media_id,
+        file_id,
+        content_type,
+        media_length,
+        upload_name,
+        created_ts,
+        last_access_ts,
+    }
+}
+
+/// A row of the `token_revocations` table.
+type TokenRevocationRow = (String, i64, Option<String>, i64);
+
+fn token_revocation_from_row(row: TokenRevocationRow) -> (String, TokenRevocation) {
+    (
+        row.0,
+        TokenRevocation {
+            revoked_ts: row.1,
+            kept_device_id: row.2,
+            kept_since_ts: row.3,
+        },
+    )
+}
+
+/// A row of the `ip_blocks` table.
+type IpBlockRow = (i64, String, String, Option<String>, i64, Option<i64>);
+
+/// Reads an IP block, unless it is of an API this version doesn't know.
+fn ip_block_from_row(row: IpBlockRow) -> Option<IpBlock> {
+    Some(IpBlock {
+        block_id: row.0,
+        net: row.1,
+        api: Api::parse(&row.2)?,
+        reason: row.3,
+        created_ts: row.4,
+        expires_ts: row.5,
+    })
+}
+
+/// Tables holding per-device data, cleared when a device is removed.
+const DEVICE_TABLES: &[&str] = &[
+    "devices",
+    "e2e_device_keys",
+    "e2e_one_time_keys",
+    "e2e_fallback_keys",
+    "device_inbox",
+];
+
+/// The tables `schema/postgres.sql` creates.
+fn schema_tables() -> impl Iterator<Item = &'static str> {
+    include_str!("../../schema/postgres.sql")
+        .lines()
+        .filter_map(|line| line.strip_prefix("CREATE TABLE IF NOT EXISTS "))
+        .filter_map(|rest| rest.split_whitespace().next())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_tables() {
+        let tables: Vec<&str> = schema_tables().collect();
+        assert!(tables.contains(&"accounts"));
+        assert!(tables.contains(&"push_counts"));
+        assert!(tables.iter().all(|table| !table.contains('(')));
+    }
 }
@@ -0,0 +1,212 @@
+//! Finding slow storage calls.
+//!
+//! When `SLOW_QUERY_THRESHOLD` is set, every call to the `Store` that takes
+//! at least that long is logged along with the request it was made for, and
+//! counted by query in the metrics served at `/metrics`. Calls are timed by
+//! their spans, from when they are made to when they return, so time spent
+//! waiting for a connection counts too.
+//!
+//! Each process counts its own calls, from when it starts.
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::{
+    field::{Field, Visit},
+    span, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// The target of the spans storage calls are made in.
+const STORE_TARGET: &str = "maelstrom::db::postgres";
+/// The name of the span each request is handled in.
+const REQUEST_SPAN: &str = "request";
+
+lazy_static::lazy_static! {
+    static ref COUNTERS: Mutex<BTreeMap<&'static str, Counter>> = Mutex::new(BTreeMap::new());
+}
+
+/// The slow calls to one query.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Counter {
+    count: u64,
+    seconds: f64,
+}
+
+/// The request a storage call was made for.
+#[derive(Clone, Debug, Default)]
+struct Request {
+    id: String,
+    method: String,
+    path: String,
+}
+
+impl Visit for Request {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "request_id" => self.id = value.to_owned(),
+            "method" => self.method = value.to_owned(),
+            "path" => self.path = value.to_owned(),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+/// When a storage call was made, and for which request, if any.
+struct Call {
+    start: Instant,
+    request: Option<Request>,
+}
+
+/// Times storage calls, and logs and counts those that take at least
+/// `threshold`.
+pub struct SlowQueries {
+    threshold: Duration,
+}
+
+impl SlowQueries {
+    pub fn new(threshold: Duration) -> Self {
+        SlowQueries { threshold }
+    }
+}
+
+impl<S> Layer<S> for SlowQueries
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    fn new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let metadata = attrs.metadata();
+        if metadata.name() == REQUEST_SPAN {
+            let mut request = Request::default();
+            attrs.record(&mut request);
+            span.extensions_mut().insert(request);
+        } else if metadata.target() == STORE_TARGET {
+            let request = span
+                .parents()
+                .find_map(|parent| parent.extensions().get::<Request>().cloned());
+            span.extensions_mut().insert(Call {
+                start: Instant::now(),
+                request,
+            });
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let (query, call) = {
+            let span = match ctx.span(&id) {
+                Some(span) => span,
+                None => return,
+            };
+            let call = span.extensions_mut().remove::<Call>();
+            match call {
+                Some(call) => (span.name(), call),
+                None => return,
+            }
+        };
+        let elapsed = call.start.elapsed();
+        if elapsed < self.threshold {
+            return;
+        }
+        count(query, elapsed);
+
+        let elapsed_ms = elapsed.as_millis() as u64;
+        match call.request {
+            Some(request) => tracing::warn!(
+                query,
+                elapsed_ms,
+                request_id = %request.id,
+                endpoint = %format_args!("{} {}", request.method, request.path),
+                "Slow query"
+            ),
+            None => tracing::warn!(query, elapsed_ms, "Slow query"),
+        }
+    }
+}
+
+/// Counts a slow call to `query`.
+fn count(query: &'static str, elapsed: Duration) {
+    let mut counters = COUNTERS.lock().unwrap();
+    let counter = counters.entry(query).or_default();
+    counter.count += 1;
+    counter.seconds += elapsed.as_secs_f64();
+}
+
+/// The slow query counters, in the Prometheus text format.
+pub fn metrics() -> String {
+    render(&COUNTERS.lock().unwrap())
+}
+
+fn render(counters: &BTreeMap<&'static str, Counter>) -> String {
+    let mut out = String::new();
+    out.push_str(
+        "# HELP maelstrom_slow_queries_total Storage calls at least as slow as the threshold.\n\
+         # TYPE maelstrom_slow_queries_total counter\n",
+    );
+    for (query, counter) in counters {
+        let _ = writeln!(
+            out,
+            "maelstrom_slow_queries_total{{query=\"{}\"}} {}",
+            query, counter.count
+        );
+    }
+    out.push_str(
+        "# HELP maelstrom_slow_query_seconds_total Time spent in slow storage calls.\n\
+         # TYPE maelstrom_slow_query_seconds_total counter\n",
+    );
+    for (query, counter) in counters {
+        let _ = writeln!(
+            out,
+            "maelstrom_slow_query_seconds_total{{query=\"{}\"}} {}",
+            query, counter.seconds
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_render() {
+        let mut counters = BTreeMap::new();
+        counters.insert(
+            "get_account",
+            Counter {
+                count: 2,
+                seconds: 1.5,
+            },
+        );
+        let text = render(&counters);
+        assert!(text.contains("# TYPE maelstrom_slow_queries_total counter\n"));
+        assert!(text.contains("maelstrom_slow_queries_total{query=\"get_account\"} 2\n"));
+        assert!(text.contains("maelstrom_slow_query_seconds_total{query=\"get_account\"} 1.5\n"));
+    }
+
+    #[test]
+    fn test_counts_slow_calls() {
+        let subscriber =
+            tracing_subscriber::registry().with(SlowQueries::new(Duration::from_secs(0)));
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request", method = %"GET", path = %"/");
+            let _entered = request.enter();
+            tracing::info_span!(target: "maelstrom::db::postgres", "test_slow_call")
+                .in_scope(|| {});
+            // Only storage calls are timed
+            tracing::info_span!("test_other_call").in_scope(|| {});
+        });
+        let counters = COUNTERS.lock().unwrap();
+        assert_eq!(counters["test_slow_call"].count, 1);
+        assert!(!counters.contains_key("test_other_call"));
+    }
+}
@@ -0,0 +1,192 @@
+//! Deciding which servers may be federated with: server access control
+//! lists, which rooms use to keep servers from taking part in them, and the
+//! server-wide allow and deny lists.
+use std::net::Ipv4Addr;
+
+use serde::Deserialize;
+
+/// The content of an `m.room.server_acl` state event.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ServerAcl {
+    /// Globs of the servers allowed in the room. Servers not matching any
+    /// are denied.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Globs of the servers denied, overriding `allow`.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Whether servers named by an IP address rather than a hostname are
+    /// allowed.
+    #[serde(default = "default_allow_ip_literals")]
+    pub allow_ip_literals: bool,
+}
+
+fn default_allow_ip_literals() -> bool {
+    true
+}
+
+impl ServerAcl {
+    /// Whether a server may take part in the room: send events to it, or
+    /// be sent its events. Ports are not part of what the globs match.
+    ///
+    /// TODO: Look the ACL up from the room's current state when handling
+    /// PDUs and EDUs, and when picking the servers to send a room's events
+    /// to, once rooms exist.
+    pub fn is_allowed(&self, server_name: &str) -> bool {
+        let host = strip_port(server_name);
+        if !self.allow_ip_literals && is_ip_literal(host) {
+            return false;
+        }
+        if self.deny.iter().any(|glob| glob_match(glob, host)) {
+            return false;
+        }
+        self.allow.iter().any(|glob| glob_match(glob, host))
+    }
+}
+
+/// Which servers this server federates with at all, as configured.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DomainPolicy {
+    /// Globs of the servers federated with. If empty, all servers are.
+    pub allow: Vec<String>,
+    /// Globs of the servers never federated with, overriding `allow`.
+    pub deny: Vec<String>,
+}
+
+impl DomainPolicy {
+    /// Parses comma separated lists of globs.
+    pub fn parse(allow: &str, deny: &str) -> Self {
+        let globs = |list: &str| -> Vec<String> {
+            list.split(',')
+                .map(|glob| glob.trim().to_owned())
+                .filter(|glob| !glob.is_empty())
+                .collect()
+        };
+        DomainPolicy {
+            allow: globs(allow),
+            deny: globs(deny),
+        }
+    }
+
+    /// Whether requests may be sent to or accepted from a server. Ports are
+    /// not part of what the globs match.
+    pub fn is_allowed(&self, server_name: &str) -> bool {
+        let host = strip_port(server_name);
+        if self.deny.iter().any(|glob| glob_match(glob, host)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|glob| glob_match(glob, host))
+    }
+}
+
+/// Removes the port from a server name, if it has one.
+fn strip_port(server_name: &str) -> &str {
+    // IPv6 literals are bracketed, so a port follows the last `]`
+    let host_end = server_name.rfind(']').unwrap_or(0);
+    match server_name[host_end..].rfind(':') {
+        Some(i) => &server_name[..host_end + i],
+        None => server_name,
+    }
+}
+
+/// Whether a host is an IPv4 address or a bracketed IPv6 address.
+fn is_ip_literal(host: &str) -> bool {
+    host.starts_with('[') || host.parse::<Ipv4Addr>().is_ok()
+}
+
+/// Matches a server name against a glob, where `*` matches any number of
+/// characters and `?` any one character. Server names are matched without
+/// regard to case, as DNS does.
+pub fn glob_match(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.to_lowercase().chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+    let (mut g, mut n) = (0, 0);
+    // Where to resume after the last `*`, if the match after it fails
+    let mut backtrack = None;
+    while n < name.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g, n));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    g = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", "example.com"));
+        assert!(glob_match("*.example.com", "matrix.example.com"));
+        assert!(!glob_match("*.example.com", "example.com"));
+        assert!(glob_match("ex?mple.com", "EXAMPLE.com"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*a*b", "xxaxxbxx"));
+        assert!(!glob_match("example.com", "example.co"));
+    }
+
+    #[test]
+    fn test_server_acl() {
+        let acl: ServerAcl = serde_json::from_value(serde_json::json!({
+            "allow": ["*"],
+            "deny": ["*.evil.com", "evil.com"],
+            "allow_ip_literals": false,
+        }))
+        .unwrap();
+        assert!(acl.is_allowed("example.com"));
+        assert!(acl.is_allowed("example.com:8448"));
+        assert!(!acl.is_allowed("evil.com:8448"));
+        assert!(!acl.is_allowed("matrix.evil.com"));
+        assert!(!acl.is_allowed("1.2.3.4"));
+        assert!(!acl.is_allowed("[::1]:8448"));
+    }
+
+    #[test]
+    fn test_server_acl_defaults() {
+        // An ACL allowing nothing denies everyone, including its own server
+        let acl: ServerAcl = serde_json::from_str("{}").unwrap();
+        assert!(!acl.is_allowed("example.com"));
+
+        let acl: ServerAcl = serde_json::from_str(r#"{"allow": ["*"]}"#).unwrap();
+        assert!(acl.is_allowed("1.2.3.4:8448"));
+    }
+
+    #[test]
+    fn test_domain_policy() {
+        let open = DomainPolicy::parse("", "");
+        assert!(open.is_allowed("example.com"));
+
+        let partners = DomainPolicy::parse("partner.com, *.partner.org", "bad.partner.org");
+        assert!(partners.is_allowed("partner.com:8448"));
+        assert!(partners.is_allowed("matrix.partner.org"));
+        assert!(!partners.is_allowed("bad.partner.org"));
+        assert!(!partners.is_allowed("example.com"));
+
+        let isolated = DomainPolicy::parse("", "*");
+        assert!(!isolated.is_allowed("example.com"));
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("example.com:8448"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:8448"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+}
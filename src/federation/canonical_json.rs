@@ -0,0 +1,203 @@
+//! Canonical JSON, the encoding everything that is signed or hashed is
+//! serialized with, so that every server computes the same bytes.
+use std::fmt;
+
+use serde_json::{value::RawValue, Number, Value};
+
+/// The largest integer Canonical JSON may contain. Integers are limited to
+/// those a double can represent exactly.
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+/// Why a value can't be encoded as Canonical JSON.
+#[derive(Debug, PartialEq)]
+pub enum CanonicalJsonError {
+    /// The value contains a number with a fractional part
+    Float,
+    /// The value contains an integer outside of `[-(2**53)+1, (2**53)-1]`
+    IntegerOutOfRange,
+}
+
+impl fmt::Display for CanonicalJsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CanonicalJsonError::Float => write!(f, "Canonical JSON may not contain floats"),
+            CanonicalJsonError::IntegerOutOfRange => {
+                write!(f, "Canonical JSON integers must be within ±(2**53)-1")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CanonicalJsonError {}
+
+/// Encodes a JSON value as Canonical JSON: no insignificant whitespace,
+/// object keys sorted by codepoint and whole numbers written as integers.
+pub fn encode(value: &Value) -> String {
+    let mut out = String::new();
+    write_canonical(value, &mut out);
+    out
+}
+
+/// Encodes JSON text as Canonical JSON, e.g. an event kept as the text it
+/// was received as.
+pub fn encode_raw(json: &RawValue) -> Result<String, serde_json::Error> {
+    let value: Value = serde_json::from_str(json.get())?;
+    Ok(encode(&value))
+}
+
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(value, out);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(key, out);
+                out.push(':');
+                write_canonical(value, out);
+            }
+            out.push('}');
+        }
+        Value::String(string) => write_string(string, out),
+        Value::Number(number) => out.push_str(&write_number(number)),
+        Value::Bool(true) => out.push_str("true"),
+        Value::Bool(false) => out.push_str("false"),
+        Value::Null => out.push_str("null"),
+    }
+}
+
+/// Writes a string, escaping only what JSON requires. Other characters,
+/// however exotic, are written as UTF-8.
+fn write_string(string: &str, out: &mut String) {
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Writes whole floats such as `1e10` or `-0.0` as the integers they equal.
+fn write_number(number: &Number) -> String {
+    match number.as_f64() {
+        Some(float)
+            if !number.is_i64()
+                && !number.is_u64()
+                && float.fract() == 0.0
+                && float.abs() <= MAX_SAFE_INTEGER as f64 =>
+        {
+            (float as i64).to_string()
+        }
+        _ => number.to_string(),
+    }
+}
+
+/// Checks that a value only contains numbers Canonical JSON allows, as
+/// events in room versions 6 and later must.
+pub fn check(value: &Value) -> Result<(), CanonicalJsonError> {
+    match value {
+        Value::Array(values) => values.iter().try_for_each(check),
+        Value::Object(map) => map.values().try_for_each(check),
+        Value::Number(number) => match number.as_i64() {
+            Some(int) if int.abs() <= MAX_SAFE_INTEGER => Ok(()),
+            Some(_) => Err(CanonicalJsonError::IntegerOutOfRange),
+            None if number.is_u64() => Err(CanonicalJsonError::IntegerOutOfRange),
+            None => Err(CanonicalJsonError::Float),
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// The examples from the Canonical JSON appendix of the spec.
+    #[test]
+    fn test_encode_spec_examples() {
+        let examples = [
+            (r#"{}"#, r#"{}"#),
+            (r#"{"one": 1, "two": "Two"}"#, r#"{"one":1,"two":"Two"}"#),
+            (r#"{"b": "2", "a": "1"}"#, r#"{"a":"1","b":"2"}"#),
+            (
+                r#"{
+                    "auth": {
+                        "success": true,
+                        "mxid": "@john.doe:example.com",
+                        "profile": {
+                            "display_name": "John Doe",
+                            "three_pids": [
+                                {"medium": "email", "address": "john.doe@example.org"},
+                                {"medium": "msisdn", "address": "123456789"}
+                            ]
+                        }
+                    }
+                }"#,
+                r#"{"auth":{"mxid":"@john.doe:example.com","profile":{"display_name":"John Doe","three_pids":[{"address":"john.doe@example.org","medium":"email"},{"address":"123456789","medium":"msisdn"}]},"success":true}}"#,
+            ),
+            (r#"{"a": "日本語"}"#, r#"{"a":"日本語"}"#),
+            (r#"{"本": 2, "日": 1}"#, r#"{"日":1,"本":2}"#),
+            (r#"{"a": "日"}"#, r#"{"a":"日"}"#),
+            (r#"{"a": null}"#, r#"{"a":null}"#),
+            (r#"{"a": -0, "b": 1e10}"#, r#"{"a":0,"b":10000000000}"#),
+        ];
+        for (input, expected) in examples.iter() {
+            let value: Value = serde_json::from_str(input).unwrap();
+            assert_eq!(encode(&value), *expected);
+        }
+    }
+
+    #[test]
+    fn test_encode_escapes() {
+        assert_eq!(
+            encode(&json!({"a": "\u{1}\n\"\\", "\u{1f}\t": "\u{8}\u{c}\r/"})),
+            r#"{"\u001f\t":"\b\f\r/","a":"\u0001\n\"\\"}"#
+        );
+    }
+
+    #[test]
+    fn test_encode_raw() {
+        let raw = RawValue::from_string(r#"{ "b": [1, 2.0], "a": "\u00e9" }"#.to_owned()).unwrap();
+        assert_eq!(encode_raw(&raw).unwrap(), r#"{"a":"é","b":[1,2]}"#);
+    }
+
+    #[test]
+    fn test_check() {
+        assert_eq!(check(&json!({"a": [1, -1, MAX_SAFE_INTEGER]})), Ok(()));
+        assert_eq!(
+            check(&json!({"a": {"b": 1.5}})),
+            Err(CanonicalJsonError::Float)
+        );
+        assert_eq!(
+            check(&json!([MAX_SAFE_INTEGER + 1])),
+            Err(CanonicalJsonError::IntegerOutOfRange)
+        );
+        assert_eq!(
+            check(&json!(u64::MAX)),
+            Err(CanonicalJsonError::IntegerOutOfRange)
+        );
+    }
+}
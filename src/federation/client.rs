@@ -0,0 +1,52 @@
+//! Sending requests to other homeservers.
+use std::time::Duration;
+
+use actix_web::{
+    client::{Client, ClientRequest},
+    http::{header, Method},
+};
+use serde_json::Value;
+
+use super::{
+    canonical_json, resolve,
+    xmatrix::{self, XMatrix},
+};
+use crate::CONFIG;
+
+/// How long to wait for another server to respond.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Builds an unauthenticated request to another server, such as for its
+/// published keys. `uri` is the path and query string of the request.
+pub async fn unsigned_request(method: Method, destination: &str, uri: &str) -> ClientRequest {
+    let resolved = resolve::resolve(destination).await;
+    Client::build()
+        .timeout(TIMEOUT)
+        .finish()
+        .request(method, format!("{}{}", resolved.base_url, uri))
+        .set_header(header::HOST, resolved.host)
+}
+
+/// Builds a request to another server, signed with `X-Matrix`
+/// authorization. `uri` is the path and query string of the request, and
+/// `content` the JSON body it will be sent with, if any.
+pub async fn request(
+    method: Method,
+    destination: &str,
+    uri: &str,
+    content: Option<&Value>,
+) -> ClientRequest {
+    let signable =
+        xmatrix::signable_request(method.as_str(), uri, &CONFIG.hostname, destination, content);
+    let auth = XMatrix {
+        origin: CONFIG.hostname.clone(),
+        destination: Some(destination.to_owned()),
+        key: CONFIG.signing_key.key_id.clone(),
+        sig: CONFIG
+            .signing_key
+            .sign(canonical_json::encode(&signable).as_bytes()),
+    };
+    unsigned_request(method, destination, uri)
+        .await
+        .header(header::AUTHORIZATION, auth.to_string())
+}
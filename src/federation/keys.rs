@@ -0,0 +1,295 @@
+//! Fetching and caching the keys other servers sign with.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use actix_web::http::{Method, StatusCode};
+use serde_json::{json, Value};
+
+use super::{
+    client, resolve,
+    signing::{self, ED25519},
+};
+use crate::{
+    clock::now_ms,
+    db::Store,
+    models::federation::{KeyQueryResponse, ServerKey, ServerKeys},
+    CONFIG,
+};
+
+/// How long to wait for a server to publish its keys.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// The largest key response accepted.
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+/// The longest a key is trusted for after it was fetched, whatever the
+/// server says, in milliseconds.
+const MAX_VALIDITY: i64 = 7 * 24 * 60 * 60 * 1000;
+
+#[derive(Debug)]
+pub enum KeyError {
+    /// The server couldn't be reached or gave a bad response
+    Request(String),
+    /// The server's response was not validly signed
+    Invalid(&'static str),
+    /// None of the server's keys can be trusted at the time asked about
+    NoValidKey,
+    /// Federation with the server is not allowed
+    Denied,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyError::Request(e) => write!(f, "Unable to fetch server keys: {}", e),
+            KeyError::Invalid(e) => write!(f, "Invalid server keys: {}", e),
+            KeyError::NoValidKey => write!(f, "No server key is valid at the time of signing"),
+            KeyError::Denied => write!(f, "Federation with this server is not allowed"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Gets the keys a server's signatures made at `ts` can be checked with, as
+/// key ID to public key.
+///
+/// Keys are cached. When none of the cached keys are valid at `ts`, fresh
+/// keys are fetched from the server itself, or failing that, from the
+/// trusted notary servers.
+pub async fn get_verify_keys<T: Store>(
+    storage: &T,
+    server_name: &str,
+    ts: i64,
+) -> Result<BTreeMap<String, Vec<u8>>, KeyError> {
+    if server_name == CONFIG.hostname {
+        let mut keys = BTreeMap::new();
+        let public_key = signing::decode_base64(&CONFIG.signing_key.public_key());
+        keys.insert(
+            CONFIG.signing_key.key_id.clone(),
+            public_key.unwrap_or_default(),
+        );
+        return Ok(keys);
+    }
+    if !CONFIG.federation_policy.is_allowed(server_name) {
+        return Err(KeyError::Denied);
+    }
+
+    let cached = storage
+        .get_server_keys(server_name)
+        .await
+        .map_err(|e| KeyError::Request(e.to_string()))?;
+    let keys = valid_at(&cached, ts);
+    if !keys.is_empty() {
+        return Ok(keys);
+    }
+
+    let mut fetched = fetch_direct(server_name).await;
+    for notary in &CONFIG.trusted_key_servers {
+        if fetched.is_ok() {
+            break;
+        }
+        if notary != server_name && CONFIG.federation_policy.is_allowed(notary) {
+            fetched = fetch_from_notary(storage, notary, server_name).await;
+        }
+    }
+    let fetched = fetched?;
+    storage
+        .set_server_keys(server_name, &fetched)
+        .await
+        .map_err(|e| KeyError::Request(e.to_string()))?;
+
+    let keys = valid_at(&fetched, ts);
+    if keys.is_empty() {
+        return Err(KeyError::NoValidKey);
+    }
+    Ok(keys)
+}
+
+/// Picks out the keys that were valid at `ts`, decoding them.
+fn valid_at(keys: &[ServerKey], ts: i64) -> BTreeMap<String, Vec<u8>> {
+    keys.iter()
+        .filter(|key| match key.expired_ts {
+            Some(expired_ts) => ts < expired_ts,
+            None => ts <= key.valid_until_ts,
+        })
+        .filter_map(|key| Some((key.key_id.clone(), signing::decode_base64(&key.key)?)))
+        .collect()
+}
+
+/// Checks a server's published keys, which must be signed by every key the
+/// server currently uses, and converts them for caching.
+fn parse_server_keys(
+    body: &Value,
+    server_name: &str,
+    now: i64,
+) -> Result<Vec<ServerKey>, KeyError> {
+    let server_keys: ServerKeys = serde_json::from_value(body.clone())
+        .map_err(|_| KeyError::Invalid("Malformed response"))?;
+    if server_keys.server_name != server_name {
+        return Err(KeyError::Invalid("Keys are for another server"));
+    }
+
+    let valid_until_ts = server_keys.valid_until_ts.min(now + MAX_VALIDITY);
+    let mut keys = Vec::new();
+    for (key_id, verify_key) in server_keys.verify_keys {
+        if !key_id.starts_with(&format!("{}:", ED25519)) {
+            continue;
+        }
+        let public_key =
+            signing::decode_base64(&verify_key.key).ok_or(KeyError::Invalid("Malformed key"))?;
+        signing::verify_json(body, server_name, &key_id, &public_key)
+            .map_err(|_| KeyError::Invalid("Keys are not self-signed"))?;
+        keys.push(ServerKey {
+            key_id,
+            key: verify_key.key,
+            valid_until_ts,
+            expired_ts: None,
+        });
+    }
+    for (key_id, old_key) in server_keys.old_verify_keys {
+        if key_id.starts_with(&format!("{}:", ED25519)) {
+            keys.push(ServerKey {
+                key_id,
+                key: old_key.key,
+                valid_until_ts: old_key.expired_ts,
+                expired_ts: Some(old_key.expired_ts),
+            });
+        }
+    }
+    Ok(keys)
+}
+
+/// Fetches the keys a server publishes, directly from the server.
+async fn fetch_direct(server_name: &str) -> Result<Vec<ServerKey>, KeyError> {
+    let mut res = client::unsigned_request(Method::GET, server_name, "/_matrix/key/v2/server")
+        .await
+        .timeout(TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| {
+            resolve::forget(server_name);
+            KeyError::Request(e.to_string())
+        })?;
+    if res.status() != StatusCode::OK {
+        return Err(KeyError::Request(format!(
+            "Server responded {}",
+            res.status()
+        )));
+    }
+    let body: Value = res
+        .json()
+        .limit(MAX_RESPONSE_SIZE)
+        .await
+        .map_err(|e| KeyError::Request(e.to_string()))?;
+    parse_server_keys(&body, server_name, now_ms())
+}
+
+/// Fetches a server's keys through a notary server, which vouches for them
+/// by adding its own signature. The notary's keys are always fetched
+/// directly from it.
+async fn fetch_from_notary<T: Store>(
+    storage: &T,
+    notary: &str,
+    server_name: &str,
+) -> Result<Vec<ServerKey>, KeyError> {
+    let now = now_ms();
+    let mut notary_keys = valid_at(
+        &storage
+            .get_server_keys(notary)
+            .await
+            .map_err(|e| KeyError::Request(e.to_string()))?,
+        now,
+    );
+    if notary_keys.is_empty() {
+        let fetched = fetch_direct(notary).await?;
+        storage
+            .set_server_keys(notary, &fetched)
+            .await
+            .map_err(|e| KeyError::Request(e.to_string()))?;
+        notary_keys = valid_at(&fetched, now);
+    }
+
+    let mut res = client::unsigned_request(Method::POST, notary, "/_matrix/key/v2/query")
+        .await
+        .timeout(TIMEOUT)
+        .send_json(&json!({ "server_keys": { server_name: {} } }))
+        .await
+        .map_err(|e| {
+            resolve::forget(notary);
+            KeyError::Request(e.to_string())
+        })?;
+    if res.status() != StatusCode::OK {
+        return Err(KeyError::Request(format!(
+            "Notary responded {}",
+            res.status()
+        )));
+    }
+    let body: KeyQueryResponse = res
+        .json()
+        .limit(MAX_RESPONSE_SIZE)
+        .await
+        .map_err(|e| KeyError::Request(e.to_string()))?;
+
+    let mut keys = Vec::new();
+    for server_keys in body.server_keys {
+        signing::verify_signed_by(&server_keys, notary, &notary_keys)
+            .map_err(|_| KeyError::Invalid("Keys are not signed by the notary"))?;
+        keys.extend(parse_server_keys(&server_keys, server_name, now)?);
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::signing::SigningKey;
+
+    fn key(key_id: &str, valid_until_ts: i64, expired_ts: Option<i64>) -> ServerKey {
+        ServerKey {
+            key_id: key_id.to_owned(),
+            key: "AAAA".to_owned(),
+            valid_until_ts,
+            expired_ts,
+        }
+    }
+
+    #[test]
+    fn test_valid_at() {
+        let keys = [
+            key("ed25519:new", 2000, None),
+            key("ed25519:old", 1000, Some(1000)),
+        ];
+        let valid = |ts| {
+            valid_at(&keys, ts)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(valid(500), vec!["ed25519:new", "ed25519:old"]);
+        assert_eq!(valid(1500), vec!["ed25519:new"]);
+        assert!(valid(2500).is_empty());
+    }
+
+    #[test]
+    fn test_parse_server_keys() {
+        let signing_key: SigningKey = SigningKey::generate().parse().unwrap();
+        let mut body = json!({
+            "server_name": "example.com",
+            "verify_keys": { &signing_key.key_id: { "key": signing_key.public_key() } },
+            "old_verify_keys": { "ed25519:old": { "key": "AAAA", "expired_ts": 500 } },
+            "valid_until_ts": i64::MAX,
+        });
+        signing_key.sign_json("example.com", &mut body);
+
+        let keys = parse_server_keys(&body, "example.com", 1000).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].key_id, signing_key.key_id);
+        // Keys are trusted for a week at most
+        assert_eq!(keys[0].valid_until_ts, 1000 + MAX_VALIDITY);
+        assert_eq!(keys[1].expired_ts, Some(500));
+
+        assert!(parse_server_keys(&body, "other.example.com", 1000).is_err());
+        body["valid_until_ts"] = json!(0);
+        assert!(parse_server_keys(&body, "example.com", 1000).is_err());
+    }
+}
@@ -0,0 +1,15 @@
+//! Talking to other homeservers: signing and verifying what is sent between
+//! servers, and finding and authenticating remote servers.
+pub mod acl;
+pub mod canonical_json;
+pub mod client;
+pub mod keys;
+pub mod report;
+pub mod resolve;
+pub mod sender;
+pub mod signing;
+pub mod xmatrix;
+
+/// The room versions whose events we can check. Rooms of other versions
+/// can't be joined.
+pub const ROOM_VERSIONS: [&str; 6] = ["1", "2", "3", "4", "5", "6"];
@@ -0,0 +1,81 @@
+//! Forwarding reports of events to the servers the events came from, so
+//! their admins can deal with the senders. Forwarding is off unless turned
+//! on with `FEDERATION_SEND_REPORTS`, as it tells another server what its
+//! events were reported for, though never by whom.
+//!
+//! Reports are sent with the unstable endpoint of MSC3843, and received
+//! with it from servers that forward theirs.
+use std::error::Error;
+
+use actix_web::http::Method;
+
+use super::client;
+use crate::{models::report::ForwardedReport, CONFIG};
+
+/// The prefix of the endpoints reports are forwarded with.
+pub const PREFIX: &str = "/_matrix/federation/unstable/org.matrix.msc3843";
+
+/// The server an event came from, as far as its ID tells: the IDs of room
+/// versions 1 and 2 end with it.
+///
+/// TODO: Take the server from the sender of the stored event once events
+/// are stored, as later room versions' event IDs are hashes.
+pub fn event_origin(event_id: &str) -> Option<&str> {
+    let local = event_id.strip_prefix('$')?;
+    let server_name = &local[local.find(':')? + 1..];
+    Some(server_name).filter(|name| !name.is_empty())
+}
+
+/// Whether a report of an event from `server_name` should be forwarded to
+/// it.
+pub fn should_forward(server_name: &str) -> bool {
+    CONFIG.federation_send_reports
+        && server_name != CONFIG.hostname
+        && CONFIG.federation_policy.is_allowed(server_name)
+}
+
+/// Forwards a report of an event to the server it came from.
+pub async fn forward(
+    server_name: &str,
+    room_id: &str,
+    event_id: &str,
+    report: &ForwardedReport,
+) -> Result<(), Box<dyn Error>> {
+    let encode = |id: &str| url::form_urlencoded::byte_serialize(id.as_bytes()).collect::<String>();
+    let uri = format!(
+        "{}/rooms/{}/report/{}",
+        PREFIX,
+        encode(room_id),
+        encode(event_id)
+    );
+    let body = serde_json::to_value(report)?;
+    let res = client::request(Method::POST, server_name, &uri, Some(&body))
+        .await
+        .send_json(&body)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("{} responded {}", server_name, res.status()).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_origin() {
+        assert_eq!(event_origin("$abc:example.com"), Some("example.com"));
+        assert_eq!(
+            event_origin("$abc:example.com:8448"),
+            Some("example.com:8448")
+        );
+        assert_eq!(
+            event_origin("$Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg"),
+            None
+        );
+        assert_eq!(event_origin("$abc:"), None);
+        assert_eq!(event_origin("abc:example.com"), None);
+    }
+}
@@ -0,0 +1,335 @@
+//! Finding where to send requests for another server, following the server
+//! discovery rules of the server-server API.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    client::{Client, ClientResponse},
+    http::{header, StatusCode},
+};
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use serde_json::Value;
+use trust_dns_resolver::AsyncResolver;
+
+use crate::bus::{Message, BUS};
+
+/// The port servers listen for federation on, unless they say otherwise.
+const DEFAULT_PORT: u16 = 8448;
+/// How long to wait for a server's `.well-known/matrix/server`.
+const WELL_KNOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// The largest `.well-known/matrix/server` response accepted.
+const MAX_WELL_KNOWN_SIZE: usize = 64 * 1024;
+/// How long a delegation is remembered when the server doesn't say.
+const DEFAULT_WELL_KNOWN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+/// The longest a delegation is remembered, whatever the server says.
+const MAX_WELL_KNOWN_LIFETIME: Duration = Duration::from_secs(48 * 60 * 60);
+/// The name delegations are invalidated by on the replication bus.
+const CACHE_NAME: &str = "well_known";
+/// How long to remember that a server has no delegation.
+const WELL_KNOWN_ERROR_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Where requests for a server are sent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Destination {
+    /// The base URL requests are sent to
+    pub base_url: String,
+    /// The `Host` header requests are sent with
+    pub host: String,
+}
+
+lazy_static! {
+    /// The server each server delegates to, if any, by server name, and
+    /// until when that is remembered.
+    static ref WELL_KNOWN_CACHE: Mutex<HashMap<String, (Option<String>, Instant)>> =
+        Mutex::new(HashMap::new());
+}
+
+thread_local! {
+    /// Each worker thread has its own resolver, as its background task runs
+    /// on the thread's runtime.
+    static RESOLVER: RefCell<Option<AsyncResolver>> = RefCell::new(None);
+}
+
+/// Works out where to send requests for `server_name`:
+///
+/// 1. IP literals, and hostnames with a port, are used as they are.
+/// 2. Otherwise the server may delegate to another through
+///    `/.well-known/matrix/server`, which is resolved the same way, with
+///    SRV records taking the place of further delegation.
+/// 3. Otherwise the `_matrix._tcp` SRV record of the hostname is used.
+/// 4. Otherwise the hostname is used, with the default port 8448.
+///
+/// TODO: Certificates should be checked against the server name, or the
+/// name delegated to, rather than the host connected to, which takes a
+/// custom TLS connector.
+pub async fn resolve(server_name: &str) -> Destination {
+    let (host, port) = split_port(server_name);
+    if is_ip_literal(host) || port.is_some() {
+        return Destination {
+            base_url: format!("https://{}:{}", host, port.unwrap_or(DEFAULT_PORT)),
+            host: server_name.to_owned(),
+        };
+    }
+
+    if let Some(delegated) = well_known(host).await {
+        let (delegated_host, delegated_port) = split_port(&delegated);
+        let base_url = if is_ip_literal(delegated_host) || delegated_port.is_some() {
+            format!(
+                "https://{}:{}",
+                delegated_host,
+                delegated_port.unwrap_or(DEFAULT_PORT)
+            )
+        } else {
+            srv_lookup(delegated_host)
+                .await
+                .unwrap_or_else(|| format!("https://{}:{}", delegated_host, DEFAULT_PORT))
+        };
+        return Destination {
+            base_url,
+            host: delegated,
+        };
+    }
+
+    Destination {
+        base_url: srv_lookup(host)
+            .await
+            .unwrap_or_else(|| format!("https://{}:{}", host, DEFAULT_PORT)),
+        host: server_name.to_owned(),
+    }
+}
+
+/// Forgets what was learnt about a server's delegation, in every process,
+/// so that it is looked up again the next time. Called when the server
+/// can't be reached, as it may have moved.
+pub fn forget(server_name: &str) {
+    forget_here(server_name);
+    BUS.publish(Message::Invalidate {
+        cache: CACHE_NAME.to_owned(),
+        key: server_name.to_owned(),
+    });
+}
+
+fn forget_here(server_name: &str) {
+    if let Ok(mut cache) = WELL_KNOWN_CACHE.lock() {
+        cache.remove(server_name);
+    }
+}
+
+/// Forgets the delegations other processes found were stale, for as long as
+/// the server runs.
+pub async fn follow_invalidations() {
+    let mut messages = BUS.subscribe();
+    while let Some(message) = messages.next().await {
+        if let Message::Invalidate { cache, key } = message {
+            if cache == CACHE_NAME {
+                forget_here(&key);
+            }
+        }
+    }
+}
+
+/// Splits the port off a server name, if it has one.
+fn split_port(server_name: &str) -> (&str, Option<u16>) {
+    // IPv6 literals are bracketed, so a port follows the last `]`
+    let host_end = server_name.rfind(']').unwrap_or(0);
+    match server_name[host_end..].rfind(':') {
+        Some(i) => {
+            let i = host_end + i;
+            match server_name[i + 1..].parse() {
+                Ok(port) => (&server_name[..i], Some(port)),
+                Err(_) => (server_name, None),
+            }
+        }
+        None => (server_name, None),
+    }
+}
+
+/// Whether a host is an IPv4 address or a bracketed IPv6 address.
+fn is_ip_literal(host: &str) -> bool {
+    host.starts_with('[') || host.parse::<Ipv4Addr>().is_ok()
+}
+
+/// Gets the server a hostname delegates to, remembering the answer.
+async fn well_known(host: &str) -> Option<String> {
+    if let Some((delegated, expires)) = WELL_KNOWN_CACHE
+        .lock()
+        .ok()
+        .and_then(|cache| cache.get(host).cloned())
+    {
+        if expires > Instant::now() {
+            return delegated;
+        }
+    }
+
+    let (delegated, lifetime) = match fetch_well_known(host).await {
+        Ok((delegated, lifetime)) => (Some(delegated), lifetime),
+        Err(e) => {
+            tracing::debug!(%host, error = %e, "No delegation");
+            (None, WELL_KNOWN_ERROR_LIFETIME)
+        }
+    };
+    if let Ok(mut cache) = WELL_KNOWN_CACHE.lock() {
+        cache.insert(
+            host.to_owned(),
+            (delegated.clone(), Instant::now() + lifetime),
+        );
+    }
+    delegated
+}
+
+async fn fetch_well_known(host: &str) -> Result<(String, Duration), String> {
+    let mut res = Client::build()
+        .timeout(WELL_KNOWN_TIMEOUT)
+        .finish()
+        .get(format!("https://{}/.well-known/matrix/server", host))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if res.status() != StatusCode::OK {
+        return Err(format!("Server responded {}", res.status()));
+    }
+    let lifetime = cache_lifetime(&res);
+    let body: Value = res
+        .json()
+        .limit(MAX_WELL_KNOWN_SIZE)
+        .await
+        .map_err(|e| e.to_string())?;
+    let delegated = parse_well_known(&body).ok_or("Invalid m.server")?;
+    Ok((delegated, lifetime))
+}
+
+/// Gets the server name a `.well-known/matrix/server` document delegates
+/// to, if it is valid.
+fn parse_well_known(body: &Value) -> Option<String> {
+    let delegated = body.get("m.server")?.as_str()?;
+    let (host, _) = split_port(delegated);
+    let valid = !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-.[]:".contains(c));
+    if valid {
+        Some(delegated.to_owned())
+    } else {
+        None
+    }
+}
+
+/// How long a response may be remembered for, going by its `Cache-Control`
+/// header.
+fn cache_lifetime<S>(res: &ClientResponse<S>) -> Duration {
+    let max_age = res
+        .headers()
+        .get(header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_max_age);
+    max_age.map_or(DEFAULT_WELL_KNOWN_LIFETIME, |max_age| {
+        max_age.min(MAX_WELL_KNOWN_LIFETIME)
+    })
+}
+
+fn parse_max_age(cache_control: &str) -> Option<Duration> {
+    cache_control
+        .split(',')
+        .filter_map(|directive| {
+            let mut split = directive.trim().splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(name), Some(value)) if name.eq_ignore_ascii_case("max-age") => {
+                    value.trim_matches('"').parse().ok()
+                }
+                _ => None,
+            }
+        })
+        .next()
+        .map(Duration::from_secs)
+}
+
+/// Looks up the `_matrix._tcp` SRV record of a hostname, giving the base
+/// URL of its best target.
+async fn srv_lookup(host: &str) -> Option<String> {
+    let resolver = RESOLVER.with(|resolver| {
+        let mut resolver = resolver.borrow_mut();
+        if resolver.is_none() {
+            match AsyncResolver::from_system_conf() {
+                Ok((new, background)) => {
+                    actix_rt::spawn(background);
+                    *resolver = Some(new);
+                }
+                Err(e) => tracing::error!(error = %e, "Unable to start DNS resolver"),
+            }
+        }
+        resolver.clone()
+    })?;
+
+    let lookup = resolver
+        .lookup_srv(format!("_matrix._tcp.{}.", host).as_str())
+        .await
+        .ok()?;
+    // Lowest priority first, and the heaviest of those
+    let srv = lookup
+        .iter()
+        .min_by_key(|srv| (srv.priority(), std::cmp::Reverse(srv.weight())))?;
+    let target = srv.target().to_utf8();
+    Some(format!(
+        "https://{}:{}",
+        target.trim_end_matches('.'),
+        srv.port()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_split_port() {
+        assert_eq!(split_port("example.com"), ("example.com", None));
+        assert_eq!(split_port("example.com:443"), ("example.com", Some(443)));
+        assert_eq!(split_port("[::1]"), ("[::1]", None));
+        assert_eq!(split_port("[::1]:8000"), ("[::1]", Some(8000)));
+        assert_eq!(split_port("1.2.3.4:8000"), ("1.2.3.4", Some(8000)));
+    }
+
+    #[actix_rt::test]
+    async fn test_resolve_literals() {
+        assert_eq!(
+            resolve("1.2.3.4").await,
+            Destination {
+                base_url: "https://1.2.3.4:8448".to_owned(),
+                host: "1.2.3.4".to_owned(),
+            }
+        );
+        assert_eq!(resolve("[::1]:8000").await.base_url, "https://[::1]:8000");
+        assert_eq!(
+            resolve("example.com:443").await,
+            Destination {
+                base_url: "https://example.com:443".to_owned(),
+                host: "example.com:443".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_well_known() {
+        assert_eq!(
+            parse_well_known(&json!({"m.server": "matrix.example.com:443"})),
+            Some("matrix.example.com:443".to_owned())
+        );
+        assert_eq!(parse_well_known(&json!({"m.server": ""})), None);
+        assert_eq!(parse_well_known(&json!({"m.server": "a/b"})), None);
+        assert_eq!(parse_well_known(&json!({})), None);
+    }
+
+    #[test]
+    fn test_parse_max_age() {
+        assert_eq!(
+            parse_max_age("public, max-age=3600"),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(parse_max_age("no-cache"), None);
+    }
+}
@@ -0,0 +1,310 @@
+//! Delivers PDUs and EDUs to other servers.
+//!
+//! Everything to be sent is first queued in the `Store`, so nothing is lost
+//! if the server restarts before it is delivered. Each destination has its
+//! own delivery task, which sends what is queued for it in transactions
+//! until the queue is empty. Destinations that can't be reached are retried
+//! with exponential backoff.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use actix_web::http::Method;
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+use serde_json::{json, Value};
+
+use super::{client, resolve};
+use crate::{
+    clock::now_ms, db::Store, models::federation::DestinationRetry, server::worker,
+    shutdown::InFlight, CONFIG,
+};
+
+/// The most PDUs sent in one transaction.
+pub const MAX_PDUS: i64 = 50;
+/// The most EDUs sent in one transaction.
+pub const MAX_EDUS: i64 = 100;
+/// How long to wait before retrying a destination the first time it fails.
+const MIN_RETRY_INTERVAL: i64 = 30 * 1000;
+/// The longest to wait between retries of a destination.
+const MAX_RETRY_INTERVAL: i64 = 24 * 60 * 60 * 1000;
+
+/// Wakes the sender when something new is queued for a destination. Can be
+/// cloned and used from any thread.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    wakeups: UnboundedSender<String>,
+    sending: InFlight,
+}
+
+impl Notifier {
+    pub fn notify(&self, destination: &str) {
+        // The sender only stops when the server does
+        let _ = self.wakeups.unbounded_send(destination.to_owned());
+    }
+
+    /// Waits for the transactions being sent to finish, for at most
+    /// `timeout`. Returns whether they all did.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        self.sending.wait(timeout).await
+    }
+}
+
+/// The destinations with a running delivery task, and whether more was
+/// queued for each since its task last looked.
+type Active = Rc<RefCell<HashMap<String, bool>>>;
+
+/// Starts the sender on the current thread, first catching up on whatever
+/// was left undelivered when the server last stopped.
+pub fn start<T: Store + 'static>(storage: T) -> Notifier {
+    let (sender, receiver) = mpsc::unbounded();
+    let sending = InFlight::default();
+    actix_rt::spawn(run(storage, receiver, sending.clone()));
+    Notifier {
+        wakeups: sender,
+        sending,
+    }
+}
+
+/// Forwards wakeups to the federation sender worker at `url`, for processes
+/// that leave sending federation to it. Nothing is sent from this process,
+/// so there is never anything to flush.
+pub fn forward(url: String, secret: String) -> Notifier {
+    let (sender, receiver) = mpsc::unbounded();
+    actix_rt::spawn(forward_wakeups(url, secret, receiver));
+    Notifier {
+        wakeups: sender,
+        sending: InFlight::default(),
+    }
+}
+
+async fn forward_wakeups(url: String, secret: String, mut wakeups: UnboundedReceiver<String>) {
+    while let Some(destination) = wakeups.next().await {
+        // What was queued stays queued, and is picked up when the worker
+        // next polls
+        if let Err(e) = worker::wake_federation_sender(&url, &secret, &destination).await {
+            tracing::warn!(%destination, error = %e, "Unable to wake the federation sender");
+        }
+    }
+}
+
+/// Wakes the sender for every destination with something queued every
+/// `period`, picking up anything whose wakeup was lost on the way from
+/// another process.
+pub async fn poll<T: Store>(storage: T, notifier: Notifier, period: Duration) {
+    let mut interval = actix_rt::time::interval(period);
+    loop {
+        interval.tick().await;
+        match storage.get_federation_destinations().await {
+            Ok(destinations) => {
+                for destination in destinations {
+                    notifier.notify(&destination);
+                }
+            }
+            Err(e) => tracing::error!(error = %e, "Unable to load the federation queue"),
+        }
+    }
+}
+
+async fn run<T: Store + 'static>(
+    storage: T,
+    mut wakeups: UnboundedReceiver<String>,
+    sending: InFlight,
+) {
+    let active = Active::default();
+    match storage.get_federation_destinations().await {
+        Ok(destinations) => {
+            for destination in destinations {
+                wake(&storage, &active, &sending, destination);
+            }
+        }
+        Err(e) => tracing::error!(error = %e, "Unable to load the federation queue"),
+    }
+    while let Some(destination) = wakeups.next().await {
+        wake(&storage, &active, &sending, destination);
+    }
+}
+
+/// Makes sure a delivery task is running for a destination.
+fn wake<T: Store + 'static>(storage: &T, active: &Active, sending: &InFlight, destination: String) {
+    let mut tasks = active.borrow_mut();
+    if let Some(queued) = tasks.get_mut(&destination) {
+        *queued = true;
+        return;
+    }
+    tasks.insert(destination.clone(), false);
+    actix_rt::spawn(deliver(
+        storage.clone(),
+        active.clone(),
+        sending.clone(),
+        destination,
+    ));
+}
+
+/// Sends everything queued for a destination, one transaction at a time.
+async fn deliver<T: Store>(storage: T, active: Active, sending: InFlight, destination: String) {
+    loop {
+        // A backoff survives restarts, so a dead server isn't hammered
+        // every time we start
+        match storage.get_destination_retry(&destination).await {
+            Ok(Some(retry)) => {
+                let wait = retry.retry_last_ts + retry.retry_interval - now_ms();
+                if wait > 0 {
+                    actix_rt::time::delay_for(Duration::from_millis(wait as u64)).await;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::error!(%destination, error = %e, "Unable to load retry state");
+                actix_rt::time::delay_for(Duration::from_millis(MIN_RETRY_INTERVAL as u64)).await;
+                continue;
+            }
+        }
+
+        let (pdus, edus) = match storage
+            .get_federation_outbound(&destination, MAX_PDUS, MAX_EDUS)
+            .await
+        {
+            Ok(items) => items,
+            Err(e) => {
+                tracing::error!(%destination, error = %e, "Unable to load the queue");
+                actix_rt::time::delay_for(Duration::from_millis(MIN_RETRY_INTERVAL as u64)).await;
+                continue;
+            }
+        };
+        if pdus.is_empty() && edus.is_empty() {
+            // Anything queued while we were looking is picked up by going
+            // round again
+            let mut tasks = active.borrow_mut();
+            if tasks.get(&destination) == Some(&true) {
+                tasks.insert(destination.clone(), false);
+                continue;
+            }
+            tasks.remove(&destination);
+            return;
+        }
+
+        let ids: Vec<i64> = pdus.iter().chain(&edus).map(|(id, _)| *id).collect();
+        // Anything queued before the destination was denied is dropped
+        if !CONFIG.federation_policy.is_allowed(&destination) {
+            if let Err(e) = storage.delete_federation_outbound(&ids).await {
+                tracing::error!(%destination, error = %e, "Unable to update the queue");
+                actix_rt::time::delay_for(Duration::from_millis(MIN_RETRY_INTERVAL as u64)).await;
+            }
+            continue;
+        }
+        // Retrying the same items reuses the transaction ID, so the
+        // destination can tell it has seen them
+        let txn_id = format!(
+            "{}-{}",
+            ids.iter().min().unwrap_or(&0),
+            ids.iter().max().unwrap_or(&0)
+        );
+        let pdus = pdus.into_iter().map(|(_, pdu)| pdu).collect();
+        let edus = edus.into_iter().map(|(_, edu)| edu).collect();
+
+        // A transaction cut off by a shutdown would be sent again once the
+        // server is back, so it is counted until its outcome is stored
+        let started = sending.start();
+        let result = match send_transaction(&destination, &txn_id, pdus, edus).await {
+            Ok(()) => {
+                let deleted = storage.delete_federation_outbound(&ids).await;
+                match deleted {
+                    Ok(()) => storage.set_destination_retry(&destination, None).await,
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => {
+                tracing::warn!(%destination, error = %e, "Unable to send transaction");
+                let previous = storage
+                    .get_destination_retry(&destination)
+                    .await
+                    .ok()
+                    .flatten();
+                let retry = DestinationRetry {
+                    retry_last_ts: now_ms(),
+                    retry_interval: next_retry_interval(previous),
+                };
+                storage
+                    .set_destination_retry(&destination, Some(&retry))
+                    .await
+            }
+        };
+        drop(started);
+        if let Err(e) = result {
+            tracing::error!(%destination, error = %e, "Unable to update the queue");
+            actix_rt::time::delay_for(Duration::from_millis(MIN_RETRY_INTERVAL as u64)).await;
+        }
+    }
+}
+
+/// How long to wait before retrying a destination that failed again.
+fn next_retry_interval(previous: Option<DestinationRetry>) -> i64 {
+    previous.map_or(MIN_RETRY_INTERVAL, |previous| {
+        (previous.retry_interval * 2).min(MAX_RETRY_INTERVAL)
+    })
+}
+
+#[tracing::instrument(skip(pdus, edus), fields(pdus = pdus.len(), edus = edus.len()))]
+async fn send_transaction(
+    destination: &str,
+    txn_id: &str,
+    pdus: Vec<Value>,
+    edus: Vec<Value>,
+) -> Result<(), String> {
+    let body = json!({
+        "origin": CONFIG.hostname,
+        "origin_server_ts": now_ms(),
+        "pdus": pdus,
+        "edus": edus,
+    });
+    let uri = format!("/_matrix/federation/v1/send/{}", txn_id);
+    let mut res = client::request(Method::PUT, destination, &uri, Some(&body))
+        .await
+        .send_json(&body)
+        .await
+        .map_err(|e| {
+            // The server may have moved
+            resolve::forget(destination);
+            e.to_string()
+        })?;
+    if !res.status().is_success() {
+        return Err(format!("Server responded {}", res.status()));
+    }
+
+    // Rejected PDUs won't be accepted on a retry either, so they are only
+    // logged
+    let response: Value = res.json().await.map_err(|e| e.to_string())?;
+    if let Some(results) = response.get("pdus").and_then(Value::as_object) {
+        for (event_id, result) in results {
+            if let Some(error) = result.get("error") {
+                tracing::warn!(%destination, %event_id, %error, "PDU rejected");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_retry_interval() {
+        assert_eq!(next_retry_interval(None), MIN_RETRY_INTERVAL);
+        let retry = |retry_interval| {
+            Some(DestinationRetry {
+                retry_last_ts: 0,
+                retry_interval,
+            })
+        };
+        assert_eq!(next_retry_interval(retry(MIN_RETRY_INTERVAL)), 60 * 1000);
+        assert_eq!(
+            next_retry_interval(retry(MAX_RETRY_INTERVAL)),
+            MAX_RETRY_INTERVAL
+        );
+    }
+}
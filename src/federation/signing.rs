@@ -0,0 +1,581 @@
+//! Signing and verifying JSON objects and events, as described in the
+//! "Signing JSON" and "Signing Events" sections of the server-server API.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use rand::{distributions::Alphanumeric, Rng};
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+    signature,
+};
+use serde_json::{json, Map, Value};
+
+use super::canonical_json;
+
+/// The algorithm of the signing keys we understand.
+pub const ED25519: &str = "ed25519";
+
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    /// The object carries no signature by the expected server or key
+    Missing,
+    /// The signature isn't valid base64
+    Malformed,
+    /// The signature doesn't match
+    Invalid,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "Missing signature"),
+            SignatureError::Malformed => write!(f, "Malformed signature"),
+            SignatureError::Invalid => write!(f, "Invalid signature"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// A key this server signs with.
+pub struct SigningKey {
+    /// The key's ID, e.g. `ed25519:a_AbCd`
+    pub key_id: String,
+    key_pair: signature::Ed25519KeyPair,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidSigningKey;
+
+impl fmt::Display for InvalidSigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid signing key, expected `ed25519 <version> <base64 seed>`"
+        )
+    }
+}
+
+impl std::error::Error for InvalidSigningKey {}
+
+impl SigningKey {
+    pub fn from_seed(version: &str, seed: &[u8]) -> Result<Self, InvalidSigningKey> {
+        Ok(SigningKey {
+            key_id: format!("{}:{}", ED25519, version),
+            key_pair: signature::Ed25519KeyPair::from_seed_unchecked(seed)
+                .map_err(|_| InvalidSigningKey)?,
+        })
+    }
+
+    /// Generates a new key with a random version, returned in the format
+    /// keys are parsed from.
+    pub fn generate() -> String {
+        let mut seed = [0; 32];
+        SystemRandom::new()
+            .fill(&mut seed)
+            .expect("System random number generator failed.");
+        let version: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(4)
+            .collect();
+        format!("{} a_{} {}", ED25519, version, encode_base64(&seed))
+    }
+
+    /// The unpadded base64 encoded public half of the key.
+    pub fn public_key(&self) -> String {
+        use signature::KeyPair as _;
+        encode_base64(self.key_pair.public_key().as_ref())
+    }
+
+    /// Signs `message`, returning the unpadded base64 encoded signature.
+    pub fn sign(&self, message: &[u8]) -> String {
+        encode_base64(self.key_pair.sign(message).as_ref())
+    }
+
+    /// Signs a JSON object as `server_name`, keeping any signatures it
+    /// already has.
+    pub fn sign_json(&self, server_name: &str, value: &mut Value) {
+        let sig = self.sign(canonical_json::encode(&signable(value)).as_bytes());
+        if let Some(object) = value.as_object_mut() {
+            let signatures = object
+                .entry("signatures")
+                .or_insert_with(|| Value::Object(Map::new()));
+            signatures[server_name][&self.key_id] = Value::String(sig);
+        }
+    }
+
+    /// Hashes and signs an event created on this server as `server_name`.
+    /// The content hash is added first, and the signature is made over the
+    /// redacted event, so it stays valid if the event is later redacted.
+    pub fn sign_event(&self, server_name: &str, event: &mut Value) {
+        let hash = content_hash(event);
+        if let Some(object) = event.as_object_mut() {
+            object.insert("hashes".to_owned(), json!({ "sha256": hash }));
+        }
+        self.countersign_event(server_name, event);
+    }
+
+    /// Adds a signature to an event as `server_name`, keeping its hashes and
+    /// the signatures it already has, as when accepting an invite created on
+    /// another server.
+    pub fn countersign_event(&self, server_name: &str, event: &mut Value) {
+        let mut redacted = redact(event);
+        self.sign_json(server_name, &mut redacted);
+        if let Some(object) = event.as_object_mut() {
+            object.insert("signatures".to_owned(), redacted["signatures"].take());
+        }
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("key_id", &self.key_id)
+            .finish()
+    }
+}
+
+/// Parses a key in the format Synapse keeps signing keys in:
+/// `ed25519 <version> <unpadded base64 seed>`.
+impl FromStr for SigningKey {
+    type Err = InvalidSigningKey;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        match parts.as_slice() {
+            [ED25519, version, seed] => {
+                let seed = decode_base64(seed).ok_or(InvalidSigningKey)?;
+                SigningKey::from_seed(version, &seed)
+            }
+            _ => Err(InvalidSigningKey),
+        }
+    }
+}
+
+/// Encodes bytes as unpadded base64, as used throughout the Matrix APIs.
+pub fn encode_base64(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::STANDARD_NO_PAD)
+}
+
+/// Decodes unpadded base64. Padding is tolerated, as the spec asks.
+pub fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    base64::decode_config(s.trim_end_matches('='), base64::STANDARD_NO_PAD).ok()
+}
+
+/// Removes the keys of an object that its signatures don't cover.
+fn signable(value: &Value) -> Value {
+    let mut value = value.clone();
+    if let Some(object) = value.as_object_mut() {
+        object.remove("signatures");
+        object.remove("unsigned");
+    }
+    value
+}
+
+/// Verifies an Ed25519 signature of `message`.
+pub fn verify_bytes(message: &[u8], sig: &[u8], public_key: &[u8]) -> Result<(), SignatureError> {
+    signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+        .verify(message, sig)
+        .map_err(|_| SignatureError::Invalid)
+}
+
+/// Checks the signature made on a JSON object by `server_name` with the
+/// key `key_id`.
+pub fn verify_json(
+    value: &Value,
+    server_name: &str,
+    key_id: &str,
+    public_key: &[u8],
+) -> Result<(), SignatureError> {
+    let sig = value
+        .get("signatures")
+        .and_then(|signatures| signatures.get(server_name))
+        .and_then(|signatures| signatures.get(key_id))
+        .and_then(Value::as_str)
+        .ok_or(SignatureError::Missing)?;
+    let sig = decode_base64(sig).ok_or(SignatureError::Malformed)?;
+    verify_bytes(
+        canonical_json::encode(&signable(value)).as_bytes(),
+        &sig,
+        public_key,
+    )
+}
+
+/// Checks that a JSON object was signed by `server_name` with at least one
+/// of its keys, given as key ID to public key. Signatures made with
+/// algorithms we don't understand are ignored.
+pub fn verify_signed_by(
+    value: &Value,
+    server_name: &str,
+    keys: &BTreeMap<String, Vec<u8>>,
+) -> Result<(), SignatureError> {
+    let key_ids: Vec<&String> = value
+        .get("signatures")
+        .and_then(|signatures| signatures.get(server_name))
+        .and_then(Value::as_object)
+        .map(|signatures| {
+            signatures
+                .keys()
+                .filter(|key_id| key_id.starts_with("ed25519:"))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut result = Err(SignatureError::Missing);
+    for key_id in key_ids {
+        if let Some(public_key) = keys.get(key_id) {
+            result = verify_json(value, server_name, key_id, public_key);
+            if result.is_ok() {
+                break;
+            }
+        }
+    }
+    result
+}
+
+/// The keys of an event kept by redaction.
+const REDACTION_KEPT_KEYS: &[&str] = &[
+    "event_id",
+    "type",
+    "room_id",
+    "sender",
+    "state_key",
+    "content",
+    "hashes",
+    "signatures",
+    "depth",
+    "prev_events",
+    "prev_state",
+    "auth_events",
+    "origin",
+    "origin_server_ts",
+    "membership",
+];
+
+/// The content keys kept by redaction, by event type.
+fn redaction_kept_content_keys(event_type: &str) -> &'static [&'static str] {
+    match event_type {
+        "m.room.member" => &["membership"],
+        "m.room.create" => &["creator"],
+        "m.room.join_rules" => &["join_rule"],
+        "m.room.power_levels" => &[
+            "ban",
+            "events",
+            "events_default",
+            "kick",
+            "redact",
+            "state_default",
+            "users",
+            "users_default",
+        ],
+        "m.room.aliases" => &["aliases"],
+        "m.room.history_visibility" => &["history_visibility"],
+        _ => &[],
+    }
+}
+
+/// Strips an event down to the keys that survive redaction, which are the
+/// ones its signatures cover.
+///
+/// TODO: Apply the changes later room versions make to the algorithm.
+pub fn redact(event: &Value) -> Value {
+    let event = match event.as_object() {
+        Some(event) => event,
+        None => return event.clone(),
+    };
+    let mut redacted: Map<String, Value> = event
+        .iter()
+        .filter(|(key, _)| REDACTION_KEPT_KEYS.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+
+    let event_type = event.get("type").and_then(Value::as_str).unwrap_or("");
+    let kept_content_keys = redaction_kept_content_keys(event_type);
+    let content: Map<String, Value> = event
+        .get("content")
+        .and_then(Value::as_object)
+        .map(|content| {
+            content
+                .iter()
+                .filter(|(key, _)| kept_content_keys.contains(&key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        })
+        .unwrap_or_default();
+    redacted.insert("content".to_owned(), Value::Object(content));
+    Value::Object(redacted)
+}
+
+/// Computes the content hash of an event: the SHA-256 of the event without
+/// its `unsigned`, `signatures` and `hashes` keys, unpadded base64 encoded.
+pub fn content_hash(event: &Value) -> String {
+    let mut event = signable(event);
+    if let Some(event) = event.as_object_mut() {
+        event.remove("hashes");
+    }
+    let hash = digest::digest(&digest::SHA256, canonical_json::encode(&event).as_bytes());
+    encode_base64(hash.as_ref())
+}
+
+/// Whether an event's content hash matches its content. Events failing the
+/// check must be redacted before being used.
+pub fn check_content_hash(event: &Value) -> bool {
+    event
+        .get("hashes")
+        .and_then(|hashes| hashes.get("sha256"))
+        .and_then(Value::as_str)
+        .map_or(false, |hash| hash == content_hash(event))
+}
+
+/// The SHA-256 of an event's redacted form, which is what other events
+/// refer to it by.
+fn reference_digest(event: &Value) -> digest::Digest {
+    let redacted = signable(&redact(event));
+    digest::digest(
+        &digest::SHA256,
+        canonical_json::encode(&redacted).as_bytes(),
+    )
+}
+
+/// Computes the reference hash of an event, unpadded base64 encoded, as
+/// room versions 1 and 2 list next to the IDs of the events they refer to.
+pub fn reference_hash(event: &Value) -> String {
+    encode_base64(reference_digest(event).as_ref())
+}
+
+/// Computes the ID of an event in room versions 4 and later: the reference
+/// hash of the event, URL-safe base64 encoded.
+pub fn event_id(event: &Value) -> String {
+    let mut event = event.clone();
+    if let Some(event) = event.as_object_mut() {
+        event.remove("event_id");
+    }
+    format!(
+        "${}",
+        base64::encode_config(reference_digest(&event).as_ref(), base64::URL_SAFE_NO_PAD)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::KeyPair;
+
+    /// The key from the signing examples in the server-server API. The spec
+    /// gives the seed with stray trailing bits, which we don't accept.
+    fn example_key() -> signature::Ed25519KeyPair {
+        let seed = decode_base64("YJDBA9Xnr2sVqXD9Vj7XVUnmFZcZrlw8Md7kMW+3XA0").unwrap();
+        signature::Ed25519KeyPair::from_seed_unchecked(&seed).unwrap()
+    }
+
+    #[test]
+    fn test_verify_spec_example() {
+        let key = example_key();
+        let signed = json!({
+            "signatures": {
+                "domain": {
+                    "ed25519:1": "K8280/U9SSy9IVtjBuVeLr+HpOB4BQFWbg+UZaADMtTdGYI7Geitb76LTrr5QV/7Xg4ahLwYGYZzuHGZKM5ZAQ"
+                }
+            }
+        });
+        assert_eq!(
+            verify_json(&signed, "domain", "ed25519:1", key.public_key().as_ref()),
+            Ok(())
+        );
+        assert_eq!(
+            verify_json(&signed, "domain", "ed25519:2", key.public_key().as_ref()),
+            Err(SignatureError::Missing)
+        );
+
+        let mut tampered = signed;
+        tampered["one"] = json!(1);
+        assert_eq!(
+            verify_json(&tampered, "domain", "ed25519:1", key.public_key().as_ref()),
+            Err(SignatureError::Invalid)
+        );
+    }
+
+    #[test]
+    fn test_sign_spec_example() {
+        let key: SigningKey = "ed25519 1 YJDBA9Xnr2sVqXD9Vj7XVUnmFZcZrlw8Md7kMW+3XA0"
+            .parse()
+            .unwrap();
+        assert_eq!(key.key_id, "ed25519:1");
+
+        let mut value = json!({"one": 1, "two": "Two"});
+        key.sign_json("domain", &mut value);
+        assert_eq!(
+            value["signatures"]["domain"]["ed25519:1"],
+            "KqmLSbO39/Bzb0QIYE82zqLwsA+PDzYIpIRA2sRQ4sL53+sN6/fpNSoqE7BP7vBZhG6kYdD13EIMJpvhJI+6Bw"
+        );
+    }
+
+    /// The event signing examples from the server-server API.
+    #[test]
+    fn test_sign_event_spec_examples() {
+        let key: SigningKey = "ed25519 1 YJDBA9Xnr2sVqXD9Vj7XVUnmFZcZrlw8Md7kMW+3XA0"
+            .parse()
+            .unwrap();
+
+        let mut event = json!({
+            "room_id": "!x:domain",
+            "sender": "@a:domain",
+            "origin": "domain",
+            "origin_server_ts": 1000000,
+            "signatures": {},
+            "hashes": {},
+            "type": "X",
+            "content": {},
+            "prev_events": [],
+            "auth_events": [],
+            "depth": 3,
+            "unsigned": {
+                "age_ts": 1000000
+            }
+        });
+        key.sign_event("domain", &mut event);
+        assert_eq!(
+            event["hashes"],
+            json!({"sha256": "5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos"})
+        );
+        assert_eq!(
+            event["signatures"],
+            json!({
+                "domain": {
+                    "ed25519:1": "KxwGjPSDEtvnFgU00fwFz+l6d2pJM6XBIaMEn81SXPTRl16AqLAYqfIReFGZlHi5KLjAWbOoMszkwsQma+lYAg"
+                }
+            })
+        );
+        assert_eq!(event["unsigned"], json!({"age_ts": 1000000}));
+        assert!(check_content_hash(&event));
+
+        let mut event = json!({
+            "content": {
+                "body": "Here is the message content"
+            },
+            "event_id": "$0:domain",
+            "origin": "domain",
+            "origin_server_ts": 1000000,
+            "type": "m.room.message",
+            "room_id": "!r:domain",
+            "sender": "@u:domain",
+            "signatures": {},
+            "unsigned": {
+                "age_ts": 1000000
+            }
+        });
+        key.sign_event("domain", &mut event);
+        assert_eq!(
+            event["hashes"]["sha256"],
+            "onLKD1bGljeBWQhWZ1kaP9SorVmRQNdN5aM2JYU2n/g"
+        );
+        assert_eq!(
+            event["signatures"]["domain"]["ed25519:1"],
+            "Wm+VzmOUOz08Ds+0NTWb1d4CZrVsJSikkeRxh6aCcUwu6pNC78FunoD7KNWzqFn241eYHYMGCA5McEiVPdhzBA"
+        );
+
+        // The signature survives the event being redacted
+        let mut keys = BTreeMap::new();
+        keys.insert(
+            "ed25519:1".to_owned(),
+            decode_base64(&key.public_key()).unwrap(),
+        );
+        assert_eq!(verify_signed_by(&redact(&event), "domain", &keys), Ok(()));
+    }
+
+    #[test]
+    fn test_reference_hash() {
+        let event = json!({
+            "room_id": "!x:domain",
+            "sender": "@a:domain",
+            "origin": "domain",
+            "origin_server_ts": 1000000,
+            "hashes": {"sha256": "5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos"},
+            "type": "X",
+            "content": {},
+            "prev_events": [],
+            "auth_events": [],
+            "depth": 3,
+        });
+        assert_eq!(
+            event_id(&event),
+            "$8yif6p8EqgoSten2BLje9ntKm720NyFLWQv9tn8memc"
+        );
+        assert_eq!(
+            reference_hash(&event),
+            "8yif6p8EqgoSten2BLje9ntKm720NyFLWQv9tn8memc"
+        );
+
+        // Neither signatures nor anything redaction removes are covered
+        let mut signed = event.clone();
+        signed["signatures"] = json!({"domain": {"ed25519:1": "abc"}});
+        signed["content"] = json!({"body": "hello"});
+        assert_eq!(reference_hash(&signed), reference_hash(&event));
+    }
+
+    #[test]
+    fn test_generate_signing_key() {
+        let key: SigningKey = SigningKey::generate().parse().unwrap();
+        assert!(key.key_id.starts_with("ed25519:a_"));
+        assert_ne!(SigningKey::generate(), SigningKey::generate());
+    }
+
+    #[test]
+    fn test_unsigned_is_not_covered() {
+        let key: SigningKey = "ed25519 1 YJDBA9Xnr2sVqXD9Vj7XVUnmFZcZrlw8Md7kMW+3XA0"
+            .parse()
+            .unwrap();
+        let mut value = json!({"one": 1, "two": "Two", "unsigned": {"age_ts": 1000000}});
+        key.sign_json("domain", &mut value);
+        value["unsigned"] = json!({"age_ts": 2000000});
+
+        let mut keys = BTreeMap::new();
+        keys.insert(
+            "ed25519:1".to_owned(),
+            decode_base64(&key.public_key()).unwrap(),
+        );
+        assert_eq!(verify_signed_by(&value, "domain", &keys), Ok(()));
+        assert_eq!(
+            verify_signed_by(&value, "other", &keys),
+            Err(SignatureError::Missing)
+        );
+    }
+
+    #[test]
+    fn test_redact() {
+        let event = json!({
+            "type": "m.room.member",
+            "room_id": "!room:example.com",
+            "content": {"membership": "join", "displayname": "Alice"},
+            "unsigned": {"age": 5},
+            "extra": true,
+        });
+        assert_eq!(
+            redact(&event),
+            json!({
+                "type": "m.room.member",
+                "room_id": "!room:example.com",
+                "content": {"membership": "join"},
+            })
+        );
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let mut event = json!({
+            "type": "m.room.message",
+            "content": {"body": "hello"},
+            "unsigned": {"age": 5},
+        });
+        event["hashes"] = json!({"sha256": content_hash(&event)});
+        assert!(check_content_hash(&event));
+
+        // Unsigned data isn't covered by the hash
+        event["unsigned"] = json!({"age": 6});
+        assert!(check_content_hash(&event));
+
+        event["content"]["body"] = json!("goodbye");
+        assert!(!check_content_hash(&event));
+    }
+}
@@ -0,0 +1,164 @@
+//! The `X-Matrix` authorization scheme servers sign their requests to each
+//! other with.
+use std::fmt;
+use std::str::FromStr;
+
+use serde_json::{json, Value};
+
+/// The parameters of an `Authorization: X-Matrix ...` header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct XMatrix {
+    /// The server that sent the request
+    pub origin: String,
+    /// The server the request was meant for. Older servers leave it out.
+    pub destination: Option<String>,
+    /// The ID of the key the request was signed with
+    pub key: String,
+    /// The base64 encoded signature
+    pub sig: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidXMatrix;
+
+impl fmt::Display for InvalidXMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Malformed X-Matrix authorization")
+    }
+}
+
+impl std::error::Error for InvalidXMatrix {}
+
+impl FromStr for XMatrix {
+    type Err = InvalidXMatrix;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let params = match s.find(' ') {
+            Some(i) if s[..i].eq_ignore_ascii_case("X-Matrix") => &s[i + 1..],
+            _ => return Err(InvalidXMatrix),
+        };
+
+        let (mut origin, mut destination, mut key, mut sig) = (None, None, None, None);
+        for param in split_params(params) {
+            let mut split = param.splitn(2, '=');
+            let (name, value) = match (split.next(), split.next()) {
+                (Some(name), Some(value)) => (name.trim(), unquote(value.trim())),
+                _ => return Err(InvalidXMatrix),
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "origin" => origin = Some(value),
+                "destination" => destination = Some(value),
+                "key" => key = Some(value),
+                "sig" => sig = Some(value),
+                // Unknown parameters are ignored, so the scheme can grow
+                _ => {}
+            }
+        }
+        Ok(XMatrix {
+            origin: origin.ok_or(InvalidXMatrix)?,
+            destination,
+            key: key.ok_or(InvalidXMatrix)?,
+            sig: sig.ok_or(InvalidXMatrix)?,
+        })
+    }
+}
+
+impl fmt::Display for XMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "X-Matrix origin=\"{}\"", self.origin)?;
+        if let Some(destination) = &self.destination {
+            write!(f, ",destination=\"{}\"", destination)?;
+        }
+        write!(f, ",key=\"{}\",sig=\"{}\"", self.key, self.sig)
+    }
+}
+
+/// Splits comma separated parameters, ignoring commas within quotes.
+fn split_params(params: &str) -> Vec<&str> {
+    let mut split = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in params.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                split.push(&params[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    split.push(&params[start..]);
+    split.into_iter().filter(|p| !p.trim().is_empty()).collect()
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => value.to_owned(),
+    }
+}
+
+/// The JSON object a request's `X-Matrix` signature is made over. `uri` is
+/// the path and query string of the request, and `content` its JSON body,
+/// if it has one.
+pub fn signable_request(
+    method: &str,
+    uri: &str,
+    origin: &str,
+    destination: &str,
+    content: Option<&Value>,
+) -> Value {
+    let mut request = json!({
+        "method": method,
+        "uri": uri,
+        "origin": origin,
+        "destination": destination,
+    });
+    if let Some(content) = content {
+        request["content"] = content.clone();
+    }
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_xmatrix() {
+        let header = XMatrix {
+            origin: "origin.hs.example.com".to_owned(),
+            destination: Some("destination.hs.example.com".to_owned()),
+            key: "ed25519:key1".to_owned(),
+            sig: "ABCDEF...".to_owned(),
+        };
+        assert_eq!(
+            r#"X-Matrix origin="origin.hs.example.com",destination="destination.hs.example.com",key="ed25519:key1",sig="ABCDEF...""#
+                .parse(),
+            Ok(header.clone())
+        );
+        assert_eq!(header.to_string().parse(), Ok(header));
+    }
+
+    #[test]
+    fn test_parse_xmatrix_lenient() {
+        assert_eq!(
+            "x-matrix origin=origin.example.com, key=\"ed25519:key1\", sig=\"abc\", extra=\"a,b\""
+                .parse(),
+            Ok(XMatrix {
+                origin: "origin.example.com".to_owned(),
+                destination: None,
+                key: "ed25519:key1".to_owned(),
+                sig: "abc".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_xmatrix_rejects() {
+        assert!("Bearer abc".parse::<XMatrix>().is_err());
+        assert!("X-Matrix origin=a,key=b".parse::<XMatrix>().is_err());
+    }
+}
@@ -0,0 +1,136 @@
+//! Talking to identity servers, which map third party identifiers such as
+//! email addresses to Matrix IDs.
+//!
+//! Users invite someone by email through an identity server: it stores the
+//! invite and emails the address. Once the address's owner binds it to
+//! their Matrix ID, the identity server tells the owner's homeserver
+//! through `/_matrix/federation/v1/3pid/onbind`, with each invite signed
+//! by one of the keys it handed out when the invite was stored.
+use std::time::Duration;
+
+use actix_web::client::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{federation::signing, models::room::PublicKey};
+
+/// How long to wait for an identity server to respond.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An invite for an identity server to store, and tell the invitee about.
+#[derive(Debug, Serialize)]
+pub struct StoreInviteRequest<'a> {
+    pub medium: &'a str,
+    pub address: &'a str,
+    pub room_id: &'a str,
+    pub sender: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_display_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_avatar_url: Option<&'a str>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StoreInviteResponse {
+    /// Identifies the invite, once it is bound.
+    pub token: String,
+    /// The keys the bound invite may be signed with.
+    pub public_keys: Vec<PublicKey>,
+    /// A display name for the invitee, hiding most of the identifier.
+    pub display_name: String,
+}
+
+/// Whether `id_server` is a hostname, with an optional port, rather than a
+/// URL, as clients must give identity servers.
+pub fn is_valid_server_name(id_server: &str) -> bool {
+    !id_server.is_empty()
+        && id_server
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c))
+}
+
+/// Asks an identity server to store an invite, authenticated with the
+/// access token the inviting user registered with it.
+pub async fn store_invite(
+    id_server: &str,
+    id_access_token: &str,
+    invite: &StoreInviteRequest<'_>,
+) -> Result<StoreInviteResponse, String> {
+    let url = format!("https://{}/_matrix/identity/v2/store-invite", id_server);
+    let mut res = Client::build()
+        .timeout(TIMEOUT)
+        .finish()
+        .post(url)
+        .bearer_auth(id_access_token)
+        .send_json(invite)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("The identity server responded {}", res.status()));
+    }
+    res.json().await.map_err(|e| e.to_string())
+}
+
+/// Whether an identity server signed a bound invite with one of the keys it
+/// gave for it. The signature is made as the identity server, whatever its
+/// name, so every signature is tried.
+pub fn verify_signed(signed: &Value, public_keys: &[PublicKey]) -> bool {
+    let signatures = match signed.get("signatures").and_then(Value::as_object) {
+        Some(signatures) => signatures,
+        None => return false,
+    };
+    let public_keys: Vec<Vec<u8>> = public_keys
+        .iter()
+        .filter_map(|key| signing::decode_base64(&key.public_key))
+        .collect();
+    signatures.iter().any(|(server_name, keys)| {
+        keys.as_object().map_or(false, |keys| {
+            keys.keys()
+                .filter(|key_id| key_id.starts_with("ed25519:"))
+                .any(|key_id| {
+                    public_keys.iter().any(|public_key| {
+                        signing::verify_json(signed, server_name, key_id, public_key).is_ok()
+                    })
+                })
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::signing::SigningKey;
+    use serde_json::json;
+
+    #[test]
+    fn test_is_valid_server_name() {
+        assert!(is_valid_server_name("id.example.com"));
+        assert!(is_valid_server_name("id.example.com:8090"));
+        assert!(!is_valid_server_name("https://id.example.com"));
+        assert!(!is_valid_server_name("id.example.com/path"));
+        assert!(!is_valid_server_name(""));
+    }
+
+    #[test]
+    fn test_verify_signed() {
+        let key = SigningKey::from_seed("0", &[1; 32]).unwrap();
+        let public_key = PublicKey {
+            public_key: key.public_key(),
+            key_validity_url: "https://id.example.com/_matrix/identity/v2/pubkey/isvalid"
+                .to_owned(),
+        };
+        let mut signed = json!({ "mxid": "@alice:example.com", "token": "abc" });
+        key.sign_json("id.example.com", &mut signed);
+        assert!(verify_signed(&signed, &[public_key.clone()]));
+
+        let other = SigningKey::from_seed("0", &[2; 32]).unwrap();
+        let other_key = PublicKey {
+            public_key: other.public_key(),
+            ..public_key.clone()
+        };
+        assert!(!verify_signed(&signed, &[other_key]));
+
+        signed["mxid"] = json!("@mallory:example.com");
+        assert!(!verify_signed(&signed, &[public_key]));
+    }
+}
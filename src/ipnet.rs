@@ -0,0 +1,139 @@
+//! IP networks in CIDR notation, used wherever addresses are allowed or
+//! denied by configuration.
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 network, e.g. `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Whether `ip` lies within this network. IPv4-mapped IPv6 addresses
+    /// (`::ffff:a.b.c.d`) are matched as the IPv4 address they carry.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*ip, IpAddr::V4),
+            IpAddr::V4(_) => *ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+
+    /// Parses a comma separated list of networks, as found in configuration.
+    pub fn parse_list(list: &str) -> Result<Vec<IpNet>, InvalidIpNet> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|net| !net.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+/// Whether the first `prefix_len` bits of two addresses are equal.
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = usize::from(prefix_len / 8);
+    let rest_bits = prefix_len % 8;
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidIpNet(String);
+
+impl fmt::Display for InvalidIpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'{}' is not an IP network in CIDR notation.", self.0)
+    }
+}
+
+impl std::error::Error for InvalidIpNet {}
+
+impl FromStr for IpNet {
+    type Err = InvalidIpNet;
+
+    /// Parses `addr/prefix_len`. A bare address is a network of just that
+    /// address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidIpNet(s.to_owned());
+        let mut split = s.splitn(2, '/');
+        let addr: IpAddr = split
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match split.next() {
+            Some(len) => len.parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(IpNet { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_contains_v4() {
+        let net: IpNet = "172.16.0.0/12".parse().unwrap();
+        assert!(net.contains(&ip("172.16.0.1")));
+        assert!(net.contains(&ip("172.31.255.255")));
+        assert!(!net.contains(&ip("172.32.0.0")));
+        assert!(!net.contains(&ip("::1")));
+    }
+
+    #[test]
+    fn test_contains_v6() {
+        let net: IpNet = "fc00::/7".parse().unwrap();
+        assert!(net.contains(&ip("fd12:3456::1")));
+        assert!(!net.contains(&ip("fe80::1")));
+    }
+
+    #[test]
+    fn test_contains_v4_mapped() {
+        let net: IpNet = "127.0.0.0/8".parse().unwrap();
+        assert!(net.contains(&ip("::ffff:127.0.0.1")));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "10.0.0.1".parse::<IpNet>().unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("not an ip/8".parse::<IpNet>().is_err());
+        assert_eq!(IpNet::parse_list(" 10.0.0.0/8, ,::1 ").unwrap().len(), 2);
+    }
+}
@@ -1,8 +1,25 @@
 use dotenv::dotenv;
 
+mod appservice;
+mod audit;
+mod bus;
+mod cache;
+mod clock;
 mod db;
+mod federation;
+mod identity;
+mod ipnet;
+mod media;
 mod models;
+mod moderation;
+mod push;
+mod room_summary;
+mod scheduler;
 mod server;
+mod shutdown;
+mod stats;
+mod telemetry;
+mod webhooks;
 
 lazy_static::lazy_static! {
     pub static ref CONFIG: server::Config = server::Config::new_from_env();
@@ -12,8 +29,38 @@ lazy_static::lazy_static! {
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
 
+    // Prints an OpenAPI document of the implemented endpoints, which needs
+    // no configuration
+    if std::env::args().nth(1).as_deref() == Some("openapi") {
+        let document = server::openapi::document();
+        println!("{}", serde_json::to_string_pretty(&document)?);
+        return Ok(());
+    }
+
     &*CONFIG; // eagerly load config
-    let _server = server::start().await;
+    match std::env::args().nth(1).as_deref() {
+        // Runs the media retention policies once. `--dry-run` only lists
+        // what would be purged.
+        Some("purge-media") => {
+            let dry_run = std::env::args().any(|arg| arg == "--dry-run");
+            if let Err(e) = server::purge_media_command(dry_run).await {
+                eprintln!("Media purge failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        // Manages local users without the server running, e.g.
+        // `user create alice --admin`.
+        Some("user") => {
+            let args: Vec<String> = std::env::args().skip(2).collect();
+            if let Err(e) = server::cli::user_command(&args).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        _ => {
+            let _server = server::start().await;
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,214 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use actix_web::{error::BlockingError, web};
+use async_trait::async_trait;
+use futures::stream;
+
+use super::{ByteStream, MediaStore};
+
+/// How much of a file is read at a time when streaming it.
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Keeps media on the local filesystem.
+///
+/// Files are spread over two levels of directories named after the start of
+/// the media ID (`local_content/ab/cd/efgh...`), so no single directory grows
+/// too large. Thumbnails are kept in a directory per media ID under
+/// `local_thumbnails`, laid out the same way.
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    base_path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(base_path: impl Into<PathBuf>) -> Self {
+        FileStore {
+            base_path: base_path.into(),
+        }
+    }
+
+    /// The path a piece of local media is stored at. `media_id` must have
+    /// been checked with `is_valid_media_id`.
+    fn local_path(&self, media_id: &str) -> PathBuf {
+        self.sharded_path("local_content", media_id)
+    }
+
+    /// The path a thumbnail of a piece of local media is cached at.
+    fn thumbnail_path(&self, media_id: &str, name: &str) -> PathBuf {
+        self.sharded_path("local_thumbnails", media_id).join(name)
+    }
+
+    fn sharded_path(&self, dir: &str, media_id: &str) -> PathBuf {
+        let mut path = self.base_path.join(dir);
+        if media_id.len() > 4 {
+            path.push(&media_id[0..2]);
+            path.push(&media_id[2..4]);
+            path.push(&media_id[4..]);
+        } else {
+            path.push(media_id);
+        }
+        path
+    }
+}
+
+#[async_trait(?Send)]
+impl MediaStore for FileStore {
+    async fn put(&self, media_id: &str, data: web::Bytes) -> io::Result<()> {
+        let path = self.local_path(media_id);
+        blocking(move || write_file(path, &data)).await
+    }
+
+    async fn get(&self, media_id: &str) -> io::Result<Option<Vec<u8>>> {
+        let path = self.local_path(media_id);
+        blocking(move || read_file(path)).await
+    }
+
+    async fn get_stream(
+        &self,
+        media_id: &str,
+        range: Option<(u64, u64)>,
+    ) -> io::Result<Option<ByteStream>> {
+        let path = self.local_path(media_id);
+        let (start, len) = match range {
+            Some((start, end)) => (start, end - start + 1),
+            None => (0, u64::MAX),
+        };
+        let file = blocking(move || match File::open(&path) {
+            Ok(mut file) => {
+                file.seek(SeekFrom::Start(start))?;
+                Ok(Some(file))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        })
+        .await?;
+        Ok(file.map(|file| read_chunks(file, len)))
+    }
+
+    async fn put_thumbnail(&self, media_id: &str, name: &str, data: Vec<u8>) -> io::Result<()> {
+        let path = self.thumbnail_path(media_id, name);
+        blocking(move || write_file(path, &data)).await
+    }
+
+    async fn get_thumbnail(&self, media_id: &str, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let path = self.thumbnail_path(media_id, name);
+        blocking(move || read_file(path)).await
+    }
+
+    async fn delete(&self, media_id: &str) -> io::Result<()> {
+        let path = self.local_path(media_id);
+        let thumbnails = self.sharded_path("local_thumbnails", media_id);
+        blocking(move || {
+            ignore_not_found(std::fs::remove_file(path))?;
+            ignore_not_found(std::fs::remove_dir_all(thumbnails))
+        })
+        .await
+    }
+}
+
+fn write_file(path: PathBuf, data: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Write to a temporary file first, so a failed write never leaves a
+    // truncated file in place of the real one
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, data)?;
+    std::fs::rename(&tmp_path, &path)
+}
+
+fn read_file(path: PathBuf) -> io::Result<Option<Vec<u8>>> {
+    match std::fs::read(&path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Streams up to `len` bytes of `file`, reading each chunk on the blocking
+/// thread pool.
+fn read_chunks(file: File, len: u64) -> ByteStream {
+    Box::pin(stream::try_unfold(
+        (file, len),
+        |(mut file, remaining)| async move {
+            if remaining == 0 {
+                return Ok(None);
+            }
+            let (file, chunk) = blocking(move || {
+                let mut chunk = vec![0; remaining.min(CHUNK_SIZE) as usize];
+                let read = file.read(&mut chunk)?;
+                chunk.truncate(read);
+                Ok((file, chunk))
+            })
+            .await?;
+            if chunk.is_empty() {
+                return Ok(None);
+            }
+            let remaining = remaining - chunk.len() as u64;
+            Ok(Some((web::Bytes::from(chunk), (file, remaining))))
+        },
+    ))
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Runs filesystem work on the blocking thread pool.
+async fn blocking<F, I>(f: F) -> io::Result<I>
+where
+    F: FnOnce() -> io::Result<I> + Send + 'static,
+    I: Send + 'static,
+{
+    web::block(f).await.map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => io::Error::new(io::ErrorKind::Other, "Thread pool is gone"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    #[test]
+    fn test_local_path_layout() {
+        let store = FileStore::new("/var/lib/maelstrom/media");
+        assert_eq!(
+            store.local_path("abcdefghij"),
+            PathBuf::from("/var/lib/maelstrom/media/local_content/ab/cd/efghij")
+        );
+        assert_eq!(
+            store.thumbnail_path("abcdefghij", "32-32-crop"),
+            PathBuf::from("/var/lib/maelstrom/media/local_thumbnails/ab/cd/efghij/32-32-crop")
+        );
+    }
+
+    async fn read(store: &FileStore, media_id: &str, range: Option<(u64, u64)>) -> Option<Vec<u8>> {
+        let stream = store.get_stream(media_id, range).await.unwrap()?;
+        Some(stream.try_concat().await.unwrap().to_vec())
+    }
+
+    #[actix_rt::test]
+    async fn test_get_stream() {
+        let base_path =
+            std::env::temp_dir().join(format!("maelstrom-{}", crate::media::generate_media_id()));
+        let store = FileStore::new(&base_path);
+        let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        store.put("abcdefghij", data.clone().into()).await.unwrap();
+
+        assert_eq!(read(&store, "abcdefghij", None).await, Some(data.clone()));
+        assert_eq!(
+            read(&store, "abcdefghij", Some((70_000, 140_000))).await,
+            Some(data[70_000..=140_000].to_vec())
+        );
+        assert_eq!(read(&store, "missing", None).await, None);
+
+        std::fs::remove_dir_all(base_path).unwrap();
+    }
+}
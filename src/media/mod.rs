@@ -0,0 +1,240 @@
+//! The media repository: where uploaded content is kept, and the helpers the
+//! media endpoints share for naming and serving it.
+use std::io;
+use std::pin::Pin;
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures::Stream;
+use rand::{distributions::Alphanumeric, Rng};
+
+mod fs;
+pub mod preview;
+pub mod remote;
+pub mod retention;
+pub mod s3;
+pub mod scan;
+pub mod thumbnail;
+
+pub use fs::FileStore;
+pub use s3::S3Store;
+
+/// The content of a piece of media, read a chunk at a time.
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>>>>;
+
+/// A place to keep media content.
+///
+/// This trait encapsulates the storage of uploaded files and their
+/// thumbnails, e.g. on the local disk or in an object store. Metadata about
+/// the media is kept by the `Store`. Media IDs passed in must have been
+/// checked with `is_valid_media_id`.
+#[async_trait(?Send)]
+pub trait MediaStore: Clone + Send + 'static {
+    /// Stores the content of a piece of local media.
+    async fn put(&self, media_id: &str, data: Bytes) -> io::Result<()>;
+
+    /// Reads the content of a piece of local media, or `None` if there is
+    /// no such media.
+    async fn get(&self, media_id: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Streams the content of a piece of local media, or `None` if there is
+    /// no such media. Only the inclusive `(start, end)` byte range is read if
+    /// one is given, so large files never have to be held in memory.
+    async fn get_stream(
+        &self,
+        media_id: &str,
+        range: Option<(u64, u64)>,
+    ) -> io::Result<Option<ByteStream>>;
+
+    /// Caches a thumbnail of a piece of local media under `name`.
+    async fn put_thumbnail(&self, media_id: &str, name: &str, data: Vec<u8>) -> io::Result<()>;
+
+    /// Reads a cached thumbnail, or `None` if it hasn't been generated.
+    async fn get_thumbnail(&self, media_id: &str, name: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Deletes the content of a piece of media and its thumbnails. Deleting
+    /// media that doesn't exist is not an error.
+    async fn delete(&self, media_id: &str) -> io::Result<()>;
+}
+
+/// The length of generated media IDs.
+const MEDIA_ID_LENGTH: usize = 24;
+
+/// Generates a new random media ID.
+pub fn generate_media_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(MEDIA_ID_LENGTH)
+        .collect()
+}
+
+/// Whether `media_id` could have been generated by this server. Anything
+/// else is rejected before it gets near a filesystem path.
+pub fn is_valid_media_id(media_id: &str) -> bool {
+    !media_id.is_empty()
+        && media_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Magic numbers of common media formats, as `(offset, bytes, content type)`.
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (8, b"WEBP", "image/webp"),
+    (0, b"%PDF-", "application/pdf"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"\x1a\x45\xdf\xa3", "video/webm"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"ID3", "audio/mpeg"),
+];
+
+/// Guesses the content type of uploaded data from its first bytes.
+pub fn sniff_content_type(data: &[u8]) -> Option<&'static str> {
+    SIGNATURES
+        .iter()
+        .find(|(offset, magic, _)| data.get(*offset..offset + magic.len()) == Some(*magic))
+        .map(|(_, _, content_type)| *content_type)
+}
+
+/// The content type to store an upload under: the one the client declared,
+/// unless it declared nothing useful, in which case it is sniffed.
+pub fn content_type(declared: Option<&str>, data: &[u8]) -> String {
+    match declared.map(str::trim) {
+        Some(declared) if !declared.is_empty() && declared != "application/octet-stream" => {
+            declared.to_owned()
+        }
+        _ => sniff_content_type(data)
+            .unwrap_or("application/octet-stream")
+            .to_owned(),
+    }
+}
+
+/// The `Range` requested by a client cannot be served for this content.
+#[derive(Debug, PartialEq)]
+pub struct RangeNotSatisfiable;
+
+/// Parses a `Range` header against content of length `len`, returning the
+/// inclusive `(start, end)` byte positions to serve.
+///
+/// `Ok(None)` means the whole content should be served: the header was not
+/// a byte range, or asked for several ranges, which we don't support.
+pub fn parse_range(header: &str, len: u64) -> Result<Option<(u64, u64)>, RangeNotSatisfiable> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let mut split = spec.splitn(2, '-');
+    let (start, end) = match (split.next(), split.next()) {
+        (Some(start), Some(end)) => (start.trim(), end.trim()),
+        _ => return Ok(None),
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=-N: the last N bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 || len == 0 {
+                return Err(RangeNotSatisfiable);
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        // bytes=N-: everything from N
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        _ => return Ok(None),
+    };
+    if range.0 >= len {
+        return Err(RangeNotSatisfiable);
+    }
+    Ok(Some(range))
+}
+
+/// Builds an inline `Content-Disposition` header value naming the file.
+/// Names that aren't plain ASCII are sent percent-encoded (RFC 6266).
+pub fn content_disposition(file_name: &str) -> String {
+    let plain = file_name
+        .chars()
+        .all(|c| c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\');
+    if plain {
+        return format!("inline; filename=\"{}\"", file_name);
+    }
+    let encoded: String = file_name
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    format!("inline; filename*=utf-8''{}", encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_media_id_validation() {
+        assert!(is_valid_media_id(&generate_media_id()));
+        assert!(!is_valid_media_id(""));
+        assert!(!is_valid_media_id("../../etc/passwd"));
+        assert!(!is_valid_media_id("abc/def"));
+    }
+
+    #[test]
+    fn test_sniff_content_type() {
+        assert_eq!(
+            sniff_content_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some("image/png")
+        );
+        assert_eq!(
+            sniff_content_type(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(sniff_content_type(b"\0\0\0\x18ftypmp42"), Some("video/mp4"));
+        assert_eq!(sniff_content_type(b"hello"), None);
+        assert_eq!(sniff_content_type(b""), None);
+    }
+
+    #[test]
+    fn test_content_type_prefers_declared() {
+        assert_eq!(content_type(Some("text/plain"), b"GIF89a"), "text/plain");
+        assert_eq!(
+            content_type(Some("application/octet-stream"), b"GIF89a"),
+            "image/gif"
+        );
+        assert_eq!(content_type(None, b"???"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), Ok(Some((0, 9))));
+        assert_eq!(parse_range("bytes=90-", 100), Ok(Some((90, 99))));
+        assert_eq!(parse_range("bytes=-10", 100), Ok(Some((90, 99))));
+        assert_eq!(parse_range("bytes=-500", 100), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=50-500", 100), Ok(Some((50, 99))));
+        assert_eq!(parse_range("bytes=100-", 100), Err(RangeNotSatisfiable));
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), Ok(None));
+        assert_eq!(parse_range("items=0-1", 100), Ok(None));
+        assert_eq!(parse_range("bytes=9-0", 100), Ok(None));
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("cat.png"),
+            "inline; filename=\"cat.png\""
+        );
+        assert_eq!(
+            content_disposition("chat \"noir\".png"),
+            "inline; filename*=utf-8''chat%20%22noir%22.png"
+        );
+        assert_eq!(
+            content_disposition("ü.txt"),
+            "inline; filename*=utf-8''%C3%BC.txt"
+        );
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use actix_web::{
+    client::Client,
+    http::{header, StatusCode},
+    web,
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::Value;
+use url::Url;
+
+use crate::ipnet::IpNet;
+
+/// How many redirects are followed before giving up on a URL.
+const MAX_REDIRECTS: usize = 5;
+/// How long to wait for a remote page.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Networks that previews are never fetched from unless configured
+/// otherwise: loopback, private, link-local and other special-purpose
+/// ranges, where a preview could be used to reach internal services.
+pub const DEFAULT_IP_DENYLIST: &str = "127.0.0.0/8, 10.0.0.0/8, 172.16.0.0/12, \
+     192.168.0.0/16, 100.64.0.0/10, 169.254.0.0/16, 0.0.0.0/8, 192.0.0.0/24, \
+     192.0.2.0/24, 198.18.0.0/15, 198.51.100.0/24, 203.0.113.0/24, 224.0.0.0/4, \
+     240.0.0.0/4, ::1/128, ::/128, fe80::/10, fc00::/7, ff00::/8, 2001:db8::/32";
+
+/// Which remote hosts previews may be fetched from.
+#[derive(Clone, Debug, Default)]
+pub struct Denylist {
+    pub ips: Vec<IpNet>,
+    /// Denied host names. Subdomains of these are denied as well.
+    pub hosts: Vec<String>,
+}
+
+impl Denylist {
+    pub fn is_host_denied(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.hosts.iter().any(|denied| {
+            host == *denied
+                || (host.ends_with(denied.as_str())
+                    && host[..host.len() - denied.len()].ends_with('.'))
+        })
+    }
+
+    pub fn is_ip_denied(&self, ip: &IpAddr) -> bool {
+        self.ips.iter().any(|net| net.contains(ip))
+    }
+}
+
+#[derive(Debug)]
+pub enum PreviewError {
+    /// The URL is malformed, or not http(s).
+    InvalidUrl,
+    /// The URL points somewhere previews may not be fetched from.
+    Denied,
+    TooManyRedirects,
+    /// The remote server answered with an error status.
+    Status(StatusCode),
+    Request(String),
+}
+
+impl fmt::Display for PreviewError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreviewError::InvalidUrl => write!(f, "Invalid URL."),
+            PreviewError::Denied => write!(f, "URL is not allowed to be previewed."),
+            PreviewError::TooManyRedirects => write!(f, "Too many redirects."),
+            PreviewError::Status(status) => write!(f, "Remote server returned {}.", status),
+            PreviewError::Request(e) => write!(f, "Unable to fetch URL: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PreviewError {}
+
+/// A fetched resource.
+pub struct Fetched {
+    /// The URL the content was found at, after redirects
+    pub url: Url,
+    pub content_type: Option<String>,
+    pub body: web::Bytes,
+}
+
+/// Checks that a URL may be fetched: it must be http(s), its host must not
+/// be denied, and every address it resolves to must be allowed. Returns the
+/// address to connect to.
+///
+/// The host is only resolved here, and the request has to be sent to the
+/// returned address, so a DNS server answering differently the second time
+/// can't get around the IP denylist.
+pub async fn check_url(url: &Url, denylist: &Denylist) -> Result<SocketAddr, PreviewError> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(PreviewError::InvalidUrl);
+    }
+    let host = url.host_str().ok_or(PreviewError::InvalidUrl)?.to_owned();
+    if denylist.is_host_denied(&host) {
+        return Err(PreviewError::Denied);
+    }
+    let port = url
+        .port_or_known_default()
+        .ok_or(PreviewError::InvalidUrl)?;
+    let lookup_host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+    let addrs: Vec<SocketAddr> = web::block(move || {
+        (lookup_host.as_str(), port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect())
+    })
+    .await
+    .map_err(|e| PreviewError::Request(e.to_string()))?;
+    if addrs.iter().any(|addr| denylist.is_ip_denied(&addr.ip())) {
+        return Err(PreviewError::Denied);
+    }
+    addrs.into_iter().next().ok_or(PreviewError::Denied)
+}
+
+/// Fetches a URL, following redirects, reading at most `max_size` bytes of
+/// the body. Every URL along the way is checked against the denylist, and
+/// connected to at the address that was checked.
+pub async fn fetch(
+    url: &str,
+    denylist: &Denylist,
+    max_size: usize,
+) -> Result<Fetched, PreviewError> {
+    let client = Client::build()
+        .timeout(TIMEOUT)
+        .header(header::USER_AGENT, "maelstrom (URL preview)")
+        .finish();
+    let mut url = Url::parse(url).map_err(|_| PreviewError::InvalidUrl)?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let addr = check_url(&url, denylist).await?;
+        let mut res = client
+            .get(url.as_str())
+            .address(addr)
+            .send()
+            .await
+            .map_err(|e| PreviewError::Request(e.to_string()))?;
+
+        if res.status().is_redirection() {
+            let location = res
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .ok_or(PreviewError::Status(res.status()))?;
+            url = url.join(location).map_err(|_| PreviewError::InvalidUrl)?;
+            continue;
+        }
+        if !res.status().is_success() {
+            return Err(PreviewError::Status(res.status()));
+        }
+
+        let content_type = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+            .map(str::to_owned);
+        let body = res
+            .body()
+            .limit(max_size)
+            .await
+            .map_err(|e| PreviewError::Request(e.to_string()))?;
+        return Ok(Fetched {
+            url,
+            content_type,
+            body,
+        });
+    }
+    Err(PreviewError::TooManyRedirects)
+}
+
+lazy_static! {
+    static ref META_TAG: Regex = Regex::new(r"(?is)<meta\s[^>]*>").unwrap();
+    static ref ATTRIBUTE: Regex =
+        Regex::new(r#"(?s)([a-zA-Z][a-zA-Z0-9:_-]*)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>/]+))"#)
+            .unwrap();
+    static ref TITLE: Regex = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+}
+
+/// Decodes the handful of HTML entities common in titles and descriptions.
+fn decode_entities(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Extracts OpenGraph metadata from an HTML page.
+///
+/// `og:*` meta tags are taken as they are. Pages without an `og:title` or
+/// `og:description` fall back to their `<title>` and `description` meta tag.
+/// Relative `og:image` URLs are resolved against `base`.
+pub fn parse_open_graph(html: &str, base: &Url) -> BTreeMap<String, Value> {
+    let mut og = BTreeMap::new();
+    let mut description = None;
+
+    for tag in META_TAG.find_iter(html) {
+        let mut attributes = BTreeMap::new();
+        for attr in ATTRIBUTE.captures_iter(tag.as_str()) {
+            let value = attr
+                .get(2)
+                .or_else(|| attr.get(3))
+                .or_else(|| attr.get(4))
+                .map_or("", |v| v.as_str());
+            attributes.insert(attr[1].to_ascii_lowercase(), decode_entities(value.trim()));
+        }
+        let content = match attributes.remove("content") {
+            Some(content) => content,
+            None => continue,
+        };
+        let key = attributes
+            .remove("property")
+            .or_else(|| attributes.remove("name"))
+            .unwrap_or_default();
+        if key.starts_with("og:") {
+            og.entry(key).or_insert(Value::String(content));
+        } else if key.eq_ignore_ascii_case("description") {
+            description = description.or(Some(content));
+        }
+    }
+
+    if !og.contains_key("og:title") {
+        if let Some(title) = TITLE.captures(html) {
+            let title = decode_entities(title[1].trim());
+            if !title.is_empty() {
+                og.insert("og:title".to_owned(), Value::String(title));
+            }
+        }
+    }
+    if let Some(description) = description {
+        og.entry("og:description".to_owned())
+            .or_insert(Value::String(description));
+    }
+    let image = og
+        .get("og:image")
+        .and_then(Value::as_str)
+        .map(|image| base.join(image));
+    match image {
+        Some(Ok(image)) => {
+            og.insert("og:image".to_owned(), Value::String(image.into_string()));
+        }
+        Some(Err(_)) => {
+            og.remove("og:image");
+        }
+        None => {}
+    }
+    og
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_open_graph() {
+        let html = r#"<html><head>
+            <title>Fallback</title>
+            <meta property="og:title" content="Matrix &amp; friends" />
+            <meta content='A description' property='og:description'>
+            <meta property="og:image" content="/logo.png">
+            <meta name="twitter:card" content="summary">
+        </head></html>"#;
+        let base = Url::parse("https://example.com/blog/post").unwrap();
+        let og = parse_open_graph(html, &base);
+        assert_eq!(og["og:title"], "Matrix & friends");
+        assert_eq!(og["og:description"], "A description");
+        assert_eq!(og["og:image"], "https://example.com/logo.png");
+        assert!(!og.contains_key("twitter:card"));
+    }
+
+    #[test]
+    fn test_parse_open_graph_falls_back_to_title_and_description() {
+        let html = r#"<title> Plain page </title><meta name="description" content="Words">"#;
+        let base = Url::parse("https://example.com/").unwrap();
+        let og = parse_open_graph(html, &base);
+        assert_eq!(og["og:title"], "Plain page");
+        assert_eq!(og["og:description"], "Words");
+    }
+
+    #[test]
+    fn test_denylist() {
+        let denylist = Denylist {
+            ips: IpNet::parse_list(DEFAULT_IP_DENYLIST).unwrap(),
+            hosts: vec!["internal.example".to_owned()],
+        };
+        assert!(denylist.is_host_denied("internal.example"));
+        assert!(denylist.is_host_denied("api.INTERNAL.example."));
+        assert!(!denylist.is_host_denied("notinternal.example"));
+        assert!(denylist.is_ip_denied(&"10.1.2.3".parse().unwrap()));
+        assert!(denylist.is_ip_denied(&"::ffff:192.168.1.1".parse().unwrap()));
+        assert!(!denylist.is_ip_denied(&"93.184.216.34".parse().unwrap()));
+    }
+
+    #[actix_rt::test]
+    async fn test_check_url() {
+        let denylist = Denylist {
+            ips: IpNet::parse_list(DEFAULT_IP_DENYLIST).unwrap(),
+            hosts: vec![],
+        };
+        let url = Url::parse("http://93.184.216.34/page").unwrap();
+        assert_eq!(
+            check_url(&url, &denylist).await.unwrap(),
+            "93.184.216.34:80".parse().unwrap()
+        );
+        for denied in &[
+            "http://127.0.0.1:8008/",
+            "https://[::1]/",
+            "http://10.0.0.1/",
+        ] {
+            let url = Url::parse(denied).unwrap();
+            assert!(matches!(
+                check_url(&url, &denylist).await,
+                Err(PreviewError::Denied)
+            ));
+        }
+        let url = Url::parse("file:///etc/passwd").unwrap();
+        assert!(matches!(
+            check_url(&url, &denylist).await,
+            Err(PreviewError::InvalidUrl)
+        ));
+    }
+}
@@ -0,0 +1,446 @@
+//! Fetching media from other servers, so it can be cached and served to
+//! local clients.
+use std::fmt;
+use std::time::Duration;
+
+use actix_web::{
+    client::Client,
+    http::{header, Method, StatusCode},
+    web::Bytes,
+};
+use url::Url;
+
+use super::preview::{self, Denylist, PreviewError};
+use crate::federation::client;
+
+/// How long to wait for another server's media. Media can be large, so this
+/// is longer than for other federation requests.
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// Room for the JSON metadata part of a multipart response, on top of the
+/// content itself.
+const MULTIPART_OVERHEAD: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum RemoteMediaError {
+    /// The remote server doesn't have the media.
+    NotFound,
+    /// The remote server answered with an error status.
+    Status(StatusCode),
+    /// The remote server's response could not be understood.
+    Invalid(&'static str),
+    /// The remote server redirected somewhere media may not be fetched from.
+    Denied,
+    Request(String),
+}
+
+impl fmt::Display for RemoteMediaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemoteMediaError::NotFound => write!(f, "Remote server does not have the media."),
+            RemoteMediaError::Status(status) => write!(f, "Remote server returned {}.", status),
+            RemoteMediaError::Invalid(e) => write!(f, "Invalid response from remote server: {}", e),
+            RemoteMediaError::Denied => write!(f, "Remote server redirected to a denied address."),
+            RemoteMediaError::Request(e) => write!(f, "Unable to fetch remote media: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RemoteMediaError {}
+
+/// A piece of media fetched from another server.
+#[derive(Debug, PartialEq)]
+pub struct Fetched {
+    pub content_type: Option<String>,
+    /// The file name the remote server gave, if any
+    pub upload_name: Option<String>,
+    pub body: Bytes,
+}
+
+/// Fetches a piece of media from the server it was uploaded to, reading at
+/// most `max_size` bytes of it.
+///
+/// The authenticated federation endpoint is tried first. Servers that don't
+/// have it yet are asked through the legacy media API instead, with
+/// `allow_remote=false` so they don't go fetching it from elsewhere. A
+/// server may redirect to the content, which is only followed to addresses
+/// `denylist` allows, like URL previews.
+pub async fn fetch(
+    origin: &str,
+    media_id: &str,
+    denylist: &Denylist,
+    max_size: usize,
+) -> Result<Fetched, RemoteMediaError> {
+    match fetch_federation(origin, media_id, denylist, max_size).await {
+        // Servers without the endpoint respond 404 M_UNRECOGNIZED, which
+        // can't be told apart from missing media by its status alone
+        Err(RemoteMediaError::NotFound)
+        | Err(RemoteMediaError::Status(StatusCode::METHOD_NOT_ALLOWED)) => {
+            fetch_legacy(origin, media_id, max_size).await
+        }
+        result => result,
+    }
+}
+
+/// GET /_matrix/federation/v1/media/download/{mediaId}
+async fn fetch_federation(
+    origin: &str,
+    media_id: &str,
+    denylist: &Denylist,
+    max_size: usize,
+) -> Result<Fetched, RemoteMediaError> {
+    let uri = format!("/_matrix/federation/v1/media/download/{}", media_id);
+    let mut res = client::request(Method::GET, origin, &uri, None)
+        .await
+        .timeout(TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| RemoteMediaError::Request(e.to_string()))?;
+    check_status(res.status())?;
+
+    let boundary = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .and_then(multipart_boundary)
+        .ok_or(RemoteMediaError::Invalid(
+            "expected a multipart/mixed response",
+        ))?;
+    let body = res
+        .body()
+        .limit(max_size + MULTIPART_OVERHEAD)
+        .await
+        .map_err(|e| RemoteMediaError::Request(e.to_string()))?;
+    let parts = parse_multipart(&body, &boundary);
+    // The first part is JSON metadata, which has nothing in it yet
+    let content = parts
+        .get(1)
+        .ok_or(RemoteMediaError::Invalid("missing content part"))?;
+
+    if let Some(location) = content.header("location") {
+        return fetch_url(location, denylist, max_size).await;
+    }
+    if content.body.len() > max_size {
+        return Err(RemoteMediaError::Request("Media is too large.".to_owned()));
+    }
+    Ok(Fetched {
+        content_type: content.header("content-type").map(str::to_owned),
+        upload_name: content
+            .header("content-disposition")
+            .and_then(disposition_file_name),
+        body: body.slice_ref(content.body),
+    })
+}
+
+/// GET /_matrix/media/r0/download/{serverName}/{mediaId}
+async fn fetch_legacy(
+    origin: &str,
+    media_id: &str,
+    max_size: usize,
+) -> Result<Fetched, RemoteMediaError> {
+    let uri = format!(
+        "/_matrix/media/r0/download/{}/{}?allow_remote=false",
+        origin, media_id
+    );
+    let mut res = client::unsigned_request(Method::GET, origin, &uri)
+        .await
+        .timeout(TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| RemoteMediaError::Request(e.to_string()))?;
+    check_status(res.status())?;
+    fetched_from(&mut res, max_size).await
+}
+
+/// Follows the redirect a server may give instead of the content, such as
+/// to a CDN. Any server can give one, so it is checked against `denylist`,
+/// and connected to at the address that was checked.
+async fn fetch_url(
+    url: &str,
+    denylist: &Denylist,
+    max_size: usize,
+) -> Result<Fetched, RemoteMediaError> {
+    let url = Url::parse(url).map_err(|_| RemoteMediaError::Invalid("invalid redirect URL"))?;
+    let addr = preview::check_url(&url, denylist)
+        .await
+        .map_err(|e| match e {
+            PreviewError::InvalidUrl => RemoteMediaError::Invalid("invalid redirect URL"),
+            PreviewError::Denied => RemoteMediaError::Denied,
+            e => RemoteMediaError::Request(e.to_string()),
+        })?;
+    let mut res = Client::build()
+        .timeout(TIMEOUT)
+        .finish()
+        .get(url.as_str())
+        .address(addr)
+        .send()
+        .await
+        .map_err(|e| RemoteMediaError::Request(e.to_string()))?;
+    check_status(res.status())?;
+    fetched_from(&mut res, max_size).await
+}
+
+fn check_status(status: StatusCode) -> Result<(), RemoteMediaError> {
+    match status {
+        StatusCode::OK => Ok(()),
+        StatusCode::NOT_FOUND => Err(RemoteMediaError::NotFound),
+        status => Err(RemoteMediaError::Status(status)),
+    }
+}
+
+async fn fetched_from<S>(
+    res: &mut actix_web::client::ClientResponse<S>,
+    max_size: usize,
+) -> Result<Fetched, RemoteMediaError>
+where
+    S: futures::Stream<Item = Result<Bytes, actix_web::error::PayloadError>> + Unpin,
+{
+    let content_type = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .map(str::to_owned);
+    let upload_name = res
+        .headers()
+        .get(header::CONTENT_DISPOSITION)
+        .and_then(|d| d.to_str().ok())
+        .and_then(disposition_file_name);
+    let body = res
+        .body()
+        .limit(max_size)
+        .await
+        .map_err(|e| RemoteMediaError::Request(e.to_string()))?;
+    Ok(Fetched {
+        content_type,
+        upload_name,
+        body,
+    })
+}
+
+/// A part of a multipart body.
+#[derive(Debug, PartialEq)]
+struct Part<'a> {
+    /// Header names, lowercased, and their values
+    headers: Vec<(String, &'a str)>,
+    body: &'a [u8],
+}
+
+impl<'a> Part<'a> {
+    fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+/// Gets the boundary of a `multipart/mixed` content type.
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/mixed")
+    {
+        return None;
+    }
+    params
+        .filter_map(|param| {
+            let mut split = param.trim().splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(name), Some(value)) if name.eq_ignore_ascii_case("boundary") => {
+                    Some(value.trim_matches('"').to_owned())
+                }
+                _ => None,
+            }
+        })
+        .find(|boundary| !boundary.is_empty())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Splits a multipart body into its parts (RFC 2046). Anything after a part
+/// that isn't terminated properly is ignored.
+fn parse_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<Part<'a>> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = Vec::new();
+    let mut rest = match find(body, delimiter.as_bytes()) {
+        Some(i) => &body[i + delimiter.len()..],
+        None => return parts,
+    };
+    // Parts are separated by the delimiter on a line of its own
+    let separator = format!("\r\n{}", delimiter);
+
+    // After the last part the delimiter is followed by `--`
+    while !rest.starts_with(b"--") {
+        let part_start = match find(rest, b"\r\n") {
+            Some(i) => i + 2,
+            None => break,
+        };
+        rest = &rest[part_start..];
+        let part_end = match find(rest, separator.as_bytes()) {
+            Some(i) => i,
+            None => break,
+        };
+        let part = &rest[..part_end];
+        rest = &rest[part_end + separator.len()..];
+
+        // A part with no headers starts with the blank line
+        let (head, body) = if part.starts_with(b"\r\n") {
+            (&part[..0], &part[2..])
+        } else {
+            match find(part, b"\r\n\r\n") {
+                Some(i) => (&part[..i], &part[i + 4..]),
+                None => break,
+            }
+        };
+        let headers = std::str::from_utf8(head)
+            .unwrap_or("")
+            .split("\r\n")
+            .filter_map(|line| {
+                let mut split = line.splitn(2, ':');
+                match (split.next(), split.next()) {
+                    (Some(name), Some(value)) => {
+                        Some((name.trim().to_ascii_lowercase(), value.trim()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        parts.push(Part { headers, body });
+    }
+    parts
+}
+
+/// Gets the file name from a `Content-Disposition` header, preferring the
+/// percent-encoded `filename*` when there is one (RFC 6266).
+fn disposition_file_name(disposition: &str) -> Option<String> {
+    let mut file_name = None;
+    for param in disposition.split(';').skip(1) {
+        let mut split = param.trim().splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(name), Some(value)) if name.eq_ignore_ascii_case("filename*") => {
+                let mut split = value.splitn(3, '\'');
+                if let (Some(charset), Some(_), Some(encoded)) =
+                    (split.next(), split.next(), split.next())
+                {
+                    if charset.eq_ignore_ascii_case("utf-8") {
+                        return percent_decode(encoded);
+                    }
+                }
+            }
+            (Some(name), Some(value)) if name.eq_ignore_ascii_case("filename") => {
+                file_name = Some(value.trim_matches('"').to_owned());
+            }
+            _ => {}
+        }
+    }
+    file_name.filter(|name| !name.is_empty())
+}
+
+fn percent_decode(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut iter = encoded.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multipart_boundary() {
+        assert_eq!(
+            multipart_boundary("multipart/mixed; boundary=abc123"),
+            Some("abc123".to_owned())
+        );
+        assert_eq!(
+            multipart_boundary("Multipart/Mixed; charset=utf-8; boundary=\"a b\""),
+            Some("a b".to_owned())
+        );
+        assert_eq!(multipart_boundary("image/png"), None);
+        assert_eq!(multipart_boundary("multipart/mixed"), None);
+    }
+
+    #[test]
+    fn test_parse_multipart() {
+        let body = b"--xyz\r\n\
+            Content-Type: application/json\r\n\
+            \r\n\
+            {}\r\n\
+            --xyz\r\n\
+            Content-Type: text/plain\r\n\
+            Content-Disposition: inline; filename=\"a.txt\"\r\n\
+            \r\n\
+            hello\r\nworld\r\n\
+            --xyz--\r\n";
+        let parts = parse_multipart(body, "xyz");
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].header("content-type"), Some("application/json"));
+        assert_eq!(parts[0].body, b"{}");
+        assert_eq!(parts[1].header("content-type"), Some("text/plain"));
+        assert_eq!(parts[1].body, b"hello\r\nworld");
+    }
+
+    #[test]
+    fn test_parse_multipart_redirect() {
+        let body =
+            b"--xyz\r\n\r\n{}\r\n--xyz\r\nLocation: https://cdn.example.com/a\r\n\r\n\r\n--xyz--";
+        let parts = parse_multipart(body, "xyz");
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].headers.is_empty());
+        assert_eq!(
+            parts[1].header("location"),
+            Some("https://cdn.example.com/a")
+        );
+        assert_eq!(parts[1].body, b"");
+    }
+
+    #[test]
+    fn test_disposition_file_name() {
+        assert_eq!(
+            disposition_file_name("inline; filename=\"cat.png\""),
+            Some("cat.png".to_owned())
+        );
+        assert_eq!(
+            disposition_file_name("attachment; filename=\"u.txt\"; filename*=utf-8''%C3%BC.txt"),
+            Some("ü.txt".to_owned())
+        );
+        assert_eq!(disposition_file_name("inline"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_url_denied() {
+        let denylist = Denylist {
+            ips: crate::ipnet::IpNet::parse_list(preview::DEFAULT_IP_DENYLIST).unwrap(),
+            hosts: vec!["internal.example.com".to_owned()],
+        };
+        for location in &[
+            "http://169.254.169.254/latest/meta-data/",
+            "http://127.0.0.1:8008/_matrix/media/r0/download/a/b",
+            "http://[fd00::1]/media",
+            "https://internal.example.com/media",
+        ] {
+            assert!(matches!(
+                fetch_url(location, &denylist, 1024).await,
+                Err(RemoteMediaError::Denied)
+            ));
+        }
+        assert!(matches!(
+            fetch_url("file:///etc/passwd", &denylist, 1024).await,
+            Err(RemoteMediaError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,249 @@
+//! Media retention: works out which media the configured policies say
+//! should be removed, and removes it.
+use std::error::Error;
+use std::fmt;
+
+use super::MediaStore;
+use crate::db::Store;
+use crate::models::media::{mxc_uri, LocalMedia, RemoteMedia};
+
+/// The configured media policies. A policy left unset is not enforced.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    /// The most bytes of media a user may have uploaded at once
+    pub quota_bytes: Option<i64>,
+    /// How long cached remote media is kept after it was last served, in
+    /// milliseconds
+    pub remote_max_age_ms: Option<i64>,
+    /// The most bytes of remote media kept cached at once
+    pub remote_cache_bytes: Option<i64>,
+}
+
+/// Why a piece of media is to be purged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reason {
+    /// The uploader is over their quota; their oldest uploads go first.
+    OverQuota,
+    /// The uploader's account is deactivated.
+    Deactivated,
+    /// Cached remote media that hasn't been requested in a while.
+    Expired,
+    /// The remote media cache is over its size limit; the least recently
+    /// requested media goes first.
+    CacheFull,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Reason::OverQuota => "uploader over quota",
+            Reason::Deactivated => "uploader deactivated",
+            Reason::Expired => "remote media not accessed recently",
+            Reason::CacheFull => "remote media cache full",
+        })
+    }
+}
+
+/// A piece of media to purge.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Purge {
+    /// The server the media belongs to
+    pub origin: String,
+    pub media_id: String,
+    /// The ID of the content in the media store
+    pub file_id: String,
+    pub media_length: i64,
+    pub reason: Reason,
+}
+
+impl fmt::Display for Purge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} bytes, {})",
+            mxc_uri(&self.origin, &self.media_id),
+            self.media_length,
+            self.reason
+        )
+    }
+}
+
+impl Purge {
+    fn local(media: &LocalMedia, server_name: &str, reason: Reason) -> Self {
+        Purge {
+            origin: server_name.to_owned(),
+            media_id: media.media_id.clone(),
+            file_id: media.media_id.clone(),
+            media_length: media.media_length,
+            reason,
+        }
+    }
+
+    fn remote(media: RemoteMedia, reason: Reason) -> Self {
+        Purge {
+            origin: media.origin,
+            media_id: media.media_id,
+            file_id: media.file_id,
+            media_length: media.media_length,
+            reason,
+        }
+    }
+}
+
+/// How many of the first pieces of media, of the given sizes, have to go to
+/// bring their total back under `limit`.
+fn count_over(lengths: &[i64], limit: i64) -> usize {
+    let mut usage: i64 = lengths.iter().sum();
+    let mut purged = 0;
+    for length in lengths {
+        if usage <= limit {
+            break;
+        }
+        usage -= length;
+        purged += 1;
+    }
+    purged
+}
+
+/// Picks which of a user's uploads (given oldest first) have to go to bring
+/// them back under `quota`. The oldest uploads are removed first.
+pub fn over_quota(media: &[LocalMedia], quota: i64) -> &[LocalMedia] {
+    let lengths: Vec<i64> = media.iter().map(|m| m.media_length).collect();
+    &media[..count_over(&lengths, quota)]
+}
+
+/// Picks which cached remote media (given least recently served first) has
+/// to go to bring the cache back under `limit`.
+pub fn over_cache_limit(media: &[RemoteMedia], limit: i64) -> &[RemoteMedia] {
+    let lengths: Vec<i64> = media.iter().map(|m| m.media_length).collect();
+    &media[..count_over(&lengths, limit)]
+}
+
+/// Works out everything the policies say should be purged as of `now`.
+pub async fn plan<T: Store>(
+    storage: &T,
+    policy: &Policy,
+    server_name: &str,
+    now: i64,
+) -> Result<Vec<Purge>, Box<dyn Error>> {
+    let mut purges: Vec<Purge> = storage
+        .get_local_media_of_deactivated_users(server_name)
+        .await?
+        .iter()
+        .map(|media| Purge::local(media, server_name, Reason::Deactivated))
+        .collect();
+
+    if let Some(quota) = policy.quota_bytes {
+        for user_id in storage.get_users_over_media_quota(quota).await? {
+            let media = storage.get_local_media_by_user(&user_id).await?;
+            for media in over_quota(&media, quota) {
+                if !purges.iter().any(|p| p.media_id == media.media_id) {
+                    purges.push(Purge::local(media, server_name, Reason::OverQuota));
+                }
+            }
+        }
+    }
+
+    if let Some(max_age) = policy.remote_max_age_ms {
+        let expired = storage
+            .get_remote_media_accessed_before(now - max_age)
+            .await?;
+        purges.extend(
+            expired
+                .into_iter()
+                .map(|media| Purge::remote(media, Reason::Expired)),
+        );
+    }
+
+    if let Some(limit) = policy.remote_cache_bytes {
+        // Media that expired is going anyway, so it doesn't count
+        let cached: Vec<RemoteMedia> = storage
+            .get_remote_media_by_access()
+            .await?
+            .into_iter()
+            .filter(|media| {
+                !purges
+                    .iter()
+                    .any(|p| p.origin == media.origin && p.media_id == media.media_id)
+            })
+            .collect();
+        purges.extend(
+            over_cache_limit(&cached, limit)
+                .iter()
+                .cloned()
+                .map(|media| Purge::remote(media, Reason::CacheFull)),
+        );
+    }
+
+    Ok(purges)
+}
+
+/// Purges media, removing its content before its record so nothing is left
+/// behind if a deletion fails half way.
+pub async fn apply<T: Store, M: MediaStore>(
+    storage: &T,
+    media_store: &M,
+    server_name: &str,
+    purges: &[Purge],
+) -> Result<(), Box<dyn Error>> {
+    for purge in purges {
+        media_store.delete(&purge.file_id).await?;
+        if purge.origin == server_name {
+            storage.delete_local_media(&purge.media_id).await?;
+        } else {
+            storage
+                .delete_remote_media(&purge.origin, &purge.media_id)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn media(media_id: &str, media_length: i64) -> LocalMedia {
+        LocalMedia {
+            media_id: media_id.to_owned(),
+            content_type: "image/png".to_owned(),
+            media_length,
+            upload_name: None,
+            user_id: "@alice:example.com".to_owned(),
+            created_ts: 0,
+        }
+    }
+
+    #[test]
+    fn test_over_quota_removes_oldest_first() {
+        let uploads = vec![media("a", 40), media("b", 30), media("c", 50)];
+        let purged: Vec<&str> = over_quota(&uploads, 60)
+            .iter()
+            .map(|m| m.media_id.as_str())
+            .collect();
+        assert_eq!(purged, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_over_cache_limit_removes_least_recently_served() {
+        let remote = |media_id: &str, media_length: i64| RemoteMedia {
+            origin: "example.org".to_owned(),
+            media_id: media_id.to_owned(),
+            file_id: media_id.to_owned(),
+            content_type: "image/png".to_owned(),
+            media_length,
+            upload_name: None,
+            created_ts: 0,
+            last_access_ts: 0,
+        };
+        let cached = vec![remote("a", 10), remote("b", 10), remote("c", 10)];
+        assert_eq!(over_cache_limit(&cached, 15).len(), 2);
+        assert!(over_cache_limit(&cached, 30).is_empty());
+    }
+
+    #[test]
+    fn test_over_quota_keeps_everything_within_quota() {
+        let uploads = vec![media("a", 40), media("b", 20)];
+        assert!(over_quota(&uploads, 60).is_empty());
+    }
+}
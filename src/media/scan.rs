@@ -0,0 +1,187 @@
+//! Content scanning of uploads, e.g. for malware, by handing each upload to
+//! an external scanner before it is stored.
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+use actix_web::{error::BlockingError, web};
+
+/// How long a scanner may take to give a verdict on an upload.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The size of the chunks uploads are sent to clamd in.
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// An external content scanner.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Scanner {
+    /// A command run with the upload on its stdin. Like `clamscan`, it must
+    /// exit with 0 if the upload is clean and 1 if it was flagged, printing a
+    /// description of what was found; any other exit status is an error.
+    Command(Vec<String>),
+    /// A clamd daemon listening on a TCP address, sent the upload with
+    /// `INSTREAM`.
+    Clamd(String),
+}
+
+/// What a scanner made of an upload.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    Clean,
+    /// The upload was flagged, with the scanner's description of why
+    Flagged(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidScanner(String);
+
+impl fmt::Display for InvalidScanner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid media scanner `{}`, expected `clamd://host:port` or `command:<command line>`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidScanner {}
+
+impl FromStr for Scanner {
+    type Err = InvalidScanner;
+
+    /// Parses `clamd://host:port` or `command:<command line>`. The command
+    /// line is split on whitespace; it isn't run through a shell.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(addr) = s.strip_prefix("clamd://") {
+            if !addr.is_empty() {
+                return Ok(Scanner::Clamd(addr.to_owned()));
+            }
+        } else if let Some(command) = s.strip_prefix("command:") {
+            let args: Vec<String> = command.split_whitespace().map(str::to_owned).collect();
+            if !args.is_empty() {
+                return Ok(Scanner::Command(args));
+            }
+        }
+        Err(InvalidScanner(s.to_owned()))
+    }
+}
+
+impl Scanner {
+    /// Scans an upload. Scanners are blocking, so this runs on the blocking
+    /// thread pool.
+    pub async fn scan(&self, data: web::Bytes) -> io::Result<Verdict> {
+        let scanner = self.clone();
+        web::block(move || match scanner {
+            Scanner::Command(args) => scan_command(&args, &data),
+            Scanner::Clamd(addr) => scan_clamd(&addr, &data),
+        })
+        .await
+        .map_err(|e| match e {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => io::Error::new(io::ErrorKind::Other, "Thread pool is gone"),
+        })
+    }
+}
+
+fn scan_command(args: &[String], data: &[u8]) -> io::Result<Verdict> {
+    let mut child = Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    // Scanners may stop reading once they've seen enough, so a broken pipe
+    // isn't an error; the exit status says what they found
+    if let Some(mut stdin) = child.stdin.take() {
+        match stdin.write_all(data) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+            _ => {}
+        }
+    }
+    let output = child.wait_with_output()?;
+    match output.status.code() {
+        Some(0) => Ok(Verdict::Clean),
+        Some(1) => Ok(Verdict::Flagged(
+            String::from_utf8_lossy(&output.stdout).trim().to_owned(),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Media scanner failed with {}", output.status),
+        )),
+    }
+}
+
+fn scan_clamd(addr: &str, data: &[u8]) -> io::Result<Verdict> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    // Each chunk is prefixed with its length, and a zero length ends the
+    // stream
+    stream.write_all(b"zINSTREAM\0")?;
+    for chunk in data.chunks(CLAMD_CHUNK_SIZE) {
+        stream.write_all(&(chunk.len() as u32).to_be_bytes())?;
+        stream.write_all(chunk)?;
+    }
+    stream.write_all(&[0; 4])?;
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    parse_clamd_reply(&String::from_utf8_lossy(&reply))
+}
+
+/// Parses clamd's reply to `INSTREAM`, e.g. `stream: OK` or
+/// `stream: Eicar-Signature FOUND`.
+fn parse_clamd_reply(reply: &str) -> io::Result<Verdict> {
+    let reply = reply.trim_end_matches('\0').trim();
+    let result = reply.strip_prefix("stream:").map(str::trim);
+    match result {
+        Some("OK") => Ok(Verdict::Clean),
+        Some(result) if result.ends_with(" FOUND") => Ok(Verdict::Flagged(
+            result.trim_end_matches(" FOUND").to_owned(),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Unexpected reply from clamd: {}", reply),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scanner() {
+        assert_eq!(
+            "clamd://127.0.0.1:3310".parse(),
+            Ok(Scanner::Clamd("127.0.0.1:3310".to_owned()))
+        );
+        assert_eq!(
+            "command:clamdscan --no-summary -".parse(),
+            Ok(Scanner::Command(vec![
+                "clamdscan".to_owned(),
+                "--no-summary".to_owned(),
+                "-".to_owned()
+            ]))
+        );
+        assert!("clamd://".parse::<Scanner>().is_err());
+        assert!("command: ".parse::<Scanner>().is_err());
+        assert!("icap://localhost".parse::<Scanner>().is_err());
+    }
+
+    #[test]
+    fn test_parse_clamd_reply() {
+        assert_eq!(parse_clamd_reply("stream: OK\0").unwrap(), Verdict::Clean);
+        assert_eq!(
+            parse_clamd_reply("stream: Win.Test.EICAR_HDB-1 FOUND\0").unwrap(),
+            Verdict::Flagged("Win.Test.EICAR_HDB-1".to_owned())
+        );
+        assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+}
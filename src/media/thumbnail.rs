@@ -0,0 +1,225 @@
+use std::fmt;
+use std::io::Cursor;
+
+use image::{imageops::FilterType, GenericImageView, ImageOutputFormat};
+use serde::Deserialize;
+
+/// How a thumbnail is fitted to the requested size.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Method {
+    /// Fill the requested size exactly, cropping whatever sticks out.
+    Crop,
+    /// Fit within the requested size, keeping the aspect ratio.
+    Scale,
+}
+
+/// A thumbnail size, as kept in the thumbnail cache.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Spec {
+    pub width: u32,
+    pub height: u32,
+    pub method: Method,
+}
+
+impl Spec {
+    /// The name the thumbnail is cached under.
+    pub fn name(&self) -> String {
+        let method = match self.method {
+            Method::Crop => "crop",
+            Method::Scale => "scale",
+        };
+        format!("{}-{}-{}", self.width, self.height, method)
+    }
+}
+
+/// The sizes generated for every uploaded image. Requests are served from
+/// the closest of these, so clients can't fill the cache with arbitrary
+/// sizes.
+pub const SIZES: &[Spec] = &[
+    Spec {
+        width: 32,
+        height: 32,
+        method: Method::Crop,
+    },
+    Spec {
+        width: 96,
+        height: 96,
+        method: Method::Crop,
+    },
+    Spec {
+        width: 320,
+        height: 240,
+        method: Method::Scale,
+    },
+    Spec {
+        width: 640,
+        height: 480,
+        method: Method::Scale,
+    },
+    Spec {
+        width: 800,
+        height: 600,
+        method: Method::Scale,
+    },
+];
+
+/// Picks the size to serve for a request: the smallest size at least as big
+/// as requested, preferring the requested method, or the biggest size if the
+/// request is larger than all of them.
+pub fn choose(width: u32, height: u32, method: Method) -> Spec {
+    let big_enough = |spec: &&Spec| spec.width >= width && spec.height >= height;
+    SIZES
+        .iter()
+        .filter(big_enough)
+        .find(|spec| spec.method == method)
+        .or_else(|| SIZES.iter().find(big_enough))
+        .or_else(|| SIZES.last())
+        .copied()
+        .unwrap_or(Spec {
+            width,
+            height,
+            method,
+        })
+}
+
+/// Whether we can thumbnail content of this type.
+pub fn is_thumbnailable(content_type: &str) -> bool {
+    matches!(
+        content_type,
+        "image/png" | "image/jpeg" | "image/gif" | "image/webp" | "image/bmp"
+    )
+}
+
+/// The content type of thumbnails of a source image. Formats that may carry
+/// transparency are thumbnailed as PNG, everything else as JPEG.
+pub fn content_type(source_type: &str) -> &'static str {
+    match source_type {
+        "image/png" | "image/gif" | "image/webp" => "image/png",
+        _ => "image/jpeg",
+    }
+}
+
+#[derive(Debug)]
+pub enum ThumbnailError {
+    /// The image has more pixels than we are willing to decode.
+    TooManyPixels(u64),
+    Image(image::ImageError),
+}
+
+impl fmt::Display for ThumbnailError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThumbnailError::TooManyPixels(pixels) => {
+                write!(f, "Image is too large to thumbnail ({} pixels).", pixels)
+            }
+            ThumbnailError::Image(e) => write!(f, "Unable to process image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ThumbnailError {}
+
+impl From<image::ImageError> for ThumbnailError {
+    fn from(e: image::ImageError) -> Self {
+        ThumbnailError::Image(e)
+    }
+}
+
+impl From<std::io::Error> for ThumbnailError {
+    fn from(e: std::io::Error) -> Self {
+        ThumbnailError::Image(e.into())
+    }
+}
+
+/// Generates a thumbnail of an image, encoded as `content_type(source_type)`.
+///
+/// The dimensions are read from the image header before anything is decoded,
+/// and images with more than `max_pixels` pixels are refused, so a small file
+/// can't claim a huge canvas and exhaust memory (a decompression bomb).
+/// Images are never scaled up.
+pub fn generate(
+    data: &[u8],
+    source_type: &str,
+    spec: Spec,
+    max_pixels: u64,
+) -> Result<Vec<u8>, ThumbnailError> {
+    let (width, height) = image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_dimensions()?;
+    let pixels = u64::from(width) * u64::from(height);
+    if pixels > max_pixels {
+        return Err(ThumbnailError::TooManyPixels(pixels));
+    }
+
+    let image = image::load_from_memory(data)?;
+    let thumbnail = match spec.method {
+        _ if image.width() <= spec.width && image.height() <= spec.height => image,
+        Method::Crop => image.resize_to_fill(spec.width, spec.height, FilterType::Triangle),
+        Method::Scale => image.resize(spec.width, spec.height, FilterType::Triangle),
+    };
+
+    let format = match content_type(source_type) {
+        "image/png" => ImageOutputFormat::Png,
+        _ => ImageOutputFormat::Jpeg(80),
+    };
+    let mut out = Vec::new();
+    thumbnail.write_to(&mut out, format)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, RgbImage};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut out, ImageOutputFormat::Png)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_choose_prefers_method_then_size() {
+        assert_eq!(choose(30, 30, Method::Crop), SIZES[0]);
+        assert_eq!(choose(50, 50, Method::Crop), SIZES[1]);
+        assert_eq!(choose(50, 50, Method::Scale), SIZES[2]);
+        assert_eq!(choose(100, 100, Method::Crop), SIZES[2]);
+        assert_eq!(choose(4000, 4000, Method::Scale), SIZES[4]);
+    }
+
+    #[test]
+    fn test_generate_crop() {
+        let spec = choose(96, 96, Method::Crop);
+        let thumbnail = generate(&png(400, 200), "image/png", spec, 1_000_000).unwrap();
+        let image = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!(image.dimensions(), (96, 96));
+    }
+
+    #[test]
+    fn test_generate_scale_keeps_aspect_ratio() {
+        let spec = choose(320, 240, Method::Scale);
+        let thumbnail = generate(&png(640, 160), "image/png", spec, 1_000_000).unwrap();
+        let image = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!(image.dimensions(), (320, 80));
+    }
+
+    #[test]
+    fn test_generate_never_upscales() {
+        let spec = choose(800, 600, Method::Scale);
+        let thumbnail = generate(&png(10, 10), "image/png", spec, 1_000_000).unwrap();
+        let image = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!(image.dimensions(), (10, 10));
+    }
+
+    #[test]
+    fn test_generate_refuses_huge_images() {
+        let spec = choose(32, 32, Method::Crop);
+        match generate(&png(200, 200), "image/png", spec, 10_000) {
+            Err(ThumbnailError::TooManyPixels(40_000)) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The APIs an IP block keeps clients out of.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Api {
+    /// The client, media and admin APIs, and everything else but federation
+    Client,
+    /// The server-server API, and the key API
+    Federation,
+}
+
+impl Api {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Api::Client => "client",
+            Api::Federation => "federation",
+        }
+    }
+
+    pub fn parse(api: &str) -> Option<Self> {
+        match api {
+            "client" => Some(Api::Client),
+            "federation" => Some(Api::Federation),
+            _ => None,
+        }
+    }
+}
+
+/// A network kept out of an API, by an admin or for too many failed logins.
+#[derive(Clone, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct IpBlock {
+    pub block_id: i64,
+    /// The network in CIDR notation, e.g. `192.0.2.0/24`
+    pub net: String,
+    pub api: Api,
+    pub reason: Option<String>,
+    /// When the block was added, as a unix timestamp (ms resolution).
+    pub created_ts: i64,
+    /// When the block is lifted, as a unix timestamp (ms resolution), if it
+    /// ever is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_ts: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct IpBlockPath {
+    pub block_id: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct AddIpBlockRequest {
+    /// The network in CIDR notation. A bare address blocks just it.
+    pub net: String,
+    pub api: Api,
+    pub reason: Option<String>,
+    /// How long the block lasts, in milliseconds. Without it the block lasts
+    /// until it is removed.
+    pub duration_ms: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct ListIpBlocksResponse {
+    pub blocks: Vec<IpBlock>,
+}
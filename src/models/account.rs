@@ -0,0 +1,98 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::auth::AuthData;
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DeactivateRequest {
+    /// Authentication for the User-Interactive Authentication API.
+    pub auth: Option<AuthData>,
+    /// Whether to erase the user's data and redact the events they sent.
+    #[serde(default)]
+    pub erase: bool,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct DeactivateResponse {
+    /// `success` if the user's third party identifiers were unbound from
+    /// their identity server, `no-support` if they couldn't be.
+    pub id_server_unbind_result: &'static str,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct PasswordRequest {
+    /// Authentication for the User-Interactive Authentication API.
+    pub auth: Option<AuthData>,
+    pub new_password: String,
+    /// Whether to log out the user's other devices. Defaults to `true`.
+    pub logout_devices: Option<bool>,
+}
+
+/// Which of a user's access tokens are refused before they expire.
+///
+/// Tokens issued up to `revoked_ts` are refused, except the kept device's
+/// issued after `kept_since_ts`, so a device that was logged out before
+/// isn't logged back in by being kept later.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenRevocation {
+    /// As a unix timestamp (ms resolution), like tokens' `iat_ms`.
+    pub revoked_ts: i64,
+    pub kept_device_id: Option<String>,
+    /// As a unix timestamp (ms resolution), like tokens' `iat_ms`.
+    pub kept_since_ts: i64,
+}
+
+impl TokenRevocation {
+    /// Revokes every token issued up to `now`, but the ones of
+    /// `kept_device_id`, on top of `previous`.
+    pub fn new(previous: Option<&Self>, now: i64, kept_device_id: Option<&str>) -> Self {
+        let kept_since_ts = match previous {
+            Some(previous) if previous.kept_device_id.as_deref() == kept_device_id => {
+                previous.kept_since_ts
+            }
+            Some(previous) => previous.revoked_ts,
+            None => i64::MIN,
+        };
+        TokenRevocation {
+            revoked_ts: now,
+            kept_device_id: kept_device_id.map(str::to_owned),
+            kept_since_ts,
+        }
+    }
+
+    /// Whether a token of `device_id` issued at `issued_ts` is refused.
+    pub fn revokes(&self, device_id: &str, issued_ts: i64) -> bool {
+        let kept =
+            self.kept_device_id.as_deref() == Some(device_id) && issued_ts > self.kept_since_ts;
+        issued_ts <= self.revoked_ts && !kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_revocation() {
+        let first = TokenRevocation::new(None, 100, Some("PHONE"));
+        assert!(first.revokes("LAPTOP", 50));
+        assert!(first.revokes("LAPTOP", 100));
+        assert!(!first.revokes("LAPTOP", 101));
+        assert!(!first.revokes("PHONE", 50));
+
+        // Keeping the laptop later doesn't bring back its revoked tokens
+        let second = TokenRevocation::new(Some(&first), 200, Some("LAPTOP"));
+        assert!(second.revokes("LAPTOP", 50));
+        assert!(!second.revokes("LAPTOP", 150));
+        assert!(second.revokes("PHONE", 150));
+
+        // Keeping the same device again keeps all of its tokens
+        let third = TokenRevocation::new(Some(&second), 300, Some("LAPTOP"));
+        assert!(!third.revokes("LAPTOP", 150));
+        assert!(third.revokes("LAPTOP", 50));
+
+        let all = TokenRevocation::new(Some(&third), 400, None);
+        assert!(all.revokes("LAPTOP", 350));
+        assert!(!all.revokes("LAPTOP", 401));
+    }
+}
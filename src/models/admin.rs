@@ -0,0 +1,376 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A local account, as the admin API sees it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Account {
+    pub localpart: String,
+    /// When the account was created, as a unix timestamp (ms resolution).
+    pub created_ts: i64,
+    pub is_admin: bool,
+    pub is_guest: bool,
+    pub deactivated: bool,
+    /// The application service the account belongs to, if any
+    pub appservice_id: Option<String>,
+    /// Whether the account's events are sent without being throttled
+    pub send_limit_exempt: bool,
+}
+
+#[derive(Deserialize)]
+pub struct UserPath {
+    pub user_id: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ListUsersParams {
+    /// The localpart to list users after, as given in `next_token`
+    pub from: Option<String>,
+    pub limit: Option<i64>,
+    /// Whether to list guests. Defaults to `true`.
+    pub guests: Option<bool>,
+    /// Whether to list deactivated users. Defaults to `false`.
+    pub deactivated: Option<bool>,
+}
+
+/// A user, as returned by the admin API.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct UserResponse {
+    /// The fully qualified user ID
+    pub name: String,
+    pub creation_ts: i64,
+    pub admin: bool,
+    pub guest: bool,
+    pub deactivated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub appservice_id: Option<String>,
+    /// Whether the user's events are sent without being throttled
+    pub send_limit_exempt: bool,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct ListUsersResponse {
+    pub users: Vec<UserResponse>,
+    /// Where to continue listing from, if there may be more users
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct CreateUserRequest {
+    /// The localpart of the new user
+    pub username: String,
+    /// The user's password. Without one the user can't log in with a
+    /// password.
+    pub password: Option<String>,
+    /// Whether the user is a server admin
+    #[serde(default)]
+    pub admin: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct ResetPasswordRequest {
+    pub new_password: String,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct SendLimitRequest {
+    /// Whether the user's events are sent without being throttled
+    pub exempt: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[schemars(rename = "AdminDeactivateRequest")]
+pub struct DeactivateRequest {
+    /// Whether to also erase the user's data
+    #[serde(default)]
+    pub erase: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RoomPath {
+    pub room_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct RoomMemberPath {
+    pub room_id: String,
+    pub user_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct JobPath {
+    pub job_id: i64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ListRoomsParams {
+    /// The room ID to list rooms after, as given in `next_token`
+    pub from: Option<String>,
+    pub limit: Option<i64>,
+}
+
+/// A room the server knows of, as the admin API sees it.
+///
+/// TODO: Add joined member counts once rooms exist.
+#[derive(Clone, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct AdminRoom {
+    pub room_id: String,
+    /// How many local users are invited to the room
+    pub invited_members: i64,
+    /// Whether the room was shut down, so local users can't join it
+    pub blocked: bool,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct ListRoomsResponse {
+    pub rooms: Vec<AdminRoom>,
+    /// Where to continue listing from, if there may be more rooms
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct ShutdownRequest {
+    /// Why the room was shut down, told to the users removed from it
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct PurgeHistoryRequest {
+    /// Events sent before this unix timestamp (ms resolution) are purged
+    pub before_ts: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct BulkInviteRequest {
+    /// The fully qualified IDs of the users to invite
+    pub user_ids: Vec<String>,
+}
+
+/// How a room's exported members are downloaded.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExportParams {
+    /// Defaults to `json`.
+    pub format: Option<ExportFormat>,
+}
+
+/// A user's membership of a room, as exported.
+#[derive(Clone, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct RoomMember {
+    pub user_id: String,
+    /// join, invite, leave, ban or knock
+    pub membership: String,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct ExportedMembersResponse {
+    pub room_id: String,
+    pub members: Vec<RoomMember>,
+}
+
+/// What a room moderation job does.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RoomJob {
+    /// Removes a local user from the room
+    RemoveMember { user_id: String },
+    /// Blocks the room, removes every local user from it and tombstones it
+    Shutdown { message: Option<String> },
+    /// Deletes the room's events sent before a unix timestamp (ms
+    /// resolution)
+    PurgeHistory { before_ts: i64 },
+    /// Snapshots the room's members, for admins to download
+    ExportMembers,
+    /// Invites users to the room on behalf of the admin who started the job
+    BulkInvite { user_ids: Vec<String> },
+}
+
+/// How far a room moderation job has got.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Complete,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Complete => "complete",
+            JobStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "pending" => Some(JobStatus::Pending),
+            "running" => Some(JobStatus::Running),
+            "complete" => Some(JobStatus::Complete),
+            "failed" => Some(JobStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A room moderation job, and its progress.
+#[derive(Clone, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct AdminJob {
+    pub job_id: i64,
+    pub room_id: String,
+    #[serde(flatten)]
+    pub job: RoomJob,
+    pub status: JobStatus,
+    /// What the job has done so far, e.g. `{"invites_removed": 2}`
+    pub progress: Value,
+    /// Why the job failed, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The admin who started the job
+    pub created_by: String,
+    pub created_ts: i64,
+    pub updated_ts: i64,
+}
+
+/// A job run in the background by the scheduler, either once or every
+/// so often.
+#[derive(Clone, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct ScheduledJob {
+    pub job_id: i64,
+    /// The task that runs the job, e.g. `media_retention`
+    pub kind: String,
+    /// What the task is given to run the job with
+    pub payload: Value,
+    /// Milliseconds between runs of a periodic job, or `None` for a job run
+    /// once
+    pub interval_ms: Option<i64>,
+    /// Pending between the runs of a periodic job
+    pub status: JobStatus,
+    /// When the job is next due, as a unix timestamp (ms resolution). A
+    /// running job is due again if it hasn't finished by then. For a
+    /// finished job run once, when it finished.
+    pub next_run_ts: i64,
+    /// When the job last started, as a unix timestamp (ms resolution).
+    pub last_run_ts: Option<i64>,
+    /// How many times in a row the job has failed
+    pub failures: i32,
+    /// Why the job last failed, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ListScheduledJobsParams {
+    /// Only lists jobs of this kind
+    pub kind: Option<String>,
+    /// Only lists jobs with this status
+    pub status: Option<JobStatus>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct ListScheduledJobsResponse {
+    pub jobs: Vec<ScheduledJob>,
+}
+
+/// Filters the audit log is listed with, newest entries first.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuditLogParams {
+    /// The ID of the entry to list entries before, as given in `next_token`
+    pub from: Option<i64>,
+    pub limit: Option<i64>,
+    /// Only lists the actions of this user
+    pub user_id: Option<String>,
+    /// Only lists actions starting with this, e.g. `admin.`
+    pub action: Option<String>,
+    /// Only lists actions from this unix timestamp (ms resolution) on
+    pub since: Option<i64>,
+    /// Only lists actions before this unix timestamp (ms resolution)
+    pub until: Option<i64>,
+}
+
+/// An entry of the audit log of security relevant actions.
+#[derive(Clone, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct AuditLogEntry {
+    pub id: i64,
+    /// When the action happened, as a unix timestamp (ms resolution).
+    pub ts: i64,
+    /// The user who performed the action, if any
+    pub user_id: Option<String>,
+    /// The client address the action was performed from, if any
+    pub ip: Option<String>,
+    /// What happened, e.g. `admin.user.deactivate`
+    pub action: String,
+    pub details: Value,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct AuditLogResponse {
+    pub entries: Vec<AuditLogEntry>,
+    /// Where to continue listing from, if there may be more entries
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_token: Option<i64>,
+}
+
+/// Aggregates about the server, as given to admins and, if the server is
+/// configured to, reported each day.
+#[derive(Clone, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct Statistics {
+    /// The server's name
+    pub homeserver: String,
+    pub server_version: String,
+    /// When the aggregates were gathered, as a unix timestamp (ms
+    /// resolution)
+    pub timestamp: i64,
+    /// The local accounts that aren't deactivated, guests included
+    pub total_users: i64,
+    /// The rooms the server knows of
+    pub total_room_count: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_room_job_serde() {
+        let job = RoomJob::PurgeHistory { before_ts: 1000 };
+        let value = serde_json::to_value(&job).unwrap();
+        assert_eq!(value, json!({"kind": "purge_history", "before_ts": 1000}));
+        assert_eq!(serde_json::from_value::<RoomJob>(value).unwrap(), job);
+
+        let value = serde_json::to_value(&RoomJob::ExportMembers).unwrap();
+        assert_eq!(value, json!({"kind": "export_members"}));
+        assert_eq!(
+            serde_json::from_value::<RoomJob>(value).unwrap(),
+            RoomJob::ExportMembers
+        );
+    }
+
+    #[test]
+    fn test_job_status_str() {
+        for status in [
+            JobStatus::Pending,
+            JobStatus::Running,
+            JobStatus::Complete,
+            JobStatus::Failed,
+        ]
+        .iter()
+        {
+            assert_eq!(JobStatus::parse(status.as_str()), Some(*status));
+        }
+        assert_eq!(JobStatus::parse("done"), None);
+    }
+}
@@ -0,0 +1,16 @@
+/// How far sending an application service the events queued for it has got.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AppServiceState {
+    /// The ID of the last transaction sent, or being sent
+    pub txn_id: i64,
+    /// The last queued event the service accepted
+    pub last_stream_id: i64,
+    /// The last queued event in the transaction being sent, if one is. It
+    /// is sent again with the same ID and events until it is accepted.
+    pub pending_stream_id: Option<i64>,
+    /// When sending last failed, as a unix timestamp (ms resolution).
+    pub retry_last_ts: i64,
+    /// How long to wait after the last failure before retrying, in ms, or 0
+    /// if the last transaction was accepted.
+    pub retry_interval: i64,
+}
@@ -16,36 +16,68 @@ pub struct LoginFlow {
     pub login_type: LoginType,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct UserId {
     pub local_part: String,
     pub domain: Cow<'static, str>,
 }
-impl<'de> serde::Deserialize<'de> for UserId {
-    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        let mut str_id: String = serde::Deserialize::deserialize(deserializer)?;
-        if let Some(colon_idx) = str_id.find(":") {
-            let domain = str_id.split_off(colon_idx + 1);
-            str_id.truncate(str_id.len() - 1);
-            Ok(UserId {
-                local_part: str_id,
-                domain: Cow::Owned(domain),
-            })
+impl UserId {
+    /// Parses a Matrix user ID of the form `@localpart:domain`. The leading
+    /// sigil is optional, and an ID without a domain is assumed to belong to
+    /// this homeserver.
+    pub fn parse(id: &str) -> Self {
+        let id = id.strip_prefix('@').unwrap_or(id);
+        if let Some(colon_idx) = id.find(':') {
+            UserId {
+                local_part: id[..colon_idx].to_owned(),
+                domain: Cow::Owned(id[colon_idx + 1..].to_owned()),
+            }
         } else {
-            Ok(UserId {
-                local_part: str_id,
+            UserId {
+                local_part: id.to_owned(),
                 domain: Cow::Borrowed(&CONFIG.hostname),
-            })
+            }
         }
     }
+
+    /// Whether this user belongs to this homeserver.
+    pub fn is_local(&self) -> bool {
+        self.domain == CONFIG.hostname.as_str()
+    }
+}
+impl std::fmt::Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "@{}:{}", self.local_part, self.domain)
+    }
+}
+impl<'de> serde::Deserialize<'de> for UserId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let str_id: String = serde::Deserialize::deserialize(deserializer)?;
+        Ok(UserId::parse(&str_id))
+    }
 }
 impl serde::Serialize for UserId {
     fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_str(&format!("{}:{}", self.local_part, self.domain))
+        serializer.collect_str(self)
     }
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+/// User IDs are given in their string form, e.g. `@alice:example.com`.
+impl schemars::JsonSchema for UserId {
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn schema_name() -> String {
+        "UserId".to_owned()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        gen.subschema_for::<String>()
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 #[serde(tag = "medium")]
 #[serde(rename_all = "lowercase")]
 pub enum ThirdParty {
@@ -53,7 +85,7 @@ pub enum ThirdParty {
     MSISDN { address: String },
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 #[serde(tag = "type")]
 pub enum UserIdentifier {
     #[serde(rename = "m.id.user")]
@@ -67,7 +99,7 @@ pub enum UserIdentifier {
     },
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 #[serde(tag = "type")]
 pub enum Challenge {
     #[serde(rename = "m.login.password")]
@@ -76,7 +108,35 @@ pub enum Challenge {
     Token { token: String },
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+/// Authentication data supplied to an endpoint protected by the
+/// User-Interactive Authentication API.
+#[derive(Clone, Debug, serde::Deserialize, schemars::JsonSchema)]
+pub struct AuthData {
+    /// The login type that the client is attempting to complete.
+    #[serde(rename = "type")]
+    pub auth_type: Option<String>,
+    /// The value of the session key given by the homeserver.
+    pub session: Option<String>,
+    /// Identifies the user, for the `m.login.password` stage.
+    pub identifier: Option<UserIdentifier>,
+    /// The user's password, for the `m.login.password` stage.
+    pub password: Option<String>,
+}
+
+/// A User-Interactive Authentication session, through which one request is
+/// authorized.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UiaSession {
+    /// The user and device the session was started for
+    pub localpart: String,
+    pub device_id: String,
+    /// What the session authorizes, e.g. `account.deactivate`
+    pub operation: String,
+    /// When the session was started, as a unix timestamp (ms resolution)
+    pub created_ts: i64,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub struct LoginRequest {
     #[serde(flatten)]
     pub challenge: Challenge,
@@ -85,19 +145,42 @@ pub struct LoginRequest {
     pub initial_device_display_name: Option<String>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub struct HomeserverInfo {
     pub base_url: Cow<'static, str>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub struct IdentityServerInfo {
+    pub base_url: Cow<'static, str>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub struct DiscoveryInfo {
     #[serde(rename = "m.homeserver")]
     pub homeserver: HomeserverInfo,
-    // TODO: identity server??
+    #[serde(rename = "m.identity_server", skip_serializing_if = "Option::is_none")]
+    pub identity_server: Option<IdentityServerInfo>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+impl DiscoveryInfo {
+    /// The discovery information of this homeserver, as configured.
+    pub fn from_config() -> Self {
+        DiscoveryInfo {
+            homeserver: HomeserverInfo {
+                base_url: Cow::Borrowed(&CONFIG.base_url),
+            },
+            identity_server: CONFIG
+                .identity_server
+                .as_deref()
+                .map(|base_url| IdentityServerInfo {
+                    base_url: Cow::Borrowed(base_url),
+                }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub struct LoginResponse {
     pub user_id: UserId,
     pub access_token: String,
@@ -0,0 +1,76 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::keys::UploadRequest;
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct PutRequest {
+    /// The ID of the new device.
+    pub device_id: String,
+    /// The encrypted device, opaque to the server. Must contain an
+    /// `algorithm` describing how it was dehydrated.
+    pub device_data: Value,
+    /// A display name to set for the new device.
+    pub initial_device_display_name: Option<String>,
+    /// The device's identity, one-time and fallback keys.
+    #[serde(flatten)]
+    pub keys: UploadRequest,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct DehydratedDevice {
+    /// The ID of the dehydrated device.
+    pub device_id: String,
+    /// The encrypted device, as uploaded.
+    pub device_data: Value,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct DeviceIdResponse {
+    /// The ID of the affected device.
+    pub device_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct EventsPath {
+    pub device_id: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct EventsRequest {
+    /// The `next_batch` of a previous response, acknowledging every event
+    /// it returned.
+    pub next_batch: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct EventsResponse {
+    /// To-device events sent to the dehydrated device.
+    pub events: Vec<Value>,
+    /// The token to pass to fetch the following batch.
+    pub next_batch: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_put_request_includes_keys() {
+        let req: PutRequest = serde_json::from_value(json!({
+            "device_id": "DEHYDRATED",
+            "device_data": {"algorithm": "org.matrix.msc3814.v1.olm"},
+            "device_keys": {"user_id": "@alice:example.com", "device_id": "DEHYDRATED"},
+            "one_time_keys": {"signed_curve25519:AAAAHQ": {"key": "abc"}},
+            "fallback_keys": {"signed_curve25519:AAAAGj": {"key": "def"}}
+        }))
+        .unwrap();
+        assert_eq!(req.device_id, "DEHYDRATED");
+        assert!(req.initial_device_display_name.is_none());
+        assert!(req.keys.device_keys.is_some());
+        assert_eq!(req.keys.one_time_keys.len(), 1);
+        assert_eq!(req.keys.fallback_keys.len(), 1);
+    }
+}
@@ -0,0 +1,114 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The query of `GET /publicRooms`, which clients send without a filter.
+#[derive(Deserialize)]
+pub struct PublicRoomsQuery {
+    /// The server whose directory to list, if not this one.
+    pub server: Option<String>,
+    pub limit: Option<usize>,
+    pub since: Option<String>,
+}
+
+/// The query of `POST /publicRooms`, whose other parameters are in its body.
+#[derive(Deserialize)]
+pub struct ServerQuery {
+    /// The server whose directory to list, if not this one.
+    pub server: Option<String>,
+}
+
+/// Which page of a room directory to list, and which rooms on it. Sent by
+/// clients, and to other servers, as a query string or JSON body.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, JsonSchema)]
+pub struct PublicRoomsRequest {
+    /// The most rooms to list.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// The `next_batch` or `prev_batch` of a previous page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+    #[serde(default, skip_serializing_if = "Filter::is_empty")]
+    pub filter: Filter,
+    /// Whether to list the rooms of every third party network as well.
+    #[serde(default)]
+    pub include_all_networks: bool,
+    /// The third party network to list the rooms of, instead of Matrix's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub third_party_instance_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, JsonSchema)]
+pub struct Filter {
+    /// A string to search rooms' names, topics and aliases for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generic_search_term: Option<String>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.generic_search_term.is_none()
+    }
+}
+
+/// A room published to a room directory.
+///
+/// TODO: Fill in the room's name, topic, aliases, avatar, history
+/// visibility and guest access from its current state once events are
+/// stored.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, JsonSchema)]
+pub struct PublicRoom {
+    pub room_id: String,
+    pub num_joined_members: i64,
+    pub world_readable: bool,
+    pub guest_can_join: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canonical_alias: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+}
+
+/// A page of a room directory.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, JsonSchema)]
+pub struct PublicRoomsResponse {
+    pub chunk: Vec<PublicRoom>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_batch: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_batch: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_room_count_estimate: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_public_rooms_request() {
+        let req: PublicRoomsRequest = serde_json::from_value(json!({
+            "limit": 10,
+            "filter": { "generic_search_term": "rust" },
+        }))
+        .unwrap();
+        assert_eq!(req.limit, Some(10));
+        assert_eq!(req.filter.generic_search_term.as_deref(), Some("rust"));
+        assert!(!req.include_all_networks);
+
+        // Sent on as it came, leaving out what wasn't given
+        let req = PublicRoomsRequest {
+            since: Some("20".to_owned()),
+            ..PublicRoomsRequest::default()
+        };
+        assert_eq!(
+            serde_json::to_value(&req).unwrap(),
+            json!({ "since": "20", "include_all_networks": false })
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{value::RawValue, Value};
+
+use crate::federation::canonical_json;
+
+/// An event, kept as the exact JSON text it was received or created as.
+///
+/// Passing it on, e.g. in a sync response or a transaction to another
+/// server, writes the text as it is instead of parsing and serializing the
+/// whole event again. Only the fields needed to route it are read, with
+/// `header`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct RawEvent(Box<RawValue>);
+
+/// The fields of an event needed to route it, borrowed from its JSON text
+/// unless they had to be unescaped.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+pub struct EventHeader<'a> {
+    /// Only carried by events of room versions 1 and 2
+    #[serde(borrow, default)]
+    pub event_id: Option<Cow<'a, str>>,
+    #[serde(borrow, default)]
+    pub room_id: Option<Cow<'a, str>>,
+    #[serde(borrow, default)]
+    pub sender: Option<Cow<'a, str>>,
+    #[serde(rename = "type", borrow, default)]
+    pub event_type: Option<Cow<'a, str>>,
+    /// Only carried by state events
+    #[serde(borrow, default)]
+    pub state_key: Option<Cow<'a, str>>,
+    #[serde(default)]
+    pub origin_server_ts: Option<i64>,
+}
+
+impl RawEvent {
+    /// Keeps JSON text as an event, checking it is valid JSON.
+    pub fn from_string(json: String) -> Result<Self, serde_json::Error> {
+        RawValue::from_string(json).map(RawEvent)
+    }
+
+    /// Keeps an event built as a value.
+    pub fn from_value(event: &Value) -> Result<Self, serde_json::Error> {
+        RawEvent::from_string(serde_json::to_string(event)?)
+    }
+
+    /// The event's JSON text.
+    pub fn json(&self) -> &str {
+        self.0.get()
+    }
+
+    /// Reads the fields needed to route the event, skipping the rest.
+    pub fn header(&self) -> Result<EventHeader<'_>, serde_json::Error> {
+        serde_json::from_str(self.0.get())
+    }
+
+    /// Parses the whole event.
+    pub fn to_value(&self) -> Result<Value, serde_json::Error> {
+        serde_json::from_str(self.0.get())
+    }
+
+    /// The event as Canonical JSON, as it is hashed and signed.
+    pub fn canonical(&self) -> Result<String, serde_json::Error> {
+        canonical_json::encode_raw(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const EVENT: &str = r#"{"type": "m.room.member", "room_id": "!room:example.com",
+        "sender": "@alice:example.com", "state_key": "@alice:example.com",
+        "origin_server_ts": 1432735824653, "content": {"membership": "join"}}"#;
+
+    #[test]
+    fn test_header() {
+        let event = RawEvent::from_string(EVENT.to_owned()).unwrap();
+        let header = event.header().unwrap();
+        assert_eq!(header.event_type.as_deref(), Some("m.room.member"));
+        assert_eq!(header.sender.as_deref(), Some("@alice:example.com"));
+        assert_eq!(header.state_key.as_deref(), Some("@alice:example.com"));
+        assert_eq!(header.origin_server_ts, Some(1432735824653));
+        assert_eq!(header.event_id, None);
+
+        // Escaped fields are unescaped
+        let event =
+            RawEvent::from_string(r#"{"sender": "@alice\u003aexample.com"}"#.to_owned()).unwrap();
+        assert_eq!(
+            event.header().unwrap().sender.as_deref(),
+            Some("@alice:example.com")
+        );
+        assert!(RawEvent::from_string("42".to_owned())
+            .unwrap()
+            .header()
+            .is_err());
+    }
+
+    #[test]
+    fn test_passes_through() {
+        // Serialized as the exact text, whitespace and key order included
+        let event = RawEvent::from_string(EVENT.to_owned()).unwrap();
+        let response = serde_json::to_string(&[&event]).unwrap();
+        assert_eq!(response, format!("[{}]", EVENT));
+
+        let events: Vec<RawEvent> = serde_json::from_str(&format!("[{}]", EVENT)).unwrap();
+        assert_eq!(events[0].json(), EVENT);
+        assert_eq!(
+            events[0].to_value().unwrap()["content"]["membership"],
+            "join"
+        );
+    }
+
+    #[test]
+    fn test_canonical() {
+        let event = RawEvent::from_value(&json!({"b": 1, "a": "é"})).unwrap();
+        assert_eq!(event.canonical().unwrap(), r#"{"a":"é","b":1}"#);
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Deserialize)]
+pub struct TransactionPath {
+    pub txn_id: String,
+}
+
+/// Asks the federation sender worker to send what is queued for a
+/// destination.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct WakeRequest {
+    pub destination: String,
+}
+
+/// A batch of events pushed from one server to another.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Transaction {
+    /// The server that sent the transaction.
+    pub origin: String,
+    /// When the transaction was created by the origin server.
+    pub origin_server_ts: i64,
+    /// Persistent events, as raw JSON so their signatures can be checked.
+    pub pdus: Vec<Value>,
+    /// Ephemeral messages.
+    #[serde(default)]
+    pub edus: Vec<Edu>,
+}
+
+/// An ephemeral message sent between servers.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Edu {
+    pub edu_type: String,
+    pub content: Value,
+}
+
+/// The content of an `m.direct_to_device` EDU.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DirectToDevice {
+    /// The user who sent the messages.
+    pub sender: String,
+    /// The type of the messages.
+    #[serde(rename = "type")]
+    pub event_type: String,
+    /// Unique per sending server, so retried EDUs can be ignored.
+    pub message_id: String,
+    /// The messages to send, as user ID to device ID (or `*`) to content.
+    pub messages: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+/// The content of an `m.device_list_update` or `m.signing_key_update` EDU.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeviceListUpdate {
+    /// The user whose devices changed.
+    pub user_id: String,
+}
+
+/// The content of an `m.presence` EDU.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PresenceEdu {
+    /// Presence updates of the sending server's users.
+    pub push: Vec<PresenceUpdate>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PresenceUpdate {
+    pub user_id: String,
+    pub presence: String,
+    #[serde(default)]
+    pub status_msg: Option<String>,
+    /// Milliseconds since the user was last active.
+    pub last_active_ago: i64,
+    #[serde(default)]
+    pub currently_active: bool,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct TransactionResponse {
+    /// The result of processing each PDU, by event ID. Successfully
+    /// processed PDUs have an empty result.
+    pub pdus: BTreeMap<String, PduResult>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, JsonSchema)]
+pub struct PduResult {
+    /// Why the PDU was rejected, if it was.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RoomPath {
+    pub room_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct MakeMembershipPath {
+    pub room_id: String,
+    pub user_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct EventPath {
+    pub room_id: String,
+    pub event_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct InviteRequest {
+    /// The version of the room the user is invited to.
+    pub room_version: String,
+    /// The invite event, signed by the inviting server.
+    pub event: Value,
+    /// Stripped state events giving the invited user an idea of the room.
+    #[serde(default)]
+    pub invite_room_state: Vec<Value>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct InviteResponse {
+    /// The invite event, with this server's signature added.
+    pub event: Value,
+}
+
+/// An invite of a local user to a room, as shown to them until they
+/// respond to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoomInvite {
+    pub room_id: String,
+    /// The invite event.
+    pub event: Value,
+    /// Stripped state events of the room, as given by the inviting server.
+    pub invite_room_state: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+pub struct MissingEventsRequest {
+    /// The most events to return.
+    #[serde(default = "default_missing_events_limit")]
+    pub limit: usize,
+    /// The lowest depth of events to return.
+    #[serde(default)]
+    pub min_depth: i64,
+    /// The events the requesting server already has.
+    pub earliest_events: Vec<String>,
+    /// The events the requesting server is missing the history of.
+    pub latest_events: Vec<String>,
+}
+
+fn default_missing_events_limit() -> usize {
+    10
+}
+
+/// The keys a server publishes at `/_matrix/key/v2/server`.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ServerKeys {
+    pub server_name: String,
+    /// The keys the server currently signs with, by key ID.
+    pub verify_keys: BTreeMap<String, VerifyKey>,
+    /// Keys the server used to sign with, by key ID.
+    #[serde(default)]
+    pub old_verify_keys: BTreeMap<String, OldVerifyKey>,
+    /// When the keys should be fetched again, as a unix timestamp in
+    /// milliseconds.
+    pub valid_until_ts: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct VerifyKey {
+    /// The unpadded base64 encoded public key.
+    pub key: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct OldVerifyKey {
+    /// The unpadded base64 encoded public key.
+    pub key: String,
+    /// When the key stopped being used, as a unix timestamp in milliseconds.
+    pub expired_ts: i64,
+}
+
+/// When delivery to a server that could not be reached may be tried again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DestinationRetry {
+    /// When delivery last failed, as a unix timestamp in milliseconds.
+    pub retry_last_ts: i64,
+    /// How long to wait after the last failure, in milliseconds.
+    pub retry_interval: i64,
+}
+
+/// A remote server's key, as cached.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerKey {
+    pub key_id: String,
+    /// The unpadded base64 encoded public key.
+    pub key: String,
+    /// Until when signatures made with the key can be trusted, as a unix
+    /// timestamp in milliseconds.
+    pub valid_until_ts: i64,
+    /// When the server stopped using the key, if it has.
+    pub expired_ts: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct KeyQueryResponse {
+    /// The keys of the servers queried, signed by the notary server.
+    pub server_keys: Vec<Value>,
+}
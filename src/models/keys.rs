@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::auth::AuthData;
+
+/// The usage of a user's master cross-signing key.
+pub const MASTER: &str = "master";
+/// The usage of the key that signs the user's own devices.
+pub const SELF_SIGNING: &str = "self_signing";
+/// The usage of the key that signs other users' master keys.
+pub const USER_SIGNING: &str = "user_signing";
+
+/// A map of `algorithm:key_id` to key object, as used for one-time and
+/// fallback keys.
+pub type KeyMap = BTreeMap<String, Value>;
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct UploadRequest {
+    /// Identity keys for the device. May be absent if no new identity keys
+    /// are required.
+    pub device_keys: Option<Value>,
+    /// One-time public keys for "pre-key" messages.
+    #[serde(default)]
+    pub one_time_keys: KeyMap,
+    /// Keys to hand out once the device's one-time keys have run out.
+    #[serde(default, alias = "org.matrix.msc2732.fallback_keys")]
+    pub fallback_keys: KeyMap,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct UploadResponse {
+    /// For each key algorithm, the number of unclaimed one-time keys of that
+    /// type the server has for this device.
+    pub one_time_key_counts: BTreeMap<String, i64>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct QueryRequest {
+    /// The time (in milliseconds) to wait when downloading keys from remote
+    /// servers.
+    pub timeout: Option<u64>,
+    /// The keys to be downloaded. An empty list of devices indicates all
+    /// devices for the corresponding user.
+    pub device_keys: BTreeMap<String, Vec<String>>,
+    /// If the client is fetching keys as a result of a device update
+    /// received in a sync request, this should be the 'since' token of that
+    /// sync request.
+    pub token: Option<String>,
+}
+
+/// Also the response of other servers to a query over federation, which
+/// leaves out what they have no reason to give.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(default)]
+pub struct QueryResponse {
+    /// Remote homeservers that could not be reached, keyed by server name.
+    pub failures: BTreeMap<String, Value>,
+    /// Information on the queried devices, keyed by user then device ID.
+    pub device_keys: BTreeMap<String, BTreeMap<String, Value>>,
+    /// The master cross-signing keys of the queried users.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub master_keys: BTreeMap<String, Value>,
+    /// The self-signing keys of the queried users.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub self_signing_keys: BTreeMap<String, Value>,
+    /// The user-signing key of the requesting user, if it was queried.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub user_signing_keys: BTreeMap<String, Value>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct ClaimRequest {
+    /// The time (in milliseconds) to wait when downloading keys from remote
+    /// servers.
+    pub timeout: Option<u64>,
+    /// The keys to be claimed, as user ID to device ID to algorithm name.
+    pub one_time_keys: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// Also the response of other servers to a claim over federation.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(default)]
+pub struct ClaimResponse {
+    /// Remote homeservers that could not be reached, keyed by server name.
+    pub failures: BTreeMap<String, Value>,
+    /// One-time keys for the queried devices, keyed by user then device ID.
+    pub one_time_keys: BTreeMap<String, BTreeMap<String, KeyMap>>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct DeviceSigningUploadRequest {
+    /// The user's master key.
+    pub master_key: Option<Value>,
+    /// The user's self-signing key, signed by the master key.
+    pub self_signing_key: Option<Value>,
+    /// The user's user-signing key, signed by the master key.
+    pub user_signing_key: Option<Value>,
+    /// Additional authentication information for the user-interactive
+    /// authentication API.
+    pub auth: Option<AuthData>,
+}
+
+/// Signed keys to publish, keyed by user ID then by device ID or, for
+/// cross-signing keys, the unpadded base64 public key.
+pub type SignaturesUploadRequest = BTreeMap<String, BTreeMap<String, Value>>;
+
+#[derive(Clone, Debug, Default, Serialize, JsonSchema)]
+pub struct SignaturesUploadResponse {
+    /// The signatures that could not be stored, keyed like the request.
+    pub failures: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+/// A signature made over one of `target_user_id`'s keys.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeySignature {
+    /// The fully qualified ID of the user who made the signature
+    pub signer: String,
+    /// The signer's key used, e.g. `ed25519:<public key>`
+    pub signing_key_id: String,
+    pub target_user_id: String,
+    /// The device ID or cross-signing public key that was signed
+    pub target_id: String,
+    pub signature: String,
+}
+
+/// Checks the shape of an uploaded cross-signing key, returning its key ID
+/// (`ed25519:<public key>`).
+pub fn validate_cross_signing_key(
+    key: &Value,
+    user_id: &str,
+    usage: &str,
+) -> Result<String, &'static str> {
+    if key.get("user_id").and_then(Value::as_str) != Some(user_id) {
+        return Err("Cross-signing key does not belong to the user.");
+    }
+    let has_usage = key
+        .get("usage")
+        .and_then(Value::as_array)
+        .map_or(false, |u| u.iter().any(|u| u.as_str() == Some(usage)));
+    if !has_usage {
+        return Err("Cross-signing key has the wrong usage.");
+    }
+    match key.get("keys").and_then(Value::as_object) {
+        Some(keys) if keys.len() == 1 => Ok(keys.keys().next().cloned().unwrap_or_default()),
+        _ => Err("Cross-signing key must contain exactly one public key."),
+    }
+}
+
+/// The public key part of a cross-signing key, i.e. the ID other users sign
+/// it under.
+pub fn public_key(key: &Value) -> Option<&str> {
+    key.get("keys")
+        .and_then(Value::as_object)
+        .and_then(|keys| keys.values().next())
+        .and_then(Value::as_str)
+}
+
+/// Whether `key` carries a signature by `signer` with `signing_key_id`.
+pub fn is_signed_by(key: &Value, signer: &str, signing_key_id: &str) -> bool {
+    key.get("signatures")
+        .and_then(|s| s.get(signer))
+        .and_then(|s| s.get(signing_key_id))
+        .is_some()
+}
+
+/// A key object with its `signatures` and `unsigned` sections removed, which
+/// is the part every signature covers.
+pub fn signed_part(key: &Value) -> Value {
+    let mut key = key.clone();
+    if let Some(obj) = key.as_object_mut() {
+        obj.remove("signatures");
+        obj.remove("unsigned");
+    }
+    key
+}
+
+/// Merges stored signatures into the `signatures` section of a key.
+pub fn add_signatures<'a, I: IntoIterator<Item = &'a KeySignature>>(key: &mut Value, sigs: I) {
+    for sig in sigs {
+        if !key["signatures"].is_object() {
+            key["signatures"] = Value::Object(Default::default());
+        }
+        if !key["signatures"][&sig.signer].is_object() {
+            key["signatures"][&sig.signer] = Value::Object(Default::default());
+        }
+        key["signatures"][&sig.signer][&sig.signing_key_id] = Value::String(sig.signature.clone());
+    }
+}
+
+/// A one-time or fallback key ready to be persisted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OneTimeKey {
+    pub algorithm: String,
+    pub key_id: String,
+    pub key: Value,
+}
+
+impl OneTimeKey {
+    /// Splits an uploaded `algorithm:key_id` map into individual keys.
+    /// Returns the offending key name if one is not of that form.
+    pub fn from_key_map(keys: KeyMap) -> Result<Vec<Self>, String> {
+        keys.into_iter()
+            .map(|(name, key)| {
+                let mut split = name.splitn(2, ':');
+                match (split.next(), split.next()) {
+                    (Some(algorithm), Some(key_id))
+                        if !algorithm.is_empty() && !key_id.is_empty() =>
+                    {
+                        Ok(OneTimeKey {
+                            algorithm: algorithm.to_owned(),
+                            key_id: key_id.to_owned(),
+                            key,
+                        })
+                    }
+                    _ => Err(name),
+                }
+            })
+            .collect()
+    }
+
+    /// The `algorithm:key_id` name this key is published under.
+    pub fn name(&self) -> String {
+        format!("{}:{}", self.algorithm, self.key_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_one_time_key_from_key_map() {
+        let mut keys = KeyMap::new();
+        keys.insert("signed_curve25519:AAAAHQ".to_owned(), json!({"key": "abc"}));
+        let parsed = OneTimeKey::from_key_map(keys).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].algorithm, "signed_curve25519");
+        assert_eq!(parsed[0].key_id, "AAAAHQ");
+        assert_eq!(parsed[0].name(), "signed_curve25519:AAAAHQ");
+    }
+
+    #[test]
+    fn test_one_time_key_from_key_map_rejects_missing_key_id() {
+        let mut keys = KeyMap::new();
+        keys.insert("signed_curve25519".to_owned(), json!("abc"));
+        assert_eq!(
+            OneTimeKey::from_key_map(keys),
+            Err("signed_curve25519".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_validate_cross_signing_key() {
+        let key = json!({
+            "user_id": "@alice:example.com",
+            "usage": ["master"],
+            "keys": {"ed25519:base64+master+public+key": "base64+master+public+key"}
+        });
+        assert_eq!(
+            validate_cross_signing_key(&key, "@alice:example.com", MASTER),
+            Ok("ed25519:base64+master+public+key".to_owned())
+        );
+        assert_eq!(public_key(&key), Some("base64+master+public+key"));
+        assert!(validate_cross_signing_key(&key, "@bob:example.com", MASTER).is_err());
+        assert!(validate_cross_signing_key(&key, "@alice:example.com", SELF_SIGNING).is_err());
+    }
+
+    #[test]
+    fn test_add_signatures_merges_into_existing() {
+        let mut key = json!({
+            "keys": {},
+            "signatures": {"@alice:example.com": {"ed25519:DEV": "sig1"}}
+        });
+        add_signatures(
+            &mut key,
+            &[KeySignature {
+                signer: "@alice:example.com".to_owned(),
+                signing_key_id: "ed25519:self".to_owned(),
+                target_user_id: "@alice:example.com".to_owned(),
+                target_id: "DEV".to_owned(),
+                signature: "sig2".to_owned(),
+            }],
+        );
+        assert_eq!(
+            key["signatures"]["@alice:example.com"]["ed25519:DEV"],
+            "sig1"
+        );
+        assert_eq!(
+            key["signatures"]["@alice:example.com"]["ed25519:self"],
+            "sig2"
+        );
+        assert!(is_signed_by(&key, "@alice:example.com", "ed25519:self"));
+        assert_eq!(signed_part(&key), json!({"keys": {}}));
+    }
+
+    #[test]
+    fn test_upload_request_accepts_unstable_fallback_keys() {
+        let req: UploadRequest = serde_json::from_value(json!({
+            "org.matrix.msc2732.fallback_keys": {"signed_curve25519:AAAAGj": {"key": "abc"}}
+        }))
+        .unwrap();
+        assert!(req.device_keys.is_none());
+        assert_eq!(req.fallback_keys.len(), 1);
+    }
+}
@@ -0,0 +1,100 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::media::thumbnail::Method;
+
+#[derive(Deserialize)]
+pub struct UploadParams {
+    /// The name of the file being uploaded.
+    pub filename: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+#[schemars(rename = "MediaUploadResponse")]
+pub struct UploadResponse {
+    /// The MXC URI to the uploaded content.
+    pub content_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct DownloadPath {
+    /// The server name from the `mxc://` URI (the authoritory component).
+    pub server_name: String,
+    /// The media ID from the `mxc://` URI (the path component).
+    pub media_id: String,
+    /// A filename to give in the `Content-Disposition` header.
+    pub file_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ThumbnailPath {
+    pub server_name: String,
+    pub media_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ThumbnailParams {
+    /// The desired width of the thumbnail. The actual thumbnail may be
+    /// larger than the size specified.
+    pub width: u32,
+    /// The desired height of the thumbnail. The actual thumbnail may be
+    /// larger than the size specified.
+    pub height: u32,
+    /// The desired resizing method, `crop` or `scale`.
+    pub method: Option<Method>,
+}
+
+#[derive(Deserialize)]
+pub struct PreviewParams {
+    /// The URL to get a preview of.
+    pub url: String,
+    /// The preferred point in time to return a preview for. The server may
+    /// return a newer version if it does not have the requested version
+    /// available.
+    pub ts: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct ConfigResponse {
+    /// The maximum size an upload can be in bytes.
+    #[serde(rename = "m.upload.size")]
+    pub upload_size: u64,
+}
+
+/// Metadata about a piece of media uploaded to this server.
+#[derive(Clone, Debug)]
+pub struct LocalMedia {
+    pub media_id: String,
+    pub content_type: String,
+    /// The size of the content in bytes
+    pub media_length: i64,
+    /// The file name given by the uploader
+    pub upload_name: Option<String>,
+    /// The fully qualified ID of the uploader
+    pub user_id: String,
+    /// When the media was uploaded, as a unix timestamp (ms resolution).
+    pub created_ts: i64,
+}
+
+/// Metadata about a piece of media from another server, cached locally.
+#[derive(Clone, Debug)]
+pub struct RemoteMedia {
+    /// The server the media was uploaded to
+    pub origin: String,
+    pub media_id: String,
+    /// The ID the content is kept under in the media store
+    pub file_id: String,
+    pub content_type: String,
+    /// The size of the content in bytes
+    pub media_length: i64,
+    pub upload_name: Option<String>,
+    /// When the media was fetched, as a unix timestamp (ms resolution).
+    pub created_ts: i64,
+    /// When the media was last served, as a unix timestamp (ms resolution).
+    pub last_access_ts: i64,
+}
+
+/// Formats an `mxc://` URI for a piece of media.
+pub fn mxc_uri(server_name: &str, media_id: &str) -> String {
+    format!("mxc://{}/{}", server_name, media_id)
+}
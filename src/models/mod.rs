@@ -1,2 +1,23 @@
+pub mod access;
+pub mod account;
+pub mod account_data;
+pub mod admin;
+pub mod appservice;
 pub mod auth;
+pub mod dehydrated_device;
+pub mod directory;
+// TODO: Only used by tests until events are stored
+#[allow(dead_code)]
+pub mod event;
+pub mod federation;
+pub mod keys;
+pub mod media;
+pub mod presence;
+pub mod profile;
+pub mod push;
 pub mod registration;
+pub mod report;
+pub mod room;
+pub mod room_keys;
+pub mod sync;
+pub mod to_device;
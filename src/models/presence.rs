@@ -0,0 +1,34 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The presence states a user can be in.
+pub const PRESENCE_STATES: [&str; 3] = ["online", "unavailable", "offline"];
+
+#[derive(Deserialize)]
+pub struct PresencePath {
+    pub user_id: String,
+}
+
+/// A user's last known presence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Presence {
+    /// The fully qualified ID of the user
+    pub user_id: String,
+    /// One of `PRESENCE_STATES`
+    pub presence: String,
+    pub status_msg: Option<String>,
+    /// When the user was last active, as a unix timestamp (ms resolution).
+    pub last_active_ts: i64,
+    /// Whether the user is using a client right now
+    pub currently_active: bool,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct PresenceResponse {
+    pub presence: String,
+    /// Milliseconds since the user was last active
+    pub last_active_ago: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_msg: Option<String>,
+    pub currently_active: bool,
+}
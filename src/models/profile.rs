@@ -0,0 +1,84 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The profile fields that can be asked for on their own.
+pub const DISPLAYNAME: &str = "displayname";
+pub const AVATAR_URL: &str = "avatar_url";
+
+/// A user's display name and avatar. Fields a user hasn't set are left
+/// out.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, JsonSchema)]
+pub struct Profile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub displayname: Option<String>,
+    /// The `mxc://` URI of the user's avatar
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+}
+
+impl Profile {
+    /// Only the named field of the profile, or the whole profile without a
+    /// field.
+    pub fn only(self, field: Option<&str>) -> Self {
+        match field {
+            Some(DISPLAYNAME) => Profile {
+                avatar_url: None,
+                ..self
+            },
+            Some(AVATAR_URL) => Profile {
+                displayname: None,
+                ..self
+            },
+            _ => self,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ProfilePath {
+    pub user_id: String,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct DisplaynameRequest {
+    /// The new display name, or `None` to remove it
+    pub displayname: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct AvatarUrlRequest {
+    /// The new avatar, or `None` to remove it
+    pub avatar_url: Option<String>,
+}
+
+/// A profile query from another server.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProfileQuery {
+    pub user_id: String,
+    /// `displayname` or `avatar_url`, or `None` for the whole profile
+    pub field: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_profile() {
+        let profile = Profile {
+            displayname: Some("Alice".to_owned()),
+            avatar_url: Some("mxc://example.com/abc".to_owned()),
+        };
+        assert_eq!(
+            json!(profile.clone().only(Some(DISPLAYNAME))),
+            json!({ "displayname": "Alice" })
+        );
+        assert_eq!(
+            json!(profile.clone().only(Some(AVATAR_URL))),
+            json!({ "avatar_url": "mxc://example.com/abc" })
+        );
+        assert_eq!(profile.clone().only(None), profile);
+        assert_eq!(json!(Profile::default()), json!({}));
+    }
+}
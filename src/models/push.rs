@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The kinds of push rule, highest priority first.
+pub const RULE_KINDS: [&str; 5] = ["override", "content", "room", "sender", "underride"];
+
+#[derive(Deserialize)]
+pub struct PushRulePath {
+    pub scope: String,
+    pub kind: String,
+    pub rule_id: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PutPushRuleParams {
+    /// The rule ID of a user-defined rule of the same kind that the new
+    /// rule should come before.
+    pub before: Option<String>,
+    /// The rule ID of a user-defined rule of the same kind that the new
+    /// rule should come after.
+    pub after: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct PutPushRuleRequest {
+    pub actions: Vec<Value>,
+    /// Only for `override` and `underride` rules.
+    #[serde(default)]
+    pub conditions: Option<Vec<Condition>>,
+    /// Only for `content` rules.
+    #[serde(default)]
+    pub pattern: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct PushRuleEnabled {
+    pub enabled: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct PushRuleActions {
+    pub actions: Vec<Value>,
+}
+
+/// A rule deciding whether, and how, an event notifies a user.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, JsonSchema)]
+pub struct PushRule {
+    pub rule_id: String,
+    /// Whether this is one of the server's default rules
+    #[serde(default)]
+    pub default: bool,
+    pub enabled: bool,
+    /// What to do with matching events: `notify`, `dont_notify`, `coalesce`
+    /// or `{"set_tweak": ...}`
+    pub actions: Vec<Value>,
+    /// All of these must match. Only for `override` and `underride` rules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conditions: Option<Vec<Condition>>,
+    /// The glob the event's body must match. Only for `content` rules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+/// A condition of an `override` or `underride` push rule.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Condition {
+    /// A field of the event, by dotted path, matches a glob.
+    EventMatch { key: String, pattern: String },
+    /// The event's body contains the user's display name.
+    ContainsDisplayName,
+    /// The room's member count compares as given, e.g. `2` or `>=10`.
+    RoomMemberCount { is: String },
+    /// The sender has the power level needed to notify the room with
+    /// `key`, e.g. `room`.
+    SenderNotificationPermission { key: String },
+    /// A condition this server doesn't know. It never matches.
+    #[serde(other)]
+    Unknown,
+}
+
+/// A user's push rules, by kind, each kind in priority order.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, JsonSchema)]
+pub struct Ruleset {
+    #[serde(rename = "override", default)]
+    pub override_: Vec<PushRule>,
+    #[serde(default)]
+    pub content: Vec<PushRule>,
+    #[serde(default)]
+    pub room: Vec<PushRule>,
+    #[serde(default)]
+    pub sender: Vec<PushRule>,
+    #[serde(default)]
+    pub underride: Vec<PushRule>,
+}
+
+impl Ruleset {
+    /// The rules of a kind, or `None` for an unknown kind.
+    pub fn kind(&self, kind: &str) -> Option<&Vec<PushRule>> {
+        match kind {
+            "override" => Some(&self.override_),
+            "content" => Some(&self.content),
+            "room" => Some(&self.room),
+            "sender" => Some(&self.sender),
+            "underride" => Some(&self.underride),
+            _ => None,
+        }
+    }
+
+    pub fn kind_mut(&mut self, kind: &str) -> Option<&mut Vec<PushRule>> {
+        match kind {
+            "override" => Some(&mut self.override_),
+            "content" => Some(&mut self.content),
+            "room" => Some(&mut self.room),
+            "sender" => Some(&mut self.sender),
+            "underride" => Some(&mut self.underride),
+            _ => None,
+        }
+    }
+}
+
+/// What a user changed about one of the server's default rules.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DefaultRuleChange {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actions: Option<Vec<Value>>,
+}
+
+/// The push rules a user has set, as stored. The server's default rules
+/// are not stored, so that changes to them reach every user; only what the
+/// user changed about them is.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct UserPushRules {
+    /// The user's own rules
+    pub rules: Ruleset,
+    /// Changes to default rules, by rule ID
+    pub defaults: BTreeMap<String, DefaultRuleChange>,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct PushRulesResponse {
+    pub global: Ruleset,
+}
+
+/// A pusher: where to deliver a user's notifications.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, JsonSchema)]
+pub struct Pusher {
+    /// Identifies the device to the push gateway, or the address of an
+    /// `email` pusher
+    pub pushkey: String,
+    /// `http` or `email`
+    pub kind: String,
+    /// Identifies the app the pusher is for, reverse-DNS style
+    pub app_id: String,
+    pub app_display_name: String,
+    pub device_display_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_tag: Option<String>,
+    /// The preferred language for notifications, e.g. `en`
+    pub lang: String,
+    pub data: PusherData,
+}
+
+/// The configuration of a pusher.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, JsonSchema)]
+pub struct PusherData {
+    /// Where `http` pushers send notifications, which must be a push
+    /// gateway's `/_matrix/push/v1/notify` endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// `event_id_only` to send only the IDs of events, not their content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Anything else the app set, which is passed on to the gateway
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+/// How far a pusher has got delivering its user's notifications.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PusherState {
+    /// The last queued notification delivered
+    pub last_stream_id: i64,
+    /// When delivery last failed, as a unix timestamp (ms resolution).
+    pub retry_last_ts: i64,
+    /// How long to wait after the last failure before retrying, in ms, or 0
+    /// if the last delivery succeeded.
+    pub retry_interval: i64,
+    /// When delivery last succeeded, as a unix timestamp (ms resolution).
+    pub last_success_ts: i64,
+}
+
+/// A notification queued for a user's pushers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedNotification {
+    /// The notification's position in the user's queue
+    pub stream_id: i64,
+    /// When the notification was queued, as a unix timestamp (ms
+    /// resolution).
+    pub queued_ts: i64,
+    /// The notification for the push gateway, without its devices
+    pub notification: Value,
+    /// The tweaks from the push rule actions, e.g. sound and highlight
+    pub tweaks: Value,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct SetPusherRequest {
+    pub pushkey: String,
+    /// `null` deletes the pusher
+    pub kind: Option<String>,
+    pub app_id: String,
+    #[serde(default)]
+    pub app_display_name: String,
+    #[serde(default)]
+    pub device_display_name: String,
+    #[serde(default)]
+    pub profile_tag: Option<String>,
+    #[serde(default)]
+    pub lang: String,
+    #[serde(default)]
+    pub data: PusherData,
+    /// Whether to keep other users' pushers with the same app ID and
+    /// pushkey, rather than replace them
+    #[serde(default)]
+    pub append: bool,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct PushersResponse {
+    pub pushers: Vec<Pusher>,
+}
+
+/// The thread ID of a room's main timeline.
+pub const MAIN_THREAD: &str = "main";
+
+/// A user's unread notification counts in a thread of a room.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PushCounts {
+    pub room_id: String,
+    /// The thread, or `MAIN_THREAD` for the main timeline
+    pub thread_id: String,
+    /// Events since the user's last read receipt that notified them
+    pub notification_count: i64,
+    /// Of those, the events that were highlighted
+    pub highlight_count: i64,
+}
@@ -1,7 +1,8 @@
+use schemars::JsonSchema;
 use serde::Deserialize;
 
 /// The kind of account to register.
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum Kind {
     /// An Anonymous user with no password.
@@ -30,9 +31,16 @@ pub struct AvailableParams {
     pub username: String,
 }
 
+/// The registration type application services register their users with.
+pub const APPSERVICE_LOGIN_TYPE: &str = "m.login.application_service";
+
 // TODO: Support `auth` and `authentication_data` fields
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct Request {
+    /// `m.login.application_service` when an application service registers
+    /// a user in its namespace
+    #[serde(rename = "type")]
+    pub login_type: Option<String>,
     /// ID of the client device. If this does not correspond to a known
     /// client device, a new device will be created. The server will
     /// auto-generate a device_id if this is not specified.
@@ -0,0 +1,39 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The lowest score a report may give: the most offensive.
+pub const MIN_SCORE: i64 = -100;
+
+#[derive(Deserialize)]
+pub struct ReportPath {
+    pub room_id: String,
+    pub event_id: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ReportRequest {
+    pub reason: Option<String>,
+    /// How offensive the event is, from -100, the most, to 0, inoffensive.
+    pub score: Option<i64>,
+}
+
+/// A report another server forwarded about one of our users' events. Who
+/// reported it is not told.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ForwardedReport {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// A report of an event, for admins to look into.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventReport {
+    pub room_id: String,
+    pub event_id: String,
+    /// The local user who reported the event, if they were local.
+    pub reporter: Option<String>,
+    /// The server that forwarded the report, if it was forwarded.
+    pub origin: Option<String>,
+    pub reason: Option<String>,
+    pub score: Option<i64>,
+}
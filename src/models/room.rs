@@ -0,0 +1,192 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The membership of a user who has joined a room.
+pub const JOIN: &str = "join";
+/// The membership of a user who has been invited to a room.
+pub const INVITE: &str = "invite";
+
+/// Whether a membership counts the user as one of the room's members, as
+/// its summary does: joined, or invited.
+pub fn is_member(membership: Option<&str>) -> bool {
+    membership == Some(JOIN) || membership == Some(INVITE)
+}
+
+/// A summary of a room's members, as given to clients to name rooms
+/// without a name of their own.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub struct RoomSummary {
+    /// Joined or invited members, earliest first. As stored, one more than
+    /// the heroes a client is given, so there are enough once the client's
+    /// own user is left out.
+    #[serde(rename = "m.heroes")]
+    pub heroes: Vec<String>,
+    /// The number of joined members.
+    #[serde(rename = "m.joined_member_count")]
+    pub joined_member_count: i64,
+    /// The number of invited members.
+    #[serde(rename = "m.invited_member_count")]
+    pub invited_member_count: i64,
+}
+
+impl RoomSummary {
+    /// The summary as given to `user_id`, whose own user is never one of
+    /// their heroes.
+    pub fn for_user(&self, user_id: &str, max_heroes: usize) -> RoomSummary {
+        RoomSummary {
+            heroes: self
+                .heroes
+                .iter()
+                .filter(|hero| *hero != user_id)
+                .take(max_heroes)
+                .cloned()
+                .collect(),
+            ..*self
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SummaryPath {
+    pub room_id_or_alias: String,
+}
+
+/// A room's summary, for clients deciding whether to join it.
+///
+/// TODO: Add the name, topic, avatar, canonical alias, join rule and room
+/// type from the room's current state once events are stored.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SummaryResponse {
+    pub room_id: String,
+    pub num_joined_members: i64,
+    /// The requesting user's membership of the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub membership: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RoomPath {
+    pub room_id: String,
+}
+
+/// Who to invite to a room: a Matrix user, or the owner of a third party
+/// identifier, through an identity server.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum InviteRequest {
+    ThirdParty {
+        /// The hostname of the identity server to store the invite on.
+        id_server: String,
+        /// An access token registered with the identity server.
+        id_access_token: String,
+        /// The kind of identifier, e.g. `email`.
+        medium: String,
+        address: String,
+    },
+    User {
+        user_id: String,
+    },
+}
+
+/// A key an identity server signs the invites it stored with, once their
+/// invitee binds the invited identifier to a Matrix ID.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PublicKey {
+    /// The unpadded base64 encoded Ed25519 public key.
+    pub public_key: String,
+    /// Where the identity server says whether the key is still valid.
+    pub key_validity_url: String,
+}
+
+/// An invite to a room of whoever binds a third party identifier, stored
+/// on an identity server, waiting for them to bind it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThirdPartyInvite {
+    pub room_id: String,
+    /// The identity server's token for the invite.
+    pub token: String,
+    /// The user who sent the invite.
+    pub sender: String,
+    /// A display name for the invitee, from the identity server, hiding
+    /// most of the identifier.
+    pub display_name: String,
+    /// The keys the invite may be signed with once it is bound.
+    pub public_keys: Vec<PublicKey>,
+}
+
+/// An identity server's notice that a third party identifier was bound to
+/// a Matrix ID, with the invites it had stored for the identifier.
+#[derive(Deserialize, JsonSchema)]
+pub struct OnBindRequest {
+    pub mxid: String,
+    #[serde(default)]
+    pub invites: Vec<BoundInvite>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct BoundInvite {
+    pub room_id: String,
+    pub mxid: String,
+    /// The invite's token and Matrix ID, signed by the identity server.
+    pub signed: Value,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_for_user() {
+        let summary = RoomSummary {
+            heroes: vec![
+                "@alice:example.com".to_owned(),
+                "@bob:example.com".to_owned(),
+                "@carol:example.com".to_owned(),
+            ],
+            joined_member_count: 2,
+            invited_member_count: 1,
+        };
+        let summary = summary.for_user("@bob:example.com", 5);
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap(),
+            json!({
+                "m.heroes": ["@alice:example.com", "@carol:example.com"],
+                "m.joined_member_count": 2,
+                "m.invited_member_count": 1,
+            })
+        );
+        assert_eq!(
+            summary.for_user("@dave:example.com", 1).heroes,
+            vec!["@alice:example.com"]
+        );
+    }
+
+    #[test]
+    fn test_invite_request() {
+        let req: InviteRequest = serde_json::from_value(json!({
+            "id_server": "id.example.com",
+            "id_access_token": "abc",
+            "medium": "email",
+            "address": "alice@example.com",
+        }))
+        .unwrap();
+        assert!(matches!(req, InviteRequest::ThirdParty { medium, .. } if medium == "email"));
+        let req: InviteRequest = serde_json::from_value(json!({
+            "user_id": "@bob:example.com",
+            "reason": "Come along",
+        }))
+        .unwrap();
+        assert!(matches!(req, InviteRequest::User { user_id } if user_id == "@bob:example.com"));
+        assert!(serde_json::from_value::<InviteRequest>(json!({ "medium": "email" })).is_err());
+    }
+
+    #[test]
+    fn test_is_member() {
+        assert!(is_member(Some(JOIN)));
+        assert!(is_member(Some(INVITE)));
+        assert!(!is_member(Some("leave")));
+        assert!(!is_member(None));
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct CreateVersionRequest {
+    /// The algorithm used for storing backups.
+    pub algorithm: String,
+    /// Algorithm-dependent data, e.g. the public key backups are encrypted to.
+    pub auth_data: Value,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct UpdateVersionRequest {
+    /// The algorithm used for storing backups. Must match the existing backup.
+    pub algorithm: String,
+    /// Algorithm-dependent data.
+    pub auth_data: Value,
+    /// The backup version. If present, must match the version in the path.
+    pub version: Option<String>,
+}
+
+/// A backup version, as stored and as returned to clients.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct VersionInfo {
+    pub algorithm: String,
+    pub auth_data: Value,
+    /// The number of keys stored in the backup.
+    pub count: i64,
+    /// An opaque string representing stored keys in the backup. Clients can
+    /// compare it with the etag they received last time to see whether the
+    /// backup was changed by another client.
+    pub etag: String,
+    pub version: String,
+}
+
+#[derive(Deserialize)]
+pub struct VersionPath {
+    pub version: String,
+}
+
+#[derive(Deserialize)]
+pub struct VersionParams {
+    /// The backup the keys belong to.
+    pub version: String,
+}
+
+#[derive(Deserialize)]
+pub struct RoomPath {
+    pub room_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct SessionPath {
+    pub room_id: String,
+    pub session_id: String,
+}
+
+/// A backed up megolm session.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+pub struct KeyBackupData {
+    /// The index of the first message in the session that the key can
+    /// decrypt.
+    pub first_message_index: i64,
+    /// The number of times this key has been forwarded via key-sharing
+    /// between devices.
+    pub forwarded_count: i64,
+    /// Whether the device backing up the key verified the device that the
+    /// key is from.
+    pub is_verified: bool,
+    /// Algorithm-dependent data.
+    pub session_data: Value,
+}
+
+impl KeyBackupData {
+    /// Whether this key should replace `existing` in the backup.
+    ///
+    /// A verified key always beats an unverified one. Otherwise the key that
+    /// can decrypt more of the session (the lower `first_message_index`)
+    /// wins, with the less forwarded key breaking ties.
+    pub fn is_better_than(&self, existing: &KeyBackupData) -> bool {
+        if self.is_verified != existing.is_verified {
+            return self.is_verified;
+        }
+        if self.first_message_index != existing.first_message_index {
+            return self.first_message_index < existing.first_message_index;
+        }
+        self.forwarded_count < existing.forwarded_count
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct RoomKeyBackup {
+    /// The backed up sessions of the room, keyed by session ID.
+    pub sessions: BTreeMap<String, KeyBackupData>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct KeysBackup {
+    /// The backed up keys, keyed by room ID.
+    pub rooms: BTreeMap<String, RoomKeyBackup>,
+}
+
+impl KeysBackup {
+    /// Groups stored keys by room.
+    pub fn from_keys(keys: Vec<RoomKey>) -> Self {
+        let mut backup = KeysBackup::default();
+        for key in keys {
+            backup
+                .rooms
+                .entry(key.room_id)
+                .or_default()
+                .sessions
+                .insert(key.session_id, key.data);
+        }
+        backup
+    }
+
+    /// Flattens the backup into individual keys.
+    pub fn into_keys(self) -> Vec<RoomKey> {
+        self.rooms
+            .into_iter()
+            .flat_map(|(room_id, room)| {
+                room.sessions
+                    .into_iter()
+                    .map(move |(session_id, data)| RoomKey {
+                        room_id: room_id.clone(),
+                        session_id,
+                        data,
+                    })
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct UpdateResponse {
+    /// The new etag value representing stored keys in the backup.
+    pub etag: String,
+    /// The number of keys stored in the backup.
+    pub count: i64,
+}
+
+/// A backup version as kept in storage.
+#[derive(Clone, Debug)]
+pub struct BackupVersion {
+    pub version: i64,
+    pub algorithm: String,
+    pub auth_data: Value,
+    /// Bumped every time the keys in the backup change
+    pub etag: i64,
+}
+
+/// A single backed up session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoomKey {
+    pub room_id: String,
+    pub session_id: String,
+    pub data: KeyBackupData,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key(first_message_index: i64, forwarded_count: i64, is_verified: bool) -> KeyBackupData {
+        KeyBackupData {
+            first_message_index,
+            forwarded_count,
+            is_verified,
+            session_data: json!({}),
+        }
+    }
+
+    #[test]
+    fn test_verified_key_wins() {
+        assert!(key(10, 5, true).is_better_than(&key(0, 0, false)));
+        assert!(!key(0, 0, false).is_better_than(&key(10, 5, true)));
+    }
+
+    #[test]
+    fn test_lower_first_message_index_wins() {
+        assert!(key(1, 5, false).is_better_than(&key(2, 0, false)));
+        assert!(!key(2, 0, false).is_better_than(&key(1, 5, false)));
+    }
+
+    #[test]
+    fn test_lower_forwarded_count_breaks_ties() {
+        assert!(key(1, 0, true).is_better_than(&key(1, 1, true)));
+        assert!(!key(1, 1, true).is_better_than(&key(1, 1, true)));
+    }
+
+    #[test]
+    fn test_keys_backup_round_trip() {
+        let keys = vec![
+            RoomKey {
+                room_id: "!a:example.com".to_owned(),
+                session_id: "s1".to_owned(),
+                data: key(0, 0, true),
+            },
+            RoomKey {
+                room_id: "!a:example.com".to_owned(),
+                session_id: "s2".to_owned(),
+                data: key(3, 1, false),
+            },
+        ];
+        let backup = KeysBackup::from_keys(keys.clone());
+        assert_eq!(backup.rooms["!a:example.com"].sessions.len(), 2);
+        assert_eq!(backup.into_keys(), keys);
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::room::RoomSummary;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SyncParams {
+    /// A point in time to continue a sync from, as returned in `next_batch`.
+    pub since: Option<String>,
+    /// The maximum time to wait, in milliseconds, before returning.
+    pub timeout: Option<u64>,
+    /// Controls whether to include the full state for all rooms the user is
+    /// a member of.
+    pub full_state: Option<bool>,
+    /// Controls whether the client is automatically marked as online.
+    pub set_presence: Option<String>,
+    /// The ID of a filter created using the filter API or a filter JSON
+    /// object encoded as a string.
+    pub filter: Option<String>,
+}
+
+/// A position in each of the streams a sync response is built from.
+///
+/// Every kind of change a sync delivers is numbered by its own ever
+/// increasing counter, its stream, so what a client hasn't seen yet is a
+/// range of positions rather than a scan of everything it could see.
+///
+/// Serialized as the stream positions joined by underscores, so new streams
+/// can be appended without invalidating tokens handed out earlier.
+///
+/// TODO: Append the event, receipt and presence streams once rooms, receipts
+/// and local presence are stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SyncToken {
+    /// The last to-device message delivered to the device
+    pub to_device: i64,
+    /// The last device list change the client was told about
+    pub device_lists: i64,
+    /// The last invite the client was told about
+    pub invites: i64,
+    /// The last change to unread notification counts the client was told
+    /// about
+    pub push_counts: i64,
+    /// The last change to the user's account data the client was told about
+    pub account_data: i64,
+}
+
+/// The latest committed positions of the streams. Read once when a sync
+/// starts, so every section of the response covers the same range, up to
+/// and including these.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StreamPositions {
+    pub to_device: i64,
+    pub device_lists: i64,
+    pub invites: i64,
+    pub push_counts: i64,
+    pub account_data: i64,
+}
+
+impl fmt::Display for SyncToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}_{}_{}_{}_{}",
+            self.to_device, self.device_lists, self.invites, self.push_counts, self.account_data
+        )
+    }
+}
+
+impl FromStr for SyncToken {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('_');
+        let to_device = parts.next().unwrap_or_default().parse()?;
+        // Streams added after a token was handed out start from the beginning
+        let mut next = || parts.next().map(str::parse).unwrap_or(Ok(0));
+        let device_lists = next()?;
+        let invites = next()?;
+        let push_counts = next()?;
+        let account_data = next()?;
+        Ok(SyncToken {
+            to_device,
+            device_lists,
+            invites,
+            push_counts,
+            account_data,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, JsonSchema)]
+pub struct ToDevice {
+    /// List of send-to-device messages.
+    pub events: Vec<Value>,
+}
+
+/// Account data that changed since the previous sync.
+#[derive(Clone, Debug, Default, Serialize, JsonSchema)]
+pub struct AccountData {
+    /// The changed account data, as events with a `type` and `content`.
+    pub events: Vec<Value>,
+}
+
+impl AccountData {
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+/// Users whose devices changed since the previous sync.
+#[derive(Clone, Debug, Default, Serialize, JsonSchema)]
+pub struct DeviceLists {
+    /// Users who have changed their device identity or cross-signing keys,
+    /// or who now share an encrypted room with the client.
+    pub changed: Vec<String>,
+    /// Users who no longer share any encrypted room with the client.
+    pub left: Vec<String>,
+}
+
+impl DeviceLists {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.left.is_empty()
+    }
+}
+
+/// Updates to rooms.
+#[derive(Clone, Debug, Default, Serialize, JsonSchema)]
+pub struct Rooms {
+    /// The rooms that the user has joined, by room ID.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub join: BTreeMap<String, JoinedRoom>,
+    /// The rooms that the user has been invited to, by room ID.
+    pub invite: BTreeMap<String, InvitedRoom>,
+}
+
+impl Rooms {
+    pub fn is_empty(&self) -> bool {
+        self.join.is_empty() && self.invite.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, JsonSchema)]
+pub struct JoinedRoom {
+    /// The room's account data that changed.
+    #[serde(skip_serializing_if = "AccountData::is_empty")]
+    pub account_data: AccountData,
+    /// A summary of the room's members, for naming rooms without a name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<RoomSummary>,
+    /// Counts of unread notifications for this room, if they changed. Unless
+    /// the client asked for thread counts, these include the room's threads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unread_notifications: Option<UnreadNotificationCounts>,
+    /// Counts of unread notifications for each thread in this room, by
+    /// thread root ID, if the client asked for them.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub unread_thread_notifications: BTreeMap<String, UnreadNotificationCounts>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, JsonSchema)]
+pub struct UnreadNotificationCounts {
+    /// The number of unread notifications with the highlight flag set.
+    pub highlight_count: i64,
+    /// The total number of unread notifications.
+    pub notification_count: i64,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct InvitedRoom {
+    /// The state of the room the user is invited to.
+    pub invite_state: InviteState,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct InviteState {
+    /// Stripped state events of the room, including the invite itself.
+    pub events: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+pub struct KeyChangesParams {
+    /// The desired start point of the list, as a `next_batch` token.
+    pub from: String,
+    /// The desired end point of the list, as a `next_batch` token.
+    pub to: String,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct SyncResponse {
+    /// The batch token to supply in the `since` param of the next `/sync`
+    /// request.
+    pub next_batch: String,
+    /// Updates to rooms.
+    #[serde(skip_serializing_if = "Rooms::is_empty")]
+    pub rooms: Rooms,
+    /// The global account data that changed.
+    #[serde(skip_serializing_if = "AccountData::is_empty")]
+    pub account_data: AccountData,
+    /// Information on the send-to-device messages for the client device.
+    pub to_device: ToDevice,
+    /// Information on end-to-end device updates.
+    #[serde(skip_serializing_if = "DeviceLists::is_empty")]
+    pub device_lists: DeviceLists,
+    /// For each key algorithm, the number of unclaimed one-time keys
+    /// currently held on the server for this device.
+    pub device_one_time_keys_count: BTreeMap<String, i64>,
+    /// The algorithms for which the device has an unused fallback key.
+    pub device_unused_fallback_key_types: Vec<String>,
+}
+
+impl SyncResponse {
+    /// Whether there is nothing new in the response. Key counts are always
+    /// given, so they don't count.
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+            && self.account_data.is_empty()
+            && self.to_device.events.is_empty()
+            && self.device_lists.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_token_round_trip() {
+        let token = SyncToken {
+            to_device: 42,
+            device_lists: 3,
+            invites: 5,
+            push_counts: 8,
+            account_data: 13,
+        };
+        assert_eq!(token.to_string().parse::<SyncToken>(), Ok(token));
+    }
+
+    #[test]
+    fn test_sync_token_ignores_unknown_streams() {
+        assert_eq!(
+            "7_12_3_9_4_1".parse::<SyncToken>(),
+            Ok(SyncToken {
+                to_device: 7,
+                device_lists: 12,
+                invites: 3,
+                push_counts: 9,
+                account_data: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn test_sync_token_defaults_missing_streams() {
+        assert_eq!(
+            "7".parse::<SyncToken>(),
+            Ok(SyncToken {
+                to_device: 7,
+                device_lists: 0,
+                invites: 0,
+                push_counts: 0,
+                account_data: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_sync_token_rejects_garbage() {
+        assert!("abc".parse::<SyncToken>().is_err());
+    }
+}
@@ -0,0 +1,30 @@
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Targets every device of a user in a send-to-device request.
+pub const ALL_DEVICES: &str = "*";
+
+#[derive(Deserialize)]
+pub struct SendPath {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub txn_id: String,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct SendRequest {
+    /// The messages to send, as user ID to device ID to message body. The
+    /// device ID may also be `*`, meaning all known devices for the user.
+    pub messages: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+/// A to-device message addressed to a single local device.
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub localpart: String,
+    pub device_id: String,
+    pub content: Value,
+}
@@ -0,0 +1,303 @@
+//! Runs the room moderation jobs admins start through the admin API.
+//!
+//! Jobs are queued in the `Store` before they run, and record what they
+//! have done as they go so admins can follow them. They run one at a time,
+//! in the order they were queued. Every step of a job can safely be done
+//! again, so a job cut off by a restart is run again. Long jobs record where
+//! they got to as they go, and carry on from there.
+use std::error::Error;
+use std::time::Duration;
+
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    db::Store,
+    models::{
+        admin::{AdminJob, JobStatus, RoomJob},
+        auth::UserId,
+    },
+    room_summary, CONFIG,
+};
+
+/// How many members an export reads at once.
+const EXPORT_BATCH_SIZE: i64 = 500;
+
+/// Wakes the job runner when a job is queued. Can be cloned and used from
+/// any thread.
+#[derive(Clone, Debug)]
+pub struct Notifier(UnboundedSender<i64>);
+
+impl Notifier {
+    pub fn notify(&self, job_id: i64) {
+        // The runner only stops when the server does
+        let _ = self.0.unbounded_send(job_id);
+    }
+}
+
+/// Starts the job runner on the current thread, first running the jobs left
+/// unfinished when the server last stopped.
+pub fn start<T: Store + 'static>(storage: T) -> Notifier {
+    let (sender, receiver) = mpsc::unbounded();
+    actix_rt::spawn(run(storage, receiver));
+    Notifier(sender)
+}
+
+async fn run<T: Store>(storage: T, mut wakeups: UnboundedReceiver<i64>) {
+    match storage.get_unfinished_admin_jobs().await {
+        Ok(jobs) => {
+            for job_id in jobs {
+                run_job(&storage, job_id).await;
+            }
+        }
+        Err(e) => tracing::error!(error = %e, "Unable to load unfinished admin jobs"),
+    }
+    while let Some(job_id) = wakeups.next().await {
+        run_job(&storage, job_id).await;
+    }
+}
+
+/// Runs a job, recording whether it completed or failed.
+#[tracing::instrument(skip(storage))]
+async fn run_job<T: Store>(storage: &T, job_id: i64) {
+    let job = match storage.get_admin_job(job_id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::error!(error = %e, "Unable to load admin job");
+            return;
+        }
+    };
+    // Jobs resumed at startup may be queued again by the admin API
+    if job.status == JobStatus::Complete || job.status == JobStatus::Failed {
+        return;
+    }
+
+    let mut progress = Progress {
+        job_id,
+        done: job.progress.as_object().cloned().unwrap_or_default(),
+    };
+    let result = match progress.save(storage).await {
+        Ok(()) => match &job.job {
+            RoomJob::RemoveMember { user_id } => {
+                remove_member(storage, &job, user_id, &mut progress).await
+            }
+            RoomJob::Shutdown { .. } => shutdown(storage, &job, &mut progress).await,
+            RoomJob::PurgeHistory { before_ts } => {
+                purge_history(storage, &job, *before_ts, &mut progress).await
+            }
+            RoomJob::ExportMembers => export_members(storage, &job, &mut progress).await,
+            RoomJob::BulkInvite { user_ids } => {
+                bulk_invite(storage, &job, user_ids, &mut progress).await
+            }
+        },
+        Err(e) => Err(e),
+    };
+
+    let done = Value::Object(progress.done);
+    let update = match result {
+        Ok(()) => {
+            tracing::info!(room_id = %job.room_id, "Admin job complete");
+            storage
+                .update_admin_job(job_id, JobStatus::Complete, &done, None)
+                .await
+        }
+        Err(e) => {
+            tracing::warn!(room_id = %job.room_id, error = %e, "Admin job failed");
+            storage
+                .update_admin_job(job_id, JobStatus::Failed, &done, Some(&e.to_string()))
+                .await
+        }
+    };
+    if let Err(e) = update {
+        tracing::error!(error = %e, "Unable to record the outcome of an admin job");
+    }
+}
+
+/// What a running job has done so far.
+struct Progress {
+    job_id: i64,
+    done: Map<String, Value>,
+}
+
+impl Progress {
+    /// Records that the job did something, e.g. `invites_removed`.
+    async fn set<T: Store>(
+        &mut self,
+        storage: &T,
+        step: &str,
+        value: Value,
+    ) -> Result<(), Box<dyn Error>> {
+        self.done.insert(step.to_owned(), value);
+        self.save(storage).await
+    }
+
+    /// What the job recorded for `step` before, if it was resumed.
+    fn get(&self, step: &str) -> Option<&Value> {
+        self.done.get(step)
+    }
+
+    async fn save<T: Store>(&self, storage: &T) -> Result<(), Box<dyn Error>> {
+        storage
+            .update_admin_job(
+                self.job_id,
+                JobStatus::Running,
+                &Value::Object(self.done.clone()),
+                None,
+            )
+            .await
+    }
+}
+
+/// Removes a local user from a room.
+///
+/// TODO: Make the user leave the room once rooms exist. Until then only a
+/// pending invite is removed.
+async fn remove_member<T: Store>(
+    storage: &T,
+    job: &AdminJob,
+    user_id: &str,
+    progress: &mut Progress,
+) -> Result<(), Box<dyn Error>> {
+    let user_id = UserId::parse(user_id);
+    if !user_id.is_local() {
+        return Err(format!("{} is not a local user", user_id).into());
+    }
+    let removed = storage
+        .delete_room_invite(&user_id.local_part, &job.room_id)
+        .await?;
+    if removed {
+        room_summary::set_membership(storage, &job.room_id, &user_id.to_string(), None).await?;
+    }
+    progress
+        .set(storage, "invite_removed", json!(removed))
+        .await
+}
+
+/// Shuts a room down: blocks it, so local users can't join it again,
+/// removes every local user from it, and tombstones it.
+///
+/// TODO: Kick the local members with the job's message and send a
+/// tombstone once rooms exist. Until then pending invites are removed.
+async fn shutdown<T: Store>(
+    storage: &T,
+    job: &AdminJob,
+    progress: &mut Progress,
+) -> Result<(), Box<dyn Error>> {
+    storage.block_room(&job.room_id, &job.created_by).await?;
+    progress.set(storage, "blocked", json!(true)).await?;
+
+    let invitees = storage.get_room_invitees(&job.room_id).await?;
+    progress
+        .set(storage, "invites_total", json!(invitees.len()))
+        .await?;
+    for (removed, localpart) in invitees.iter().enumerate() {
+        if storage.delete_room_invite(localpart, &job.room_id).await? {
+            let user_id = UserId::parse(localpart);
+            room_summary::set_membership(storage, &job.room_id, &user_id.to_string(), None).await?;
+        }
+        progress
+            .set(storage, "invites_removed", json!(removed + 1))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Purges a room's history from before a time.
+///
+/// TODO: Delete the room's events sent before `before_ts`, keeping its
+/// current state, once events are stored. Until then there is nothing to
+/// purge.
+async fn purge_history<T: Store>(
+    storage: &T,
+    _job: &AdminJob,
+    _before_ts: i64,
+    progress: &mut Progress,
+) -> Result<(), Box<dyn Error>> {
+    progress.set(storage, "events_purged", json!(0)).await
+}
+
+/// Snapshots a room's members a batch at a time, for admins to download
+/// once the job is complete.
+async fn export_members<T: Store>(
+    storage: &T,
+    job: &AdminJob,
+    progress: &mut Progress,
+) -> Result<(), Box<dyn Error>> {
+    let mut from = progress
+        .get("last_user_id")
+        .and_then(Value::as_str)
+        .map(str::to_owned);
+    loop {
+        let members = storage
+            .get_room_members(&job.room_id, from.as_deref(), EXPORT_BATCH_SIZE)
+            .await?;
+        let last = match members.last() {
+            Some(last) => last.user_id.clone(),
+            None => break,
+        };
+        storage.add_exported_members(job.job_id, &members).await?;
+        progress.set(storage, "last_user_id", json!(last)).await?;
+        if (members.len() as i64) < EXPORT_BATCH_SIZE {
+            break;
+        }
+        from = Some(last);
+    }
+
+    let exported = storage.get_exported_members(job.job_id).await?.len();
+    progress
+        .set(storage, "members_exported", json!(exported))
+        .await
+}
+
+/// Invites users to a room one at a time, no faster than users may send
+/// events, so a large invite doesn't flood the room or the invitees'
+/// servers. A user who can't be invited is counted as failed, with why, and
+/// the rest are still invited.
+async fn bulk_invite<T: Store>(
+    storage: &T,
+    job: &AdminJob,
+    user_ids: &[String],
+    progress: &mut Progress,
+) -> Result<(), Box<dyn Error>> {
+    let next = progress.get("next").and_then(Value::as_u64).unwrap_or(0) as usize;
+    let pause = CONFIG
+        .rate_limits
+        .event_send
+        .map(|rate| Duration::from_secs_f64(1.0 / rate.per_second));
+
+    for (i, user_id) in user_ids.iter().enumerate().skip(next) {
+        let outcome = match invite(job, user_id).await {
+            Ok(()) => "invited",
+            Err(e) => {
+                tracing::debug!(%user_id, error = %e, "Unable to invite user");
+                let errors = progress.done.entry("errors").or_insert_with(|| json!({}));
+                errors[user_id.as_str()] = json!(e.to_string());
+                "failed"
+            }
+        };
+        let count = progress.get(outcome).and_then(Value::as_u64).unwrap_or(0);
+        progress.done.insert(outcome.to_owned(), json!(count + 1));
+        progress.set(storage, "next", json!(i + 1)).await?;
+        if let Some(pause) = pause {
+            actix_rt::time::delay_for(pause).await;
+        }
+    }
+    Ok(())
+}
+
+/// Invites a user to a room on behalf of the admin who started the job.
+///
+/// TODO: Send an `m.room.member` invite event, and send it to remote users'
+/// servers over federation, once events are stored. Until then users can't
+/// be invited by ID, so every invite fails.
+async fn invite(_job: &AdminJob, user_id: &str) -> Result<(), Box<dyn Error>> {
+    Err(format!(
+        "Unable to invite {}: users can't be invited by ID yet",
+        user_id
+    )
+    .into())
+}
@@ -0,0 +1,276 @@
+//! Emails users digests of the highlights they missed.
+//!
+//! Users get email notifications by adding an `email` pusher with their
+//! address as its pushkey. Highlights queued for them are held until they
+//! have been idle for a while, then sent together as one digest. Digests
+//! are throttled per pusher, and users can turn them off in their account
+//! data without removing the pusher.
+use std::error::Error;
+use std::io;
+use std::path::Path;
+
+use serde_json::Value;
+
+use super::{
+    mailer::{Email, Mailer},
+    pusher::Progress,
+};
+use crate::{
+    clock::now_ms,
+    db::Store,
+    models::push::{Pusher, PusherState, QueuedNotification},
+    CONFIG,
+};
+
+/// The app ID of email pushers.
+pub const APP_ID: &str = "m.email";
+/// The account data users turn email notifications off with, by setting
+/// `{"enabled": false}`.
+pub const SETTINGS_TYPE: &str = "im.maelstrom.email_notifications";
+/// The most notifications considered for one digest.
+const DIGEST_SIZE: i64 = 50;
+
+const DEFAULT_SUBJECT: &str = "{{count}} new highlights on {{server_name}}";
+const DEFAULT_BODY: &str = "Hi {{user_id}},
+
+You have {{count}} new highlights on {{server_name}} since you were last active:
+
+{{notifications}}
+You are getting this email because you turned on email notifications. You
+can turn them off in your client's notification settings.
+";
+const DEFAULT_ITEM: &str = "{{sender}} in {{room}}: {{body}}\n";
+
+/// How and when digests are sent.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub mailer: Mailer,
+    /// The address digests are sent from
+    pub from: String,
+    /// How long a user must have been idle before being sent a digest, in ms
+    pub idle_ms: i64,
+    /// The least time between two digests to the same address, in ms
+    pub throttle_ms: i64,
+    pub templates: Templates,
+}
+
+/// The templates digests are made from. `{{name}}` placeholders are
+/// replaced with values: `count`, `server_name`, `user_id` and
+/// `notifications` in the subject and body, and `sender`, `room` and `body`
+/// in the item, which is repeated for each highlight to make
+/// `notifications`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Templates {
+    pub subject: String,
+    pub body: String,
+    pub item: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            subject: DEFAULT_SUBJECT.to_owned(),
+            body: DEFAULT_BODY.to_owned(),
+            item: DEFAULT_ITEM.to_owned(),
+        }
+    }
+}
+
+impl Templates {
+    /// Loads `subject.txt`, `body.txt` and `item.txt` from a directory,
+    /// using the built in template for any that is missing.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let load = |name: &str, default: &str| match std::fs::read_to_string(dir.join(name)) {
+            Ok(template) => Ok(template),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(default.to_owned()),
+            Err(e) => Err(e),
+        };
+        Ok(Templates {
+            subject: load("subject.txt", DEFAULT_SUBJECT)?.trim().to_owned(),
+            body: load("body.txt", DEFAULT_BODY)?,
+            item: load("item.txt", DEFAULT_ITEM)?,
+        })
+    }
+}
+
+/// Sends an email pusher a digest of the highlights queued for its user, if
+/// one is due. Everything else queued is skipped, as are highlights the user
+/// has been active since.
+pub async fn push<T: Store>(
+    storage: &T,
+    settings: &Settings,
+    localpart: &str,
+    pusher: &Pusher,
+    state: &mut PusherState,
+) -> Result<Progress, Box<dyn Error>> {
+    let queued = storage
+        .get_push_notifications(localpart, state.last_stream_id, DIGEST_SIZE)
+        .await?;
+    let last = match queued.last() {
+        Some(last) => last.stream_id,
+        None => return Ok(Progress::CaughtUp),
+    };
+
+    let user_id = format!("@{}:{}", localpart, CONFIG.hostname);
+    // TODO: Nothing sets the presence of local users yet, so until it does
+    // users are never seen to have been active
+    let last_active = storage
+        .get_presence(&user_id)
+        .await?
+        .map_or(0, |presence| presence.last_active_ts);
+    let highlights: Vec<&QueuedNotification> = queued
+        .iter()
+        .filter(|queued| queued.queued_ts > last_active && is_highlight(queued))
+        .collect();
+    if highlights.is_empty() || !enabled(storage, localpart).await? {
+        state.last_stream_id = last;
+        storage
+            .set_pusher_state(localpart, &pusher.app_id, &pusher.pushkey, state)
+            .await?;
+        return Ok(Progress::CaughtUp);
+    }
+
+    let send_at = (highlights[0].queued_ts + settings.idle_ms)
+        .max(state.last_success_ts + settings.throttle_ms);
+    let wait = send_at - now_ms();
+    if wait > 0 {
+        return Ok(Progress::Waiting(wait));
+    }
+
+    let notifications: Vec<&Value> = highlights.iter().map(|h| &h.notification).collect();
+    let (subject, body) = digest(&settings.templates, &user_id, &notifications);
+    let email = Email {
+        to: pusher.pushkey.clone(),
+        subject,
+        body,
+    };
+    if let Err(e) = settings.mailer.send(&settings.from, &email).await {
+        return Ok(Progress::Failed(e.to_string()));
+    }
+    state.last_stream_id = last;
+    Ok(Progress::Delivered)
+}
+
+/// Whether a notification is for an event, which the push rules said to
+/// highlight.
+fn is_highlight(queued: &QueuedNotification) -> bool {
+    queued.notification.get("event_id").is_some()
+        && queued
+            .tweaks
+            .get("highlight")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+}
+
+/// Whether the user wants email notifications. They do unless they turned
+/// them off.
+async fn enabled<T: Store>(storage: &T, localpart: &str) -> Result<bool, Box<dyn Error>> {
+    let settings = storage
+        .get_account_data(localpart, None, SETTINGS_TYPE)
+        .await?;
+    Ok(settings
+        .as_ref()
+        .and_then(|settings| settings.get("enabled"))
+        .and_then(Value::as_bool)
+        .unwrap_or(true))
+}
+
+/// Makes the subject and body of a digest of notifications.
+fn digest(templates: &Templates, user_id: &str, notifications: &[&Value]) -> (String, String) {
+    let items: String = notifications
+        .iter()
+        .map(|notification| {
+            let field = |name: &str| notification.get(name).and_then(Value::as_str);
+            let sender = field("sender_display_name")
+                .or_else(|| field("sender"))
+                .unwrap_or_default();
+            let room = field("room_name")
+                .or_else(|| field("room_alias"))
+                .or_else(|| field("room_id"))
+                .unwrap_or_default();
+            let body = notification
+                .pointer("/content/body")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            render(
+                &templates.item,
+                &[("sender", sender), ("room", room), ("body", body)],
+            )
+        })
+        .collect();
+    let count = notifications.len().to_string();
+    let vars = [
+        ("count", count.as_str()),
+        ("server_name", CONFIG.hostname.as_str()),
+        ("user_id", user_id),
+        ("notifications", items.as_str()),
+    ];
+    (
+        render(&templates.subject, &vars),
+        render(&templates.body, &vars),
+    )
+}
+
+/// Replaces the `{{name}}` placeholders in a template with their values.
+/// Unknown placeholders are left as they are, and values are not searched
+/// for placeholders themselves.
+fn render(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = match after.find("}}") {
+            Some(end) => end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let name = after[..end].trim();
+        match vars.iter().find(|(var, _)| *var == name) {
+            Some((_, value)) => rendered.push_str(value),
+            None => rendered.push_str(&rest[start..start + end + 4]),
+        }
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render() {
+        let vars = [("count", "2"), ("body", "{{count}}")];
+        assert_eq!(render("{{count}} new", &vars), "2 new");
+        assert_eq!(render("{{ count }}/{{count}}", &vars), "2/2");
+        assert_eq!(render("said {{body}}", &vars), "said {{count}}");
+        assert_eq!(render("{{unknown}} {{count", &vars), "{{unknown}} {{count");
+    }
+
+    #[test]
+    fn test_is_highlight() {
+        let queued = |notification: Value, tweaks: Value| QueuedNotification {
+            stream_id: 1,
+            queued_ts: 0,
+            notification,
+            tweaks,
+        };
+        let event = json!({"event_id": "$event", "room_id": "!room:example.com"});
+        assert!(is_highlight(&queued(
+            event.clone(),
+            json!({"highlight": true})
+        )));
+        assert!(!is_highlight(&queued(
+            event.clone(),
+            json!({"highlight": false})
+        )));
+        assert!(!is_highlight(&queued(event, json!({}))));
+        let badge = json!({"counts": {"unread": 1}});
+        assert!(!is_highlight(&queued(badge, json!({"highlight": true}))));
+    }
+}
@@ -0,0 +1,258 @@
+//! Sending email, through either a `sendmail` compatible command or an SMTP
+//! relay.
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+use actix_web::{error::BlockingError, web};
+
+use crate::CONFIG;
+
+/// How long an SMTP relay may take to answer.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Where email is handed over for delivery.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mailer {
+    /// A command run with the message on its stdin, which reads the
+    /// recipient from its headers, like `sendmail -t`. It must exit with 0
+    /// if it accepted the message.
+    Sendmail(Vec<String>),
+    /// An SMTP relay listening on a TCP address, which accepts mail from
+    /// this server without authentication or TLS, e.g. a local MTA.
+    Smtp(String),
+}
+
+/// An email to send.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    /// The plain text body
+    pub body: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidMailer(String);
+
+impl fmt::Display for InvalidMailer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid mailer `{}`, expected `smtp://host:port` or `sendmail:<command line>`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidMailer {}
+
+impl FromStr for Mailer {
+    type Err = InvalidMailer;
+
+    /// Parses `smtp://host:port` or `sendmail:<command line>`. The command
+    /// line is split on whitespace; it isn't run through a shell.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(addr) = s.strip_prefix("smtp://") {
+            if !addr.is_empty() {
+                return Ok(Mailer::Smtp(addr.to_owned()));
+            }
+        } else if let Some(command) = s.strip_prefix("sendmail:") {
+            let args: Vec<String> = command.split_whitespace().map(str::to_owned).collect();
+            if !args.is_empty() {
+                return Ok(Mailer::Sendmail(args));
+            }
+        }
+        Err(InvalidMailer(s.to_owned()))
+    }
+}
+
+impl Mailer {
+    /// Sends an email from `from`. Mailers are blocking, so this runs on the
+    /// blocking thread pool.
+    pub async fn send(&self, from: &str, email: &Email) -> io::Result<()> {
+        let mailer = self.clone();
+        let from = from.to_owned();
+        let to = email.to.clone();
+        let message = message(&from, email, &chrono::Utc::now().to_rfc2822());
+        web::block(move || match mailer {
+            Mailer::Sendmail(args) => send_sendmail(&args, &message),
+            Mailer::Smtp(addr) => send_smtp(&addr, &from, &to, &message),
+        })
+        .await
+        .map_err(|e| match e {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => io::Error::new(io::ErrorKind::Other, "Thread pool is gone"),
+        })
+    }
+}
+
+/// Formats an email as a message, with CRLF line endings.
+fn message(from: &str, email: &Email, date: &str) -> String {
+    let headers = [
+        ("From", from.to_owned()),
+        ("To", email.to.clone()),
+        ("Subject", encode_header(&email.subject)),
+        ("Date", date.to_owned()),
+        ("MIME-Version", "1.0".to_owned()),
+        ("Content-Type", "text/plain; charset=utf-8".to_owned()),
+        ("Content-Transfer-Encoding", "8bit".to_owned()),
+    ];
+    let mut message = String::new();
+    for (name, value) in headers.iter() {
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str("\r\n");
+    for line in email.body.lines() {
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message
+}
+
+/// Headers must be ASCII, so anything else is sent as an RFC 2047 encoded
+/// word.
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_owned()
+    } else {
+        format!("=?UTF-8?B?{}?=", base64::encode(value))
+    }
+}
+
+fn send_sendmail(args: &[String], message: &str) -> io::Result<()> {
+    let mut child = Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(message.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Mailer failed with {}", status),
+        ));
+    }
+    Ok(())
+}
+
+fn send_smtp(addr: &str, from: &str, to: &str, message: &str) -> io::Result<()> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    expect_reply(&mut reader, 2)?;
+    for command in &[
+        format!("EHLO {}", CONFIG.hostname),
+        format!("MAIL FROM:<{}>", from),
+        format!("RCPT TO:<{}>", to),
+    ] {
+        write!(writer, "{}\r\n", command)?;
+        expect_reply(&mut reader, 2)?;
+    }
+    writer.write_all(b"DATA\r\n")?;
+    expect_reply(&mut reader, 3)?;
+    writer.write_all(dot_stuff(message).as_bytes())?;
+    writer.write_all(b".\r\n")?;
+    expect_reply(&mut reader, 2)?;
+    writer.write_all(b"QUIT\r\n")?;
+    Ok(())
+}
+
+/// Reads an SMTP reply, which may span several lines, and checks its code
+/// is in the class expected, e.g. 2 for `250`.
+fn expect_reply<R: BufRead>(reader: &mut R, class: u8) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "SMTP relay closed the connection",
+            ));
+        }
+        let line = line.trim_end();
+        // All but the last line of a reply have a `-` after the code
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        return match line.as_bytes().first() {
+            Some(&code) if code == b'0' + class => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Unexpected reply from SMTP relay: {}", line),
+            )),
+        };
+    }
+}
+
+/// Lines starting with `.` get another, so none is taken for the `.` that
+/// ends the message.
+fn dot_stuff(message: &str) -> String {
+    let stuffed = message.replace("\r\n.", "\r\n..");
+    if stuffed.starts_with('.') {
+        format!(".{}", stuffed)
+    } else {
+        stuffed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mailer() {
+        assert_eq!(
+            "smtp://localhost:25".parse(),
+            Ok(Mailer::Smtp("localhost:25".to_owned()))
+        );
+        assert_eq!(
+            "sendmail:/usr/sbin/sendmail -t".parse(),
+            Ok(Mailer::Sendmail(vec![
+                "/usr/sbin/sendmail".to_owned(),
+                "-t".to_owned()
+            ]))
+        );
+        assert!("smtp://".parse::<Mailer>().is_err());
+        assert!("sendmail:".parse::<Mailer>().is_err());
+        assert!("localhost:25".parse::<Mailer>().is_err());
+    }
+
+    #[test]
+    fn test_message() {
+        let email = Email {
+            to: "alice@example.com".to_owned(),
+            subject: "Grüße".to_owned(),
+            body: "Hello\n.\nBye".to_owned(),
+        };
+        let message = message(
+            "matrix@example.org",
+            &email,
+            "Thu, 1 Jan 1970 00:00:00 +0000",
+        );
+        assert!(message.starts_with("From: matrix@example.org\r\nTo: alice@example.com\r\n"));
+        assert!(message.contains("\r\nSubject: =?UTF-8?B?R3LDvMOfZQ==?=\r\n"));
+        assert!(message.ends_with("\r\n\r\nHello\r\n.\r\nBye\r\n"));
+        assert!(dot_stuff(&message).ends_with("\r\n\r\nHello\r\n..\r\nBye\r\n"));
+    }
+
+    #[test]
+    fn test_expect_reply() {
+        let mut reply = "250-example.com\r\n250-8BITMIME\r\n250 SIZE 1000\r\n".as_bytes();
+        assert!(expect_reply(&mut reply, 2).is_ok());
+        let mut reply = "354 Go ahead\r\n".as_bytes();
+        assert!(expect_reply(&mut reply, 2).is_err());
+        let mut reply = "".as_bytes();
+        assert!(expect_reply(&mut reply, 2).is_err());
+    }
+}
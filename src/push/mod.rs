@@ -0,0 +1,6 @@
+//! Push notifications: which events notify which users, and how.
+pub mod email;
+pub mod mailer;
+pub mod notify;
+pub mod pusher;
+pub mod rules;
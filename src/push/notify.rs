@@ -0,0 +1,188 @@
+//! Acting on what the push rules decide: counting the events that notify a
+//! user, and queueing them for the user's pushers.
+//!
+//! Unread counts are kept per room and thread as events arrive and read
+//! receipts clear them, so neither sync nor the pushers ever recount a
+//! room's history.
+use std::error::Error;
+
+use serde_json::{json, Map, Value};
+
+use super::{
+    pusher::Notifier,
+    rules::{self, Context},
+};
+use crate::{
+    db::Store,
+    models::push::{Ruleset, MAIN_THREAD},
+};
+
+/// What a user's push rules decided about an event.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Decision {
+    pub notify: bool,
+    pub highlight: bool,
+    /// The tweaks the actions set, e.g. `sound`
+    pub tweaks: Map<String, Value>,
+}
+
+impl Decision {
+    /// Reads the actions of the rule an event matched. `highlight` is on if
+    /// it is set without a value.
+    pub fn from_actions(actions: &[Value]) -> Self {
+        let mut decision = Decision::default();
+        for action in actions {
+            match action {
+                Value::String(action) if action == "notify" || action == "coalesce" => {
+                    decision.notify = true
+                }
+                Value::Object(action) => {
+                    if let Some(tweak) = action.get("set_tweak").and_then(Value::as_str) {
+                        let value = action.get("value").cloned().unwrap_or(Value::Bool(true));
+                        decision.tweaks.insert(tweak.to_owned(), value);
+                    }
+                }
+                _ => {}
+            }
+        }
+        decision.highlight = decision
+            .tweaks
+            .get("highlight")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        decision
+    }
+}
+
+/// The thread an event is in, or `MAIN_THREAD`.
+pub fn thread_id(event: &Value) -> &str {
+    let relation = event.pointer("/content/m.relates_to");
+    match relation
+        .and_then(|r| r.get("rel_type"))
+        .and_then(Value::as_str)
+    {
+        Some("m.thread") => relation
+            .and_then(|r| r.get("event_id"))
+            .and_then(Value::as_str)
+            .unwrap_or(MAIN_THREAD),
+        _ => MAIN_THREAD,
+    }
+}
+
+/// Evaluates a user's push rules against an event that arrived in a room
+/// they are in. If it notifies them, it is counted as unread and queued for
+/// their pushers.
+///
+/// TODO: Call this for each local member of a room as events arrive, once
+/// rooms exist.
+pub async fn handle_event<T: Store>(
+    storage: &T,
+    pusher: &Notifier,
+    localpart: &str,
+    ruleset: &Ruleset,
+    ctx: &Context<'_>,
+    event: &Value,
+) -> Result<(), Box<dyn Error>> {
+    let decision = Decision::from_actions(rules::actions(ruleset, event, ctx));
+    if !decision.notify {
+        return Ok(());
+    }
+    let room_id = event
+        .get("room_id")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    storage
+        .add_push_counts(
+            localpart,
+            room_id,
+            thread_id(event),
+            1,
+            decision.highlight as i64,
+        )
+        .await?;
+
+    let unread = storage.get_unread_count(localpart).await?;
+    let notification = json!({
+        "event_id": event["event_id"],
+        "room_id": room_id,
+        "type": event["type"],
+        "sender": event["sender"],
+        "content": event["content"],
+        "prio": if decision.highlight { "high" } else { "low" },
+        "counts": {"unread": unread},
+    });
+    storage
+        .add_push_notification(localpart, &notification, &Value::Object(decision.tweaks))
+        .await?;
+    pusher.notify(localpart);
+    Ok(())
+}
+
+/// Clears a user's unread counts in a room once they have read it, and
+/// updates the badges of their apps. `thread_id` limits it to one thread,
+/// for a threaded receipt.
+///
+/// TODO: Call this when the user sends a read receipt, once receipts exist.
+pub async fn handle_read<T: Store>(
+    storage: &T,
+    pusher: &Notifier,
+    localpart: &str,
+    room_id: &str,
+    thread_id: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    storage
+        .clear_push_counts(localpart, room_id, thread_id)
+        .await?;
+
+    let unread = storage.get_unread_count(localpart).await?;
+    let badge = json!({"prio": "low", "counts": {"unread": unread}});
+    storage
+        .add_push_notification(localpart, &badge, &json!({}))
+        .await?;
+    pusher.notify(localpart);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decision_from_actions() {
+        let actions: Vec<Value> = serde_json::from_value(json!([
+            "notify",
+            {"set_tweak": "sound", "value": "default"},
+            {"set_tweak": "highlight"}
+        ]))
+        .unwrap();
+        let decision = Decision::from_actions(&actions);
+        assert!(decision.notify);
+        assert!(decision.highlight);
+        assert_eq!(decision.tweaks["sound"], "default");
+
+        let actions = vec![
+            json!("notify"),
+            json!({"set_tweak": "highlight", "value": false}),
+        ];
+        let decision = Decision::from_actions(&actions);
+        assert!(decision.notify);
+        assert!(!decision.highlight);
+
+        assert_eq!(
+            Decision::from_actions(&[json!("dont_notify")]),
+            Decision::default()
+        );
+    }
+
+    #[test]
+    fn test_thread_id() {
+        let threaded = json!({"content": {"m.relates_to": {
+            "rel_type": "m.thread",
+            "event_id": "$root",
+        }}});
+        assert_eq!(thread_id(&threaded), "$root");
+        let reply = json!({"content": {"m.relates_to": {"m.in_reply_to": {"event_id": "$a"}}}});
+        assert_eq!(thread_id(&reply), MAIN_THREAD);
+        assert_eq!(thread_id(&json!({"content": {}})), MAIN_THREAD);
+    }
+}
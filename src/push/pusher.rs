@@ -0,0 +1,410 @@
+//! Delivers notifications to users' pushers: through push gateways for
+//! `http` pushers, and as email digests for `email` pushers.
+//!
+//! Notifications are first queued in the `Store` for the user they are for,
+//! so nothing is lost if the server restarts before they are delivered.
+//! Each pusher keeps its own position in its user's queue, so a gateway that
+//! is down only holds up the pushers using it. Each user with something
+//! queued has a delivery task, which sends to every pusher until all have
+//! caught up. Pushers that fail are retried with exponential backoff.
+//!
+//! A queued notification is the gateway's `notification` object without its
+//! `devices`, which are filled in for each pusher. One without an `event_id`
+//! only updates the app's badge with its `counts`.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::Rc;
+use std::time::Duration;
+
+use actix_web::client::Client;
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+use serde_json::{json, Value};
+
+use super::email;
+use crate::{
+    clock::now_ms,
+    db::Store,
+    models::push::{Pusher, PusherState},
+    shutdown::InFlight,
+    CONFIG,
+};
+
+/// The most notifications loaded from the queue at once.
+const BATCH_SIZE: i64 = 50;
+/// How long to wait for a push gateway to respond.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait before retrying a pusher the first time it fails.
+const MIN_RETRY_INTERVAL: i64 = 30 * 1000;
+/// The longest to wait between retries of a pusher.
+const MAX_RETRY_INTERVAL: i64 = 60 * 60 * 1000;
+/// The fields of a notification sent to `event_id_only` pushers.
+const EVENT_ID_ONLY_FIELDS: [&str; 4] = ["event_id", "room_id", "counts", "prio"];
+
+/// Wakes the pusher when a notification is queued for a user. Can be cloned
+/// and used from any thread.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    wakeups: UnboundedSender<String>,
+    pushing: InFlight,
+}
+
+impl Notifier {
+    pub fn notify(&self, localpart: &str) {
+        // The pusher only stops when the server does
+        let _ = self.wakeups.unbounded_send(localpart.to_owned());
+    }
+
+    /// Waits for the notifications being pushed to be delivered, for at most
+    /// `timeout`. Returns whether they all were.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        self.pushing.wait(timeout).await
+    }
+}
+
+/// The users with a running delivery task, and whether more was queued for
+/// each since its task last looked.
+type Active = Rc<RefCell<HashMap<String, bool>>>;
+
+/// Starts the pusher on the current thread, first catching up on whatever
+/// was left undelivered when the server last stopped.
+pub fn start<T: Store + 'static>(storage: T) -> Notifier {
+    let (sender, receiver) = mpsc::unbounded();
+    let pushing = InFlight::default();
+    actix_rt::spawn(run(storage, receiver, pushing.clone()));
+    Notifier {
+        wakeups: sender,
+        pushing,
+    }
+}
+
+async fn run<T: Store + 'static>(
+    storage: T,
+    mut wakeups: UnboundedReceiver<String>,
+    pushing: InFlight,
+) {
+    let active = Active::default();
+    match storage.get_push_users().await {
+        Ok(users) => {
+            for localpart in users {
+                wake(&storage, &active, &pushing, localpart);
+            }
+        }
+        Err(e) => tracing::error!(error = %e, "Unable to load the push queue"),
+    }
+    while let Some(localpart) = wakeups.next().await {
+        wake(&storage, &active, &pushing, localpart);
+    }
+}
+
+/// Makes sure a delivery task is running for a user.
+fn wake<T: Store + 'static>(storage: &T, active: &Active, pushing: &InFlight, localpart: String) {
+    let mut tasks = active.borrow_mut();
+    if let Some(queued) = tasks.get_mut(&localpart) {
+        *queued = true;
+        return;
+    }
+    tasks.insert(localpart.clone(), false);
+    actix_rt::spawn(deliver(
+        storage.clone(),
+        active.clone(),
+        pushing.clone(),
+        localpart,
+    ));
+}
+
+/// Sends everything queued for a user to their pushers, waiting out the
+/// backoff of pushers that fail.
+async fn deliver<T: Store>(storage: T, active: Active, pushing: InFlight, localpart: String) {
+    loop {
+        // Notifications cut off by a shutdown would be pushed again once
+        // the server is back
+        let started = pushing.start();
+        let wait = match push_all(&storage, &localpart).await {
+            Ok(wait) => wait,
+            Err(e) => {
+                tracing::error!(%localpart, error = %e, "Unable to update the push queue");
+                Some(MIN_RETRY_INTERVAL)
+            }
+        };
+        drop(started);
+        if let Some(wait) = wait {
+            actix_rt::time::delay_for(Duration::from_millis(wait as u64)).await;
+            continue;
+        }
+
+        // Anything queued while we were sending is picked up by going round
+        // again
+        let mut tasks = active.borrow_mut();
+        if tasks.get(&localpart) == Some(&true) {
+            tasks.insert(localpart.clone(), false);
+            continue;
+        }
+        tasks.remove(&localpart);
+        return;
+    }
+}
+
+/// How far a pusher got delivering what is queued for its user.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Progress {
+    /// Delivered something, and may have more to deliver
+    Delivered,
+    /// Has nothing left to deliver
+    CaughtUp,
+    /// Has something to deliver, but not for this many ms
+    Waiting(i64),
+    /// Delivery failed, with why, so the pusher backs off
+    Failed(String),
+    /// The pusher's pushkey was rejected, so it is of no more use
+    Rejected,
+}
+
+/// Whether a pusher is delivered to. Email pushers are only while email
+/// notifications are set up.
+fn is_active(pusher: &Pusher) -> bool {
+    match pusher.kind.as_str() {
+        "http" => true,
+        "email" => CONFIG.email_notifications.is_some(),
+        _ => false,
+    }
+}
+
+/// Delivers what is queued to each of a user's pushers, as far as each can
+/// get, then drops what every pusher has delivered. Returns how long to wait
+/// until a pusher that is waiting or failed can go on, or `None` if all
+/// caught up.
+async fn push_all<T: Store>(storage: &T, localpart: &str) -> Result<Option<i64>, Box<dyn Error>> {
+    let mut wait: Option<i64> = None;
+    for (pusher, mut state) in storage.get_pushers(localpart).await? {
+        if !is_active(&pusher) {
+            continue;
+        }
+        let retry_wait = state.retry_last_ts + state.retry_interval - now_ms();
+        if state.retry_interval > 0 && retry_wait > 0 {
+            wait = Some(wait.map_or(retry_wait, |wait| wait.min(retry_wait)));
+            continue;
+        }
+
+        loop {
+            let progress = match (&CONFIG.email_notifications, pusher.kind.as_str()) {
+                (Some(settings), "email") => {
+                    email::push(storage, settings, localpart, &pusher, &mut state).await?
+                }
+                _ => push_http(storage, localpart, &pusher, &mut state).await?,
+            };
+            match progress {
+                Progress::Delivered => {
+                    state.retry_interval = 0;
+                    state.last_success_ts = now_ms();
+                    storage
+                        .set_pusher_state(localpart, &pusher.app_id, &pusher.pushkey, &state)
+                        .await?;
+                    continue;
+                }
+                Progress::CaughtUp => {}
+                Progress::Waiting(pusher_wait) => {
+                    wait = Some(wait.map_or(pusher_wait, |wait| wait.min(pusher_wait)));
+                }
+                Progress::Failed(e) => {
+                    tracing::warn!(%localpart, app_id = %pusher.app_id, error = %e, "Unable to push");
+                    state.retry_last_ts = now_ms();
+                    state.retry_interval = next_retry_interval(state.retry_interval);
+                    storage
+                        .set_pusher_state(localpart, &pusher.app_id, &pusher.pushkey, &state)
+                        .await?;
+                    wait = Some(
+                        wait.map_or(state.retry_interval, |wait| wait.min(state.retry_interval)),
+                    );
+                }
+                Progress::Rejected => {
+                    tracing::info!(%localpart, "Push gateway rejected pushkey, removing pusher");
+                    storage
+                        .delete_pusher(localpart, &pusher.app_id, &pusher.pushkey)
+                        .await?;
+                }
+            }
+            break;
+        }
+    }
+
+    // Users without pushers have no use for their notifications
+    let delivered = storage
+        .get_pushers(localpart)
+        .await?
+        .iter()
+        .filter(|(pusher, _)| is_active(pusher))
+        .map(|(_, state)| state.last_stream_id)
+        .min()
+        .unwrap_or(i64::MAX);
+    storage
+        .delete_push_notifications(localpart, delivered)
+        .await?;
+    Ok(wait)
+}
+
+/// Sends an `http` pusher everything queued for its user, recording its
+/// progress after each notification.
+async fn push_http<T: Store>(
+    storage: &T,
+    localpart: &str,
+    pusher: &Pusher,
+    state: &mut PusherState,
+) -> Result<Progress, Box<dyn Error>> {
+    let mut progress = Progress::CaughtUp;
+    loop {
+        let queued = storage
+            .get_push_notifications(localpart, state.last_stream_id, BATCH_SIZE)
+            .await?;
+        if queued.is_empty() {
+            return Ok(progress);
+        }
+        for queued in queued {
+            match send_notification(pusher, queued.notification, queued.tweaks).await {
+                Ok(true) => {}
+                // The gateway no longer knows the device
+                Ok(false) => return Ok(Progress::Rejected),
+                Err(e) => return Ok(Progress::Failed(e)),
+            }
+            state.last_stream_id = queued.stream_id;
+            storage
+                .set_pusher_state(localpart, &pusher.app_id, &pusher.pushkey, state)
+                .await?;
+            progress = Progress::Delivered;
+        }
+    }
+}
+
+/// How long to wait before retrying a pusher that failed again.
+fn next_retry_interval(previous: i64) -> i64 {
+    if previous <= 0 {
+        MIN_RETRY_INTERVAL
+    } else {
+        (previous * 2).min(MAX_RETRY_INTERVAL)
+    }
+}
+
+/// Sends a notification to a pusher's push gateway. Returns whether the
+/// gateway accepted the pushkey.
+async fn send_notification(
+    pusher: &Pusher,
+    notification: Value,
+    tweaks: Value,
+) -> Result<bool, String> {
+    let url = pusher.data.url.as_deref().ok_or("Pusher has no URL")?;
+    let body = json!({ "notification": gateway_notification(pusher, notification, tweaks) });
+    let mut res = Client::build()
+        .timeout(TIMEOUT)
+        .finish()
+        .post(url)
+        .send_json(&body)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("Push gateway responded {}", res.status()));
+    }
+
+    let response: Value = res.json().await.map_err(|e| e.to_string())?;
+    let rejected = response
+        .get("rejected")
+        .and_then(Value::as_array)
+        .map_or(false, |rejected| {
+            rejected
+                .iter()
+                .any(|pushkey| pushkey.as_str() == Some(pusher.pushkey.as_str()))
+        });
+    Ok(!rejected)
+}
+
+/// Builds the notification sent to a gateway for one pusher, with the
+/// pusher as its only device. `event_id_only` pushers are only sent the
+/// IDs and counts.
+fn gateway_notification(pusher: &Pusher, notification: Value, tweaks: Value) -> Value {
+    let mut notification = match notification {
+        Value::Object(fields) => fields,
+        _ => Default::default(),
+    };
+    if pusher.data.format.as_deref() == Some("event_id_only") {
+        notification.retain(|field, _| EVENT_ID_ONLY_FIELDS.contains(&field.as_str()));
+    }
+
+    // The gateway is given the pusher's data without its URL
+    let mut data = serde_json::to_value(&pusher.data).unwrap_or_default();
+    if let Some(data) = data.as_object_mut() {
+        data.remove("url");
+    }
+    notification.insert(
+        "devices".to_owned(),
+        json!([{
+            "app_id": pusher.app_id,
+            "pushkey": pusher.pushkey,
+            "data": data,
+            "tweaks": tweaks,
+        }]),
+    );
+    Value::Object(notification)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::push::PusherData;
+
+    fn pusher(format: Option<&str>) -> Pusher {
+        Pusher {
+            pushkey: "key".to_owned(),
+            kind: "http".to_owned(),
+            app_id: "com.example.app".to_owned(),
+            app_display_name: "Example".to_owned(),
+            device_display_name: "Phone".to_owned(),
+            profile_tag: None,
+            lang: "en".to_owned(),
+            data: PusherData {
+                url: Some("https://push.example.com/_matrix/push/v1/notify".to_owned()),
+                format: format.map(str::to_owned),
+                extra: Default::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_gateway_notification() {
+        let notification = json!({
+            "event_id": "$event",
+            "room_id": "!room:example.com",
+            "sender": "@bob:example.com",
+            "content": {"body": "hello"},
+            "counts": {"unread": 2},
+        });
+        let tweaks = json!({"sound": "default"});
+
+        let full = gateway_notification(&pusher(None), notification.clone(), tweaks.clone());
+        assert_eq!(full["content"]["body"], "hello");
+        assert_eq!(
+            full["devices"],
+            json!([{
+                "app_id": "com.example.app",
+                "pushkey": "key",
+                "data": {},
+                "tweaks": {"sound": "default"},
+            }])
+        );
+
+        let ids_only = gateway_notification(&pusher(Some("event_id_only")), notification, tweaks);
+        assert_eq!(ids_only.get("content"), None);
+        assert_eq!(ids_only.get("sender"), None);
+        assert_eq!(ids_only["event_id"], "$event");
+        assert_eq!(ids_only["counts"]["unread"], 2);
+        assert_eq!(ids_only["devices"][0]["data"]["format"], "event_id_only");
+    }
+
+    #[test]
+    fn test_next_retry_interval() {
+        assert_eq!(next_retry_interval(0), MIN_RETRY_INTERVAL);
+        assert_eq!(next_retry_interval(MIN_RETRY_INTERVAL), 60 * 1000);
+        assert_eq!(next_retry_interval(MAX_RETRY_INTERVAL), MAX_RETRY_INTERVAL);
+    }
+}
@@ -0,0 +1,448 @@
+//! Push rules: deciding whether an event notifies a user, and how.
+use std::collections::BTreeMap;
+
+use regex::Regex;
+use serde_json::{json, Value};
+
+use crate::models::{
+    auth::UserId,
+    push::{Condition, PushRule, Ruleset, UserPushRules, RULE_KINDS},
+};
+
+/// The power level needed to notify the whole room, if the room's power
+/// levels don't say.
+const DEFAULT_ROOM_NOTIFICATION_LEVEL: i64 = 50;
+
+/// What an event is evaluated against, besides the rules themselves.
+pub struct Context<'a> {
+    /// The user's display name in the room
+    pub display_name: Option<&'a str>,
+    /// How many users are joined to the room
+    pub member_count: u64,
+    /// The power level of the event's sender in the room
+    pub sender_power_level: i64,
+    /// The `notifications` section of the room's power levels
+    pub notification_power_levels: &'a BTreeMap<String, i64>,
+}
+
+fn default_rule(rule_id: &str, conditions: Vec<Condition>, actions: Value) -> PushRule {
+    PushRule {
+        rule_id: rule_id.to_owned(),
+        default: true,
+        enabled: true,
+        actions: serde_json::from_value(actions).unwrap_or_default(),
+        conditions: Some(conditions),
+        pattern: None,
+    }
+}
+
+fn event_match(key: &str, pattern: &str) -> Condition {
+    Condition::EventMatch {
+        key: key.to_owned(),
+        pattern: pattern.to_owned(),
+    }
+}
+
+fn member_count(is: &str) -> Condition {
+    Condition::RoomMemberCount { is: is.to_owned() }
+}
+
+/// The server's default rules for a user, as the spec gives them.
+pub fn server_default(user_id: &str) -> Ruleset {
+    let notify_highlight = json!([
+        "notify",
+        {"set_tweak": "sound", "value": "default"},
+        {"set_tweak": "highlight"}
+    ]);
+    let notify_no_highlight = json!(["notify", {"set_tweak": "highlight", "value": false}]);
+    let notify_sound_no_highlight = json!([
+        "notify",
+        {"set_tweak": "sound", "value": "default"},
+        {"set_tweak": "highlight", "value": false}
+    ]);
+
+    let mut master = default_rule(".m.rule.master", vec![], json!(["dont_notify"]));
+    master.enabled = false;
+
+    Ruleset {
+        override_: vec![
+            master,
+            default_rule(
+                ".m.rule.suppress_notices",
+                vec![event_match("content.msgtype", "m.notice")],
+                json!(["dont_notify"]),
+            ),
+            default_rule(
+                ".m.rule.invite_for_me",
+                vec![
+                    event_match("type", "m.room.member"),
+                    event_match("content.membership", "invite"),
+                    event_match("state_key", user_id),
+                ],
+                notify_sound_no_highlight.clone(),
+            ),
+            default_rule(
+                ".m.rule.member_event",
+                vec![event_match("type", "m.room.member")],
+                json!(["dont_notify"]),
+            ),
+            default_rule(
+                ".m.rule.contains_display_name",
+                vec![Condition::ContainsDisplayName],
+                notify_highlight.clone(),
+            ),
+            default_rule(
+                ".m.rule.tombstone",
+                vec![
+                    event_match("type", "m.room.tombstone"),
+                    event_match("state_key", ""),
+                ],
+                json!(["notify", {"set_tweak": "highlight"}]),
+            ),
+            default_rule(
+                ".m.rule.roomnotif",
+                vec![
+                    event_match("content.body", "@room"),
+                    Condition::SenderNotificationPermission {
+                        key: "room".to_owned(),
+                    },
+                ],
+                json!(["notify", {"set_tweak": "highlight"}]),
+            ),
+        ],
+        content: vec![PushRule {
+            rule_id: ".m.rule.contains_user_name".to_owned(),
+            default: true,
+            enabled: true,
+            actions: serde_json::from_value(notify_highlight).unwrap_or_default(),
+            conditions: None,
+            pattern: Some(UserId::parse(user_id).local_part),
+        }],
+        room: vec![],
+        sender: vec![],
+        underride: vec![
+            default_rule(
+                ".m.rule.call",
+                vec![event_match("type", "m.call.invite")],
+                json!([
+                    "notify",
+                    {"set_tweak": "sound", "value": "ring"},
+                    {"set_tweak": "highlight", "value": false}
+                ]),
+            ),
+            default_rule(
+                ".m.rule.encrypted_room_one_to_one",
+                vec![member_count("2"), event_match("type", "m.room.encrypted")],
+                notify_sound_no_highlight.clone(),
+            ),
+            default_rule(
+                ".m.rule.room_one_to_one",
+                vec![member_count("2"), event_match("type", "m.room.message")],
+                notify_sound_no_highlight,
+            ),
+            default_rule(
+                ".m.rule.message",
+                vec![event_match("type", "m.room.message")],
+                notify_no_highlight.clone(),
+            ),
+            default_rule(
+                ".m.rule.encrypted",
+                vec![event_match("type", "m.room.encrypted")],
+                notify_no_highlight,
+            ),
+        ],
+    }
+}
+
+/// A user's rules as evaluated: the server's defaults, with the user's
+/// changes to them, interleaved with the user's own rules.
+///
+/// The user's rules come before the defaults of the same kind, except that
+/// `.m.rule.master` is always evaluated first.
+pub fn effective(user_id: &str, user_rules: &UserPushRules) -> Ruleset {
+    let mut defaults = server_default(user_id);
+    for kind in RULE_KINDS.iter() {
+        for rule in defaults.kind_mut(kind).into_iter().flatten() {
+            if let Some(change) = user_rules.defaults.get(&rule.rule_id) {
+                if let Some(enabled) = change.enabled {
+                    rule.enabled = enabled;
+                }
+                if let Some(actions) = &change.actions {
+                    rule.actions = actions.clone();
+                }
+            }
+        }
+    }
+
+    let own = &user_rules.rules;
+    let master = defaults.override_.remove(0);
+    let mut ruleset = Ruleset {
+        override_: vec![master],
+        content: own.content.clone(),
+        room: own.room.clone(),
+        sender: own.sender.clone(),
+        underride: own.underride.clone(),
+    };
+    ruleset.override_.extend(own.override_.iter().cloned());
+    ruleset.override_.append(&mut defaults.override_);
+    ruleset.content.append(&mut defaults.content);
+    ruleset.underride.append(&mut defaults.underride);
+    ruleset
+}
+
+/// Evaluates the rules against an event, giving the actions of the first
+/// enabled rule that matches. No rule matching means no actions.
+pub fn actions<'r>(ruleset: &'r Ruleset, event: &Value, ctx: &Context) -> &'r [Value] {
+    RULE_KINDS
+        .iter()
+        .flat_map(|kind| {
+            ruleset
+                .kind(kind)
+                .into_iter()
+                .flatten()
+                .map(move |rule| (*kind, rule))
+        })
+        .find(|(kind, rule)| rule.enabled && rule_matches(kind, rule, event, ctx))
+        .map_or(&[][..], |(_, rule)| rule.actions.as_slice())
+}
+
+fn rule_matches(kind: &str, rule: &PushRule, event: &Value, ctx: &Context) -> bool {
+    match kind {
+        "override" | "underride" => rule
+            .conditions
+            .iter()
+            .flatten()
+            .all(|condition| condition_matches(condition, event, ctx)),
+        "content" => match (&rule.pattern, lookup(event, "content.body")) {
+            (Some(pattern), Some(body)) => {
+                glob_regex(pattern, true).map_or(false, |re| re.is_match(body))
+            }
+            _ => false,
+        },
+        "room" => lookup(event, "room_id") == Some(rule.rule_id.as_str()),
+        "sender" => lookup(event, "sender") == Some(rule.rule_id.as_str()),
+        _ => false,
+    }
+}
+
+fn condition_matches(condition: &Condition, event: &Value, ctx: &Context) -> bool {
+    match condition {
+        Condition::EventMatch { key, pattern } => match lookup(event, key) {
+            // The body is searched for the pattern as a word; anything else
+            // must match it as a whole
+            Some(value) => {
+                glob_regex(pattern, key == "content.body").map_or(false, |re| re.is_match(value))
+            }
+            None => false,
+        },
+        Condition::ContainsDisplayName => match (ctx.display_name, lookup(event, "content.body")) {
+            (Some(name), Some(body)) if !name.is_empty() => {
+                word_regex(&regex::escape(name)).map_or(false, |re| re.is_match(body))
+            }
+            _ => false,
+        },
+        Condition::RoomMemberCount { is } => member_count_matches(is, ctx.member_count),
+        Condition::SenderNotificationPermission { key } => {
+            let required = ctx.notification_power_levels.get(key).copied().or_else(|| {
+                if key == "room" {
+                    Some(DEFAULT_ROOM_NOTIFICATION_LEVEL)
+                } else {
+                    None
+                }
+            });
+            required.map_or(false, |required| ctx.sender_power_level >= required)
+        }
+        Condition::Unknown => false,
+    }
+}
+
+/// Looks up a string field of an event by its dotted path, e.g.
+/// `content.body`.
+fn lookup<'e>(event: &'e Value, key: &str) -> Option<&'e str> {
+    key.split('.')
+        .try_fold(event, |value, field| value.get(field))?
+        .as_str()
+}
+
+/// Compiles a push rule glob, where `*` matches any number of characters
+/// and `?` any one, into a case-insensitive regex matching either the whole
+/// value or any word in it.
+fn glob_regex(glob: &str, as_word: bool) -> Option<Regex> {
+    let mut pattern = String::new();
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*?"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    if as_word {
+        word_regex(&pattern)
+    } else {
+        Regex::new(&format!("(?is)^{}$", pattern)).ok()
+    }
+}
+
+fn word_regex(pattern: &str) -> Option<Regex> {
+    Regex::new(&format!(r"(?is)(^|\W){}(\W|$)", pattern)).ok()
+}
+
+/// Whether a member count satisfies a `room_member_count` condition such
+/// as `2`, `==2`, `<10` or `>=3`.
+fn member_count_matches(is: &str, count: u64) -> bool {
+    let is = is.trim();
+    let split = is
+        .find(|c: char| c.is_ascii_digit())
+        .unwrap_or_else(|| is.len());
+    let (op, number) = is.split_at(split);
+    let number: u64 = match number.parse() {
+        Ok(number) => number,
+        Err(_) => return false,
+    };
+    match op {
+        "" | "==" => count == number,
+        "<" => count < number,
+        ">" => count > number,
+        "<=" => count <= number,
+        ">=" => count >= number,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::push::DefaultRuleChange;
+
+    const USER_ID: &str = "@alice:example.com";
+
+    fn context(levels: &BTreeMap<String, i64>) -> Context<'_> {
+        Context {
+            display_name: Some("Alice Liddell"),
+            member_count: 5,
+            sender_power_level: 0,
+            notification_power_levels: levels,
+        }
+    }
+
+    fn message(body: &str) -> Value {
+        json!({
+            "type": "m.room.message",
+            "room_id": "!room:example.com",
+            "sender": "@bob:example.com",
+            "content": {"msgtype": "m.text", "body": body},
+        })
+    }
+
+    #[test]
+    fn test_default_rules() {
+        let levels = BTreeMap::new();
+        let ctx = context(&levels);
+        let ruleset = effective(USER_ID, &UserPushRules::default());
+
+        let plain = actions(&ruleset, &message("hello"), &ctx);
+        assert_eq!(
+            plain,
+            json!(["notify", {"set_tweak": "highlight", "value": false}])
+                .as_array()
+                .unwrap()
+                .as_slice()
+        );
+
+        let mention = actions(&ruleset, &message("hey ALICE, look"), &ctx);
+        assert!(mention.contains(&json!({"set_tweak": "highlight"})));
+        let display_name = actions(&ruleset, &message("Alice Liddell: hi"), &ctx);
+        assert!(display_name.contains(&json!({"set_tweak": "highlight"})));
+        let not_a_word = actions(&ruleset, &message("malice"), &ctx);
+        assert!(!not_a_word.contains(&json!({"set_tweak": "highlight"})));
+
+        let mut notice = message("beep");
+        notice["content"]["msgtype"] = json!("m.notice");
+        assert_eq!(actions(&ruleset, &notice, &ctx), [json!("dont_notify")]);
+
+        // @room needs the power to notify the room
+        assert!(!actions(&ruleset, &message("@room"), &ctx)
+            .contains(&json!({"set_tweak": "highlight"})));
+        let ctx = Context {
+            sender_power_level: 50,
+            ..context(&levels)
+        };
+        assert!(
+            actions(&ruleset, &message("@room"), &ctx).contains(&json!({"set_tweak": "highlight"}))
+        );
+    }
+
+    #[test]
+    fn test_one_to_one_rooms() {
+        let levels = BTreeMap::new();
+        let ctx = Context {
+            member_count: 2,
+            ..context(&levels)
+        };
+        let ruleset = effective(USER_ID, &UserPushRules::default());
+        let tweaks = actions(&ruleset, &message("hello"), &ctx);
+        assert!(tweaks.contains(&json!({"set_tweak": "sound", "value": "default"})));
+    }
+
+    #[test]
+    fn test_user_rules() {
+        let levels = BTreeMap::new();
+        let ctx = context(&levels);
+        let mut user_rules = UserPushRules::default();
+        user_rules.rules.sender.push(PushRule {
+            rule_id: "@bob:example.com".to_owned(),
+            default: false,
+            enabled: true,
+            actions: vec![json!("dont_notify")],
+            conditions: None,
+            pattern: None,
+        });
+        let ruleset = effective(USER_ID, &user_rules);
+        assert_eq!(
+            actions(&ruleset, &message("hello"), &ctx),
+            [json!("dont_notify")]
+        );
+        // Mentions are override and content rules, which come first
+        assert_ne!(
+            actions(&ruleset, &message("alice"), &ctx),
+            [json!("dont_notify")]
+        );
+
+        user_rules.defaults.insert(
+            ".m.rule.master".to_owned(),
+            DefaultRuleChange {
+                enabled: Some(true),
+                actions: None,
+            },
+        );
+        let ruleset = effective(USER_ID, &user_rules);
+        assert_eq!(ruleset.override_[0].rule_id, ".m.rule.master");
+        assert_eq!(
+            actions(&ruleset, &message("alice"), &ctx),
+            [json!("dont_notify")]
+        );
+    }
+
+    #[test]
+    fn test_glob_regex() {
+        let re = glob_regex("cake*lie", false).unwrap();
+        assert!(re.is_match("cakeisalie"));
+        assert!(!re.is_match("the cakeisalie"));
+        let re = glob_regex("m.room.*", false).unwrap();
+        assert!(re.is_match("m.room.message"));
+        let re = glob_regex("b?b", true).unwrap();
+        assert!(re.is_match("hi Bob!"));
+        assert!(!re.is_match("bobby"));
+    }
+
+    #[test]
+    fn test_member_count_matches() {
+        assert!(member_count_matches("2", 2));
+        assert!(member_count_matches("==2", 2));
+        assert!(member_count_matches("<10", 9));
+        assert!(!member_count_matches(">10", 10));
+        assert!(member_count_matches(">=10", 10));
+        assert!(!member_count_matches("~2", 2));
+        assert!(!member_count_matches("lots", 2));
+    }
+}
@@ -0,0 +1,202 @@
+//! Summaries of rooms' members: how many have joined or are invited, and
+//! the few members, the heroes, clients name a room after when it has no
+//! name of its own.
+//!
+//! Counting a room's members on every sync would read its whole member
+//! list, so each room's summary is stored, and changed by every membership
+//! change as it happens. Only when a hero leaves and no other known member
+//! can take their place are the room's earliest members read again.
+//!
+//! Summaries are kept in a size-bounded cache, and invalidated over the
+//! replication bus when they change.
+//!
+//! So far the only memberships recorded are local users' invites from other
+//! servers, and their removal.
+//!
+//! TODO: Change the summaries as membership events are persisted, once
+//! rooms and events are stored.
+use std::error::Error;
+use std::sync::Mutex;
+
+use futures::StreamExt;
+
+use crate::{
+    bus::{Message, BUS},
+    cache::Lru,
+    db::Store,
+    models::room::{self as model, RoomSummary},
+};
+
+/// The most heroes a client is given for a room.
+pub const MAX_HEROES: usize = 5;
+/// How many members are stored as heroes: one more than a client is given,
+/// so leaving the client's own user out still leaves enough.
+const STORED_HEROES: usize = MAX_HEROES + 1;
+/// The name summaries are invalidated under on the replication bus.
+const ROOM_SUMMARIES: &str = "room_summaries";
+/// How many bytes of summaries are cached.
+const SUMMARY_CACHE_SIZE: usize = 4 * 1024 * 1024;
+
+lazy_static::lazy_static! {
+    static ref SUMMARIES: Mutex<Lru<String, RoomSummary>> = Mutex::new(Lru::new(
+        SUMMARY_CACHE_SIZE,
+        |room_id, summary| summary_size(room_id, summary),
+    ));
+}
+
+/// Roughly how many bytes a cached summary takes.
+fn summary_size(room_id: &str, summary: &RoomSummary) -> usize {
+    room_id.len() + 16 + summary.heroes.iter().map(String::len).sum::<usize>()
+}
+
+/// Changes a summary for `user_id`'s membership changing from `old` to
+/// `new`. Returns whether the heroes need to be read again, because a hero
+/// left and there may be members who aren't heroes yet.
+fn apply(summary: &mut RoomSummary, user_id: &str, old: Option<&str>, new: Option<&str>) -> bool {
+    let count = |summary: &mut RoomSummary, membership: Option<&str>, by: i64| match membership {
+        Some(model::JOIN) => summary.joined_member_count += by,
+        Some(model::INVITE) => summary.invited_member_count += by,
+        _ => {}
+    };
+    count(summary, old, -1);
+    count(summary, new, 1);
+
+    let is_hero = summary.heroes.iter().any(|hero| hero == user_id);
+    match (model::is_member(new), is_hero) {
+        (true, false) if summary.heroes.len() < STORED_HEROES => {
+            summary.heroes.push(user_id.to_owned());
+            false
+        }
+        (false, true) => {
+            summary.heroes.retain(|hero| hero != user_id);
+            let members = summary.joined_member_count + summary.invited_member_count;
+            members > summary.heroes.len() as i64
+        }
+        _ => false,
+    }
+}
+
+/// Gets a room's summary, if any member's membership is known.
+pub async fn summary<T: Store>(
+    storage: &T,
+    room_id: &str,
+) -> Result<Option<RoomSummary>, Box<dyn Error>> {
+    if let Some(summary) = SUMMARIES.lock().unwrap().get(room_id) {
+        return Ok(Some(summary));
+    }
+    let summary = storage.get_room_summary(room_id).await?;
+    if let Some(summary) = &summary {
+        SUMMARIES
+            .lock()
+            .unwrap()
+            .insert(room_id.to_owned(), summary.clone());
+    }
+    Ok(summary)
+}
+
+/// Records a user's new membership of a room, or that they are no longer in
+/// it, and changes the room's summary to match, telling every process
+/// sharing the database.
+///
+/// Membership changes to a room must be recorded one at a time, in the
+/// order the room's events are persisted.
+pub async fn set_membership<T: Store>(
+    storage: &T,
+    room_id: &str,
+    user_id: &str,
+    membership: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let old = storage
+        .set_room_membership(room_id, user_id, membership)
+        .await?;
+    if old.as_deref() == membership {
+        return Ok(());
+    }
+    let mut summary = summary(storage, room_id).await?.unwrap_or_default();
+    if apply(&mut summary, user_id, old.as_deref(), membership) {
+        summary.heroes = storage.get_room_heroes(room_id, STORED_HEROES).await?;
+    }
+    storage.set_room_summary(room_id, &summary).await?;
+
+    SUMMARIES
+        .lock()
+        .unwrap()
+        .insert(room_id.to_owned(), summary);
+    BUS.publish(Message::Invalidate {
+        cache: ROOM_SUMMARIES.to_owned(),
+        key: room_id.to_owned(),
+    });
+    Ok(())
+}
+
+/// Forgets the summaries other processes say are stale, for as long as the
+/// process runs.
+pub async fn follow_invalidations() {
+    let mut messages = BUS.subscribe();
+    while let Some(message) = messages.next().await {
+        if let Message::Invalidate { cache, key } = message {
+            if cache == ROOM_SUMMARIES {
+                SUMMARIES.lock().unwrap().remove(key.as_str());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heroes(summary: &RoomSummary) -> Vec<&str> {
+        summary.heroes.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn test_apply_counts() {
+        let mut summary = RoomSummary::default();
+        let join = Some(model::JOIN);
+        let invite = Some(model::INVITE);
+        assert!(!apply(&mut summary, "@alice:example.com", None, join));
+        assert!(!apply(&mut summary, "@bob:example.com", None, invite));
+        assert_eq!(
+            (summary.joined_member_count, summary.invited_member_count),
+            (1, 1)
+        );
+        assert!(!apply(&mut summary, "@bob:example.com", invite, join));
+        assert_eq!(
+            (summary.joined_member_count, summary.invited_member_count),
+            (2, 0)
+        );
+        assert_eq!(heroes(&summary), ["@alice:example.com", "@bob:example.com"]);
+
+        // Leaving, or being banned, takes a member out of the counts and the
+        // heroes, with nobody else to take their place
+        assert!(!apply(
+            &mut summary,
+            "@alice:example.com",
+            join,
+            Some("ban")
+        ));
+        assert!(!apply(&mut summary, "@bob:example.com", join, None));
+        assert_eq!(summary, RoomSummary::default());
+    }
+
+    #[test]
+    fn test_apply_heroes() {
+        let mut summary = RoomSummary::default();
+        for i in 0..8 {
+            let user_id = format!("@user{}:example.com", i);
+            assert!(!apply(&mut summary, &user_id, None, Some(model::JOIN)));
+        }
+        assert_eq!(summary.heroes.len(), STORED_HEROES);
+        assert_eq!(summary.joined_member_count, 8);
+
+        // Members who aren't heroes come and go without reading them again
+        let last = "@user7:example.com";
+        assert!(!apply(&mut summary, last, Some(model::JOIN), None));
+        // A hero leaving does, while there are members who could replace them
+        let first = "@user0:example.com";
+        assert!(apply(&mut summary, first, Some(model::JOIN), Some("leave")));
+        assert_eq!(summary.heroes.len(), STORED_HEROES - 1);
+        assert!(!summary.heroes.iter().any(|hero| hero == first));
+    }
+}
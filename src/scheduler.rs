@@ -0,0 +1,311 @@
+//! Runs background jobs, either every so often or once at a set time.
+//!
+//! Jobs are kept in the `Store`, so they survive restarts and are shared by
+//! every process. Each process runs the kinds of jobs it has a task for, and
+//! claims a due job before running it so that no two processes run it at
+//! once. A claimed job is due again after `LEASE`, so a job left running by
+//! a process that stopped is picked up again. Every task must therefore be
+//! safe to run again.
+//!
+//! A failed job is retried after a delay that doubles with each failure. A
+//! job run once gives up after `MAX_ATTEMPTS`, while a periodic job is
+//! retried at least as often as it normally runs. Runs are spread with a
+//! little jitter, so processes started together don't all do their periodic
+//! work at the same moment.
+//!
+//! TODO: Remind admins to rotate the server's signing key, once keys can
+//! expire. Until then a server keeps its key for good.
+use std::{collections::HashMap, error::Error, future::Future, rc::Rc, time::Duration};
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use rand::Rng;
+use serde_json::Value;
+
+use crate::{
+    clock::now_ms,
+    db::Store,
+    models::admin::{JobStatus, ScheduledJob},
+};
+
+/// Purges the media the retention policies say should go.
+pub const MEDIA_RETENTION: &str = "media_retention";
+/// Marks users whose presence hasn't been heard of for a while as offline.
+pub const PRESENCE_TIMEOUTS: &str = "presence_timeouts";
+/// Redacts the events of a user whose data was erased.
+pub const REDACT_USER_EVENTS: &str = "redact_user_events";
+/// Forwards a report of an event to the server it came from.
+pub const FORWARD_REPORT: &str = "forward_report";
+/// Delivers a notification of a security relevant action to a webhook.
+pub const DELIVER_WEBHOOK: &str = "deliver_webhook";
+/// Reports the server's aggregates to the configured stats endpoint.
+pub const REPORT_STATS: &str = "report_stats";
+/// Deletes the jobs run once that finished a while ago.
+pub const JOB_CLEANUP: &str = "job_cleanup";
+/// Forgets the transactions and PDUs received from other servers before the
+/// replay window.
+pub const RECEIVED_CLEANUP: &str = "received_cleanup";
+
+/// How often due jobs are looked for.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// The most jobs claimed at once.
+const BATCH_SIZE: i64 = 10;
+/// How long a process has to finish a job it claimed before another may run
+/// it, in milliseconds.
+const LEASE: i64 = 60 * 60 * 1000;
+/// How long the first retry of a failed job waits, in milliseconds. Each
+/// further failure doubles it.
+const RETRY_DELAY: i64 = 30 * 1000;
+/// The longest a failed job waits to be retried, in milliseconds.
+const MAX_RETRY_DELAY: i64 = 60 * 60 * 1000;
+/// How many times a job run once is tried before it is marked failed.
+const MAX_ATTEMPTS: i32 = 5;
+/// How long finished jobs run once are kept for admins to look at, in
+/// milliseconds.
+const KEEP_FINISHED: i64 = 7 * 24 * 60 * 60 * 1000;
+
+type Task = Rc<dyn Fn(Value) -> LocalBoxFuture<'static, Result<(), Box<dyn Error>>>>;
+
+/// The tasks this process runs jobs with, and the periodic jobs it
+/// schedules.
+pub struct Scheduler<T> {
+    storage: T,
+    tasks: HashMap<String, Task>,
+    periodic: Vec<(String, Duration)>,
+}
+
+impl<T: Store + 'static> Scheduler<T> {
+    pub fn new(storage: T) -> Self {
+        Scheduler {
+            storage,
+            tasks: HashMap::new(),
+            periodic: Vec::new(),
+        }
+    }
+
+    /// Runs jobs of `kind` with `task`, given the payload they were
+    /// scheduled with.
+    pub fn register<F, Fut>(&mut self, kind: &str, task: F) -> &mut Self
+    where
+        F: Fn(Value) -> Fut + 'static,
+        Fut: Future<Output = Result<(), Box<dyn Error>>> + 'static,
+    {
+        self.tasks.insert(
+            kind.to_owned(),
+            Rc::new(move |payload| task(payload).boxed_local()),
+        );
+        self
+    }
+
+    /// Runs jobs of `kind` with `task`, and schedules one to run every
+    /// `period`.
+    pub fn every<F, Fut>(&mut self, kind: &str, period: Duration, task: F) -> &mut Self
+    where
+        F: Fn(Value) -> Fut + 'static,
+        Fut: Future<Output = Result<(), Box<dyn Error>>> + 'static,
+    {
+        self.periodic.push((kind.to_owned(), period));
+        self.register(kind, task)
+    }
+
+    /// Starts running jobs on the current thread, if there are any this
+    /// process has tasks for.
+    pub fn start(self) {
+        if !self.tasks.is_empty() {
+            actix_rt::spawn(self.run());
+        }
+    }
+
+    async fn run(self) {
+        for (kind, period) in &self.periodic {
+            let interval_ms = period.as_millis() as i64;
+            // The first run is soon, but spread like the rest
+            let first_run_ts = now_ms() + rand::thread_rng().gen_range(0, interval_ms / 10 + 1);
+            if let Err(e) = self
+                .storage
+                .add_periodic_job(kind, interval_ms, first_run_ts)
+                .await
+            {
+                tracing::error!(%kind, error = %e, "Unable to schedule periodic job");
+            }
+        }
+
+        let kinds: Vec<String> = self.tasks.keys().cloned().collect();
+        let mut interval = actix_rt::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = now_ms();
+            let jobs = match self
+                .storage
+                .claim_scheduled_jobs(&kinds, now, now + LEASE, BATCH_SIZE)
+                .await
+            {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    tracing::error!(error = %e, "Unable to claim scheduled jobs");
+                    continue;
+                }
+            };
+            for job in jobs {
+                if let Some(task) = self.tasks.get(&job.kind) {
+                    actix_rt::spawn(run_job(self.storage.clone(), task.clone(), job));
+                }
+            }
+        }
+    }
+}
+
+/// Schedules a job of `kind` to run once, after `delay`, returning its ID.
+/// It is run by whichever process has a task for it.
+pub async fn schedule<T: Store>(
+    storage: &T,
+    kind: &str,
+    payload: &Value,
+    delay: Duration,
+) -> Result<i64, Box<dyn Error>> {
+    let run_ts = now_ms() + delay.as_millis() as i64;
+    storage.add_scheduled_job(kind, payload, run_ts).await
+}
+
+/// Deletes the jobs run once that finished more than `KEEP_FINISHED` ago.
+pub async fn clean_up<T: Store>(storage: &T) -> Result<(), Box<dyn Error>> {
+    let deleted = storage
+        .delete_finished_scheduled_jobs(now_ms() - KEEP_FINISHED)
+        .await?;
+    if deleted > 0 {
+        tracing::info!(deleted, "Deleted finished jobs");
+    }
+    Ok(())
+}
+
+/// Runs a claimed job, recording whether it succeeded and when it is next
+/// due.
+async fn run_job<T: Store>(storage: T, task: Task, job: ScheduledJob) {
+    let error = task(job.payload.clone()).await.err().map(|e| e.to_string());
+    match &error {
+        None => tracing::debug!(job_id = job.job_id, kind = %job.kind, "Job complete"),
+        Some(e) => tracing::warn!(job_id = job.job_id, kind = %job.kind, error = %e, "Job failed"),
+    }
+
+    let (status, next_run_ts, failures) =
+        next_run(&job, error.is_some(), now_ms(), &mut rand::thread_rng());
+    if let Err(e) = storage
+        .finish_scheduled_job(job.job_id, status, next_run_ts, failures, error.as_deref())
+        .await
+    {
+        tracing::error!(job_id = job.job_id, error = %e, "Unable to record the outcome of a job");
+    }
+}
+
+/// What becomes of a job after a run, failed or not: its status, when it is
+/// next due, and how many times in a row it has failed. A job run once that
+/// is finished is left "due" when it finished.
+fn next_run<R: Rng>(
+    job: &ScheduledJob,
+    failed: bool,
+    now: i64,
+    rng: &mut R,
+) -> (JobStatus, i64, i32) {
+    match (job.interval_ms, failed) {
+        (Some(interval), false) => (JobStatus::Pending, now + jittered(interval, rng), 0),
+        (None, false) => (JobStatus::Complete, now, 0),
+        (interval, true) => {
+            let failures = job.failures + 1;
+            let delay = retry_delay(failures);
+            match interval {
+                Some(interval) => (
+                    JobStatus::Pending,
+                    now + jittered(delay.min(interval), rng),
+                    failures,
+                ),
+                None if failures >= MAX_ATTEMPTS => (JobStatus::Failed, now, failures),
+                None => (JobStatus::Pending, now + jittered(delay, rng), failures),
+            }
+        }
+    }
+}
+
+/// How long a job waits to be retried after failing `failures` times in a
+/// row, in milliseconds.
+fn retry_delay(failures: i32) -> i64 {
+    let doublings = (failures - 1).max(0).min(20) as u32;
+    (RETRY_DELAY << doublings).min(MAX_RETRY_DELAY)
+}
+
+/// Moves a delay by up to a tenth either way.
+fn jittered<R: Rng>(delay: i64, rng: &mut R) -> i64 {
+    let spread = delay / 10;
+    delay + rng.gen_range(-spread, spread + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use serde_json::json;
+
+    fn job(interval_ms: Option<i64>, failures: i32) -> ScheduledJob {
+        ScheduledJob {
+            job_id: 1,
+            kind: MEDIA_RETENTION.to_owned(),
+            payload: json!(null),
+            interval_ms,
+            status: JobStatus::Running,
+            next_run_ts: 0,
+            last_run_ts: Some(0),
+            failures,
+            last_error: None,
+        }
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), RETRY_DELAY);
+        assert_eq!(retry_delay(2), 2 * RETRY_DELAY);
+        assert_eq!(retry_delay(3), 4 * RETRY_DELAY);
+        assert_eq!(retry_delay(100), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_jittered() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let delay = jittered(1000, &mut rng);
+            assert!((900..=1100).contains(&delay));
+        }
+        assert_eq!(jittered(5, &mut rng), 5);
+    }
+
+    #[test]
+    fn test_next_run_periodic() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let hour = 60 * 60 * 1000;
+        let (status, next, failures) = next_run(&job(Some(hour), 2), false, 0, &mut rng);
+        assert_eq!((status, failures), (JobStatus::Pending, 0));
+        assert!((hour - hour / 10..=hour + hour / 10).contains(&next));
+
+        // Retried sooner than the next run, but never later
+        let (status, next, failures) = next_run(&job(Some(hour), 0), true, 0, &mut rng);
+        assert_eq!((status, failures), (JobStatus::Pending, 1));
+        assert!(next <= RETRY_DELAY + RETRY_DELAY / 10);
+        let (_, next, _) = next_run(&job(Some(1000), 10), true, 0, &mut rng);
+        assert!(next <= 1100);
+    }
+
+    #[test]
+    fn test_next_run_once() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(
+            next_run(&job(None, 1), false, 42, &mut rng),
+            (JobStatus::Complete, 42, 0)
+        );
+
+        let (status, next, failures) = next_run(&job(None, 1), true, 0, &mut rng);
+        assert_eq!((status, failures), (JobStatus::Pending, 2));
+        assert!(next >= retry_delay(2) * 9 / 10);
+
+        assert_eq!(
+            next_run(&job(None, MAX_ATTEMPTS - 1), true, 42, &mut rng),
+            (JobStatus::Failed, 42, MAX_ATTEMPTS)
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! Keeping networks out of the client and federation APIs.
+//!
+//! Networks are denied by configuration, with `CLIENT_IP_DENYLIST` and
+//! `FEDERATION_IP_DENYLIST`, or blocked while the server runs: by admins
+//! through the admin API, and for a while after too many failed logins from
+//! one address. Requests from a blocked network are refused with
+//! `M_FORBIDDEN` before they are rate limited or handled, once the client
+//! behind any trusted proxies is known.
+//!
+//! Blocks made while running are stored, and every process keeps the ones
+//! that haven't expired in memory: it loads them when it starts, and reloads
+//! them when another process says over the replication bus that they
+//! changed. Failed logins are counted by each process on its own.
+//!
+//! Networks can't be denied by country or ASN, as no GeoIP database is
+//! read; see "Deferred Features" in the README.
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use actix_web::http::StatusCode;
+use futures::StreamExt;
+use serde_json::json;
+
+use crate::{
+    audit,
+    bus::{Message, BUS},
+    clock::now_ms,
+    db::Store,
+    ipnet::IpNet,
+    models::access::{Api, IpBlock},
+    server::{
+        error::{ErrorCode, MatrixError},
+        limits::Category,
+        ratelimit,
+    },
+    CONFIG,
+};
+
+/// The name blocks are invalidated under on the replication bus.
+const IP_BLOCKS: &str = "ip_blocks";
+/// How many addresses failed logins are counted for before those whose
+/// window has passed are forgotten.
+const MAX_TRACKED: usize = 10_000;
+
+lazy_static::lazy_static! {
+    static ref BLOCKS: RwLock<Vec<Block>> = RwLock::new(Vec::new());
+    static ref FAILURES: Mutex<HashMap<String, Failures>> = Mutex::new(HashMap::new());
+}
+
+/// Which networks are kept out, and when an address is blocked for failing
+/// to log in.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// The networks kept out of the client API
+    pub client_denylist: Vec<IpNet>,
+    /// The networks kept out of the federation API
+    pub federation_denylist: Vec<IpNet>,
+    /// When an address is blocked for failed logins, if it ever is
+    pub login_failures: Option<LoginFailures>,
+}
+
+impl Settings {
+    /// Reads the settings from `env` vars. Panics if any can't be parsed.
+    pub fn from_env() -> Self {
+        let denylist = |var: &str| {
+            IpNet::parse_list(&std::env::var(var).unwrap_or_default())
+                .unwrap_or_else(|e| panic!("Unable to parse {}: {}", var, e))
+        };
+        let seconds = |var: &str, default: u64| {
+            std::env::var(var)
+                .map(|seconds| {
+                    seconds
+                        .parse()
+                        .unwrap_or_else(|_| panic!("Unable to parse {} as u64.", var))
+                })
+                .map(Duration::from_secs)
+                .unwrap_or_else(|_| Duration::from_secs(default))
+        };
+        let limit: u32 = std::env::var("LOGIN_FAILURE_LIMIT")
+            .map(|limit| {
+                limit
+                    .parse()
+                    .expect("Unable to parse LOGIN_FAILURE_LIMIT as u32.")
+            })
+            .unwrap_or(10);
+        Settings {
+            client_denylist: denylist("CLIENT_IP_DENYLIST"),
+            federation_denylist: denylist("FEDERATION_IP_DENYLIST"),
+            login_failures: Some(LoginFailures {
+                limit,
+                window: seconds("LOGIN_FAILURE_WINDOW", 10 * 60),
+                block: seconds("LOGIN_FAILURE_BLOCK", 60 * 60),
+            })
+            .filter(|failures| failures.limit > 0),
+        }
+    }
+
+    fn denylist(&self, api: Api) -> &[IpNet] {
+        match api {
+            Api::Client => &self.client_denylist,
+            Api::Federation => &self.federation_denylist,
+        }
+    }
+}
+
+/// How many failed logins get an address blocked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoginFailures {
+    /// How many failed logins from one address get it blocked
+    pub limit: u32,
+    /// How close together the failed logins have to be
+    pub window: Duration,
+    /// How long the address is blocked for
+    pub block: Duration,
+}
+
+/// A block, as kept in memory.
+#[derive(Clone, Copy, Debug)]
+struct Block {
+    api: Api,
+    net: IpNet,
+    expires_ts: Option<i64>,
+}
+
+/// The failed logins from an address in its current window.
+#[derive(Clone, Copy, Debug)]
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+/// The API a request for `path` is to.
+pub fn api_of(path: &str) -> Api {
+    match Category::of(path) {
+        Category::Federation => Api::Federation,
+        _ => Api::Client,
+    }
+}
+
+/// Whether `ip` is kept out of `api`.
+pub fn is_blocked(api: Api, ip: IpAddr) -> bool {
+    if CONFIG
+        .access
+        .denylist(api)
+        .iter()
+        .any(|net| net.contains(&ip))
+    {
+        return true;
+    }
+    let now = now_ms();
+    BLOCKS.read().unwrap().iter().any(|block| {
+        block.api == api
+            && block.expires_ts.map_or(true, |expires_ts| expires_ts > now)
+            && block.net.contains(&ip)
+    })
+}
+
+/// The error requests from blocked networks are refused with.
+pub fn blocked() -> MatrixError {
+    MatrixError::new(
+        StatusCode::FORBIDDEN,
+        ErrorCode::FORBIDDEN,
+        "Your network is blocked from this server.",
+    )
+}
+
+/// Loads the blocks that haven't expired yet.
+pub async fn load<T: Store>(storage: &T) -> Result<(), Box<dyn Error>> {
+    let blocks = storage
+        .get_ip_blocks(now_ms())
+        .await?
+        .into_iter()
+        .filter_map(|block| {
+            let net = block.net.parse().ok()?;
+            Some(Block {
+                api: block.api,
+                net,
+                expires_ts: block.expires_ts,
+            })
+        })
+        .collect();
+    *BLOCKS.write().unwrap() = blocks;
+    Ok(())
+}
+
+/// Blocks a network, telling every process sharing the database. Returns
+/// the block's ID.
+pub async fn add<T: Store>(storage: &T, block: &IpBlock) -> Result<i64, Box<dyn Error>> {
+    let block_id = storage.add_ip_block(block).await?;
+    changed(storage).await?;
+    Ok(block_id)
+}
+
+/// Lifts a block, telling every process sharing the database. Returns
+/// whether there was one.
+pub async fn remove<T: Store>(storage: &T, block_id: i64) -> Result<bool, Box<dyn Error>> {
+    let removed = storage.delete_ip_block(block_id).await?;
+    if removed {
+        changed(storage).await?;
+    }
+    Ok(removed)
+}
+
+async fn changed<T: Store>(storage: &T) -> Result<(), Box<dyn Error>> {
+    load(storage).await?;
+    BUS.publish(Message::Invalidate {
+        cache: IP_BLOCKS.to_owned(),
+        key: String::new(),
+    });
+    Ok(())
+}
+
+/// Counts a failed login from `key` at `now`. Returns whether it was one too
+/// many, in which case the count starts over.
+fn count_failure(
+    failures: &mut HashMap<String, Failures>,
+    settings: &LoginFailures,
+    key: &str,
+    now: Instant,
+) -> bool {
+    if failures.len() >= MAX_TRACKED {
+        failures.retain(|_, f| now.saturating_duration_since(f.since) < settings.window);
+    }
+    let entry = failures.entry(key.to_owned()).or_insert(Failures {
+        count: 0,
+        since: now,
+    });
+    if now.saturating_duration_since(entry.since) >= settings.window {
+        *entry = Failures {
+            count: 0,
+            since: now,
+        };
+    }
+    entry.count += 1;
+    if entry.count >= settings.limit {
+        failures.remove(key);
+        true
+    } else {
+        false
+    }
+}
+
+/// Counts a failed login from `ip`, and blocks it from the client API for a
+/// while if it has failed too often. IPv6 clients are blocked along with
+/// the rest of their /64, as they are rate limited.
+pub async fn record_failed_login<T: Store>(storage: &T, ip: IpAddr) -> Result<(), Box<dyn Error>> {
+    let settings = match &CONFIG.access.login_failures {
+        Some(settings) => settings,
+        None => return Ok(()),
+    };
+    let key = ratelimit::ip_key(ip);
+    let exceeded = count_failure(
+        &mut FAILURES.lock().unwrap(),
+        settings,
+        &key,
+        Instant::now(),
+    );
+    if !exceeded {
+        return Ok(());
+    }
+
+    let now = now_ms();
+    let block = IpBlock {
+        block_id: 0,
+        net: key,
+        api: Api::Client,
+        reason: Some("Too many failed logins".to_owned()),
+        created_ts: now,
+        expires_ts: Some(now + settings.block.as_millis() as i64),
+    };
+    let block_id = add(storage, &block).await?;
+    tracing::warn!(net = %block.net, "Blocked for too many failed logins");
+    audit::record(
+        storage,
+        None,
+        Some(ip),
+        "access.ip_blocked",
+        &json!({ "block_id": block_id, "net": block.net, "expires_ts": block.expires_ts }),
+    )
+    .await
+}
+
+/// Reloads the blocks when other processes say they changed, for as long as
+/// the process runs.
+pub async fn follow_invalidations<T: Store>(storage: T) {
+    let mut messages = BUS.subscribe();
+    while let Some(message) = messages.next().await {
+        if let Message::Invalidate { cache, .. } = message {
+            if cache != IP_BLOCKS {
+                continue;
+            }
+            if let Err(e) = load(&storage).await {
+                tracing::error!(error = %e, "Unable to reload IP blocks");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: LoginFailures = LoginFailures {
+        limit: 3,
+        window: Duration::from_secs(60),
+        block: Duration::from_secs(3600),
+    };
+
+    #[test]
+    fn test_api_of() {
+        assert_eq!(api_of("/_matrix/client/r0/login"), Api::Client);
+        assert_eq!(api_of("/_matrix/media/r0/upload"), Api::Client);
+        assert_eq!(api_of("/_maelstrom/admin/v1/ip_blocks"), Api::Client);
+        assert_eq!(api_of("/_matrix/federation/v1/send/1"), Api::Federation);
+        assert_eq!(api_of("/_matrix/key/v2/server"), Api::Federation);
+    }
+
+    #[test]
+    fn test_count_failure() {
+        let mut failures = HashMap::new();
+        let start = Instant::now();
+        assert!(!count_failure(&mut failures, &SETTINGS, "192.0.2.1", start));
+        assert!(!count_failure(&mut failures, &SETTINGS, "192.0.2.1", start));
+        // Other addresses are counted on their own
+        assert!(!count_failure(&mut failures, &SETTINGS, "192.0.2.2", start));
+        assert!(count_failure(&mut failures, &SETTINGS, "192.0.2.1", start));
+        // Once blocked, the count starts over
+        assert!(!count_failure(&mut failures, &SETTINGS, "192.0.2.1", start));
+
+        // Failures further apart than the window never add up
+        let later = start + Duration::from_secs(61);
+        assert!(!count_failure(&mut failures, &SETTINGS, "192.0.2.2", later));
+        assert!(!count_failure(&mut failures, &SETTINGS, "192.0.2.2", later));
+        assert!(count_failure(&mut failures, &SETTINGS, "192.0.2.2", later));
+    }
+}
@@ -0,0 +1,150 @@
+//! What the admin API has in common. It may only be used by server admins,
+//! and every change made through it is recorded in the audit log.
+use std::error::Error as StdError;
+use std::time::Duration;
+
+use actix_web::{http::StatusCode, Error};
+use serde_json::{json, Value};
+
+use crate::{
+    audit,
+    db::Store,
+    models::auth::UserId,
+    room_summary, scheduler,
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+        tokens,
+    },
+};
+
+/// Requires the requester to be a server admin whose account is active.
+pub async fn require_admin<T: Store>(storage: &T, auth: &Authenticated) -> Result<(), Error> {
+    let account = storage
+        .get_account(&auth.user_id.local_part)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    match account {
+        Some(account) if account.is_admin && !account.deactivated => Ok(()),
+        _ => Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "You are not a server admin.",
+        )
+        .into()),
+    }
+}
+
+/// Records a change an admin made in the audit log. `action` is prefixed
+/// with `admin.`.
+pub async fn audit<T: Store>(
+    storage: &T,
+    auth: &Authenticated,
+    action: &str,
+    details: &Value,
+) -> Result<(), Error> {
+    audit::record(
+        storage,
+        Some(&auth.user_id.to_string()),
+        auth.ip,
+        &format!("admin.{}", action),
+        details,
+    )
+    .await
+    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    Ok(())
+}
+
+/// Parses the ID of a user on this server.
+pub fn local_user(user_id: &str) -> Result<UserId, Error> {
+    let user_id = UserId::parse(user_id);
+    if !user_id.is_local() {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_PARAM,
+            "Only local users can be managed.",
+        )
+        .into());
+    }
+    Ok(user_id)
+}
+
+/// Deactivates a local user, so they can no longer log in, logs out all of
+/// their devices, removes their pushers and rejects their pending invites. With `erase` their data is
+/// erased too, and the events they sent are redacted in the background.
+///
+/// TODO: Make the user leave the rooms they are in, and remove them from
+/// the user directory, once rooms and the directory exist. Tell the servers
+/// that invited them their invites were rejected once rooms can be left
+/// over federation.
+pub async fn deactivate<T: Store>(
+    storage: &T,
+    user_id: &UserId,
+    erase: bool,
+) -> Result<(), Box<dyn StdError>> {
+    let localpart = &user_id.local_part;
+    storage.set_account_deactivated(localpart, true).await?;
+    tokens::revoke(storage, localpart, None).await?;
+    for (pusher, _) in storage.get_pushers(localpart).await? {
+        storage
+            .delete_pusher(localpart, &pusher.app_id, &pusher.pushkey)
+            .await?;
+    }
+    for (_, invite) in storage.get_room_invites(localpart, 0, i64::MAX).await? {
+        if storage
+            .delete_room_invite(localpart, &invite.room_id)
+            .await?
+        {
+            room_summary::set_membership(storage, &invite.room_id, &user_id.to_string(), None)
+                .await?;
+        }
+    }
+    if erase {
+        storage.erase_user(localpart, &user_id.to_string()).await?;
+        scheduler::schedule(
+            storage,
+            scheduler::REDACT_USER_EVENTS,
+            &json!({ "user_id": user_id }),
+            Duration::from_secs(0),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Redacts every event an erased user sent, so what they said is gone for
+/// good. Run as a `scheduler::REDACT_USER_EVENTS` job, which `deactivate`
+/// schedules.
+///
+/// TODO: Redact the user's events a batch at a time once events are stored.
+/// Until then there is nothing to redact.
+pub async fn redact_user_events<T: Store>(
+    _storage: &T,
+    user_id: &str,
+) -> Result<(), Box<dyn StdError>> {
+    tracing::info!(%user_id, redacted = 0, "Redacted an erased user's events");
+    Ok(())
+}
+
+/// Whether a localpart only has the characters user IDs may have.
+pub fn is_valid_localpart(localpart: &str) -> bool {
+    !localpart.is_empty()
+        && localpart
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._=-/".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_localpart() {
+        assert!(is_valid_localpart("alice"));
+        assert!(is_valid_localpart("bob_2=x.y/z-"));
+        assert!(!is_valid_localpart(""));
+        assert!(!is_valid_localpart("Alice"));
+        assert!(!is_valid_localpart("alice:example.com"));
+        assert!(!is_valid_localpart("al ice"));
+    }
+}
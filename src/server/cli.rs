@@ -0,0 +1,255 @@
+//! The `maelstrom user` subcommands, which manage local users straight in
+//! the database, without the server running. They are configured like the
+//! server, and record what they change in the audit log.
+use std::error::Error;
+use std::io::BufRead;
+
+use serde_json::json;
+
+use super::{admin, uia};
+use crate::{audit, db, db::Store, models::auth::UserId, CONFIG};
+
+const USAGE: &str = "Usage:
+    maelstrom user create <localpart> [--admin] [--no-password]
+    maelstrom user set-password <localpart>
+    maelstrom user deactivate <localpart> [--erase]
+    maelstrom user list [--deactivated] [--no-guests]
+
+Passwords are read from the first line of stdin.";
+/// How many users `list` loads at once.
+const PAGE_SIZE: i64 = 1000;
+
+/// A `maelstrom user` subcommand.
+#[derive(Clone, Debug, PartialEq)]
+enum Command {
+    Create {
+        localpart: String,
+        admin: bool,
+        password: bool,
+    },
+    SetPassword {
+        localpart: String,
+    },
+    Deactivate {
+        localpart: String,
+        erase: bool,
+    },
+    List {
+        deactivated: bool,
+        guests: bool,
+    },
+}
+
+/// Parses the arguments after `maelstrom user`.
+fn parse(args: &[String]) -> Result<Command, String> {
+    let (subcommand, rest) = args.split_first().ok_or(USAGE)?;
+    let mut positional = Vec::new();
+    let mut flags = Vec::new();
+    for arg in rest {
+        if arg.starts_with("--") {
+            flags.push(arg.as_str());
+        } else {
+            positional.push(arg.as_str());
+        }
+    }
+    let allowed: &[&str] = match subcommand.as_str() {
+        "create" => &["--admin", "--no-password"],
+        "deactivate" => &["--erase"],
+        "list" => &["--deactivated", "--no-guests"],
+        _ => &[],
+    };
+    for flag in &flags {
+        if !allowed.contains(flag) {
+            return Err(format!("Unknown option {}\n\n{}", flag, USAGE));
+        }
+    }
+    let has = |flag: &str| flags.contains(&flag);
+    let localpart = || match positional.as_slice() {
+        [localpart] => Ok((*localpart).to_owned()),
+        _ => Err(USAGE.to_owned()),
+    };
+
+    match subcommand.as_str() {
+        "create" => Ok(Command::Create {
+            localpart: localpart()?,
+            admin: has("--admin"),
+            password: !has("--no-password"),
+        }),
+        "set-password" => Ok(Command::SetPassword {
+            localpart: localpart()?,
+        }),
+        "deactivate" => Ok(Command::Deactivate {
+            localpart: localpart()?,
+            erase: has("--erase"),
+        }),
+        "list" if positional.is_empty() => Ok(Command::List {
+            deactivated: has("--deactivated"),
+            guests: !has("--no-guests"),
+        }),
+        _ => Err(USAGE.to_owned()),
+    }
+}
+
+/// Reads a password from the first line of stdin.
+fn read_password() -> Result<String, Box<dyn Error>> {
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        return Err("No password given on stdin.".into());
+    }
+    Ok(password.to_owned())
+}
+
+/// Runs `maelstrom user` with the arguments after it.
+pub async fn user_command(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let command = parse(args)?;
+    let storage = db::PostgresStore::new(&CONFIG.database_url).await?;
+    let result = run(&storage, command).await;
+    storage.close().await;
+    result
+}
+
+async fn run<T: Store>(storage: &T, command: Command) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Create {
+            localpart,
+            admin,
+            password,
+        } => {
+            // Admins may give out reserved names
+            let localpart = CONFIG.localparts.localpart(&localpart)?;
+            let password_hash = if password {
+                Some(uia::hash_password(&read_password()?)?)
+            } else {
+                None
+            };
+            if !storage
+                .create_account(&localpart, password_hash.as_deref(), admin)
+                .await?
+            {
+                return Err(format!("User {} already exists.", localpart).into());
+            }
+            audit(
+                storage,
+                "user.create",
+                &localpart,
+                json!({ "admin": admin }),
+            )
+            .await?;
+            println!("Created {}", UserId::parse(&localpart));
+        }
+        Command::SetPassword { localpart } => {
+            existing(storage, &localpart).await?;
+            let password_hash = uia::hash_password(&read_password()?)?;
+            storage
+                .set_password_hash(&localpart, Some(&password_hash))
+                .await?;
+            audit(storage, "user.reset_password", &localpart, json!({})).await?;
+            println!("Set the password of {}", UserId::parse(&localpart));
+        }
+        Command::Deactivate { localpart, erase } => {
+            existing(storage, &localpart).await?;
+            let user_id = UserId::parse(&localpart);
+            admin::deactivate(storage, &user_id, erase).await?;
+            audit(
+                storage,
+                "user.deactivate",
+                &localpart,
+                json!({ "erase": erase }),
+            )
+            .await?;
+            println!("Deactivated {}", user_id);
+        }
+        Command::List {
+            deactivated,
+            guests,
+        } => {
+            let mut from = None;
+            loop {
+                let accounts = storage
+                    .get_accounts(from.as_deref(), PAGE_SIZE, guests, deactivated)
+                    .await?;
+                for account in &accounts {
+                    let mut flags = Vec::new();
+                    if account.is_admin {
+                        flags.push("admin");
+                    }
+                    if account.is_guest {
+                        flags.push("guest");
+                    }
+                    if account.deactivated {
+                        flags.push("deactivated");
+                    }
+                    println!("{}\t{}", UserId::parse(&account.localpart), flags.join(","));
+                }
+                match accounts.last() {
+                    Some(last) if accounts.len() as i64 == PAGE_SIZE => {
+                        from = Some(last.localpart.clone())
+                    }
+                    _ => break,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fails unless a local account exists.
+async fn existing<T: Store>(storage: &T, localpart: &str) -> Result<(), Box<dyn Error>> {
+    match storage.get_account(localpart).await? {
+        Some(_) => Ok(()),
+        None => Err(format!("User {} does not exist.", localpart).into()),
+    }
+}
+
+/// Records a change made from the command line in the audit log.
+/// `action` is prefixed with `cli.`.
+async fn audit<T: Store>(
+    storage: &T,
+    action: &str,
+    localpart: &str,
+    mut details: serde_json::Value,
+) -> Result<(), Box<dyn Error>> {
+    details["user_id"] = json!(UserId::parse(localpart));
+    audit::record(storage, None, None, &format!("cli.{}", action), &details).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| (*arg).to_owned()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(&args(&["create", "alice", "--admin"])),
+            Ok(Command::Create {
+                localpart: "alice".to_owned(),
+                admin: true,
+                password: true,
+            })
+        );
+        assert_eq!(
+            parse(&args(&["deactivate", "--erase", "bob"])),
+            Ok(Command::Deactivate {
+                localpart: "bob".to_owned(),
+                erase: true,
+            })
+        );
+        assert_eq!(
+            parse(&args(&["list", "--no-guests"])),
+            Ok(Command::List {
+                deactivated: false,
+                guests: false,
+            })
+        );
+        assert!(parse(&args(&["set-password"])).is_err());
+        assert!(parse(&args(&["create", "alice", "--erase"])).is_err());
+        assert!(parse(&args(&["delete", "alice"])).is_err());
+        assert!(parse(&[]).is_err());
+    }
+}
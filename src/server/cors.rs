@@ -0,0 +1,293 @@
+//! Cross-origin resource sharing, so web clients served from other origins
+//! can use the APIs.
+//!
+//! The spec has every client-server endpoint answer with
+//! `Access-Control-Allow-Origin: *`, and answer `OPTIONS` preflight requests
+//! itself. The admin API can be limited to some origins instead, as any page
+//! an admin visits could otherwise make requests with their access token.
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, HeaderMap, HeaderValue, Method},
+    Error, HttpResponse,
+};
+use futures::future::{ok, Ready};
+
+/// The paths of the admin API.
+const ADMIN_PREFIX: &str = "/_maelstrom/admin";
+const ALLOW_METHODS: &str = "GET, POST, PUT, DELETE, OPTIONS";
+const ALLOW_HEADERS: &str = "X-Requested-With, Content-Type, Authorization";
+
+/// The origins allowed to make cross-origin requests.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Origins {
+    Any,
+    /// Only these, e.g. `https://admin.example.com`. None if empty.
+    Only(Vec<String>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidOrigin(String);
+
+impl fmt::Display for InvalidOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid origin `{}`, expected `*` or e.g. `https://example.com`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidOrigin {}
+
+impl FromStr for Origins {
+    type Err = InvalidOrigin;
+
+    /// Parses `*`, or a comma separated list of origins.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim() == "*" {
+            return Ok(Origins::Any);
+        }
+        s.split(',')
+            .map(|origin| origin.trim().trim_end_matches('/'))
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| {
+                if origin.starts_with("https://") || origin.starts_with("http://") {
+                    Ok(origin.to_ascii_lowercase())
+                } else {
+                    Err(InvalidOrigin(origin.to_owned()))
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Origins::Only)
+    }
+}
+
+impl Origins {
+    /// The `Access-Control-Allow-Origin` a request from `origin` is answered
+    /// with, if it is allowed.
+    fn allow(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        match self {
+            Origins::Any => Some(HeaderValue::from_static("*")),
+            Origins::Only(allowed) => {
+                let origin = origin?;
+                let matches = origin.to_str().map_or(false, |origin| {
+                    allowed.iter().any(|a| a.eq_ignore_ascii_case(origin))
+                });
+                if matches {
+                    Some(origin.clone())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Adds the CORS headers, and answers preflight requests.
+#[derive(Clone, Debug)]
+pub struct Cors {
+    admin: Arc<Origins>,
+}
+
+impl Cors {
+    /// Allows any origin, except on the admin API, which allows
+    /// `admin_origins`.
+    pub fn new(admin_origins: Origins) -> Self {
+        Cors {
+            admin: Arc::new(admin_origins),
+        }
+    }
+
+    fn origins(&self, path: &str) -> &Origins {
+        if path.starts_with(ADMIN_PREFIX) {
+            &self.admin
+        } else {
+            &Origins::Any
+        }
+    }
+}
+
+/// Sets the CORS headers of a response, allowing `allow_origin`, if any.
+/// `vary` is whether the answer depends on the origin, so caches must keep
+/// them apart.
+fn set_headers(headers: &mut HeaderMap, allow_origin: Option<HeaderValue>, vary: bool) {
+    if let Some(allow_origin) = allow_origin {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    }
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static(ALLOW_METHODS),
+    );
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static(ALLOW_HEADERS),
+    );
+    if vary {
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+impl<S, B> Transform<S> for Cors
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CorsMiddleware {
+            service,
+            cors: self.clone(),
+        })
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: S,
+    cors: Cors,
+}
+
+impl<S, B> Service for CorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let origins = self.cors.origins(req.path());
+        let allow_origin = origins.allow(req.headers().get(header::ORIGIN));
+        let vary = matches!(origins, Origins::Only(_));
+
+        // Every `OPTIONS` request is answered here, whether or not the path
+        // exists, as the spec asks
+        if req.method() == Method::OPTIONS {
+            let mut res = HttpResponse::Ok().finish();
+            set_headers(res.headers_mut(), allow_origin, vary);
+            return Box::pin(ok(req.into_response(res.into_body())));
+        }
+
+        let res = self.service.call(req);
+        Box::pin(async move {
+            let mut res = res.await?;
+            set_headers(res.headers_mut(), allow_origin, vary);
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_origins() {
+        assert_eq!("*".parse(), Ok(Origins::Any));
+        assert_eq!(
+            "https://Admin.example.com/, http://localhost:8080".parse(),
+            Ok(Origins::Only(vec![
+                "https://admin.example.com".to_owned(),
+                "http://localhost:8080".to_owned(),
+            ]))
+        );
+        assert_eq!("".parse(), Ok(Origins::Only(vec![])));
+        assert!("admin.example.com".parse::<Origins>().is_err());
+    }
+
+    #[test]
+    fn test_allow() {
+        let origin = HeaderValue::from_static("https://admin.example.com");
+        assert_eq!(
+            Origins::Any.allow(None),
+            Some(HeaderValue::from_static("*"))
+        );
+        let only = Origins::Only(vec!["https://admin.example.com".to_owned()]);
+        assert_eq!(only.allow(Some(&origin)), Some(origin));
+        assert_eq!(
+            only.allow(Some(&HeaderValue::from_static("https://evil.example.com"))),
+            None
+        );
+        assert_eq!(only.allow(None), None);
+        assert_eq!(Origins::Only(vec![]).allow(None), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_middleware() {
+        use actix_web::{test, web, App};
+
+        let admin = Origins::Only(vec!["https://admin.example.com".to_owned()]);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Cors::new(admin))
+                .route(
+                    "/_matrix/client/versions",
+                    web::get().to(|| async { HttpResponse::Ok() }),
+                )
+                .route(
+                    "/_maelstrom/admin/v1/users",
+                    web::get().to(|| async { HttpResponse::Ok() }),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/_matrix/client/r0/anything")
+            .method(Method::OPTIONS)
+            .header(header::ORIGIN, "https://app.example.com")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert!(res.status().is_success());
+        assert_eq!(
+            res.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "*"
+        );
+        assert_eq!(
+            res.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_HEADERS)
+                .unwrap(),
+            ALLOW_HEADERS
+        );
+
+        let req = test::TestRequest::with_uri("/_matrix/client/versions").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(
+            res.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_METHODS)
+                .unwrap(),
+            ALLOW_METHODS
+        );
+
+        let req = test::TestRequest::with_uri("/_maelstrom/admin/v1/users")
+            .header(header::ORIGIN, "https://app.example.com")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "Origin");
+    }
+}
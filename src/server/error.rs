@@ -7,6 +7,15 @@ pub struct MatrixError {
     pub errcode: ErrorCode,
     pub error: String,
 }
+impl MatrixError {
+    pub fn new(status: StatusCode, errcode: ErrorCode, error: impl Into<String>) -> Self {
+        MatrixError {
+            status,
+            errcode,
+            error: error.into(),
+        }
+    }
+}
 impl From<MatrixError> for Error {
     fn from(e: MatrixError) -> Self {
         HttpResponse::build(e.status).json(e).into()
@@ -95,6 +104,10 @@ pub enum ErrorCode {
     EXCLUSIVE, // 	The resource being requested is reserved by an application service, or the application service making the request has not created the resource.
     #[serde(rename = "M_RESOURCE_LIMIT_EXCEEDED")]
     RESOURCE_LIMIT_EXCEEDED, //  	The request cannot be completed because the homeserver has reached a resource limit imposed on it. For example, a homeserver held in a shared hosting environment may reach a resource limit if it starts using too much memory or disk space. The error MUST have an admin_contact field to provide the user receiving the error a place to reach out to. Typically, this error will appear on routes which attempt to modify state (eg: sending messages, account data, etc) and not routes which only read state (eg: /sync, get account data, etc).
+    #[serde(rename = "M_INVALID_SIGNATURE")]
+    INVALID_SIGNATURE, //  	A signature on an uploaded key or event could not be verified.
+    #[serde(rename = "M_WRONG_ROOM_KEYS_VERSION")]
+    WRONG_ROOM_KEYS_VERSION, //  	The version of the room keys backup provided in the request does not match the current backup version.
     #[serde(rename = "M_CANNOT_LEAVE_SERVER_NOTICE_ROOM")]
     CANNOT_LEAVE_SERVER_NOTICE_ROOM, //  	The user is unable to reject an invite to join the server notices room. See the Server Notices module for more information.
 }
@@ -0,0 +1,217 @@
+use std::net::IpAddr;
+
+use actix_web::{
+    dev::Payload,
+    http::{HeaderMap, StatusCode},
+    Error, FromRequest, HttpRequest,
+};
+use futures::future::{err, ok, Ready};
+use jsonwebtoken as jwt;
+
+use crate::{
+    appservice::AppService,
+    models::auth::UserId,
+    server::{
+        error::{ErrorCode, MatrixError},
+        proxy, tokens,
+    },
+    CONFIG,
+};
+
+/// The claims we read back out of an access token. Mirrors
+/// `handlers::auth::Claims`, which is used when issuing tokens.
+#[derive(Debug, serde::Deserialize)]
+struct TokenClaims {
+    sub: UserId,
+    device_id: String,
+    iat: i64,
+    /// Missing from tokens issued before it was added
+    iat_ms: Option<i64>,
+}
+
+impl TokenClaims {
+    /// When the token was issued, as a unix timestamp in milliseconds. Older
+    /// tokens are taken to be issued at the start of their second, so they
+    /// are refused if they may have been revoked.
+    fn issued_ms(&self) -> i64 {
+        self.iat_ms.unwrap_or(self.iat * 1000)
+    }
+}
+
+/// The query parameters an access token may be given in.
+#[derive(Debug, serde::Deserialize)]
+struct TokenParams {
+    access_token: Option<String>,
+}
+
+/// An authenticated requester.
+///
+/// Extracts and validates the access token supplied either in the
+/// `Authorization: Bearer` header or the `access_token` query parameter.
+/// Application services authenticate with their `as_token`, and may act as
+/// any user in their namespace by naming them in the `user_id` query
+/// parameter.
+#[derive(Clone, Debug)]
+pub struct Authenticated {
+    pub user_id: UserId,
+    /// The application service's ID, for application services, which have
+    /// no devices of their own
+    pub device_id: String,
+    /// The raw access token the request was made with
+    pub access_token: String,
+    /// The address the request came from, behind any trusted proxies, if
+    /// known
+    pub ip: Option<IpAddr>,
+    /// The application service making the request, if it is one
+    pub appservice_id: Option<String>,
+}
+
+impl Authenticated {
+    /// Returns the access token supplied with a request, if any, given its
+    /// headers and query string.
+    pub fn access_token(headers: &HeaderMap, query: &str) -> Option<String> {
+        if let Some(header) = headers.get("Authorization") {
+            return header
+                .to_str()
+                .ok()
+                .and_then(|h| h.strip_prefix("Bearer "))
+                .map(|t| t.trim().to_owned());
+        }
+        serde_urlencoded::from_str::<TokenParams>(query)
+            .ok()
+            .and_then(|params| params.access_token)
+    }
+
+    /// Validates an access token, returning who it was issued to, or who an
+    /// application service acts as given the request's query string.
+    pub fn from_token(access_token: String, query: &str) -> Result<Self, MatrixError> {
+        if let Some(service) = CONFIG.appservices.by_token(&access_token) {
+            return Self::from_appservice(service, access_token, query);
+        }
+        let validation = jwt::Validation::new(jwt::Algorithm::ES256);
+        let claims =
+            match jwt::decode::<TokenClaims>(&access_token, &CONFIG.auth_decoding_key, &validation)
+            {
+                Ok(data) => data.claims,
+                Err(_) => {
+                    return Err(MatrixError::new(
+                        StatusCode::UNAUTHORIZED,
+                        ErrorCode::UNKNOWN_TOKEN,
+                        "Unrecognised access token.",
+                    ))
+                }
+            };
+        if tokens::is_revoked(
+            &claims.sub.local_part,
+            &claims.device_id,
+            claims.issued_ms(),
+        ) {
+            return Err(MatrixError::new(
+                StatusCode::UNAUTHORIZED,
+                ErrorCode::UNKNOWN_TOKEN,
+                "Access token has been revoked.",
+            ));
+        }
+        Ok(Authenticated {
+            user_id: claims.sub,
+            device_id: claims.device_id,
+            access_token,
+            ip: None,
+            appservice_id: None,
+        })
+    }
+
+    /// An application service acting as the user named by `user_id` in the
+    /// query string, or as its sender if none is. The user must be on this
+    /// server and in the service's namespace.
+    fn from_appservice(
+        service: &AppService,
+        access_token: String,
+        query: &str,
+    ) -> Result<Self, MatrixError> {
+        let user_id = url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "user_id")
+            .map_or_else(
+                || service.sender.clone(),
+                |(_, user_id)| user_id.into_owned(),
+            );
+        let parsed = UserId::parse(&user_id);
+        if !parsed.is_local() || !service.is_user_in_namespace(&parsed.to_string()) {
+            return Err(MatrixError::new(
+                StatusCode::FORBIDDEN,
+                ErrorCode::FORBIDDEN,
+                "Application service may not act as this user.",
+            ));
+        }
+        Ok(Authenticated {
+            user_id: parsed,
+            device_id: service.id.clone(),
+            access_token,
+            ip: None,
+            appservice_id: Some(service.id.clone()),
+        })
+    }
+}
+
+impl FromRequest for Authenticated {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let access_token = match Self::access_token(req.headers(), req.query_string()) {
+            Some(token) => token,
+            None => {
+                return err(MatrixError::new(
+                    StatusCode::UNAUTHORIZED,
+                    ErrorCode::MISSING_TOKEN,
+                    "Missing access token.",
+                )
+                .into())
+            }
+        };
+        match Self::from_token(access_token, req.query_string()) {
+            Ok(mut auth) => {
+                // TODO: Record as the device's last seen IP once devices are
+                // tracked on use
+                auth.ip = proxy::client_ip(req);
+                tracing::Span::current().record("user_id", &auth.user_id.to_string().as_str());
+                ok(auth)
+            }
+            Err(e) => err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_token_query() {
+        let headers = HeaderMap::new();
+        assert_eq!(
+            Authenticated::access_token(&headers, "user_id=%40a%3Ab&access_token=abc%2Bd%3D"),
+            Some("abc+d=".to_owned())
+        );
+        assert_eq!(Authenticated::access_token(&headers, "user_id=x"), None);
+    }
+
+    #[test]
+    fn test_token_issued_ms() {
+        let claims = |claims| serde_json::from_value::<TokenClaims>(claims).unwrap();
+        let new = claims(serde_json::json!({
+            "sub": "@alice:example.com",
+            "device_id": "PHONE",
+            "iat": 1_600_000_000,
+            "iat_ms": 1_600_000_000_750_i64,
+        }));
+        assert_eq!(new.issued_ms(), 1_600_000_000_750);
+        let old = claims(serde_json::json!({
+            "sub": "@alice:example.com",
+            "device_id": "PHONE",
+            "iat": 1_600_000_000,
+        }));
+        assert_eq!(old.issued_ms(), 1_600_000_000_000);
+    }
+}
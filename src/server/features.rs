@@ -0,0 +1,148 @@
+//! The experimental features the server implements, and which are turned
+//! on.
+//!
+//! Each feature is advertised to clients in the `unstable_features` of
+//! `/versions` by the flags its proposal asks for, and only served while it
+//! is turned on, so the two can't disagree.
+use std::fmt;
+
+/// An experimental feature.
+#[derive(Debug, PartialEq)]
+pub struct Feature {
+    /// What the feature is turned on or off by in `EXPERIMENTAL_FEATURES`
+    pub name: &'static str,
+    /// The `unstable_features` flags advertised while the feature is on
+    pub flags: &'static [&'static str],
+    /// Whether the feature is on unless configured otherwise
+    pub default: bool,
+}
+
+/// Dehydrated devices (MSC3814).
+pub const DEHYDRATED_DEVICES: &Feature = &Feature {
+    name: "dehydrated_devices",
+    flags: &["org.matrix.msc3814"],
+    default: true,
+};
+
+/// Media downloads that need an access token (MSC3916), served under
+/// `/_matrix/client/v1/media`.
+pub const AUTHENTICATED_MEDIA: &Feature = &Feature {
+    name: "authenticated_media",
+    flags: &["org.matrix.msc3916.stable"],
+    default: true,
+};
+
+/// Summaries of rooms by ID (MSC3266).
+pub const ROOM_SUMMARY: &Feature = &Feature {
+    name: "room_summary",
+    flags: &["im.nheko.summary"],
+    default: true,
+};
+
+/// Every experimental feature the server implements.
+const FEATURES: &[&Feature] = &[DEHYDRATED_DEVICES, AUTHENTICATED_MEDIA, ROOM_SUMMARY];
+
+/// Why the configured features can't be used.
+#[derive(Debug, PartialEq)]
+pub struct UnknownFeature(String);
+
+impl fmt::Display for UnknownFeature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown experimental feature {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownFeature {}
+
+/// The experimental features that are turned on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Features(Vec<&'static Feature>);
+
+impl Default for Features {
+    fn default() -> Self {
+        Features(FEATURES.iter().copied().filter(|f| f.default).collect())
+    }
+}
+
+impl Features {
+    /// Turns on the features named in a comma separated list, and only
+    /// those.
+    pub fn parse(names: &str) -> Result<Self, UnknownFeature> {
+        names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                FEATURES
+                    .iter()
+                    .copied()
+                    .find(|feature| feature.name == name)
+                    .ok_or_else(|| UnknownFeature(name.to_owned()))
+            })
+            .collect::<Result<_, _>>()
+            .map(Features)
+    }
+
+    /// Reads the features from `EXPERIMENTAL_FEATURES`, falling back to the
+    /// defaults. Panics if a feature is unknown.
+    pub fn from_env() -> Self {
+        std::env::var("EXPERIMENTAL_FEATURES")
+            .map(|names| Features::parse(&names).expect("Unable to parse EXPERIMENTAL_FEATURES."))
+            .unwrap_or_default()
+    }
+
+    pub fn is_enabled(&self, feature: &Feature) -> bool {
+        self.0.contains(&feature)
+    }
+
+    /// The `unstable_features` of `/versions`: every flag the server knows,
+    /// and whether its feature is on.
+    pub fn unstable_features(&self) -> serde_json::Map<String, serde_json::Value> {
+        FEATURES
+            .iter()
+            .flat_map(|feature| {
+                let enabled = self.is_enabled(feature);
+                feature
+                    .flags
+                    .iter()
+                    .map(move |flag| (flag.to_string(), enabled.into()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        let features = Features::parse("dehydrated_devices, ").unwrap();
+        assert!(features.is_enabled(DEHYDRATED_DEVICES));
+        assert!(!features.is_enabled(AUTHENTICATED_MEDIA));
+        assert_eq!(Features::parse("").unwrap(), Features(Vec::new()));
+        assert_eq!(
+            Features::parse("dehydrated_devices,knocking"),
+            Err(UnknownFeature("knocking".to_owned()))
+        );
+
+        let defaults = Features::default();
+        assert!(defaults.is_enabled(DEHYDRATED_DEVICES));
+        assert!(defaults.is_enabled(AUTHENTICATED_MEDIA));
+        assert!(defaults.is_enabled(ROOM_SUMMARY));
+    }
+
+    #[test]
+    fn test_unstable_features() {
+        let features = Features::parse("authenticated_media").unwrap();
+        assert_eq!(
+            serde_json::Value::Object(features.unstable_features()),
+            json!({
+                "org.matrix.msc3814": false,
+                "org.matrix.msc3916.stable": true,
+                "im.nheko.summary": false,
+            })
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json},
+    Error, HttpResponse,
+};
+use serde_json::json;
+
+use crate::{
+    audit,
+    db::Store,
+    models::account as model,
+    server::{
+        admin::deactivate,
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+        tokens, uia,
+    },
+    CONFIG,
+};
+
+/// Changes the user's password, logging out their other devices unless
+/// `logout_devices` is `false`. Refused unless password changes are
+/// enabled, as advertised in `/capabilities`.
+///
+/// This API endpoint uses the User-Interactive Authentication API.
+///
+/// POST /_matrix/client/r0/account/password
+pub async fn change_password<T: Store>(
+    auth: Authenticated,
+    req: Json<model::PasswordRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    if !CONFIG.password_change_enabled {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "Password changes are disabled.",
+        )
+        .into());
+    }
+    // Checked before authenticating, as that completes the UIA session
+    if req.new_password.is_empty() {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_PARAM,
+            "The new password can't be empty.",
+        )
+        .into());
+    }
+    uia::authenticate(
+        storage.get_ref(),
+        &auth,
+        "account.password_change",
+        req.auth.as_ref(),
+    )
+    .await?;
+
+    let localpart = &auth.user_id.local_part;
+    let password_hash = uia::hash_password(&req.new_password)
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    storage
+        .set_password_hash(localpart, Some(&password_hash))
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let logout_devices = req.logout_devices.unwrap_or(true);
+    if logout_devices {
+        tokens::revoke(storage.get_ref(), localpart, Some(&auth.device_id))
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    }
+    audit::record(
+        storage.get_ref(),
+        Some(&auth.user_id.to_string()),
+        auth.ip,
+        "account.password_change",
+        &json!({ "device_id": auth.device_id, "logout_devices": logout_devices }),
+    )
+    .await
+    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Deactivates the user's account, so they can no longer log in, logs out
+/// all of their devices and rejects their pending invites. With `erase` their data is erased too,
+/// and the events they sent are redacted in the background.
+///
+/// No third party identifiers are bound to identity servers by this server,
+/// so there are none to unbind.
+///
+/// This API endpoint uses the User-Interactive Authentication API.
+///
+/// POST /_matrix/client/r0/account/deactivate
+pub async fn deactivate_account<T: Store>(
+    auth: Authenticated,
+    req: Json<model::DeactivateRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    uia::authenticate(
+        storage.get_ref(),
+        &auth,
+        "account.deactivate",
+        req.auth.as_ref(),
+    )
+    .await?;
+
+    deactivate(storage.get_ref(), &auth.user_id, req.erase)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    audit::record(
+        storage.get_ref(),
+        Some(&auth.user_id.to_string()),
+        auth.ip,
+        "account.deactivate",
+        &json!({ "device_id": auth.device_id, "erase": req.erase }),
+    )
+    .await
+    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(model::DeactivateResponse {
+        id_server_unbind_result: "success",
+    }))
+}
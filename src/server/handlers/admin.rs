@@ -1,5 +1,17 @@
-use actix_web::{Error, HttpResponse};
-use serde_json::json;
+use actix_web::{http::StatusCode, Error, HttpResponse};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    models::auth::DiscoveryInfo,
+    server::{
+        error::{ErrorCode, MatrixError},
+        features::Features,
+    },
+    CONFIG,
+};
+
+/// The versions of the specification the server implements.
+const VERSIONS: &[&str] = &["r0.5.0"];
 
 /// Gets discovery information about the domain. The file may include
 /// additional keys, which MUST follow the Java package naming convention,
@@ -9,8 +21,40 @@ use serde_json::json;
 ///
 /// Note that this endpoint is not necessarily handled by the homeserver,
 /// but by another webserver, to be used for discovering the homeserver URL.
+///
+/// GET /.well-known/matrix/client
 pub async fn get_wellknown() -> Result<HttpResponse, Error> {
-    Ok(HttpResponse::Ok().json("unimplemented!"))
+    Ok(HttpResponse::Ok().json(client_discovery(
+        DiscoveryInfo::from_config(),
+        &CONFIG.well_known_client_extra,
+    )))
+}
+
+/// Gets the server name other homeservers should reach this one at, for
+/// deployments delegating federation to another host or port. Not found if
+/// federation isn't delegated, so the usual port is used.
+///
+/// GET /.well-known/matrix/server
+pub async fn get_wellknown_server() -> Result<HttpResponse, Error> {
+    let server = CONFIG.well_known_server.as_deref().ok_or_else(|| {
+        MatrixError::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::NOT_FOUND,
+            "Federation is not delegated.",
+        )
+    })?;
+    Ok(HttpResponse::Ok().json(json!({ "m.server": server })))
+}
+
+/// The client discovery document: the discovery information, with the
+/// additional keys. The discovery information wins over keys of the same
+/// name.
+fn client_discovery(info: DiscoveryInfo, extra: &Map<String, Value>) -> Value {
+    let mut document = extra.clone();
+    if let Value::Object(info) = json!(info) {
+        document.extend(info);
+    }
+    Value::Object(document)
 }
 
 /// Gets the versions of the specification supported by the server.
@@ -30,10 +74,20 @@ pub async fn get_wellknown() -> Result<HttpResponse, Error> {
 /// Servers may wish to keep advertising features here after they've been released into
 /// the spec to give clients a chance to upgrade appropriately. Additionally, clients should
 /// avoid using unstable features in their stable releases.
+///
+/// The unstable features are those of the `features` registry, so turning
+/// an experimental feature on or off advertises it accordingly.
+///
+/// GET /_matrix/client/versions
 pub async fn get_versions() -> Result<HttpResponse, Error> {
-    Ok(HttpResponse::Ok()
-        .content_type("application/json")
-        .body("{\"versions\":[\"r0.5.0\"]}"))
+    Ok(HttpResponse::Ok().json(versions(&CONFIG.features)))
+}
+
+fn versions(features: &Features) -> Value {
+    json!({
+        "versions": VERSIONS,
+        "unstable_features": features.unstable_features(),
+    })
 }
 
 #[cfg(test)]
@@ -56,4 +110,54 @@ mod tests {
         let resp = get_versions().await;
         assert_eq!(resp.unwrap().status(), http::StatusCode::OK);
     }
+
+    #[test]
+    fn test_client_discovery() {
+        use crate::models::auth::{HomeserverInfo, IdentityServerInfo};
+        use std::borrow::Cow;
+
+        let info = DiscoveryInfo {
+            homeserver: HomeserverInfo {
+                base_url: Cow::Borrowed("https://matrix.example.com:8448"),
+            },
+            identity_server: None,
+        };
+        assert_eq!(
+            client_discovery(info.clone(), &Map::new()),
+            json!({"m.homeserver": {"base_url": "https://matrix.example.com:8448"}})
+        );
+
+        let extra = json!({
+            "com.example.app": {"theme": "dark"},
+            "m.homeserver": {"base_url": "https://elsewhere.example.com"},
+        });
+        let info = DiscoveryInfo {
+            identity_server: Some(IdentityServerInfo {
+                base_url: Cow::Borrowed("https://id.example.com"),
+            }),
+            ..info
+        };
+        assert_eq!(
+            client_discovery(info, extra.as_object().unwrap()),
+            json!({
+                "m.homeserver": {"base_url": "https://matrix.example.com:8448"},
+                "m.identity_server": {"base_url": "https://id.example.com"},
+                "com.example.app": {"theme": "dark"},
+            })
+        );
+    }
+
+    #[test]
+    fn test_versions() {
+        let versions = versions(&Features::parse("dehydrated_devices").unwrap());
+        assert_eq!(versions["versions"], json!(["r0.5.0"]));
+        assert_eq!(
+            versions["unstable_features"]["org.matrix.msc3814"],
+            json!(true)
+        );
+        assert_eq!(
+            versions["unstable_features"]["org.matrix.msc3916.stable"],
+            json!(false)
+        );
+    }
 }
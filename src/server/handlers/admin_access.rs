@@ -0,0 +1,122 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json, Path},
+    Error, HttpResponse,
+};
+use serde_json::json;
+
+use crate::{
+    clock::now_ms,
+    db::Store,
+    ipnet::IpNet,
+    models::access::{self as model, IpBlock},
+    server::{
+        access,
+        admin::{audit, require_admin},
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+    },
+};
+
+/// Lists the networks blocked through the admin API, and for failed logins,
+/// that are still blocked. Those denied by configuration aren't listed.
+///
+/// GET /_maelstrom/admin/v1/ip_blocks
+pub async fn list_ip_blocks<T: Store>(
+    auth: Authenticated,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let now = now_ms();
+    let blocks = storage
+        .get_ip_blocks(now)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(model::ListIpBlocksResponse { blocks }))
+}
+
+/// Keeps a network out of the client or federation API, for good or for
+/// `duration_ms`. Every process sharing the database refuses it at once.
+///
+/// POST /_maelstrom/admin/v1/ip_blocks
+pub async fn add_ip_block<T: Store>(
+    auth: Authenticated,
+    req: Json<model::AddIpBlockRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let net: IpNet = req
+        .net
+        .parse()
+        .with_codes(StatusCode::BAD_REQUEST, ErrorCode::INVALID_PARAM)?;
+    if req
+        .duration_ms
+        .map_or(false, |duration_ms| duration_ms <= 0)
+    {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_PARAM,
+            "duration_ms must be positive.",
+        )
+        .into());
+    }
+    let now = now_ms();
+    let mut block = IpBlock {
+        block_id: 0,
+        net: net.to_string(),
+        api: req.api,
+        reason: req.reason.clone(),
+        created_ts: now,
+        expires_ts: req.duration_ms.map(|duration_ms| now + duration_ms),
+    };
+
+    block.block_id = access::add(storage.get_ref(), &block)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    audit(
+        storage.get_ref(),
+        &auth,
+        "ip_block.add",
+        &json!({
+            "block_id": block.block_id,
+            "net": block.net,
+            "api": block.api,
+            "expires_ts": block.expires_ts,
+        }),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(block))
+}
+
+/// Lifts a block added through the admin API, or for failed logins.
+///
+/// DELETE /_maelstrom/admin/v1/ip_blocks/{blockId}
+pub async fn remove_ip_block<T: Store>(
+    auth: Authenticated,
+    path: Path<model::IpBlockPath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let removed = access::remove(storage.get_ref(), path.block_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if !removed {
+        return Err(MatrixError::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::NOT_FOUND,
+            "No such IP block.",
+        )
+        .into());
+    }
+    audit(
+        storage.get_ref(),
+        &auth,
+        "ip_block.remove",
+        &json!({ "block_id": path.block_id }),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
@@ -0,0 +1,47 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Query},
+    Error, HttpResponse,
+};
+
+use crate::{
+    db::Store,
+    models::admin as model,
+    server::{
+        admin::require_admin,
+        error::{ErrorCode, ResultExt as _},
+        extract::Authenticated,
+    },
+};
+
+/// How many entries are listed at once, unless asked for fewer.
+const DEFAULT_LIMIT: i64 = 100;
+/// The most entries listed at once.
+const MAX_LIMIT: i64 = 1000;
+
+/// Lists the audit log, newest entries first, a page at a time. Entries can
+/// be filtered by `user_id`, by an `action` prefix, and by time with `since`
+/// and `until`.
+///
+/// GET /_maelstrom/admin/v1/audit_log
+pub async fn get_audit_log<T: Store>(
+    auth: Authenticated,
+    params: Query<model::AuditLogParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT);
+    let entries = storage
+        .get_audit_log(&params, limit)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    let next_token = match entries.last() {
+        Some(last) if entries.len() as i64 == limit => Some(last.id),
+        _ => None,
+    };
+    Ok(HttpResponse::Ok().json(model::AuditLogResponse {
+        entries,
+        next_token,
+    }))
+}
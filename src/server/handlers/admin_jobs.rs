@@ -0,0 +1,72 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Query},
+    Error, HttpResponse,
+};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::{
+    db::Store,
+    models::admin as model,
+    scheduler,
+    server::{
+        admin::{audit, require_admin},
+        error::{ErrorCode, ResultExt as _},
+        extract::Authenticated,
+    },
+};
+
+/// How many jobs are listed at once, unless asked for fewer.
+const DEFAULT_LIMIT: i64 = 100;
+/// The most jobs listed at once.
+const MAX_LIMIT: i64 = 1000;
+
+/// Lists the background jobs in the order they were scheduled, with when
+/// they last ran and are next due, and why they last failed. Jobs can be
+/// filtered by `kind` and `status`. Jobs run once are listed for a week
+/// after they finish.
+///
+/// GET /_maelstrom/admin/v1/scheduled_jobs
+pub async fn list_scheduled_jobs<T: Store>(
+    auth: Authenticated,
+    params: Query<model::ListScheduledJobsParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT);
+    let jobs = storage
+        .get_scheduled_jobs(&params, limit)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(model::ListScheduledJobsResponse { jobs }))
+}
+
+/// Runs the media retention policies now, instead of waiting for their
+/// next run, responding with the ID of the job to follow it with.
+///
+/// POST /_maelstrom/admin/v1/media/purge
+pub async fn purge_media<T: Store>(
+    auth: Authenticated,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let job_id = scheduler::schedule(
+        storage.get_ref(),
+        scheduler::MEDIA_RETENTION,
+        &Value::Null,
+        Duration::from_secs(0),
+    )
+    .await
+    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    audit(
+        storage.get_ref(),
+        &auth,
+        "media.purge",
+        &json!({ "job_id": job_id }),
+    )
+    .await?;
+
+    Ok(HttpResponse::Accepted().json(json!({ "job_id": job_id })))
+}
@@ -0,0 +1,324 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json, Path, Query},
+    Error, HttpResponse,
+};
+use serde_json::json;
+
+use crate::{
+    db::Store,
+    models::admin::{self as model, JobStatus, RoomJob, RoomMember},
+    moderation::Notifier,
+    server::{
+        admin::{audit, local_user, require_admin},
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+    },
+};
+
+/// How many rooms are listed at once, unless asked for fewer.
+const DEFAULT_LIMIT: i64 = 100;
+/// The most rooms listed at once.
+const MAX_LIMIT: i64 = 1000;
+/// The most users a bulk invite may invite.
+const MAX_BULK_INVITES: usize = 10_000;
+
+/// Queues a room moderation job and wakes the runner, responding with the
+/// job's ID to follow it with.
+async fn queue_job<T: Store>(
+    storage: &T,
+    notifier: &Notifier,
+    auth: &Authenticated,
+    room_id: &str,
+    job: RoomJob,
+) -> Result<HttpResponse, Error> {
+    let job_id = storage
+        .add_admin_job(room_id, &job, &auth.user_id.to_string())
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    audit(
+        storage,
+        auth,
+        "room.job",
+        &json!({"job_id": job_id, "room_id": room_id, "job": job}),
+    )
+    .await?;
+    notifier.notify(job_id);
+
+    Ok(HttpResponse::Accepted().json(json!({ "job_id": job_id })))
+}
+
+/// Lists the rooms the server knows of in order of room ID, a page at a
+/// time, with how many local users are in each.
+///
+/// TODO: Only rooms local users are invited to, or that are blocked, are
+/// known until rooms exist.
+///
+/// GET /_maelstrom/admin/v1/rooms
+pub async fn list_rooms<T: Store>(
+    auth: Authenticated,
+    params: Query<model::ListRoomsParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT);
+    let rooms = storage
+        .get_admin_rooms(params.from.as_deref(), limit)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    let next_token = match rooms.last() {
+        Some(last) if rooms.len() as i64 == limit => Some(last.room_id.clone()),
+        _ => None,
+    };
+    Ok(HttpResponse::Ok().json(model::ListRoomsResponse { rooms, next_token }))
+}
+
+/// Removes a local user from a room, in the background.
+///
+/// DELETE /_maelstrom/admin/v1/rooms/{roomId}/members/{userId}
+pub async fn remove_member<T: Store>(
+    auth: Authenticated,
+    path: Path<model::RoomMemberPath>,
+    storage: Data<T>,
+    notifier: Data<Notifier>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let user_id = local_user(&path.user_id)?;
+    let job = RoomJob::RemoveMember {
+        user_id: user_id.to_string(),
+    };
+
+    queue_job(storage.get_ref(), &notifier, &auth, &path.room_id, job).await
+}
+
+/// Shuts a room down in the background: blocks it so local users can't
+/// join it again, removes every local user from it, and tombstones it.
+///
+/// POST /_maelstrom/admin/v1/rooms/{roomId}/shutdown
+pub async fn shutdown_room<T: Store>(
+    auth: Authenticated,
+    path: Path<model::RoomPath>,
+    req: Option<Json<model::ShutdownRequest>>,
+    storage: Data<T>,
+    notifier: Data<Notifier>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let job = RoomJob::Shutdown {
+        message: req.and_then(|req| req.into_inner().message),
+    };
+
+    queue_job(storage.get_ref(), &notifier, &auth, &path.room_id, job).await
+}
+
+/// Purges the events of a room sent before `before_ts`, in the background.
+///
+/// POST /_maelstrom/admin/v1/rooms/{roomId}/purge_history
+pub async fn purge_history<T: Store>(
+    auth: Authenticated,
+    path: Path<model::RoomPath>,
+    req: Json<model::PurgeHistoryRequest>,
+    storage: Data<T>,
+    notifier: Data<Notifier>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let job = RoomJob::PurgeHistory {
+        before_ts: req.before_ts,
+    };
+
+    queue_job(storage.get_ref(), &notifier, &auth, &path.room_id, job).await
+}
+
+/// Invites users to a room in the background, on behalf of the admin, e.g.
+/// to bring a community over from another room. The job records who it
+/// invited, and why anyone couldn't be.
+///
+/// POST /_maelstrom/admin/v1/rooms/{roomId}/bulk_invite
+pub async fn bulk_invite<T: Store>(
+    auth: Authenticated,
+    path: Path<model::RoomPath>,
+    req: Json<model::BulkInviteRequest>,
+    storage: Data<T>,
+    notifier: Data<Notifier>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    if req.user_ids.is_empty() || req.user_ids.len() > MAX_BULK_INVITES {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_PARAM,
+            format!("Between 1 and {} users can be invited.", MAX_BULK_INVITES),
+        )
+        .into());
+    }
+    if let Some(invalid) = req.user_ids.iter().find(|id| !is_user_id(id)) {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_PARAM,
+            format!("{} is not a user ID.", invalid),
+        )
+        .into());
+    }
+    let job = RoomJob::BulkInvite {
+        user_ids: req.into_inner().user_ids,
+    };
+
+    queue_job(storage.get_ref(), &notifier, &auth, &path.room_id, job).await
+}
+
+/// Whether `id` is a fully qualified user ID, `@localpart:server`.
+fn is_user_id(id: &str) -> bool {
+    match id
+        .strip_prefix('@')
+        .and_then(|id| id.find(':').map(|i| (id, i)))
+    {
+        Some((id, colon_idx)) => colon_idx > 0 && colon_idx + 1 < id.len(),
+        None => false,
+    }
+}
+
+/// Snapshots the members of a room in the background, with their
+/// membership, for admins to download from the job once it is complete.
+///
+/// POST /_maelstrom/admin/v1/rooms/{roomId}/export_members
+pub async fn export_members<T: Store>(
+    auth: Authenticated,
+    path: Path<model::RoomPath>,
+    storage: Data<T>,
+    notifier: Data<Notifier>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+
+    queue_job(
+        storage.get_ref(),
+        &notifier,
+        &auth,
+        &path.room_id,
+        RoomJob::ExportMembers,
+    )
+    .await
+}
+
+/// Downloads the members a complete export job snapshotted, as JSON, or as
+/// CSV with `format=csv`.
+///
+/// GET /_maelstrom/admin/v1/jobs/{jobId}/members
+pub async fn get_exported_members<T: Store>(
+    auth: Authenticated,
+    path: Path<model::JobPath>,
+    params: Query<model::ExportParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let job = storage
+        .get_admin_job(path.job_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let job = match job {
+        Some(job) if job.job == RoomJob::ExportMembers => job,
+        _ => {
+            return Err(MatrixError::new(
+                StatusCode::NOT_FOUND,
+                ErrorCode::NOT_FOUND,
+                "Export job not found.",
+            )
+            .into())
+        }
+    };
+    if job.status != JobStatus::Complete {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::UNKNOWN,
+            "The export hasn't completed.",
+        )
+        .into());
+    }
+    let members = storage
+        .get_exported_members(job.job_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    match params.format.unwrap_or(model::ExportFormat::Json) {
+        model::ExportFormat::Json => Ok(HttpResponse::Ok().json(model::ExportedMembersResponse {
+            room_id: job.room_id,
+            members,
+        })),
+        model::ExportFormat::Csv => Ok(HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .body(members_csv(&members))),
+    }
+}
+
+/// Writes members as CSV, with a header row.
+fn members_csv(members: &[RoomMember]) -> String {
+    let field = |value: &str| {
+        if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_owned()
+        }
+    };
+    let mut csv = "user_id,membership\r\n".to_owned();
+    for member in members {
+        csv.push_str(&format!(
+            "{},{}\r\n",
+            field(&member.user_id),
+            field(&member.membership)
+        ));
+    }
+    csv
+}
+
+/// Gets a room moderation job, with its status and what it has done so far.
+///
+/// GET /_maelstrom/admin/v1/jobs/{jobId}
+pub async fn get_job<T: Store>(
+    auth: Authenticated,
+    path: Path<model::JobPath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let job = storage
+        .get_admin_job(path.job_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .ok_or_else(|| {
+            MatrixError::new(
+                StatusCode::NOT_FOUND,
+                ErrorCode::NOT_FOUND,
+                "Job not found.",
+            )
+        })?;
+
+    Ok(HttpResponse::Ok().json(job))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_user_id() {
+        assert!(is_user_id("@alice:example.com"));
+        assert!(!is_user_id("alice:example.com"));
+        assert!(!is_user_id("@alice"));
+        assert!(!is_user_id("@:example.com"));
+    }
+
+    #[test]
+    fn test_members_csv() {
+        let member = |user_id: &str, membership: &str| RoomMember {
+            user_id: user_id.to_owned(),
+            membership: membership.to_owned(),
+        };
+        let members = [
+            member("@alice:example.com", "join"),
+            member("@bob,\"the builder\":example.com", "ban"),
+        ];
+        assert_eq!(
+            members_csv(&members),
+            "user_id,membership\r\n\
+             @alice:example.com,join\r\n\
+             \"@bob,\"\"the builder\"\":example.com\",ban\r\n"
+        );
+    }
+}
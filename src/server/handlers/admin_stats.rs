@@ -0,0 +1,28 @@
+use actix_web::{http::StatusCode, web::Data, Error, HttpResponse};
+
+use crate::{
+    db::Store,
+    server::{
+        admin::require_admin,
+        error::{ErrorCode, ResultExt as _},
+        extract::Authenticated,
+    },
+    stats,
+};
+
+/// Gets the server's aggregates: how many users and rooms it has, and which
+/// version it runs. They are what the server reports each day, if it is
+/// configured to.
+///
+/// GET /_maelstrom/admin/v1/statistics
+pub async fn get_statistics<T: Store>(
+    auth: Authenticated,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let stats = stats::collect(storage.get_ref())
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(stats))
+}
@@ -0,0 +1,260 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json, Path, Query},
+    Error, HttpResponse,
+};
+use serde_json::json;
+
+use crate::{
+    db::Store,
+    models::admin::{self as model, Account},
+    server::{
+        admin::{audit, deactivate, local_user, require_admin},
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+        uia::hash_password,
+    },
+    CONFIG,
+};
+
+/// How many users are listed at once, unless asked for fewer.
+const DEFAULT_LIMIT: i64 = 100;
+/// The most users listed at once.
+const MAX_LIMIT: i64 = 1000;
+
+fn user_response(account: Account) -> model::UserResponse {
+    model::UserResponse {
+        name: format!("@{}:{}", account.localpart, CONFIG.hostname),
+        creation_ts: account.created_ts,
+        admin: account.is_admin,
+        guest: account.is_guest,
+        deactivated: account.deactivated,
+        appservice_id: account.appservice_id,
+        send_limit_exempt: account.send_limit_exempt,
+    }
+}
+
+fn not_found() -> MatrixError {
+    MatrixError::new(
+        StatusCode::NOT_FOUND,
+        ErrorCode::NOT_FOUND,
+        "User not found.",
+    )
+}
+
+/// Gets the account of a local user, or `404 Not Found`.
+async fn get_account<T: Store>(storage: &T, localpart: &str) -> Result<Account, Error> {
+    Ok(storage
+        .get_account(localpart)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .ok_or_else(not_found)?)
+}
+
+/// Lists the local users in order of user ID, a page at a time. Guests are
+/// listed unless `guests=false`, and deactivated users only if
+/// `deactivated=true`.
+///
+/// GET /_maelstrom/admin/v1/users
+pub async fn list_users<T: Store>(
+    auth: Authenticated,
+    params: Query<model::ListUsersParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT);
+    let accounts = storage
+        .get_accounts(
+            params.from.as_deref(),
+            limit,
+            params.guests.unwrap_or(true),
+            params.deactivated.unwrap_or(false),
+        )
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    let next_token = match accounts.last() {
+        Some(last) if accounts.len() as i64 == limit => Some(last.localpart.clone()),
+        _ => None,
+    };
+    Ok(HttpResponse::Ok().json(model::ListUsersResponse {
+        users: accounts.into_iter().map(user_response).collect(),
+        next_token,
+    }))
+}
+
+/// Creates a local user, optionally with a password and as a server admin.
+///
+/// POST /_maelstrom/admin/v1/users
+pub async fn create_user<T: Store>(
+    auth: Authenticated,
+    req: Json<model::CreateUserRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    // Admins may give out reserved names
+    let localpart = CONFIG.localparts.localpart(&req.username)?;
+    let password_hash = req
+        .password
+        .as_deref()
+        .map(hash_password)
+        .transpose()
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    let created = storage
+        .create_account(&localpart, password_hash.as_deref(), req.admin)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if !created {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::USER_IN_USE,
+            "User ID already taken.",
+        )
+        .into());
+    }
+    audit(
+        storage.get_ref(),
+        &auth,
+        "user.create",
+        &json!({"localpart": localpart, "admin": req.admin}),
+    )
+    .await?;
+
+    let account = get_account(storage.get_ref(), &localpart).await?;
+    Ok(HttpResponse::Created().json(user_response(account)))
+}
+
+/// Gets a local user.
+///
+/// GET /_maelstrom/admin/v1/users/{userId}
+pub async fn get_user<T: Store>(
+    auth: Authenticated,
+    path: Path<model::UserPath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let user_id = local_user(&path.user_id)?;
+    let account = get_account(storage.get_ref(), &user_id.local_part).await?;
+
+    Ok(HttpResponse::Ok().json(user_response(account)))
+}
+
+/// Replaces a local user's password.
+///
+/// POST /_maelstrom/admin/v1/users/{userId}/password
+pub async fn reset_password<T: Store>(
+    auth: Authenticated,
+    path: Path<model::UserPath>,
+    req: Json<model::ResetPasswordRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let user_id = local_user(&path.user_id)?;
+    get_account(storage.get_ref(), &user_id.local_part).await?;
+    let password_hash = hash_password(&req.new_password)
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    storage
+        .set_password_hash(&user_id.local_part, Some(&password_hash))
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    audit(
+        storage.get_ref(),
+        &auth,
+        "user.reset_password",
+        &json!({ "user_id": user_id }),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Deactivates a local user, so they can no longer log in, logs out all of
+/// their devices, removes their pushers and rejects their pending invites.
+/// With `erase` their data is erased too, and the events they sent are
+/// redacted in the background.
+///
+/// POST /_maelstrom/admin/v1/users/{userId}/deactivate
+pub async fn deactivate_user<T: Store>(
+    auth: Authenticated,
+    path: Path<model::UserPath>,
+    req: Option<Json<model::DeactivateRequest>>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let user_id = local_user(&path.user_id)?;
+    get_account(storage.get_ref(), &user_id.local_part).await?;
+    let erase = req.map_or(false, |req| req.erase);
+
+    deactivate(storage.get_ref(), &user_id, erase)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    audit(
+        storage.get_ref(),
+        &auth,
+        "user.deactivate",
+        &json!({ "user_id": user_id, "erase": erase }),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Reactivates a deactivated local user. If their data was erased they have
+/// no password, and need a new one to log in.
+///
+/// POST /_maelstrom/admin/v1/users/{userId}/reactivate
+pub async fn reactivate_user<T: Store>(
+    auth: Authenticated,
+    path: Path<model::UserPath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let user_id = local_user(&path.user_id)?;
+    get_account(storage.get_ref(), &user_id.local_part).await?;
+
+    storage
+        .set_account_deactivated(&user_id.local_part, false)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    audit(
+        storage.get_ref(),
+        &auth,
+        "user.reactivate",
+        &json!({ "user_id": user_id }),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Lets a local user send events as fast as they like, or throttles them
+/// again, e.g. for a trusted bot. Their other requests are still rate
+/// limited.
+///
+/// PUT /_maelstrom/admin/v1/users/{userId}/send_limit
+pub async fn set_send_limit<T: Store>(
+    auth: Authenticated,
+    path: Path<model::UserPath>,
+    req: Json<model::SendLimitRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    require_admin(storage.get_ref(), &auth).await?;
+    let user_id = local_user(&path.user_id)?;
+    get_account(storage.get_ref(), &user_id.local_part).await?;
+
+    storage
+        .set_send_limit_exempt(&user_id.local_part, req.exempt)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    audit(
+        storage.get_ref(),
+        &auth,
+        "user.send_limit",
+        &json!({ "user_id": user_id, "exempt": req.exempt }),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
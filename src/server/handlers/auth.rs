@@ -1,12 +1,21 @@
-use std::borrow::Cow;
-
-use actix_web::{http::StatusCode, web::Json, Error, HttpResponse};
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json},
+    Error, HttpRequest, HttpResponse,
+};
 use jsonwebtoken as jwt;
 use serde_json::json;
 
 use crate::{
+    audit,
+    clock::now_ms,
+    db::Store,
     models::auth as model,
-    server::error::{ErrorCode, ResultExt as _},
+    server::{
+        access,
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        proxy, uia,
+    },
     CONFIG,
 };
 
@@ -15,9 +24,6 @@ lazy_static::lazy_static! {
         "flows": vec![
             model::LoginFlow {
                 login_type: model::LoginType::Password
-            },
-            model::LoginFlow {
-                login_type: model::LoginType::Token
             }
         ]
     })).unwrap();
@@ -39,19 +45,21 @@ pub async fn login_info() -> Result<HttpResponse, Error> {
 pub struct Claims<'a, 'b> {
     pub iss: &'static str,
     pub iat: i64,
+    /// `iat` in milliseconds, so a token issued in the same second as its
+    /// user's tokens were revoked can be told apart from the revoked ones
+    pub iat_ms: i64,
     pub exp: i64,
     pub sub: &'a model::UserId,
     pub device_id: &'b str,
 }
 impl<'a, 'b> Claims<'a, 'b> {
     pub fn new(user_id: &'a model::UserId, device_id: &'b str) -> Self {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .map(|a| a.as_secs() as i64)
-            .unwrap_or_else(|a| -(a.duration().as_secs() as i64));
+        let issued_ms = now_ms();
+        let now = issued_ms / 1000;
         Self {
             iss: &CONFIG.hostname,
             iat: now,
+            iat_ms: issued_ms,
             exp: now + CONFIG.session_expiration,
             sub: user_id,
             device_id,
@@ -59,16 +67,123 @@ impl<'a, 'b> Claims<'a, 'b> {
     }
 }
 
-pub async fn login(req: Json<model::LoginRequest>) -> Result<HttpResponse, Error> {
-    let user_id = match &req.challenge {
-        model::Challenge::Password { password } => {
-            unimplemented!("check password against user db") // TODO: will finish once user db model is complete
+/// Logs a user in with their password, issuing them an access token.
+/// Successful and failed logins are recorded in the audit log, as
+/// `auth.login` and `auth.login_failed`, and failed ones are counted towards
+/// blocking the client's address. Logins that register a device are also
+/// recorded as `auth.new_device`.
+///
+/// POST /_matrix/client/r0/login
+pub async fn login<T: Store>(
+    http_req: HttpRequest,
+    req: Json<model::LoginRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let storage = storage.get_ref();
+    let ip = proxy::client_ip(&http_req);
+    let password = match &req.challenge {
+        model::Challenge::Password { password } => password,
+        model::Challenge::Token { .. } => {
+            return Err(MatrixError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::UNKNOWN,
+                "Unsupported login type.",
+            )
+            .into())
+        }
+    };
+    let user_id = match &req.identifier {
+        model::UserIdentifier::UserId { user } => user.clone(),
+        _ => {
+            return Err(MatrixError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::UNKNOWN,
+                "Unsupported identifier type.",
+            )
+            .into())
         }
-        model::Challenge::Token { token } => {
-            unimplemented!("check OTP against user db") // TODO: will finish once user db model is complete
+    };
+
+    let account = if user_id.domain == CONFIG.hostname {
+        let hash = storage
+            .get_password_hash(&user_id.local_part)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+        match hash {
+            Some(hash) if uia::verify_password(&hash, password) => storage
+                .get_account(&user_id.local_part)
+                .await
+                .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?,
+            _ => None,
         }
+    } else {
+        None
     };
-    let device_id: String = unimplemented!("find or create device id");
+    let account = match account {
+        Some(account) => account,
+        None => {
+            audit::record(
+                storage,
+                Some(&user_id.to_string()),
+                ip,
+                "auth.login_failed",
+                &json!({ "device_id": req.device_id }),
+            )
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+            if let Some(ip) = ip {
+                access::record_failed_login(storage, ip)
+                    .await
+                    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+            }
+            return Err(MatrixError::new(
+                StatusCode::FORBIDDEN,
+                ErrorCode::FORBIDDEN,
+                "Invalid username or password.",
+            )
+            .into());
+        }
+    };
+    if account.deactivated {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::USER_DEACTIVATED,
+            "This account has been deactivated.",
+        )
+        .into());
+    }
+
+    let device_id = req.device_id.clone().unwrap_or_else(new_device_id);
+    let new_device = storage
+        .add_device(
+            &user_id.local_part,
+            &device_id,
+            req.initial_device_display_name.as_deref(),
+        )
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let details = json!({ "device_id": device_id });
+    audit::record(
+        storage,
+        Some(&user_id.to_string()),
+        ip,
+        "auth.login",
+        &details,
+    )
+    .await
+    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if new_device {
+        audit::record(
+            storage,
+            Some(&user_id.to_string()),
+            ip,
+            "auth.new_device",
+            &details,
+        )
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    }
+
     let access_token = jwt::encode(
         &jwt::Header::new(jwt::Algorithm::ES256),
         &Claims::new(&user_id, &device_id),
@@ -79,10 +194,16 @@ pub async fn login(req: Json<model::LoginRequest>) -> Result<HttpResponse, Error
         user_id,
         access_token,
         device_id,
-        well_known: model::DiscoveryInfo {
-            homeserver: model::HomeserverInfo {
-                base_url: Cow::Borrowed(&CONFIG.base_url),
-            },
-        },
+        well_known: model::DiscoveryInfo::from_config(),
     }))
 }
+
+/// A random ID for a device the client didn't name.
+pub fn new_device_id() -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .collect::<String>()
+        .to_uppercase()
+}
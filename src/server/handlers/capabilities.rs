@@ -0,0 +1,58 @@
+use actix_web::{Error, HttpResponse};
+use serde_json::{json, Map, Value};
+
+use crate::{federation::ROOM_VERSIONS, server::extract::Authenticated, CONFIG};
+
+/// What this server lets clients do, as set in its configuration.
+///
+/// TODO: Users can't change their 3PIDs yet, so `m.3pid_changes` only
+/// reflects what the server will allow once they can.
+///
+/// GET /_matrix/client/r0/capabilities
+/// GET /_matrix/client/v3/capabilities
+pub async fn get_capabilities(_auth: Authenticated) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(json!({
+        "capabilities": capabilities(
+            &CONFIG.default_room_version,
+            CONFIG.password_change_enabled,
+            CONFIG.threepid_changes_enabled,
+        )
+    })))
+}
+
+fn capabilities(
+    default_room_version: &str,
+    password_change_enabled: bool,
+    threepid_changes_enabled: bool,
+) -> Value {
+    let available: Map<String, Value> = ROOM_VERSIONS
+        .iter()
+        .map(|version| (version.to_string(), json!("stable")))
+        .collect();
+    json!({
+        "m.change_password": { "enabled": password_change_enabled },
+        "m.room_versions": {
+            "default": default_room_version,
+            "available": available,
+        },
+        "m.3pid_changes": { "enabled": threepid_changes_enabled },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = capabilities("5", false, true);
+        assert_eq!(capabilities["m.change_password"]["enabled"], false);
+        assert_eq!(capabilities["m.3pid_changes"]["enabled"], true);
+        assert_eq!(capabilities["m.room_versions"]["default"], "5");
+        let available = capabilities["m.room_versions"]["available"]
+            .as_object()
+            .unwrap();
+        assert_eq!(available.len(), ROOM_VERSIONS.len());
+        assert_eq!(available["6"], "stable");
+    }
+}
@@ -0,0 +1,185 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json, Path},
+    Error, HttpResponse,
+};
+
+use crate::{
+    db::Store,
+    models::dehydrated_device as model,
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+        handlers::{keys::store_keys, user::ignored_users},
+    },
+};
+
+/// The most to-device events returned for a dehydrated device at once.
+const EVENTS_LIMIT: i64 = 100;
+
+fn not_found() -> MatrixError {
+    MatrixError::new(
+        StatusCode::NOT_FOUND,
+        ErrorCode::NOT_FOUND,
+        "No dehydrated device available.",
+    )
+}
+
+/// Uploads a dehydrated device, along with its keys, replacing any the user
+/// already has. The device keeps receiving to-device messages while all of
+/// the user's other devices are logged out, and can later be rehydrated by
+/// a new login.
+///
+/// PUT /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device
+pub async fn put_dehydrated_device<T: Store>(
+    auth: Authenticated,
+    req: Json<model::PutRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let req = req.into_inner();
+    let localpart = &auth.user_id.local_part;
+
+    if req.device_data.get("algorithm").is_none() {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_PARAM,
+            "device_data must contain an algorithm.",
+        )
+        .into());
+    }
+
+    let current = storage
+        .get_dehydrated_device(localpart)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .map(|(device_id, _)| device_id);
+    let device_ids = storage
+        .get_device_ids(localpart)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if device_ids.contains(&req.device_id) && current.as_ref() != Some(&req.device_id) {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_PARAM,
+            "A device with this ID already exists.",
+        )
+        .into());
+    }
+
+    storage
+        .set_dehydrated_device(
+            localpart,
+            &req.device_id,
+            &req.device_data,
+            req.initial_device_display_name.as_deref(),
+        )
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    store_keys(storage.get_ref(), &auth.user_id, &req.device_id, req.keys).await?;
+    // Other users need to learn about both the removed and the new device
+    storage
+        .add_device_list_change(&auth.user_id.to_string())
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(model::DeviceIdResponse {
+        device_id: req.device_id,
+    }))
+}
+
+/// Gets the user's dehydrated device, so that a new login can rehydrate it.
+///
+/// GET /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device
+pub async fn get_dehydrated_device<T: Store>(
+    auth: Authenticated,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let (device_id, device_data) = storage
+        .get_dehydrated_device(&auth.user_id.local_part)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .ok_or_else(not_found)?;
+
+    Ok(HttpResponse::Ok().json(model::DehydratedDevice {
+        device_id,
+        device_data,
+    }))
+}
+
+/// Deletes the user's dehydrated device, along with its keys and any
+/// messages waiting for it.
+///
+/// DELETE /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device
+pub async fn delete_dehydrated_device<T: Store>(
+    auth: Authenticated,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let device_id = storage
+        .delete_dehydrated_device(&auth.user_id.local_part)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .ok_or_else(not_found)?;
+    storage
+        .add_device_list_change(&auth.user_id.to_string())
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(model::DeviceIdResponse { device_id }))
+}
+
+/// Fetches the to-device events sent to the dehydrated device while the user
+/// was offline. Passing `next_batch` acknowledges, and removes, every event
+/// returned up to that point.
+///
+/// POST /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device/{device_id}/events
+pub async fn get_events<T: Store>(
+    auth: Authenticated,
+    path: Path<model::EventsPath>,
+    req: Option<Json<model::EventsRequest>>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let localpart = &auth.user_id.local_part;
+    let req = req.map(Json::into_inner).unwrap_or_default();
+    let since = match &req.next_batch {
+        Some(next_batch) => next_batch
+            .parse::<i64>()
+            .with_codes(StatusCode::BAD_REQUEST, ErrorCode::INVALID_PARAM)?,
+        None => 0,
+    };
+
+    let current = storage
+        .get_dehydrated_device(localpart)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    match current {
+        Some((device_id, _)) if device_id == path.device_id => {}
+        _ => return Err(not_found().into()),
+    }
+
+    // Anything after this is left for the next request
+    let positions = storage
+        .get_stream_positions()
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    storage
+        .delete_to_device_messages(localpart, &path.device_id, since)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let messages = storage
+        .get_to_device_messages(
+            localpart,
+            &path.device_id,
+            since,
+            positions.to_device,
+            EVENTS_LIMIT,
+        )
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let next_batch = messages.last().map_or(since, |(stream_id, _)| *stream_id);
+
+    let ignored = ignored_users(storage.get_ref(), localpart).await?;
+    Ok(HttpResponse::Ok().json(model::EventsResponse {
+        events: ignored.filter_events(messages.into_iter().map(|(_, event)| event).collect()),
+        next_batch: next_batch.to_string(),
+    }))
+}
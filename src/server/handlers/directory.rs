@@ -0,0 +1,228 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    http::{Method, StatusCode},
+    web::{Data, Json, Query},
+    Error, HttpRequest, HttpResponse,
+};
+use serde_json::Value;
+
+use crate::{
+    cache::{self, Lru},
+    db::Store,
+    federation::client,
+    models::directory::{self as model, PublicRoomsRequest, PublicRoomsResponse},
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+        server_auth,
+    },
+    CONFIG,
+};
+
+/// The most rooms listed on one page.
+const MAX_PUBLIC_ROOMS: usize = 100;
+/// The largest page of another server's directory accepted, in bytes.
+const MAX_REMOTE_PAGE_SIZE: usize = 1024 * 1024;
+/// How long a page of another server's directory is remembered.
+const REMOTE_PAGE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+/// How many bytes of other servers' directories are cached.
+const REMOTE_CACHE_SIZE: usize = 4 * 1024 * 1024;
+
+lazy_static::lazy_static! {
+    /// Pages of other servers' directories, by server name and request, and
+    /// when they were fetched. Each process fetches its own.
+    static ref REMOTE_PAGES: Mutex<Lru<String, (Value, Instant)>> = Mutex::new(Lru::new(
+        REMOTE_CACHE_SIZE,
+        |key, (page, _)| key.len() + cache::json_size(page),
+    ));
+}
+
+/// Lists a page of the rooms published to this server's directory.
+///
+/// TODO: Match the search term against rooms' names, topics and aliases
+/// once room state is stored. Until then no room matches a search.
+async fn local_public_rooms<T: Store>(
+    storage: &T,
+    req: &PublicRoomsRequest,
+) -> Result<PublicRoomsResponse, Error> {
+    // No application service publishes rooms to a third party network's
+    // directory here
+    if req.third_party_instance_id.is_some() || !req.filter.is_empty() {
+        return Ok(PublicRoomsResponse::default());
+    }
+    let limit = req
+        .limit
+        .map_or(MAX_PUBLIC_ROOMS, |limit| limit.min(MAX_PUBLIC_ROOMS));
+    let offset: usize = match &req.since {
+        Some(since) => since
+            .parse()
+            .with_codes(StatusCode::BAD_REQUEST, ErrorCode::INVALID_PARAM)?,
+        None => 0,
+    };
+
+    let (chunk, total) = storage
+        .get_public_rooms(offset as i64, limit as i64)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let next = offset + chunk.len();
+    Ok(PublicRoomsResponse {
+        next_batch: if !chunk.is_empty() && (next as i64) < total {
+            Some(next.to_string())
+        } else {
+            None
+        },
+        prev_batch: if offset > 0 {
+            Some(offset.saturating_sub(limit).to_string())
+        } else {
+            None
+        },
+        total_room_count_estimate: Some(total),
+        chunk,
+    })
+}
+
+/// Asks another server for a page of its directory, or remembers the page
+/// from asking recently. Requests with a filter are sent as `POST`, as
+/// `GET` can't carry one.
+async fn remote_public_rooms(server: &str, req: &PublicRoomsRequest) -> Result<Value, Error> {
+    if !CONFIG.federation_policy.is_allowed(server) {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "Federation with this server is not allowed.",
+        )
+        .into());
+    }
+    let body = serde_json::to_value(req)
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let key = format!("{} {}", server, body);
+    if let Some((page, fetched)) = REMOTE_PAGES.lock().unwrap().get(&key) {
+        if fetched.elapsed() < REMOTE_PAGE_LIFETIME {
+            return Ok(page);
+        }
+    }
+
+    let uri = "/_matrix/federation/v1/publicRooms";
+    let res = if req.filter.is_empty() {
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        if let Some(limit) = req.limit {
+            query.append_pair("limit", &limit.to_string());
+        }
+        if let Some(since) = &req.since {
+            query.append_pair("since", since);
+        }
+        if req.include_all_networks {
+            query.append_pair("include_all_networks", "true");
+        }
+        if let Some(instance_id) = &req.third_party_instance_id {
+            query.append_pair("third_party_instance_id", instance_id);
+        }
+        let uri = format!("{}?{}", uri, query.finish());
+        client::request(Method::GET, server, &uri, None)
+            .await
+            .send()
+            .await
+    } else {
+        client::request(Method::POST, server, uri, Some(&body))
+            .await
+            .send_json(&body)
+            .await
+    };
+    let mut res = res.with_codes(StatusCode::BAD_GATEWAY, ErrorCode::UNKNOWN)?;
+    if !res.status().is_success() {
+        return Err(MatrixError::new(
+            StatusCode::BAD_GATEWAY,
+            ErrorCode::UNKNOWN,
+            format!("The server responded {}.", res.status()),
+        )
+        .into());
+    }
+    let page: PublicRoomsResponse = res
+        .json()
+        .limit(MAX_REMOTE_PAGE_SIZE)
+        .await
+        .with_codes(StatusCode::BAD_GATEWAY, ErrorCode::UNKNOWN)?;
+    let page = serde_json::to_value(page)
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    REMOTE_PAGES
+        .lock()
+        .unwrap()
+        .insert(key, (page.clone(), Instant::now()));
+    Ok(page)
+}
+
+/// Lists a page of this server's directory, or of another server's, asked
+/// for over federation.
+async fn public_rooms<T: Store>(
+    storage: &T,
+    server: Option<&str>,
+    req: &PublicRoomsRequest,
+) -> Result<HttpResponse, Error> {
+    match server {
+        Some(server) if server != CONFIG.hostname => {
+            Ok(HttpResponse::Ok().json(remote_public_rooms(server, req).await?))
+        }
+        _ => Ok(HttpResponse::Ok().json(local_public_rooms(storage, req).await?)),
+    }
+}
+
+/// Lists the public rooms of the server's room directory, or of the
+/// directory of the server named by `server`.
+///
+/// GET /_matrix/client/r0/publicRooms
+pub async fn get_public_rooms<T: Store>(
+    query: Query<model::PublicRoomsQuery>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+    let req = PublicRoomsRequest {
+        limit: query.limit,
+        since: query.since,
+        ..PublicRoomsRequest::default()
+    };
+    public_rooms(storage.get_ref(), query.server.as_deref(), &req).await
+}
+
+/// Lists the public rooms of the server's room directory, or of the
+/// directory of the server named by `server`, optionally filtered.
+///
+/// POST /_matrix/client/r0/publicRooms
+pub async fn post_public_rooms<T: Store>(
+    _auth: Authenticated,
+    query: Query<model::ServerQuery>,
+    req: Json<PublicRoomsRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    public_rooms(storage.get_ref(), query.server.as_deref(), &req).await
+}
+
+/// Lists the public rooms of the server's room directory for another
+/// server, whose users are browsing it.
+///
+/// GET /_matrix/federation/v1/publicRooms
+pub async fn get_federation_public_rooms<T: Store>(
+    req: HttpRequest,
+    query: Query<PublicRoomsRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    server_auth::authenticate(storage.get_ref(), &req, None).await?;
+    Ok(HttpResponse::Ok().json(local_public_rooms(storage.get_ref(), &query).await?))
+}
+
+/// Lists the public rooms of the server's room directory for another
+/// server, whose users are browsing it, optionally filtered.
+///
+/// POST /_matrix/federation/v1/publicRooms
+pub async fn post_federation_public_rooms<T: Store>(
+    req: HttpRequest,
+    body: Json<Value>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    server_auth::authenticate(storage.get_ref(), &req, Some(&*body)).await?;
+    let request: PublicRoomsRequest = serde_json::from_value(body.into_inner())
+        .with_codes(StatusCode::BAD_REQUEST, ErrorCode::BAD_JSON)?;
+    Ok(HttpResponse::Ok().json(local_public_rooms(storage.get_ref(), &request).await?))
+}
@@ -0,0 +1,613 @@
+use std::collections::{BTreeMap, HashSet};
+
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json, Path},
+    Error, HttpRequest, HttpResponse,
+};
+use serde_json::Value;
+
+use crate::{
+    appservice,
+    bus::BUS,
+    clock::now_ms,
+    db::Store,
+    federation::{self, keys, signing},
+    models::{
+        auth::UserId,
+        federation as model,
+        presence::{Presence, PRESENCE_STATES},
+        room,
+    },
+    room_summary,
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        handlers::to_device,
+        server_auth,
+    },
+    CONFIG,
+};
+
+/// The most PDUs a transaction may carry.
+const MAX_PDUS: usize = 50;
+/// The most EDUs a transaction may carry.
+const MAX_EDUS: usize = 100;
+/// The most events returned by one backfill or missing events request.
+const MAX_HISTORY_EVENTS: usize = 100;
+/// How long other servers may cache our keys for, in milliseconds.
+const KEY_VALIDITY: i64 = 24 * 60 * 60 * 1000;
+/// Why a well-formed PDU is rejected, until room events are supported.
+const PDUS_UNSUPPORTED: &str = "Room events are not supported by this server.";
+
+fn unknown_room() -> Error {
+    MatrixError::new(
+        StatusCode::NOT_FOUND,
+        ErrorCode::NOT_FOUND,
+        "Room is not known to this server.",
+    )
+    .into()
+}
+
+/// Checks that an event was signed by `server_name`, in its redacted form,
+/// with a key that was valid when the event was sent.
+async fn check_signature<T: Store>(
+    storage: &T,
+    pdu: &Value,
+    server_name: &str,
+) -> Result<(), String> {
+    let origin_server_ts = pdu
+        .get("origin_server_ts")
+        .and_then(Value::as_i64)
+        .unwrap_or(0);
+    let verify_keys = keys::get_verify_keys(storage, server_name, origin_server_ts)
+        .await
+        .map_err(|e| e.to_string())?;
+    signing::verify_signed_by(&signing::redact(pdu), server_name, &verify_keys)
+        .map_err(|e| format!("Signature check failed: {}", e))
+}
+
+/// Checks a membership event another server sent to be signed or accepted
+/// directly, rather than in a transaction: it must be about one of the
+/// requesting server's users, have the given membership and be signed by
+/// the server.
+async fn check_membership_event<T: Store>(
+    storage: &T,
+    origin: &str,
+    room_id: &str,
+    event: &Value,
+    membership: &str,
+) -> Result<(), Error> {
+    let bad_event = |error: &str| -> Error {
+        MatrixError::new(StatusCode::BAD_REQUEST, ErrorCode::BAD_JSON, error).into()
+    };
+    let sender = event
+        .get("sender")
+        .and_then(Value::as_str)
+        .ok_or_else(|| bad_event("Event has no sender."))?;
+    if event.get("room_id").and_then(Value::as_str) != Some(room_id) {
+        return Err(bad_event("Event is for another room."));
+    }
+    if event.get("type").and_then(Value::as_str) != Some("m.room.member")
+        || event.pointer("/content/membership").and_then(Value::as_str) != Some(membership)
+    {
+        return Err(bad_event(&format!("Event is not a {} event.", membership)));
+    }
+    if event.get("state_key").and_then(Value::as_str) != Some(sender) {
+        return Err(bad_event("Event's state key does not match its sender."));
+    }
+    if UserId::parse(sender).domain != origin {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "Event is not from the requesting server.",
+        )
+        .into());
+    }
+    check_signature(storage, event, origin)
+        .await
+        .with_codes(StatusCode::FORBIDDEN, ErrorCode::FORBIDDEN)?;
+    Ok(())
+}
+
+/// Checks that the event ID an event was sent with is its ID.
+fn check_event_id(event: &Value, event_id: &str) -> Result<(), Error> {
+    // Room versions 1 and 2 carry the event ID, later versions derive it from
+    // the event's reference hash, which room version 3 encodes as standard
+    // rather than URL-safe base64
+    let matches = match event.get("event_id").and_then(Value::as_str) {
+        Some(id) => id == event_id,
+        None => {
+            event_id == signing::event_id(event)
+                || event_id == format!("${}", signing::reference_hash(event))
+        }
+    };
+    if !matches {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::BAD_JSON,
+            "Event ID does not match the event.",
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// The ID of an event received from another server. Room versions 1 and 2
+/// carry the event ID, later versions derive it from the event's reference
+/// hash.
+fn pdu_event_id(pdu: &Value) -> String {
+    pdu.get("event_id")
+        .and_then(Value::as_str)
+        .map_or_else(|| signing::event_id(pdu), str::to_owned)
+}
+
+/// Checks a PDU received from another server, returning why it was
+/// rejected.
+///
+/// Room events are not supported yet, see "Deferred Features" in the
+/// README: there is no room state to evaluate the auth rules against, so
+/// every PDU is rejected as unsupported once its sender, signatures and
+/// hashes are checked, and none is ever persisted. Forged or malformed PDUs
+/// are rejected with what is wrong with them instead.
+async fn handle_pdu<T: Store>(storage: &T, pdu: &Value) -> Result<(), String> {
+    let sender = match pdu.get("sender").and_then(Value::as_str) {
+        Some(sender) => UserId::parse(sender),
+        None => return Err("Event has no sender.".to_owned()),
+    };
+    if pdu.get("room_id").and_then(Value::as_str).is_none() {
+        return Err("Event has no room ID.".to_owned());
+    }
+
+    check_signature(storage, pdu, &sender.domain).await?;
+
+    // An event whose content doesn't match its hash is processed as if it
+    // had been redacted
+    let _event = if signing::check_content_hash(pdu) {
+        pdu.clone()
+    } else {
+        signing::redact(pdu)
+    };
+
+    Err(PDUS_UNSUPPORTED.to_owned())
+}
+
+/// Handles an EDU received from `origin`. EDUs can't be rejected, so
+/// malformed ones are dropped; only storage errors are returned.
+///
+/// Typing notifications and receipts are dropped: they are room events, which
+/// are not supported yet, see "Deferred Features" in the README.
+async fn handle_edu<T: Store>(storage: &T, origin: &str, edu: model::Edu) -> Result<(), Error> {
+    match edu.edu_type.as_str() {
+        "m.direct_to_device" => {
+            let content: model::DirectToDevice = match serde_json::from_value(edu.content) {
+                Ok(content) => content,
+                Err(_) => return Ok(()),
+            };
+            // Servers may only send messages on behalf of their own users
+            if UserId::parse(&content.sender).domain != origin {
+                return Ok(());
+            }
+            let messages = to_device::local_messages(storage, &content.messages).await?;
+            // Remote senders have no device here; the message ID is unique
+            // per sending server
+            storage
+                .add_to_device_messages(
+                    &content.sender,
+                    "",
+                    &content.message_id,
+                    &content.event_type,
+                    &messages,
+                )
+                .await
+                .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+            BUS.new_data(messages.iter().map(|message| message.localpart.as_str()));
+        }
+        // TODO: Cache remote users' device lists
+        "m.device_list_update" | "m.signing_key_update" => {
+            let content: model::DeviceListUpdate = match serde_json::from_value(edu.content) {
+                Ok(content) => content,
+                Err(_) => return Ok(()),
+            };
+            if UserId::parse(&content.user_id).domain != origin {
+                return Ok(());
+            }
+            storage
+                .add_device_list_change(&content.user_id)
+                .await
+                .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+        }
+        "m.presence" => {
+            let content: model::PresenceEdu = match serde_json::from_value(edu.content) {
+                Ok(content) => content,
+                Err(_) => return Ok(()),
+            };
+            let now = now_ms();
+            for update in content.push {
+                let valid = UserId::parse(&update.user_id).domain == origin
+                    && PRESENCE_STATES.contains(&update.presence.as_str());
+                if !valid {
+                    continue;
+                }
+                let presence = Presence {
+                    user_id: update.user_id,
+                    presence: update.presence,
+                    status_msg: update.status_msg,
+                    last_active_ts: now - update.last_active_ago.max(0),
+                    currently_active: update.currently_active,
+                };
+                storage
+                    .set_presence(&presence)
+                    .await
+                    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Push messages representing live activity to another server.
+///
+/// The request must be signed by the sending server with `X-Matrix`
+/// authorization. EDUs are dispatched to the subsystems they are for. Each
+/// PDU's signatures and hashes are checked, but as room events aren't
+/// supported yet every PDU is then rejected as unsupported, see
+/// `handle_pdu`. Retried transactions
+/// are answered with the response to the original, without being processed
+/// again, and are remembered for `FEDERATION_REPLAY_WINDOW`.
+///
+/// PUT /_matrix/federation/v1/send/{txnId}
+pub async fn send_transaction<T: Store>(
+    req: HttpRequest,
+    path: Path<model::TransactionPath>,
+    body: Json<Value>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let origin = server_auth::authenticate(storage.get_ref(), &req, Some(&*body)).await?;
+    let txn: model::Transaction = serde_json::from_value(body.into_inner())
+        .with_codes(StatusCode::BAD_REQUEST, ErrorCode::BAD_JSON)?;
+    if txn.origin != origin {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "Transaction origin does not match the signing server.",
+        )
+        .into());
+    }
+    if txn.pdus.len() > MAX_PDUS || txn.edus.len() > MAX_EDUS {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::BAD_JSON,
+            format!(
+                "Transactions may carry at most {} PDUs and {} EDUs.",
+                MAX_PDUS, MAX_EDUS
+            ),
+        )
+        .into());
+    }
+
+    if let Some(response) = storage
+        .get_received_transaction(&origin, &path.txn_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+    {
+        return Ok(HttpResponse::Ok().json(response));
+    }
+
+    let event_ids: Vec<String> = txn.pdus.iter().map(pdu_event_id).collect();
+    let received: HashSet<String> = storage
+        .get_received_pdus(&event_ids)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .into_iter()
+        .collect();
+    let mut pdus = BTreeMap::new();
+    // Always empty until `handle_pdu` can accept PDUs
+    let mut accepted = Vec::new();
+    for (pdu, event_id) in txn.pdus.iter().zip(event_ids) {
+        // Duplicates within the transaction get the first one's result
+        if received.contains(&event_id) || pdus.contains_key(&event_id) {
+            pdus.entry(event_id)
+                .or_insert(model::PduResult { error: None });
+            continue;
+        }
+        let result = handle_pdu(storage.get_ref(), pdu).await;
+        if result.is_ok() {
+            accepted.push(event_id.clone());
+        }
+        pdus.insert(
+            event_id,
+            model::PduResult {
+                error: result.err(),
+            },
+        );
+    }
+    if !accepted.is_empty() {
+        storage
+            .add_received_pdus(&origin, &accepted)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    }
+    for edu in txn.edus {
+        handle_edu(storage.get_ref(), &origin, edu).await?;
+    }
+
+    let response = serde_json::to_value(model::TransactionResponse { pdus })
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    storage
+        .set_received_transaction(&origin, &path.txn_id, &response)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Retrieves a sliding window of history of the room, going backwards from
+/// the events given by the `v` query parameters, which may be repeated.
+///
+/// TODO: Walk the room's event graph once rooms exist. Only servers in the
+/// room, and events whose history visibility allows it, may be served. The
+/// other side, fetching history from remote servers when a client scrolls
+/// back past what is stored, needs the same.
+///
+/// GET /_matrix/federation/v1/backfill/{roomId}
+pub async fn backfill<T: Store>(
+    req: HttpRequest,
+    _path: Path<model::RoomPath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    server_auth::authenticate(storage.get_ref(), &req, None).await?;
+
+    let params: Vec<(String, String)> = url::form_urlencoded::parse(req.query_string().as_bytes())
+        .into_owned()
+        .collect();
+    let from: Vec<&str> = params
+        .iter()
+        .filter(|(name, _)| name == "v")
+        .map(|(_, event_id)| event_id.as_str())
+        .collect();
+    if from.is_empty() {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::MISSING_PARAM,
+            "At least one event to backfill from is required.",
+        )
+        .into());
+    }
+    let limit: usize = params
+        .iter()
+        .find(|(name, _)| name == "limit")
+        .ok_or_else(|| {
+            MatrixError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::MISSING_PARAM,
+                "Missing limit parameter.",
+            )
+        })?
+        .1
+        .parse()
+        .with_codes(StatusCode::BAD_REQUEST, ErrorCode::INVALID_PARAM)?;
+    let _limit = limit.min(MAX_HISTORY_EVENTS);
+
+    Err(unknown_room())
+}
+
+/// Retrieves previous events that the sender is missing, walking back from
+/// `latest_events` until `earliest_events` or `min_depth` are reached.
+///
+/// TODO: Walk the room's event graph once rooms exist, as for backfill.
+///
+/// POST /_matrix/federation/v1/get_missing_events/{roomId}
+pub async fn get_missing_events<T: Store>(
+    req: HttpRequest,
+    _path: Path<model::RoomPath>,
+    body: Json<Value>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    server_auth::authenticate(storage.get_ref(), &req, Some(&*body)).await?;
+    let request: model::MissingEventsRequest = serde_json::from_value(body.into_inner())
+        .with_codes(StatusCode::BAD_REQUEST, ErrorCode::BAD_JSON)?;
+    let _limit = request.limit.min(MAX_HISTORY_EVENTS);
+
+    Err(unknown_room())
+}
+
+/// Asks the server for an event template a remote user can join the room
+/// with. `ver` query parameters list the room versions the requesting
+/// server supports.
+///
+/// TODO: Build the template from the room's current state once rooms
+/// exist, answering `M_INCOMPATIBLE_ROOM_VERSION` if the room's version is
+/// not among those supported.
+///
+/// GET /_matrix/federation/v1/make_join/{roomId}/{userId}
+pub async fn make_join<T: Store>(
+    req: HttpRequest,
+    path: Path<model::MakeMembershipPath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let origin = server_auth::authenticate(storage.get_ref(), &req, None).await?;
+    if UserId::parse(&path.user_id).domain != origin {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "User is not from the requesting server.",
+        )
+        .into());
+    }
+
+    Err(unknown_room())
+}
+
+/// Submits a signed join event to the server, which accepts it into the
+/// room and returns the room's state and auth chain.
+///
+/// TODO: Check the event against the auth rules, persist and send it to the
+/// other servers in the room once rooms exist. Version 1 of the endpoint
+/// wraps its response as `[200, response]`.
+///
+/// PUT /_matrix/federation/v2/send_join/{roomId}/{eventId}
+pub async fn send_join<T: Store>(
+    req: HttpRequest,
+    path: Path<model::EventPath>,
+    body: Json<Value>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let origin = server_auth::authenticate(storage.get_ref(), &req, Some(&*body)).await?;
+    check_membership_event(storage.get_ref(), &origin, &path.room_id, &body, "join").await?;
+    check_event_id(&body, &path.event_id)?;
+
+    Err(unknown_room())
+}
+
+/// Invites a local user to a room on another server. The invite is signed
+/// by this server and returned, and the user is shown the stripped state of
+/// the room in their syncs until they respond. Application services
+/// interested in the invite are sent it.
+///
+/// TODO: Let the user accept or reject the invite once remote joins are
+/// possible, and invite remote users from local rooms once rooms exist.
+///
+/// PUT /_matrix/federation/v2/invite/{roomId}/{eventId}
+pub async fn invite<T: Store>(
+    req: HttpRequest,
+    path: Path<model::EventPath>,
+    body: Json<Value>,
+    storage: Data<T>,
+    appservice_notifier: Data<appservice::sender::Notifier>,
+) -> Result<HttpResponse, Error> {
+    let origin = server_auth::authenticate(storage.get_ref(), &req, Some(&*body)).await?;
+    let request: model::InviteRequest = serde_json::from_value(body.into_inner())
+        .with_codes(StatusCode::BAD_REQUEST, ErrorCode::BAD_JSON)?;
+    // The user couldn't join a room of another version
+    if !federation::ROOM_VERSIONS.contains(&request.room_version.as_str()) {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INCOMPATIBLE_ROOM_VERSION,
+            format!("Room version {} is not supported.", request.room_version),
+        )
+        .into());
+    }
+
+    if storage
+        .is_room_blocked(&path.room_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+    {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "This room has been blocked on this server.",
+        )
+        .into());
+    }
+
+    let mut event = request.event;
+    let bad_event = |error: &str| -> Error {
+        MatrixError::new(StatusCode::BAD_REQUEST, ErrorCode::BAD_JSON, error).into()
+    };
+    if event.get("room_id").and_then(Value::as_str) != Some(&path.room_id) {
+        return Err(bad_event("Event is for another room."));
+    }
+    if event.get("type").and_then(Value::as_str) != Some("m.room.member")
+        || event.pointer("/content/membership").and_then(Value::as_str) != Some("invite")
+    {
+        return Err(bad_event("Event is not an invite event."));
+    }
+    let sender = event
+        .get("sender")
+        .and_then(Value::as_str)
+        .map(UserId::parse)
+        .ok_or_else(|| bad_event("Event has no sender."))?;
+    if sender.domain != origin {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "Event is not from the requesting server.",
+        )
+        .into());
+    }
+    let invitee = event
+        .get("state_key")
+        .and_then(Value::as_str)
+        .map(UserId::parse)
+        .ok_or_else(|| bad_event("Event has no state key."))?;
+    if !invitee.is_local()
+        || storage
+            .is_username_available(&invitee.local_part)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+    {
+        return Err(MatrixError::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::NOT_FOUND,
+            "Invited user does not exist on this server.",
+        )
+        .into());
+    }
+    check_event_id(&event, &path.event_id)?;
+    check_signature(storage.get_ref(), &event, &origin)
+        .await
+        .with_codes(StatusCode::FORBIDDEN, ErrorCode::FORBIDDEN)?;
+
+    CONFIG
+        .signing_key
+        .countersign_event(&CONFIG.hostname, &mut event);
+    storage
+        .add_room_invite(
+            &invitee.local_part,
+            &model::RoomInvite {
+                room_id: path.room_id.clone(),
+                event: event.clone(),
+                invite_room_state: request.invite_room_state,
+            },
+        )
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    room_summary::set_membership(
+        storage.get_ref(),
+        &path.room_id,
+        &invitee.to_string(),
+        Some(room::INVITE),
+    )
+    .await
+    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    BUS.new_data(vec![invitee.local_part.as_str()]);
+    appservice::queue_event(
+        storage.get_ref(),
+        &CONFIG.appservices,
+        &appservice_notifier,
+        &event,
+    )
+    .await
+    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(model::InviteResponse { event }))
+}
+
+/// Gets the homeserver's published signing keys, signed with them. The key
+/// ID in the path is deprecated and ignored; all keys are always returned.
+///
+/// GET /_matrix/key/v2/server
+pub async fn get_server_keys() -> Result<HttpResponse, Error> {
+    let now = now_ms();
+    let mut verify_keys = BTreeMap::new();
+    verify_keys.insert(
+        CONFIG.signing_key.key_id.clone(),
+        model::VerifyKey {
+            key: CONFIG.signing_key.public_key(),
+        },
+    );
+    let mut response = serde_json::to_value(model::ServerKeys {
+        server_name: CONFIG.hostname.clone(),
+        verify_keys,
+        old_verify_keys: BTreeMap::new(),
+        valid_until_ts: now + KEY_VALIDITY,
+    })
+    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    CONFIG
+        .signing_key
+        .sign_json(&CONFIG.hostname, &mut response);
+
+    Ok(HttpResponse::Ok().json(response))
+}
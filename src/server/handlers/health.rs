@@ -0,0 +1,86 @@
+use actix_web::{web::Data, Error, HttpResponse};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    db::Store,
+    federation::signing::{decode_base64, verify_bytes},
+    CONFIG,
+};
+
+/// What the signing key is checked with.
+const PROBE: &[u8] = b"maelstrom health check";
+
+/// Whether the process is alive. Answers as long as the server can answer
+/// requests at all, so supervisors restart it only if it hangs.
+///
+/// GET /health/live
+pub async fn get_live() -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok" })))
+}
+
+/// Whether the server is ready to serve requests: the database can be
+/// reached and has the whole schema, and the signing key can sign. Responds
+/// with 503 Service Unavailable, and what failed, otherwise.
+///
+/// GET /health/ready
+pub async fn get_ready<T: Store>(storage: Data<T>) -> Result<HttpResponse, Error> {
+    let mut checks = Map::new();
+    let (database, schema) = match storage.check_schema().await {
+        Ok(missing) if missing.is_empty() => (Ok(()), Ok(())),
+        Ok(missing) => (
+            Ok(()),
+            Err(format!("Missing tables: {}", missing.join(", "))),
+        ),
+        Err(e) => (Err(e.to_string()), Err("Unknown".to_owned())),
+    };
+    let signing_key = check_signing_key();
+
+    let mut ready = true;
+    for (name, check) in [
+        ("database", database),
+        ("schema", schema),
+        ("signing_key", signing_key),
+    ]
+    .iter()
+    {
+        let status = match check {
+            Ok(()) => "ok".to_owned(),
+            Err(e) => {
+                ready = false;
+                e.clone()
+            }
+        };
+        checks.insert((*name).to_owned(), Value::String(status));
+    }
+
+    let mut res = if ready {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    Ok(res.json(json!({
+        "status": if ready { "ok" } else { "unavailable" },
+        "checks": checks,
+    })))
+}
+
+/// Signs a probe with the signing key and verifies it with the public key
+/// other servers are given.
+fn check_signing_key() -> Result<(), String> {
+    let key = &CONFIG.signing_key;
+    let signature = decode_base64(&key.sign(PROBE)).ok_or("Unable to decode signature")?;
+    let public_key = decode_base64(&key.public_key()).ok_or("Unable to decode public key")?;
+    verify_bytes(PROBE, &signature, &public_key).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http;
+
+    #[actix_rt::test]
+    async fn test_get_live_ok() {
+        let resp = get_live().await;
+        assert_eq!(resp.unwrap().status(), http::StatusCode::OK);
+    }
+}
@@ -0,0 +1,177 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json, Path},
+    Error, HttpResponse,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    db::Store,
+    identity::{self, StoreInviteRequest},
+    models::{
+        auth::UserId,
+        room::{self as model, InviteRequest, ThirdPartyInvite},
+    },
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+    },
+};
+
+/// Invites the owner of an email address to a room the requester has
+/// joined, through the identity server the client names. The identity
+/// server stores the invite and emails the address, and tells the owner's
+/// homeserver once they bind the address to their Matrix ID.
+///
+/// TODO: Send the `m.room.third_party_invite` event, and invite users by
+/// ID, once events are stored. Look the address up on the identity server
+/// first, to invite an owner who bound it already directly.
+///
+/// POST /_matrix/client/r0/rooms/{roomId}/invite
+pub async fn invite<T: Store>(
+    auth: Authenticated,
+    path: Path<model::RoomPath>,
+    req: Json<InviteRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let sender = auth.user_id.to_string();
+    let membership = storage
+        .get_room_membership(&path.room_id, &sender)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if membership.as_deref() != Some(model::JOIN) {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "You are not in this room.",
+        )
+        .into());
+    }
+    let (id_server, id_access_token, medium, address) = match &*req {
+        InviteRequest::ThirdParty {
+            id_server,
+            id_access_token,
+            medium,
+            address,
+        } => (id_server, id_access_token, medium, address),
+        InviteRequest::User { user_id } => {
+            return Err(MatrixError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::UNRECOGNIZED,
+                format!(
+                    "Unable to invite {}: users can't be invited by ID yet.",
+                    user_id
+                ),
+            )
+            .into())
+        }
+    };
+    if medium != "email" {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::UNRECOGNIZED,
+            "Only email addresses can be invited.",
+        )
+        .into());
+    }
+    if !identity::is_valid_server_name(id_server) {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_PARAM,
+            "id_server must be a hostname.",
+        )
+        .into());
+    }
+
+    let profile = storage
+        .get_profile(&auth.user_id.local_part)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let stored = identity::store_invite(
+        id_server,
+        id_access_token,
+        &StoreInviteRequest {
+            medium,
+            address,
+            room_id: &path.room_id,
+            sender: &sender,
+            sender_display_name: profile.displayname.as_deref(),
+            sender_avatar_url: profile.avatar_url.as_deref(),
+        },
+    )
+    .await
+    .with_codes(StatusCode::BAD_GATEWAY, ErrorCode::UNKNOWN)?;
+    storage
+        .add_third_party_invite(&ThirdPartyInvite {
+            room_id: path.room_id.clone(),
+            token: stored.token,
+            sender,
+            display_name: stored.display_name,
+            public_keys: stored.public_keys,
+        })
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Takes an identity server's word that a third party identifier was bound
+/// to a local user, along with the invites it stored for the identifier.
+/// An invite is accepted if the identity server signed it, for this user,
+/// with one of the keys it gave when the invite was stored.
+///
+/// TODO: Invite the user with an `m.room.member` event carrying the signed
+/// invite once events are stored, and have the servers of rooms on other
+/// servers do so through `/exchange_third_party_invite`. Until then an
+/// accepted invite is only forgotten.
+///
+/// PUT /_matrix/federation/v1/3pid/onbind
+pub async fn on_bind<T: Store>(
+    req: Json<model::OnBindRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    if !UserId::parse(&req.mxid).is_local() {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_PARAM,
+            "The user is not on this server.",
+        )
+        .into());
+    }
+
+    for invite in &req.invites {
+        let field = |name: &str| invite.signed.get(name).and_then(Value::as_str);
+        let token = match field("token") {
+            Some(token) if invite.mxid == req.mxid && field("mxid") == Some(req.mxid.as_str()) => {
+                token
+            }
+            _ => {
+                tracing::warn!(room_id = %invite.room_id, "Third party invite for another user");
+                continue;
+            }
+        };
+        let pending = storage
+            .get_third_party_invite(&invite.room_id, token)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+        match pending {
+            Some(pending) if identity::verify_signed(&invite.signed, &pending.public_keys) => {
+                storage
+                    .delete_third_party_invite(&invite.room_id, token)
+                    .await
+                    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+                tracing::info!(
+                    room_id = %invite.room_id,
+                    user_id = %req.mxid,
+                    "Third party invite bound"
+                );
+            }
+            Some(_) => {
+                tracing::warn!(room_id = %invite.room_id, "Third party invite has a bad signature")
+            }
+            None => tracing::debug!(room_id = %invite.room_id, "Unknown third party invite"),
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
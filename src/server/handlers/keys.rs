@@ -0,0 +1,725 @@
+use actix_web::{
+    http::{Method, StatusCode},
+    web::{Data, Json, Query},
+    Error, HttpResponse,
+};
+use futures::future;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use crate::{
+    audit,
+    db::Store,
+    federation::{
+        client,
+        signing::{self, SignatureError},
+    },
+    models::{
+        auth::UserId,
+        keys::{self as model, KeySignature, OneTimeKey},
+        sync::{KeyChangesParams, SyncToken},
+    },
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+        uia,
+    },
+    CONFIG,
+};
+
+/// How long other servers are given to answer a query or claim, unless the
+/// client says otherwise.
+const DEFAULT_REMOTE_TIMEOUT: u64 = 10_000;
+/// The longest a client may have other servers given.
+const MAX_REMOTE_TIMEOUT: u64 = 60_000;
+/// The largest response accepted from another server, in bytes.
+const MAX_REMOTE_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// The failure reported for another server that couldn't give keys.
+fn remote_failure(status: u16, message: &str) -> Value {
+    json!({
+        "status": status,
+        "message": message,
+    })
+}
+
+/// Sends a keys query or claim, `kind`, to another server, within
+/// `timeout_ms` of the client's request. Gives the failure to report for the
+/// server if it can't be asked, doesn't answer in time, or refuses.
+async fn remote_keys<R: DeserializeOwned>(
+    server: &str,
+    kind: &str,
+    body: Value,
+    timeout_ms: Option<u64>,
+) -> Result<R, Value> {
+    if !CONFIG.federation_policy.is_allowed(server) {
+        return Err(remote_failure(
+            403,
+            "Federation with this server is not allowed.",
+        ));
+    }
+    let uri = format!("/_matrix/federation/v1/user/keys/{}", kind);
+    let send = async {
+        let mut res = client::request(Method::POST, server, &uri, Some(&body))
+            .await
+            .send_json(&body)
+            .await
+            .map_err(|e| remote_failure(503, &e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(remote_failure(
+                res.status().as_u16(),
+                &format!("The server responded {}.", res.status()),
+            ));
+        }
+        res.json()
+            .limit(MAX_REMOTE_RESPONSE_SIZE)
+            .await
+            .map_err(|e| remote_failure(502, &e.to_string()))
+    };
+    let timeout = timeout_ms
+        .unwrap_or(DEFAULT_REMOTE_TIMEOUT)
+        .min(MAX_REMOTE_TIMEOUT);
+    actix_rt::time::timeout(Duration::from_millis(timeout), send)
+        .await
+        .unwrap_or_else(|_| Err(remote_failure(504, "The server did not respond in time.")))
+}
+
+/// Checks the signature `signer` made on a key object with their key
+/// `signing_key_id`, whose public key is `public_key` in unpadded base64.
+fn verify_key_signature(
+    key: &Value,
+    signer: &str,
+    signing_key_id: &str,
+    public_key: &str,
+) -> Result<(), SignatureError> {
+    let public_key = signing::decode_base64(public_key).ok_or(SignatureError::Malformed)?;
+    signing::verify_json(key, signer, signing_key_id, &public_key)
+}
+
+/// Stores uploaded identity, one-time and fallback keys for one of the
+/// user's devices.
+///
+/// Device identity keys must belong to that device. One-time keys are added
+/// to the device's pool, and fallback keys replace any previously uploaded
+/// fallback key of the same algorithm.
+pub async fn store_keys<T: Store>(
+    storage: &T,
+    user_id: &UserId,
+    device_id: &str,
+    req: model::UploadRequest,
+) -> Result<(), Error> {
+    let localpart = &user_id.local_part;
+
+    if let Some(device_keys) = &req.device_keys {
+        let key_user_id = device_keys.get("user_id").and_then(Value::as_str);
+        let key_device_id = device_keys.get("device_id").and_then(Value::as_str);
+        let expected_user_id = user_id.to_string();
+        if key_user_id != Some(expected_user_id.as_str()) || key_device_id != Some(device_id) {
+            return Err(MatrixError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::INVALID_PARAM,
+                "Device keys must match the authenticated user and device.",
+            )
+            .into());
+        }
+        storage
+            .set_device_keys(localpart, device_id, device_keys)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+        storage
+            .add_device_list_change(&expected_user_id)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    }
+
+    let invalid_key = |name: String| {
+        MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::BAD_JSON,
+            format!("Key name '{}' is not of the form algorithm:key_id.", name),
+        )
+    };
+    let one_time_keys = OneTimeKey::from_key_map(req.one_time_keys).map_err(invalid_key)?;
+    let fallback_keys = OneTimeKey::from_key_map(req.fallback_keys).map_err(invalid_key)?;
+
+    if !one_time_keys.is_empty() {
+        storage
+            .add_one_time_keys(localpart, device_id, &one_time_keys)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    }
+    if !fallback_keys.is_empty() {
+        storage
+            .set_fallback_keys(localpart, device_id, &fallback_keys)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    }
+
+    Ok(())
+}
+
+/// Publishes end-to-end encryption keys for the device.
+///
+/// Device identity keys must belong to the device the access token was
+/// issued for. One-time keys are added to the device's pool, and fallback
+/// keys replace any previously uploaded fallback key of the same algorithm.
+///
+/// POST /_matrix/client/r0/keys/upload
+pub async fn upload<T: Store>(
+    auth: Authenticated,
+    req: Json<model::UploadRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    store_keys(
+        storage.get_ref(),
+        &auth.user_id,
+        &auth.device_id,
+        req.into_inner(),
+    )
+    .await?;
+
+    let one_time_key_counts = storage
+        .count_one_time_keys(&auth.user_id.local_part, &auth.device_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(model::UploadResponse {
+        one_time_key_counts,
+    }))
+}
+
+/// Returns the current devices and identity keys for the given users, along
+/// with their cross-signing keys. A user's user-signing key is only returned
+/// to that user, and signatures made by other users are only visible to the
+/// user who made them.
+///
+/// Users on other servers are queried over federation, each server at once.
+/// A server that can't be reached is listed in `failures`, and what a
+/// server says about users of another is ignored.
+///
+/// POST /_matrix/client/r0/keys/query
+pub async fn query<T: Store>(
+    auth: Authenticated,
+    req: Json<model::QueryRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let mut res = model::QueryResponse::default();
+    let requester = auth.user_id.to_string();
+    let mut remote: BTreeMap<String, BTreeMap<String, Vec<String>>> = BTreeMap::new();
+
+    for (user_id, device_ids) in &req.device_keys {
+        let parsed = UserId::parse(user_id);
+        if !parsed.is_local() {
+            remote
+                .entry(parsed.domain.into_owned())
+                .or_default()
+                .insert(user_id.clone(), device_ids.clone());
+            continue;
+        }
+        let user_id = parsed.to_string();
+        let signatures: Vec<KeySignature> = storage
+            .get_key_signatures(&user_id)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+            .into_iter()
+            .filter(|sig| sig.signer == user_id || sig.signer == requester)
+            .collect();
+        let signatures_for = |target_id: &str| {
+            signatures
+                .iter()
+                .filter(move |sig| sig.target_id == target_id)
+                .collect::<Vec<_>>()
+        };
+
+        let keys = storage
+            .get_device_keys(&parsed.local_part, device_ids)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+        let devices = keys
+            .into_iter()
+            .map(|(device_id, mut key)| {
+                model::add_signatures(&mut key, signatures_for(&device_id));
+                (device_id, key)
+            })
+            .collect();
+        res.device_keys.insert(user_id.clone(), devices);
+
+        let cross_signing_keys = storage
+            .get_cross_signing_keys(&parsed.local_part)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+        for (usage, mut key) in cross_signing_keys {
+            if let Some(public_key) = model::public_key(&key).map(str::to_owned) {
+                model::add_signatures(&mut key, signatures_for(&public_key));
+            }
+            match usage.as_str() {
+                model::MASTER => {
+                    res.master_keys.insert(user_id.clone(), key);
+                }
+                model::SELF_SIGNING => {
+                    res.self_signing_keys.insert(user_id.clone(), key);
+                }
+                model::USER_SIGNING if user_id == requester => {
+                    res.user_signing_keys.insert(user_id.clone(), key);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let timeout = req.timeout;
+    let queries = remote.into_iter().map(|(server, device_keys)| async move {
+        let body = json!({ "device_keys": device_keys });
+        let answer = remote_keys::<model::QueryResponse>(&server, "query", body, timeout);
+        (server, answer.await)
+    });
+    for (server, answer) in future::join_all(queries).await {
+        let answer = match answer {
+            Ok(answer) => answer,
+            Err(failure) => {
+                res.failures.insert(server, failure);
+                continue;
+            }
+        };
+        let on_server = |user_id: &String| UserId::parse(user_id).domain == server;
+        res.device_keys
+            .extend(answer.device_keys.into_iter().filter(|(u, _)| on_server(u)));
+        res.master_keys
+            .extend(answer.master_keys.into_iter().filter(|(u, _)| on_server(u)));
+        res.self_signing_keys.extend(
+            answer
+                .self_signing_keys
+                .into_iter()
+                .filter(|(u, _)| on_server(u)),
+        );
+    }
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+/// Claims one-time keys for use in pre-key messages.
+///
+/// Each claimed key is removed from the device's pool, so it is never handed
+/// out twice. Once a device has run out of one-time keys its fallback key is
+/// returned instead.
+///
+/// Keys of users on other servers are claimed over federation, each server
+/// at once. A server that can't be reached is listed in `failures`.
+///
+/// POST /_matrix/client/r0/keys/claim
+pub async fn claim<T: Store>(
+    _auth: Authenticated,
+    req: Json<model::ClaimRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let mut res = model::ClaimResponse::default();
+    let mut remote: BTreeMap<String, BTreeMap<String, BTreeMap<String, String>>> = BTreeMap::new();
+
+    for (user_id, devices) in &req.one_time_keys {
+        let parsed = UserId::parse(user_id);
+        if !parsed.is_local() {
+            remote
+                .entry(parsed.domain.into_owned())
+                .or_default()
+                .insert(user_id.clone(), devices.clone());
+            continue;
+        }
+        for (device_id, algorithm) in devices {
+            let key = storage
+                .claim_one_time_key(&parsed.local_part, device_id, algorithm)
+                .await
+                .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+            if let Some(key) = key {
+                res.one_time_keys
+                    .entry(user_id.clone())
+                    .or_default()
+                    .entry(device_id.clone())
+                    .or_default()
+                    .insert(key.name(), key.key);
+            }
+        }
+    }
+
+    let timeout = req.timeout;
+    let claims = remote
+        .into_iter()
+        .map(|(server, one_time_keys)| async move {
+            let body = json!({ "one_time_keys": one_time_keys });
+            let answer = remote_keys::<model::ClaimResponse>(&server, "claim", body, timeout);
+            (server, answer.await)
+        });
+    for (server, answer) in future::join_all(claims).await {
+        match answer {
+            Ok(answer) => res.one_time_keys.extend(
+                answer
+                    .one_time_keys
+                    .into_iter()
+                    .filter(|(user_id, _)| UserId::parse(user_id).domain == server),
+            ),
+            Err(failure) => {
+                res.failures.insert(server, failure);
+            }
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+/// Publishes cross-signing keys for the user. The self-signing and
+/// user-signing keys must be signed by the master key, either the one
+/// uploaded alongside them or the one already published.
+///
+/// This API endpoint uses the User-Interactive Authentication API.
+///
+/// POST /_matrix/client/r0/keys/device_signing/upload
+pub async fn upload_device_signing<T: Store>(
+    auth: Authenticated,
+    req: Json<model::DeviceSigningUploadRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    uia::authenticate(
+        storage.get_ref(),
+        &auth,
+        "keys.device_signing_upload",
+        req.auth.as_ref(),
+    )
+    .await?;
+
+    let localpart = &auth.user_id.local_part;
+    let user_id = auth.user_id.to_string();
+    let invalid =
+        |error: &str| MatrixError::new(StatusCode::BAD_REQUEST, ErrorCode::INVALID_PARAM, error);
+
+    let existing = storage
+        .get_cross_signing_keys(localpart)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let master = req
+        .master_key
+        .as_ref()
+        .or_else(|| existing.get(model::MASTER));
+    let master_key_id = match master {
+        Some(master) => Some(
+            model::validate_cross_signing_key(master, &user_id, model::MASTER).map_err(invalid)?,
+        ),
+        None => None,
+    };
+
+    let subkeys = [
+        (&req.self_signing_key, model::SELF_SIGNING),
+        (&req.user_signing_key, model::USER_SIGNING),
+    ];
+    for (key, usage) in subkeys.iter() {
+        if let Some(key) = key {
+            model::validate_cross_signing_key(key, &user_id, usage).map_err(invalid)?;
+            let signed_by_master = match (&master_key_id, master.and_then(model::public_key)) {
+                (Some(id), Some(public_key)) => {
+                    verify_key_signature(key, &user_id, id, public_key).is_ok()
+                }
+                _ => false,
+            };
+            if !signed_by_master {
+                return Err(MatrixError::new(
+                    StatusCode::BAD_REQUEST,
+                    ErrorCode::INVALID_SIGNATURE,
+                    format!("The {} key must be signed by the master key.", usage),
+                )
+                .into());
+            }
+        }
+    }
+
+    // Replacing the master key resets cross-signing: everyone who verified
+    // the user has to verify them again, so it is audited
+    let previous_master_key_id = existing
+        .get(model::MASTER)
+        .and_then(|master| model::validate_cross_signing_key(master, &user_id, model::MASTER).ok());
+    let reset = req.master_key.is_some()
+        && previous_master_key_id.is_some()
+        && previous_master_key_id != master_key_id;
+
+    if let Some(master) = &req.master_key {
+        storage
+            .set_cross_signing_key(localpart, model::MASTER, master)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    }
+    for (key, usage) in subkeys.iter() {
+        if let Some(key) = key {
+            storage
+                .set_cross_signing_key(localpart, usage, key)
+                .await
+                .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+        }
+    }
+    storage
+        .add_device_list_change(&user_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if reset {
+        audit::record(
+            storage.get_ref(),
+            Some(&user_id),
+            auth.ip,
+            "keys.cross_signing_reset",
+            &json!({
+                "device_id": auth.device_id,
+                "previous_master_key": previous_master_key_id,
+                "master_key": master_key_id,
+            }),
+        )
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    }
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Works out which new signatures in an uploaded key object the signer is
+/// allowed to publish:
+///
+/// - their own devices, signed with their self-signing key;
+/// - their own master key, signed with one of their devices;
+/// - another user's master key, signed with their user-signing key.
+async fn accept_signatures<T: Store>(
+    storage: &T,
+    signer: &UserId,
+    own_keys: &BTreeMap<String, Value>,
+    target: &UserId,
+    target_id: &str,
+    signed: &Value,
+) -> Result<Vec<KeySignature>, (ErrorCode, &'static str)> {
+    let internal = |_: Box<dyn std::error::Error>| {
+        (ErrorCode::UNKNOWN, "Internal error while looking up keys.")
+    };
+    // Signing keys as key ID to public key
+    let cross_signing_key = |usage: &str| -> BTreeMap<String, String> {
+        own_keys
+            .get(usage)
+            .and_then(model::public_key)
+            .map(|k| (format!("ed25519:{}", k), k.to_owned()))
+            .into_iter()
+            .collect()
+    };
+
+    if !target.is_local() {
+        return Err((
+            ErrorCode::UNKNOWN,
+            "Keys of remote users are not supported.",
+        ));
+    }
+    let target_keys = storage
+        .get_cross_signing_keys(&target.local_part)
+        .await
+        .map_err(internal)?;
+    let target_master = target_keys
+        .get(model::MASTER)
+        .filter(|key| model::public_key(key) == Some(target_id));
+
+    let (stored, allowed_signing_keys): (Value, BTreeMap<String, String>) = if target == signer {
+        let device = storage
+            .get_device_keys(&target.local_part, &[target_id.to_owned()])
+            .await
+            .map_err(internal)?
+            .pop()
+            .map(|(_, key)| key);
+        match (device, target_master) {
+            (Some(device), _) => (device, cross_signing_key(model::SELF_SIGNING)),
+            (None, Some(master)) => {
+                let devices = storage
+                    .get_device_keys(&signer.local_part, &[])
+                    .await
+                    .map_err(internal)?;
+                let device_keys = devices
+                    .into_iter()
+                    .filter_map(|(device_id, key)| {
+                        let key_id = format!("ed25519:{}", device_id);
+                        let public_key = key["keys"][&key_id].as_str()?.to_owned();
+                        Some((key_id, public_key))
+                    })
+                    .collect();
+                (master.clone(), device_keys)
+            }
+            (None, None) => return Err((ErrorCode::NOT_FOUND, "Unknown key.")),
+        }
+    } else {
+        match target_master {
+            Some(master) => (master.clone(), cross_signing_key(model::USER_SIGNING)),
+            None => return Err((ErrorCode::NOT_FOUND, "Unknown key.")),
+        }
+    };
+
+    if model::signed_part(signed) != model::signed_part(&stored) {
+        return Err((
+            ErrorCode::INVALID_SIGNATURE,
+            "Key does not match the published key.",
+        ));
+    }
+
+    let signer_id = signer.to_string();
+    let signatures: Vec<KeySignature> = signed
+        .get("signatures")
+        .and_then(|s| s.get(&signer_id))
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .filter(|(key_id, _)| allowed_signing_keys.contains_key(*key_id))
+        .filter(|(key_id, _)| !model::is_signed_by(&stored, &signer_id, key_id))
+        .filter_map(|(key_id, signature)| {
+            Some(KeySignature {
+                signer: signer_id.clone(),
+                signing_key_id: key_id.clone(),
+                target_user_id: target.to_string(),
+                target_id: target_id.to_owned(),
+                signature: signature.as_str()?.to_owned(),
+            })
+        })
+        .collect();
+    if signatures.is_empty() {
+        return Err((
+            ErrorCode::INVALID_SIGNATURE,
+            "No new signature from an acceptable key.",
+        ));
+    }
+    for signature in &signatures {
+        let public_key = &allowed_signing_keys[&signature.signing_key_id];
+        if verify_key_signature(signed, &signer_id, &signature.signing_key_id, public_key).is_err()
+        {
+            return Err((ErrorCode::INVALID_SIGNATURE, "Invalid signature."));
+        }
+    }
+    Ok(signatures)
+}
+
+/// Publishes cross-signing signatures for the user's own devices and master
+/// key, or for other users' master keys. Signatures that cannot be accepted
+/// are reported in `failures` without affecting the rest of the upload.
+///
+/// POST /_matrix/client/r0/keys/signatures/upload
+pub async fn upload_signatures<T: Store>(
+    auth: Authenticated,
+    req: Json<model::SignaturesUploadRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let own_keys = storage
+        .get_cross_signing_keys(&auth.user_id.local_part)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    let mut res = model::SignaturesUploadResponse::default();
+    let mut accepted = Vec::new();
+    for (user_id, keys) in req.iter() {
+        let target = UserId::parse(user_id);
+        for (target_id, signed) in keys {
+            let result = accept_signatures(
+                storage.get_ref(),
+                &auth.user_id,
+                &own_keys,
+                &target,
+                target_id,
+                signed,
+            )
+            .await;
+            match result {
+                Ok(signatures) => accepted.extend(signatures),
+                Err((errcode, error)) => {
+                    res.failures.entry(user_id.clone()).or_default().insert(
+                        target_id.clone(),
+                        json!({ "errcode": errcode, "error": error }),
+                    );
+                }
+            }
+        }
+    }
+
+    storage
+        .add_key_signatures(&accepted)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    // Whoever had a key signed needs to be re-queried by the users watching them
+    let changed: BTreeSet<&str> = accepted
+        .iter()
+        .map(|sig| sig.target_user_id.as_str())
+        .collect();
+    for user_id in changed {
+        storage
+            .add_device_list_change(user_id)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    }
+
+    Ok(HttpResponse::Ok().json(res))
+}
+
+/// Gets a list of users who have updated their device identity keys since a
+/// previous sync token.
+///
+/// Known limitation: the spec lists every user who shares an encrypted room
+/// with the requester, but with no rooms to share `changed` only ever holds
+/// the requester, if their own keys changed, and `left` is always empty.
+/// Clients tracking other users' devices must query their keys instead.
+///
+/// GET /_matrix/client/r0/keys/changes
+pub async fn changes<T: Store>(
+    auth: Authenticated,
+    params: Query<KeyChangesParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let from = params
+        .from
+        .parse::<SyncToken>()
+        .with_codes(StatusCode::BAD_REQUEST, ErrorCode::INVALID_PARAM)?;
+    let to = params
+        .to
+        .parse::<SyncToken>()
+        .with_codes(StatusCode::BAD_REQUEST, ErrorCode::INVALID_PARAM)?;
+
+    let user_id = auth.user_id.to_string();
+    let changed: Vec<String> = storage
+        .get_device_list_changes(from.device_lists, to.device_lists)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .into_iter()
+        .filter(|changed| changed == &user_id)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "changed": changed,
+        "left": [],
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use signing::SigningKey;
+
+    #[test]
+    fn test_verify_key_signature() {
+        let seed = [7; 32];
+        let public_key = SigningKey::from_seed("unused", &seed).unwrap().public_key();
+        let master = SigningKey::from_seed(&public_key, &seed).unwrap();
+        let mut key = json!({
+            "user_id": "@alice:example.com",
+            "usage": ["self_signing"],
+            "keys": { "ed25519:self": "self" },
+        });
+        master.sign_json("@alice:example.com", &mut key);
+
+        assert_eq!(
+            verify_key_signature(&key, "@alice:example.com", &master.key_id, &public_key),
+            Ok(())
+        );
+        key["usage"] = json!(["user_signing"]);
+        assert_eq!(
+            verify_key_signature(&key, "@alice:example.com", &master.key_id, &public_key),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            verify_key_signature(&key, "@bob:example.com", &master.key_id, &public_key),
+            Err(SignatureError::Missing)
+        );
+    }
+}
@@ -0,0 +1,755 @@
+use actix_web::{
+    dev::SizedStream,
+    http::{
+        header::{self, EntityTag},
+        StatusCode,
+    },
+    web::{self, BytesMut, Data, Path, Payload, Query},
+    Error, HttpMessage as _, HttpRequest, HttpResponse,
+};
+use futures::{StreamExt, TryStreamExt};
+use serde_json::{json, Value};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    audit,
+    clock::now_ms,
+    db::Store,
+    media::{
+        self,
+        preview::{self, PreviewError},
+        remote::{self, RemoteMediaError},
+        scan::{Scanner, Verdict},
+        thumbnail::{self, Method, Spec},
+        MediaStore, RangeNotSatisfiable,
+    },
+    models::media::{self as model, LocalMedia, RemoteMedia},
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+    },
+    CONFIG,
+};
+
+fn too_large() -> MatrixError {
+    MatrixError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        ErrorCode::TOO_LARGE,
+        format!(
+            "Cannot upload files larger than {} bytes.",
+            CONFIG.max_upload_size
+        ),
+    )
+}
+
+fn not_found() -> MatrixError {
+    MatrixError::new(
+        StatusCode::NOT_FOUND,
+        ErrorCode::NOT_FOUND,
+        "Media not found.",
+    )
+}
+
+/// Generates a thumbnail on the blocking thread pool, as decoding and
+/// resizing images is too slow to do on the server's event loop.
+async fn generate_thumbnail(
+    data: web::Bytes,
+    content_type: String,
+    spec: Spec,
+) -> Result<Vec<u8>, Error> {
+    let max_pixels = CONFIG.max_image_pixels;
+    web::block(move || thumbnail::generate(&data, &content_type, spec, max_pixels))
+        .await
+        .with_codes(StatusCode::BAD_REQUEST, ErrorCode::UNKNOWN)
+}
+
+/// Stores new local media and records it, generating thumbnails up front in
+/// the common sizes if it is an image.
+async fn store_local_media<T: Store, M: MediaStore>(
+    storage: &T,
+    media_store: &M,
+    user_id: String,
+    upload_name: Option<String>,
+    content_type: String,
+    data: web::Bytes,
+) -> Result<LocalMedia, Error> {
+    let local_media = LocalMedia {
+        media_id: media::generate_media_id(),
+        content_type,
+        media_length: data.len() as i64,
+        upload_name,
+        user_id,
+        created_ts: now_ms(),
+    };
+    let media_id = &local_media.media_id;
+
+    media_store
+        .put(media_id, data.clone())
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    storage
+        .add_local_media(&local_media)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    if thumbnail::is_thumbnailable(&local_media.content_type) {
+        for spec in thumbnail::SIZES {
+            // Anything that fails here is retried when the thumbnail is
+            // requested, and reported to that client instead
+            let generated =
+                generate_thumbnail(data.clone(), local_media.content_type.clone(), *spec).await;
+            if let Ok(generated) = generated {
+                let _ = media_store
+                    .put_thumbnail(media_id, &spec.name(), generated)
+                    .await;
+            }
+        }
+    }
+
+    Ok(local_media)
+}
+
+/// Checks an upload with the content scanner, recording the result in the
+/// audit log. Uploads the scanner flags, or fails to check, are rejected.
+async fn scan_upload<T: Store>(
+    storage: &T,
+    scanner: &Scanner,
+    user_id: &str,
+    ip: Option<IpAddr>,
+    upload_name: Option<&str>,
+    content_type: &str,
+    data: web::Bytes,
+) -> Result<(), Error> {
+    let media_length = data.len();
+    let verdict = scanner.scan(data).await;
+    let (result, reason) = match &verdict {
+        Ok(Verdict::Clean) => ("clean", None),
+        Ok(Verdict::Flagged(reason)) => ("flagged", Some(reason.clone())),
+        Err(e) => ("error", Some(e.to_string())),
+    };
+    audit::record(
+        storage,
+        Some(user_id),
+        ip,
+        "media.scan",
+        &json!({
+            "result": result,
+            "reason": reason,
+            "upload_name": upload_name,
+            "content_type": content_type,
+            "media_length": media_length,
+        }),
+    )
+    .await
+    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    match verdict {
+        Ok(Verdict::Clean) => Ok(()),
+        Ok(Verdict::Flagged(_)) => Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "This file was flagged by the content scanner.",
+        )
+        .into()),
+        Err(e) => {
+            tracing::error!(error = %e, "Media scanner failed");
+            Err(MatrixError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::UNKNOWN,
+                "Unable to scan the upload.",
+            )
+            .into())
+        }
+    }
+}
+
+/// Upload some content to the content repository.
+///
+/// The body is stored as-is. Its content type is taken from the
+/// `Content-Type` header, or sniffed from the content when the client did not
+/// give a specific one. Thumbnails of images are generated up front in the
+/// common sizes. If a content scanner is configured, uploads it flags are
+/// rejected.
+///
+/// POST /_matrix/media/r0/upload
+pub async fn upload<T: Store, M: MediaStore>(
+    auth: Authenticated,
+    params: Query<model::UploadParams>,
+    req: HttpRequest,
+    mut payload: Payload,
+    storage: Data<T>,
+    media_store: Data<M>,
+) -> Result<HttpResponse, Error> {
+    let declared_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<u64>().ok());
+    if declared_length.map_or(false, |len| len > CONFIG.max_upload_size) {
+        return Err(too_large().into());
+    }
+
+    let mut data = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.with_codes(StatusCode::BAD_REQUEST, ErrorCode::UNKNOWN)?;
+        if (data.len() + chunk.len()) as u64 > CONFIG.max_upload_size {
+            return Err(too_large().into());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    let data = data.freeze();
+
+    let user_id = auth.user_id.to_string();
+    if let Some(quota) = CONFIG.media_retention.quota_bytes {
+        let usage = storage
+            .get_media_usage(&user_id)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+        if usage + data.len() as i64 > quota {
+            return Err(MatrixError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                ErrorCode::TOO_LARGE,
+                "This upload would take you over your media quota.",
+            )
+            .into());
+        }
+    }
+
+    let declared_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok());
+    let content_type = media::content_type(declared_type, &data);
+    if let Some(scanner) = &CONFIG.media_scanner {
+        scan_upload(
+            storage.get_ref(),
+            scanner,
+            &user_id,
+            auth.ip,
+            params.filename.as_deref(),
+            &content_type,
+            data.clone(),
+        )
+        .await?;
+    }
+    let local_media = store_local_media(
+        storage.get_ref(),
+        &media_store,
+        user_id,
+        params.filename.clone(),
+        content_type,
+        data,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(model::UploadResponse {
+        content_uri: model::mxc_uri(&CONFIG.hostname, &local_media.media_id),
+    }))
+}
+
+/// Whether media may still be downloaded without an access token.
+fn check_legacy_media_enabled() -> Result<(), MatrixError> {
+    if CONFIG.legacy_media_enabled {
+        Ok(())
+    } else {
+        Err(MatrixError::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::NOT_FOUND,
+            "Unauthenticated media is disabled on this server. Use /_matrix/client/v1/media.",
+        ))
+    }
+}
+
+/// Whether the client's cached copy of media is still current, going by the
+/// `If-None-Match` and `If-Modified-Since` headers.
+fn is_not_modified(req: &HttpRequest, etag: &EntityTag, last_modified: SystemTime) -> bool {
+    match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        // If-Modified-Since is ignored when If-None-Match is given
+        None => match req.get_header::<header::IfModifiedSince>() {
+            Some(header::IfModifiedSince(since)) => last_modified <= SystemTime::from(since),
+            None => false,
+        },
+    }
+}
+
+/// Whether a requested range may be served, going by the `If-Range` header.
+/// Otherwise the client's partial copy is out of date and the whole content
+/// must be sent.
+fn is_range_current(req: &HttpRequest, etag: &EntityTag, last_modified: SystemTime) -> bool {
+    match req.get_header::<header::IfRange>() {
+        Some(header::IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+        Some(header::IfRange::Date(date)) => last_modified <= SystemTime::from(date),
+        None => true,
+    }
+}
+
+/// A piece of media to serve, local or cached from another server.
+struct MediaFile {
+    /// The ID the content is kept under in the media store
+    file_id: String,
+    content_type: String,
+    /// The size of the content in bytes
+    media_length: i64,
+    upload_name: Option<String>,
+    /// When the media was uploaded, or fetched if it is remote
+    created_ts: i64,
+}
+
+impl From<LocalMedia> for MediaFile {
+    fn from(media: LocalMedia) -> Self {
+        MediaFile {
+            file_id: media.media_id,
+            content_type: media.content_type,
+            media_length: media.media_length,
+            upload_name: media.upload_name,
+            created_ts: media.created_ts,
+        }
+    }
+}
+
+impl From<RemoteMedia> for MediaFile {
+    fn from(media: RemoteMedia) -> Self {
+        MediaFile {
+            file_id: media.file_id,
+            content_type: media.content_type,
+            media_length: media.media_length,
+            upload_name: media.upload_name,
+            created_ts: media.created_ts,
+        }
+    }
+}
+
+/// Looks up a piece of media by its `mxc://` URI, fetching it from the
+/// server it belongs to if it is remote and not cached yet.
+async fn find_media<T: Store, M: MediaStore>(
+    server_name: &str,
+    media_id: &str,
+    storage: &T,
+    media_store: &M,
+) -> Result<MediaFile, Error> {
+    if !media::is_valid_media_id(media_id) {
+        return Err(not_found().into());
+    }
+    if server_name == CONFIG.hostname {
+        let local_media = storage
+            .get_local_media(media_id)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+            .ok_or_else(not_found)?;
+        return Ok(local_media.into());
+    }
+    let remote_media = cache_remote_media(server_name, media_id, storage, media_store).await?;
+    Ok(remote_media.into())
+}
+
+/// Gets a piece of remote media from the cache, or fetches it over
+/// federation and caches it. Cached media is kept until the retention job
+/// expires it or makes room in the cache.
+async fn cache_remote_media<T: Store, M: MediaStore>(
+    origin: &str,
+    media_id: &str,
+    storage: &T,
+    media_store: &M,
+) -> Result<RemoteMedia, Error> {
+    let valid_origin = !origin.is_empty()
+        && origin
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c));
+    if !valid_origin || !CONFIG.federation_policy.is_allowed(origin) {
+        return Err(not_found().into());
+    }
+
+    let now = now_ms();
+    let cached = storage
+        .get_remote_media(origin, media_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if let Some(remote_media) = cached {
+        storage
+            .touch_remote_media(origin, media_id, now)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+        return Ok(remote_media);
+    }
+
+    let fetched = remote::fetch(
+        origin,
+        media_id,
+        &CONFIG.url_preview_denylist,
+        CONFIG.max_upload_size as usize,
+    )
+    .await
+    .map_err(remote_media_error)?;
+    let remote_media = RemoteMedia {
+        origin: origin.to_owned(),
+        media_id: media_id.to_owned(),
+        file_id: media::generate_media_id(),
+        content_type: media::content_type(fetched.content_type.as_deref(), &fetched.body),
+        media_length: fetched.body.len() as i64,
+        upload_name: fetched.upload_name,
+        created_ts: now,
+        last_access_ts: now,
+    };
+    media_store
+        .put(&remote_media.file_id, fetched.body)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    storage
+        .add_remote_media(&remote_media)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    // Another request may have fetched the same media meanwhile, in which
+    // case its copy is kept and ours thrown away
+    let stored = storage
+        .get_remote_media(origin, media_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .ok_or_else(not_found)?;
+    if stored.file_id != remote_media.file_id {
+        let _ = media_store.delete(&remote_media.file_id).await;
+    }
+    Ok(stored)
+}
+
+fn remote_media_error(e: RemoteMediaError) -> MatrixError {
+    match e {
+        RemoteMediaError::NotFound => not_found(),
+        e => {
+            tracing::warn!(error = %e, "Unable to fetch remote media");
+            MatrixError::new(StatusCode::BAD_GATEWAY, ErrorCode::UNKNOWN, e.to_string())
+        }
+    }
+}
+
+/// Serves a piece of media, streaming it from the media store. A single
+/// byte range may be requested with the `Range` header, so clients can
+/// resume downloads and seek through audio and video.
+///
+/// Media never changes once uploaded, so the ID of its content doubles as
+/// its `ETag`.
+async fn serve_download<T: Store, M: MediaStore>(
+    path: &model::DownloadPath,
+    req: &HttpRequest,
+    storage: &T,
+    media_store: &M,
+) -> Result<HttpResponse, Error> {
+    let media_file = find_media(&path.server_name, &path.media_id, storage, media_store).await?;
+
+    let etag = EntityTag::strong(media_file.file_id.clone());
+    // HTTP dates only have second precision
+    let last_modified =
+        UNIX_EPOCH + Duration::from_secs(media_file.created_ts.max(0) as u64 / 1000);
+    if is_not_modified(req, &etag, last_modified) {
+        return Ok(HttpResponse::NotModified()
+            .set(header::ETag(etag))
+            .set(header::LastModified(last_modified.into()))
+            .finish());
+    }
+
+    let len = media_file.media_length.max(0) as u64;
+    let range = match req.headers().get(header::RANGE) {
+        Some(range) if is_range_current(req, &etag, last_modified) => range
+            .to_str()
+            .map_or(Ok(None), |range| media::parse_range(range, len)),
+        _ => Ok(None),
+    };
+    let range = match range {
+        Ok(range) => range,
+        Err(RangeNotSatisfiable) => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                .finish())
+        }
+    };
+    let body = media_store
+        .get_stream(&media_file.file_id, range)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .ok_or_else(not_found)?
+        .map_err(Error::from);
+
+    let mut res = HttpResponse::Ok();
+    res.content_type(media_file.content_type.as_str())
+        .header(header::ACCEPT_RANGES, "bytes")
+        .set(header::ETag(etag))
+        .set(header::LastModified(last_modified.into()))
+        // Uploads are untrusted, so never let them run as a page on our origin
+        .header(
+            header::CONTENT_SECURITY_POLICY,
+            "sandbox; default-src 'none'; script-src 'none'; plugin-types application/pdf; \
+             style-src 'unsafe-inline'; object-src 'self';",
+        );
+    if let Some(file_name) = path.file_name.as_ref().or(media_file.upload_name.as_ref()) {
+        res.header(
+            header::CONTENT_DISPOSITION,
+            media::content_disposition(file_name),
+        );
+    }
+
+    match range {
+        Some((start, end)) => Ok(res
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, len),
+            )
+            .body(SizedStream::new(end - start + 1, body))),
+        None => Ok(res.body(SizedStream::new(len, body))),
+    }
+}
+
+/// Serves a thumbnail of a piece of media.
+///
+/// Thumbnails are only made in a few fixed sizes; the closest size at least
+/// as large as requested is returned. Sizes not generated at upload time are
+/// generated on first request and cached. Thumbnails of remote media are
+/// generated locally from the cached original.
+async fn serve_thumbnail<T: Store, M: MediaStore>(
+    path: &model::ThumbnailPath,
+    params: &model::ThumbnailParams,
+    storage: &T,
+    media_store: &M,
+) -> Result<HttpResponse, Error> {
+    let media_file = find_media(&path.server_name, &path.media_id, storage, media_store).await?;
+    if !thumbnail::is_thumbnailable(&media_file.content_type) {
+        return Err(MatrixError::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::NOT_FOUND,
+            "Cannot generate a thumbnail for this media.",
+        )
+        .into());
+    }
+
+    let spec = thumbnail::choose(
+        params.width,
+        params.height,
+        params.method.unwrap_or(Method::Scale),
+    );
+    let cached = media_store
+        .get_thumbnail(&media_file.file_id, &spec.name())
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let data = match cached {
+        Some(data) => data,
+        None => {
+            let original = media_store
+                .get(&media_file.file_id)
+                .await
+                .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+                .ok_or_else(not_found)?;
+            let generated =
+                generate_thumbnail(original.into(), media_file.content_type.clone(), spec).await?;
+            media_store
+                .put_thumbnail(&media_file.file_id, &spec.name(), generated.clone())
+                .await
+                .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+            generated
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(thumbnail::content_type(&media_file.content_type))
+        .body(data))
+}
+
+/// Download content from the content repository.
+///
+/// Only available while `LEGACY_MEDIA_ENABLED` is set; clients should use the
+/// authenticated endpoint instead.
+///
+/// GET /_matrix/media/r0/download/{serverName}/{mediaId}
+/// GET /_matrix/media/r0/download/{serverName}/{mediaId}/{fileName}
+pub async fn download<T: Store, M: MediaStore>(
+    path: Path<model::DownloadPath>,
+    req: HttpRequest,
+    storage: Data<T>,
+    media_store: Data<M>,
+) -> Result<HttpResponse, Error> {
+    check_legacy_media_enabled()?;
+    serve_download(&path, &req, storage.get_ref(), media_store.get_ref()).await
+}
+
+/// Download content from the content repository, as an authenticated user.
+///
+/// GET /_matrix/client/v1/media/download/{serverName}/{mediaId}
+/// GET /_matrix/client/v1/media/download/{serverName}/{mediaId}/{fileName}
+pub async fn download_authenticated<T: Store, M: MediaStore>(
+    _auth: Authenticated,
+    path: Path<model::DownloadPath>,
+    req: HttpRequest,
+    storage: Data<T>,
+    media_store: Data<M>,
+) -> Result<HttpResponse, Error> {
+    serve_download(&path, &req, storage.get_ref(), media_store.get_ref()).await
+}
+
+/// Download a thumbnail of content from the content repository.
+///
+/// Only available while `LEGACY_MEDIA_ENABLED` is set; clients should use the
+/// authenticated endpoint instead.
+///
+/// GET /_matrix/media/r0/thumbnail/{serverName}/{mediaId}
+pub async fn thumbnail<T: Store, M: MediaStore>(
+    path: Path<model::ThumbnailPath>,
+    params: Query<model::ThumbnailParams>,
+    storage: Data<T>,
+    media_store: Data<M>,
+) -> Result<HttpResponse, Error> {
+    check_legacy_media_enabled()?;
+    serve_thumbnail(&path, &params, storage.get_ref(), media_store.get_ref()).await
+}
+
+/// Download a thumbnail of content from the content repository, as an
+/// authenticated user.
+///
+/// GET /_matrix/client/v1/media/thumbnail/{serverName}/{mediaId}
+pub async fn thumbnail_authenticated<T: Store, M: MediaStore>(
+    _auth: Authenticated,
+    path: Path<model::ThumbnailPath>,
+    params: Query<model::ThumbnailParams>,
+    storage: Data<T>,
+    media_store: Data<M>,
+) -> Result<HttpResponse, Error> {
+    serve_thumbnail(&path, &params, storage.get_ref(), media_store.get_ref()).await
+}
+
+/// This endpoint allows clients to retrieve the configuration of the content
+/// repository, such as upload limitations.
+///
+/// GET /_matrix/media/r0/config
+/// GET /_matrix/client/v1/media/config
+pub async fn get_config(_auth: Authenticated) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(model::ConfigResponse {
+        upload_size: CONFIG.max_upload_size,
+    }))
+}
+
+/// How long a URL preview is cached for.
+const PREVIEW_TTL_MS: i64 = 60 * 60 * 1000;
+
+/// Fetches an image for a URL preview and stores it as local media, so
+/// clients can show it without contacting the remote site themselves.
+/// Returns the image's metadata.
+async fn preview_image<T: Store, M: MediaStore>(
+    storage: &T,
+    media_store: &M,
+    user_id: String,
+    fetched: preview::Fetched,
+) -> Result<LocalMedia, Error> {
+    let content_type = media::content_type(fetched.content_type.as_deref(), &fetched.body);
+    let upload_name = fetched
+        .url
+        .path_segments()
+        .and_then(Iterator::last)
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned);
+    store_local_media(
+        storage,
+        media_store,
+        user_id,
+        upload_name,
+        content_type,
+        fetched.body,
+    )
+    .await
+}
+
+/// Get information about a URL for the client. Typically this is called
+/// when a client sees a URL in a message and wants to render a preview for
+/// the user.
+///
+/// The page's OpenGraph data is returned, with any image copied into the
+/// content repository. Pages are only fetched from hosts and addresses the
+/// configured denylists allow, and results are cached for an hour.
+///
+/// GET /_matrix/media/r0/preview_url
+/// GET /_matrix/client/v1/media/preview_url
+pub async fn preview_url<T: Store, M: MediaStore>(
+    auth: Authenticated,
+    params: Query<model::PreviewParams>,
+    storage: Data<T>,
+    media_store: Data<M>,
+) -> Result<HttpResponse, Error> {
+    if !CONFIG.url_preview_enabled {
+        return Err(MatrixError::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::UNRECOGNIZED,
+            "URL previews are disabled on this server.",
+        )
+        .into());
+    }
+
+    let now = now_ms();
+    let cached = storage
+        .get_url_preview(&params.url, now)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if let Some(og) = cached {
+        return Ok(HttpResponse::Ok().json(og));
+    }
+
+    let max_size = CONFIG.max_upload_size as usize;
+    let denylist = &CONFIG.url_preview_denylist;
+    let fetched = preview::fetch(&params.url, denylist, max_size)
+        .await
+        .map_err(preview_error)?;
+    let user_id = auth.user_id.to_string();
+
+    let is_image = fetched
+        .content_type
+        .as_deref()
+        .map_or(false, |t| t.starts_with("image/"));
+    let (mut og, image) = if is_image {
+        let image = preview_image(storage.get_ref(), &media_store, user_id, fetched).await?;
+        (Default::default(), Some(image))
+    } else {
+        let html = String::from_utf8_lossy(&fetched.body);
+        let og = preview::parse_open_graph(&html, &fetched.url);
+        // The preview is still useful without its image, so failing to
+        // fetch it is not an error
+        let image = match og.get("og:image").and_then(Value::as_str) {
+            Some(image_url) => match preview::fetch(image_url, denylist, max_size).await {
+                Ok(image) => {
+                    Some(preview_image(storage.get_ref(), &media_store, user_id, image).await?)
+                }
+                Err(_) => None,
+            },
+            None => None,
+        };
+        (og, image)
+    };
+
+    og.remove("og:image");
+    if let Some(image) = image {
+        og.insert(
+            "og:image".to_owned(),
+            Value::String(model::mxc_uri(&CONFIG.hostname, &image.media_id)),
+        );
+        og.insert(
+            "og:image:type".to_owned(),
+            Value::String(image.content_type),
+        );
+        og.insert("matrix:image:size".to_owned(), image.media_length.into());
+    }
+
+    let og = Value::Object(og.into_iter().collect());
+    storage
+        .set_url_preview(&params.url, &og, now + PREVIEW_TTL_MS)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(og))
+}
+
+fn preview_error(e: PreviewError) -> MatrixError {
+    let (status, errcode) = match e {
+        PreviewError::InvalidUrl => (StatusCode::BAD_REQUEST, ErrorCode::INVALID_PARAM),
+        PreviewError::Denied => (StatusCode::FORBIDDEN, ErrorCode::FORBIDDEN),
+        _ => (StatusCode::BAD_GATEWAY, ErrorCode::UNKNOWN),
+    };
+    MatrixError::new(status, errcode, e.to_string())
+}
@@ -0,0 +1,14 @@
+use actix_web::{Error, HttpResponse};
+
+use crate::db::slow_queries;
+
+/// The process's metrics, in the Prometheus text format. For now these are
+/// the slow storage calls it has made, by query, which are only counted when
+/// `SLOW_QUERY_THRESHOLD` is set.
+///
+/// GET /metrics
+pub async fn get_metrics() -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(slow_queries::metrics()))
+}
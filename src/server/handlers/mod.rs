@@ -1,7 +1,31 @@
 pub mod account;
 pub mod admin;
+pub mod admin_access;
+pub mod admin_audit;
+pub mod admin_jobs;
+pub mod admin_rooms;
+pub mod admin_stats;
+pub mod admin_users;
 pub mod auth;
+pub mod capabilities;
+pub mod dehydrated_device;
 pub mod devices;
+pub mod directory;
+pub mod federation;
+pub mod health;
+pub mod invite;
+pub mod keys;
+pub mod media;
+pub mod metrics;
+pub mod presence;
 pub mod profile;
+pub mod push_rules;
+pub mod pushers;
 pub mod registration;
+pub mod replication;
+pub mod report;
+pub mod room_keys;
+pub mod room_summary;
+pub mod sync;
+pub mod to_device;
 pub mod user;
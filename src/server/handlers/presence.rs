@@ -0,0 +1,48 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Path},
+    Error, HttpResponse,
+};
+
+use crate::{
+    clock::now_ms,
+    db::Store,
+    models::presence as model,
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+    },
+};
+
+/// Get the given user's presence state. Presence of users on other servers
+/// is as last pushed to us over federation.
+///
+/// TODO: Only show the presence of users sharing a room with the requester,
+/// once rooms exist.
+///
+/// GET /_matrix/client/r0/presence/{userId}/status
+pub async fn get_presence<T: Store>(
+    _auth: Authenticated,
+    path: Path<model::PresencePath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let presence = storage
+        .get_presence(&path.user_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .ok_or_else(|| {
+            MatrixError::new(
+                StatusCode::NOT_FOUND,
+                ErrorCode::NOT_FOUND,
+                "No presence state known for this user.",
+            )
+        })?;
+    let now = now_ms();
+
+    Ok(HttpResponse::Ok().json(model::PresenceResponse {
+        presence: presence.presence,
+        last_active_ago: (now - presence.last_active_ts).max(0),
+        status_msg: presence.status_msg,
+        currently_active: presence.currently_active,
+    }))
+}
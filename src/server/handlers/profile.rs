@@ -0,0 +1,240 @@
+use actix_web::{
+    http::{Method, StatusCode},
+    web::{Data, Json, Path, Query},
+    Error, HttpRequest, HttpResponse,
+};
+use serde_json::json;
+
+use crate::{
+    db::Store,
+    federation::client,
+    models::{
+        auth::UserId,
+        profile::{self as model, Profile, AVATAR_URL, DISPLAYNAME},
+    },
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+        server_auth,
+    },
+    CONFIG,
+};
+
+/// The longest display name a user may set, in characters.
+const MAX_DISPLAYNAME_LENGTH: usize = 256;
+/// The longest avatar URI a user may set, in bytes.
+const MAX_AVATAR_URL_LENGTH: usize = 1000;
+
+fn not_found() -> MatrixError {
+    MatrixError::new(
+        StatusCode::NOT_FOUND,
+        ErrorCode::NOT_FOUND,
+        "Profile not found.",
+    )
+}
+
+/// Gets a local user's profile, or `404 Not Found` if they don't exist.
+async fn local_profile<T: Store>(storage: &T, localpart: &str) -> Result<Profile, Error> {
+    storage
+        .get_account(localpart)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .ok_or_else(not_found)?;
+    Ok(storage
+        .get_profile(localpart)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?)
+}
+
+/// Asks a remote user's server for their profile, or one field of it.
+async fn remote_profile(user_id: &UserId, field: Option<&str>) -> Result<Profile, Error> {
+    if !CONFIG.federation_policy.is_allowed(&user_id.domain) {
+        return Err(not_found().into());
+    }
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    query.append_pair("user_id", &user_id.to_string());
+    if let Some(field) = field {
+        query.append_pair("field", field);
+    }
+    let uri = format!("/_matrix/federation/v1/query/profile?{}", query.finish());
+    let mut res = client::request(Method::GET, &user_id.domain, &uri, None)
+        .await
+        .send()
+        .await
+        .with_codes(StatusCode::BAD_GATEWAY, ErrorCode::UNKNOWN)?;
+    if res.status() == StatusCode::NOT_FOUND {
+        return Err(not_found().into());
+    }
+    if !res.status().is_success() {
+        return Err(MatrixError::new(
+            StatusCode::BAD_GATEWAY,
+            ErrorCode::UNKNOWN,
+            format!("The user's server responded {}.", res.status()),
+        )
+        .into());
+    }
+    let profile: Profile = res
+        .json()
+        .await
+        .with_codes(StatusCode::BAD_GATEWAY, ErrorCode::UNKNOWN)?;
+    Ok(profile.only(field))
+}
+
+/// Gets a user's profile, or one field of it, from this server or theirs.
+async fn profile<T: Store>(
+    storage: &T,
+    user_id: &str,
+    field: Option<&str>,
+) -> Result<HttpResponse, Error> {
+    let user_id = UserId::parse(user_id);
+    let profile = if user_id.is_local() {
+        local_profile(storage, &user_id.local_part)
+            .await?
+            .only(field)
+    } else {
+        remote_profile(&user_id, field).await?
+    };
+    Ok(HttpResponse::Ok().json(profile))
+}
+
+/// Gets a user's display name and avatar. Remote users' profiles are asked
+/// for over federation.
+///
+/// GET /_matrix/client/r0/profile/{userId}
+pub async fn get_profile<T: Store>(
+    path: Path<model::ProfilePath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    profile(storage.get_ref(), &path.user_id, None).await
+}
+
+/// Gets a user's display name.
+///
+/// GET /_matrix/client/r0/profile/{userId}/displayname
+pub async fn get_displayname<T: Store>(
+    path: Path<model::ProfilePath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    profile(storage.get_ref(), &path.user_id, Some(DISPLAYNAME)).await
+}
+
+/// Gets a user's avatar.
+///
+/// GET /_matrix/client/r0/profile/{userId}/avatar_url
+pub async fn get_avatar_url<T: Store>(
+    path: Path<model::ProfilePath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    profile(storage.get_ref(), &path.user_id, Some(AVATAR_URL)).await
+}
+
+/// Checks that a user is changing their own profile.
+fn own_profile(auth: &Authenticated, user_id: &str) -> Result<(), MatrixError> {
+    if UserId::parse(user_id) != auth.user_id {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "Cannot change another user's profile.",
+        ));
+    }
+    Ok(())
+}
+
+/// Sets or removes the user's display name.
+///
+/// TODO: Send the user's new membership to the rooms they are in, from a
+/// background job, once rooms exist.
+///
+/// PUT /_matrix/client/r0/profile/{userId}/displayname
+pub async fn put_displayname<T: Store>(
+    auth: Authenticated,
+    path: Path<model::ProfilePath>,
+    req: Json<model::DisplaynameRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    own_profile(&auth, &path.user_id)?;
+    let displayname = req.displayname.as_deref().filter(|name| !name.is_empty());
+    if let Some(displayname) = displayname {
+        if displayname.chars().count() > MAX_DISPLAYNAME_LENGTH {
+            return Err(MatrixError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::INVALID_PARAM,
+                format!(
+                    "Display names may be at most {} characters.",
+                    MAX_DISPLAYNAME_LENGTH
+                ),
+            )
+            .into());
+        }
+    }
+
+    storage
+        .set_displayname(&auth.user_id.local_part, displayname)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Sets or removes the user's avatar, which must be an `mxc://` URI.
+///
+/// TODO: Send the user's new membership to the rooms they are in, from a
+/// background job, once rooms exist.
+///
+/// PUT /_matrix/client/r0/profile/{userId}/avatar_url
+pub async fn put_avatar_url<T: Store>(
+    auth: Authenticated,
+    path: Path<model::ProfilePath>,
+    req: Json<model::AvatarUrlRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    own_profile(&auth, &path.user_id)?;
+    let avatar_url = req.avatar_url.as_deref().filter(|url| !url.is_empty());
+    if let Some(avatar_url) = avatar_url {
+        if !avatar_url.starts_with("mxc://") || avatar_url.len() > MAX_AVATAR_URL_LENGTH {
+            return Err(MatrixError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::INVALID_PARAM,
+                "Avatars must be mxc:// URIs.",
+            )
+            .into());
+        }
+    }
+
+    storage
+        .set_avatar_url(&auth.user_id.local_part, avatar_url)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Answers another server's query for a local user's profile, or one field
+/// of it.
+///
+/// GET /_matrix/federation/v1/query/profile
+pub async fn query_profile<T: Store>(
+    req: HttpRequest,
+    query: Query<model::ProfileQuery>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    server_auth::authenticate(storage.get_ref(), &req, None).await?;
+    let field = query.field.as_deref();
+    if let Some(field) = field {
+        if field != DISPLAYNAME && field != AVATAR_URL {
+            return Err(MatrixError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::INVALID_PARAM,
+                "Unknown profile field.",
+            )
+            .into());
+        }
+    }
+    let user_id = UserId::parse(&query.user_id);
+    if !user_id.is_local() {
+        return Err(not_found().into());
+    }
+
+    let profile = local_profile(storage.get_ref(), &user_id.local_part).await?;
+    Ok(HttpResponse::Ok().json(profile.only(field)))
+}
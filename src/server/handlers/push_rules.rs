@@ -0,0 +1,305 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json, Path, Query},
+    Error, HttpResponse,
+};
+use serde_json::json;
+
+use crate::{
+    db::Store,
+    models::push::{self as model, PushRule, UserPushRules, RULE_KINDS},
+    push::rules,
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+    },
+};
+
+fn not_found() -> MatrixError {
+    MatrixError::new(
+        StatusCode::NOT_FOUND,
+        ErrorCode::NOT_FOUND,
+        "Push rule not found.",
+    )
+}
+
+fn invalid(error: &str) -> MatrixError {
+    MatrixError::new(StatusCode::BAD_REQUEST, ErrorCode::INVALID_PARAM, error)
+}
+
+/// Only the `global` scope exists, with the five kinds of rule.
+fn check_path(path: &model::PushRulePath) -> Result<(), MatrixError> {
+    if path.scope != "global" {
+        return Err(invalid("Unknown push rule scope."));
+    }
+    if !RULE_KINDS.contains(&path.kind.as_str()) {
+        return Err(invalid("Unknown push rule kind."));
+    }
+    Ok(())
+}
+
+/// Default rules are named with a leading dot, which user-defined rules
+/// may not use.
+fn is_default_rule(rule_id: &str) -> bool {
+    rule_id.starts_with('.')
+}
+
+async fn load_rules<T: Store>(storage: &T, localpart: &str) -> Result<UserPushRules, Error> {
+    Ok(storage
+        .get_push_rules(localpart)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .unwrap_or_default())
+}
+
+async fn save_rules<T: Store>(
+    storage: &T,
+    localpart: &str,
+    user_rules: &UserPushRules,
+) -> Result<(), Error> {
+    storage
+        .set_push_rules(localpart, user_rules)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    Ok(())
+}
+
+/// Finds a rule, default or user-defined, as it is evaluated.
+async fn find_rule<T: Store>(
+    storage: &T,
+    auth: &Authenticated,
+    path: &model::PushRulePath,
+) -> Result<PushRule, Error> {
+    check_path(path)?;
+    let user_rules = load_rules(storage, &auth.user_id.local_part).await?;
+    let ruleset = rules::effective(&auth.user_id.to_string(), &user_rules);
+    let rule = ruleset
+        .kind(&path.kind)
+        .and_then(|rules| rules.iter().find(|rule| rule.rule_id == path.rule_id))
+        .ok_or_else(not_found)?;
+    Ok(rule.clone())
+}
+
+/// Retrieve all push rulesets for this user. The server's default rules
+/// are included, with any changes the user made to them.
+///
+/// GET /_matrix/client/r0/pushrules/
+pub async fn get_push_rules<T: Store>(
+    auth: Authenticated,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let user_rules = load_rules(storage.get_ref(), &auth.user_id.local_part).await?;
+
+    Ok(HttpResponse::Ok().json(model::PushRulesResponse {
+        global: rules::effective(&auth.user_id.to_string(), &user_rules),
+    }))
+}
+
+/// Retrieve a single specified push rule.
+///
+/// GET /_matrix/client/r0/pushrules/{scope}/{kind}/{ruleId}
+pub async fn get_push_rule<T: Store>(
+    auth: Authenticated,
+    path: Path<model::PushRulePath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let rule = find_rule(storage.get_ref(), &auth, &path).await?;
+
+    Ok(HttpResponse::Ok().json(rule))
+}
+
+/// This endpoint allows the creation or modification of user defined push
+/// rules. If a rule with the same `rule_id` already exists among rules of
+/// the same kind, it is updated with the new parameters, otherwise a new
+/// rule is created.
+///
+/// New rules are the highest priority user-defined rule of their kind
+/// unless placed `before` or `after` another. The server's default rules
+/// can't be changed this way.
+///
+/// PUT /_matrix/client/r0/pushrules/{scope}/{kind}/{ruleId}
+pub async fn put_push_rule<T: Store>(
+    auth: Authenticated,
+    path: Path<model::PushRulePath>,
+    params: Query<model::PutPushRuleParams>,
+    req: Json<model::PutPushRuleRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    check_path(&path)?;
+    if is_default_rule(&path.rule_id) {
+        return Err(invalid("Cannot change the conditions of a default rule.").into());
+    }
+    let req = req.into_inner();
+    let (conditions, pattern) = match path.kind.as_str() {
+        "override" | "underride" => (Some(req.conditions.unwrap_or_default()), None),
+        "content" => match req.pattern {
+            Some(pattern) => (None, Some(pattern)),
+            None => return Err(invalid("Content rules must have a pattern.").into()),
+        },
+        _ => (None, None),
+    };
+    let rule = PushRule {
+        rule_id: path.rule_id.clone(),
+        default: false,
+        enabled: true,
+        actions: req.actions,
+        conditions,
+        pattern,
+    };
+
+    let localpart = &auth.user_id.local_part;
+    let mut user_rules = load_rules(storage.get_ref(), localpart).await?;
+    let kind_rules = user_rules
+        .rules
+        .kind_mut(&path.kind)
+        .ok_or_else(not_found)?;
+    let existing = kind_rules.iter().position(|r| r.rule_id == rule.rule_id);
+    if let Some(i) = existing {
+        kind_rules.remove(i);
+    }
+    let position = |rule_id: &str| {
+        kind_rules
+            .iter()
+            .position(|r| r.rule_id == rule_id)
+            .ok_or_else(not_found)
+    };
+    let index = match (&params.before, &params.after) {
+        (Some(before), _) => position(before.as_str())?,
+        (None, Some(after)) => position(after.as_str())? + 1,
+        (None, None) => existing.unwrap_or(0),
+    };
+    kind_rules.insert(index, rule);
+    save_rules(storage.get_ref(), localpart, &user_rules).await?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// This endpoint removes the push rule defined in the path. The server's
+/// default rules can only be disabled, not removed.
+///
+/// DELETE /_matrix/client/r0/pushrules/{scope}/{kind}/{ruleId}
+pub async fn delete_push_rule<T: Store>(
+    auth: Authenticated,
+    path: Path<model::PushRulePath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    check_path(&path)?;
+    if is_default_rule(&path.rule_id) {
+        return Err(invalid("Cannot delete a default rule.").into());
+    }
+    let localpart = &auth.user_id.local_part;
+    let mut user_rules = load_rules(storage.get_ref(), localpart).await?;
+    let kind_rules = user_rules
+        .rules
+        .kind_mut(&path.kind)
+        .ok_or_else(not_found)?;
+    let i = kind_rules
+        .iter()
+        .position(|r| r.rule_id == path.rule_id)
+        .ok_or_else(not_found)?;
+    kind_rules.remove(i);
+    save_rules(storage.get_ref(), localpart, &user_rules).await?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// This endpoint gets whether the specified push rule is enabled.
+///
+/// GET /_matrix/client/r0/pushrules/{scope}/{kind}/{ruleId}/enabled
+pub async fn get_push_rule_enabled<T: Store>(
+    auth: Authenticated,
+    path: Path<model::PushRulePath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let rule = find_rule(storage.get_ref(), &auth, &path).await?;
+
+    Ok(HttpResponse::Ok().json(model::PushRuleEnabled {
+        enabled: rule.enabled,
+    }))
+}
+
+/// Applies a change to a rule, default or user-defined. Changes to default
+/// rules are stored apart from the rules themselves.
+async fn change_rule<T: Store, F>(
+    storage: &T,
+    auth: &Authenticated,
+    path: &model::PushRulePath,
+    change: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(&mut Option<bool>, &mut Option<Vec<serde_json::Value>>),
+{
+    // Checks the rule exists, and the path is valid
+    find_rule(storage, auth, path).await?;
+    let localpart = &auth.user_id.local_part;
+    let mut user_rules = load_rules(storage, localpart).await?;
+
+    if is_default_rule(&path.rule_id) {
+        let default = user_rules.defaults.entry(path.rule_id.clone()).or_default();
+        change(&mut default.enabled, &mut default.actions);
+    } else {
+        let rule = user_rules
+            .rules
+            .kind_mut(&path.kind)
+            .and_then(|rules| rules.iter_mut().find(|r| r.rule_id == path.rule_id))
+            .ok_or_else(not_found)?;
+        let mut enabled = Some(rule.enabled);
+        let mut actions = Some(rule.actions.clone());
+        change(&mut enabled, &mut actions);
+        rule.enabled = enabled.unwrap_or(rule.enabled);
+        rule.actions = actions.unwrap_or_default();
+    }
+    save_rules(storage, localpart, &user_rules).await
+}
+
+/// This endpoint allows clients to enable or disable the specified push
+/// rule.
+///
+/// PUT /_matrix/client/r0/pushrules/{scope}/{kind}/{ruleId}/enabled
+pub async fn put_push_rule_enabled<T: Store>(
+    auth: Authenticated,
+    path: Path<model::PushRulePath>,
+    req: Json<model::PushRuleEnabled>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    change_rule(storage.get_ref(), &auth, &path, |enabled, _| {
+        *enabled = Some(req.enabled)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// This endpoint get the actions for the specified push rule.
+///
+/// GET /_matrix/client/r0/pushrules/{scope}/{kind}/{ruleId}/actions
+pub async fn get_push_rule_actions<T: Store>(
+    auth: Authenticated,
+    path: Path<model::PushRulePath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let rule = find_rule(storage.get_ref(), &auth, &path).await?;
+
+    Ok(HttpResponse::Ok().json(model::PushRuleActions {
+        actions: rule.actions,
+    }))
+}
+
+/// This endpoint allows clients to change the actions of a push rule. This
+/// can be used to change the actions of builtin rules.
+///
+/// PUT /_matrix/client/r0/pushrules/{scope}/{kind}/{ruleId}/actions
+pub async fn put_push_rule_actions<T: Store>(
+    auth: Authenticated,
+    path: Path<model::PushRulePath>,
+    req: Json<model::PushRuleActions>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let new_actions = req.into_inner().actions;
+    change_rule(storage.get_ref(), &auth, &path, |_, actions| {
+        *actions = Some(new_actions)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
@@ -0,0 +1,158 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json},
+    Error, HttpResponse,
+};
+use serde_json::json;
+use url::Url;
+
+use crate::{
+    db::Store,
+    models::push::{self as model, Pusher},
+    push::email,
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+    },
+    CONFIG,
+};
+
+/// The path of a push gateway's endpoint for notifications.
+const NOTIFY_PATH: &str = "/_matrix/push/v1/notify";
+
+fn invalid(error: &str) -> MatrixError {
+    MatrixError::new(StatusCode::BAD_REQUEST, ErrorCode::INVALID_PARAM, error)
+}
+
+/// `http` pushers must give the push gateway they use.
+fn check_http_pusher(req: &model::SetPusherRequest) -> Result<(), MatrixError> {
+    let url = req
+        .data
+        .url
+        .as_deref()
+        .ok_or_else(|| {
+            MatrixError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::MISSING_PARAM,
+                "HTTP pushers must have a URL.",
+            )
+        })?
+        .parse::<Url>()
+        .map_err(|_| invalid("Invalid pusher URL."))?;
+    if !["http", "https"].contains(&url.scheme()) || url.path() != NOTIFY_PATH {
+        return Err(invalid(
+            "Pusher URL must be a push gateway's notify endpoint.",
+        ));
+    }
+    Ok(())
+}
+
+/// `email` pushers need email notifications set up, and an address that at
+/// least looks like one.
+fn check_email_pusher(req: &model::SetPusherRequest) -> Result<(), MatrixError> {
+    if CONFIG.email_notifications.is_none() {
+        return Err(invalid(
+            "Email notifications are not enabled on this server.",
+        ));
+    }
+    if req.app_id != email::APP_ID {
+        return Err(invalid("Email pushers must have the app ID m.email."));
+    }
+    let address = req.pushkey.as_str();
+    let valid = match address.find('@') {
+        Some(at) => at > 0 && at < address.len() - 1,
+        None => false,
+    };
+    if !valid
+        || address
+            .chars()
+            .any(|c| c.is_whitespace() || c == '<' || c == '>')
+    {
+        return Err(invalid("Invalid email address."));
+    }
+    Ok(())
+}
+
+/// Gets all currently active pushers for the authenticated user.
+///
+/// GET /_matrix/client/r0/pushers
+pub async fn get_pushers<T: Store>(
+    auth: Authenticated,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let pushers = storage
+        .get_pushers(&auth.user_id.local_part)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(model::PushersResponse {
+        pushers: pushers.into_iter().map(|(pusher, _)| pusher).collect(),
+    }))
+}
+
+/// This endpoint allows the creation, modification and deletion of pushers
+/// for this user ID. The behaviour of this endpoint varies depending on the
+/// values in the JSON body.
+///
+/// A `kind` of `null` deletes the pusher. `http` pushers must have a
+/// `data.url` that is a push gateway's notify endpoint. `email` pushers, if
+/// email notifications are enabled, have the address as their pushkey and
+/// `m.email` as their app ID.
+///
+/// POST /_matrix/client/r0/pushers/set
+pub async fn set_pusher<T: Store>(
+    auth: Authenticated,
+    req: Json<model::SetPusherRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let mut req = req.into_inner();
+    let localpart = &auth.user_id.local_part;
+
+    let kind = match req.kind.take() {
+        Some(kind) => kind,
+        None => {
+            storage
+                .delete_pusher(localpart, &req.app_id, &req.pushkey)
+                .await
+                .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+            return Ok(HttpResponse::Ok().json(json!({})));
+        }
+    };
+    if req.app_display_name.is_empty() || req.device_display_name.is_empty() || req.lang.is_empty()
+    {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::MISSING_PARAM,
+            "Missing app_display_name, device_display_name or lang.",
+        )
+        .into());
+    }
+    match kind.as_str() {
+        "http" => check_http_pusher(&req)?,
+        "email" => check_email_pusher(&req)?,
+        _ => return Err(invalid("Unsupported pusher kind.").into()),
+    }
+
+    let pusher = Pusher {
+        pushkey: req.pushkey,
+        kind,
+        app_id: req.app_id,
+        app_display_name: req.app_display_name,
+        device_display_name: req.device_display_name,
+        profile_tag: req.profile_tag,
+        lang: req.lang,
+        data: req.data,
+    };
+    storage
+        .set_pusher(localpart, &pusher)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if !req.append {
+        storage
+            .delete_other_pushers(localpart, &pusher.app_id, &pusher.pushkey)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    }
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
@@ -1,8 +1,22 @@
-use crate::{db::Store, models::registration};
+use crate::{
+    audit,
+    db::Store,
+    models::{auth::UserId, registration},
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+        handlers::auth::{new_device_id, Claims},
+        proxy, uia,
+    },
+    CONFIG,
+};
 use actix_web::{
+    http::StatusCode,
     web::{Data, Json, Query},
-    Error, HttpResponse,
+    Error, HttpRequest, HttpResponse,
 };
+use jsonwebtoken as jwt;
+use rand::{distributions::Alphanumeric, Rng};
 use serde_json::json;
 
 /// Checks to see if a username is available, and valid, for the server.
@@ -21,11 +35,13 @@ pub async fn get_available<T: Store>(
     params: Query<registration::AvailableParams>,
     storage: Data<T>,
 ) -> Result<HttpResponse, Error> {
-    // TODO: !!!Validate Username:
-    // M_INVALID_USERNAME : The desired username is not a valid user name.
-    // M_EXCLUSIVE : The desired username is in the exclusive namespace claimed by an application service.
+    let localpart = CONFIG.localparts.user_localpart(&params.username)?;
+    let user_id = format!("@{}:{}", localpart, CONFIG.hostname);
+    if CONFIG.appservices.exclusive_user(&user_id).is_some() {
+        return Err(exclusive());
+    }
 
-    let res = storage.is_username_available(&params.username).await;
+    let res = storage.is_username_available(&localpart).await;
 
     match res {
         Ok(available) if available => Ok(HttpResponse::Ok().json(json!({"avaiable": true}))),
@@ -72,13 +88,206 @@ pub async fn get_available<T: Store>(
 /// device_id for the account regardless of input.
 ///
 /// Any user ID returned by this API must conform to the grammar given in the Matrix specification_.
+///
+/// Application services register the users in their namespace with the
+/// `m.login.application_service` type and their `as_token`, without
+/// User-Interactive Authentication. Nobody else may register users in an
+/// exclusive namespace.
+///
+/// Other users register with a password, and no User-Interactive
+/// Authentication stages are required of them yet. Guest accounts aren't
+/// supported. Registrations are recorded in the audit log as
+/// `account.register`.
+///
+/// POST /_matrix/client/r0/register
 pub async fn post_register<T: Store>(
+    http_req: HttpRequest,
     params: Query<registration::RequestParams>,
     mut req: Json<registration::Request>,
     storage: Data<T>,
 ) -> Result<HttpResponse, Error> {
     req.kind = params.kind.clone();
-    println!("{}", storage.get_type());
+    if req.login_type.as_deref() == Some(registration::APPSERVICE_LOGIN_TYPE) {
+        return register_appservice_user(&http_req, &req, storage.get_ref()).await;
+    }
+    if req.kind == Some(registration::Kind::Guest) {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::GUEST_ACCESS_FORBIDDEN,
+            "Guest accounts are not supported.",
+        )
+        .into());
+    }
+    let password = req
+        .password
+        .as_deref()
+        .filter(|password| !password.is_empty())
+        .ok_or_else(|| {
+            MatrixError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::MISSING_PARAM,
+                "A password is required.",
+            )
+        })?;
+
+    let username = req.username.clone().unwrap_or_else(new_username);
+    let localpart = CONFIG.localparts.user_localpart(&username)?;
+    let user_id = UserId::parse(&localpart);
+    let full_id = user_id.to_string();
+    if CONFIG.appservices.exclusive_user(&full_id).is_some() {
+        return Err(exclusive());
+    }
+
+    let password_hash = uia::hash_password(password)
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let created = storage
+        .create_account(&localpart, Some(&password_hash), false)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if !created {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::USER_IN_USE,
+            "Desired user ID is already taken.",
+        )
+        .into());
+    }
+
+    let inhibit_login = req.inhibit_login.unwrap_or(false);
+    let device_id = if inhibit_login {
+        None
+    } else {
+        let device_id = req.device_id.clone().unwrap_or_else(new_device_id);
+        storage
+            .add_device(
+                &localpart,
+                &device_id,
+                req.initial_device_display_name.as_deref(),
+            )
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+        Some(device_id)
+    };
+    audit::record(
+        storage.get_ref(),
+        Some(&full_id),
+        proxy::client_ip(&http_req),
+        "account.register",
+        &json!({ "device_id": device_id }),
+    )
+    .await
+    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    let device_id = match device_id {
+        Some(device_id) => device_id,
+        None => return Ok(HttpResponse::Ok().json(json!({ "user_id": full_id }))),
+    };
+    let access_token = jwt::encode(
+        &jwt::Header::new(jwt::Algorithm::ES256),
+        &Claims::new(&user_id, &device_id),
+        &CONFIG.auth_key,
+    )
+    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    Ok(HttpResponse::Ok().json(json!({
+        "user_id": full_id,
+        "access_token": access_token,
+        "device_id": device_id,
+    })))
+}
+
+/// A random username for a user who didn't ask for one.
+fn new_username() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Registers a user in the namespace of the application service making the
+/// request, logging them in unless `inhibit_login` is set.
+async fn register_appservice_user<T: Store>(
+    http_req: &HttpRequest,
+    req: &registration::Request,
+    storage: &T,
+) -> Result<HttpResponse, Error> {
+    let access_token = Authenticated::access_token(http_req.headers(), http_req.query_string())
+        .ok_or_else(|| {
+            MatrixError::new(
+                StatusCode::UNAUTHORIZED,
+                ErrorCode::MISSING_TOKEN,
+                "Missing access token.",
+            )
+        })?;
+    let auth = Authenticated::from_token(access_token, "")?;
+    let service = auth
+        .appservice_id
+        .as_deref()
+        .and_then(|id| CONFIG.appservices.get(id))
+        .ok_or_else(|| {
+            MatrixError::new(
+                StatusCode::UNAUTHORIZED,
+                ErrorCode::UNKNOWN_TOKEN,
+                "Only application services may register this way.",
+            )
+        })?;
+
+    let username = req.username.as_deref().ok_or_else(|| {
+        MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::MISSING_PARAM,
+            "A username is required.",
+        )
+    })?;
+    // Application services may register reserved names in their namespace,
+    // e.g. for the bridges they were reserved for
+    let localpart = CONFIG.localparts.localpart(username)?;
+    let user_id = UserId::parse(&localpart);
+    let full_id = user_id.to_string();
+    let claimed_by_other = CONFIG
+        .appservices
+        .exclusive_user(&full_id)
+        .map_or(false, |other| other.id != service.id);
+    if !service.is_user_in_namespace(&full_id) || claimed_by_other {
+        return Err(exclusive());
+    }
+
+    let created = storage
+        .create_appservice_account(&localpart, &service.id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if !created {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::USER_IN_USE,
+            "Desired user ID is already taken.",
+        )
+        .into());
+    }
+
+    if req.inhibit_login.unwrap_or(false) {
+        return Ok(HttpResponse::Ok().json(json!({ "user_id": full_id })));
+    }
+    let device_id = req.device_id.clone().unwrap_or_else(new_device_id);
+    let access_token = jwt::encode(
+        &jwt::Header::new(jwt::Algorithm::ES256),
+        &Claims::new(&user_id, &device_id),
+        &CONFIG.auth_key,
+    )
+    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    Ok(HttpResponse::Ok().json(json!({
+        "user_id": full_id,
+        "access_token": access_token,
+        "device_id": device_id,
+    })))
+}
 
-    unimplemented!()
+/// The error for a user ID reserved by an application service.
+fn exclusive() -> Error {
+    MatrixError::new(
+        StatusCode::BAD_REQUEST,
+        ErrorCode::EXCLUSIVE,
+        "This user ID is reserved by an application service.",
+    )
+    .into()
 }
@@ -0,0 +1,35 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json},
+    Error, HttpRequest, HttpResponse,
+};
+use serde_json::json;
+
+use crate::{
+    federation::sender::Notifier,
+    models::federation as model,
+    server::error::{ErrorCode, MatrixError},
+    CONFIG,
+};
+
+/// Wakes the federation sender for a destination something was queued for
+/// by another process. Only other maelstrom processes may call it.
+///
+/// POST /_maelstrom/replication/v1/federation/wake
+pub async fn wake_federation_sender(
+    req: HttpRequest,
+    body: Json<model::WakeRequest>,
+    notifier: Data<Notifier>,
+) -> Result<HttpResponse, Error> {
+    if !CONFIG.workers.is_replication_request(&req) {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "Invalid replication secret.",
+        )
+        .into());
+    }
+    notifier.notify(&body.destination);
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
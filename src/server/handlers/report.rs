@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json, Path},
+    Error, HttpRequest, HttpResponse,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    db::Store,
+    federation::report,
+    models::{
+        report::{self as model, EventReport, ForwardedReport},
+        room,
+    },
+    scheduler,
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+        server_auth,
+    },
+};
+
+/// Reports an event as inappropriate to the server's admins. If the event
+/// came from another server, and forwarding reports is turned on, the
+/// report is forwarded to that server too, without saying who sent it.
+///
+/// TODO: Check that the event exists, and that the user can see it, once
+/// events are stored.
+///
+/// POST /_matrix/client/r0/rooms/{roomId}/report/{eventId}
+pub async fn report_event<T: Store>(
+    auth: Authenticated,
+    path: Path<model::ReportPath>,
+    req: Json<model::ReportRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    if req
+        .score
+        .map_or(false, |score| score < model::MIN_SCORE || score > 0)
+    {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_PARAM,
+            "score must be between -100 and 0.",
+        )
+        .into());
+    }
+    let user_id = auth.user_id.to_string();
+    let membership = storage
+        .get_room_membership(&path.room_id, &user_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if membership.as_deref() != Some(room::JOIN) {
+        return Err(MatrixError::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::NOT_FOUND,
+            "Unable to report an event in a room you are not in.",
+        )
+        .into());
+    }
+
+    let req = req.into_inner();
+    let report_id = storage
+        .add_event_report(&EventReport {
+            room_id: path.room_id.clone(),
+            event_id: path.event_id.clone(),
+            reporter: Some(user_id),
+            origin: None,
+            reason: req.reason.clone(),
+            score: req.score,
+        })
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    tracing::info!(report_id, room_id = %path.room_id, event_id = %path.event_id, "Event reported");
+
+    if let Some(server_name) = report::event_origin(&path.event_id) {
+        if report::should_forward(server_name) {
+            scheduler::schedule(
+                storage.get_ref(),
+                scheduler::FORWARD_REPORT,
+                &json!({
+                    "server_name": server_name,
+                    "room_id": path.room_id,
+                    "event_id": path.event_id,
+                    "reason": req.reason,
+                }),
+                Duration::from_secs(0),
+            )
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Receives a report another server forwarded about an event, for the
+/// server's admins to look into.
+///
+/// TODO: Check that the event was sent by one of our users, and that the
+/// reporting server is in the room, once events are stored.
+///
+/// POST /_matrix/federation/unstable/org.matrix.msc3843/rooms/{roomId}/report/{eventId}
+pub async fn receive_report<T: Store>(
+    req: HttpRequest,
+    path: Path<model::ReportPath>,
+    body: Json<Value>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let origin = server_auth::authenticate(storage.get_ref(), &req, Some(&*body)).await?;
+    let report: ForwardedReport = serde_json::from_value(body.into_inner())
+        .with_codes(StatusCode::BAD_REQUEST, ErrorCode::BAD_JSON)?;
+
+    let report_id = storage
+        .add_event_report(&EventReport {
+            room_id: path.room_id.clone(),
+            event_id: path.event_id.clone(),
+            reporter: None,
+            origin: Some(origin.clone()),
+            reason: report.reason,
+            score: None,
+        })
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    tracing::info!(
+        report_id,
+        %origin,
+        room_id = %path.room_id,
+        event_id = %path.event_id,
+        "Event report forwarded"
+    );
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
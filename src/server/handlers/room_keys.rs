@@ -0,0 +1,469 @@
+use std::collections::HashMap;
+
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json, Path, Query},
+    Error, HttpResponse,
+};
+use serde_json::json;
+
+use crate::{
+    db::Store,
+    models::room_keys::{self as model, KeyBackupData, KeysBackup, RoomKey, RoomKeyBackup},
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+    },
+};
+
+fn unknown_version() -> MatrixError {
+    MatrixError::new(
+        StatusCode::NOT_FOUND,
+        ErrorCode::NOT_FOUND,
+        "Unknown backup version.",
+    )
+}
+
+fn parse_version(version: &str) -> Result<i64, MatrixError> {
+    version.parse().map_err(|_| unknown_version())
+}
+
+/// Loads a backup version along with its key count, as returned to clients.
+async fn version_info<T: Store>(
+    storage: &T,
+    localpart: &str,
+    version: Option<i64>,
+) -> Result<model::VersionInfo, MatrixError> {
+    let backup = storage
+        .get_backup_version(localpart, version)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .ok_or_else(unknown_version)?;
+    let count = storage
+        .count_room_keys(localpart, backup.version)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(model::VersionInfo {
+        algorithm: backup.algorithm,
+        auth_data: backup.auth_data,
+        count,
+        etag: backup.etag.to_string(),
+        version: backup.version.to_string(),
+    })
+}
+
+/// Keys may only be added to the current backup version, so clients that
+/// have not noticed a new backup was created stop writing to the old one.
+async fn check_current_version<T: Store>(
+    storage: &T,
+    localpart: &str,
+    version: &str,
+) -> Result<i64, Error> {
+    let current = storage
+        .get_backup_version(localpart, None)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    match current {
+        Some(current) if current.version.to_string() == version => Ok(current.version),
+        Some(current) => Err(HttpResponse::Forbidden()
+            .json(json!({
+                "errcode": ErrorCode::WRONG_ROOM_KEYS_VERSION,
+                "error": "Wrong backup version.",
+                "current_version": current.version.to_string(),
+            }))
+            .into()),
+        None => Err(unknown_version().into()),
+    }
+}
+
+/// The etag and key count returned after modifying a backup.
+async fn update_response<T: Store>(
+    storage: &T,
+    localpart: &str,
+    version: i64,
+) -> Result<HttpResponse, Error> {
+    let info = version_info(storage, localpart, Some(version)).await?;
+    Ok(HttpResponse::Ok().json(model::UpdateResponse {
+        etag: info.etag,
+        count: info.count,
+    }))
+}
+
+/// Stores uploaded keys, keeping any existing key that is better than the
+/// uploaded one.
+async fn store_keys<T: Store>(
+    storage: &T,
+    localpart: &str,
+    version: &str,
+    keys: Vec<RoomKey>,
+) -> Result<HttpResponse, Error> {
+    let version = check_current_version(storage, localpart, version).await?;
+
+    let mut existing = HashMap::new();
+    let mut rooms: Vec<&str> = keys.iter().map(|k| k.room_id.as_str()).collect();
+    rooms.dedup();
+    for room_id in rooms {
+        let room_keys = storage
+            .get_room_keys(localpart, version, Some(room_id), None)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+        existing.extend(
+            room_keys
+                .into_iter()
+                .map(|k| ((k.room_id, k.session_id), k.data)),
+        );
+    }
+
+    let to_store: Vec<RoomKey> = keys
+        .iter()
+        .filter(
+            |k| match existing.get(&(k.room_id.clone(), k.session_id.clone())) {
+                Some(current) => k.data.is_better_than(current),
+                None => true,
+            },
+        )
+        .cloned()
+        .collect();
+    if !to_store.is_empty() {
+        storage
+            .put_room_keys(localpart, version, &to_store)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    }
+
+    update_response(storage, localpart, version).await
+}
+
+async fn load_keys<T: Store>(
+    storage: &T,
+    localpart: &str,
+    version: &str,
+    room_id: Option<&str>,
+    session_id: Option<&str>,
+) -> Result<Vec<RoomKey>, MatrixError> {
+    let version = parse_version(version)?;
+    storage
+        .get_backup_version(localpart, Some(version))
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .ok_or_else(unknown_version)?;
+    storage
+        .get_room_keys(localpart, version, room_id, session_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)
+}
+
+async fn remove_keys<T: Store>(
+    storage: &T,
+    localpart: &str,
+    version: &str,
+    room_id: Option<&str>,
+    session_id: Option<&str>,
+) -> Result<HttpResponse, Error> {
+    let version = parse_version(version)?;
+    storage
+        .get_backup_version(localpart, Some(version))
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .ok_or_else(unknown_version)?;
+    storage
+        .delete_room_keys(localpart, version, room_id, session_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    update_response(storage, localpart, version).await
+}
+
+/// Creates a new backup.
+///
+/// POST /_matrix/client/r0/room_keys/version
+pub async fn create_version<T: Store>(
+    auth: Authenticated,
+    req: Json<model::CreateVersionRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let version = storage
+        .create_backup_version(&auth.user_id.local_part, &req.algorithm, &req.auth_data)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(json!({ "version": version.to_string() })))
+}
+
+/// Get information about the latest backup version.
+///
+/// GET /_matrix/client/r0/room_keys/version
+pub async fn get_latest_version<T: Store>(
+    auth: Authenticated,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let info = version_info(storage.get_ref(), &auth.user_id.local_part, None).await?;
+    Ok(HttpResponse::Ok().json(info))
+}
+
+/// Get information about an existing backup.
+///
+/// GET /_matrix/client/r0/room_keys/version/{version}
+pub async fn get_version<T: Store>(
+    auth: Authenticated,
+    path: Path<model::VersionPath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let version = parse_version(&path.version)?;
+    let info = version_info(storage.get_ref(), &auth.user_id.local_part, Some(version)).await?;
+    Ok(HttpResponse::Ok().json(info))
+}
+
+/// Update information about an existing backup. Only `auth_data` can be
+/// modified.
+///
+/// PUT /_matrix/client/r0/room_keys/version/{version}
+pub async fn update_version<T: Store>(
+    auth: Authenticated,
+    path: Path<model::VersionPath>,
+    req: Json<model::UpdateVersionRequest>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let localpart = &auth.user_id.local_part;
+    let version = parse_version(&path.version)?;
+    if req.version.as_ref().map_or(false, |v| v != &path.version) {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_PARAM,
+            "Version in body does not match the version in the path.",
+        )
+        .into());
+    }
+    let backup = storage
+        .get_backup_version(localpart, Some(version))
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .ok_or_else(unknown_version)?;
+    if backup.algorithm != req.algorithm {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_PARAM,
+            "The algorithm of a backup cannot be changed.",
+        )
+        .into());
+    }
+    storage
+        .update_backup_version(localpart, version, &req.auth_data)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Delete an existing key backup. Both the information about the backup, as
+/// well as all key data related to the backup will be deleted.
+///
+/// DELETE /_matrix/client/r0/room_keys/version/{version}
+pub async fn delete_version<T: Store>(
+    auth: Authenticated,
+    path: Path<model::VersionPath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let version = parse_version(&path.version)?;
+    let deleted = storage
+        .delete_backup_version(&auth.user_id.local_part, version)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    if deleted {
+        Ok(HttpResponse::Ok().json(json!({})))
+    } else {
+        Err(unknown_version().into())
+    }
+}
+
+/// Store several keys in the backup.
+///
+/// PUT /_matrix/client/r0/room_keys/keys
+pub async fn put_keys<T: Store>(
+    auth: Authenticated,
+    params: Query<model::VersionParams>,
+    req: Json<KeysBackup>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    store_keys(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        &params.version,
+        req.into_inner().into_keys(),
+    )
+    .await
+}
+
+/// Store several keys for a room in the backup.
+///
+/// PUT /_matrix/client/r0/room_keys/keys/{roomId}
+pub async fn put_room_keys<T: Store>(
+    auth: Authenticated,
+    path: Path<model::RoomPath>,
+    params: Query<model::VersionParams>,
+    req: Json<RoomKeyBackup>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let mut backup = KeysBackup::default();
+    backup.rooms.insert(path.room_id.clone(), req.into_inner());
+    store_keys(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        &params.version,
+        backup.into_keys(),
+    )
+    .await
+}
+
+/// Store a single key in the backup.
+///
+/// PUT /_matrix/client/r0/room_keys/keys/{roomId}/{sessionId}
+pub async fn put_session_key<T: Store>(
+    auth: Authenticated,
+    path: Path<model::SessionPath>,
+    params: Query<model::VersionParams>,
+    req: Json<KeyBackupData>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let key = RoomKey {
+        room_id: path.room_id.clone(),
+        session_id: path.session_id.clone(),
+        data: req.into_inner(),
+    };
+    store_keys(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        &params.version,
+        vec![key],
+    )
+    .await
+}
+
+/// Retrieve the keys from the backup.
+///
+/// GET /_matrix/client/r0/room_keys/keys
+pub async fn get_keys<T: Store>(
+    auth: Authenticated,
+    params: Query<model::VersionParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let keys = load_keys(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        &params.version,
+        None,
+        None,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(KeysBackup::from_keys(keys)))
+}
+
+/// Retrieve the keys from the backup for a given room.
+///
+/// GET /_matrix/client/r0/room_keys/keys/{roomId}
+pub async fn get_room_keys<T: Store>(
+    auth: Authenticated,
+    path: Path<model::RoomPath>,
+    params: Query<model::VersionParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let keys = load_keys(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        &params.version,
+        Some(&path.room_id),
+        None,
+    )
+    .await?;
+    let room = KeysBackup::from_keys(keys)
+        .rooms
+        .remove(&path.room_id)
+        .unwrap_or_default();
+    Ok(HttpResponse::Ok().json(room))
+}
+
+/// Retrieve a key from the backup.
+///
+/// GET /_matrix/client/r0/room_keys/keys/{roomId}/{sessionId}
+pub async fn get_session_key<T: Store>(
+    auth: Authenticated,
+    path: Path<model::SessionPath>,
+    params: Query<model::VersionParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let mut keys = load_keys(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        &params.version,
+        Some(&path.room_id),
+        Some(&path.session_id),
+    )
+    .await?;
+    match keys.pop() {
+        Some(key) => Ok(HttpResponse::Ok().json(key.data)),
+        None => Err(MatrixError::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::NOT_FOUND,
+            "No key found for this session.",
+        )
+        .into()),
+    }
+}
+
+/// Delete the keys from the backup.
+///
+/// DELETE /_matrix/client/r0/room_keys/keys
+pub async fn delete_keys<T: Store>(
+    auth: Authenticated,
+    params: Query<model::VersionParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    remove_keys(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        &params.version,
+        None,
+        None,
+    )
+    .await
+}
+
+/// Delete the keys from the backup for a given room.
+///
+/// DELETE /_matrix/client/r0/room_keys/keys/{roomId}
+pub async fn delete_room_keys<T: Store>(
+    auth: Authenticated,
+    path: Path<model::RoomPath>,
+    params: Query<model::VersionParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    remove_keys(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        &params.version,
+        Some(&path.room_id),
+        None,
+    )
+    .await
+}
+
+/// Delete a key from the backup.
+///
+/// DELETE /_matrix/client/r0/room_keys/keys/{roomId}/{sessionId}
+pub async fn delete_session_key<T: Store>(
+    auth: Authenticated,
+    path: Path<model::SessionPath>,
+    params: Query<model::VersionParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    remove_keys(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        &params.version,
+        Some(&path.room_id),
+        Some(&path.session_id),
+    )
+    .await
+}
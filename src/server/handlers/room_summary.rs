@@ -0,0 +1,52 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Path},
+    Error, HttpResponse,
+};
+
+use crate::{
+    db::Store,
+    models::room as model,
+    room_summary,
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+    },
+};
+
+/// Summarizes a room the user has joined or is invited to. Any other room
+/// is as unknown as one that doesn't exist.
+///
+/// TODO: Resolve room aliases, and summarize rooms anyone may join or
+/// preview, once aliases and rooms' join rules are stored.
+///
+/// GET /_matrix/client/unstable/im.nheko.summary/rooms/{roomIdOrAlias}/summary
+pub async fn get_summary<T: Store>(
+    auth: Authenticated,
+    path: Path<model::SummaryPath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let room_id = &path.room_id_or_alias;
+    let membership = storage
+        .get_room_membership(room_id, &auth.user_id.to_string())
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if !model::is_member(membership.as_deref()) {
+        return Err(MatrixError::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::NOT_FOUND,
+            "Room not found.",
+        )
+        .into());
+    }
+
+    let summary = room_summary::summary(storage.get_ref(), room_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+        .unwrap_or_default();
+    Ok(HttpResponse::Ok().json(model::SummaryResponse {
+        room_id: room_id.clone(),
+        num_joined_members: summary.joined_member_count,
+        membership,
+    }))
+}
@@ -0,0 +1,283 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Query},
+    Error, HttpResponse,
+};
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::{
+    bus::{Message, BUS},
+    db::Store,
+    models::{
+        push::MAIN_THREAD,
+        sync::{self as model, SyncToken},
+    },
+    room_summary::{self, MAX_HEROES},
+    server::{
+        error::{ErrorCode, ResultExt as _},
+        extract::Authenticated,
+        handlers::user::ignored_users,
+    },
+};
+
+/// The most to-device messages handed to a device in a single sync. Anything
+/// beyond this is delivered by the following syncs.
+const TO_DEVICE_LIMIT: i64 = 100;
+/// The longest a sync waits for something new, in milliseconds.
+const MAX_TIMEOUT: u64 = 5 * 60 * 1000;
+
+/// Whether a sync filter, given inline, asks for unread counts per thread.
+///
+/// TODO: Look up filters given by ID, once they can be uploaded.
+fn unread_thread_notifications(filter: Option<&str>) -> bool {
+    filter
+        .and_then(|filter| serde_json::from_str::<Value>(filter).ok())
+        .and_then(|filter| {
+            filter
+                .pointer("/room/timeline/unread_thread_notifications")
+                .and_then(Value::as_bool)
+        })
+        .unwrap_or(false)
+}
+
+/// Synchronise the client's state with the latest state on the server.
+///
+/// Clients use this API when they first log in to get an initial snapshot of
+/// the state on the server, and then continue to call this API to get
+/// incremental deltas to the state, and to receive new messages.
+///
+/// Passing a `since` token acknowledges everything delivered up to that
+/// point, so queued to-device messages are only removed once the client has
+/// shown it received them.
+///
+/// `device_lists` tells the client whose device keys to re-query. On an
+/// initial sync the client has no keys cached yet, so it is left empty.
+///
+/// Invites from other servers are listed under `rooms.invite`, unless they
+/// were sent by an ignored user.
+///
+/// Joined rooms whose unread notification counts changed are listed under
+/// `rooms.join` with their counts, per thread if the filter sets
+/// `room.timeline.unread_thread_notifications`.
+///
+/// Account data that changed is listed under `account_data`, or the room's
+/// `account_data` for data scoped to a room. An initial sync has all of it.
+///
+/// Every joined room listed has the summary of its members, from which
+/// clients name rooms without a name of their own.
+///
+/// Each section is read from its own stream, up to the positions the
+/// streams were at when the sync started. Positions are committed in order,
+/// so `next_batch` never skips a change still being stored.
+///
+/// Unless it is an initial sync, the response waits up to `timeout` for
+/// something new, woken through the replication bus by whichever process
+/// stored it.
+///
+/// TODO: Joined room timelines and state, left rooms and presence sections.
+/// TODO: Include users sharing an encrypted room with the requester in
+/// `device_lists.changed`, and fill `device_lists.left`, once rooms exist.
+///
+/// GET /_matrix/client/r0/sync
+pub async fn get_sync<T: Store>(
+    auth: Authenticated,
+    params: Query<model::SyncParams>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    let since = match &params.since {
+        Some(since) => since
+            .parse::<SyncToken>()
+            .with_codes(StatusCode::BAD_REQUEST, ErrorCode::INVALID_PARAM)?,
+        None => SyncToken::default(),
+    };
+    // Subscribed before looking, so nothing new is missed in between
+    let mut updates = BUS.subscribe();
+    let mut response = sync_once(storage.get_ref(), &auth, &params, since).await?;
+
+    // Initial syncs are answered straight away
+    if params.since.is_some() && response.is_empty() {
+        let timeout = Duration::from_millis(params.timeout.unwrap_or(0).min(MAX_TIMEOUT));
+        let localpart = &auth.user_id.local_part;
+        let woken = async {
+            while let Some(message) = updates.next().await {
+                if let Message::NewData { localparts } = message {
+                    if localparts.contains(localpart) {
+                        return true;
+                    }
+                }
+            }
+            false
+        };
+        if let Ok(true) = actix_rt::time::timeout(timeout, woken).await {
+            response = sync_once(storage.get_ref(), &auth, &params, since).await?;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Builds a sync response from what is new since `since`.
+async fn sync_once<T: Store>(
+    storage: &T,
+    auth: &Authenticated,
+    params: &model::SyncParams,
+    since: SyncToken,
+) -> Result<model::SyncResponse, Error> {
+    let localpart = &auth.user_id.local_part;
+    let mut next_batch = since;
+    // Anything after these is left for the next sync
+    let positions = storage
+        .get_stream_positions()
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    storage
+        .delete_to_device_messages(localpart, &auth.device_id, since.to_device)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let messages = storage
+        .get_to_device_messages(
+            localpart,
+            &auth.device_id,
+            since.to_device,
+            positions.to_device,
+            TO_DEVICE_LIMIT,
+        )
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if let Some((stream_id, _)) = messages.last() {
+        next_batch.to_device = *stream_id;
+    }
+
+    let ignored = ignored_users(storage, localpart).await?;
+    let to_device = model::ToDevice {
+        events: ignored.filter_events(messages.into_iter().map(|(_, event)| event).collect()),
+    };
+
+    let invites = storage
+        .get_room_invites(localpart, since.invites, positions.invites)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    if let Some((stream_id, _)) = invites.last() {
+        next_batch.invites = *stream_id;
+    }
+    let mut rooms = model::Rooms::default();
+    for (_, invite) in invites {
+        let sender = invite.event.get("sender").and_then(Value::as_str);
+        if sender.map_or(false, |sender| ignored.is_ignored(sender)) {
+            continue;
+        }
+        // Clients are only given the stripped form of the invite, like the
+        // rest of the room's state
+        let mut events = invite.invite_room_state;
+        events.push(json!({
+            "type": invite.event["type"],
+            "state_key": invite.event["state_key"],
+            "sender": invite.event["sender"],
+            "content": invite.event["content"],
+        }));
+        rooms.invite.insert(
+            invite.room_id,
+            model::InvitedRoom {
+                invite_state: model::InviteState { events },
+            },
+        );
+    }
+
+    let mut account_data = model::AccountData::default();
+    let changes = storage
+        .get_account_data_changes(localpart, since.account_data, positions.account_data)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    next_batch.account_data = positions.account_data;
+    for (room_id, data_type, content) in changes {
+        let event = json!({ "type": data_type, "content": content });
+        match room_id {
+            Some(room_id) => rooms
+                .join
+                .entry(room_id)
+                .or_default()
+                .account_data
+                .events
+                .push(event),
+            None => account_data.events.push(event),
+        }
+    }
+
+    let push_counts = storage
+        .get_push_counts(localpart, since.push_counts, positions.push_counts)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let by_thread = unread_thread_notifications(params.filter.as_deref());
+    for counts in push_counts {
+        let room = rooms.join.entry(counts.room_id).or_default();
+        let unread = if by_thread && counts.thread_id != MAIN_THREAD {
+            room.unread_thread_notifications
+                .entry(counts.thread_id)
+                .or_default()
+        } else {
+            room.unread_notifications
+                .get_or_insert_with(Default::default)
+        };
+        unread.notification_count += counts.notification_count;
+        unread.highlight_count += counts.highlight_count;
+    }
+    next_batch.push_counts = positions.push_counts;
+
+    // TODO: Only send the summaries that changed since the previous sync,
+    // once rooms' timelines say which did.
+    let user_id = auth.user_id.to_string();
+    for (room_id, room) in rooms.join.iter_mut() {
+        room.summary = room_summary::summary(storage, room_id)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+            .map(|summary| summary.for_user(&user_id, MAX_HEROES));
+    }
+
+    next_batch.device_lists = positions.device_lists;
+    let mut device_lists = model::DeviceLists::default();
+    if params.since.is_some() {
+        device_lists.changed = storage
+            .get_device_list_changes(since.device_lists, next_batch.device_lists)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+            .into_iter()
+            .filter(|changed| changed == &user_id)
+            .collect();
+    }
+
+    let device_one_time_keys_count = storage
+        .count_one_time_keys(localpart, &auth.device_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let device_unused_fallback_key_types = storage
+        .get_unused_fallback_key_types(localpart, &auth.device_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    Ok(model::SyncResponse {
+        next_batch: next_batch.to_string(),
+        rooms,
+        account_data,
+        to_device,
+        device_lists,
+        device_one_time_keys_count,
+        device_unused_fallback_key_types,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unread_thread_notifications() {
+        let filter = r#"{"room": {"timeline": {"unread_thread_notifications": true}}}"#;
+        assert!(unread_thread_notifications(Some(filter)));
+        assert!(!unread_thread_notifications(Some(r#"{"room": {}}"#)));
+        assert!(!unread_thread_notifications(Some("filter_id")));
+        assert!(!unread_thread_notifications(None));
+    }
+}
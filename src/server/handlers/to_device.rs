@@ -0,0 +1,131 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json, Path},
+    Error, HttpResponse,
+};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use crate::{
+    bus::BUS,
+    db::Store,
+    federation::sender::Notifier,
+    models::{auth::UserId, to_device as model},
+    server::{
+        error::{ErrorCode, ResultExt as _},
+        extract::Authenticated,
+        ratelimit::SendLimit,
+    },
+    CONFIG,
+};
+
+/// Addresses to-device messages, given as user ID to device ID (or `*`) to
+/// content, to each local device they are for. Messages for remote users
+/// are skipped.
+pub async fn local_messages<T: Store>(
+    storage: &T,
+    messages: &BTreeMap<String, BTreeMap<String, Value>>,
+) -> Result<Vec<model::Message>, Error> {
+    let mut local = Vec::new();
+    for (user_id, devices) in messages {
+        let recipient = UserId::parse(user_id);
+        if !recipient.is_local() {
+            continue;
+        }
+        for (device_id, content) in devices {
+            let device_ids = if device_id == model::ALL_DEVICES {
+                storage
+                    .get_device_ids(&recipient.local_part)
+                    .await
+                    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?
+            } else {
+                vec![device_id.clone()]
+            };
+            local.extend(device_ids.into_iter().map(|device_id| model::Message {
+                localpart: recipient.local_part.clone(),
+                device_id,
+                content: content.clone(),
+            }));
+        }
+    }
+    Ok(local)
+}
+
+/// Groups the messages for remote users by the server they are on.
+fn remote_messages(
+    messages: &BTreeMap<String, BTreeMap<String, Value>>,
+) -> BTreeMap<String, BTreeMap<String, BTreeMap<String, Value>>> {
+    let mut remote: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+    for (user_id, devices) in messages {
+        let recipient = UserId::parse(user_id);
+        if !recipient.is_local() {
+            remote
+                .entry(recipient.domain.to_string())
+                .or_default()
+                .insert(user_id.clone(), devices.clone());
+        }
+    }
+    remote
+}
+
+/// This endpoint is used to send send-to-device events to a set of client
+/// devices.
+///
+/// Messages for local users are queued per device and delivered in the
+/// `to_device` section of that device's next `/sync`. A device ID of `*`
+/// addresses every device the user has. Messages for remote users are sent
+/// to their servers as `m.direct_to_device` EDUs. Retrying a transaction ID
+/// returns success without queueing the messages a second time.
+///
+/// Each request takes one event from the sender's event send budget.
+///
+/// PUT /_matrix/client/r0/sendToDevice/{eventType}/{txnId}
+pub async fn send<T: Store>(
+    auth: Authenticated,
+    path: Path<model::SendPath>,
+    req: Json<model::SendRequest>,
+    storage: Data<T>,
+    notifier: Data<Notifier>,
+    send_limit: Data<SendLimit>,
+) -> Result<HttpResponse, Error> {
+    send_limit.check(storage.get_ref(), &auth).await?;
+    let messages = local_messages(storage.get_ref(), &req.messages).await?;
+
+    let sender = auth.user_id.to_string();
+    let is_new = storage
+        .add_to_device_messages(
+            &sender,
+            &auth.device_id,
+            &path.txn_id,
+            &path.event_type,
+            &messages,
+        )
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    if is_new {
+        BUS.new_data(messages.iter().map(|message| message.localpart.as_str()));
+        for (destination, messages) in remote_messages(&req.messages) {
+            // Messages for servers we don't federate with are dropped
+            if !CONFIG.federation_policy.is_allowed(&destination) {
+                continue;
+            }
+            let edu = json!({
+                "edu_type": "m.direct_to_device",
+                "content": {
+                    "sender": sender,
+                    "type": path.event_type,
+                    "message_id": format!("{}_{}", auth.device_id, path.txn_id),
+                    "messages": messages,
+                },
+            });
+            storage
+                .add_federation_outbound(&destination, &[], &[edu])
+                .await
+                .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+            notifier.notify(&destination);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
@@ -1 +1,184 @@
+use actix_web::{
+    http::StatusCode,
+    web::{Data, Json, Path},
+    Error, HttpResponse,
+};
+use serde_json::{json, Value};
 
+use crate::{
+    bus::BUS,
+    db::Store,
+    models::{
+        account_data::{self as model, IgnoredUserList},
+        auth::UserId,
+    },
+    server::{
+        error::{ErrorCode, MatrixError, ResultExt as _},
+        extract::Authenticated,
+    },
+};
+
+/// Ensures the requester is only touching their own account data.
+fn check_owner(auth: &Authenticated, user_id: &str) -> Result<(), MatrixError> {
+    if UserId::parse(user_id) == auth.user_id {
+        Ok(())
+    } else {
+        Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "Cannot access account data for other users.",
+        ))
+    }
+}
+
+/// Stores the content, updating the indexed ignore list along with it when
+/// the client updates `m.ignored_user_list`.
+async fn store_account_data<T: Store>(
+    storage: &T,
+    localpart: &str,
+    room_id: Option<&str>,
+    data_type: &str,
+    content: &Value,
+) -> Result<(), MatrixError> {
+    if room_id.is_none() && data_type == model::IGNORED_USER_LIST {
+        let list: IgnoredUserList = serde_json::from_value(content.clone())
+            .with_codes(StatusCode::BAD_REQUEST, ErrorCode::BAD_JSON)?;
+        let ignored: Vec<String> = list.ignored_users.keys().cloned().collect();
+        storage
+            .set_ignored_users(localpart, &ignored, content)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    } else {
+        storage
+            .set_account_data(localpart, room_id, data_type, content)
+            .await
+            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    }
+    // Wakes the user's other clients' syncs
+    BUS.new_data(vec![localpart]);
+    Ok(())
+}
+
+async fn fetch_account_data<T: Store>(
+    storage: &T,
+    localpart: &str,
+    room_id: Option<&str>,
+    data_type: &str,
+) -> Result<HttpResponse, Error> {
+    let content = storage
+        .get_account_data(localpart, room_id, data_type)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+
+    match content {
+        Some(content) => Ok(HttpResponse::Ok().json(content)),
+        None => Err(MatrixError::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::NOT_FOUND,
+            "Account data not found.",
+        )
+        .into()),
+    }
+}
+
+/// Set some account_data for the client. This config is only visible to the user
+/// that set the account_data. The config will be synced to clients in the
+/// top-level `account_data`.
+///
+/// Setting `m.ignored_user_list` additionally updates the server-side ignore
+/// list used to hide the ignored users' events from the client.
+///
+/// PUT /_matrix/client/r0/user/{userId}/account_data/{type}
+pub async fn put_account_data<T: Store>(
+    auth: Authenticated,
+    path: Path<model::AccountDataPath>,
+    content: Json<Value>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    check_owner(&auth, &path.user_id)?;
+    store_account_data(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        None,
+        &path.data_type,
+        &content,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Get some account_data for the client. This config is only visible to the user
+/// that set the account_data.
+///
+/// GET /_matrix/client/r0/user/{userId}/account_data/{type}
+pub async fn get_account_data<T: Store>(
+    auth: Authenticated,
+    path: Path<model::AccountDataPath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    check_owner(&auth, &path.user_id)?;
+    fetch_account_data(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        None,
+        &path.data_type,
+    )
+    .await
+}
+
+/// Set some account_data for the client on a given room. This config is only
+/// visible to the user that set the account_data. The config will be synced to
+/// clients in the per-room `account_data`.
+///
+/// PUT /_matrix/client/r0/user/{userId}/rooms/{roomId}/account_data/{type}
+pub async fn put_room_account_data<T: Store>(
+    auth: Authenticated,
+    path: Path<model::RoomAccountDataPath>,
+    content: Json<Value>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    check_owner(&auth, &path.user_id)?;
+    store_account_data(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        Some(&path.room_id),
+        &path.data_type,
+        &content,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Get some account_data for the client on a given room. This config is only
+/// visible to the user that set the account_data.
+///
+/// GET /_matrix/client/r0/user/{userId}/rooms/{roomId}/account_data/{type}
+pub async fn get_room_account_data<T: Store>(
+    auth: Authenticated,
+    path: Path<model::RoomAccountDataPath>,
+    storage: Data<T>,
+) -> Result<HttpResponse, Error> {
+    check_owner(&auth, &path.user_id)?;
+    fetch_account_data(
+        storage.get_ref(),
+        &auth.user_id.local_part,
+        Some(&path.room_id),
+        &path.data_type,
+    )
+    .await
+}
+
+/// Loads the ignore list for a local user, for filtering the events served
+/// to them.
+pub async fn ignored_users<T: Store>(
+    storage: &T,
+    localpart: &str,
+) -> Result<IgnoredUserList, MatrixError> {
+    storage
+        .get_ignored_users(localpart)
+        .await
+        .map(IgnoredUserList::from_user_ids)
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)
+}
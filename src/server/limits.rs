@@ -0,0 +1,264 @@
+//! Limits on how long a request may take to be answered, and how large its
+//! body may be, by the kind of API it is for.
+//!
+//! Requests declaring a body larger than their limit are refused with
+//! `M_TOO_LARGE` before they are read. Chunked bodies are caught as they
+//! are read instead: JSON bodies by `Limit::json_config`, and uploads by their
+//! handler, which streams them against `MAX_UPLOAD_SIZE`. A request still
+//! being handled when its timeout runs out is cancelled, and answered with
+//! a 504, so a stuck database or remote server can't tie up connections.
+use std::time::Duration;
+
+use actix_web::{
+    error::JsonPayloadError,
+    http::{header, HeaderMap, StatusCode},
+    web::JsonConfig,
+    Error,
+};
+
+use super::error::{ErrorCode, MatrixError};
+
+/// The largest JSON body the client and admin APIs accept by default, in
+/// bytes.
+pub const DEFAULT_MAX_REQUEST_SIZE: u64 = 1024 * 1024;
+/// The largest federation request body accepted by default, in bytes. PDUs
+/// are at most 64KiB each, and EDUs are small, so a full transaction fits.
+pub const DEFAULT_MAX_FEDERATION_REQUEST_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The kinds of API requests are limited by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category {
+    /// The client and admin APIs, and everything else not listed here
+    Client,
+    /// `/sync`, which waits for up to 5 minutes for something to happen
+    Sync,
+    /// The server-server API
+    Federation,
+    /// Media downloads, thumbnails and URL previews
+    Media,
+    /// Media uploads
+    Upload,
+}
+
+impl Category {
+    /// The category of a request for `path`.
+    pub fn of(path: &str) -> Self {
+        if path.starts_with("/_matrix/media/") || path.starts_with("/_matrix/client/v1/media/") {
+            if path.ends_with("/upload") {
+                Category::Upload
+            } else {
+                Category::Media
+            }
+        } else if path.starts_with("/_matrix/federation/") || path.starts_with("/_matrix/key/") {
+            Category::Federation
+        } else if path.starts_with("/_matrix/client/") && path.ends_with("/sync") {
+            Category::Sync
+        } else {
+            Category::Client
+        }
+    }
+}
+
+/// The limits of one category of requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    /// How long a request may take, or `None` for no limit
+    pub timeout: Option<Duration>,
+    /// The largest body a request may declare, in bytes, or `None` to leave
+    /// it to the handler
+    pub max_body_size: Option<u64>,
+}
+
+impl Limit {
+    /// Refuses a request whose `Content-Length` is over the limit.
+    pub fn check_body(&self, headers: &HeaderMap) -> Result<(), MatrixError> {
+        let declared = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<u64>().ok());
+        match (declared, self.max_body_size) {
+            (Some(declared), Some(max)) if declared > max => Err(too_large()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads JSON bodies up to the limit, answering those that can't be
+    /// read with the matching Matrix error.
+    pub fn json_config(&self) -> JsonConfig {
+        let limit = self.max_body_size.map_or(usize::MAX, |size| size as usize);
+        JsonConfig::default().limit(limit).error_handler(|err, _| {
+            let error = match &err {
+                JsonPayloadError::Overflow => too_large(),
+                JsonPayloadError::Deserialize(e) => {
+                    MatrixError::new(StatusCode::BAD_REQUEST, ErrorCode::BAD_JSON, e.to_string())
+                }
+                _ => MatrixError::new(
+                    StatusCode::BAD_REQUEST,
+                    ErrorCode::NOT_JSON,
+                    err.to_string(),
+                ),
+            };
+            error.into()
+        })
+    }
+}
+
+/// The limits of every category of requests.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    pub client: Limit,
+    pub sync: Limit,
+    pub federation: Limit,
+    pub media: Limit,
+    pub upload: Limit,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        let limit = |timeout: u64, max_body_size: Option<u64>| Limit {
+            timeout: Some(Duration::from_secs(timeout)),
+            max_body_size,
+        };
+        Limits {
+            client: limit(30, Some(DEFAULT_MAX_REQUEST_SIZE)),
+            // Longer than the longest a sync waits
+            sync: limit(6 * 60, Some(DEFAULT_MAX_REQUEST_SIZE)),
+            federation: limit(60, Some(DEFAULT_MAX_FEDERATION_REQUEST_SIZE)),
+            // Remote media and previews are fetched while the client waits
+            media: limit(2 * 60, Some(DEFAULT_MAX_REQUEST_SIZE)),
+            upload: limit(10 * 60, None),
+        }
+    }
+}
+
+impl Limits {
+    /// Reads the limits from `env` vars, falling back to the defaults.
+    /// Timeouts are in seconds, and 0 turns one off.
+    pub fn from_env() -> Self {
+        let defaults = Limits::default();
+        let timeout = |var: &str, default: Option<Duration>| {
+            std::env::var(var)
+                .map(|seconds| {
+                    let seconds: u64 = seconds
+                        .parse()
+                        .unwrap_or_else(|_| panic!("Unable to parse {} as u64.", var));
+                    Some(Duration::from_secs(seconds)).filter(|_| seconds > 0)
+                })
+                .unwrap_or(default)
+        };
+        let size = |var: &str, default: Option<u64>| {
+            std::env::var(var)
+                .map(|size| {
+                    Some(
+                        size.parse()
+                            .unwrap_or_else(|_| panic!("Unable to parse {} as u64.", var)),
+                    )
+                })
+                .unwrap_or(default)
+        };
+        let max_request_size = size("MAX_REQUEST_SIZE", defaults.client.max_body_size);
+        Limits {
+            client: Limit {
+                timeout: timeout("REQUEST_TIMEOUT", defaults.client.timeout),
+                max_body_size: max_request_size,
+            },
+            sync: Limit {
+                timeout: timeout("SYNC_REQUEST_TIMEOUT", defaults.sync.timeout),
+                max_body_size: max_request_size,
+            },
+            federation: Limit {
+                timeout: timeout("FEDERATION_REQUEST_TIMEOUT", defaults.federation.timeout),
+                max_body_size: size(
+                    "MAX_FEDERATION_REQUEST_SIZE",
+                    defaults.federation.max_body_size,
+                ),
+            },
+            media: Limit {
+                timeout: timeout("MEDIA_REQUEST_TIMEOUT", defaults.media.timeout),
+                max_body_size: max_request_size,
+            },
+            upload: Limit {
+                timeout: timeout("UPLOAD_REQUEST_TIMEOUT", defaults.upload.timeout),
+                max_body_size: None,
+            },
+        }
+    }
+
+    /// The limits of a request for `path`.
+    pub fn for_path(&self, path: &str) -> &Limit {
+        match Category::of(path) {
+            Category::Client => &self.client,
+            Category::Sync => &self.sync,
+            Category::Federation => &self.federation,
+            Category::Media => &self.media,
+            Category::Upload => &self.upload,
+        }
+    }
+}
+
+fn too_large() -> MatrixError {
+    MatrixError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        ErrorCode::TOO_LARGE,
+        "Request body is too large.",
+    )
+}
+
+/// The error a request that ran out of time is answered with.
+pub fn timed_out() -> Error {
+    MatrixError::new(
+        StatusCode::GATEWAY_TIMEOUT,
+        ErrorCode::UNKNOWN,
+        "The request took too long to handle.",
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::HeaderValue;
+
+    #[test]
+    fn test_category() {
+        assert_eq!(Category::of("/_matrix/media/r0/upload"), Category::Upload);
+        assert_eq!(
+            Category::of("/_matrix/client/v1/media/download/example.com/abc"),
+            Category::Media
+        );
+        assert_eq!(Category::of("/_matrix/client/r0/sync"), Category::Sync);
+        assert_eq!(
+            Category::of("/_matrix/federation/v1/send/1"),
+            Category::Federation
+        );
+        assert_eq!(Category::of("/_matrix/key/v2/server"), Category::Federation);
+        assert_eq!(
+            Category::of("/_matrix/client/r0/keys/upload"),
+            Category::Client
+        );
+        assert_eq!(Category::of("/_maelstrom/admin/v1/users"), Category::Client);
+    }
+
+    #[test]
+    fn test_check_body() {
+        let limit = Limit {
+            timeout: None,
+            max_body_size: Some(10),
+        };
+        let mut headers = HeaderMap::new();
+        assert!(limit.check_body(&headers).is_ok());
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("10"));
+        assert!(limit.check_body(&headers).is_ok());
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("11"));
+        assert_eq!(
+            limit.check_body(&headers).unwrap_err().status,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        let unlimited = Limit {
+            max_body_size: None,
+            ..limit
+        };
+        assert!(unlimited.check_body(&headers).is_ok());
+    }
+}
@@ -0,0 +1,89 @@
+//! Tuning the connections the server accepts.
+//!
+//! HTTPS listeners offer HTTP/2 with ALPN, so other servers and clients can
+//! multiplex their requests over few connections, which federation traffic
+//! benefits from most. Plain HTTP listeners speak HTTP/1.1, as they are
+//! expected to sit behind a reverse proxy that keeps its own connections.
+//!
+//! HTTP/2 can't be turned off, nor its concurrent streams limited: the HTTP
+//! server overwrites the ALPN protocols of the rustls config it is given
+//! with `h2` and `http/1.1`, and starts HTTP/2 connections with the `h2`
+//! crate's default settings. Both are listed under "Deferred Features" in
+//! the README.
+
+/// How connections are accepted and kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// Seconds an idle connection is kept open for, or `None` to close it
+    /// after each response
+    pub keep_alive: Option<usize>,
+    /// Milliseconds a client has to send a request's headers, or 0 for no
+    /// limit
+    pub client_timeout: u64,
+    /// Milliseconds a connection has to be closed cleanly once it is done
+    /// with, or 0 for no limit
+    pub client_shutdown: u64,
+    /// The most connections each worker thread has open at once
+    pub max_connections: usize,
+    /// The most TLS handshakes each worker thread does at once
+    pub max_connection_rate: usize,
+    /// The most connections waiting to be accepted
+    pub backlog: i32,
+}
+
+impl Default for Settings {
+    /// The HTTP server's own defaults.
+    fn default() -> Self {
+        Settings {
+            keep_alive: Some(5),
+            client_timeout: 5000,
+            client_shutdown: 5000,
+            max_connections: 25_000,
+            max_connection_rate: 256,
+            backlog: 2048,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the connection settings from `env` vars, falling back to the
+    /// defaults.
+    pub fn from_env() -> Self {
+        let defaults = Settings::default();
+        Settings {
+            keep_alive: std::env::var("KEEP_ALIVE")
+                .map(|seconds| {
+                    let seconds: usize = seconds
+                        .parse()
+                        .expect("Unable to parse KEEP_ALIVE as usize.");
+                    Some(seconds).filter(|seconds| *seconds > 0)
+                })
+                .unwrap_or(defaults.keep_alive),
+            client_timeout: std::env::var("CLIENT_TIMEOUT")
+                .map(|ms| ms.parse().expect("Unable to parse CLIENT_TIMEOUT as u64."))
+                .unwrap_or(defaults.client_timeout),
+            client_shutdown: std::env::var("CLIENT_SHUTDOWN")
+                .map(|ms| ms.parse().expect("Unable to parse CLIENT_SHUTDOWN as u64."))
+                .unwrap_or(defaults.client_shutdown),
+            max_connections: std::env::var("MAX_CONNECTIONS")
+                .map(|max| {
+                    max.parse()
+                        .expect("Unable to parse MAX_CONNECTIONS as usize.")
+                })
+                .unwrap_or(defaults.max_connections),
+            max_connection_rate: std::env::var("MAX_CONNECTION_RATE")
+                .map(|max| {
+                    max.parse()
+                        .expect("Unable to parse MAX_CONNECTION_RATE as usize.")
+                })
+                .unwrap_or(defaults.max_connection_rate),
+            backlog: std::env::var("LISTEN_BACKLOG")
+                .map(|backlog| {
+                    backlog
+                        .parse()
+                        .expect("Unable to parse LISTEN_BACKLOG as i32.")
+                })
+                .unwrap_or(defaults.backlog),
+        }
+    }
+}
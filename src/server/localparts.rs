@@ -0,0 +1,205 @@
+//! Which localparts users may be registered with.
+//!
+//! Every localpart must fit the Matrix grammar, only a-z, 0-9 and `._=-/`.
+//! Operators can narrow that down with `LOCALPART_PATTERN`, a regex whole
+//! localparts must match, and reserve names with `RESERVED_LOCALPARTS`, e.g.
+//! `admin,bridge_*`, so that only admins and application services can give
+//! them out. Reserved names cover their lookalikes too: reserving `admin`
+//! reserves `adm1n`, and reserving `bridge_*` reserves `bridge.irc`.
+//!
+//! Usernames with capitals are rejected, unless `LOCALPART_CASE_FOLDING` is
+//! `lowercase`, in which case they are registered lowercased.
+use std::fmt;
+
+use actix_web::{http::StatusCode, Error};
+use regex::Regex;
+
+use super::{
+    admin::is_valid_localpart,
+    error::{ErrorCode, MatrixError},
+};
+
+/// Which localparts may be registered.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    /// Matches the whole of every localpart that may be registered
+    pattern: Option<Regex>,
+    /// Each matches the whole of the skeletons of reserved localparts
+    reserved: Vec<Regex>,
+    /// Whether usernames are lowercased, rather than rejected for capitals
+    fold_case: bool,
+}
+
+/// Why a username can't be registered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+    /// It doesn't fit the Matrix grammar
+    Invalid,
+    /// It doesn't match `LOCALPART_PATTERN`
+    NotAllowed,
+    /// It is, or looks like, a reserved name
+    Reserved,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Rejection::Invalid => "User IDs may only contain a-z, 0-9, and ._=-/",
+            Rejection::NotAllowed => "This user ID is not allowed on this server.",
+            Rejection::Reserved => "This user ID is reserved.",
+        })
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+impl From<Rejection> for Error {
+    fn from(rejection: Rejection) -> Self {
+        MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::INVALID_USERNAME,
+            rejection.to_string(),
+        )
+        .into()
+    }
+}
+
+impl Policy {
+    /// Reads the policy from `env` vars. Panics if any can't be parsed.
+    pub fn from_env() -> Self {
+        let pattern = std::env::var("LOCALPART_PATTERN").ok().map(|pattern| {
+            Regex::new(&format!("^(?:{})$", pattern)).expect("Unable to parse LOCALPART_PATTERN.")
+        });
+        let reserved = std::env::var("RESERVED_LOCALPARTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(reserved_name)
+            .collect();
+        let fold_case = match std::env::var("LOCALPART_CASE_FOLDING")
+            .unwrap_or_default()
+            .as_str()
+        {
+            "" | "reject" => false,
+            "lowercase" => true,
+            other => panic!(
+                "Unknown LOCALPART_CASE_FOLDING {}, expected reject or lowercase.",
+                other
+            ),
+        };
+        Policy {
+            pattern,
+            reserved,
+            fold_case,
+        }
+    }
+
+    /// The localpart `username` is registered with, if the policy allows
+    /// it. Reserved names are allowed, as admins and application services
+    /// may give them out.
+    pub fn localpart(&self, username: &str) -> Result<String, Rejection> {
+        let localpart = if self.fold_case {
+            username.to_lowercase()
+        } else {
+            username.to_owned()
+        };
+        if !is_valid_localpart(&localpart) {
+            return Err(Rejection::Invalid);
+        }
+        if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(&localpart) {
+                return Err(Rejection::NotAllowed);
+            }
+        }
+        Ok(localpart)
+    }
+
+    /// The localpart `username` is registered with, if users may register it
+    /// for themselves.
+    pub fn user_localpart(&self, username: &str) -> Result<String, Rejection> {
+        let localpart = self.localpart(username)?;
+        if self.is_reserved(&localpart) {
+            return Err(Rejection::Reserved);
+        }
+        Ok(localpart)
+    }
+
+    /// Whether a localpart is, or looks like, a reserved name.
+    pub fn is_reserved(&self, localpart: &str) -> bool {
+        let skeleton = skeleton(localpart);
+        self.reserved.iter().any(|name| name.is_match(&skeleton))
+    }
+}
+
+/// Matches the skeletons of the localparts a reserved name covers, where `*`
+/// stands for anything.
+fn reserved_name(name: &str) -> Regex {
+    let parts: Vec<String> = name
+        .split('*')
+        .map(|part| regex::escape(&skeleton(part)))
+        .collect();
+    Regex::new(&format!("^{}$", parts.join(".*"))).expect("An escaped name is a valid regex")
+}
+
+/// What a localpart looks like: lowercased, with digits that pass for
+/// letters replaced by them, `i` and `l` alike, and every separator an
+/// underscore. Lookalike localparts have the same skeleton.
+fn skeleton(localpart: &str) -> String {
+    localpart
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | 'i' => 'l',
+            '3' => 'e',
+            '5' => 's',
+            '.' | '-' | '=' | '/' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(pattern: Option<&str>, reserved: &[&str], fold_case: bool) -> Policy {
+        Policy {
+            pattern: pattern.map(|pattern| Regex::new(&format!("^(?:{})$", pattern)).unwrap()),
+            reserved: reserved.iter().map(|name| reserved_name(name)).collect(),
+            fold_case,
+        }
+    }
+
+    #[test]
+    fn test_localpart() {
+        let default = Policy::default();
+        assert_eq!(default.localpart("alice"), Ok("alice".to_owned()));
+        assert_eq!(default.localpart("Alice"), Err(Rejection::Invalid));
+        assert_eq!(default.localpart(""), Err(Rejection::Invalid));
+
+        let folding = policy(Some("[a-z][a-z0-9_]{2,}"), &[], true);
+        assert_eq!(folding.localpart("Alice"), Ok("alice".to_owned()));
+        // The pattern has to match the whole localpart
+        assert_eq!(folding.localpart("al"), Err(Rejection::NotAllowed));
+        assert_eq!(folding.localpart("alice.b"), Err(Rejection::NotAllowed));
+        assert_eq!(folding.localpart("alice:b"), Err(Rejection::Invalid));
+    }
+
+    #[test]
+    fn test_reserved() {
+        let policy = policy(None, &["admin", "bridge_*"], false);
+        assert!(policy.is_reserved("admin"));
+        assert!(policy.is_reserved("adm1n"));
+        assert!(policy.is_reserved("bridge_irc"));
+        assert!(policy.is_reserved("bridge.irc"));
+        assert!(!policy.is_reserved("administrator"));
+        assert!(!policy.is_reserved("bridgework"));
+        assert!(!policy.is_reserved("alice"));
+
+        assert_eq!(policy.user_localpart("adm1n"), Err(Rejection::Reserved));
+        // Admins and application services may give reserved names out
+        assert_eq!(policy.localpart("admin"), Ok("admin".to_owned()));
+    }
+}
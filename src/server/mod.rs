@@ -1,13 +1,73 @@
-use actix_cors::Cors;
-use actix_web::{middleware::Logger, App, HttpServer};
+use actix_web::{
+    dev::{Server, Service},
+    middleware::Logger,
+    App, HttpServer,
+};
+use futures::future::{ok, Either};
 use jsonwebtoken as jwt;
+use tracing_futures::Instrument;
 
+use crate::appservice::{self, AppServices};
+use crate::audit;
+use crate::bus;
+use crate::clock::now_ms;
 use crate::db;
+use crate::federation::{self, acl::DomainPolicy, signing::SigningKey};
+use crate::ipnet::IpNet;
+use crate::media::{
+    preview, retention, s3::S3Config, scan::Scanner, FileStore, MediaStore, S3Store,
+};
+use crate::models::report::ForwardedReport;
+use crate::moderation;
+use crate::push::{self, email};
+use crate::room_summary;
+use crate::scheduler::{self, Scheduler};
+use crate::shutdown;
+use crate::stats;
+use crate::telemetry;
+use crate::webhooks;
 use crate::CONFIG;
 
+mod access;
+mod admin;
+pub mod cli;
+mod cors;
 mod error;
+mod extract;
+mod features;
 mod handlers;
+mod limits;
+mod listener;
+mod localparts;
+pub mod openapi;
+mod proxy;
+mod ratelimit;
 mod routes;
+mod server_auth;
+mod systemd;
+mod tls;
+mod tokens;
+mod uia;
+pub mod worker;
+
+use worker::Role;
+
+/// How often a federation sender worker looks for queued federation whose
+/// wakeup was lost.
+const FEDERATION_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+/// How often users whose presence went quiet are looked for.
+const PRESENCE_TIMEOUT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+/// How long a user's presence is believed without hearing of it again, in
+/// milliseconds. Servers are expected to repeat their users' presence well
+/// within this.
+const PRESENCE_TIMEOUT: i64 = 30 * 60 * 1000;
+/// How often the server's aggregates are reported, if they are.
+const STATS_REPORT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+/// How often finished jobs are cleaned up.
+const JOB_CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// How often received transactions and PDUs past the replay window are
+/// forgotten.
+const RECEIVED_CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
 
 #[derive(Clone)]
 pub struct Config {
@@ -21,8 +81,141 @@ pub struct Config {
     pub database_url: String,
     /// PEM encoded ES256 key for creating auth tokens
     pub auth_key: jwt::EncodingKey,
+    /// Public half of `auth_key`, used to validate auth tokens
+    pub auth_decoding_key: jwt::DecodingKey<'static>,
     /// Duration in seconds that an auth token is valid for
     pub session_expiration: i64,
+    /// The Ed25519 key this server signs federation requests and events with
+    pub signing_key: std::sync::Arc<SigningKey>,
+    /// The servers asked for another server's keys when it can't be reached
+    pub trusted_key_servers: Vec<String>,
+    /// Which servers are federated with
+    pub federation_policy: DomainPolicy,
+    /// Whether reports of remote events are forwarded to their servers
+    pub federation_send_reports: bool,
+    /// How long transactions and PDUs received from other servers are
+    /// remembered, so those received again aren't processed twice
+    pub federation_replay_window: std::time::Duration,
+    /// Where uploaded media is stored
+    pub media_backend: MediaBackend,
+    /// The largest media upload accepted, in bytes
+    pub max_upload_size: u64,
+    /// Whether media can still be downloaded without an access token
+    pub legacy_media_enabled: bool,
+    /// The most pixels an image may have for us to thumbnail it
+    pub max_image_pixels: u64,
+    /// Whether clients may ask the server to preview URLs
+    pub url_preview_enabled: bool,
+    /// Where URL previews, and remote media redirected to, may not be
+    /// fetched from
+    pub url_preview_denylist: preview::Denylist,
+    /// Media quotas and retention periods
+    pub media_retention: retention::Policy,
+    /// Seconds between runs of the media retention job
+    pub media_retention_interval: u64,
+    /// The scanner uploads are checked with before being stored, if any
+    pub media_scanner: Option<Scanner>,
+    /// How digests of missed highlights are emailed, if they are
+    pub email_notifications: Option<email::Settings>,
+    /// How log lines are written
+    pub log_format: telemetry::LogFormat,
+    /// Where traces are exported to, if anywhere
+    pub telemetry: Option<telemetry::Config>,
+    /// How long a storage call takes before it is logged and counted as
+    /// slow, if they are
+    pub slow_query_threshold: Option<std::time::Duration>,
+    /// Seconds in-flight requests, and then transactions being sent, are
+    /// given to finish when shutting down
+    pub shutdown_timeout: u64,
+    /// Whether to listen on the sockets systemd passes for socket
+    /// activation, instead of binding `server_addr`
+    pub socket_activation: bool,
+    /// Whether to tell systemd when the server is ready and stopping, and
+    /// ping its watchdog
+    pub systemd_notify: bool,
+    /// How requests to the client and media APIs are rate limited
+    pub rate_limits: ratelimit::RateLimits,
+    /// Where audit log entries are forwarded to, if anywhere
+    pub audit_forwarder: Option<audit::Forwarder>,
+    /// Where operators are notified of security relevant actions, if
+    /// anywhere
+    pub webhooks: Option<webhooks::Settings>,
+    /// Where the server's aggregates are reported to each day, if anywhere
+    pub stats_report_url: Option<String>,
+    /// Which role this process has, and how it shares work with the others
+    pub workers: worker::Settings,
+    /// The Redis server the replication bus is shared through, if any
+    pub redis_addr: Option<String>,
+    /// The experimental features that are turned on
+    pub features: features::Features,
+    /// The room version new rooms are created with
+    pub default_room_version: String,
+    /// Which localparts users may be registered with
+    pub localparts: localparts::Policy,
+    /// Whether users may change their own passwords
+    pub password_change_enabled: bool,
+    /// Whether users may add and remove their own 3PIDs
+    pub threepid_changes_enabled: bool,
+    /// The identity server advertised to clients, if any
+    pub identity_server: Option<String>,
+    /// The `host:port` other homeservers are told to reach this one at,
+    /// if federation is delegated
+    pub well_known_server: Option<String>,
+    /// Additional, namespaced keys served in `/.well-known/matrix/client`
+    pub well_known_client_extra: serde_json::Map<String, serde_json::Value>,
+    /// The certificate and key to serve HTTPS with, if TLS isn't terminated
+    /// by a reverse proxy
+    pub tls: Option<tls::Settings>,
+    /// The reverse proxies whose forwarding headers are believed
+    pub trusted_proxies: Vec<IpNet>,
+    /// Which networks are kept out of the client and federation APIs
+    pub access: access::Settings,
+    /// The origins web pages using the admin API may be served from
+    pub admin_cors_origins: cors::Origins,
+    /// How connections are accepted and kept
+    pub listener: listener::Settings,
+    /// How long requests may take, and how large their bodies may be
+    pub limits: limits::Limits,
+    /// The registered application services
+    pub appservices: AppServices,
+}
+
+/// Where uploaded media is stored.
+#[derive(Clone)]
+pub enum MediaBackend {
+    /// A directory on the local filesystem
+    File(std::path::PathBuf),
+    /// An S3 compatible object store
+    S3(S3Config),
+}
+
+impl MediaBackend {
+    /// Reads the media backend from `env` vars. Panics if the chosen backend
+    /// is missing any of its settings.
+    fn from_env() -> Self {
+        let backend = std::env::var("MEDIA_STORE").unwrap_or_else(|_| "file".to_owned());
+        match backend.as_str() {
+            "file" => MediaBackend::File(
+                std::env::var("MEDIA_PATH")
+                    .unwrap_or_else(|_| "media_store".to_owned())
+                    .into(),
+            ),
+            "s3" => MediaBackend::S3(S3Config {
+                endpoint: std::env::var("S3_ENDPOINT")
+                    .expect("S3_ENDPOINT env var missing.")
+                    .parse()
+                    .expect("Unable to parse S3_ENDPOINT as a URL."),
+                bucket: std::env::var("S3_BUCKET").expect("S3_BUCKET env var missing."),
+                region: std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_owned()),
+                access_key_id: std::env::var("S3_ACCESS_KEY_ID")
+                    .expect("S3_ACCESS_KEY_ID env var missing."),
+                secret_access_key: std::env::var("S3_SECRET_ACCESS_KEY")
+                    .expect("S3_SECRET_ACCESS_KEY env var missing."),
+                prefix: std::env::var("S3_PREFIX").unwrap_or_default(),
+            }),
+            other => panic!("Unknown MEDIA_STORE '{}', expected 'file' or 's3'.", other),
+        }
+    }
 }
 
 impl Config {
@@ -30,56 +223,686 @@ impl Config {
     /// to load from `env` vars.  Panics if
     /// any are missing.
     pub fn new_from_env() -> Self {
+        let auth_key_data = {
+            use std::io::Read;
+            let var = std::env::var("AUTH_KEY_FILE").expect("AUTH_KEY_FILE env var missing.");
+            let path = std::path::Path::new(&var);
+            let mut key_data = Vec::with_capacity(
+                path.metadata()
+                    .expect("Error fetcing metadata for AUTH_KEY_FILE.")
+                    .len() as usize,
+            );
+            std::fs::File::open(path)
+                .expect("Error opening AUTH_KEY_FILE.")
+                .read_to_end(&mut key_data)
+                .expect("Error reading AUTH_KEY_FILE.");
+            key_data
+        };
         Self {
             server_addr: std::env::var("SERVER_ADDR").expect("SERVER_ADDR env var missing."),
             hostname: std::env::var("HOSTNAME").expect("HOSTNAME env var missing."),
             base_url: std::env::var("BASE_URL").expect("BASE_URL env var missing."),
             database_url: std::env::var("DATABASE_URL").expect("DATABASE_URL env var missing."),
-            auth_key: {
-                use std::io::Read;
-                let var = std::env::var("AUTH_KEY_FILE").expect("AUTH_KEY_FILE env var missing.");
-                let path = std::path::Path::new(&var);
-                let mut key_data = Vec::with_capacity(
-                    path.metadata()
-                        .expect("Error fetcing metadata for AUTH_KEY_FILE.")
-                        .len() as usize,
-                );
-                std::fs::File::open(path)
-                    .expect("Error opening AUTH_KEY_FILE.")
-                    .read_to_end(&mut key_data)
-                    .expect("Error reading AUTH_KEY_FILE.");
-                jwt::EncodingKey::from_ec_pem(&key_data)
-                    .expect("Error decoding AUTH_KEY_FILE contents as a PEM encoded ECDSA key.")
+            auth_key: jwt::EncodingKey::from_ec_pem(&auth_key_data)
+                .expect("Error decoding AUTH_KEY_FILE contents as a PEM encoded ECDSA key."),
+            auth_decoding_key: {
+                use ring::signature::{self, KeyPair};
+                let pkcs8 = pem::parse(&auth_key_data)
+                    .expect("Error decoding AUTH_KEY_FILE contents as PEM.")
+                    .contents;
+                let key_pair = signature::EcdsaKeyPair::from_pkcs8(
+                    &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                    &pkcs8,
+                )
+                .expect("Error decoding AUTH_KEY_FILE contents as a PKCS#8 ECDSA key.");
+                jwt::DecodingKey::from_ec_der(key_pair.public_key().as_ref()).into_static()
             },
             session_expiration: std::env::var("SESSION_EXPIRATION")
                 .expect("SESSION_EXPIRATION env var missing.")
                 .parse()
                 .expect("Unable to parse SESSION_EXPIRATION as i64."),
+            signing_key: {
+                let path =
+                    std::env::var("SIGNING_KEY_FILE").expect("SIGNING_KEY_FILE env var missing.");
+                // A server keeps its key for good, as other servers cache it
+                if !std::path::Path::new(&path).exists() {
+                    use std::io::Write;
+                    use std::os::unix::fs::OpenOptionsExt;
+                    std::fs::OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .mode(0o600)
+                        .open(&path)
+                        .and_then(|mut file| file.write_all(SigningKey::generate().as_bytes()))
+                        .expect("Error writing generated key to SIGNING_KEY_FILE.");
+                }
+                std::fs::read_to_string(path)
+                    .expect("Error reading SIGNING_KEY_FILE.")
+                    .parse::<SigningKey>()
+                    .expect("Error decoding SIGNING_KEY_FILE contents as a signing key.")
+                    .into()
+            },
+            trusted_key_servers: std::env::var("TRUSTED_KEY_SERVERS")
+                .unwrap_or_else(|_| "matrix.org".to_owned())
+                .split(',')
+                .map(|server| server.trim().to_owned())
+                .filter(|server| !server.is_empty())
+                .collect(),
+            federation_policy: DomainPolicy::parse(
+                &std::env::var("FEDERATION_ALLOW").unwrap_or_default(),
+                &std::env::var("FEDERATION_DENY").unwrap_or_default(),
+            ),
+            federation_send_reports: std::env::var("FEDERATION_SEND_REPORTS")
+                .map(|enabled| {
+                    enabled
+                        .parse()
+                        .expect("Unable to parse FEDERATION_SEND_REPORTS as bool.")
+                })
+                .unwrap_or(false),
+            federation_replay_window: std::time::Duration::from_secs(
+                std::env::var("FEDERATION_REPLAY_WINDOW")
+                    .map(|seconds| {
+                        seconds
+                            .parse()
+                            .expect("Unable to parse FEDERATION_REPLAY_WINDOW as u64.")
+                    })
+                    .unwrap_or(24 * 60 * 60),
+            ),
+            media_backend: MediaBackend::from_env(),
+            max_upload_size: std::env::var("MAX_UPLOAD_SIZE")
+                .map(|size| {
+                    size.parse()
+                        .expect("Unable to parse MAX_UPLOAD_SIZE as u64.")
+                })
+                .unwrap_or(50 * 1024 * 1024),
+            legacy_media_enabled: std::env::var("LEGACY_MEDIA_ENABLED")
+                .map(|enabled| {
+                    enabled
+                        .parse()
+                        .expect("Unable to parse LEGACY_MEDIA_ENABLED as bool.")
+                })
+                .unwrap_or(true),
+            max_image_pixels: std::env::var("MAX_IMAGE_PIXELS")
+                .map(|pixels| {
+                    pixels
+                        .parse()
+                        .expect("Unable to parse MAX_IMAGE_PIXELS as u64.")
+                })
+                .unwrap_or(32_000_000),
+            url_preview_enabled: std::env::var("URL_PREVIEW_ENABLED")
+                .map(|enabled| {
+                    enabled
+                        .parse()
+                        .expect("Unable to parse URL_PREVIEW_ENABLED as bool.")
+                })
+                .unwrap_or(false),
+            url_preview_denylist: preview::Denylist {
+                ips: IpNet::parse_list(
+                    &std::env::var("URL_PREVIEW_IP_DENYLIST")
+                        .unwrap_or_else(|_| preview::DEFAULT_IP_DENYLIST.to_owned()),
+                )
+                .expect("Unable to parse URL_PREVIEW_IP_DENYLIST."),
+                hosts: std::env::var("URL_PREVIEW_HOST_DENYLIST")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|host| host.trim().trim_end_matches('.').to_ascii_lowercase())
+                    .filter(|host| !host.is_empty())
+                    .collect(),
+            },
+            media_retention: retention::Policy {
+                quota_bytes: std::env::var("MEDIA_QUOTA_BYTES").ok().map(|quota| {
+                    quota
+                        .parse()
+                        .expect("Unable to parse MEDIA_QUOTA_BYTES as i64.")
+                }),
+                remote_max_age_ms: std::env::var("REMOTE_MEDIA_MAX_AGE").ok().map(|age| {
+                    age.parse::<i64>()
+                        .expect("Unable to parse REMOTE_MEDIA_MAX_AGE as i64.")
+                        * 1000
+                }),
+                remote_cache_bytes: std::env::var("REMOTE_MEDIA_CACHE_BYTES").ok().map(|size| {
+                    size.parse()
+                        .expect("Unable to parse REMOTE_MEDIA_CACHE_BYTES as i64.")
+                }),
+            },
+            media_retention_interval: std::env::var("MEDIA_RETENTION_INTERVAL")
+                .map(|interval| {
+                    interval
+                        .parse()
+                        .expect("Unable to parse MEDIA_RETENTION_INTERVAL as u64.")
+                })
+                .unwrap_or(60 * 60),
+            media_scanner: std::env::var("MEDIA_SCANNER")
+                .ok()
+                .map(|scanner| scanner.parse().expect("Unable to parse MEDIA_SCANNER.")),
+            email_notifications: std::env::var("MAILER").ok().map(|mailer| email::Settings {
+                mailer: mailer.parse().expect("Unable to parse MAILER."),
+                from: std::env::var("MAIL_FROM").unwrap_or_else(|_| {
+                    format!(
+                        "noreply@{}",
+                        std::env::var("HOSTNAME").expect("HOSTNAME env var missing.")
+                    )
+                }),
+                idle_ms: std::env::var("EMAIL_NOTIFICATION_IDLE")
+                    .map(|idle| {
+                        idle.parse::<i64>()
+                            .expect("Unable to parse EMAIL_NOTIFICATION_IDLE as i64.")
+                    })
+                    .unwrap_or(10 * 60)
+                    * 1000,
+                throttle_ms: std::env::var("EMAIL_NOTIFICATION_THROTTLE")
+                    .map(|throttle| {
+                        throttle
+                            .parse::<i64>()
+                            .expect("Unable to parse EMAIL_NOTIFICATION_THROTTLE as i64.")
+                    })
+                    .unwrap_or(60 * 60)
+                    * 1000,
+                templates: std::env::var("EMAIL_TEMPLATE_DIR")
+                    .map(|dir| {
+                        email::Templates::load(std::path::Path::new(&dir))
+                            .expect("Error reading EMAIL_TEMPLATE_DIR.")
+                    })
+                    .unwrap_or_default(),
+            }),
+            log_format: std::env::var("LOG_FORMAT")
+                .map(|format| format.parse().expect("Unable to parse LOG_FORMAT."))
+                .unwrap_or(telemetry::LogFormat::Text),
+            telemetry: std::env::var("OTLP_ENDPOINT")
+                .ok()
+                .map(|otlp_endpoint| telemetry::Config { otlp_endpoint }),
+            slow_query_threshold: std::env::var("SLOW_QUERY_THRESHOLD").ok().map(|ms| {
+                std::time::Duration::from_millis(
+                    ms.parse()
+                        .expect("Unable to parse SLOW_QUERY_THRESHOLD as u64."),
+                )
+            }),
+            shutdown_timeout: std::env::var("SHUTDOWN_TIMEOUT")
+                .map(|timeout| {
+                    timeout
+                        .parse()
+                        .expect("Unable to parse SHUTDOWN_TIMEOUT as u64.")
+                })
+                .unwrap_or(30),
+            socket_activation: std::env::var("SYSTEMD_SOCKET_ACTIVATION")
+                .map(|enabled| {
+                    enabled
+                        .parse()
+                        .expect("Unable to parse SYSTEMD_SOCKET_ACTIVATION as bool.")
+                })
+                .unwrap_or(false),
+            systemd_notify: std::env::var("SYSTEMD_NOTIFY")
+                .map(|enabled| {
+                    enabled
+                        .parse()
+                        .expect("Unable to parse SYSTEMD_NOTIFY as bool.")
+                })
+                .unwrap_or(false),
+            rate_limits: ratelimit::RateLimits::from_env(),
+            audit_forwarder: std::env::var("AUDIT_LOG_SYSLOG").ok().map(|forwarder| {
+                forwarder
+                    .parse()
+                    .expect("Unable to parse AUDIT_LOG_SYSLOG.")
+            }),
+            webhooks: webhooks::Settings::from_env(),
+            stats_report_url: std::env::var("REPORT_STATS_URL").ok(),
+            workers: worker::Settings::from_env(),
+            redis_addr: std::env::var("REDIS_ADDR").ok(),
+            features: features::Features::from_env(),
+            default_room_version: {
+                let version =
+                    std::env::var("DEFAULT_ROOM_VERSION").unwrap_or_else(|_| "6".to_owned());
+                if !federation::ROOM_VERSIONS.contains(&version.as_str()) {
+                    panic!("DEFAULT_ROOM_VERSION {} is not supported.", version);
+                }
+                version
+            },
+            localparts: localparts::Policy::from_env(),
+            password_change_enabled: std::env::var("PASSWORD_CHANGE_ENABLED")
+                .map(|enabled| {
+                    enabled
+                        .parse()
+                        .expect("Unable to parse PASSWORD_CHANGE_ENABLED as bool.")
+                })
+                .unwrap_or(true),
+            threepid_changes_enabled: std::env::var("THREEPID_CHANGES_ENABLED")
+                .map(|enabled| {
+                    enabled
+                        .parse()
+                        .expect("Unable to parse THREEPID_CHANGES_ENABLED as bool.")
+                })
+                .unwrap_or(false),
+            identity_server: std::env::var("IDENTITY_SERVER_URL")
+                .ok()
+                .map(|url| url.trim_end_matches('/').to_owned()),
+            well_known_server: std::env::var("WELL_KNOWN_SERVER").ok(),
+            well_known_client_extra: std::env::var("WELL_KNOWN_CLIENT_EXTRA")
+                .map(|extra| {
+                    serde_json::from_str(&extra)
+                        .expect("Unable to parse WELL_KNOWN_CLIENT_EXTRA as a JSON object.")
+                })
+                .unwrap_or_default(),
+            tls: tls::Settings::from_env(),
+            trusted_proxies: IpNet::parse_list(
+                &std::env::var("TRUSTED_PROXIES").unwrap_or_default(),
+            )
+            .expect("Unable to parse TRUSTED_PROXIES."),
+            access: access::Settings::from_env(),
+            admin_cors_origins: std::env::var("ADMIN_CORS_ORIGINS")
+                .map(|origins| {
+                    origins
+                        .parse()
+                        .expect("Unable to parse ADMIN_CORS_ORIGINS.")
+                })
+                .unwrap_or(cors::Origins::Any),
+            listener: listener::Settings::from_env(),
+            limits: limits::Limits::from_env(),
+            appservices: {
+                let paths: Vec<String> = std::env::var("APPSERVICE_CONFIG_FILES")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|path| path.trim().to_owned())
+                    .filter(|path| !path.is_empty())
+                    .collect();
+                let hostname = std::env::var("HOSTNAME").expect("HOSTNAME env var missing.");
+                AppServices::load(&paths, &hostname)
+                    .expect("Unable to load APPSERVICE_CONFIG_FILES.")
+            },
         }
     }
 }
 
 /// Starts the server. Takes a `ServerConfig`.
 pub async fn start() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_web=info");
-    env_logger::init();
-
-    let addr = CONFIG.server_addr.clone();
+    let _telemetry = telemetry::init(
+        CONFIG.log_format,
+        CONFIG.telemetry.as_ref(),
+        CONFIG.slow_query_threshold,
+    );
 
     // TODO: Dynamically set db store
     let pg_store = db::PostgresStore::new(&CONFIG.database_url)
         .await
         .expect("Could not establish database connection.");
-    let cfg = routes::config::<db::PostgresStore>;
 
-    HttpServer::new(move || {
-        App::new()
-            .data(pg_store.clone())
-            .wrap(Cors::new().send_wildcard().finish())
+    match &CONFIG.media_backend {
+        MediaBackend::File(path) => serve(pg_store, FileStore::new(path)).await,
+        MediaBackend::S3(config) => serve(pg_store, S3Store::new(config.clone())).await,
+    }
+}
+
+/// Runs the server on top of the chosen storage until it is shut down by
+/// SIGTERM or SIGINT. Only the APIs and background work this process's role
+/// owns are run.
+async fn serve<M: MediaStore>(pg_store: db::PostgresStore, media_store: M) -> std::io::Result<()> {
+    let addr = CONFIG.server_addr.clone();
+    let workers = &CONFIG.workers;
+    let role = workers.role;
+    tracing::info!(?role, "Starting");
+    if role != Role::Main && CONFIG.redis_addr.is_none() {
+        tracing::warn!("REDIS_ADDR is not set, so this worker won't hear of changes");
+    }
+    lazy_static::initialize(&bus::BUS);
+    actix_rt::spawn(federation::resolve::follow_invalidations());
+    actix_rt::spawn(room_summary::follow_invalidations());
+    tokens::load(&pg_store)
+        .await
+        .expect("Unable to load revoked access tokens.");
+    actix_rt::spawn(tokens::follow_invalidations(pg_store.clone()));
+    access::load(&pg_store)
+        .await
+        .expect("Unable to load IP blocks.");
+    actix_rt::spawn(access::follow_invalidations(pg_store.clone()));
+
+    let mut jobs = Scheduler::new(pg_store.clone());
+    if workers.enforces_media_retention() {
+        let (storage, media_store) = (pg_store.clone(), media_store.clone());
+        jobs.every(
+            scheduler::MEDIA_RETENTION,
+            std::time::Duration::from_secs(CONFIG.media_retention_interval),
+            move |_| {
+                let (storage, media_store) = (storage.clone(), media_store.clone());
+                async move { purge_media(&storage, &media_store, false).await.map(|_| ()) }
+            },
+        );
+    }
+    if role == Role::Main {
+        let storage = pg_store.clone();
+        jobs.every(
+            scheduler::PRESENCE_TIMEOUTS,
+            PRESENCE_TIMEOUT_INTERVAL,
+            move |_| time_out_presence(storage.clone()),
+        );
+        let storage = pg_store.clone();
+        jobs.register(scheduler::REDACT_USER_EVENTS, move |payload| {
+            let storage = storage.clone();
+            async move {
+                let user_id = payload["user_id"]
+                    .as_str()
+                    .ok_or("The job has no user_id")?;
+                admin::redact_user_events(&storage, user_id).await
+            }
+        });
+        jobs.register(scheduler::DELIVER_WEBHOOK, |payload| async move {
+            let url = payload["url"].as_str().ok_or("The job has no url")?;
+            webhooks::deliver(url, &payload["body"]).await
+        });
+        jobs.register(scheduler::FORWARD_REPORT, |payload| async move {
+            let field = |name: &str| {
+                payload[name]
+                    .as_str()
+                    .ok_or_else(|| format!("The job has no {}", name))
+            };
+            let report = ForwardedReport {
+                reason: payload["reason"].as_str().map(str::to_owned),
+            };
+            federation::report::forward(
+                field("server_name")?,
+                field("room_id")?,
+                field("event_id")?,
+                &report,
+            )
+            .await
+        });
+        if let Some(url) = &CONFIG.stats_report_url {
+            let storage = pg_store.clone();
+            jobs.every(scheduler::REPORT_STATS, STATS_REPORT_INTERVAL, move |_| {
+                let storage = storage.clone();
+                async move { stats::report(&storage, url).await }
+            });
+        }
+        let storage = pg_store.clone();
+        jobs.every(scheduler::JOB_CLEANUP, JOB_CLEANUP_INTERVAL, move |_| {
+            let storage = storage.clone();
+            async move { scheduler::clean_up(&storage).await }
+        });
+        let storage = pg_store.clone();
+        jobs.every(
+            scheduler::RECEIVED_CLEANUP,
+            RECEIVED_CLEANUP_INTERVAL,
+            move |_| forget_received(storage.clone()),
+        );
+    }
+    jobs.start();
+    let notifier = if workers.sends_federation() {
+        let notifier = federation::sender::start(pg_store.clone());
+        if role == Role::FederationSender {
+            actix_rt::spawn(federation::sender::poll(
+                pg_store.clone(),
+                notifier.clone(),
+                FEDERATION_POLL_INTERVAL,
+            ));
+        }
+        Some(notifier)
+    } else {
+        match (role, &workers.federation_sender_url) {
+            (Role::Main, Some(url)) => Some(federation::sender::forward(
+                url.clone(),
+                workers.replication_secret.clone().unwrap_or_default(),
+            )),
+            _ => None,
+        }
+    };
+    let (push_notifier, job_notifier, appservice_notifier) = if role == Role::Main {
+        (
+            Some(push::pusher::start(pg_store.clone())),
+            Some(moderation::start(pg_store.clone())),
+            Some(appservice::sender::start(pg_store.clone())),
+        )
+    } else {
+        (None, None, None)
+    };
+
+    let rate_limit = ratelimit::RateLimit::new(&CONFIG.rate_limits, pg_store.clone());
+    let send_limit = ratelimit::SendLimit::new(&CONFIG.rate_limits);
+    let app_store = pg_store.clone();
+    let app_notifier = notifier.clone();
+    let app_push_notifier = push_notifier.clone();
+    let mut server = HttpServer::new(move || {
+        let mut app = App::new()
+            .data(app_store.clone())
+            .data(media_store.clone())
+            .data(send_limit.clone())
+            .app_data(CONFIG.limits.client.json_config());
+        if let Some(notifier) = &app_notifier {
+            app = app.data(notifier.clone());
+        }
+        if let Some(push_notifier) = &app_push_notifier {
+            app = app.data(push_notifier.clone());
+        }
+        if let Some(job_notifier) = &job_notifier {
+            app = app.data(job_notifier.clone());
+        }
+        if let Some(appservice_notifier) = &appservice_notifier {
+            app = app.data(appservice_notifier.clone());
+        }
+        app.wrap(rate_limit.clone())
+            // Refuses blocked networks before they count against rate limits
+            .wrap_fn(|req, srv| {
+                let api = access::api_of(req.path());
+                match proxy::client_ip(req.request()) {
+                    Some(ip) if access::is_blocked(api, ip) => {
+                        tracing::debug!(%ip, "Refused a blocked network");
+                        // A response rather than an error, so it gets CORS headers
+                        Either::Left(ok(req.error_response(access::blocked())))
+                    }
+                    _ => Either::Right(srv.call(req)),
+                }
+            })
+            // Refuses bodies declared too large, and cancels requests that
+            // run out of time
+            .wrap_fn(|req, srv| {
+                let limit = CONFIG.limits.for_path(req.path());
+                let timeout = limit.timeout;
+                let res = limit.check_body(req.headers()).map(|()| srv.call(req));
+                async move {
+                    let res = res?;
+                    match timeout {
+                        Some(timeout) => actix_rt::time::timeout(timeout, res)
+                            .await
+                            .map_err(|_| limits::timed_out())?,
+                        None => res.await,
+                    }
+                }
+            })
+            .wrap(cors::Cors::new(CONFIG.admin_cors_origins.clone()))
             .wrap(Logger::default())
-            .configure(cfg)
+            .wrap_fn(|req, srv| {
+                // `user_id` is filled in once the requester is authenticated
+                let span = tracing::info_span!(
+                    "request",
+                    request_id = %request_id(),
+                    method = %req.method(),
+                    path = %req.path(),
+                    client_ip = tracing::field::Empty,
+                    user_id = tracing::field::Empty,
+                    room_id = tracing::field::Empty,
+                    status = tracing::field::Empty,
+                );
+                if let Some(client_ip) = proxy::client_ip(req.request()) {
+                    span.record("client_ip", &tracing::field::display(client_ip));
+                }
+                let res = srv.call(req);
+                async move {
+                    let res = res.await;
+                    if let Ok(res) = &res {
+                        let span = tracing::Span::current();
+                        if let Some(room_id) = res.request().match_info().get("room_id") {
+                            span.record("room_id", &room_id);
+                        }
+                        span.record("status", &res.status().as_u16());
+                    }
+                    res
+                }
+                .instrument(span)
+            })
+            // Outermost, so everything sees the client behind the proxies
+            .wrap_fn(|mut req, srv| {
+                proxy::forward(&mut req, &CONFIG.trusted_proxies);
+                srv.call(req)
+            })
+            .configure(|cfg| routes::config::<db::PostgresStore, M>(role, cfg))
     })
-    .bind(addr)?
-    .run()
-    .await
+    .shutdown_timeout(CONFIG.shutdown_timeout)
+    .keep_alive(CONFIG.listener.keep_alive)
+    .client_timeout(CONFIG.listener.client_timeout)
+    .client_shutdown(CONFIG.listener.client_shutdown)
+    .maxconn(CONFIG.listener.max_connections)
+    .maxconnrate(CONFIG.listener.max_connection_rate)
+    .backlog(CONFIG.listener.backlog)
+    .disable_signals();
+    let tls_config = match &CONFIG.tls {
+        Some(settings) => {
+            let certificates = tls::Certificates::load(settings)?;
+            actix_rt::spawn(tls::watch(certificates.clone(), tls::WATCH_INTERVAL));
+            Some(certificates.server_config())
+        }
+        None => None,
+    };
+    let listeners = if CONFIG.socket_activation {
+        systemd::listeners()?
+    } else {
+        Vec::new()
+    };
+    if listeners.is_empty() {
+        if CONFIG.socket_activation {
+            tracing::warn!(%addr, "No sockets passed by systemd, binding the server address");
+        }
+        server = match &tls_config {
+            Some(config) => server.bind_rustls(addr, config.clone())?,
+            None => server.bind(addr)?,
+        };
+    }
+    for listener in listeners {
+        server = match &tls_config {
+            Some(config) => server.listen_rustls(listener, config.clone())?,
+            None => server.listen(listener)?,
+        };
+    }
+    let server = server.run();
+    actix_rt::spawn(stop_on_signal(server.clone()));
+    if CONFIG.systemd_notify {
+        if let Err(e) = systemd::notify("READY=1") {
+            tracing::warn!(error = %e, "Unable to notify systemd");
+        }
+        if let Some(interval) = systemd::watchdog_interval() {
+            actix_rt::spawn(systemd::watchdog(interval));
+        }
+    }
+    server.await?;
+
+    // No requests are left to queue anything, so once what is being sent is
+    // done the queues are safe to leave until the next start
+    let timeout = std::time::Duration::from_secs(CONFIG.shutdown_timeout);
+    let (federation_flushed, push_flushed) = futures::join!(
+        async {
+            match &notifier {
+                Some(notifier) => notifier.flush(timeout).await,
+                None => true,
+            }
+        },
+        async {
+            match &push_notifier {
+                Some(push_notifier) => push_notifier.flush(timeout).await,
+                None => true,
+            }
+        },
+    );
+    if !federation_flushed {
+        tracing::warn!("Timed out waiting for federation transactions to be sent");
+    }
+    if !push_flushed {
+        tracing::warn!("Timed out waiting for notifications to be pushed");
+    }
+    pg_store.close().await;
+    tracing::info!("Shut down");
+    Ok(())
+}
+
+/// Stops the server on SIGTERM or SIGINT, letting the requests in flight
+/// finish first.
+async fn stop_on_signal(server: Server) {
+    match shutdown::signal_received().await {
+        Ok(signal) => {
+            tracing::info!(%signal, "Shutting down, finishing in-flight requests");
+            if CONFIG.systemd_notify {
+                if let Err(e) = systemd::notify("STOPPING=1") {
+                    tracing::warn!(error = %e, "Unable to notify systemd");
+                }
+            }
+            server.stop(true).await;
+        }
+        Err(e) => tracing::error!(error = %e, "Unable to listen for shutdown signals"),
+    }
+}
+
+/// A random ID to tell a request's log lines apart from other requests'.
+fn request_id() -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .collect()
+}
+
+/// Marks the users whose presence hasn't been heard of in
+/// `PRESENCE_TIMEOUT` as offline, as their server may have stopped telling
+/// us about them.
+async fn time_out_presence<T: db::Store>(storage: T) -> Result<(), Box<dyn std::error::Error>> {
+    let now = now_ms();
+    let timed_out = storage.time_out_presence(now - PRESENCE_TIMEOUT).await?;
+    if timed_out > 0 {
+        tracing::debug!(timed_out, "Timed out presence");
+    }
+    Ok(())
+}
+
+/// Forgets the transactions and PDUs received from other servers longer ago
+/// than the replay window.
+async fn forget_received<T: db::Store>(storage: T) -> Result<(), Box<dyn std::error::Error>> {
+    let now = now_ms();
+    let before = now - CONFIG.federation_replay_window.as_millis() as i64;
+    let forgotten = storage.delete_received_before(before).await?;
+    if forgotten > 0 {
+        tracing::debug!(forgotten, "Forgot received transactions and PDUs");
+    }
+    Ok(())
+}
+
+/// Purges the media the retention policies say should go, returning what
+/// was purged. With `dry_run` nothing is removed.
+async fn purge_media<T: db::Store, M: MediaStore>(
+    storage: &T,
+    media_store: &M,
+    dry_run: bool,
+) -> Result<Vec<retention::Purge>, Box<dyn std::error::Error>> {
+    let now = now_ms();
+    let purges = retention::plan(storage, &CONFIG.media_retention, &CONFIG.hostname, now).await?;
+    if !dry_run {
+        retention::apply(storage, media_store, &CONFIG.hostname, &purges).await?;
+        for purge in &purges {
+            tracing::info!(%purge, "Purged media");
+        }
+    }
+    Ok(purges)
+}
+
+/// Runs the media retention policies once from the command line, printing
+/// what was purged. With `dry_run` it only prints what would be purged.
+pub async fn purge_media_command(dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let pg_store = db::PostgresStore::new(&CONFIG.database_url).await?;
+    let purges = match &CONFIG.media_backend {
+        MediaBackend::File(path) => purge_media(&pg_store, &FileStore::new(path), dry_run).await?,
+        MediaBackend::S3(config) => {
+            purge_media(&pg_store, &S3Store::new(config.clone()), dry_run).await?
+        }
+    };
+
+    let verb = if dry_run { "Would purge" } else { "Purged" };
+    for purge in &purges {
+        println!("{} {}", verb, purge);
+    }
+    let bytes: i64 = purges.iter().map(|p| p.media_length).sum();
+    println!("{} {} media ({} bytes).", verb, purges.len(), bytes);
+    Ok(())
 }
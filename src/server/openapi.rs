@@ -0,0 +1,1138 @@
+//! An OpenAPI document of the endpoints this server implements, printed by
+//! `maelstrom openapi`.
+//!
+//! The implementation is partial, so the document lists exactly the routes
+//! that are served, with how each is authenticated, rather than all of the
+//! Matrix specification. Each endpoint names the models its handler reads
+//! and returns, and their schemas are derived from those types, so they
+//! follow the code. Bodies that handlers build by hand are left to the
+//! specification. A test checks `endpoints` against `routes`, so a route
+//! can't be added or removed without the document following.
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    schema::Schema,
+    JsonSchema,
+};
+use serde_json::{json, Map, Value};
+
+use crate::models::{
+    access, account, admin, auth, dehydrated_device, directory, federation, keys, media, presence,
+    profile, push, registration, report, room, room_keys, sync, to_device,
+};
+
+/// How a request to an endpoint is authenticated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Auth {
+    /// Anyone may make the request
+    None,
+    /// A user's access token, as a bearer token or an `access_token` query
+    /// parameter. Admin endpoints also need the user to be a server admin.
+    AccessToken,
+    /// Another server's `X-Matrix` signature
+    Server,
+    /// The bearer token shared by the server's processes
+    Replication,
+}
+
+use Auth::{AccessToken, Replication, Server};
+
+/// Adds the schema of a body to the document's components, returning a
+/// reference to it.
+type BodySchema = fn(&mut SchemaGenerator) -> Schema;
+
+/// An endpoint the server implements.
+#[derive(Clone, Copy)]
+struct Endpoint {
+    method: &'static str,
+    path: &'static str,
+    auth: Auth,
+    summary: &'static str,
+    /// The JSON body the handler reads, if any, and whether it must be sent
+    request: Option<(BodySchema, bool)>,
+    /// The JSON body the handler returns on success, if it is a model
+    response: Option<BodySchema>,
+}
+
+fn endpoint(
+    method: &'static str,
+    path: &'static str,
+    auth: Auth,
+    summary: &'static str,
+) -> Endpoint {
+    Endpoint {
+        method,
+        path,
+        auth,
+        summary,
+        request: None,
+        response: None,
+    }
+}
+
+impl Endpoint {
+    /// Documents the handler as reading a `T` from the request body.
+    fn request<T: JsonSchema>(mut self) -> Self {
+        self.request = Some((body::<T>, true));
+        self
+    }
+
+    /// Documents the handler as reading a `T` from the request body, if
+    /// there is one.
+    fn optional_request<T: JsonSchema>(mut self) -> Self {
+        self.request = Some((body::<T>, false));
+        self
+    }
+
+    /// Documents the handler as returning a `T` on success.
+    fn response<T: JsonSchema>(mut self) -> Self {
+        self.response = Some(body::<T>);
+        self
+    }
+}
+
+fn body<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    gen.subschema_for::<T>()
+}
+
+/// The API areas endpoints are grouped into, by path prefix. The first
+/// match wins.
+const TAGS: &[(&str, &str, &str)] = &[
+    ("/health/", "Health", "Liveness and readiness probes"),
+    ("/metrics", "Metrics", "Counters for monitoring the server"),
+    ("/.well-known/", "Discovery", "Server and client discovery"),
+    (
+        "/_matrix/client/v1/media/",
+        "Media",
+        "The content repository",
+    ),
+    (
+        "/_matrix/client/unstable/",
+        "Unstable",
+        "Client-server extensions that are not yet in the specification",
+    ),
+    ("/_matrix/client/", "Client", "The client-server API"),
+    ("/_matrix/media/", "Media", "The content repository"),
+    (
+        "/_matrix/federation/",
+        "Federation",
+        "The server-server API",
+    ),
+    ("/_matrix/key/", "Federation", "The server-server API"),
+    ("/_maelstrom/admin/", "Admin", "Server administration"),
+    (
+        "/_maelstrom/replication/",
+        "Replication",
+        "Requests between the server's own processes",
+    ),
+];
+
+/// Every endpoint the server implements, whichever process serves it.
+fn endpoints() -> Vec<Endpoint> {
+    vec![
+        endpoint(
+            "get",
+            "/health/live",
+            Auth::None,
+            "Whether the process is alive",
+        ),
+        endpoint(
+            "get",
+            "/health/ready",
+            Auth::None,
+            "Whether the server is ready to serve requests",
+        ),
+        endpoint(
+            "get",
+            "/metrics",
+            Auth::None,
+            "The process's metrics, in the Prometheus text format",
+        ),
+        endpoint(
+            "get",
+            "/.well-known/matrix/client",
+            Auth::None,
+            "Client discovery information",
+        ),
+        endpoint(
+            "get",
+            "/.well-known/matrix/server",
+            Auth::None,
+            "Where federation requests are sent",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/versions",
+            Auth::None,
+            "The specification versions supported",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/v3/capabilities",
+            AccessToken,
+            "What the server lets clients do",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/capabilities",
+            AccessToken,
+            "What the server lets clients do",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/login",
+            Auth::None,
+            "The supported login types",
+        ),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/login",
+            Auth::None,
+            "Logs in with a password",
+        )
+        .request::<auth::LoginRequest>()
+        .response::<auth::LoginResponse>(),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/register",
+            Auth::None,
+            "Registers an account",
+        )
+        .request::<registration::Request>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/register/available",
+            Auth::None,
+            "Whether a username is available",
+        ),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/account/password",
+            AccessToken,
+            "Changes the user's password",
+        )
+        .request::<account::PasswordRequest>(),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/account/deactivate",
+            AccessToken,
+            "Deactivates the user's account",
+        )
+        .request::<account::DeactivateRequest>()
+        .response::<account::DeactivateResponse>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/user/{user_id}/account_data/{type}",
+            AccessToken,
+            "Gets global account data",
+        ),
+        endpoint(
+            "put",
+            "/_matrix/client/r0/user/{user_id}/account_data/{type}",
+            AccessToken,
+            "Sets global account data",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/user/{user_id}/rooms/{room_id}/account_data/{type}",
+            AccessToken,
+            "Gets a room's account data",
+        ),
+        endpoint(
+            "put",
+            "/_matrix/client/r0/user/{user_id}/rooms/{room_id}/account_data/{type}",
+            AccessToken,
+            "Sets a room's account data",
+        ),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/keys/upload",
+            AccessToken,
+            "Uploads device and one-time keys",
+        )
+        .request::<keys::UploadRequest>()
+        .response::<keys::UploadResponse>(),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/keys/query",
+            AccessToken,
+            "Gets users' device keys",
+        )
+        .request::<keys::QueryRequest>()
+        .response::<keys::QueryResponse>(),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/keys/claim",
+            AccessToken,
+            "Claims one-time keys",
+        )
+        .request::<keys::ClaimRequest>()
+        .response::<keys::ClaimResponse>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/keys/changes",
+            AccessToken,
+            "Lists whether the requester's own keys changed",
+        ),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/keys/device_signing/upload",
+            AccessToken,
+            "Uploads cross-signing keys",
+        )
+        .request::<keys::DeviceSigningUploadRequest>(),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/keys/signatures/upload",
+            AccessToken,
+            "Uploads key signatures",
+        )
+        .request::<keys::SignaturesUploadRequest>()
+        .response::<keys::SignaturesUploadResponse>(),
+        endpoint(
+            "put",
+            "/_matrix/client/r0/sendToDevice/{type}/{txn_id}",
+            AccessToken,
+            "Sends to-device messages",
+        )
+        .request::<to_device::SendRequest>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/room_keys/version",
+            AccessToken,
+            "Gets the latest key backup version",
+        )
+        .response::<room_keys::VersionInfo>(),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/room_keys/version",
+            AccessToken,
+            "Creates a key backup version",
+        )
+        .request::<room_keys::CreateVersionRequest>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/room_keys/version/{version}",
+            AccessToken,
+            "Gets a key backup version",
+        )
+        .response::<room_keys::VersionInfo>(),
+        endpoint(
+            "put",
+            "/_matrix/client/r0/room_keys/version/{version}",
+            AccessToken,
+            "Updates a key backup version",
+        )
+        .request::<room_keys::UpdateVersionRequest>(),
+        endpoint(
+            "delete",
+            "/_matrix/client/r0/room_keys/version/{version}",
+            AccessToken,
+            "Deletes a key backup version",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/room_keys/keys",
+            AccessToken,
+            "Gets backed up keys",
+        )
+        .response::<room_keys::KeysBackup>(),
+        endpoint(
+            "put",
+            "/_matrix/client/r0/room_keys/keys",
+            AccessToken,
+            "Backs up keys",
+        )
+        .request::<room_keys::KeysBackup>()
+        .response::<room_keys::UpdateResponse>(),
+        endpoint(
+            "delete",
+            "/_matrix/client/r0/room_keys/keys",
+            AccessToken,
+            "Deletes backed up keys",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/room_keys/keys/{room_id}",
+            AccessToken,
+            "Gets a room's backed up keys",
+        )
+        .response::<room_keys::RoomKeyBackup>(),
+        endpoint(
+            "put",
+            "/_matrix/client/r0/room_keys/keys/{room_id}",
+            AccessToken,
+            "Backs up a room's keys",
+        )
+        .request::<room_keys::RoomKeyBackup>()
+        .response::<room_keys::UpdateResponse>(),
+        endpoint(
+            "delete",
+            "/_matrix/client/r0/room_keys/keys/{room_id}",
+            AccessToken,
+            "Deletes a room's backed up keys",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/room_keys/keys/{room_id}/{session_id}",
+            AccessToken,
+            "Gets a backed up session key",
+        )
+        .response::<room_keys::KeyBackupData>(),
+        endpoint(
+            "put",
+            "/_matrix/client/r0/room_keys/keys/{room_id}/{session_id}",
+            AccessToken,
+            "Backs up a session key",
+        )
+        .request::<room_keys::KeyBackupData>()
+        .response::<room_keys::UpdateResponse>(),
+        endpoint(
+            "delete",
+            "/_matrix/client/r0/room_keys/keys/{room_id}/{session_id}",
+            AccessToken,
+            "Deletes a backed up session key",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/pushrules/",
+            AccessToken,
+            "Gets all push rules",
+        )
+        .response::<push::PushRulesResponse>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/pushrules/{scope}/{kind}/{rule_id}",
+            AccessToken,
+            "Gets a push rule",
+        )
+        .response::<push::PushRule>(),
+        endpoint(
+            "put",
+            "/_matrix/client/r0/pushrules/{scope}/{kind}/{rule_id}",
+            AccessToken,
+            "Adds or replaces a push rule",
+        )
+        .request::<push::PutPushRuleRequest>(),
+        endpoint(
+            "delete",
+            "/_matrix/client/r0/pushrules/{scope}/{kind}/{rule_id}",
+            AccessToken,
+            "Deletes a push rule",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/pushrules/{scope}/{kind}/{rule_id}/enabled",
+            AccessToken,
+            "Whether a push rule is enabled",
+        )
+        .response::<push::PushRuleEnabled>(),
+        endpoint(
+            "put",
+            "/_matrix/client/r0/pushrules/{scope}/{kind}/{rule_id}/enabled",
+            AccessToken,
+            "Enables or disables a push rule",
+        )
+        .request::<push::PushRuleEnabled>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/pushrules/{scope}/{kind}/{rule_id}/actions",
+            AccessToken,
+            "Gets a push rule's actions",
+        )
+        .response::<push::PushRuleActions>(),
+        endpoint(
+            "put",
+            "/_matrix/client/r0/pushrules/{scope}/{kind}/{rule_id}/actions",
+            AccessToken,
+            "Sets a push rule's actions",
+        )
+        .request::<push::PushRuleActions>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/pushers",
+            AccessToken,
+            "Lists the user's pushers",
+        )
+        .response::<push::PushersResponse>(),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/pushers/set",
+            AccessToken,
+            "Adds, replaces or removes a pusher",
+        )
+        .request::<push::SetPusherRequest>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/profile/{user_id}",
+            Auth::None,
+            "Gets a user's display name and avatar",
+        )
+        .response::<profile::Profile>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/profile/{user_id}/displayname",
+            Auth::None,
+            "Gets a user's display name",
+        )
+        .response::<profile::Profile>(),
+        endpoint(
+            "put",
+            "/_matrix/client/r0/profile/{user_id}/displayname",
+            AccessToken,
+            "Sets the user's display name",
+        )
+        .request::<profile::DisplaynameRequest>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/profile/{user_id}/avatar_url",
+            Auth::None,
+            "Gets a user's avatar",
+        )
+        .response::<profile::Profile>(),
+        endpoint(
+            "put",
+            "/_matrix/client/r0/profile/{user_id}/avatar_url",
+            AccessToken,
+            "Sets the user's avatar",
+        )
+        .request::<profile::AvatarUrlRequest>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/presence/{user_id}/status",
+            AccessToken,
+            "Gets a user's presence",
+        )
+        .response::<presence::PresenceResponse>(),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/rooms/{room_id}/invite",
+            AccessToken,
+            "Invites a user to a room",
+        )
+        .request::<room::InviteRequest>(),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/rooms/{room_id}/report/{event_id}",
+            AccessToken,
+            "Reports an event to the server's admins",
+        )
+        .request::<report::ReportRequest>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/publicRooms",
+            None,
+            "Lists the public rooms of a room directory",
+        )
+        .response::<directory::PublicRoomsResponse>(),
+        endpoint(
+            "post",
+            "/_matrix/client/r0/publicRooms",
+            AccessToken,
+            "Searches the public rooms of a room directory",
+        )
+        .request::<directory::PublicRoomsRequest>()
+        .response::<directory::PublicRoomsResponse>(),
+        endpoint(
+            "get",
+            "/_matrix/client/r0/sync",
+            AccessToken,
+            "Syncs the client's state",
+        )
+        .response::<sync::SyncResponse>(),
+        endpoint(
+            "put",
+            "/_matrix/federation/v1/send/{txn_id}",
+            Server,
+            "Receives a transaction of PDUs and EDUs",
+        )
+        .response::<federation::TransactionResponse>(),
+        endpoint(
+            "get",
+            "/_matrix/federation/v1/backfill/{room_id}",
+            Server,
+            "Gets a room's earlier events",
+        ),
+        endpoint(
+            "post",
+            "/_matrix/federation/v1/get_missing_events/{room_id}",
+            Server,
+            "Gets events missing from a room's graph",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/federation/v1/query/profile",
+            Server,
+            "Gets a local user's profile",
+        ),
+        endpoint(
+            "put",
+            "/_matrix/federation/v1/3pid/onbind",
+            None,
+            "Tells the server a third party identifier was bound",
+        )
+        .request::<room::OnBindRequest>(),
+        endpoint(
+            "get",
+            "/_matrix/federation/v1/publicRooms",
+            Server,
+            "Lists the public rooms of the room directory",
+        )
+        .response::<directory::PublicRoomsResponse>(),
+        endpoint(
+            "post",
+            "/_matrix/federation/v1/publicRooms",
+            Server,
+            "Searches the public rooms of the room directory",
+        )
+        .response::<directory::PublicRoomsResponse>(),
+        endpoint(
+            "post",
+            "/_matrix/federation/unstable/org.matrix.msc3843/rooms/{room_id}/report/{event_id}",
+            Server,
+            "Receives a report of one of the server's events",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/federation/v1/make_join/{room_id}/{user_id}",
+            Server,
+            "Prepares a join event",
+        ),
+        endpoint(
+            "put",
+            "/_matrix/federation/v1/send_join/{room_id}/{event_id}",
+            Server,
+            "Sends a join event",
+        ),
+        endpoint(
+            "put",
+            "/_matrix/federation/v2/send_join/{room_id}/{event_id}",
+            Server,
+            "Sends a join event",
+        ),
+        endpoint(
+            "put",
+            "/_matrix/federation/v2/invite/{room_id}/{event_id}",
+            Server,
+            "Invites a local user to a room",
+        )
+        .response::<federation::InviteResponse>(),
+        endpoint(
+            "get",
+            "/_matrix/key/v2/server",
+            Auth::None,
+            "Gets the server's signing keys",
+        )
+        .response::<federation::ServerKeys>(),
+        endpoint(
+            "get",
+            "/_matrix/key/v2/server/{key_id}",
+            Auth::None,
+            "Gets the server's signing keys",
+        )
+        .response::<federation::ServerKeys>(),
+        endpoint(
+            "get",
+            "/_maelstrom/admin/v1/users",
+            AccessToken,
+            "Lists local users",
+        )
+        .response::<admin::ListUsersResponse>(),
+        endpoint(
+            "post",
+            "/_maelstrom/admin/v1/users",
+            AccessToken,
+            "Creates a local user",
+        )
+        .request::<admin::CreateUserRequest>()
+        .response::<admin::UserResponse>(),
+        endpoint(
+            "get",
+            "/_maelstrom/admin/v1/users/{user_id}",
+            AccessToken,
+            "Gets a local user",
+        )
+        .response::<admin::UserResponse>(),
+        endpoint(
+            "post",
+            "/_maelstrom/admin/v1/users/{user_id}/password",
+            AccessToken,
+            "Replaces a user's password",
+        )
+        .request::<admin::ResetPasswordRequest>(),
+        endpoint(
+            "post",
+            "/_maelstrom/admin/v1/users/{user_id}/deactivate",
+            AccessToken,
+            "Deactivates a user",
+        )
+        .optional_request::<admin::DeactivateRequest>(),
+        endpoint(
+            "post",
+            "/_maelstrom/admin/v1/users/{user_id}/reactivate",
+            AccessToken,
+            "Reactivates a user",
+        ),
+        endpoint(
+            "put",
+            "/_maelstrom/admin/v1/users/{user_id}/send_limit",
+            AccessToken,
+            "Exempts a user from event send throttling",
+        )
+        .request::<admin::SendLimitRequest>(),
+        endpoint(
+            "get",
+            "/_maelstrom/admin/v1/rooms",
+            AccessToken,
+            "Lists the rooms the server knows of",
+        )
+        .response::<admin::ListRoomsResponse>(),
+        endpoint(
+            "delete",
+            "/_maelstrom/admin/v1/rooms/{room_id}/members/{user_id}",
+            AccessToken,
+            "Removes a local user from a room",
+        ),
+        endpoint(
+            "post",
+            "/_maelstrom/admin/v1/rooms/{room_id}/shutdown",
+            AccessToken,
+            "Shuts a room down",
+        )
+        .optional_request::<admin::ShutdownRequest>(),
+        endpoint(
+            "post",
+            "/_maelstrom/admin/v1/rooms/{room_id}/purge_history",
+            AccessToken,
+            "Purges a room's old events",
+        )
+        .request::<admin::PurgeHistoryRequest>(),
+        endpoint(
+            "post",
+            "/_maelstrom/admin/v1/rooms/{room_id}/bulk_invite",
+            AccessToken,
+            "Invites many users to a room in the background",
+        )
+        .request::<admin::BulkInviteRequest>(),
+        endpoint(
+            "post",
+            "/_maelstrom/admin/v1/rooms/{room_id}/export_members",
+            AccessToken,
+            "Exports a room's members in the background",
+        ),
+        endpoint(
+            "get",
+            "/_maelstrom/admin/v1/jobs/{job_id}",
+            AccessToken,
+            "Gets a room moderation job",
+        )
+        .response::<admin::AdminJob>(),
+        endpoint(
+            "get",
+            "/_maelstrom/admin/v1/jobs/{job_id}/members",
+            AccessToken,
+            "Downloads the members an export job snapshotted, as JSON or CSV",
+        )
+        .response::<admin::ExportedMembersResponse>(),
+        endpoint(
+            "get",
+            "/_maelstrom/admin/v1/scheduled_jobs",
+            AccessToken,
+            "Lists background jobs",
+        )
+        .response::<admin::ListScheduledJobsResponse>(),
+        endpoint(
+            "post",
+            "/_maelstrom/admin/v1/media/purge",
+            AccessToken,
+            "Runs the media retention policies now",
+        ),
+        endpoint(
+            "get",
+            "/_maelstrom/admin/v1/audit_log",
+            AccessToken,
+            "Reads the audit log",
+        )
+        .response::<admin::AuditLogResponse>(),
+        endpoint(
+            "get",
+            "/_maelstrom/admin/v1/ip_blocks",
+            AccessToken,
+            "Lists blocked networks",
+        )
+        .response::<access::ListIpBlocksResponse>(),
+        endpoint(
+            "post",
+            "/_maelstrom/admin/v1/ip_blocks",
+            AccessToken,
+            "Blocks a network from the client or federation API",
+        )
+        .request::<access::AddIpBlockRequest>()
+        .response::<access::IpBlock>(),
+        endpoint(
+            "delete",
+            "/_maelstrom/admin/v1/ip_blocks/{block_id}",
+            AccessToken,
+            "Lifts a network's block",
+        ),
+        endpoint(
+            "get",
+            "/_maelstrom/admin/v1/statistics",
+            AccessToken,
+            "Gets the server's user and room counts, and version",
+        )
+        .response::<admin::Statistics>(),
+        endpoint(
+            "get",
+            "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device",
+            AccessToken,
+            "Gets the dehydrated device",
+        )
+        .response::<dehydrated_device::DehydratedDevice>(),
+        endpoint(
+            "put",
+            "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device",
+            AccessToken,
+            "Replaces the dehydrated device",
+        )
+        .request::<dehydrated_device::PutRequest>()
+        .response::<dehydrated_device::DeviceIdResponse>(),
+        endpoint(
+            "delete",
+            "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device",
+            AccessToken,
+            "Deletes the dehydrated device",
+        )
+        .response::<dehydrated_device::DeviceIdResponse>(),
+        endpoint(
+            "post",
+            "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device/{device_id}/events",
+            AccessToken,
+            "Gets the dehydrated device's to-device messages",
+        )
+        .optional_request::<dehydrated_device::EventsRequest>()
+        .response::<dehydrated_device::EventsResponse>(),
+        endpoint(
+            "get",
+            "/_matrix/client/unstable/im.nheko.summary/rooms/{room_id_or_alias}/summary",
+            AccessToken,
+            "Summarizes a room",
+        )
+        .response::<room::SummaryResponse>(),
+        endpoint(
+            "post",
+            "/_matrix/media/r0/upload",
+            AccessToken,
+            "Uploads media",
+        )
+        .response::<media::UploadResponse>(),
+        endpoint(
+            "get",
+            "/_matrix/media/r0/download/{server_name}/{media_id}",
+            Auth::None,
+            "Downloads media",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/media/r0/download/{server_name}/{media_id}/{file_name}",
+            Auth::None,
+            "Downloads media with a file name",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/media/r0/thumbnail/{server_name}/{media_id}",
+            Auth::None,
+            "Gets a thumbnail of media",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/media/r0/preview_url",
+            AccessToken,
+            "Gets a preview of a URL",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/media/r0/config",
+            AccessToken,
+            "Gets the content repository's limits",
+        )
+        .response::<media::ConfigResponse>(),
+        endpoint(
+            "get",
+            "/_matrix/client/v1/media/download/{server_name}/{media_id}",
+            AccessToken,
+            "Downloads media",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/v1/media/download/{server_name}/{media_id}/{file_name}",
+            AccessToken,
+            "Downloads media with a file name",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/v1/media/thumbnail/{server_name}/{media_id}",
+            AccessToken,
+            "Gets a thumbnail of media",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/v1/media/preview_url",
+            AccessToken,
+            "Gets a preview of a URL",
+        ),
+        endpoint(
+            "get",
+            "/_matrix/client/v1/media/config",
+            AccessToken,
+            "Gets the content repository's limits",
+        )
+        .response::<media::ConfigResponse>(),
+        endpoint(
+            "post",
+            "/_maelstrom/replication/v1/federation/wake",
+            Replication,
+            "Wakes the federation sender",
+        )
+        .request::<federation::WakeRequest>(),
+    ]
+}
+
+/// The tag an endpoint is grouped under.
+fn tag(path: &str) -> &'static str {
+    TAGS.iter()
+        .find(|(prefix, _, _)| path.starts_with(prefix))
+        .map_or("Other", |(_, name, _)| *name)
+}
+
+/// The path parameters of an endpoint, in the order they appear.
+fn path_parameters(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .collect()
+}
+
+fn security(auth: Auth) -> Value {
+    match auth {
+        Auth::None => json!([]),
+        AccessToken => json!([{ "accessToken": [] }, { "accessTokenQuery": [] }]),
+        Server => json!([{ "serverSignature": [] }]),
+        Replication => json!([{ "replicationSecret": [] }]),
+    }
+}
+
+fn operation(endpoint: &Endpoint, gen: &mut SchemaGenerator) -> Value {
+    let parameters: Vec<Value> = path_parameters(endpoint.path)
+        .into_iter()
+        .map(|name| {
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string" },
+            })
+        })
+        .collect();
+    let mut operation = json!({
+        "tags": [tag(endpoint.path)],
+        "summary": endpoint.summary,
+        "parameters": parameters,
+        "security": security(endpoint.auth),
+        "responses": {
+            "default": { "description": "As in the Matrix specification" },
+        },
+    });
+    if let Some((request, required)) = endpoint.request {
+        operation["requestBody"] = json!({
+            "required": required,
+            "content": { "application/json": { "schema": request(gen) } },
+        });
+    }
+    if let Some(response) = endpoint.response {
+        operation["responses"]["200"] = json!({
+            "description": "Success",
+            "content": { "application/json": { "schema": response(gen) } },
+        });
+    }
+    operation
+}
+
+/// Builds the OpenAPI document.
+pub fn document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let mut paths = Map::new();
+    for endpoint in endpoints() {
+        let item = paths
+            .entry(endpoint.path)
+            .or_insert_with(|| Value::Object(Map::new()));
+        item[endpoint.method] = operation(&endpoint, &mut gen);
+    }
+    let mut tags: Vec<Value> = Vec::new();
+    for (_, name, description) in TAGS {
+        if !tags.iter().any(|tag| tag["name"] == *name) {
+            tags.push(json!({ "name": name, "description": description }));
+        }
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Maelstrom",
+            "description": "The endpoints this server implements. Bodies not given here are as in the Matrix specification.",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "tags": tags,
+        "paths": paths,
+        "components": {
+            "schemas": gen.definitions(),
+            "securitySchemes": {
+                "accessToken": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "A user's access token. Admin endpoints also need the user to be a server admin.",
+                },
+                "accessTokenQuery": {
+                    "type": "apiKey",
+                    "in": "query",
+                    "name": "access_token",
+                },
+                "serverSignature": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "Authorization",
+                    "description": "An `X-Matrix` request signature by the requesting server",
+                },
+                "replicationSecret": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "The REPLICATION_SECRET shared by the server's processes",
+                },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use std::collections::BTreeSet;
+
+    /// The method and full path of every route in `routes.rs`. Scopes are
+    /// followed in the order they are written, which is how they nest there.
+    fn routes() -> BTreeSet<(String, String)> {
+        let source = include_str!("routes.rs");
+        let token = Regex::new(concat!(
+            r#"scope\(\s*"(?P<scope>[^"]+)"\)"#,
+            r#"|resource\(\s*"(?P<resource>[^"]+)"\)"#,
+            r#"|\.route\(\s*"(?P<path>[^"]+)",\s*(?P<path_method>get|put|post|delete)\(\)"#,
+            r#"|(?P<method>get|put|post|delete)\(\)\.to"#,
+        ))
+        .unwrap();
+        let (mut scope, mut resource) = ("", String::new());
+        let mut routes = BTreeSet::new();
+        for token in token.captures_iter(source) {
+            if let Some(prefix) = token.name("scope") {
+                scope = prefix.as_str();
+            } else if let Some(path) = token.name("resource") {
+                resource = format!("{}{}", scope, path.as_str());
+            } else if let Some(path) = token.name("path") {
+                routes.insert((token["path_method"].to_owned(), path.as_str().to_owned()));
+            } else {
+                routes.insert((token["method"].to_owned(), resource.clone()));
+            }
+        }
+        routes
+    }
+
+    #[test]
+    fn test_endpoints_match_routes() {
+        let endpoints = endpoints();
+        let documented: BTreeSet<(String, String)> = endpoints
+            .iter()
+            .map(|e| (e.method.to_owned(), e.path.to_owned()))
+            .collect();
+        assert_eq!(documented.len(), endpoints.len(), "duplicate endpoint");
+        assert_eq!(documented, routes());
+    }
+
+    #[test]
+    fn test_document() {
+        let document = document();
+        let operation = &document["paths"]["/_matrix/client/r0/room_keys/keys/{room_id}"]["put"];
+        assert_eq!(operation["tags"], json!(["Client"]));
+        assert_eq!(operation["parameters"][0]["name"], "room_id");
+        assert_eq!(
+            document["paths"]["/_matrix/client/r0/room_keys/keys/{room_id}"]
+                .as_object()
+                .unwrap()
+                .len(),
+            3
+        );
+        assert_eq!(
+            document["paths"]["/health/live"]["get"]["security"],
+            json!([])
+        );
+        assert_eq!(
+            document["paths"]["/_matrix/client/v1/media/config"]["get"]["tags"],
+            json!(["Media"])
+        );
+        // Tags shared by several prefixes are listed once
+        let tags = document["tags"].as_array().unwrap();
+        assert_eq!(tags.iter().filter(|tag| tag["name"] == "Media").count(), 1);
+    }
+
+    /// Every `$ref` in a document, wherever it is nested.
+    fn references(value: &Value, refs: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(reference)) = map.get("$ref") {
+                    refs.push(reference.clone());
+                }
+                map.values().for_each(|value| references(value, refs));
+            }
+            Value::Array(values) => values.iter().for_each(|value| references(value, refs)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_body_schemas() {
+        let document = document();
+        let login = &document["paths"]["/_matrix/client/r0/login"]["post"];
+        assert_eq!(
+            login["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/LoginRequest"
+        );
+        assert_eq!(
+            login["responses"]["200"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/LoginResponse"
+        );
+        let schemas = &document["components"]["schemas"];
+        assert_eq!(
+            schemas["LoginResponse"]["properties"]["user_id"]["type"],
+            "string"
+        );
+        // Models sharing a name are kept apart
+        assert!(schemas.get("UploadResponse").is_some());
+        assert!(schemas.get("MediaUploadResponse").is_some());
+
+        let mut refs = Vec::new();
+        references(&document, &mut refs);
+        for reference in refs {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            assert!(schemas.get(name).is_some(), "dangling {}", reference);
+        }
+        assert!(document["paths"]["/metrics"]["get"]
+            .get("requestBody")
+            .is_none());
+    }
+
+    #[test]
+    fn test_path_parameters() {
+        assert_eq!(
+            path_parameters("/_matrix/federation/v1/make_join/{room_id}/{user_id}"),
+            vec!["room_id", "user_id"]
+        );
+        assert!(path_parameters("/_matrix/client/r0/sync").is_empty());
+    }
+}
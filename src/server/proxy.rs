@@ -0,0 +1,244 @@
+//! Finding the real client behind reverse proxies.
+//!
+//! Proxies say who they forwarded a request for in the `Forwarded` or
+//! `X-Forwarded-For` header, and how it reached them in `X-Forwarded-Proto`.
+//! Anyone can send those headers though, so they are only believed from the
+//! networks in `TRUSTED_PROXIES`, and removed from everyone else's requests.
+use std::net::IpAddr;
+
+use actix_web::{
+    dev::ServiceRequest,
+    http::{
+        header::{HeaderName, HeaderValue},
+        HeaderMap,
+    },
+    HttpMessage, HttpRequest,
+};
+
+use crate::ipnet::IpNet;
+
+const FORWARDED: &str = "forwarded";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// The address of the client a request came from, once proxies are
+/// accounted for. Kept in the request's extensions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ClientIp(IpAddr);
+
+/// Who a request came from, and how it reached the first proxy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Client {
+    pub ip: IpAddr,
+    /// The scheme the client used, if a trusted proxy said
+    pub proto: Option<String>,
+}
+
+/// Finds the client a request from `peer` came from. Forwarded addresses are
+/// walked from the nearest proxy outwards, for as long as they are trusted
+/// proxies themselves.
+pub fn resolve(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> Client {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return Client {
+            ip: peer,
+            proto: None,
+        };
+    }
+
+    // `Forwarded` is standard, so it wins when a proxy sends both
+    let forwarded = header_list(headers, FORWARDED);
+    let (hops, proto) = if forwarded.is_empty() {
+        let hops = header_list(headers, X_FORWARDED_FOR);
+        let proto = header_list(headers, X_FORWARDED_PROTO).pop();
+        (hops, proto)
+    } else {
+        let hops = forwarded
+            .iter()
+            .filter_map(|element| parameter(element, "for"))
+            .collect();
+        let proto = forwarded
+            .last()
+            .and_then(|element| parameter(element, "proto"));
+        (hops, proto)
+    };
+
+    let mut ip = peer;
+    for hop in hops.iter().rev() {
+        match parse_node(hop) {
+            Some(hop) => {
+                ip = hop;
+                if !is_trusted(&hop) {
+                    break;
+                }
+            }
+            // An obfuscated or unknown node can't be followed any further
+            None => break,
+        }
+    }
+    Client {
+        ip,
+        proto: proto.map(|proto| proto.to_ascii_lowercase()),
+    }
+}
+
+/// Resolves the client of a request, keeping its address for `client_ip`.
+/// The forwarding headers are replaced with what was resolved, so nothing
+/// later on can be misled by them.
+pub fn forward(req: &mut ServiceRequest, trusted: &[IpNet]) {
+    let peer = match req.peer_addr() {
+        Some(addr) => addr.ip(),
+        None => return,
+    };
+    let client = resolve(peer, req.headers(), trusted);
+    let trusted_peer = trusted.iter().any(|net| net.contains(&peer));
+
+    let headers = req.headers_mut();
+    headers.remove(FORWARDED);
+    headers.remove(X_FORWARDED_FOR);
+    headers.remove(X_FORWARDED_PROTO);
+    if !trusted_peer {
+        headers.remove(X_FORWARDED_HOST);
+    }
+    if client.ip != peer {
+        if let Ok(value) = HeaderValue::from_str(&client.ip.to_string()) {
+            headers.insert(HeaderName::from_static(X_FORWARDED_FOR), value);
+        }
+    }
+    if let Some(value) = client
+        .proto
+        .and_then(|proto| HeaderValue::from_str(&proto).ok())
+    {
+        headers.insert(HeaderName::from_static(X_FORWARDED_PROTO), value);
+    }
+    req.extensions_mut().insert(ClientIp(client.ip));
+}
+
+/// The address of the client a request came from, behind any trusted
+/// proxies.
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    match req.extensions().get::<ClientIp>() {
+        Some(client) => Some(client.0),
+        None => req.peer_addr().map(|addr| addr.ip()),
+    }
+}
+
+/// The comma separated values of every instance of a header, in order, e.g.
+/// the elements of `Forwarded`, one per proxy.
+fn header_list(headers: &HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// The value of a parameter of a `Forwarded` element, e.g. `for` of
+/// `for=192.0.2.60;proto=https`.
+fn parameter(element: &str, name: &str) -> Option<String> {
+    element.split(';').find_map(|pair| {
+        let mut split = pair.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(name) => {
+                Some(value.trim().trim_matches('"').to_owned())
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Parses a forwarded node: an IP address, with an optional port, and IPv6
+/// addresses in brackets if they have one.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.rsplitn(2, ':').nth(1)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let trusted = IpNet::parse_list("10.0.0.0/8, fd00::/8").unwrap();
+        let forwarded = headers(&[
+            ("x-forwarded-for", "198.51.100.1, 203.0.113.7"),
+            ("x-forwarded-for", "10.0.0.2"),
+            ("x-forwarded-proto", "HTTPS"),
+        ]);
+
+        // Untrusted peers can claim anything
+        assert_eq!(
+            resolve(ip("192.0.2.1"), &forwarded, &trusted),
+            Client {
+                ip: ip("192.0.2.1"),
+                proto: None,
+            }
+        );
+        // The first untrusted hop is the client, not what it claims
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &forwarded, &trusted),
+            Client {
+                ip: ip("203.0.113.7"),
+                proto: Some("https".to_owned()),
+            }
+        );
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers(&[]), &trusted).ip,
+            ip("10.0.0.1")
+        );
+
+        let forwarded = headers(&[
+            ("forwarded", r#"for="[2001:db8::1]:4711";proto=http"#),
+            ("forwarded", "for=fd00::2;proto=https"),
+            ("x-forwarded-for", "198.51.100.1"),
+        ]);
+        assert_eq!(
+            resolve(ip("fd00::1"), &forwarded, &trusted),
+            Client {
+                ip: ip("2001:db8::1"),
+                proto: Some("https".to_owned()),
+            }
+        );
+
+        // Unknown nodes stop the walk at the proxy that reported them
+        let forwarded = headers(&[("forwarded", "for=198.51.100.1, for=unknown, for=10.0.0.2")]);
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &forwarded, &trusted).ip,
+            ip("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn test_parse_node() {
+        assert_eq!(parse_node("192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("192.0.2.1:8080"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[2001:db8::1]:443"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node("unknown"), None);
+    }
+}
@@ -0,0 +1,425 @@
+//! Token bucket rate limiting of the client and media APIs.
+//!
+//! Each access token has a bucket of requests it may make, which refills at
+//! a steady rate up to a burst. Requests without a valid access token are
+//! limited the same way by remote IP instead. Server admins, and the users of
+//! exempt application services, are never limited.
+//!
+//! Separately, each user has a bucket of events they may send, which is
+//! usually much smaller. It is only taken from when an event is sent, so far
+//! only to-device messages, so a spammer or a runaway bot is slowed down
+//! without cutting off their reads.
+//! Admins can also exempt individual users from it.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpResponse,
+};
+use futures::future::{ok, Ready};
+use serde_json::json;
+
+use crate::{
+    db::Store,
+    models::admin::Account,
+    server::{error::ErrorCode, extract::Authenticated, proxy},
+    CONFIG,
+};
+
+/// The paths of the APIs that are rate limited.
+const LIMITED_PREFIXES: &[&str] = &["/_matrix/client", "/_matrix/media"];
+/// How many buckets a limiter keeps before it forgets the full ones.
+const MAX_BUCKETS: usize = 10_000;
+
+/// How requests are rate limited.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimits {
+    /// The rate each access token may make requests at, if limited
+    pub client: Option<Rate>,
+    /// The rate each remote IP may make requests without an access token
+    /// at, if limited
+    pub ip: Option<Rate>,
+    /// The rate each user may send events at, if limited
+    pub event_send: Option<Rate>,
+    /// The application services whose users are not limited
+    pub exempt_appservices: Vec<String>,
+}
+
+impl RateLimits {
+    /// Reads the rate limits from `env` vars. Panics if any can't be parsed.
+    pub fn from_env() -> Self {
+        RateLimits {
+            client: Rate::from_env("RATE_LIMIT_PER_SECOND", 10.0, "RATE_LIMIT_BURST", 100.0),
+            ip: Rate::from_env("RATE_LIMIT_IP_PER_SECOND", 5.0, "RATE_LIMIT_IP_BURST", 50.0),
+            event_send: Rate::from_env("EVENT_SEND_PER_SECOND", 0.2, "EVENT_SEND_BURST", 10.0),
+            exempt_appservices: std::env::var("RATE_LIMIT_EXEMPT_APPSERVICES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|id| id.trim().to_owned())
+                .filter(|id| !id.is_empty())
+                .collect(),
+        }
+    }
+}
+
+/// A rate requests may be made at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    /// How many requests a second a bucket refills by
+    pub per_second: f64,
+    /// How many requests a bucket holds, i.e. how many can be made at once
+    pub burst: f64,
+}
+
+impl Rate {
+    /// Reads a rate from `env` vars, falling back to the defaults given. A
+    /// rate of 0 turns the limit off.
+    fn from_env(
+        per_second_var: &str,
+        per_second: f64,
+        burst_var: &str,
+        burst: f64,
+    ) -> Option<Self> {
+        let parse = |var: &str, default: f64| {
+            std::env::var(var)
+                .map(|value| {
+                    value
+                        .parse()
+                        .unwrap_or_else(|_| panic!("Unable to parse {} as f64.", var))
+                })
+                .unwrap_or(default)
+        };
+        let rate = Rate {
+            per_second: parse(per_second_var, per_second),
+            burst: parse(burst_var, burst),
+        };
+        if rate.per_second > 0.0 {
+            Some(rate)
+        } else {
+            None
+        }
+    }
+
+    /// Refills a bucket for the time since it was last used.
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+    }
+
+    /// Takes a request from a bucket, or returns how long until one can be
+    /// taken.
+    fn take(&self, bucket: &mut Bucket, now: Instant) -> Result<(), Duration> {
+        self.refill(bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+/// The requests left to someone.
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The buckets of everyone limited at one rate. Can be cloned and used from
+/// any thread.
+#[derive(Clone, Debug)]
+struct Limiter {
+    rate: Rate,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl Limiter {
+    fn new(rate: Rate) -> Self {
+        Limiter {
+            rate,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a request from `key`'s bucket, or returns how long until one
+    /// can be taken.
+    fn check(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        // Full buckets are the same as new ones, so only those are forgotten
+        if buckets.len() >= MAX_BUCKETS {
+            let rate = self.rate;
+            buckets.retain(|_, bucket| {
+                rate.refill(bucket, now);
+                bucket.tokens < rate.burst
+            });
+        }
+        let rate = self.rate;
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: rate.burst,
+            updated: now,
+        });
+        rate.take(bucket, now)
+    }
+}
+
+/// Rate limits the client and media APIs. Create it once, outside of
+/// `HttpServer::new`, so every worker shares its buckets.
+#[derive(Clone)]
+pub struct RateLimit<T> {
+    client: Option<Limiter>,
+    ip: Option<Limiter>,
+    exempt_appservices: Arc<Vec<String>>,
+    storage: T,
+}
+
+impl<T: Store> RateLimit<T> {
+    pub fn new(limits: &RateLimits, storage: T) -> Self {
+        RateLimit {
+            client: limits.client.map(Limiter::new),
+            ip: limits.ip.map(Limiter::new),
+            exempt_appservices: Arc::new(limits.exempt_appservices.clone()),
+            storage,
+        }
+    }
+}
+
+/// Whether the requester is a server admin, or a user of an exempt
+/// application service, or of one registered as not rate limited, or their
+/// account is `exempt`. Only asked once they are over their limit.
+async fn is_exempt<T: Store>(
+    storage: &T,
+    exempt_appservices: &[String],
+    auth: &Authenticated,
+    exempt: impl Fn(&Account) -> bool,
+) -> bool {
+    if let Some(appservice_id) = &auth.appservice_id {
+        let unlimited = CONFIG
+            .appservices
+            .get(appservice_id)
+            .map_or(false, |service| !service.rate_limited);
+        if unlimited || exempt_appservices.contains(appservice_id) {
+            return true;
+        }
+    }
+    match storage.get_account(&auth.user_id.local_part).await {
+        Ok(Some(account)) => {
+            (account.is_admin && !account.deactivated)
+                || exempt(&account)
+                || account
+                    .appservice_id
+                    .map_or(false, |id| exempt_appservices.contains(&id))
+        }
+        Ok(None) => false,
+        Err(e) => {
+            tracing::error!(error = %e, "Unable to check whether a user is rate limited");
+            false
+        }
+    }
+}
+
+/// Throttles how fast each user sends events. Create it once, outside of
+/// `HttpServer::new`, so every worker shares its buckets.
+#[derive(Clone, Debug)]
+pub struct SendLimit {
+    limiter: Option<Limiter>,
+    exempt_appservices: Arc<Vec<String>>,
+}
+
+impl SendLimit {
+    pub fn new(limits: &RateLimits) -> Self {
+        SendLimit {
+            limiter: limits.event_send.map(Limiter::new),
+            exempt_appservices: Arc::new(limits.exempt_appservices.clone()),
+        }
+    }
+
+    /// Takes an event from the sender's bucket, or fails with
+    /// `M_LIMIT_EXCEEDED` if they have to wait to send it. Every device of a
+    /// user shares one bucket.
+    ///
+    /// TODO: Also check room events, state and redactions once they can be
+    /// sent.
+    pub async fn check<T: Store>(&self, storage: &T, auth: &Authenticated) -> Result<(), Error> {
+        let limiter = match &self.limiter {
+            Some(limiter) => limiter,
+            None => return Ok(()),
+        };
+        if let Err(retry_after) = limiter.check(&auth.user_id.to_string(), Instant::now()) {
+            let exempt = is_exempt(storage, &self.exempt_appservices, auth, |account| {
+                account.send_limit_exempt
+            })
+            .await;
+            if !exempt {
+                tracing::debug!(?retry_after, "Event sending throttled");
+                return Err(limit_exceeded(retry_after));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S, B, T> Transform<S> for RateLimit<T>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+    T: Store + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S, T>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            limits: self.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S, T> {
+    service: Rc<RefCell<S>>,
+    limits: RateLimit<T>,
+}
+
+impl<S, B, T> Service for RateLimitMiddleware<S, T>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+    T: Store + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if !LIMITED_PREFIXES
+            .iter()
+            .any(|prefix| req.path().starts_with(prefix))
+        {
+            return Box::pin(self.service.borrow_mut().call(req));
+        }
+
+        let now = Instant::now();
+        // Invalid tokens are rejected by the handlers, but are still limited
+        let auth = Authenticated::access_token(req.headers(), req.query_string())
+            .and_then(|token| Authenticated::from_token(token, req.query_string()).ok());
+        let limited = match &auth {
+            Some(auth) => match &self.limits.client {
+                Some(limiter) => limiter.check(&auth.access_token, now),
+                None => Ok(()),
+            },
+            None => match (&self.limits.ip, proxy::client_ip(req.request())) {
+                (Some(limiter), Some(ip)) => limiter.check(&ip_key(ip), now),
+                _ => Ok(()),
+            },
+        };
+
+        let service = self.service.clone();
+        let limits = self.limits.clone();
+        Box::pin(async move {
+            if let Err(retry_after) = limited {
+                let exempt = match &auth {
+                    Some(auth) => {
+                        is_exempt(&limits.storage, &limits.exempt_appservices, auth, |_| false)
+                            .await
+                    }
+                    None => false,
+                };
+                if !exempt {
+                    tracing::debug!(?retry_after, "Rate limited");
+                    // A response rather than an error, so it gets CORS headers
+                    return Ok(req.error_response(limit_exceeded(retry_after)));
+                }
+            }
+            service.borrow_mut().call(req).await
+        })
+    }
+}
+
+/// The bucket key of a remote IP. IPv6 clients are given a whole /64, as
+/// they usually are by their ISP.
+pub fn ip_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            format!(
+                "{:x}:{:x}:{:x}:{:x}::/64",
+                segments[0], segments[1], segments[2], segments[3]
+            )
+        }
+    }
+}
+
+fn limit_exceeded(retry_after: Duration) -> Error {
+    HttpResponse::TooManyRequests()
+        .json(json!({
+            "errcode": ErrorCode::LIMIT_EXCEEDED,
+            "error": "Too many requests.",
+            "retry_after_ms": retry_after.as_millis() as u64,
+        }))
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: Rate = Rate {
+        per_second: 2.0,
+        burst: 3.0,
+    };
+
+    #[test]
+    fn test_bucket() {
+        let limiter = Limiter::new(RATE);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", start), Ok(()));
+        }
+        assert_eq!(limiter.check("a", start), Err(Duration::from_millis(500)));
+        // Others have their own buckets
+        assert_eq!(limiter.check("b", start), Ok(()));
+
+        let later = start + Duration::from_millis(750);
+        assert_eq!(limiter.check("a", later), Ok(()));
+        assert_eq!(limiter.check("a", later), Err(Duration::from_millis(250)));
+        // Buckets only refill up to the burst
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", much_later), Ok(()));
+        }
+        assert!(limiter.check("a", much_later).is_err());
+    }
+
+    #[test]
+    fn test_ip_key() {
+        assert_eq!(ip_key("192.0.2.1".parse().unwrap()), "192.0.2.1");
+        assert_eq!(
+            ip_key("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+            "2001:db8:1:2::/64"
+        );
+    }
+}
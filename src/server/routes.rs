@@ -1,26 +1,395 @@
-use super::handlers;
+use super::{features, handlers, worker::Role};
 use crate::db::Store;
+use crate::media::MediaStore;
+use crate::CONFIG;
 use actix_web::web::ServiceConfig;
-use actix_web::web::{get, post, resource, scope};
+use actix_web::web::{delete, get, post, put, resource, scope};
 
-/// Configures the routes/services for Server
-pub fn config<T: Store + 'static>(cfg: &mut ServiceConfig) {
+/// Configures the routes/services a process with `role` serves
+pub fn config<T: Store + 'static, M: MediaStore>(role: Role, cfg: &mut ServiceConfig) {
+    cfg.route("/health/live", get().to(handlers::health::get_live))
+        .route("/health/ready", get().to(handlers::health::get_ready::<T>))
+        .route("/metrics", get().to(handlers::metrics::get_metrics));
+    match role {
+        Role::Main => {
+            main::<T>(cfg);
+            media::<T, M>(cfg);
+        }
+        Role::SyncWorker => sync::<T>(cfg),
+        Role::FederationSender => replication(cfg),
+        Role::Media => media::<T, M>(cfg),
+    }
+}
+
+/// Configures the routes only the main process serves.
+fn main<T: Store + 'static>(cfg: &mut ServiceConfig) {
     cfg.route(
         "/.well-known/matrix/client",
         get().to(handlers::admin::get_wellknown),
     )
+    .route(
+        "/.well-known/matrix/server",
+        get().to(handlers::admin::get_wellknown_server),
+    )
     .route(
         "/_matrix/client/versions",
         get().to(handlers::admin::get_versions),
     )
+    .route(
+        "/_matrix/client/v3/capabilities",
+        get().to(handlers::capabilities::get_capabilities),
+    )
     .service(
         scope("/_matrix/client/r0")
+            .service(
+                resource("/login")
+                    .route(get().to(handlers::auth::login_info))
+                    .route(post().to(handlers::auth::login::<T>)),
+            )
             .service(
                 resource("/register").route(post().to(handlers::registration::post_register::<T>)),
             )
+            .service(
+                resource("/capabilities").route(get().to(handlers::capabilities::get_capabilities)),
+            )
             .service(
                 resource("/register/available")
                     .route(get().to(handlers::registration::get_available::<T>)),
+            )
+            .service(
+                resource("/account/password")
+                    .route(post().to(handlers::account::change_password::<T>)),
+            )
+            .service(
+                resource("/account/deactivate")
+                    .route(post().to(handlers::account::deactivate_account::<T>)),
+            )
+            .service(
+                resource("/user/{user_id}/account_data/{type}")
+                    .route(get().to(handlers::user::get_account_data::<T>))
+                    .route(put().to(handlers::user::put_account_data::<T>)),
+            )
+            .service(
+                resource("/user/{user_id}/rooms/{room_id}/account_data/{type}")
+                    .route(get().to(handlers::user::get_room_account_data::<T>))
+                    .route(put().to(handlers::user::put_room_account_data::<T>)),
+            )
+            .service(resource("/keys/upload").route(post().to(handlers::keys::upload::<T>)))
+            .service(resource("/keys/query").route(post().to(handlers::keys::query::<T>)))
+            .service(resource("/keys/claim").route(post().to(handlers::keys::claim::<T>)))
+            .service(resource("/keys/changes").route(get().to(handlers::keys::changes::<T>)))
+            .service(
+                resource("/keys/device_signing/upload")
+                    .route(post().to(handlers::keys::upload_device_signing::<T>)),
+            )
+            .service(
+                resource("/keys/signatures/upload")
+                    .route(post().to(handlers::keys::upload_signatures::<T>)),
+            )
+            .service(
+                resource("/sendToDevice/{type}/{txn_id}")
+                    .route(put().to(handlers::to_device::send::<T>)),
+            )
+            .service(
+                resource("/room_keys/version")
+                    .route(get().to(handlers::room_keys::get_latest_version::<T>))
+                    .route(post().to(handlers::room_keys::create_version::<T>)),
+            )
+            .service(
+                resource("/room_keys/version/{version}")
+                    .route(get().to(handlers::room_keys::get_version::<T>))
+                    .route(put().to(handlers::room_keys::update_version::<T>))
+                    .route(delete().to(handlers::room_keys::delete_version::<T>)),
+            )
+            .service(
+                resource("/room_keys/keys")
+                    .route(get().to(handlers::room_keys::get_keys::<T>))
+                    .route(put().to(handlers::room_keys::put_keys::<T>))
+                    .route(delete().to(handlers::room_keys::delete_keys::<T>)),
+            )
+            .service(
+                resource("/room_keys/keys/{room_id}")
+                    .route(get().to(handlers::room_keys::get_room_keys::<T>))
+                    .route(put().to(handlers::room_keys::put_room_keys::<T>))
+                    .route(delete().to(handlers::room_keys::delete_room_keys::<T>)),
+            )
+            .service(
+                resource("/room_keys/keys/{room_id}/{session_id}")
+                    .route(get().to(handlers::room_keys::get_session_key::<T>))
+                    .route(put().to(handlers::room_keys::put_session_key::<T>))
+                    .route(delete().to(handlers::room_keys::delete_session_key::<T>)),
+            )
+            .service(
+                resource("/pushrules/").route(get().to(handlers::push_rules::get_push_rules::<T>)),
+            )
+            .service(
+                resource("/pushrules/{scope}/{kind}/{rule_id}")
+                    .route(get().to(handlers::push_rules::get_push_rule::<T>))
+                    .route(put().to(handlers::push_rules::put_push_rule::<T>))
+                    .route(delete().to(handlers::push_rules::delete_push_rule::<T>)),
+            )
+            .service(
+                resource("/pushrules/{scope}/{kind}/{rule_id}/enabled")
+                    .route(get().to(handlers::push_rules::get_push_rule_enabled::<T>))
+                    .route(put().to(handlers::push_rules::put_push_rule_enabled::<T>)),
+            )
+            .service(
+                resource("/pushrules/{scope}/{kind}/{rule_id}/actions")
+                    .route(get().to(handlers::push_rules::get_push_rule_actions::<T>))
+                    .route(put().to(handlers::push_rules::put_push_rule_actions::<T>)),
+            )
+            .service(resource("/pushers").route(get().to(handlers::pushers::get_pushers::<T>)))
+            .service(resource("/pushers/set").route(post().to(handlers::pushers::set_pusher::<T>)))
+            .service(
+                resource("/profile/{user_id}").route(get().to(handlers::profile::get_profile::<T>)),
+            )
+            .service(
+                resource("/profile/{user_id}/displayname")
+                    .route(get().to(handlers::profile::get_displayname::<T>))
+                    .route(put().to(handlers::profile::put_displayname::<T>)),
+            )
+            .service(
+                resource("/profile/{user_id}/avatar_url")
+                    .route(get().to(handlers::profile::get_avatar_url::<T>))
+                    .route(put().to(handlers::profile::put_avatar_url::<T>)),
+            )
+            .service(
+                resource("/presence/{user_id}/status")
+                    .route(get().to(handlers::presence::get_presence::<T>)),
+            )
+            .service(
+                resource("/rooms/{room_id}/invite").route(post().to(handlers::invite::invite::<T>)),
+            )
+            .service(
+                resource("/rooms/{room_id}/report/{event_id}")
+                    .route(post().to(handlers::report::report_event::<T>)),
+            )
+            .service(
+                resource("/publicRooms")
+                    .route(get().to(handlers::directory::get_public_rooms::<T>))
+                    .route(post().to(handlers::directory::post_public_rooms::<T>)),
+            )
+            .service(resource("/sync").route(get().to(handlers::sync::get_sync::<T>))),
+    )
+    .service(
+        scope("/_matrix/federation/v1")
+            .app_data(CONFIG.limits.federation.json_config())
+            .service(
+                resource("/send/{txn_id}")
+                    .route(put().to(handlers::federation::send_transaction::<T>)),
+            )
+            .service(
+                resource("/backfill/{room_id}")
+                    .route(get().to(handlers::federation::backfill::<T>)),
+            )
+            .service(
+                resource("/get_missing_events/{room_id}")
+                    .route(post().to(handlers::federation::get_missing_events::<T>)),
+            )
+            .service(
+                resource("/query/profile").route(get().to(handlers::profile::query_profile::<T>)),
+            )
+            .service(resource("/3pid/onbind").route(put().to(handlers::invite::on_bind::<T>)))
+            .service(
+                resource("/publicRooms")
+                    .route(get().to(handlers::directory::get_federation_public_rooms::<T>))
+                    .route(post().to(handlers::directory::post_federation_public_rooms::<T>)),
+            )
+            .service(
+                resource("/make_join/{room_id}/{user_id}")
+                    .route(get().to(handlers::federation::make_join::<T>)),
+            )
+            .service(
+                resource("/send_join/{room_id}/{event_id}")
+                    .route(put().to(handlers::federation::send_join::<T>)),
+            ),
+    )
+    .service(
+        scope("/_matrix/federation/v2")
+            .app_data(CONFIG.limits.federation.json_config())
+            .service(
+                resource("/send_join/{room_id}/{event_id}")
+                    .route(put().to(handlers::federation::send_join::<T>)),
+            )
+            .service(
+                resource("/invite/{room_id}/{event_id}")
+                    .route(put().to(handlers::federation::invite::<T>)),
+            ),
+    )
+    .service(
+        scope("/_matrix/federation/unstable/org.matrix.msc3843").service(
+            resource("/rooms/{room_id}/report/{event_id}")
+                .route(post().to(handlers::report::receive_report::<T>)),
+        ),
+    )
+    .service(
+        scope("/_matrix/key/v2")
+            .service(resource("/server").route(get().to(handlers::federation::get_server_keys)))
+            .service(
+                resource("/server/{key_id}").route(get().to(handlers::federation::get_server_keys)),
+            ),
+    )
+    .service(
+        scope("/_maelstrom/admin/v1")
+            .service(
+                resource("/users")
+                    .route(get().to(handlers::admin_users::list_users::<T>))
+                    .route(post().to(handlers::admin_users::create_user::<T>)),
+            )
+            .service(
+                resource("/users/{user_id}").route(get().to(handlers::admin_users::get_user::<T>)),
+            )
+            .service(
+                resource("/users/{user_id}/password")
+                    .route(post().to(handlers::admin_users::reset_password::<T>)),
+            )
+            .service(
+                resource("/users/{user_id}/deactivate")
+                    .route(post().to(handlers::admin_users::deactivate_user::<T>)),
+            )
+            .service(
+                resource("/users/{user_id}/reactivate")
+                    .route(post().to(handlers::admin_users::reactivate_user::<T>)),
+            )
+            .service(
+                resource("/users/{user_id}/send_limit")
+                    .route(put().to(handlers::admin_users::set_send_limit::<T>)),
+            )
+            .service(resource("/rooms").route(get().to(handlers::admin_rooms::list_rooms::<T>)))
+            .service(
+                resource("/rooms/{room_id}/members/{user_id}")
+                    .route(delete().to(handlers::admin_rooms::remove_member::<T>)),
+            )
+            .service(
+                resource("/rooms/{room_id}/shutdown")
+                    .route(post().to(handlers::admin_rooms::shutdown_room::<T>)),
+            )
+            .service(
+                resource("/rooms/{room_id}/purge_history")
+                    .route(post().to(handlers::admin_rooms::purge_history::<T>)),
+            )
+            .service(
+                resource("/rooms/{room_id}/bulk_invite")
+                    .route(post().to(handlers::admin_rooms::bulk_invite::<T>)),
+            )
+            .service(
+                resource("/rooms/{room_id}/export_members")
+                    .route(post().to(handlers::admin_rooms::export_members::<T>)),
+            )
+            .service(
+                resource("/jobs/{job_id}").route(get().to(handlers::admin_rooms::get_job::<T>)),
+            )
+            .service(
+                resource("/jobs/{job_id}/members")
+                    .route(get().to(handlers::admin_rooms::get_exported_members::<T>)),
+            )
+            .service(
+                resource("/scheduled_jobs")
+                    .route(get().to(handlers::admin_jobs::list_scheduled_jobs::<T>)),
+            )
+            .service(
+                resource("/media/purge").route(post().to(handlers::admin_jobs::purge_media::<T>)),
+            )
+            .service(
+                resource("/audit_log").route(get().to(handlers::admin_audit::get_audit_log::<T>)),
+            )
+            .service(
+                resource("/ip_blocks")
+                    .route(get().to(handlers::admin_access::list_ip_blocks::<T>))
+                    .route(post().to(handlers::admin_access::add_ip_block::<T>)),
+            )
+            .service(
+                resource("/ip_blocks/{block_id}")
+                    .route(delete().to(handlers::admin_access::remove_ip_block::<T>)),
+            )
+            .service(
+                resource("/statistics").route(get().to(handlers::admin_stats::get_statistics::<T>)),
             ),
     );
+    if CONFIG.features.is_enabled(features::DEHYDRATED_DEVICES) {
+        cfg.service(
+            scope("/_matrix/client/unstable/org.matrix.msc3814.v1")
+                .service(
+                    resource("/dehydrated_device")
+                        .route(get().to(handlers::dehydrated_device::get_dehydrated_device::<T>))
+                        .route(put().to(handlers::dehydrated_device::put_dehydrated_device::<T>))
+                        .route(
+                            delete().to(handlers::dehydrated_device::delete_dehydrated_device::<T>),
+                        ),
+                )
+                .service(
+                    resource("/dehydrated_device/{device_id}/events")
+                        .route(post().to(handlers::dehydrated_device::get_events::<T>)),
+                ),
+        );
+    }
+    if CONFIG.features.is_enabled(features::ROOM_SUMMARY) {
+        cfg.service(
+            scope("/_matrix/client/unstable/im.nheko.summary").service(
+                resource("/rooms/{room_id_or_alias}/summary")
+                    .route(get().to(handlers::room_summary::get_summary::<T>)),
+            ),
+        );
+    }
+}
+
+/// Configures the media APIs.
+fn media<T: Store + 'static, M: MediaStore>(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/_matrix/media/r0")
+            .service(resource("/upload").route(post().to(handlers::media::upload::<T, M>)))
+            .service(
+                resource("/download/{server_name}/{media_id}")
+                    .route(get().to(handlers::media::download::<T, M>)),
+            )
+            .service(
+                resource("/download/{server_name}/{media_id}/{file_name}")
+                    .route(get().to(handlers::media::download::<T, M>)),
+            )
+            .service(
+                resource("/thumbnail/{server_name}/{media_id}")
+                    .route(get().to(handlers::media::thumbnail::<T, M>)),
+            )
+            .service(resource("/preview_url").route(get().to(handlers::media::preview_url::<T, M>)))
+            .service(resource("/config").route(get().to(handlers::media::get_config))),
+    );
+    if CONFIG.features.is_enabled(features::AUTHENTICATED_MEDIA) {
+        cfg.service(
+            scope("/_matrix/client/v1/media")
+                .service(
+                    resource("/download/{server_name}/{media_id}")
+                        .route(get().to(handlers::media::download_authenticated::<T, M>)),
+                )
+                .service(
+                    resource("/download/{server_name}/{media_id}/{file_name}")
+                        .route(get().to(handlers::media::download_authenticated::<T, M>)),
+                )
+                .service(
+                    resource("/thumbnail/{server_name}/{media_id}")
+                        .route(get().to(handlers::media::thumbnail_authenticated::<T, M>)),
+                )
+                .service(
+                    resource("/preview_url").route(get().to(handlers::media::preview_url::<T, M>)),
+                )
+                .service(resource("/config").route(get().to(handlers::media::get_config))),
+        );
+    }
+}
+
+/// Configures what a sync worker serves.
+fn sync<T: Store + 'static>(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/_matrix/client/r0")
+            .service(resource("/sync").route(get().to(handlers::sync::get_sync::<T>))),
+    );
+}
+
+/// Configures the replication API a federation sender worker is woken with.
+fn replication(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/_maelstrom/replication/v1").service(
+            resource("/federation/wake")
+                .route(post().to(handlers::replication::wake_federation_sender)),
+        ),
+    );
 }
@@ -0,0 +1,84 @@
+use actix_web::{
+    http::{header, StatusCode},
+    Error, HttpRequest,
+};
+use serde_json::Value;
+
+use crate::{
+    clock::now_ms,
+    db::Store,
+    federation::{
+        canonical_json, keys,
+        signing::{self, SignatureError},
+        xmatrix::{self, XMatrix},
+    },
+    server::error::{ErrorCode, MatrixError, ResultExt as _},
+    CONFIG,
+};
+
+fn unauthorized(error: impl Into<String>) -> MatrixError {
+    MatrixError::new(StatusCode::UNAUTHORIZED, ErrorCode::UNAUTHORIZED, error)
+}
+
+/// Authenticates a request from another homeserver by its `X-Matrix`
+/// signature, returning the name of the server that sent it. `content` is
+/// the JSON body of the request, if it has one.
+pub async fn authenticate<T: Store>(
+    storage: &T,
+    req: &HttpRequest,
+    content: Option<&Value>,
+) -> Result<String, Error> {
+    let auth = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<XMatrix>().ok())
+        .ok_or_else(|| unauthorized("Missing or malformed X-Matrix authorization."))?;
+    if auth
+        .destination
+        .as_deref()
+        .map_or(false, |d| d != CONFIG.hostname)
+    {
+        return Err(unauthorized("Request is meant for another server.").into());
+    }
+
+    if !CONFIG.federation_policy.is_allowed(&auth.origin) {
+        return Err(MatrixError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::FORBIDDEN,
+            "Federation with this server is not allowed.",
+        )
+        .into());
+    }
+
+    let now = now_ms();
+    let verify_keys = keys::get_verify_keys(storage, &auth.origin, now)
+        .await
+        .with_codes(StatusCode::UNAUTHORIZED, ErrorCode::UNAUTHORIZED)?;
+    let public_key = verify_keys
+        .get(&auth.key)
+        .ok_or_else(|| unauthorized("Request was signed with an unknown key."))?;
+    let uri = req
+        .uri()
+        .path_and_query()
+        .map_or_else(|| req.path(), |uri| uri.as_str());
+    let request = xmatrix::signable_request(
+        req.method().as_str(),
+        uri,
+        &auth.origin,
+        &CONFIG.hostname,
+        content,
+    );
+    signing::decode_base64(&auth.sig)
+        .ok_or(SignatureError::Malformed)
+        .and_then(|sig| {
+            signing::verify_bytes(
+                canonical_json::encode(&request).as_bytes(),
+                &sig,
+                public_key,
+            )
+        })
+        .with_codes(StatusCode::UNAUTHORIZED, ErrorCode::UNAUTHORIZED)?;
+
+    Ok(auth.origin)
+}
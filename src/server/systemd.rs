@@ -0,0 +1,148 @@
+//! Running as a systemd service.
+//!
+//! With socket activation systemd binds the listening sockets and passes
+//! them to the server, so it can be restarted without refusing connections.
+//! With notifications the server tells systemd when it is ready to serve
+//! (`Type=notify`), when it is stopping, and that it is still responsive if
+//! the unit sets `WatchdogSec`.
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::ops::Range;
+use std::os::unix::{io::FromRawFd, io::RawFd, net::UnixDatagram};
+use std::time::Duration;
+
+/// The first file descriptor systemd passes, after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the sockets systemd passed for socket activation, if any were
+/// passed to this process. The variables saying so are unset, so they aren't
+/// taken twice or inherited.
+pub fn listeners() -> io::Result<Vec<TcpListener>> {
+    let fds = listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )?;
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    fds.map(|fd| {
+        // Safe as systemd passed the descriptor for us alone to own
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        listener.local_addr().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Socket {} passed by systemd is not a TCP socket", fd),
+            )
+        })?;
+        Ok(listener)
+    })
+    .collect()
+}
+
+/// The descriptors passed for socket activation, given `LISTEN_PID` and
+/// `LISTEN_FDS`. None are for us if they were passed to another process.
+fn listen_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> io::Result<Range<RawFd>> {
+    let invalid = |var: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unable to parse {}.", var),
+        )
+    };
+    let pid: u32 = match pid {
+        Some(pid) => pid.parse().map_err(|_| invalid("LISTEN_PID"))?,
+        None => return Ok(LISTEN_FDS_START..LISTEN_FDS_START),
+    };
+    if pid != own_pid {
+        return Ok(LISTEN_FDS_START..LISTEN_FDS_START);
+    }
+    let count: RawFd = fds
+        .unwrap_or("0")
+        .parse()
+        .map_err(|_| invalid("LISTEN_FDS"))?;
+    Ok(LISTEN_FDS_START..LISTEN_FDS_START + count.max(0))
+}
+
+/// Sends systemd a notification, e.g. `READY=1`. Does nothing unless
+/// systemd asked for notifications with `NOTIFY_SOCKET`.
+pub fn notify(state: &str) -> io::Result<()> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    if path.starts_with('@') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "NOTIFY_SOCKET in the abstract namespace is not supported",
+        ));
+    }
+    UnixDatagram::unbound()?.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+/// How often to tell the watchdog the server is alive, if systemd watches
+/// this process: twice per `WatchdogSec`, so one late ping isn't fatal.
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_from(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+fn watchdog_interval_from(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+    match usec?.parse::<u64>().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec / 2)),
+    }
+}
+
+/// Tells the watchdog the server is alive every `interval`. It runs on the
+/// main thread, so pings stop if it hangs.
+pub async fn watchdog(interval: Duration) {
+    let mut interval = actix_rt::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = notify("WATCHDOG=1") {
+            tracing::warn!(error = %e, "Unable to notify the systemd watchdog");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42).unwrap(), 3..5);
+        assert_eq!(listen_fds(Some("42"), Some("2"), 7).unwrap(), 3..3);
+        assert_eq!(listen_fds(None, Some("2"), 42).unwrap(), 3..3);
+        assert!(listen_fds(Some("42"), Some("two"), 42).is_err());
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(
+            watchdog_interval_from(Some("30000000"), None, 42),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval_from(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval_from(Some("30000000"), Some("7"), 42),
+            None
+        );
+        assert_eq!(watchdog_interval_from(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval_from(None, None, 42), None);
+    }
+}
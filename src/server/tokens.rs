@@ -0,0 +1,93 @@
+//! Access tokens revoked before they expire.
+//!
+//! Access tokens are signed, so they are checked without the database. To
+//! log devices out early, when their tokens were revoked is stored instead,
+//! and tokens issued up to then are refused. Every process keeps the
+//! revocations of tokens that haven't expired yet in memory: it loads them
+//! when it starts, and reloads a user's when another process says over the
+//! replication bus that they changed. Without Redis a server doesn't hear of
+//! the revocations `maelstrom user` commands make until it restarts.
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::RwLock;
+
+use futures::StreamExt;
+
+use crate::{
+    bus::{Message, BUS},
+    clock::now_ms,
+    db::Store,
+    models::account::TokenRevocation,
+    CONFIG,
+};
+
+/// The name revocations are invalidated under on the replication bus.
+const TOKEN_REVOCATIONS: &str = "token_revocations";
+
+lazy_static::lazy_static! {
+    static ref REVOCATIONS: RwLock<HashMap<String, TokenRevocation>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Loads the revocations of tokens that may not have expired yet.
+pub async fn load<T: Store>(storage: &T) -> Result<(), Box<dyn Error>> {
+    let revocations = storage
+        .get_token_revocations(now_ms() - CONFIG.session_expiration * 1000)
+        .await?;
+    REVOCATIONS.write().unwrap().extend(revocations);
+    Ok(())
+}
+
+/// Whether a token of a local user's device issued at `issued_ts`, in
+/// milliseconds, has been revoked.
+pub fn is_revoked(localpart: &str, device_id: &str, issued_ts: i64) -> bool {
+    REVOCATIONS
+        .read()
+        .unwrap()
+        .get(localpart)
+        .map_or(false, |revocation| revocation.revokes(device_id, issued_ts))
+}
+
+/// Revokes every access token issued to a user so far, but those of
+/// `kept_device_id`, telling every process sharing the database.
+pub async fn revoke<T: Store>(
+    storage: &T,
+    localpart: &str,
+    kept_device_id: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let previous = storage.get_token_revocation(localpart).await?;
+    let revocation = TokenRevocation::new(previous.as_ref(), now_ms(), kept_device_id);
+    storage.set_token_revocation(localpart, &revocation).await?;
+
+    REVOCATIONS
+        .write()
+        .unwrap()
+        .insert(localpart.to_owned(), revocation);
+    BUS.publish(Message::Invalidate {
+        cache: TOKEN_REVOCATIONS.to_owned(),
+        key: localpart.to_owned(),
+    });
+    Ok(())
+}
+
+/// Reloads the revocations other processes say changed, for as long as the
+/// process runs.
+pub async fn follow_invalidations<T: Store>(storage: T) {
+    let mut messages = BUS.subscribe();
+    while let Some(message) = messages.next().await {
+        if let Message::Invalidate { cache, key } = message {
+            if cache != TOKEN_REVOCATIONS {
+                continue;
+            }
+            match storage.get_token_revocation(&key).await {
+                Ok(Some(revocation)) => {
+                    REVOCATIONS.write().unwrap().insert(key, revocation);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!(localpart = %key, error = %e, "Unable to reload revoked tokens")
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use actix_web::{http::StatusCode, Error, HttpResponse};
+use rand::{distributions::Alphanumeric, Rng};
+use serde_json::json;
+
+use crate::{
+    audit,
+    clock::now_ms,
+    db::Store,
+    models::auth::{AuthData, UiaSession, UserIdentifier},
+    server::{
+        access,
+        error::{ErrorCode, ResultExt as _},
+        extract::Authenticated,
+    },
+};
+
+/// The only stage currently offered: re-entering the account password.
+const PASSWORD_STAGE: &str = "m.login.password";
+/// How long a session can be used for after it was started.
+const SESSION_LIFETIME_MS: i64 = 15 * 60 * 1000;
+
+/// Builds the `401` response describing the flows the client can complete
+/// in `session`. `error` is set when the client attempted a stage and failed
+/// it.
+fn challenge(session: &str, error: Option<(ErrorCode, &str)>) -> Error {
+    let mut body = json!({
+        "flows": [{ "stages": [PASSWORD_STAGE] }],
+        "params": {},
+        "session": session,
+    });
+    if let Some((errcode, error)) = error {
+        body["errcode"] = json!(errcode);
+        body["error"] = json!(error);
+    }
+    HttpResponse::Unauthorized().json(body).into()
+}
+
+/// Starts a session for `user` to authorize `operation` through, returning
+/// the `401` challenge for it.
+async fn start_session<T: Store>(
+    storage: &T,
+    user: &Authenticated,
+    operation: &str,
+) -> Result<Error, Error> {
+    let now = now_ms();
+    storage
+        .delete_uia_sessions_before(now - SESSION_LIFETIME_MS)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let session_id: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .collect();
+    let session = UiaSession {
+        localpart: user.user_id.local_part.clone(),
+        device_id: user.device_id.clone(),
+        operation: operation.to_owned(),
+        created_ts: now,
+    };
+    storage
+        .add_uia_session(&session_id, &session)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    Ok(challenge(&session_id, None))
+}
+
+/// Checks a password against an argon2 encoded hash.
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+}
+
+/// Hashes a password with argon2 and a random salt, encoded for storing.
+pub fn hash_password(password: &str) -> Result<String, argon2::Error> {
+    let salt: [u8; 16] = rand::random();
+    argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default())
+}
+
+/// Requires the requester to re-authenticate through the User-Interactive
+/// Authentication API before a sensitive operation, e.g.
+/// `account.deactivate`.
+///
+/// Returns `Ok(())` once the client has completed a flow; otherwise the
+/// returned error is the `401` challenge the client should answer. Each
+/// session is stored, bound to the requester's device and to `operation`,
+/// and is used up once it authorizes a request, so completed stages can't
+/// be replayed for another one.
+pub async fn authenticate<T: Store>(
+    storage: &T,
+    user: &Authenticated,
+    operation: &str,
+    auth: Option<&AuthData>,
+) -> Result<(), Error> {
+    let (auth, session_id) = match auth.and_then(|auth| Some((auth, auth.session.as_deref()?))) {
+        Some(auth) => auth,
+        None => return Err(start_session(storage, user, operation).await?),
+    };
+    let session = storage
+        .get_uia_session(session_id)
+        .await
+        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+    let valid = session.map_or(false, |session| {
+        session.localpart == user.user_id.local_part
+            && session.device_id == user.device_id
+            && session.operation == operation
+            && session.created_ts >= now_ms() - SESSION_LIFETIME_MS
+    });
+    if !valid {
+        return Err(start_session(storage, user, operation).await?);
+    }
+
+    match (auth.auth_type.as_deref(), &auth.password) {
+        (Some(PASSWORD_STAGE), Some(password)) => {
+            if let Some(UserIdentifier::UserId { user: identified }) = &auth.identifier {
+                if identified != &user.user_id {
+                    return Err(challenge(
+                        session_id,
+                        Some((
+                            ErrorCode::FORBIDDEN,
+                            "Identifier does not match this session.",
+                        )),
+                    ));
+                }
+            }
+            let hash = storage
+                .get_password_hash(&user.user_id.local_part)
+                .await
+                .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+            match hash {
+                Some(hash) if verify_password(&hash, password) => {
+                    // Only one request gets to use the session up
+                    let used_up = storage
+                        .delete_uia_session(session_id)
+                        .await
+                        .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+                    if used_up {
+                        Ok(())
+                    } else {
+                        Err(start_session(storage, user, operation).await?)
+                    }
+                }
+                _ => {
+                    audit::record(
+                        storage,
+                        Some(&user.user_id.to_string()),
+                        user.ip,
+                        "auth.password_failed",
+                        &json!({ "device_id": user.device_id }),
+                    )
+                    .await
+                    .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+                    if let Some(ip) = user.ip {
+                        access::record_failed_login(storage, ip)
+                            .await
+                            .with_codes(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::UNKNOWN)?;
+                    }
+                    Err(challenge(
+                        session_id,
+                        Some((ErrorCode::FORBIDDEN, "Invalid password.")),
+                    ))
+                }
+            }
+        }
+        _ => Err(challenge(session_id, None)),
+    }
+}
@@ -0,0 +1,217 @@
+//! Running maelstrom as several processes sharing one database.
+//!
+//! Each process has a role, which decides which APIs it serves and which
+//! background work it owns. A reverse proxy sends each API to the processes
+//! serving it. Work one process queues for another, e.g. federation to send,
+//! is stored first, and the owning process is then woken over its internal
+//! replication API.
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use actix_web::{client::Client, http::header, HttpRequest};
+use serde_json::json;
+
+/// How long the owning process has to take a wakeup.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a maelstrom process does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// Serves every API, and runs whatever background work isn't given to
+    /// a worker
+    Main,
+    /// Only serves `/sync`
+    SyncWorker,
+    /// Only sends federation transactions, woken over the replication API
+    FederationSender,
+    /// Only serves the media APIs, and enforces the media retention policies
+    Media,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidRole(String);
+
+impl fmt::Display for InvalidRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid role `{}`, expected `main`, `sync-worker`, `federation-sender` or `media`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidRole {}
+
+impl FromStr for Role {
+    type Err = InvalidRole;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "main" => Ok(Role::Main),
+            "sync-worker" => Ok(Role::SyncWorker),
+            "federation-sender" => Ok(Role::FederationSender),
+            "media" => Ok(Role::Media),
+            other => Err(InvalidRole(other.to_owned())),
+        }
+    }
+}
+
+/// How this process shares work with the others.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub role: Role,
+    /// The internal base URL of the federation sender worker, if there is
+    /// one. Other processes then leave sending federation to it.
+    pub federation_sender_url: Option<String>,
+    /// Whether a media worker enforces the media retention policies, so the
+    /// main process doesn't.
+    pub media_worker: bool,
+    /// The secret processes authenticate to each other's replication APIs
+    /// with
+    pub replication_secret: Option<String>,
+}
+
+impl Settings {
+    /// Reads the worker settings from `env` vars. Panics if any can't be
+    /// parsed, or if workers are set up without a replication secret.
+    pub fn from_env() -> Self {
+        let settings = Settings {
+            role: std::env::var("ROLE")
+                .map(|role| role.parse().expect("Unable to parse ROLE."))
+                .unwrap_or(Role::Main),
+            federation_sender_url: std::env::var("FEDERATION_SENDER_URL")
+                .ok()
+                .map(|url| url.trim_end_matches('/').to_owned()),
+            media_worker: std::env::var("MEDIA_WORKER")
+                .map(|enabled| {
+                    enabled
+                        .parse()
+                        .expect("Unable to parse MEDIA_WORKER as bool.")
+                })
+                .unwrap_or(false),
+            replication_secret: std::env::var("REPLICATION_SECRET").ok(),
+        };
+        if (settings.role == Role::FederationSender || settings.federation_sender_url.is_some())
+            && settings.replication_secret.is_none()
+        {
+            panic!("REPLICATION_SECRET env var missing.");
+        }
+        settings
+    }
+
+    /// Whether this process sends federation transactions itself.
+    pub fn sends_federation(&self) -> bool {
+        match self.role {
+            Role::FederationSender => true,
+            Role::Main => self.federation_sender_url.is_none(),
+            Role::SyncWorker | Role::Media => false,
+        }
+    }
+
+    /// Whether this process enforces the media retention policies.
+    pub fn enforces_media_retention(&self) -> bool {
+        match self.role {
+            Role::Media => true,
+            Role::Main => !self.media_worker,
+            Role::SyncWorker | Role::FederationSender => false,
+        }
+    }
+
+    /// Whether a replication request carries the replication secret.
+    pub fn is_replication_request(&self, req: &HttpRequest) -> bool {
+        let secret = match &self.replication_secret {
+            Some(secret) => secret,
+            None => return false,
+        };
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .map_or(false, |token| {
+                ring::constant_time::verify_slices_are_equal(token.as_bytes(), secret.as_bytes())
+                    .is_ok()
+            })
+    }
+}
+
+/// Wakes the federation sender worker at `url` for a destination, after
+/// something was queued for it.
+pub async fn wake_federation_sender(
+    url: &str,
+    secret: &str,
+    destination: &str,
+) -> Result<(), String> {
+    let res = Client::build()
+        .timeout(TIMEOUT)
+        .finish()
+        .post(format!("{}/_maelstrom/replication/v1/federation/wake", url))
+        .bearer_auth(secret)
+        .send_json(&json!({ "destination": destination }))
+        .await
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("Federation sender responded {}", res.status()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(role: Role) -> Settings {
+        Settings {
+            role,
+            federation_sender_url: None,
+            media_worker: false,
+            replication_secret: Some("secret".to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_parse_role() {
+        assert_eq!("main".parse(), Ok(Role::Main));
+        assert_eq!("sync-worker".parse(), Ok(Role::SyncWorker));
+        assert_eq!("federation-sender".parse(), Ok(Role::FederationSender));
+        assert_eq!("media".parse(), Ok(Role::Media));
+        assert!("sync_worker".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn test_owned_work() {
+        let main = settings(Role::Main);
+        assert!(main.sends_federation());
+        assert!(main.enforces_media_retention());
+
+        let main = Settings {
+            federation_sender_url: Some("http://localhost:8009".to_owned()),
+            media_worker: true,
+            ..settings(Role::Main)
+        };
+        assert!(!main.sends_federation());
+        assert!(!main.enforces_media_retention());
+
+        assert!(settings(Role::FederationSender).sends_federation());
+        assert!(!settings(Role::SyncWorker).sends_federation());
+        assert!(settings(Role::Media).enforces_media_retention());
+        assert!(!settings(Role::Media).sends_federation());
+    }
+
+    #[test]
+    fn test_is_replication_request() {
+        use actix_web::test::TestRequest;
+
+        let settings = settings(Role::FederationSender);
+        let req = TestRequest::default()
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .to_http_request();
+        assert!(settings.is_replication_request(&req));
+        let req = TestRequest::default()
+            .header(header::AUTHORIZATION, "Bearer wrong")
+            .to_http_request();
+        assert!(!settings.is_replication_request(&req));
+        assert!(!settings.is_replication_request(&TestRequest::default().to_http_request()));
+    }
+}
@@ -0,0 +1,91 @@
+//! Shutting down gracefully.
+//!
+//! On SIGTERM or SIGINT the server stops accepting connections and gives the
+//! requests it is serving until the shutdown timeout to finish. The
+//! background senders then get what is left of it to finish the
+//! transactions they are sending, so nothing is sent twice, before the
+//! database pool is closed. Whatever is still queued is durable and sent
+//! once the server starts again.
+use std::io;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+use actix_rt::signal::unix::{signal, SignalKind};
+use futures::future::{self, Either};
+
+/// How often `InFlight::wait` checks whether the work is done.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Counts work that shouldn't be cut off by a shutdown, e.g. transactions
+/// being sent. Can be cloned and used from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+/// Work counted by an `InFlight`, until it is dropped.
+#[derive(Debug)]
+pub struct Started(Arc<AtomicUsize>);
+
+impl Drop for Started {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl InFlight {
+    /// Counts work as started, until the returned guard is dropped.
+    pub fn start(&self) -> Started {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Started(self.0.clone())
+    }
+
+    /// How much work is in flight.
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Waits until no work is in flight, for at most `timeout`. Returns
+    /// whether it all finished.
+    pub async fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.count() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            actix_rt::time::delay_for(POLL_INTERVAL).await;
+        }
+        true
+    }
+}
+
+/// Waits for SIGTERM or SIGINT, returning the name of the one received.
+pub async fn signal_received() -> io::Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let received = future::select(Box::pin(terminate.recv()), Box::pin(interrupt.recv())).await;
+    Ok(match received {
+        Either::Left(_) => "SIGTERM",
+        Either::Right(_) => "SIGINT",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_in_flight() {
+        let in_flight = InFlight::default();
+        let first = in_flight.start();
+        let second = in_flight.clone().start();
+        assert_eq!(in_flight.count(), 2);
+        drop(first);
+        assert!(!in_flight.wait(Duration::from_millis(10)).await);
+
+        drop(second);
+        assert_eq!(in_flight.count(), 0);
+        assert!(in_flight.wait(Duration::from_millis(10)).await);
+    }
+}
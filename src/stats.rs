@@ -0,0 +1,49 @@
+//! Aggregates about the server: how many users and rooms it has, and which
+//! version it runs.
+//!
+//! Admins can read them through the admin API. Servers that opt in with
+//! `REPORT_STATS_URL` also report them once a day, so whoever runs the
+//! endpoint can see how the server is used. Nothing identifying users or
+//! rooms is reported.
+use std::error::Error;
+use std::time::Duration;
+
+use actix_web::client::Client;
+
+use crate::{clock::now_ms, db::Store, models::admin::Statistics, CONFIG};
+
+/// How long to wait for the stats endpoint to respond.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Gathers the server's aggregates.
+pub async fn collect<T: Store>(storage: &T) -> Result<Statistics, Box<dyn Error>> {
+    let timestamp = now_ms();
+    Ok(Statistics {
+        homeserver: CONFIG.hostname.clone(),
+        server_version: env!("CARGO_PKG_VERSION").to_owned(),
+        timestamp,
+        total_users: storage.count_active_accounts().await?,
+        total_room_count: storage.count_rooms().await?,
+    })
+}
+
+/// Gathers the server's aggregates, and posts them to `url` as JSON.
+pub async fn report<T: Store>(storage: &T, url: &str) -> Result<(), Box<dyn Error>> {
+    let stats = collect(storage).await?;
+    let res = Client::build()
+        .timeout(TIMEOUT)
+        .finish()
+        .post(url)
+        .send_json(&stats)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("The stats endpoint responded {}", res.status()).into());
+    }
+    tracing::info!(
+        total_users = stats.total_users,
+        total_room_count = stats.total_room_count,
+        "Reported stats"
+    );
+    Ok(())
+}
@@ -0,0 +1,136 @@
+//! Logging and tracing. Requests, storage calls and federation traffic are
+//! traced with `tracing` spans, which can be exported to an OpenTelemetry
+//! collector over OTLP to follow a slow request down to its queries.
+//!
+//! Logs are written as plain text or as JSON lines, in which each line
+//! carries the fields of the spans it was logged in, e.g. the request ID,
+//! user ID and room ID of the request.
+//!
+//! Storage calls slower than `SLOW_QUERY_THRESHOLD` are logged and counted,
+//! see `db::slow_queries`.
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use tracing::Subscriber;
+use tracing_subscriber::{
+    layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt, EnvFilter,
+};
+
+use crate::db::slow_queries::SlowQueries;
+
+/// What is logged when `RUST_LOG` isn't set.
+const DEFAULT_FILTER: &str = "actix_web=info,maelstrom=info";
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidLogFormat(String);
+
+impl fmt::Display for InvalidLogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid log format `{}`, expected `text` or `json`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidLogFormat {}
+
+impl FromStr for LogFormat {
+    type Err = InvalidLogFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(InvalidLogFormat(s.to_owned())),
+        }
+    }
+}
+
+/// Where traces are exported to.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The OTLP gRPC endpoint of an OpenTelemetry collector, e.g.
+    /// `localhost:55680`
+    pub otlp_endpoint: String,
+}
+
+/// Keeps the trace exporter running. Spans still buffered are flushed when
+/// it is dropped, so it must live as long as the server.
+pub struct Guard {
+    _exporter: Option<opentelemetry_otlp::Uninstall>,
+}
+
+/// Starts logging in `format`, and exporting traces if `config` says where
+/// to. Records from the `log` macros of dependencies are logged too, and so
+/// are storage calls that take at least `slow_query_threshold`, if given.
+pub fn init(
+    format: LogFormat,
+    config: Option<&Config>,
+    slow_query_threshold: Option<Duration>,
+) -> Guard {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(slow_query_threshold.map(SlowQueries::new));
+    match format {
+        LogFormat::Text => install(registry.with(tracing_subscriber::fmt::layer()), config),
+        LogFormat::Json => install(
+            registry.with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(true),
+            ),
+            config,
+        ),
+    }
+}
+
+/// Installs a subscriber as the global default, adding the trace exporter
+/// if there is one.
+fn install<S>(subscriber: S, config: Option<&Config>) -> Guard
+where
+    S: Subscriber + for<'span> LookupSpan<'span> + Send + Sync + 'static,
+{
+    match config {
+        Some(config) => {
+            let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+                .with_endpoint(&config.otlp_endpoint)
+                .install();
+            subscriber
+                .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                .init();
+            Guard {
+                _exporter: Some(uninstall),
+            }
+        }
+        None => {
+            subscriber.init();
+            Guard { _exporter: None }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!("text".parse(), Ok(LogFormat::Text));
+        assert_eq!(" JSON ".parse(), Ok(LogFormat::Json));
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
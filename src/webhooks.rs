@@ -0,0 +1,161 @@
+//! Notifying operators of security relevant actions over HTTP, e.g. so they
+//! can be alerted in a chat room.
+//!
+//! When `WEBHOOK_URLS` is set, the audit log entries for the actions in
+//! `WEBHOOK_ACTIONS` are POSTed as JSON to each URL as they are recorded.
+//! Every body is signed with HMAC-SHA256, keyed with `WEBHOOK_SECRET`, in
+//! the `X-Maelstrom-Signature` header as `sha256=<hex>`, so receivers can
+//! tell it came from the server. Each delivery is a job, so it is retried
+//! if the receiver is down, even across restarts.
+use std::error::Error;
+use std::time::Duration;
+
+use actix_web::client::Client;
+use ring::hmac;
+use serde_json::{json, Value};
+
+use crate::{clock::now_ms, db::Store, scheduler, CONFIG};
+
+/// The header bodies are signed in.
+const SIGNATURE_HEADER: &str = "X-Maelstrom-Signature";
+/// How long to wait for a receiver to respond.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// The actions notified of unless `WEBHOOK_ACTIONS` says otherwise.
+const DEFAULT_ACTIONS: &[&str] = &[
+    "auth.new_device",
+    "keys.cross_signing_reset",
+    "account.password_change",
+    "admin.user.reset_password",
+    "access.ip_blocked",
+];
+
+/// Where, and about what, operators are notified.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// The URLs notifications are POSTed to
+    pub urls: Vec<String>,
+    /// The key bodies are signed with
+    pub secret: String,
+    /// The audit log actions notified of
+    pub actions: Vec<String>,
+}
+
+impl Settings {
+    /// Reads the settings from `env` vars, if webhooks are configured.
+    /// Panics if URLs are given without a secret.
+    pub fn from_env() -> Option<Self> {
+        let list = |var: &str| -> Option<Vec<String>> {
+            let list: Vec<String> = std::env::var(var)
+                .ok()?
+                .split(',')
+                .map(|item| item.trim().to_owned())
+                .filter(|item| !item.is_empty())
+                .collect();
+            Some(list).filter(|list| !list.is_empty())
+        };
+        let urls = list("WEBHOOK_URLS")?;
+        Some(Settings {
+            urls,
+            secret: std::env::var("WEBHOOK_SECRET")
+                .expect("WEBHOOK_SECRET must be set along with WEBHOOK_URLS."),
+            actions: list("WEBHOOK_ACTIONS").unwrap_or_else(|| {
+                DEFAULT_ACTIONS
+                    .iter()
+                    .map(|action| (*action).to_owned())
+                    .collect()
+            }),
+        })
+    }
+
+    fn notifies_of(&self, action: &str) -> bool {
+        self.actions.iter().any(|a| a == action)
+    }
+}
+
+/// Queues a notification of an audit log entry to every webhook, if its
+/// action is one they are notified of.
+pub async fn notify<T: Store>(
+    storage: &T,
+    user_id: Option<&str>,
+    ip: Option<&str>,
+    action: &str,
+    details: &Value,
+) -> Result<(), Box<dyn Error>> {
+    let settings = match &CONFIG.webhooks {
+        Some(settings) if settings.notifies_of(action) => settings,
+        _ => return Ok(()),
+    };
+    let timestamp = now_ms();
+    let body = json!({
+        "server_name": CONFIG.hostname,
+        "timestamp": timestamp,
+        "action": action,
+        "user_id": user_id,
+        "ip": ip,
+        "details": details,
+    });
+    for url in &settings.urls {
+        scheduler::schedule(
+            storage,
+            scheduler::DELIVER_WEBHOOK,
+            &json!({ "url": url, "body": body }),
+            Duration::from_secs(0),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// POSTs a notification to a webhook, signed with the configured secret.
+pub async fn deliver(url: &str, body: &Value) -> Result<(), Box<dyn Error>> {
+    let secret = match &CONFIG.webhooks {
+        Some(settings) => &settings.secret,
+        None => return Err("Webhooks are no longer configured".into()),
+    };
+    let body = serde_json::to_vec(body)?;
+    let res = Client::build()
+        .timeout(TIMEOUT)
+        .finish()
+        .post(url)
+        .content_type("application/json")
+        .header(SIGNATURE_HEADER, signature(secret, &body))
+        .send_body(body)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("The webhook responded {}", res.status()).into());
+    }
+    Ok(())
+}
+
+/// The signature of a body, as sent in `X-Maelstrom-Signature`.
+fn signature(secret: &str, body: &[u8]) -> String {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()), body);
+    let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // From RFC 4231, test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_notifies_of() {
+        let settings = Settings {
+            urls: vec!["https://hooks.example.com".to_owned()],
+            secret: "secret".to_owned(),
+            actions: vec!["keys.cross_signing_reset".to_owned()],
+        };
+        assert!(settings.notifies_of("keys.cross_signing_reset"));
+        assert!(!settings.notifies_of("auth.password_failed"));
+    }
+}